@@ -0,0 +1,11 @@
+use minuteman::utils::backfill_file_meta;
+
+fn main() {
+    let db =
+        rocksdb::DB::open_default("./db")
+            .unwrap();
+
+    let written = backfill_file_meta(&db);
+
+    println!("wrote {} file metadata records", written);
+}
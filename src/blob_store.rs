@@ -0,0 +1,1051 @@
+use std::fs::{self, File};
+use std::io::{self, Cursor, Read};
+use std::path::PathBuf;
+use std::pin::Pin;
+
+use chrono::Utc;
+use futures::{future, stream, Stream, StreamExt};
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use reqwest::Method;
+use reqwest::StatusCode;
+use rocksdb::{DBWithThreadMode, MultiThreaded};
+use sha2::Sha256;
+
+use crate::config::{BlobBackend, get_blob_backend, get_blob_dir, get_s3_config, S3Config};
+use crate::encryption::{is_encrypted_blob, open_blob, seal_blob};
+use crate::storage::ReadStore;
+use crate::storage_stats::{delete_counted, FILE_KINDS, prefix_iter};
+use crate::utils::{encode_query_value, hash_file};
+
+// stored under a `file:*` key in place of a blob kept by another store,
+// followed by that store's code. The object's name is the key itself
+const POINTER_MAGIC: &[u8] = b"\0mmptr\x01";
+
+// what `stream_blob` reads at a time
+const STREAM_CHUNK_SIZE: usize = 256 * 1024;
+
+static S3_CLIENT: Lazy<reqwest::Client> = Lazy::new(reqwest::Client::new);
+
+// where the s3 store's requests run. It's its own so that the store works
+// the same from any thread: outside of a runtime, on a current thread one
+// and on the workers of the server's
+static S3_RUNTIME: Lazy<tokio::runtime::Runtime> =
+    Lazy::new(||
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .thread_name("s3-blob-store")
+            .enable_all()
+            .build()
+            .expect("can't start the s3 blob store's runtime")
+    );
+
+// keys `place_blob_in` fails on, for tests of what a failed blob write
+// leaves behind. Per thread, like the tests
+#[cfg(test)]
+thread_local! {
+    pub static FAILING_BLOB_KEYS: std::cell::RefCell<Vec<String>> = std::cell::RefCell::new(Vec::new());
+}
+
+// one blob directory for every test of a run, the variable is process wide
+#[cfg(test)]
+pub fn use_test_blob_dir() -> PathBuf {
+    static DIR: Lazy<PathBuf> =
+        Lazy::new(|| {
+            let dir = std::env::temp_dir().join(format!("minuteman-test-blobs-{}", std::process::id()));
+
+            let _ = fs::remove_dir_all(&dir);
+
+            std::env::set_var("MINUTEMAN_BLOB_DIR", &dir);
+
+            dir
+        });
+
+    DIR.clone()
+}
+
+/// Somewhere file blobs are kept, addressed by their `file:{kind}:{id}`
+/// key. What's handed in and out is the blob as stored, encrypted when
+/// encryption is on.
+pub trait BlobStore {
+    fn put(
+        &self,
+        key: &str,
+        blob: &[u8],
+    ) -> Result<(), Box<dyn std::error::Error>>;
+
+    fn get(
+        &self,
+        key: &str,
+    ) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>>;
+
+    fn delete(
+        &self,
+        key: &str,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+
+    fn exists(
+        &self,
+        key: &str,
+    ) -> Result<bool, Box<dyn std::error::Error>>;
+
+    /// `put` from a reader. Stores that can write as they read override
+    /// it, the others take the whole blob at once.
+    fn put_stream(
+        &self,
+        key: &str,
+        reader: &mut dyn Read,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut blob = Vec::new();
+
+        reader.read_to_end(&mut blob)?;
+
+        self.put(key, &blob)
+    }
+
+    /// `get` as a reader, see `put_stream`.
+    fn get_stream(
+        &self,
+        key: &str,
+    ) -> Result<Option<Box<dyn Read + Send>>, Box<dyn std::error::Error>> {
+        Ok(
+            self.get(key)?
+                .map(|blob| Box::new(Cursor::new(blob)) as Box<dyn Read + Send>),
+        )
+    }
+}
+
+/// Blobs inline in the database, the way they always were. Writes here
+/// skip the storage counters, `minuteman blobs migrate` rebuilds them.
+pub struct RocksDbBlobStore<'a> {
+    pub db: &'a DBWithThreadMode<MultiThreaded>,
+}
+
+impl BlobStore for RocksDbBlobStore<'_> {
+    fn put(
+        &self,
+        key: &str,
+        blob: &[u8],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(self.db.put(key, blob)?)
+    }
+
+    fn get(
+        &self,
+        key: &str,
+    ) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        Ok(self.db.get(key)?)
+    }
+
+    fn delete(
+        &self,
+        key: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(self.db.delete(key)?)
+    }
+
+    fn exists(
+        &self,
+        key: &str,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        Ok(self.db.get_pinned(key)?.is_some())
+    }
+}
+
+// (kind, file id) of a blob key, None for anything that isn't one or
+// wouldn't make a safe file or object name
+fn split_blob_key(
+    key: &str,
+) -> Option<(&str, &str)> {
+    let mut parts = key.splitn(3, ':');
+
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some("file"), Some(kind), Some(file_id))
+            if FILE_KINDS.contains(&kind)
+                && !file_id.is_empty()
+                && !file_id.starts_with('.')
+                && !file_id.contains('/')
+                && !file_id.contains('\\') =>
+            Some((kind, file_id)),
+        _ => None,
+    }
+}
+
+fn not_a_blob_key(
+    key: &str,
+) -> Box<dyn std::error::Error> {
+    format!("{} isn't a file blob key", key).into()
+}
+
+/// Blobs as files under `MINUTEMAN_BLOB_DIR`, at `{kind}/ab/cd/{file_id}`
+/// with `ab/cd` from the hash of the file id. The ids of a kind share
+/// their first characters, the hash spreads them over the directories.
+pub struct FsBlobStore {
+    pub dir: PathBuf,
+}
+
+impl FsBlobStore {
+    pub fn path(
+        &self,
+        key: &str,
+    ) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        let (kind, file_id) = split_blob_key(key).ok_or_else(|| not_a_blob_key(key))?;
+
+        let hash = hash_file(file_id.as_bytes());
+
+        Ok(
+            self.dir
+                .join(kind)
+                .join(&hash[0..2])
+                .join(&hash[2..4])
+                .join(file_id),
+        )
+    }
+}
+
+impl BlobStore for FsBlobStore {
+    fn put(
+        &self,
+        key: &str,
+        blob: &[u8],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.put_stream(key, &mut Cursor::new(blob))
+    }
+
+    fn get(
+        &self,
+        key: &str,
+    ) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        match fs::read(self.path(key)?) {
+            Ok(blob) => Ok(Some(blob)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn delete(
+        &self,
+        key: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match fs::remove_file(self.path(key)?) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn exists(
+        &self,
+        key: &str,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        Ok(self.path(key)?.is_file())
+    }
+
+    fn put_stream(
+        &self,
+        key: &str,
+        reader: &mut dyn Read,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let path = self.path(key)?;
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // written next to it and moved over, nobody reads half a file
+        let mut partial = path.clone().into_os_string();
+
+        partial.push(".partial");
+
+        let mut file = File::create(&partial)?;
+
+        io::copy(reader, &mut file)?;
+
+        file.sync_all()?;
+
+        fs::rename(&partial, &path)?;
+
+        Ok(())
+    }
+
+    fn get_stream(
+        &self,
+        key: &str,
+    ) -> Result<Option<Box<dyn Read + Send>>, Box<dyn std::error::Error>> {
+        match File::open(self.path(key)?) {
+            Ok(file) => Ok(Some(Box::new(file))),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+fn hmac_sha256(
+    key: &[u8],
+    data: &[u8],
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key)
+            .map_err(|err| err.to_string())?;
+
+    mac.update(data);
+
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+/// Blobs as objects named `{kind}/{file_id}` in an S3-compatible bucket,
+/// see `get_s3_config`. Requests are signed with AWS signature version 4.
+pub struct S3BlobStore {
+    pub config: S3Config,
+}
+
+impl S3BlobStore {
+    /// Sends a signed request for the object of `key` and reads the answer.
+    /// A 404 is an answer, other failures are errors.
+    pub async fn request(
+        &self,
+        method: Method,
+        key: &str,
+        body: Option<Vec<u8>>,
+    ) -> Result<(StatusCode, Vec<u8>), String> {
+        let (kind, file_id) = split_blob_key(key).ok_or_else(|| not_a_blob_key(key).to_string())?;
+
+        let config = &self.config;
+
+        let (scheme, address) =
+            config.endpoint
+                .split_once("://")
+                .unwrap_or(("https", &config.endpoint));
+
+        // an endpoint can be served under a path of its own, the bucket is
+        // addressed below it
+        let (host, prefix) =
+            match address.find('/') {
+                Some(slash) => address.split_at(slash),
+                None => (address, ""),
+            };
+
+        let path =
+            format!(
+                "{}/{}/{}/{}",
+                prefix.trim_end_matches('/'),
+                encode_query_value(&config.bucket),
+                encode_query_value(kind),
+                encode_query_value(file_id),
+            );
+
+        let payload_hash = hash_file(body.as_deref().unwrap_or_default());
+
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+
+        let canonical_request =
+            format!(
+                "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+                method.as_str(),
+                path,
+                host,
+                payload_hash,
+                amz_date,
+                signed_headers,
+                payload_hash,
+            );
+
+        let scope = format!("{}/{}/s3/aws4_request", date, config.region);
+
+        let string_to_sign =
+            format!(
+                "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+                amz_date,
+                scope,
+                hash_file(canonical_request.as_bytes()),
+            );
+
+        let mut signing_key = format!("AWS4{}", config.secret_key).into_bytes();
+
+        for part in [date.as_str(), config.region.as_str(), "s3", "aws4_request"] {
+            signing_key = hmac_sha256(&signing_key, part.as_bytes()).map_err(|err| err.to_string())?;
+        }
+
+        let signature =
+            hmac_sha256(&signing_key, string_to_sign.as_bytes())
+                .map_err(|err| err.to_string())?
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<String>();
+
+        let authorization =
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                config.access_key,
+                scope,
+                signed_headers,
+                signature,
+            );
+
+        let mut request =
+            S3_CLIENT
+                .request(method.clone(), format!("{}://{}{}", scheme, host, path))
+                .header("x-amz-content-sha256", &payload_hash)
+                .header("x-amz-date", &amz_date)
+                .header("authorization", &authorization);
+
+        if let Some(body) = body {
+            request = request.body(body);
+        }
+
+        let response = request.send().await.map_err(|err| err.to_string())?;
+        let status = response.status();
+        let answer = response.bytes().await.map_err(|err| err.to_string())?.to_vec();
+
+        if !status.is_success() && status != StatusCode::NOT_FOUND {
+            return Err(
+                format!(
+                    "s3 answered {} to {} {}: {}",
+                    status,
+                    method,
+                    key,
+                    String::from_utf8_lossy(&answer),
+                ),
+            );
+        }
+
+        Ok((status, answer))
+    }
+
+    // `request` for the blocking `BlobStore` methods: it runs on the store's
+    // runtime and the calling thread waits for the answer
+    fn send(
+        &self,
+        method: Method,
+        key: &str,
+        body: Option<&[u8]>,
+    ) -> Result<(StatusCode, Vec<u8>), Box<dyn std::error::Error>> {
+        let store = S3BlobStore { config: self.config.clone() };
+        let key = key.to_string();
+        let body = body.map(|body| body.to_vec());
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+
+        S3_RUNTIME.spawn(async move {
+            let _ = sender.send(store.request(method, &key, body).await);
+        });
+
+        Ok(receiver.recv()??)
+    }
+}
+
+impl BlobStore for S3BlobStore {
+    fn put(
+        &self,
+        key: &str,
+        blob: &[u8],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match self.send(Method::PUT, key, Some(blob))? {
+            (StatusCode::NOT_FOUND, _) => Err(format!("s3 bucket {} doesn't exist", self.config.bucket).into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn get(
+        &self,
+        key: &str,
+    ) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        match self.send(Method::GET, key, None)? {
+            (StatusCode::NOT_FOUND, _) => Ok(None),
+            (_, blob) => Ok(Some(blob)),
+        }
+    }
+
+    fn delete(
+        &self,
+        key: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.send(Method::DELETE, key, None)?;
+
+        Ok(())
+    }
+
+    fn exists(
+        &self,
+        key: &str,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        Ok(self.send(Method::HEAD, key, None)?.0 != StatusCode::NOT_FOUND)
+    }
+}
+
+// the store of a backend that keeps blobs outside of the database
+fn external_store(
+    backend: BlobBackend,
+) -> Result<Box<dyn BlobStore>, Box<dyn std::error::Error>> {
+    match backend {
+        BlobBackend::RocksDb => Err("rocksdb isn't an external blob store".into()),
+        BlobBackend::Fs =>
+            Ok(Box::new(FsBlobStore { dir: PathBuf::from(get_blob_dir()) })),
+        BlobBackend::S3 =>
+            Ok(Box::new(S3BlobStore { config: get_s3_config().ok_or("the s3 blob store isn't configured")? })),
+    }
+}
+
+/// The store of `backend`, the RocksDB one works on `db`.
+pub fn blob_store(
+    db: &DBWithThreadMode<MultiThreaded>,
+    backend: BlobBackend,
+) -> Result<Box<dyn BlobStore + '_>, Box<dyn std::error::Error>> {
+    match backend {
+        BlobBackend::RocksDb => Ok(Box::new(RocksDbBlobStore { db })),
+        _ => external_store(backend),
+    }
+}
+
+fn build_pointer(
+    backend: BlobBackend,
+) -> Vec<u8> {
+    [POINTER_MAGIC, backend.code().as_bytes()].concat()
+}
+
+/// Where the blob whose database value is `value` is kept: RocksDB unless
+/// the value points somewhere else.
+pub fn blob_location(
+    value: &[u8],
+) -> Result<BlobBackend, String> {
+    if !value.starts_with(POINTER_MAGIC) {
+        return Ok(BlobBackend::RocksDb);
+    }
+
+    let code = String::from_utf8_lossy(&value[POINTER_MAGIC.len()..]);
+
+    BlobBackend::parse(&code).ok_or(format!("points at an unknown blob store {}", code))
+}
+
+/// The blob as stored, for the database value of `key`: fetched from the
+/// store it points at, or the value itself.
+pub fn fetch_stored(
+    key: &str,
+    value: Vec<u8>,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    match blob_location(&value)? {
+        BlobBackend::RocksDb => Ok(value),
+        backend =>
+            external_store(backend)?
+                .get(key)?
+                .ok_or_else(|| format!("{} is missing from the {} blob store", key, backend.code()).into()),
+    }
+}
+
+/// The content of the blob with the database value `value`, fetched and
+/// decrypted.
+pub fn resolve_blob(
+    key: &str,
+    value: Vec<u8>,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    Ok(open_blob(key, fetch_stored(key, value)?).map_err(|err| format!("{}: {}", key, err))?)
+}
+
+/// `resolve_blob` on the blocking pool, for async code that read `value`
+/// under the database lock and let go of it before fetching the blob.
+pub async fn resolve_blob_async(
+    key: String,
+    value: Vec<u8>,
+) -> Result<Vec<u8>, String> {
+    tokio::task::spawn_blocking(move || resolve_blob(&key, value).map_err(|err| err.to_string()))
+        .await
+        .map_err(|err| err.to_string())?
+}
+
+/// `db.get` for a `file:*` blob, wherever it's kept, decrypted.
+pub fn get_blob(
+    db: &impl ReadStore,
+    key: &str,
+) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+    match db.get(key)? {
+        Some(value) => Ok(Some(resolve_blob(key, value)?)),
+        None => Ok(None),
+    }
+}
+
+/// Whether the blob with the database value `value` is in the store it
+/// points at, asked on the blocking pool. Inline blobs are the value.
+pub async fn blob_exists(
+    key: String,
+    value: &[u8],
+) -> Result<bool, String> {
+    let backend = blob_location(value)?;
+
+    if backend == BlobBackend::RocksDb {
+        return Ok(true);
+    }
+
+    tokio::task::spawn_blocking(move || external_store(backend).and_then(|store| store.exists(&key)).map_err(|err| err.to_string()))
+        .await
+        .map_err(|err| err.to_string())?
+}
+
+pub type BlobStream = Pin<Box<dyn Stream<Item=Result<Vec<u8>, io::Error>> + Send>>;
+
+// fills `chunk` from `reader` as far as it goes, short only at the end
+fn read_chunk(
+    reader: &mut dyn Read,
+    chunk: &mut Vec<u8>,
+) -> io::Result<()> {
+    chunk.clear();
+
+    reader.take(STREAM_CHUNK_SIZE as u64).read_to_end(chunk)?;
+
+    Ok(())
+}
+
+/// The content of the blob of `key` kept in `backend`, read off the store a
+/// chunk at a time on the blocking pool. Encrypted blobs only open as a
+/// whole, those go out as one chunk.
+pub async fn stream_blob(
+    backend: BlobBackend,
+    key: String,
+) -> Result<Option<BlobStream>, String> {
+    let opened =
+        tokio::task::spawn_blocking(move || -> Result<Option<(Vec<u8>, Option<Box<dyn Read + Send>>)>, String> {
+            let mut reader =
+                match external_store(backend).map_err(|err| err.to_string())?.get_stream(&key).map_err(|err| err.to_string())? {
+                    Some(reader) => reader,
+                    None => return Ok(None),
+                };
+
+            let mut first = Vec::new();
+
+            read_chunk(&mut reader, &mut first).map_err(|err| err.to_string())?;
+
+            if !is_encrypted_blob(&first) {
+                return Ok(Some((first, Some(reader))));
+            }
+
+            reader.read_to_end(&mut first).map_err(|err| err.to_string())?;
+
+            Ok(Some((open_blob(&key, first).map_err(|err| format!("{}: {}", key, err))?, None)))
+        })
+            .await
+            .map_err(|err| err.to_string())??;
+
+    let (first, reader) =
+        match opened {
+            Some(opened) => opened,
+            None => return Ok(None),
+        };
+
+    let rest =
+        stream::try_unfold(reader, |reader| async move {
+            let mut reader =
+                match reader {
+                    Some(reader) => reader,
+                    None => return Ok(None),
+                };
+
+            let (chunk, reader) =
+                tokio::task::spawn_blocking(move || {
+                    let mut chunk = Vec::with_capacity(STREAM_CHUNK_SIZE);
+
+                    read_chunk(&mut reader, &mut chunk).map(|_| (chunk, reader))
+                })
+                    .await
+                    .map_err(|err| io::Error::new(io::ErrorKind::Other, err))??;
+
+            match chunk.is_empty() {
+                true => Ok(None),
+                false => Ok(Some((chunk, Some(reader)))),
+            }
+        });
+
+    Ok(Some(Box::pin(stream::once(future::ready(Ok(first))).chain(rest))))
+}
+
+/// Stores `blob` in `backend` and returns what goes under `key` in the
+/// database: the blob itself for RocksDB, a pointer for the others.
+pub fn place_blob_in(
+    backend: BlobBackend,
+    key: &str,
+    blob: &[u8],
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    #[cfg(test)]
+    if FAILING_BLOB_KEYS.with(|keys| keys.borrow().iter().any(|failing| failing == key)) {
+        return Err(format!("can't write {}", key).into());
+    }
+
+    let sealed = seal_blob(key, blob);
+
+    match backend {
+        BlobBackend::RocksDb => Ok(sealed.into_owned()),
+        _ => {
+            external_store(backend)?.put(key, &sealed)?;
+
+            Ok(build_pointer(backend))
+        }
+    }
+}
+
+/// `place_blob_in` the configured store, see `get_blob_backend`.
+pub fn place_blob(
+    key: &str,
+    blob: &[u8],
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    place_blob_in(get_blob_backend(), key, blob)
+}
+
+/// `delete_counted` for a blob key. Returns the store that still keeps the
+/// blob when that isn't the database, for `delete_external_blob` once the
+/// lock on `db` is let go of.
+pub fn delete_blob_pointer(
+    db: &DBWithThreadMode<MultiThreaded>,
+    key: &str,
+    counter_keys: &[String],
+) -> Result<Option<BlobBackend>, Box<dyn std::error::Error>> {
+    let backend =
+        match db.get(key)? {
+            Some(value) => Some(blob_location(&value)?).filter(|backend| *backend != BlobBackend::RocksDb),
+            None => None,
+        };
+
+    delete_counted(db, key, counter_keys)?;
+
+    Ok(backend)
+}
+
+/// Deletes the blob of `key` from `backend`, the second half of
+/// `delete_blob_pointer`.
+pub fn delete_external_blob(
+    backend: BlobBackend,
+    key: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    external_store(backend)?.delete(key)
+}
+
+/// `delete_counted` for a blob key, along with the blob in the store the
+/// key points at. The pointer goes first: interrupted in between, what's
+/// left is an object nothing points at rather than a key pointing at
+/// nothing.
+pub fn delete_blob(
+    db: &DBWithThreadMode<MultiThreaded>,
+    key: &str,
+    counter_keys: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    match delete_blob_pointer(db, key, counter_keys)? {
+        Some(backend) => delete_external_blob(backend, key),
+        None => Ok(()),
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MigrateSummary {
+    pub moved: usize,
+    // already in the target store
+    pub in_place: usize,
+    // missing from their store or not moved for another reason, left alone
+    pub failed: usize,
+}
+
+// copies one blob over, points the key at the copy, then removes the old
+// one. Interrupted anywhere, the key still points at a complete blob
+fn move_blob(
+    db: &DBWithThreadMode<MultiThreaded>,
+    key: &str,
+    from: BlobBackend,
+    to: BlobBackend,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let source = blob_store(db, from)?;
+
+    let mut reader =
+        source
+            .get_stream(key)?
+            .ok_or_else(|| format!("missing from the {} blob store", from.code()))?;
+
+    match to {
+        BlobBackend::RocksDb => {
+            let mut blob = Vec::new();
+
+            reader.read_to_end(&mut blob)?;
+
+            db.put(key, blob)?;
+        }
+        _ => {
+            external_store(to)?.put_stream(key, &mut reader)?;
+
+            db.put(key, build_pointer(to))?;
+        }
+    }
+
+    if from != BlobBackend::RocksDb {
+        source.delete(key)?;
+    }
+
+    Ok(())
+}
+
+/// Moves every blob to `to`, one at a time. Keys point at wherever their
+/// blob is while this runs, so files keep being served and a stopped
+/// migration picks up where it was. The storage counters see pointers in
+/// place of moved blobs and want a rebuild afterwards. With `dry_run`
+/// nothing moves, the summary tells what would.
+pub fn migrate_blobs(
+    db: &DBWithThreadMode<MultiThreaded>,
+    to: BlobBackend,
+    dry_run: bool,
+) -> Result<MigrateSummary, Box<dyn std::error::Error>> {
+    if to != BlobBackend::RocksDb {
+        external_store(to)?;
+    }
+
+    let mut summary = MigrateSummary::default();
+
+    for kind in FILE_KINDS.iter() {
+        for (key, value) in prefix_iter(db, &format!("file:{}:", kind)) {
+            let key = String::from_utf8_lossy(&key).to_string();
+
+            let from =
+                match blob_location(&value) {
+                    Ok(from) => from,
+                    Err(err) => {
+                        eprintln!("{}: {}", key, err);
+
+                        summary.failed += 1;
+
+                        continue;
+                    }
+                };
+
+            if from == to {
+                summary.in_place += 1;
+
+                continue;
+            }
+
+            if dry_run {
+                summary.moved += 1;
+
+                continue;
+            }
+
+            match move_blob(db, &key, from, to) {
+                Ok(()) => summary.moved += 1,
+                Err(err) => {
+                    eprintln!("{}: {}", key, err);
+
+                    summary.failed += 1;
+                }
+            }
+        }
+    }
+
+    Ok(summary)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    use warp::Filter;
+
+    use super::*;
+
+    fn open_db(name: &str) -> DBWithThreadMode<MultiThreaded> {
+        let path =
+            std::env::temp_dir()
+                .join(format!("minuteman-test-{}-{}", name, std::process::id()));
+
+        let _ = fs::remove_dir_all(&path);
+
+        DBWithThreadMode::<MultiThreaded>::open_default(path).unwrap()
+    }
+
+    #[test]
+    fn blob_keys_split_into_kind_and_file_id() {
+        assert_eq!(split_blob_key("file:chat:AgADBAAD"), Some(("chat", "AgADBAAD")));
+        assert_eq!(split_blob_key("file:video_thumb:a:b"), Some(("video_thumb", "a:b")));
+
+        for key in [
+            "file:meta:AgADBAAD",
+            "file:chat:",
+            "file:chat:../../etc/passwd",
+            "file:chat:.hidden",
+            "file:chat:a\\b",
+            "chat:-1001:2020-09-13",
+            "file:chat",
+        ] {
+            assert_eq!(split_blob_key(key), None, "{}", key);
+        }
+    }
+
+    #[test]
+    fn fs_store_gives_back_what_it_was_given() {
+        let store = FsBlobStore { dir: use_test_blob_dir() };
+
+        let key = "file:chat:fs-round-trip";
+
+        assert_eq!(store.get(key).unwrap(), None);
+        assert!(!store.exists(key).unwrap());
+
+        store.put(key, b"first").unwrap();
+        store.put(key, b"second, longer").unwrap();
+
+        assert!(store.exists(key).unwrap());
+        assert_eq!(store.get(key).unwrap().as_deref(), Some(&b"second, longer"[..]));
+
+        let mut streamed = Vec::new();
+
+        store.get_stream(key).unwrap().unwrap().read_to_end(&mut streamed).unwrap();
+
+        assert_eq!(streamed, b"second, longer");
+
+        store.delete(key).unwrap();
+        store.delete(key).unwrap();
+
+        assert!(!store.exists(key).unwrap());
+        assert!(store.put("file:meta:fs-round-trip", b"meta").is_err());
+    }
+
+    #[test]
+    fn migrated_blobs_move_both_ways_and_stay_readable() {
+        let dir = use_test_blob_dir();
+
+        let db = open_db("migrate-blobs");
+
+        let blobs = [("file:chat:migrate-a", b"a".to_vec()), ("file:user:migrate-b", vec![7u8; 300_000])];
+
+        for (key, blob) in blobs.iter() {
+            db.put(key, place_blob_in(BlobBackend::RocksDb, key, blob).unwrap()).unwrap();
+        }
+
+        let summary = migrate_blobs(&db, BlobBackend::Fs, true).unwrap();
+
+        assert_eq!((summary.moved, summary.in_place, summary.failed), (2, 0, 0));
+        assert_eq!(blob_location(&db.get("file:chat:migrate-a").unwrap().unwrap()), Ok(BlobBackend::RocksDb));
+
+        let summary = migrate_blobs(&db, BlobBackend::Fs, false).unwrap();
+
+        assert_eq!((summary.moved, summary.in_place, summary.failed), (2, 0, 0));
+
+        for (key, blob) in blobs.iter() {
+            assert_eq!(blob_location(&db.get(key).unwrap().unwrap()), Ok(BlobBackend::Fs), "{}", key);
+            assert!(FsBlobStore { dir: dir.clone() }.path(key).unwrap().is_file(), "{}", key);
+            assert_eq!(get_blob(&db, key).unwrap().as_ref(), Some(blob), "{}", key);
+        }
+
+        let summary = migrate_blobs(&db, BlobBackend::Fs, false).unwrap();
+
+        assert_eq!((summary.moved, summary.in_place, summary.failed), (0, 2, 0));
+
+        let summary = migrate_blobs(&db, BlobBackend::RocksDb, false).unwrap();
+
+        assert_eq!((summary.moved, summary.in_place, summary.failed), (2, 0, 0));
+
+        for (key, blob) in blobs.iter() {
+            assert_eq!(blob_location(&db.get(key).unwrap().unwrap()), Ok(BlobBackend::RocksDb), "{}", key);
+            assert!(!FsBlobStore { dir: dir.clone() }.path(key).unwrap().exists(), "{}", key);
+            assert_eq!(get_blob(&db, key).unwrap().as_ref(), Some(blob), "{}", key);
+        }
+    }
+
+    #[test]
+    fn deleting_a_blob_deletes_the_pointer_and_the_external_copy() {
+        let dir = use_test_blob_dir();
+
+        let db = open_db("delete-blob");
+
+        let key = "file:chat:delete-external";
+
+        db.put(key, place_blob_in(BlobBackend::Fs, key, b"blob").unwrap()).unwrap();
+
+        delete_blob(&db, key, &[]).unwrap();
+
+        assert_eq!(db.get(key).unwrap(), None);
+        assert!(!FsBlobStore { dir }.path(key).unwrap().exists());
+    }
+
+    // objects of the fake s3 service by request path
+    static S3_OBJECTS: Lazy<Mutex<HashMap<String, Vec<u8>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+    // a fake s3 service under /storage, on a thread of its own
+    static FAKE_S3: Lazy<String> =
+        Lazy::new(|| {
+            let (sender, receiver) = std::sync::mpsc::channel();
+
+            std::thread::spawn(move || {
+                let runtime =
+                    tokio::runtime::Builder::new_current_thread()
+                        .enable_all()
+                        .build()
+                        .unwrap();
+
+                runtime.block_on(async {
+                    let routes =
+                        warp::method()
+                            .and(warp::path::full())
+                            .and(warp::body::bytes())
+                            .map(|method: Method, path: warp::path::FullPath, body: warp::hyper::body::Bytes| {
+                                let mut objects = S3_OBJECTS.lock().unwrap();
+
+                                let path = path.as_str().to_string();
+
+                                let (status, body) =
+                                    match (method, objects.get(&path)) {
+                                        (_, _) if !path.starts_with("/storage/") => (StatusCode::FORBIDDEN, Vec::new()),
+                                        (Method::PUT, _) => {
+                                            objects.insert(path, body.to_vec());
+
+                                            (StatusCode::OK, Vec::new())
+                                        }
+                                        (Method::DELETE, _) => {
+                                            objects.remove(&path);
+
+                                            (StatusCode::NO_CONTENT, Vec::new())
+                                        }
+                                        (_, Some(object)) => (StatusCode::OK, object.clone()),
+                                        (_, None) => (StatusCode::NOT_FOUND, Vec::new()),
+                                    };
+
+                                warp::reply::with_status(body, status)
+                            });
+
+                    let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+
+                    sender.send(addr).unwrap();
+
+                    server.await;
+                });
+            });
+
+            format!("http://{}/storage", receiver.recv().unwrap())
+        });
+
+    // called from a current thread runtime, where waiting on the blocking
+    // client used to panic
+    #[tokio::test]
+    async fn s3_store_keeps_the_endpoint_path_and_works_from_any_runtime() {
+        let store =
+            S3BlobStore {
+                config:
+                S3Config {
+                    endpoint: FAKE_S3.clone(),
+                    bucket: "archive".to_string(),
+                    region: "us-east-1".to_string(),
+                    access_key: "access".to_string(),
+                    secret_key: "secret".to_string(),
+                },
+            };
+
+        let key = "file:chat:s3-round-trip";
+
+        assert_eq!(store.get(key).unwrap(), None);
+
+        store.put(key, b"object").unwrap();
+
+        assert!(S3_OBJECTS.lock().unwrap().contains_key("/storage/archive/chat/s3-round-trip"));
+        assert!(store.exists(key).unwrap());
+        assert_eq!(store.get(key).unwrap().as_deref(), Some(&b"object"[..]));
+
+        store.delete(key).unwrap();
+
+        assert!(!store.exists(key).unwrap());
+    }
+}
@@ -0,0 +1,750 @@
+use std::{env, fs, process};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use chrono::{NaiveDate, NaiveDateTime};
+use rocksdb::{DBWithThreadMode, MultiThreaded};
+use serde_json::Value;
+use tokio::runtime::Runtime;
+
+use crate::VACUUM_MIN_FILE_AGE;
+use crate::blob_store::migrate_blobs;
+use crate::encryption::rotate_blobs;
+use crate::config::{BlobBackend, get_backup_dir, get_backup_keep, get_bots, get_default_lang, get_search_index, ListingOrder};
+use crate::privacy::Viewer;
+use crate::renderer::chat_index::chat_days_page;
+use crate::renderer::chat_listing::{ListingFormat, ListingOptions, ListingQuery, load_day_listing, render_day_json, render_day_txt};
+use crate::search_index::rebuild_search_index;
+use crate::storage::{lock_db, ReadStore, Storage};
+use crate::storage_stats::{prefix_iter, rebuild_storage_stats};
+use crate::utils::{format_chat_day, is_archived_chat, purge_user_messages, resolve_chat_name};
+use crate::workers::chat_policy::load_chat_policies;
+use crate::workers::backup_handler::{create_backup, list_backups, restore_backup};
+use crate::workers::file_retry::retry_files;
+use crate::workers::file_verifier::{verify_files_batch, VERIFY_FILES_BATCH_SIZE, VERIFY_FILES_PROGRESS_KEY};
+use crate::workers::ignore_list::find_ignored_user_ids;
+use crate::workers::ingest_errors::find_ingest_errors;
+use crate::workers::panics::find_panics;
+use crate::workers::reprocess::reprocess_chat;
+use crate::workers::telegram_handler::Bot;
+use crate::workers::user_meta_handler::{backfill_user_meta, USER_META_PROGRESS_KEY};
+use crate::workers::vacuum::vacuum_files;
+
+const USAGE: &str = "\
+usage:
+    minuteman                  run the bot and the web server
+    minuteman reprocess --chat <id> [--from <date|ts>] [--to <date|ts>] [--dry-run]
+    minuteman backup create [--dir <path>]
+    minuteman backup list [--dir <path>]
+    minuteman backup restore --to <path> [--id <backup id>] [--dir <path>]
+    minuteman stats --rebuild
+    minuteman encryption rotate
+    minuteman blobs migrate --to <rocksdb|fs|s3> [--dry-run]
+    minuteman vacuum-files [--min-age <seconds>] [--dry-run]
+    minuteman verify-files [--restart]
+    minuteman retry-files [--now]
+    minuteman backfill-user-meta [--restart]
+    minuteman purge-ignored [--dry-run]
+    minuteman index-rebuild [--chat <id>]
+    minuteman errors [--tail <count>] [--panics] [--db <path>]
+    minuteman dump --chat <id> --date <YYYY-MM-DD> [--format txt|json|ndjson] [--order asc|desc] [--resolve] [--db <path>]
+    minuteman chats [--db <path>]
+
+backups go to MINUTEMAN_BACKUP_DIR unless --dir is given. errors, dump and
+chats open the database as a secondary instance, they work while the bot is
+running and see what it stored up to the moment they started";
+
+// subcommands that only read, see `run_read_only`
+const READ_ONLY_COMMANDS: [&str; 3] = ["errors", "dump", "chats"];
+
+fn flag_value(
+    args: &[String],
+    flag: &str,
+) -> Option<String> {
+    args.iter()
+        .position(|arg| arg == flag)
+        .map(|i| args.get(i + 1).cloned())
+        .flatten()
+}
+
+// either a unix timestamp or a YYYY-MM-DD day, which is taken as its first
+// or last second depending on which end of the range it is
+fn parse_time_bound(
+    value: &str,
+    end_of_day: bool,
+) -> Option<i64> {
+    if let Ok(timestamp) = value.parse::<i64>() {
+        return Some(timestamp);
+    }
+
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()?;
+
+    Some(
+        match end_of_day {
+            true => date.and_hms(23, 59, 59).timestamp(),
+            false => date.and_hms(0, 0, 0).timestamp(),
+        }
+    )
+}
+
+fn reprocess(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    args: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let chat_id =
+        flag_value(args, "--chat")
+            .ok_or(USAGE)?;
+
+    let from =
+        match flag_value(args, "--from") {
+            Some(from) => Some(parse_time_bound(&from, false).ok_or("invalid --from")?),
+            None => None,
+        };
+
+    let to =
+        match flag_value(args, "--to") {
+            Some(to) => Some(parse_time_bound(&to, true).ok_or("invalid --to")?),
+            None => None,
+        };
+
+    let dry_run = args.iter().any(|arg| arg == "--dry-run");
+
+    let summary =
+        Runtime::new()?
+            .block_on(
+                reprocess_chat(
+                    db,
+                    &chat_id,
+                    from,
+                    to,
+                    dry_run,
+                ),
+            )?;
+
+    println!(
+        "reprocessed chat {}{}: {} raw messages, {} rewritten, {} unchanged, {} skipped",
+        &chat_id,
+        if dry_run { " (dry run)" } else { "" },
+        summary.scanned,
+        summary.rewritten,
+        summary.unchanged,
+        summary.skipped,
+    );
+
+    for ((previous, current), count) in summary.changes.iter() {
+        println!("    {} -> {}: {}", previous, current, count);
+    }
+
+    Ok(())
+}
+
+fn backup(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    args: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let backup_dir =
+        flag_value(args, "--dir")
+            .or(get_backup_dir())
+            .ok_or(USAGE)?;
+
+    match args.first().map(|arg| arg.as_str()) {
+        Some("create") => {
+            let info =
+                create_backup(
+                    db,
+                    &backup_dir,
+                    get_backup_keep(),
+                )?;
+
+            println!(
+                "created backup {} ({} bytes, {} files)",
+                info.backup_id,
+                info.size,
+                info.num_files,
+            );
+        }
+        Some("list") => {
+            for info in list_backups(&backup_dir)?.iter() {
+                let time =
+                    NaiveDateTime::from_timestamp_opt(info.timestamp, 0)
+                        .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+                        .unwrap_or_default();
+
+                println!(
+                    "{}\t{}\t{} bytes\t{} files",
+                    info.backup_id,
+                    time,
+                    info.size,
+                    info.num_files,
+                );
+            }
+        }
+        Some("restore") => {
+            let to =
+                flag_value(args, "--to")
+                    .ok_or(USAGE)?;
+
+            // the running database is open right here, restoring over it
+            // would corrupt it
+            if Path::new(&to) == Path::new("db") {
+                return Err("refusing to restore over the live database".into());
+            }
+
+            let backup_id =
+                match flag_value(args, "--id") {
+                    Some(backup_id) => Some(backup_id.parse::<u32>()?),
+                    None => None,
+                };
+
+            restore_backup(
+                &backup_dir,
+                &to,
+                backup_id,
+            )?;
+
+            println!("restored backup into {}", &to);
+        }
+        _ => return Err(USAGE.into()),
+    }
+
+    Ok(())
+}
+
+fn stats(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    args: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !args.iter().any(|arg| arg == "--rebuild") {
+        return Err(USAGE.into());
+    }
+
+    let counters = rebuild_storage_stats(&lock_db(&db))?;
+
+    println!("rebuilt {} storage counters", counters);
+
+    Ok(())
+}
+
+fn encryption(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    args: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    if args.first().map(|arg| arg.as_str()) != Some("rotate") {
+        return Err(USAGE.into());
+    }
+
+    let db = lock_db(&db);
+
+    let summary = rotate_blobs(&db)?;
+
+    // the header makes every encrypted blob a little larger
+    rebuild_storage_stats(&db)?;
+
+    println!(
+        "{} files encrypted with the current key, {} already were, {} encrypted with an unknown key",
+        summary.rotated,
+        summary.current,
+        summary.unreadable,
+    );
+
+    Ok(())
+}
+
+fn blobs(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    args: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    if args.first().map(|arg| arg.as_str()) != Some("migrate") {
+        return Err(USAGE.into());
+    }
+
+    let to =
+        flag_value(args, "--to")
+            .map(|to| BlobBackend::parse(&to))
+            .flatten()
+            .ok_or(USAGE)?;
+
+    let dry_run = args.iter().any(|arg| arg == "--dry-run");
+
+    let db = lock_db(&db);
+
+    let summary = migrate_blobs(&db, to, dry_run)?;
+
+    // moved blobs are counted as their pointers and the other way around
+    if !dry_run {
+        rebuild_storage_stats(&db)?;
+    }
+
+    println!(
+        "{} files {} to {}, {} already there, {} failed",
+        summary.moved,
+        if dry_run { "would move" } else { "moved" },
+        to.code(),
+        summary.in_place,
+        summary.failed,
+    );
+
+    Ok(())
+}
+
+fn vacuum(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    args: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let min_age =
+        match flag_value(args, "--min-age") {
+            Some(min_age) => min_age.parse::<i64>()?,
+            None => VACUUM_MIN_FILE_AGE,
+        };
+
+    let dry_run = args.iter().any(|arg| arg == "--dry-run");
+
+    let summary =
+        vacuum_files(
+            &lock_db(&db),
+            min_age,
+            dry_run,
+        )?;
+
+    println!(
+        "{} file references, {} blobs kept, {} unreferenced but too recent, {} {} ({} bytes)",
+        summary.referenced,
+        summary.kept,
+        summary.too_recent,
+        summary.deleted,
+        if dry_run { "would be deleted" } else { "deleted" },
+        summary.reclaimed_bytes,
+    );
+
+    Ok(())
+}
+
+fn verify(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    args: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let dbi = lock_db(&db);
+
+    if args.iter().any(|arg| arg == "--restart") {
+        dbi.delete(VERIFY_FILES_PROGRESS_KEY)?;
+    }
+
+    let mut corrupt_files = 0usize;
+
+    loop {
+        let (corrupt, done) = verify_files_batch(&dbi, VERIFY_FILES_BATCH_SIZE)?;
+
+        for corrupt in corrupt.iter() {
+            println!(
+                "file:{}:{}\t{}",
+                corrupt.kind,
+                corrupt.file_id,
+                corrupt.reason,
+            );
+        }
+
+        corrupt_files += corrupt.len();
+
+        if done {
+            break;
+        }
+    }
+
+    println!("{} corrupt files", corrupt_files);
+
+    Ok(())
+}
+
+fn retry(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    args: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    // without --now only the files that are due are tried, like the
+    // background task would
+    let force = args.iter().any(|arg| arg == "--now");
+
+    let bots =
+        get_bots()
+            .map_err(|err| format!("{:?}", err))?
+            .iter()
+            .map(Bot::new)
+            .collect::<Vec<Bot>>();
+
+    let summary =
+        Runtime::new()?
+            .block_on(
+                retry_files(
+                    db,
+                    &bots,
+                    force,
+                ),
+            )?;
+
+    println!(
+        "{} files tried, {} stored, {} given up, {} still queued",
+        summary.attempted,
+        summary.stored,
+        summary.given_up,
+        summary.queued,
+    );
+
+    Ok(())
+}
+
+fn backfill(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    args: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    // scans the whole log again instead of picking up where it stopped
+    if args.iter().any(|arg| arg == "--restart") {
+        lock_db(&db).delete(USER_META_PROGRESS_KEY)?;
+    }
+
+    let bots =
+        get_bots()
+            .map_err(|err| format!("{:?}", err))?
+            .iter()
+            .map(Bot::new)
+            .collect::<Vec<Bot>>();
+
+    Runtime::new()?
+        .block_on(
+            backfill_user_meta(
+                db,
+                &bots,
+            ),
+        )?;
+
+    println!("user metadata backfill done");
+
+    Ok(())
+}
+
+fn purge_ignored(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    args: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let dry_run = args.iter().any(|arg| arg == "--dry-run");
+
+    let dbi = lock_db(&db);
+
+    let user_ids = find_ignored_user_ids(&dbi);
+
+    let purged =
+        purge_user_messages(
+            &dbi,
+            &user_ids,
+            dry_run,
+        )?;
+
+    println!(
+        "{} messages of {} ignored user(s) {}",
+        purged,
+        user_ids.len(),
+        if dry_run { "would be deleted" } else { "deleted" },
+    );
+
+    Ok(())
+}
+
+fn index_rebuild(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    args: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let chat_id = flag_value(args, "--chat");
+
+    let indexed =
+        rebuild_search_index(
+            &lock_db(&db),
+            chat_id.as_deref(),
+        )?;
+
+    println!(
+        "indexed {} messages{}",
+        indexed,
+        chat_id
+            .map(|chat_id| format!(" of chat {}", chat_id))
+            .unwrap_or_default(),
+    );
+
+    if !get_search_index() {
+        println!("MINUTEMAN_SEARCH_INDEX is off, new messages won't be indexed and searches keep scanning");
+    }
+
+    Ok(())
+}
+
+fn errors(
+    db: &impl ReadStore,
+    args: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let tail =
+        match flag_value(args, "--tail") {
+            Some(tail) => tail.parse::<usize>()?,
+            None => 50,
+        };
+
+    if args.iter().any(|arg| arg == "--panics") {
+        let mut records = find_panics(db, tail);
+
+        records.reverse();
+
+        for record in records.iter() {
+            let time =
+                NaiveDateTime::from_timestamp_opt(record.time, 0)
+                    .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+                    .unwrap_or_default();
+
+            println!(
+                "{}\tthread {}\tupdate {}\t{}\t{}\t{} at {}\n{}",
+                time,
+                record.thread,
+                record.context.update_id.map(|update_id| update_id.to_string()).unwrap_or("-".to_string()),
+                record.context.chat_id.as_deref().unwrap_or("-"),
+                record.context.message_id.as_deref().unwrap_or("-"),
+                record.message,
+                record.location.as_deref().unwrap_or("?"),
+                record.backtrace,
+            );
+        }
+
+        return Ok(());
+    }
+
+    let mut samples = find_ingest_errors(db, tail);
+
+    // oldest first, like a log
+    samples.reverse();
+
+    for sample in samples.iter() {
+        let time =
+            NaiveDateTime::from_timestamp_opt(sample.time, 0)
+                .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+                .unwrap_or_default();
+
+        println!(
+            "{}\t{}\t{}\t{}\t{}",
+            time,
+            sample.stage,
+            sample.chat_id.as_deref().unwrap_or("-"),
+            sample.message_id.as_deref().unwrap_or("-"),
+            sample.error,
+        );
+    }
+
+    Ok(())
+}
+
+fn dump(
+    db: &impl ReadStore,
+    args: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let chat_id =
+        flag_value(args, "--chat")
+            .ok_or(USAGE)?;
+
+    let date =
+        flag_value(args, "--date")
+            .map(|date| NaiveDate::parse_from_str(&date, "%Y-%m-%d").ok())
+            .flatten()
+            .ok_or("--date has to be a YYYY-MM-DD day")?;
+
+    let ndjson = flag_value(args, "--format").as_deref() == Some("ndjson");
+
+    let format =
+        match flag_value(args, "--format").as_deref() {
+            None | Some("txt") => ListingFormat::Txt,
+            Some("json") | Some("ndjson") => ListingFormat::Json,
+            Some(_) => return Err(USAGE.into()),
+        };
+
+    let order =
+        match flag_value(args, "--order") {
+            Some(order) => Some(ListingOrder::parse(&order).ok_or(USAGE)?),
+            None => None,
+        };
+
+    if !is_archived_chat(db, &chat_id) {
+        return Err(format!("chat {} isn't archived", chat_id).into());
+    }
+
+    let day = date.and_hms(0, 0, 0).timestamp() / 86_400;
+
+    if db.get(format!("chat_index:{}:{}", chat_id, day))?.is_none() {
+        return Err(format!("chat {} has nothing on {}", chat_id, date).into());
+    }
+
+    // whoever can open the database sees everything in it anyway
+    let viewer =
+        Viewer {
+            admin: true,
+            share: None,
+        };
+
+    let mut query =
+        ListingQuery {
+            limit: Some(usize::MAX),
+            order: order.map(|order| order.code().to_string()),
+            resolve: Some(1).filter(|_| args.iter().any(|arg| arg == "--resolve")),
+            ..ListingQuery::default()
+        };
+
+    let options = ListingOptions::new(&query, &viewer, get_default_lang(), false, None);
+
+    // pages are capped however large the limit, the day is walked through
+    // them the way the web listing's "next" links would
+    let mut items = Vec::<Value>::new();
+    let mut out = None::<Value>;
+
+    loop {
+        let listing = load_day_listing(db, &chat_id, date, format, &query, &viewer, false);
+
+        let (cursor, after) =
+            match listing.order {
+                ListingOrder::Desc => (listing.page.older_cursor(), None),
+                ListingOrder::Asc => (None, listing.page.newer_cursor()),
+            };
+
+        match format {
+            ListingFormat::Json => {
+                let mut page = render_day_json(&listing, &options);
+
+                if let Value::Array(ref mut data) = page["data"] {
+                    items.append(data);
+                }
+
+                out.get_or_insert(page);
+            }
+            _ => {
+                let txt = render_day_txt(&listing, &options);
+
+                if !txt.is_empty() {
+                    println!("{}", txt);
+                }
+            }
+        }
+
+        if cursor.is_none() && after.is_none() {
+            break;
+        }
+
+        query.cursor = cursor;
+        query.after = after;
+    }
+
+    match (out, ndjson) {
+        (Some(_), true) => {
+            for item in items.iter() {
+                println!("{}", serde_json::to_string(item)?);
+            }
+        }
+        (Some(mut out), false) => {
+            out["data"] = Value::Array(items);
+            out["next"] = Value::Null;
+
+            println!("{}", serde_json::to_string_pretty(&out)?);
+        }
+        (None, _) => {}
+    }
+
+    Ok(())
+}
+
+fn chats(
+    db: &impl ReadStore,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for (key, _) in prefix_iter(db, "chat_rel:") {
+        let key = String::from_utf8(key.to_vec())?;
+        let chat_id = key.trim_start_matches("chat_rel:");
+
+        let latest_day =
+            chat_days_page(db, chat_id, &None, 1).0
+                .first()
+                .copied()
+                .map(format_chat_day)
+                .flatten()
+                .unwrap_or("-".to_string());
+
+        println!(
+            "{}\t{}\t{}",
+            chat_id,
+            resolve_chat_name(db, chat_id),
+            latest_day,
+        );
+    }
+
+    Ok(())
+}
+
+/// Whether `run_read_only` is the one to hand `args` to, before the
+/// database is opened for writing.
+pub fn is_read_only_command(
+    args: &[String],
+) -> bool {
+    args.first()
+        .map(|command| READ_ONLY_COMMANDS.contains(&command.as_str()))
+        .unwrap_or(false)
+}
+
+/// Runs one of the subcommands that only read against a secondary instance
+/// of the database at `--db` (`./db` by default), see `open_secondary`. A
+/// running bot keeps writing, the command sees what it stored up to the
+/// moment the secondary was opened.
+pub fn run_read_only(
+    args: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let path = flag_value(args, "--db").unwrap_or("db".to_string());
+
+    // one per process, two commands running at once can't share it
+    let secondary_path =
+        env::temp_dir()
+            .join(format!("minuteman-secondary-{}", process::id()))
+            .to_string_lossy()
+            .to_string();
+
+    let db =
+        DBWithThreadMode::<MultiThreaded>::open_secondary(&path, &secondary_path)
+            .map_err(|err| format!("can't open the database at {}: {}", path, err))?;
+
+    load_chat_policies(&db);
+
+    let view = db.read_view();
+
+    let result =
+        match args.first().map(|arg| arg.as_str()) {
+            Some("errors") => errors(&view, &args[1..]),
+            Some("dump") => dump(&view, &args[1..]),
+            Some("chats") => chats(&view),
+            _ => Err(USAGE.into()),
+        };
+
+    drop(view);
+    drop(db);
+
+    // only the info log of the secondary is in there
+    let _ = fs::remove_dir_all(&secondary_path);
+
+    result
+}
+
+/// Runs the subcommand named by `args` (without the program name) against
+/// the database and returns once it's done.
+pub fn run(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    args: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    match args.first().map(|arg| arg.as_str()) {
+        Some("reprocess") => reprocess(db, &args[1..]),
+        Some("backup") => backup(db, &args[1..]),
+        Some("stats") => stats(db, &args[1..]),
+        Some("encryption") => encryption(db, &args[1..]),
+        Some("blobs") => blobs(db, &args[1..]),
+        Some("vacuum-files") => vacuum(db, &args[1..]),
+        Some("verify-files") => verify(db, &args[1..]),
+        Some("retry-files") => retry(db, &args[1..]),
+        Some("backfill-user-meta") => backfill(db, &args[1..]),
+        Some("purge-ignored") => purge_ignored(db, &args[1..]),
+        Some("index-rebuild") => index_rebuild(db, &args[1..]),
+        _ => Err(USAGE.into()),
+    }
+}
@@ -0,0 +1,12 @@
+use crate::utils::escape_html;
+
+/// Small photo of a chat for lists and headers. Chats without a stored photo
+/// get a placeholder from `/file/chat_photo` rather than a broken image.
+pub fn chat_avatar(
+    chat_id: &str,
+) -> String {
+    format!(
+        "<img class=\"chat-avatar\" src=\"/file/chat_photo/{}\" alt=\"\" width=\"20\" height=\"20\" loading=\"lazy\"/>",
+        escape_html(chat_id),
+    )
+}
@@ -0,0 +1,71 @@
+use crate::storage::ReadStore;
+use crate::utils::NameCache;
+use crate::workers::telegram_handler::LogItemChatType;
+
+/// Video chat lengths, like "1h 5m" or "42s".
+pub fn format_call_duration(
+    seconds: i64,
+) -> String {
+    let (hours, minutes, seconds) = (seconds / 3600, seconds % 3600 / 60, seconds % 60);
+
+    match (hours, minutes) {
+        (0, 0) => format!("{}s", seconds),
+        (0, _) => format!("{}m {}s", minutes, seconds),
+        _ => format!("{}h {}m", hours, minutes),
+    }
+}
+
+/// Auto-delete timers, which telegram only offers in whole days, weeks and
+/// months but bots can set to anything.
+pub fn format_timer(
+    seconds: i64,
+) -> String {
+    let unit = |count: i64, unit: &str| {
+        if count == 1 {
+            format!("1 {}", unit)
+        } else {
+            format!("{} {}s", count, unit)
+        }
+    };
+
+    match seconds {
+        s if s >= 30 * 86_400 && s % (30 * 86_400) == 0 => unit(s / (30 * 86_400), "month"),
+        s if s >= 7 * 86_400 && s % (7 * 86_400) == 0 => unit(s / (7 * 86_400), "week"),
+        s if s >= 86_400 && s % 86_400 == 0 => unit(s / 86_400, "day"),
+        s if s >= 3600 && s % 3600 == 0 => unit(s / 3600, "hour"),
+        s => unit(s, "second"),
+    }
+}
+
+/// What the acting user did to the chat, as plain text to be put after
+/// their nick.
+pub fn chat_event_text<S: ReadStore>(
+    chat_type: &LogItemChatType,
+    names: &mut NameCache<S>,
+) -> String {
+    match chat_type {
+        LogItemChatType::NewTitle { title } =>
+            format!("changed the title to \"{}\"", title),
+        LogItemChatType::NewPhoto { .. } =>
+            "changed the chat photo".to_string(),
+        LogItemChatType::DeletePhoto =>
+            "removed the chat photo".to_string(),
+        LogItemChatType::VideoChatStarted =>
+            "started a video chat".to_string(),
+        LogItemChatType::VideoChatEnded { duration } =>
+            format!("ended the video chat ({})", format_call_duration(*duration)),
+        LogItemChatType::VideoChatInviteSent { users } =>
+            format!(
+                "invited {} to the video chat",
+                users
+                    .iter()
+                    .map(|user_id| names.user(user_id, false))
+                    .collect::<Vec<String>>()
+                    .join(", "),
+            ),
+        LogItemChatType::AutoDeleteTimerChanged { seconds: 0 } =>
+            "turned off auto-delete".to_string(),
+        LogItemChatType::AutoDeleteTimerChanged { seconds } =>
+            format!("set messages to auto-delete after {}", format_timer(*seconds)),
+    }
+}
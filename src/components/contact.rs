@@ -0,0 +1,138 @@
+use crate::storage::{get_user_meta, ReadStore};
+use crate::utils::escape_html;
+use crate::workers::telegram_handler::LogItemSpecialType;
+
+/// Keeps the last two digits of a phone number, enough to tell shared
+/// contacts apart without giving the number away.
+pub fn mask_phone_number(
+    phone_number: &str,
+) -> String {
+    let digits = phone_number.chars().filter(|c| c.is_ascii_digit()).count();
+
+    let mut seen = 0;
+
+    phone_number
+        .chars()
+        .map(|c| {
+            if !c.is_ascii_digit() {
+                return c;
+            }
+
+            seen += 1;
+
+            if seen + 2 > digits { c } else { '•' }
+        })
+        .collect()
+}
+
+fn escape_vcard(
+    value: &str,
+) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace(';', "\\;")
+        .replace('\n', "\\n")
+        .replace('\r', "")
+}
+
+/// The vCard telegram sent along with the contact, or one built from its
+/// name and phone number.
+pub fn contact_vcard(
+    special_type: &LogItemSpecialType,
+) -> Option<String> {
+    match special_type {
+        LogItemSpecialType::Contact { vcard: Some(vcard), .. } =>
+            Some(vcard.clone()),
+        LogItemSpecialType::Contact { phone_number, first_name, last_name, .. } => {
+            let last_name = last_name.as_deref().unwrap_or_default();
+
+            Some(
+                [
+                    "BEGIN:VCARD".to_string(),
+                    "VERSION:3.0".to_string(),
+                    format!("N:{};{};;;", escape_vcard(last_name), escape_vcard(first_name)),
+                    format!("FN:{}", escape_vcard(format!("{} {}", first_name, last_name).trim())),
+                    format!("TEL;TYPE=CELL:{}", escape_vcard(phone_number)),
+                    "END:VCARD".to_string(),
+                    String::new(),
+                ].join("\r\n"),
+            )
+        }
+        _ => None,
+    }
+}
+
+/// Renders a shared contact as a small card, linking to the user's page
+/// when they're known and to the `.vcf` download at `vcard_url`. Anonymized
+/// chats get neither, the name is left out and the number masked. None for
+/// anything that isn't a contact.
+pub fn render_contact(
+    db: &impl ReadStore,
+    special_type: &LogItemSpecialType,
+    vcard_url: Option<String>,
+    anonymize: bool,
+) -> Option<String> {
+    let (user_id, phone_number, first_name, last_name) =
+        match special_type {
+            LogItemSpecialType::Contact { user_id, phone_number, first_name, last_name, .. } =>
+                (user_id, phone_number, first_name, last_name),
+            _ => return None,
+        };
+
+    if anonymize {
+        return Some(
+            format!(
+                "<div class=\"contact\"><span class=\"name\">shared a contact</span><span class=\"phone\">{}</span></div>",
+                escape_html(&mask_phone_number(phone_number)),
+            ),
+        );
+    }
+
+    let name =
+        escape_html(
+            format!(
+                "{} {}",
+                first_name,
+                last_name.as_deref().unwrap_or_default(),
+            ).trim(),
+        );
+
+    let name =
+        match user_id.map(|user_id| user_id.to_string()) {
+            Some(user_id) if get_user_meta(db, &user_id).is_some() =>
+                format!("<a href=\"/user/{}\">{}</a>", user_id, name),
+            _ => name,
+        };
+
+    Some(
+        format!(
+            "<div class=\"contact\"><span class=\"name\">{}</span><span class=\"phone\">{}</span>{}</div>",
+            name,
+            escape_html(phone_number),
+            vcard_url
+                .map(|url| format!("<a class=\"vcard\" href=\"{}\">vcard</a>", escape_html(&url)))
+                .unwrap_or_default(),
+        ),
+    )
+}
+
+/// Plain text version of `render_contact` for text exports.
+pub fn contact_summary(
+    special_type: &LogItemSpecialType,
+    anonymize: bool,
+) -> Option<String> {
+    match special_type {
+        LogItemSpecialType::Contact { phone_number, .. } if anonymize =>
+            Some(format!("shared a contact: {}", mask_phone_number(phone_number))),
+        LogItemSpecialType::Contact { phone_number, first_name, last_name, .. } =>
+            Some(
+                format!(
+                    "shared a contact: {} ({})",
+                    format!("{} {}", first_name, last_name.as_deref().unwrap_or_default()).trim(),
+                    phone_number,
+                ),
+            ),
+        _ => None,
+    }
+}
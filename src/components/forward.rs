@@ -0,0 +1,117 @@
+use chrono::NaiveDateTime;
+use serde::Serialize;
+
+use crate::locales::{Lang, t};
+use crate::privacy::{pseudonym, Viewer};
+use crate::storage::{get_chat_meta, ReadStore};
+use crate::utils::{escape_html, message_permalink, resolve_user_meta};
+use crate::workers::telegram_handler::{ForwardFromMeta, ForwardMeta};
+
+// forwards from the same origin this close to each other share one banner
+pub const FORWARD_GROUP_WINDOW: i64 = 5 * 60;
+
+/// Where a forwarded message came from, the json listing has it as is.
+#[derive(Debug, Clone, Serialize)]
+pub struct ForwardOrigin {
+    // user, channel, hidden_user or hidden_admin
+    pub kind: &'static str,
+    pub id: Option<String>,
+    pub name: String,
+    pub username: Option<String>,
+    // when sent originally
+    pub date: i64,
+    // the original message in its chat's archive, when that chat is logged
+    // too and the viewer may see it
+    pub archive_url: Option<String>,
+}
+
+impl ForwardOrigin {
+    /// Whether `other` comes from the same chat or user.
+    pub fn same_origin(
+        &self,
+        other: &ForwardOrigin,
+    ) -> bool {
+        self.kind == other.kind && self.id == other.id && self.name == other.name
+    }
+}
+
+pub fn forward_origin(
+    db: &impl ReadStore,
+    forward: &ForwardMeta,
+    viewer: &Viewer,
+    anonymize: bool,
+) -> ForwardOrigin {
+    let (kind, id, name, username) =
+        match forward.from {
+            ForwardFromMeta::User { ref user } if anonymize =>
+                ("user", None, pseudonym(&user.id), None),
+            ForwardFromMeta::User { ref user } =>
+                ("user", Some(user.id.clone()), resolve_user_meta(user), user.username.clone()),
+            ForwardFromMeta::Channel { ref channel, .. } =>
+                ("channel", Some(channel.id.clone()), channel.title.clone(), channel.username.clone()),
+            // a name the sender chose to show instead of their account
+            ForwardFromMeta::ChannelHiddenUser { ref sender_name } =>
+                ("hidden_user", None, sender_name.clone(), None),
+            ForwardFromMeta::HiddenGroupAdmin { ref chat_id, ref title } =>
+                ("hidden_admin", Some(chat_id.clone()), title.clone(), None),
+        };
+
+    let archive_url =
+        id.as_ref()
+            .filter(|id| get_chat_meta(db, id).is_some() && viewer.can_see_chat(id))
+            .map(|id| message_permalink(id, forward.date))
+            .flatten();
+
+    ForwardOrigin {
+        kind,
+        id,
+        name,
+        username,
+        date: forward.date,
+        archive_url,
+    }
+}
+
+/// The "forwarded from" line above a forwarded message or a run of them.
+pub fn render_forward_banner(
+    origin: &ForwardOrigin,
+    lang: Lang,
+) -> String {
+    let name =
+        match origin.archive_url {
+            Some(ref url) =>
+                format!(
+                    "<a href=\"{}\">{}</a>",
+                    escape_html(url),
+                    escape_html(&origin.name),
+                ),
+            None => escape_html(&origin.name),
+        };
+
+    // only channels have public usernames worth linking
+    let username =
+        origin.username
+            .as_ref()
+            .filter(|_| origin.kind == "channel")
+            .map(|username|
+                format!(
+                    " <a href=\"https://t.me/{}\">@{}</a>",
+                    escape_html(username),
+                    escape_html(username),
+                )
+            )
+            .unwrap_or_default();
+
+    let date =
+        NaiveDateTime::from_timestamp_opt(origin.date, 0)
+            .map(|date| format!(" <span class=\"note\">({})</span>", date.format("%Y-%m-%d %H:%M")))
+            .unwrap_or_default();
+
+    format!(
+        "<span class=\"forward\">↪ {} {}{}{}</span>",
+        t(lang, "forward.from"),
+        name,
+        username,
+        date,
+    )
+}
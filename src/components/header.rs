@@ -1,37 +1,61 @@
+use crate::components::avatar::chat_avatar;
+use crate::utils::escape_html;
+
+/// An item of the header bar. Labels are plain text, they're escaped when
+/// rendered; only `Raw` takes markup.
 pub enum HeaderItem {
     Title {
         label: String,
     },
+    // a title with the chat's avatar in front of it
+    ChatTitle {
+        chat_id: String,
+        label: String,
+    },
     Link {
         label: String,
         url: Option<String>,
     },
+    Active {
+        label: String,
+    },
+    Raw {
+        html: String,
+    },
 }
 
 impl From<HeaderItem> for String {
     fn from(item: HeaderItem) -> Self {
         match item {
             HeaderItem::Title { ref label } =>
-                format!("<span class=\"title\">{}</span>", label),
+                format!("<span class=\"title\">{}</span>", escape_html(label)),
+            HeaderItem::ChatTitle { ref chat_id, ref label } =>
+                format!("<span class=\"title\">{} {}</span>", chat_avatar(chat_id), escape_html(label)),
             HeaderItem::Link { ref label, ref url } =>
                 match url {
                     Some(ref url) =>
-                        format!("<a href=\"{}\">{}</a>", url, label),
+                        format!("<a href=\"{}\">{}</a>", url, escape_html(label)),
                     None =>
-                        format!("<span class=\"nolink\">{} (none)</span>", label),
-                }
+                        format!("<span class=\"nolink\">{} (none)</span>", escape_html(label)),
+                },
+            HeaderItem::Active { ref label } =>
+                format!("<span class=\"active\">{}</span>", escape_html(label)),
+            HeaderItem::Raw { html } =>
+                html,
         }
     }
 }
 
 pub struct HeaderBar {
     items: Vec<HeaderItem>,
+    right_items: Vec<HeaderItem>,
 }
 
 impl HeaderBar {
     pub fn new() -> Self {
         HeaderBar {
             items: vec!(),
+            right_items: vec!(),
         }
     }
 
@@ -44,6 +68,16 @@ impl HeaderBar {
         self
     }
 
+    /// Adds an item to the right-aligned group of the bar.
+    pub fn with_right_item(
+        mut self,
+        item: HeaderItem,
+    ) -> Self {
+        self.right_items.push(item);
+
+        self
+    }
+
     pub fn with_title(
         mut self,
         label: impl Into<String>,
@@ -57,6 +91,22 @@ impl HeaderBar {
         self
     }
 
+    /// Title of a chat's page, with its avatar in front.
+    pub fn with_chat_title(
+        mut self,
+        chat_id: &str,
+        label: impl Into<String>,
+    ) -> Self {
+        self.items.push(
+            HeaderItem::ChatTitle {
+                chat_id: chat_id.to_string(),
+                label: label.into(),
+            },
+        );
+
+        self
+    }
+
     pub fn with_link(
         mut self,
         label: &str,
@@ -72,6 +122,48 @@ impl HeaderBar {
         self
     }
 
+    /// Marks the page the user is currently on.
+    pub fn with_active(
+        mut self,
+        label: &str,
+    ) -> Self {
+        self.items.push(
+            HeaderItem::Active {
+                label: label.to_string(),
+            },
+        );
+
+        self
+    }
+
+    /// Appends the `format` alternative of `base_url` to the right-aligned
+    /// group.
+    pub fn with_format_link(
+        mut self,
+        base_url: &str,
+        format: &str,
+    ) -> Self {
+        self.right_items.push(
+            HeaderItem::Link {
+                label: format.to_string(),
+                url: Some(format!("{}.{}", base_url, format)),
+            },
+        );
+
+        self
+    }
+
+    /// Appends `.json` and `.txt` alternatives of `base_url` to the
+    /// right-aligned group.
+    pub fn with_format_links(
+        self,
+        base_url: &str,
+    ) -> Self {
+        self
+            .with_format_link(base_url, "json")
+            .with_format_link(base_url, "txt")
+    }
+
     pub fn to_string(
         self,
     ) -> String {
@@ -79,17 +171,111 @@ impl HeaderBar {
     }
 }
 
+fn join_items(
+    items: Vec<HeaderItem>,
+) -> String {
+    items
+        .into_iter()
+        .map(|item|
+            item.into()
+        )
+        .collect::<Vec<String>>()
+        .join(" | ")
+}
+
 impl From<HeaderBar> for String {
     fn from(item: HeaderBar) -> Self {
+        if item.right_items.is_empty() {
+            return format!(
+                "<div class=\"navigation\">{}</div>",
+                join_items(item.items),
+            );
+        }
+
         format!(
-            "<div class=\"navigation\">{}</div>",
-            item.items
-                .into_iter()
-                .map(|item|
-                    item.into()
-                )
-                .collect::<Vec<String>>()
-                .join(" | "),
+            "<div class=\"navigation\">{}<span class=\"right\">{}</span></div>",
+            join_items(item.items),
+            join_items(item.right_items),
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(item: HeaderItem) -> String {
+        item.into()
+    }
+
+    #[test]
+    fn renders_each_item_kind() {
+        assert_eq!(
+            render(HeaderItem::Title { label: "chat".into() }),
+            "<span class=\"title\">chat</span>",
+        );
+        assert_eq!(
+            render(HeaderItem::ChatTitle { chat_id: "-1".into(), label: "chat".into() }),
+            format!("<span class=\"title\">{} chat</span>", chat_avatar("-1")),
+        );
+        assert_eq!(
+            render(HeaderItem::Link { label: "index".into(), url: Some("/chat/-1".into()) }),
+            "<a href=\"/chat/-1\">index</a>",
+        );
+        assert_eq!(
+            render(HeaderItem::Link { label: "next".into(), url: None }),
+            "<span class=\"nolink\">next (none)</span>",
+        );
+        assert_eq!(
+            render(HeaderItem::Active { label: "pins".into() }),
+            "<span class=\"active\">pins</span>",
+        );
+        assert_eq!(
+            render(HeaderItem::Raw { html: "<form></form>".into() }),
+            "<form></form>",
+        );
+    }
+
+    #[test]
+    fn escapes_labels() {
+        let label = "<&\">";
+        let escaped = "&lt;&amp;&quot;&gt;";
+
+        assert_eq!(
+            render(HeaderItem::Title { label: label.into() }),
+            format!("<span class=\"title\">{}</span>", escaped),
+        );
+        assert_eq!(
+            render(HeaderItem::ChatTitle { chat_id: "-1".into(), label: label.into() }),
+            format!("<span class=\"title\">{} {}</span>", chat_avatar("-1"), escaped),
+        );
+        assert_eq!(
+            render(HeaderItem::Link { label: label.into(), url: Some("/".into()) }),
+            format!("<a href=\"/\">{}</a>", escaped),
+        );
+        assert_eq!(
+            render(HeaderItem::Link { label: label.into(), url: None }),
+            format!("<span class=\"nolink\">{} (none)</span>", escaped),
+        );
+        assert_eq!(
+            render(HeaderItem::Active { label: label.into() }),
+            format!("<span class=\"active\">{}</span>", escaped),
+        );
+    }
+
+    #[test]
+    fn joins_items_and_the_right_group() {
+        let bar =
+            HeaderBar::new()
+                .with_title("<b>")
+                .with_active("day")
+                .with_format_links("/chat/-1/2021-01-01")
+                .to_string();
+
+        assert_eq!(
+            bar,
+            "<div class=\"navigation\"><span class=\"title\">&lt;b&gt;</span> | <span class=\"active\">day</span>\
+             <span class=\"right\"><a href=\"/chat/-1/2021-01-01.json\">json</a> | <a href=\"/chat/-1/2021-01-01.txt\">txt</a></span></div>",
+        );
+    }
+}
@@ -0,0 +1,60 @@
+use crate::config::get_highlight_code;
+
+#[cfg(feature = "highlight")]
+mod syntax {
+    use once_cell::sync::Lazy;
+    use syntect::easy::HighlightLines;
+    use syntect::highlighting::{Theme, ThemeSet};
+    use syntect::html::{IncludeBackground, styled_line_to_highlighted_html};
+    use syntect::parsing::SyntaxSet;
+    use syntect::util::LinesWithEndings;
+
+    static SYNTAX_SET: Lazy<SyntaxSet> = Lazy::new(SyntaxSet::load_defaults_newlines);
+
+    static THEME: Lazy<Theme> =
+        Lazy::new(||
+            ThemeSet::load_defaults()
+                .themes
+                .remove("InspiredGitHub")
+                .unwrap_or_default()
+        );
+
+    pub fn highlight(
+        code: &str,
+        language: &str,
+    ) -> Option<String> {
+        let syntax = SYNTAX_SET.find_syntax_by_token(language)?;
+
+        let mut lines = HighlightLines::new(syntax, &THEME);
+        let mut out = String::new();
+
+        for line in LinesWithEndings::from(code) {
+            let regions = lines.highlight_line(line, &SYNTAX_SET).ok()?;
+
+            out.push_str(&styled_line_to_highlighted_html(&regions, IncludeBackground::No).ok()?);
+        }
+
+        Some(out)
+    }
+}
+
+/// Highlighted html of a code block, None when highlighting is off, not
+/// compiled in, or the language isn't known. The result is already escaped.
+pub fn highlight_code(
+    code: &str,
+    language: &str,
+) -> Option<String> {
+    if !get_highlight_code() {
+        return None;
+    }
+
+    #[cfg(feature = "highlight")]
+    return syntax::highlight(code, language);
+
+    #[cfg(not(feature = "highlight"))]
+    {
+        let _ = (code, language);
+
+        None
+    }
+}
@@ -0,0 +1,120 @@
+use chrono::NaiveDateTime;
+
+use crate::config::get_static_map_url;
+use crate::utils::escape_html;
+use crate::workers::telegram_handler::LogItemSpecialType;
+
+/// Six decimals are about ten centimeters, as precise as telegram gets.
+pub fn format_coordinates(
+    latitude: f64,
+    longitude: f64,
+) -> String {
+    format!("{:.6}, {:.6}", latitude, longitude)
+}
+
+pub fn map_link(
+    latitude: f64,
+    longitude: f64,
+) -> String {
+    format!(
+        "https://www.openstreetmap.org/?mlat={:.6}&mlon={:.6}#map=16/{:.6}/{:.6}",
+        latitude,
+        longitude,
+        latitude,
+        longitude,
+    )
+}
+
+fn map_thumbnail(
+    latitude: f64,
+    longitude: f64,
+) -> Option<String> {
+    get_static_map_url()
+        .map(|template|
+            template
+                .replace("{lat}", &format!("{:.6}", latitude))
+                .replace("{lon}", &format!("{:.6}", longitude))
+        )
+}
+
+/// Shared locations and venues: title and address when it's a venue, the
+/// coordinates linked to OpenStreetMap and a static map image when one is
+/// configured. None for anything else.
+pub fn render_location(
+    special_type: &LogItemSpecialType,
+) -> Option<String> {
+    let (latitude, longitude, title, address, live_updated) =
+        match special_type {
+            LogItemSpecialType::Location { latitude, longitude, live_updated } =>
+                (*latitude, *longitude, None, None, *live_updated),
+            LogItemSpecialType::Venue { location, title, address, .. } =>
+                (location.latitude, location.longitude, Some(title), Some(address), None),
+            _ => return None,
+        };
+
+    let link = map_link(latitude, longitude);
+
+    let mut out =
+        match (title, address) {
+            (Some(title), Some(address)) =>
+                format!(
+                    "shared a venue: <a href=\"{}\" rel=\"nofollow\">{}</a>, {}",
+                    escape_html(&link),
+                    escape_html(title),
+                    escape_html(address),
+                ),
+            _ =>
+                format!(
+                    "shared a location: <a href=\"{}\" rel=\"nofollow\">{}</a>",
+                    escape_html(&link),
+                    format_coordinates(latitude, longitude),
+                ),
+        };
+
+    if let Some(updated) = live_updated.map(|time| NaiveDateTime::from_timestamp_opt(time, 0)).flatten() {
+        out.push_str(
+            &format!(
+                " <span class=\"note\">live location, last updated {}</span>",
+                updated.format("%H:%M"),
+            ),
+        );
+    }
+
+    if let Some(thumbnail) = map_thumbnail(latitude, longitude) {
+        out.push_str(
+            &format!(
+                "<br/><a href=\"{}\" rel=\"nofollow\"><img class=\"map\" src=\"{}\" loading=\"lazy\"/></a>",
+                escape_html(&link),
+                escape_html(&thumbnail),
+            ),
+        );
+    }
+
+    Some(out)
+}
+
+/// Plain text version of `render_location` for text exports.
+pub fn location_summary(
+    special_type: &LogItemSpecialType,
+) -> Option<String> {
+    match special_type {
+        LogItemSpecialType::Location { latitude, longitude, .. } =>
+            Some(
+                format!(
+                    "shared a location: {} ({})",
+                    format_coordinates(*latitude, *longitude),
+                    map_link(*latitude, *longitude),
+                ),
+            ),
+        LogItemSpecialType::Venue { location, title, address, .. } =>
+            Some(
+                format!(
+                    "shared a venue: {}, {} ({})",
+                    title,
+                    address,
+                    map_link(location.latitude, location.longitude),
+                ),
+            ),
+        _ => None,
+    }
+}
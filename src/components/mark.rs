@@ -0,0 +1,133 @@
+use crate::utils::escape_html;
+
+/// The words of a search query, lowercased and without duplicates.
+pub fn search_terms(
+    q: &str,
+) -> Vec<String> {
+    let mut terms = Vec::<String>::new();
+
+    for term in q.split_whitespace() {
+        let term = term.to_lowercase();
+
+        if !terms.contains(&term) {
+            terms.push(term);
+        }
+    }
+
+    terms
+}
+
+/// Char ranges of `text` that match one of `terms`, case insensitive and
+/// merged where they overlap. Lowercasing may turn one char into several,
+/// so matches are found on the lowercased text and mapped back to the
+/// chars they came from.
+fn match_ranges(
+    text: &str,
+    terms: &[String],
+) -> Vec<(usize, usize)> {
+    let mut lowered = Vec::<char>::new();
+    let mut origin = Vec::<usize>::new();
+
+    for (index, c) in text.chars().enumerate() {
+        for lower in c.to_lowercase() {
+            lowered.push(lower);
+            origin.push(index);
+        }
+    }
+
+    let terms =
+        terms
+            .iter()
+            .map(|term| term.chars().collect::<Vec<char>>())
+            .filter(|term| !term.is_empty())
+            .collect::<Vec<Vec<char>>>();
+
+    let mut ranges = Vec::<(usize, usize)>::new();
+
+    for start in 0..lowered.len() {
+        for term in terms.iter() {
+            if !lowered[start..].starts_with(term) {
+                continue;
+            }
+
+            let range = (origin[start], origin[start + term.len() - 1] + 1);
+
+            match ranges.last_mut() {
+                Some(last) if range.0 <= last.1 => last.1 = last.1.max(range.1),
+                _ => ranges.push(range),
+            }
+        }
+    }
+
+    ranges
+}
+
+/// Escapes `text` and wraps what matches one of `terms` in `<mark>`.
+pub fn mark_matches(
+    text: &str,
+    terms: &[String],
+) -> String {
+    let chars = text.chars().collect::<Vec<char>>();
+
+    let mut out = String::new();
+    let mut position = 0;
+
+    for (start, end) in match_ranges(text, terms) {
+        out.push_str(&escape_html(&chars[position..start].iter().collect::<String>()));
+        out.push_str("<mark>");
+        out.push_str(&escape_html(&chars[start..end].iter().collect::<String>()));
+        out.push_str("</mark>");
+
+        position = end;
+    }
+
+    out.push_str(&escape_html(&chars[position..].iter().collect::<String>()));
+
+    out
+}
+
+// reverses `escape_html`, other entities are left alone
+fn unescape_html(
+    text: &str,
+) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+/// `mark_matches` for already rendered html: only the text between tags is
+/// marked, tags and their attributes are passed through untouched.
+pub fn mark_html(
+    html: &str,
+    terms: &[String],
+) -> String {
+    if terms.is_empty() {
+        return html.to_string();
+    }
+
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+
+    while !rest.is_empty() {
+        match rest.find('<') {
+            Some(0) => {
+                let end = rest.find('>').map(|end| end + 1).unwrap_or(rest.len());
+
+                out.push_str(&rest[..end]);
+                rest = &rest[end..];
+            }
+            Some(tag) => {
+                out.push_str(&mark_matches(&unescape_html(&rest[..tag]), terms));
+                rest = &rest[tag..];
+            }
+            None => {
+                out.push_str(&mark_matches(&unescape_html(rest), terms));
+                rest = "";
+            }
+        }
+    }
+
+    out
+}
@@ -0,0 +1,244 @@
+use crate::components::highlight::highlight_code;
+use crate::utils::escape_html;
+use crate::workers::telegram_handler::{LogItemMessageEntity, LogItemMessageEntityKind};
+
+/// Renders message text with its entities applied. Telegram counts entity
+/// offsets in UTF-16 code units, so the text is split on those.
+///
+/// Every run of text between two entity boundaries is wrapped separately in
+/// the tags of all entities covering it, which keeps the markup well-formed
+/// even when entities overlap without nesting.
+pub fn render_message_text(
+    text: &str,
+    entities: &[LogItemMessageEntity],
+) -> String {
+    let units = text.encode_utf16().collect::<Vec<u16>>();
+
+    let span = |start: usize, end: usize| -> String {
+        String::from_utf16_lossy(&units[start.min(units.len())..end.min(units.len())])
+    };
+
+    let entities =
+        entities
+            .iter()
+            .filter(|entity| entity.offset >= 0 && entity.length > 0)
+            .map(|entity| {
+                let start = entity.offset as usize;
+
+                (start, start + entity.length as usize, &entity.kind)
+            })
+            .collect::<Vec<(usize, usize, &LogItemMessageEntityKind)>>();
+
+    let mut boundaries = vec!(0, units.len());
+
+    for (start, end, _) in entities.iter() {
+        boundaries.push((*start).min(units.len()));
+        boundaries.push((*end).min(units.len()));
+    }
+
+    boundaries.sort_unstable();
+    boundaries.dedup();
+
+    let mut out = String::new();
+
+    for window in boundaries.windows(2) {
+        let (start, end) = (window[0], window[1]);
+
+        // code blocks without other entities inside can be highlighted as
+        // a whole
+        let highlighted =
+            entities
+                .iter()
+                .find_map(|(entity_start, entity_end, kind)|
+                    match kind {
+                        LogItemMessageEntityKind::Pre(Some(language)) if *entity_start == start && *entity_end == end =>
+                            highlight_code(&span(start, end), language),
+                        _ => None,
+                    }
+                );
+
+        let mut segment =
+            highlighted
+                .unwrap_or_else(|| escape_html(&span(start, end)));
+
+        for (entity_start, entity_end, kind) in entities.iter().rev() {
+            if *entity_start > start || *entity_end < end {
+                continue;
+            }
+
+            let (open, close) =
+                entity_tags(
+                    kind,
+                    &span(*entity_start, *entity_end),
+                );
+
+            segment = format!("{}{}{}", open, segment, close);
+        }
+
+        out.push_str(&segment);
+    }
+
+    out
+}
+
+/// Where a long text gets cut, in UTF-16 code units, None when it fits
+/// within `max_chars` characters and `max_lines` lines. Zero disables either
+/// limit. Cuts after the last line break before the limit when there is one
+/// in the second half of the kept part.
+fn collapse_point(
+    text: &str,
+    max_chars: usize,
+    max_lines: usize,
+) -> Option<usize> {
+    let mut units = 0;
+    let mut lines = 1;
+    let mut last_break = None;
+
+    for (chars, c) in text.chars().enumerate() {
+        if (max_chars > 0 && chars >= max_chars) || (max_lines > 0 && lines > max_lines) {
+            return match last_break {
+                Some(last_break) if last_break * 2 >= units => Some(last_break),
+                _ => Some(units),
+            };
+        }
+
+        units += c.len_utf16();
+
+        if c == '\n' {
+            lines += 1;
+            last_break = Some(units);
+        }
+    }
+
+    None
+}
+
+/// Entities of the units `[start, end)`, clipped to it and moved to start at
+/// zero.
+fn clip_entities(
+    entities: &[LogItemMessageEntity],
+    start: usize,
+    end: usize,
+) -> Vec<LogItemMessageEntity> {
+    entities
+        .iter()
+        .filter(|entity| entity.offset >= 0 && entity.length > 0)
+        .filter_map(|entity| {
+            let entity_start = (entity.offset as usize).max(start);
+            let entity_end = (entity.offset as usize + entity.length as usize).min(end);
+
+            if entity_start >= entity_end {
+                return None;
+            }
+
+            Some(
+                LogItemMessageEntity {
+                    offset: (entity_start - start) as i64,
+                    length: (entity_end - entity_start) as i64,
+                    kind: entity.kind.clone(),
+                },
+            )
+        })
+        .collect()
+}
+
+/// `render_message_text` for day pages: text past `max_chars` characters or
+/// `max_lines` lines goes into a `<details>` element. Both parts are
+/// rendered on their own, an entity crossing the cut is closed before it
+/// and reopened after it, so the markup stays well-formed.
+pub fn render_collapsed_message_text(
+    text: &str,
+    entities: &[LogItemMessageEntity],
+    max_chars: usize,
+    max_lines: usize,
+) -> String {
+    let cut =
+        match collapse_point(text, max_chars, max_lines) {
+            Some(cut) => cut,
+            None => return render_message_text(text, entities),
+        };
+
+    let units = text.encode_utf16().collect::<Vec<u16>>();
+
+    format!(
+        "{}<details class=\"more\"><summary>show more</summary>{}</details>",
+        render_message_text(
+            &String::from_utf16_lossy(&units[..cut]),
+            &clip_entities(entities, 0, cut),
+        ),
+        render_message_text(
+            &String::from_utf16_lossy(&units[cut..]),
+            &clip_entities(entities, cut, units.len()),
+        ),
+    )
+}
+
+fn entity_tags(
+    kind: &LogItemMessageEntityKind,
+    entity_text: &str,
+) -> (String, String) {
+    let link = |url: String| {
+        (
+            format!("<a href=\"{}\" rel=\"nofollow\">", escape_html(&url)),
+            "</a>".to_string(),
+        )
+    };
+
+    let class = |class: &str| {
+        (
+            format!("<span class=\"{}\">", class),
+            "</span>".to_string(),
+        )
+    };
+
+    match kind {
+        LogItemMessageEntityKind::Bold => class("bold"),
+        LogItemMessageEntityKind::Italic => class("italic"),
+        LogItemMessageEntityKind::Underline =>
+            ("<u>".to_string(), "</u>".to_string()),
+        LogItemMessageEntityKind::Strikethrough =>
+            ("<s>".to_string(), "</s>".to_string()),
+        // focusable so that clicking (or tabbing to) it reveals the text
+        LogItemMessageEntityKind::Spoiler =>
+            ("<span class=\"spoiler\" tabindex=\"0\">".to_string(), "</span>".to_string()),
+        LogItemMessageEntityKind::Blockquote =>
+            ("<blockquote>".to_string(), "</blockquote>".to_string()),
+        LogItemMessageEntityKind::Code =>
+            ("<code>".to_string(), "</code>".to_string()),
+        LogItemMessageEntityKind::Pre(language) =>
+            (
+                match language {
+                    Some(language) => format!("<pre><code class=\"language-{}\">", escape_html(language)),
+                    None => "<pre><code>".to_string(),
+                },
+                "</code></pre>".to_string(),
+            ),
+        LogItemMessageEntityKind::Url =>
+            if entity_text.contains("://") {
+                link(entity_text.to_string())
+            } else {
+                link(format!("https://{}", entity_text))
+            },
+        LogItemMessageEntityKind::Email =>
+            link(format!("mailto:{}", entity_text)),
+        LogItemMessageEntityKind::TextLink(url) =>
+            link(url.clone()),
+        LogItemMessageEntityKind::Mention =>
+            link(format!("https://t.me/{}", entity_text.trim_start_matches('@'))),
+        LogItemMessageEntityKind::TextMention(mention) =>
+            (
+                format!(
+                    "<a href=\"/user/{}\" title=\"{}\">",
+                    escape_html(mention.id()),
+                    escape_html(mention.name().unwrap_or(mention.id())),
+                ),
+                "</a>".to_string(),
+            ),
+        // the text already is the emoji's fallback character
+        LogItemMessageEntityKind::CustomEmoji(_) => class("custom-emoji"),
+        // kinds from newer clients still show their text, as it is
+        LogItemMessageEntityKind::Hashtag
+        | LogItemMessageEntityKind::BotCommand
+        | LogItemMessageEntityKind::Unknown(_) => (String::new(), String::new()),
+    }
+}
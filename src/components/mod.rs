@@ -1 +1,12 @@
+pub mod avatar;
+pub mod chat_event;
+pub mod contact;
+pub mod forward;
 pub mod header;
+pub mod highlight;
+pub mod location;
+pub mod mark;
+pub mod page;
+pub mod message_text;
+pub mod poll;
+pub mod special;
@@ -0,0 +1,461 @@
+use chrono::{NaiveDateTime, Utc};
+
+use crate::components::header::HeaderBar;
+use crate::config::{get_link_previews, get_noindex, get_public_url, get_version};
+use crate::locales::Lang;
+use crate::metrics::ingest_stats;
+use crate::renderer::assets::stylesheet_link;
+use crate::utils::escape_html;
+
+/// Color scheme of the pages, picked through `?theme=` and remembered in a
+/// cookie. Auto follows the system's preference.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Theme {
+    Auto,
+    Light,
+    Dark,
+}
+
+impl Theme {
+    pub fn parse(
+        theme: &str,
+    ) -> Option<Self> {
+        match theme {
+            "auto" => Some(Theme::Auto),
+            "light" => Some(Theme::Light),
+            "dark" => Some(Theme::Dark),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Theme::Auto => "auto",
+            Theme::Light => "light",
+            Theme::Dark => "dark",
+        }
+    }
+}
+
+fn format_uptime(
+    seconds: i64,
+) -> String {
+    match seconds {
+        seconds if seconds >= 86_400 => format!("{}d {}h", seconds / 86_400, seconds % 86_400 / 3_600),
+        seconds if seconds >= 3_600 => format!("{}h {}m", seconds / 3_600, seconds % 3_600 / 60),
+        seconds => format!("{}m", seconds / 60),
+    }
+}
+
+/// The uptime, messages ingested since start and the time of the newest
+/// message, as of rendering. Cached day pages show them as of when they were
+/// rendered, see `RENDER_CACHE_TTL`.
+fn render_footer_stats() -> String {
+    let stats = ingest_stats();
+    let now = Utc::now().timestamp();
+
+    let last_message_at =
+        stats.last_message_at
+            .map(|time| NaiveDateTime::from_timestamp_opt(time, 0))
+            .flatten()
+            .map(|time| time.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or("n/a".to_string());
+
+    format!(
+        "<span class=\"stats\">uptime {}, {} messages since start, last message {}</span>",
+        stats.started_at
+            .map(|started_at| format_uptime(now - started_at))
+            .unwrap_or("n/a".to_string()),
+        stats.messages,
+        last_message_at,
+    )
+}
+
+pub struct Page {
+    title: String,
+    // (property, content) of OpenGraph and Twitter card tags
+    meta: Vec<(String, String)>,
+    // seconds until the browser reloads the page
+    refresh: Option<u64>,
+    header: Option<HeaderBar>,
+    body: Vec<String>,
+    footer: Option<HeaderBar>,
+    theme: Theme,
+    lang: Lang,
+    // query string of the request, kept by the theme and lang links
+    query: String,
+}
+
+impl Page {
+    pub fn new(
+        title: impl Into<String>,
+    ) -> Self {
+        Page {
+            title: title.into(),
+            meta: vec!(),
+            refresh: None,
+            header: None,
+            body: vec!(),
+            footer: None,
+            theme: Theme::Auto,
+            lang: Lang::En,
+            query: String::new(),
+        }
+    }
+
+    /// Language of the page's text, English unless the renderer localizes
+    /// it.
+    pub fn with_lang(
+        mut self,
+        lang: Lang,
+    ) -> Self {
+        self.lang = lang;
+
+        self
+    }
+
+    /// The raw query string of the request, so that picking a theme or
+    /// language stays on the same page of a listing.
+    pub fn with_query(
+        mut self,
+        query: &str,
+    ) -> Self {
+        self.query = query.to_string();
+
+        self
+    }
+
+    /// The viewer's color scheme, see `with_theme` in the server.
+    pub fn with_theme(
+        mut self,
+        theme: Theme,
+    ) -> Self {
+        self.theme = theme;
+
+        self
+    }
+
+    pub fn with_header(
+        mut self,
+        header: HeaderBar,
+    ) -> Self {
+        self.header = Some(header);
+
+        self
+    }
+
+    /// Link preview tags for chat apps, left out when
+    /// `MINUTEMAN_LINK_PREVIEWS` is off. `image` is a path on this instance
+    /// and only used when `MINUTEMAN_PUBLIC_URL` is set.
+    pub fn with_preview(
+        mut self,
+        title: impl Into<String>,
+        description: impl Into<String>,
+        image: Option<String>,
+    ) -> Self {
+        if !get_link_previews() {
+            return self;
+        }
+
+        let title = title.into();
+        let description = description.into();
+
+        let image =
+            image
+                .map(|image| get_public_url().map(|url| format!("{}{}", url, image)))
+                .flatten();
+
+        self.meta.push(("og:type".to_string(), "website".to_string()));
+        self.meta.push(("og:site_name".to_string(), "minuteman".to_string()));
+        self.meta.push(("og:title".to_string(), title.clone()));
+        self.meta.push(("og:description".to_string(), description.clone()));
+        self.meta.push(("twitter:title".to_string(), title));
+        self.meta.push(("twitter:description".to_string(), description));
+
+        if let Some(image) = image {
+            self.meta.push(("og:image".to_string(), image.clone()));
+            self.meta.push(("twitter:image".to_string(), image));
+        }
+
+        self.meta.push(("twitter:card".to_string(), "summary".to_string()));
+
+        self
+    }
+
+    /// Has the browser reload the page every `seconds`.
+    pub fn with_refresh(
+        mut self,
+        seconds: u64,
+    ) -> Self {
+        self.refresh = Some(seconds);
+
+        self
+    }
+
+    pub fn with_body(
+        mut self,
+        body: impl Into<String>,
+    ) -> Self {
+        self.body.push(body.into());
+
+        self
+    }
+
+    /// Navigation rendered at the bottom of the page, above the version line.
+    pub fn with_footer(
+        mut self,
+        footer: HeaderBar,
+    ) -> Self {
+        self.footer = Some(footer);
+
+        self
+    }
+
+    pub fn render(
+        self,
+    ) -> String {
+        self.into()
+    }
+}
+
+/// `query` with `key` set to `value`, escaped for an href.
+fn footer_link(
+    query: &str,
+    key: &str,
+    value: &str,
+) -> String {
+    let prefix = format!("{}=", key);
+    let param = format!("{}{}", prefix, value);
+
+    let params =
+        query
+            .split('&')
+            .filter(|param| !param.is_empty() && *param != key && !param.starts_with(&prefix))
+            .chain(std::iter::once(param.as_str()))
+            .collect::<Vec<&str>>()
+            .join("&");
+
+    escape_html(&format!("?{}", params))
+}
+
+impl From<Page> for String {
+    fn from(page: Page) -> Self {
+        let mut out =
+            vec!(
+                format!("<!DOCTYPE html><html lang=\"{}\">", page.lang.code()),
+                format!(
+                    "<head><meta charset=\"utf-8\"><title>{}</title>{}{}{}{}</head>",
+                    escape_html(&page.title),
+                    if get_noindex() { "<meta name=\"robots\" content=\"noindex\">" } else { "" },
+                    page.refresh
+                        .map(|seconds| format!("<meta http-equiv=\"refresh\" content=\"{}\">", seconds))
+                        .unwrap_or_default(),
+                    page.meta
+                        .iter()
+                        .map(|(property, content)|
+                            format!(
+                                "<meta property=\"{}\" content=\"{}\">",
+                                property,
+                                escape_html(content),
+                            )
+                        )
+                        .collect::<String>(),
+                    stylesheet_link(),
+                ),
+                format!("<body class=\"theme-{}\">", page.theme.name()),
+            );
+
+        if let Some(header) = page.header {
+            out.push(header.into());
+        }
+
+        out.extend(page.body);
+
+        out.push("<div class=\"footer\">".to_string());
+
+        if let Some(footer) = page.footer {
+            out.push(footer.into());
+        }
+
+        out.push(
+            format!(
+                "<span class=\"version\">minuteman {}</span>",
+                get_version(),
+            ),
+        );
+
+        out.push(render_footer_stats());
+
+        out.push(
+            format!(
+                "<span class=\"theme\">theme: {}</span>",
+                [Theme::Auto, Theme::Light, Theme::Dark]
+                    .iter()
+                    .map(|theme| format!("<a href=\"{}\">{}</a>", footer_link(&page.query, "theme", theme.name()), theme.name()))
+                    .collect::<Vec<String>>()
+                    .join(" | "),
+            ),
+        );
+
+        out.push(
+            format!(
+                "<span class=\"lang\">lang: {}</span>",
+                [Lang::En, Lang::De, Lang::Ru]
+                    .iter()
+                    .map(|lang| format!("<a href=\"{}\">{}</a>", footer_link(&page.query, "lang", lang.code()), lang.code()))
+                    .collect::<Vec<String>>()
+                    .join(" | "),
+            ),
+        );
+
+        out.push("</div></body></html>".to_string());
+
+        out.join("")
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // elements without a closing tag
+    const VOID_ELEMENTS: [&str; 7] = ["meta", "link", "img", "br", "hr", "input", "source"];
+
+    /// Every opened element is closed again, in order.
+    pub(crate) fn assert_balanced(html: &str) {
+        let mut open = Vec::<&str>::new();
+
+        for tag in html.split('<').skip(1) {
+            let tag = &tag[..tag.find('>').expect("unterminated tag")];
+
+            if tag.starts_with('!') || tag.ends_with('/') {
+                continue;
+            }
+
+            let name = tag.split_whitespace().next().unwrap_or_default();
+
+            match name.strip_prefix('/') {
+                Some(name) => assert_eq!(open.pop(), Some(name), "{}", html),
+                None if VOID_ELEMENTS.contains(&name) => {}
+                None => open.push(name),
+            }
+        }
+
+        assert!(open.is_empty(), "unclosed {:?} in {}", open, html);
+    }
+
+    /// One document: a single html, head and body each.
+    pub(crate) fn assert_one_document(html: &str) {
+        for tag in ["<html", "</html>", "<head>", "</head>", "<body", "</body>"] {
+            assert_eq!(html.matches(tag).count(), 1, "{} in {}", tag, html);
+        }
+    }
+
+    fn title(html: &str) -> &str {
+        let start = html.find("<title>").unwrap() + "<title>".len();
+
+        &html[start..html.find("</title>").unwrap()]
+    }
+
+    #[test]
+    fn bare_page_is_balanced() {
+        let html = Page::new("chats").render();
+
+        assert_balanced(&html);
+        assert!(html.starts_with("<!DOCTYPE html><html"));
+        assert!(html.ends_with("</html>"));
+    }
+
+    #[test]
+    fn page_with_header_is_balanced() {
+        let html =
+            Page::new("chat")
+                .with_header(
+                    HeaderBar::new()
+                        .with_link("<- home", Some("/".into()))
+                        .with_title("<chat>")
+                        .with_format_links("/chat/-1/2020-09-13"),
+                )
+                .with_body("<div class=\"listing\"><p>hi</p></div>")
+                .render();
+
+        assert_balanced(&html);
+        assert!(html.contains("<div class=\"navigation\">"));
+    }
+
+    #[test]
+    fn page_with_preview_is_balanced() {
+        let html =
+            Page::new("chat - 2020-09-13")
+                .with_preview("chat \"<b>\"", "a & b", Some("/file/chat/x".to_string()))
+                .render();
+
+        assert_balanced(&html);
+
+        if get_link_previews() {
+            assert!(html.contains("<meta property=\"og:title\" content=\"chat &quot;&lt;b&gt;&quot;\">"));
+            assert!(html.contains("<meta property=\"og:description\" content=\"a &amp; b\">"));
+        }
+    }
+
+    #[test]
+    fn page_with_refresh_is_balanced() {
+        let html = Page::new("latest").with_refresh(30).render();
+
+        assert_balanced(&html);
+        assert!(html.contains("<meta http-equiv=\"refresh\" content=\"30\">"));
+    }
+
+    #[test]
+    fn page_with_footer_is_balanced() {
+        let html =
+            Page::new("chat")
+                .with_theme(Theme::Dark)
+                .with_footer(HeaderBar::new().with_link("older", Some("/chat/-1/2020-09-13?cursor=o1".into())))
+                .render();
+
+        assert_balanced(&html);
+        assert!(html.contains("<body class=\"theme-dark\">"));
+        assert!(html.contains("<div class=\"footer\"><div class=\"navigation\">"));
+    }
+
+    #[test]
+    fn title_is_escaped() {
+        let html = Page::new("<script>alert(\"x\")</script> & co").render();
+
+        assert_balanced(&html);
+        assert_eq!(title(&html), "&lt;script&gt;alert(&quot;x&quot;)&lt;/script&gt; &amp; co");
+    }
+
+    #[test]
+    fn html_lang_follows_the_page() {
+        assert!(Page::new("chats").render().starts_with("<!DOCTYPE html><html lang=\"en\">"));
+        assert!(Page::new("chats").with_lang(Lang::De).render().starts_with("<!DOCTYPE html><html lang=\"de\">"));
+    }
+
+    #[test]
+    fn theme_and_lang_links_keep_the_query() {
+        let html =
+            Page::new("chat")
+                .with_query("cursor=o1&limit=50&lang=de&theme=dark")
+                .render();
+
+        assert_balanced(&html);
+        assert!(html.contains("<a href=\"?cursor=o1&amp;limit=50&amp;lang=de&amp;theme=light\">light</a>"));
+        assert!(html.contains("<a href=\"?cursor=o1&amp;limit=50&amp;theme=dark&amp;lang=ru\">ru</a>"));
+    }
+
+    #[test]
+    fn theme_and_lang_links_without_a_query() {
+        let html = Page::new("chats").render();
+
+        assert!(html.contains("<a href=\"?theme=auto\">auto</a>"));
+        assert!(html.contains("<a href=\"?lang=en\">en</a>"));
+    }
+
+    #[test]
+    fn footer_link_escapes_the_query() {
+        assert_eq!(footer_link("q=\"><script>", "lang", "de"), "?q=&quot;&gt;&lt;script&gt;&amp;lang=de");
+        assert_eq!(footer_link("lang&theme=x&language=en", "lang", "de"), "?theme=x&amp;language=en&amp;lang=de");
+    }
+}
@@ -0,0 +1,157 @@
+use pw_telegram_bot_fork::PollType;
+
+use crate::components::message_text::render_message_text;
+use crate::utils::escape_html;
+use crate::workers::telegram_handler::{LogItemMessageEntity, LogItemSpecialType};
+
+fn percentage(
+    voter_count: i64,
+    total_voter_count: i64,
+) -> f64 {
+    if total_voter_count <= 0 {
+        return 0.0;
+    }
+
+    (voter_count as f64 * 100.0 / total_voter_count as f64).clamp(0.0, 100.0)
+}
+
+/// Renders a poll as a question heading followed by one bar per option, the
+/// bars sized relative to the number of people who voted. Quiz answers are
+/// marked once telegram told us which one is right. None for anything that
+/// isn't a poll.
+pub fn render_poll(
+    special_type: &LogItemSpecialType,
+) -> Option<String> {
+    let (question, options, total_voter_count, is_closed, is_anonymous, poll_type, allows_multiple_answers, correct_option_id, explanation, explanation_entities) =
+        match special_type {
+            LogItemSpecialType::Poll {
+                question,
+                options,
+                total_voter_count,
+                is_closed,
+                is_anonymous,
+                poll_type,
+                allows_multiple_answers,
+                correct_option_id,
+                explanation,
+                explanation_entities,
+                ..
+            } =>
+                (question, options, *total_voter_count, *is_closed, *is_anonymous, poll_type, *allows_multiple_answers, *correct_option_id, explanation, explanation_entities),
+            _ => return None,
+        };
+
+    let mut flags =
+        vec!(
+            if is_anonymous { "anonymous" } else { "public" },
+            match poll_type {
+                PollType::Quiz => "quiz",
+                _ => "poll",
+            },
+        );
+
+    if allows_multiple_answers {
+        flags.push("multiple answers");
+    }
+
+    if is_closed {
+        flags.push("closed");
+    }
+
+    let rows =
+        options
+            .iter()
+            .enumerate()
+            .map(|(index, option)| {
+                let percentage = percentage(option.voter_count, total_voter_count);
+                let correct = correct_option_id == Some(index as i64);
+
+                format!(
+                    "<tr class=\"option{}\">\
+                        <td class=\"text\">{}{}</td>\
+                        <td class=\"bar\"><div style=\"width: {:.1}%\"></div></td>\
+                        <td class=\"votes\">{} ({:.0}%)</td>\
+                    </tr>",
+                    if correct { " correct" } else { "" },
+                    if correct { "&#10003; " } else { "" },
+                    escape_html(&option.text),
+                    percentage,
+                    option.voter_count,
+                    percentage,
+                )
+            })
+            .collect::<String>();
+
+    let explanation =
+        explanation
+            .as_ref()
+            .map(|explanation| {
+                let entities =
+                    explanation_entities
+                        .iter()
+                        .flatten()
+                        .map(|entity|
+                            LogItemMessageEntity {
+                                offset: entity.offset,
+                                length: entity.length,
+                                kind: entity.kind.clone(),
+                            }
+                        )
+                        .collect::<Vec<LogItemMessageEntity>>();
+
+                format!(
+                    "<div class=\"explanation\">{}</div>",
+                    render_message_text(explanation, &entities),
+                )
+            })
+            .unwrap_or_default();
+
+    Some(
+        format!(
+            "<div class=\"poll{}\">\
+                <h4 class=\"question\">{}</h4>\
+                <span class=\"note\">{}</span>\
+                <table class=\"options\"><tbody>{}</tbody></table>\
+                <span class=\"note\">{} vote(s)</span>\
+                {}\
+            </div>",
+            if is_closed { " closed" } else { "" },
+            escape_html(question),
+            flags.join(", "),
+            rows,
+            total_voter_count,
+            explanation,
+        ),
+    )
+}
+
+/// One line summary of a poll for plain text exports, like
+/// `Poll: question — option A (12), option B (3)`.
+pub fn poll_summary(
+    special_type: &LogItemSpecialType,
+) -> Option<String> {
+    match special_type {
+        LogItemSpecialType::Poll { question, options, is_closed, correct_option_id, .. } =>
+            Some(
+                format!(
+                    "Poll{}: {} — {}",
+                    if *is_closed { " (closed)" } else { "" },
+                    question,
+                    options
+                        .iter()
+                        .enumerate()
+                        .map(|(index, option)|
+                            format!(
+                                "{}{} ({})",
+                                option.text,
+                                if *correct_option_id == Some(index as i64) { " [correct]" } else { "" },
+                                option.voter_count,
+                            )
+                        )
+                        .collect::<Vec<String>>()
+                        .join(", "),
+                ),
+            ),
+        _ => None,
+    }
+}
@@ -0,0 +1,48 @@
+use crate::utils::escape_html;
+use crate::workers::telegram_handler::LogItemSpecialType;
+
+// currencies telegram counts in whole units rather than cents
+const ZERO_DECIMAL_CURRENCIES: [&str; 6] = ["CLP", "ISK", "JPY", "KRW", "UGX", "VND"];
+
+pub fn format_amount(
+    currency: &str,
+    total_amount: i64,
+) -> String {
+    if ZERO_DECIMAL_CURRENCIES.contains(&currency) {
+        return format!("{} {}", total_amount, currency);
+    }
+
+    format!(
+        "{}{}.{:02} {}",
+        if total_amount < 0 { "-" } else { "" },
+        total_amount.abs() / 100,
+        total_amount.abs() % 100,
+        currency,
+    )
+}
+
+/// What the author did for dice rolls, games and payments, rendered after
+/// the nick like "rolled 🎲 4". Text exports use it as is. None for every
+/// other kind.
+pub fn special_action(
+    special_type: &LogItemSpecialType,
+) -> Option<String> {
+    match special_type {
+        LogItemSpecialType::Dice { emoji, value } =>
+            Some(format!("rolled {} {}", emoji, value)),
+        LogItemSpecialType::Game { title, description } if description.is_empty() =>
+            Some(format!("shared the game {}", title)),
+        LogItemSpecialType::Game { title, description } =>
+            Some(format!("shared the game {}: {}", title, description)),
+        LogItemSpecialType::Payment { currency, total_amount, .. } =>
+            Some(format!("paid {}", format_amount(currency, *total_amount))),
+        _ => None,
+    }
+}
+
+pub fn render_special_action(
+    special_type: &LogItemSpecialType,
+) -> Option<String> {
+    special_action(special_type)
+        .map(|action| format!("<span class=\"reason\">{}</span>", escape_html(&action)))
+}
@@ -1,3 +1,13 @@
+use std::env;
+use std::fs;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use chrono::FixedOffset;
+
+use crate::{get_telegram_api_token, JOB_SLEEP_INTERVAL, MAX_FILE_SIZE, MinutemanError};
+use crate::locales::Lang;
+
 pub fn get_version() -> String {
     let version = env!("CARGO_PKG_VERSION");
 
@@ -6,3 +16,907 @@ pub fn get_version() -> String {
         version,
     )
 }
+
+/// Base of the bot api, `https://api.telegram.org/` unless `TELEGRAM_API_URL`
+/// points at another server, like a local bot api server. It's the variable
+/// the fork reads for the calls it makes itself, so both go to the same one.
+pub fn get_telegram_api_url() -> String {
+    env::var("TELEGRAM_API_URL")
+        .ok()
+        .filter(|url| !url.is_empty())
+        .unwrap_or("https://api.telegram.org/".to_string())
+}
+
+/// Requests taking longer than this are logged as slow, configurable
+/// through `MINUTEMAN_SLOW_REQUEST_MS`.
+pub fn get_slow_request_threshold() -> Duration {
+    Duration::from_millis(
+        env::var("MINUTEMAN_SLOW_REQUEST_MS")
+            .ok()
+            .map(|ms| ms.parse::<u64>().ok())
+            .flatten()
+            .unwrap_or(2_000),
+    )
+}
+
+/// First delay before restarting a failed worker, doubling with every
+/// failure in a row. Configurable through `MINUTEMAN_RESTART_BACKOFF_MS`.
+pub fn get_restart_backoff_base() -> Duration {
+    Duration::from_millis(
+        env::var("MINUTEMAN_RESTART_BACKOFF_MS")
+            .ok()
+            .map(|ms| ms.parse::<u64>().ok())
+            .flatten()
+            .filter(|ms| *ms > 0)
+            .unwrap_or(JOB_SLEEP_INTERVAL),
+    )
+}
+
+/// Workers whose heartbeat is older than this are flagged on the status
+/// page, configurable through `MINUTEMAN_HEARTBEAT_STALE_SECONDS`.
+pub fn get_heartbeat_stale_after() -> Duration {
+    Duration::from_secs(
+        env::var("MINUTEMAN_HEARTBEAT_STALE_SECONDS")
+            .ok()
+            .map(|seconds| seconds.parse::<u64>().ok())
+            .flatten()
+            .unwrap_or(120),
+    )
+}
+
+/// Secret the pseudonyms of anonymized chats are derived from, set through
+/// `MINUTEMAN_ANONYMIZE_SECRET`. Changing it changes every pseudonym.
+pub fn get_anonymize_secret() -> Option<String> {
+    env::var("MINUTEMAN_ANONYMIZE_SECRET")
+        .ok()
+        .filter(|secret| !secret.is_empty())
+}
+
+/// Comma separated chat ids rendered anonymized, `*` anonymizes every chat.
+/// Configurable through `MINUTEMAN_ANONYMIZE_CHATS`.
+pub fn get_anonymized_chats() -> Vec<String> {
+    env::var("MINUTEMAN_ANONYMIZE_CHATS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|chat_id| chat_id.trim().to_string())
+        .filter(|chat_id| !chat_id.is_empty())
+        .collect()
+}
+
+/// Secret share tokens are signed with, set through `MINUTEMAN_SHARE_SECRET`.
+/// Changing it invalidates every share link, without it there are none.
+pub fn get_share_secret() -> Option<String> {
+    env::var("MINUTEMAN_SHARE_SECRET")
+        .ok()
+        .filter(|secret| !secret.is_empty())
+}
+
+/// Whether only admins and share links get to see anything, off unless
+/// `MINUTEMAN_REQUIRE_AUTH` is `1` or `true`.
+pub fn get_require_auth() -> bool {
+    env::var("MINUTEMAN_REQUIRE_AUTH")
+        .map(|value| value == "1" || value == "true")
+        .unwrap_or(false)
+}
+
+/// Whether `?debug=1` on listings shows the storage details of every row,
+/// off unless `MINUTEMAN_ENABLE_DEBUG_VIEWS` is `1` or `true`.
+pub fn get_enable_debug_views() -> bool {
+    env::var("MINUTEMAN_ENABLE_DEBUG_VIEWS")
+        .map(|value| value == "1" || value == "true")
+        .unwrap_or(false)
+}
+
+/// Token that unlocks the admin view, sent either as a bearer token or as
+/// the `minuteman_admin` cookie. Configurable through `MINUTEMAN_ADMIN_TOKEN`,
+/// without it there is no admin view.
+pub fn get_admin_token() -> Option<String> {
+    env::var("MINUTEMAN_ADMIN_TOKEN")
+        .ok()
+        .filter(|token| !token.is_empty())
+}
+
+/// Telegram user ids allowed to run admin bot commands, comma separated in
+/// `MINUTEMAN_ADMIN_USER_IDS`.
+pub fn get_admin_user_ids() -> Vec<String> {
+    env::var("MINUTEMAN_ADMIN_USER_IDS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|user_id| user_id.trim().to_string())
+        .filter(|user_id| !user_id.is_empty())
+        .collect()
+}
+
+/// Users whose messages are never stored, comma separated ids or @usernames
+/// in `MINUTEMAN_IGNORED_USERS`. Adds to the list managed through `/ignore`.
+pub fn get_ignored_users() -> Vec<String> {
+    env::var("MINUTEMAN_IGNORED_USERS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|user| user.trim().trim_start_matches('@').to_lowercase())
+        .filter(|user| !user.is_empty())
+        .collect()
+}
+
+/// Directory incremental database backups are written to, set through
+/// `MINUTEMAN_BACKUP_DIR`. Scheduled backups are off without it.
+pub fn get_backup_dir() -> Option<String> {
+    env::var("MINUTEMAN_BACKUP_DIR")
+        .ok()
+        .filter(|dir| !dir.is_empty())
+}
+
+/// Path of the database of a bot running elsewhere, set through
+/// `MINUTEMAN_SECONDARY_OF`. With it the process is a read-only web
+/// frontend: it opens that database as a secondary instance, catches up
+/// with it every `get_catch_up_interval` and runs no bots or workers.
+pub fn get_secondary_of() -> Option<String> {
+    env::var("MINUTEMAN_SECONDARY_OF")
+        .ok()
+        .filter(|path| !path.is_empty())
+}
+
+/// Directory the secondary instance of a frontend keeps its info log in,
+/// configurable through `MINUTEMAN_SECONDARY_DIR`. Defaults to
+/// `db-secondary`.
+pub fn get_secondary_dir() -> String {
+    env::var("MINUTEMAN_SECONDARY_DIR")
+        .ok()
+        .filter(|dir| !dir.is_empty())
+        .unwrap_or("db-secondary".to_string())
+}
+
+/// How far a frontend may lag behind the bot's database, configurable
+/// through `MINUTEMAN_CATCH_UP_INTERVAL_SECS`. Defaults to 5 seconds.
+pub fn get_catch_up_interval() -> Duration {
+    Duration::from_secs(
+        env::var("MINUTEMAN_CATCH_UP_INTERVAL_SECS")
+            .ok()
+            .map(|secs| secs.parse::<u64>().ok())
+            .flatten()
+            .filter(|secs| *secs > 0)
+            .unwrap_or(5),
+    )
+}
+
+/// Time between two scheduled backups, configurable through
+/// `MINUTEMAN_BACKUP_INTERVAL_SECS`. Defaults to a day.
+pub fn get_backup_interval() -> Duration {
+    Duration::from_secs(
+        env::var("MINUTEMAN_BACKUP_INTERVAL_SECS")
+            .ok()
+            .map(|secs| secs.parse::<u64>().ok())
+            .flatten()
+            .unwrap_or(86_400),
+    )
+}
+
+/// Number of backups kept around, older ones are purged after every new
+/// backup. Configurable through `MINUTEMAN_BACKUP_KEEP`.
+pub fn get_backup_keep() -> usize {
+    env::var("MINUTEMAN_BACKUP_KEEP")
+        .ok()
+        .map(|keep| keep.parse::<usize>().ok())
+        .flatten()
+        .unwrap_or(7)
+        .max(1)
+}
+
+/// Pause between two background passes over all stored files, set through
+/// `MINUTEMAN_VERIFY_FILES_INTERVAL_SECS`. Without it files are only
+/// verified through `minuteman verify-files`.
+pub fn get_verify_files_interval() -> Option<Duration> {
+    env::var("MINUTEMAN_VERIFY_FILES_INTERVAL_SECS")
+        .ok()
+        .map(|secs| secs.parse::<u64>().ok())
+        .flatten()
+        .map(Duration::from_secs)
+}
+
+/// Whether a chat's auto-delete timer also applies to the archive, off
+/// unless `MINUTEMAN_HONOR_AUTO_DELETE` is `1` or `true`. Messages logged
+/// while the timer is on are deleted once it runs out, see `auto_delete`.
+pub fn get_honor_auto_delete() -> bool {
+    env::var("MINUTEMAN_HONOR_AUTO_DELETE")
+        .map(|value| value == "1" || value == "true")
+        .unwrap_or(false)
+}
+
+/// Number of user and chat meta records kept in memory, each, configurable
+/// through `MINUTEMAN_META_CACHE_SIZE`. Zero turns the cache off.
+pub fn get_meta_cache_size() -> usize {
+    env::var("MINUTEMAN_META_CACHE_SIZE")
+        .ok()
+        .map(|size| size.parse::<usize>().ok())
+        .flatten()
+        .unwrap_or(10_000)
+}
+
+/// Memory the rendered pages of finished days may take up, in megabytes,
+/// configurable through `MINUTEMAN_RENDER_CACHE_MB`. Zero turns the page
+/// cache off.
+pub fn get_render_cache_size() -> usize {
+    env::var("MINUTEMAN_RENDER_CACHE_MB")
+        .ok()
+        .map(|size| size.parse::<usize>().ok())
+        .flatten()
+        .unwrap_or(64) * 1024 * 1024
+}
+
+/// Url template of a static map image shown next to shared locations, with
+/// `{lat}` and `{lon}` standing in for the coordinates. Configurable through
+/// `MINUTEMAN_STATIC_MAP_URL`, without it there are only links.
+pub fn get_static_map_url() -> Option<String> {
+    env::var("MINUTEMAN_STATIC_MAP_URL")
+        .ok()
+        .filter(|url| !url.is_empty())
+}
+
+/// Messages longer than this many characters are collapsed behind a "show
+/// more" on day pages, configurable through `MINUTEMAN_COLLAPSE_CHARS`. Zero
+/// turns it off.
+pub fn get_collapse_chars() -> usize {
+    env::var("MINUTEMAN_COLLAPSE_CHARS")
+        .ok()
+        .map(|chars| chars.parse::<usize>().ok())
+        .flatten()
+        .unwrap_or(1_500)
+}
+
+/// Same as `get_collapse_chars` for the number of lines, configurable
+/// through `MINUTEMAN_COLLAPSE_LINES`.
+pub fn get_collapse_lines() -> usize {
+    env::var("MINUTEMAN_COLLAPSE_LINES")
+        .ok()
+        .map(|lines| lines.parse::<usize>().ok())
+        .flatten()
+        .unwrap_or(20)
+}
+
+/// Whether pages carry OpenGraph and Twitter card tags, which make chat
+/// apps show a preview (message text included) of pasted links. On unless
+/// `MINUTEMAN_LINK_PREVIEWS` is `0` or `false`.
+pub fn get_link_previews() -> bool {
+    env::var("MINUTEMAN_LINK_PREVIEWS")
+        .map(|value| value != "0" && value != "false")
+        .unwrap_or(true)
+}
+
+/// `url` without its trailing slashes when it's a usable base for absolute
+/// links: http or https, a host and nothing after the path.
+pub fn parse_base_url(
+    url: &str,
+) -> Option<String> {
+    let url = url.trim().trim_end_matches('/');
+
+    let host =
+        url.strip_prefix("https://")
+            .or(url.strip_prefix("http://"))?;
+
+    let valid =
+        !host.is_empty()
+            && !host.starts_with('/')
+            && !host.contains(|c: char| c.is_whitespace() || c == '?' || c == '#' || c == '"' || c == '<' || c == '>');
+
+    Some(url.to_string()).filter(|_| valid)
+}
+
+/// Address the archive is reachable at, like `https://logs.example.org`, set
+/// through `MINUTEMAN_PUBLIC_URL`. Everything that links back to the archive
+/// from outside of it (digests, sitemaps, link previews, absolute file urls)
+/// goes by it, and does without those links when it's not set. See
+/// `validate_public_url` for values that aren't a url.
+pub fn get_public_url() -> Option<String> {
+    env::var("MINUTEMAN_PUBLIC_URL")
+        .ok()
+        .map(|url| parse_base_url(&url))
+        .flatten()
+}
+
+/// Fails for a `MINUTEMAN_PUBLIC_URL` that `get_public_url` would ignore.
+pub fn validate_public_url() -> Result<(), String> {
+    match env::var("MINUTEMAN_PUBLIC_URL") {
+        Ok(url) if !url.trim().is_empty() && parse_base_url(&url).is_none() =>
+            Err(format!("MINUTEMAN_PUBLIC_URL isn't an http or https url: {}", url)),
+        _ => Ok(()),
+    }
+}
+
+// 32 raw bytes, or 64 hex digits with whitespace around them
+fn parse_encryption_key(
+    key: &[u8],
+) -> Option<[u8; 32]> {
+    if let Ok(key) = <[u8; 32]>::try_from(key) {
+        return Some(key);
+    }
+
+    let hex = std::str::from_utf8(key).ok()?.trim();
+
+    if hex.len() != 64 {
+        return None;
+    }
+
+    let mut parsed = [0u8; 32];
+
+    for (i, byte) in parsed.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+
+    Some(parsed)
+}
+
+fn read_encryption_key(
+    var: &str,
+) -> Result<Option<[u8; 32]>, String> {
+    let file_var = format!("{}_FILE", var);
+
+    let (source, key) =
+        match (env::var(&file_var), env::var(var)) {
+            (Ok(path), _) if !path.is_empty() =>
+                (
+                    file_var,
+                    fs::read(&path).map_err(|err| format!("can't read {}: {}", path, err))?,
+                ),
+            (_, Ok(key)) if !key.is_empty() => (var.to_string(), key.into_bytes()),
+            _ => return Ok(None),
+        };
+
+    parse_encryption_key(&key)
+        .map(Some)
+        .ok_or(format!("{} has to be 32 bytes, raw or as 64 hex digits", source))
+}
+
+/// Key the `file:*` blobs are encrypted with, read from the file named by
+/// `MINUTEMAN_ENCRYPTION_KEY_FILE` or from `MINUTEMAN_ENCRYPTION_KEY`. Either
+/// holds 32 bytes, raw or as 64 hex digits. Blobs are stored in plain
+/// without it.
+pub fn get_encryption_key() -> Result<Option<[u8; 32]>, String> {
+    read_encryption_key("MINUTEMAN_ENCRYPTION_KEY")
+}
+
+/// The key before the current one, set the same way through
+/// `MINUTEMAN_ENCRYPTION_OLD_KEY_FILE` or `MINUTEMAN_ENCRYPTION_OLD_KEY`.
+/// Blobs encrypted with it stay readable until `minuteman encryption
+/// rotate` encrypted them with the current one.
+pub fn get_old_encryption_key() -> Result<Option<[u8; 32]>, String> {
+    read_encryption_key("MINUTEMAN_ENCRYPTION_OLD_KEY")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlobBackend {
+    // inline under the `file:*` keys, like everything else
+    RocksDb,
+    Fs,
+    S3,
+}
+
+impl BlobBackend {
+    pub fn parse(
+        backend: &str,
+    ) -> Option<Self> {
+        match backend {
+            "rocksdb" => Some(BlobBackend::RocksDb),
+            "fs" => Some(BlobBackend::Fs),
+            "s3" => Some(BlobBackend::S3),
+            _ => None,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            BlobBackend::RocksDb => "rocksdb",
+            BlobBackend::Fs => "fs",
+            BlobBackend::S3 => "s3",
+        }
+    }
+}
+
+/// Where new file blobs go, `rocksdb`, `fs` or `s3` in
+/// `MINUTEMAN_BLOB_STORE`. RocksDB when unset, `validate_blob_store` turns
+/// away anything else. Blobs stored before stay where they are until
+/// `minuteman blobs migrate` moves them.
+pub fn get_blob_backend() -> BlobBackend {
+    env::var("MINUTEMAN_BLOB_STORE")
+        .ok()
+        .map(|backend| BlobBackend::parse(&backend))
+        .flatten()
+        .unwrap_or(BlobBackend::RocksDb)
+}
+
+/// Directory the `fs` blob store keeps its files in, configurable through
+/// `MINUTEMAN_BLOB_DIR`. Defaults to `files`.
+pub fn get_blob_dir() -> String {
+    env::var("MINUTEMAN_BLOB_DIR")
+        .ok()
+        .filter(|dir| !dir.is_empty())
+        .unwrap_or("files".to_string())
+}
+
+#[derive(Debug, Clone)]
+pub struct S3Config {
+    // scheme and host of the service, like https://s3.eu-central-1.amazonaws.com
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+}
+
+/// Where the `s3` blob store keeps its objects: `MINUTEMAN_S3_ENDPOINT`,
+/// `MINUTEMAN_S3_BUCKET`, `MINUTEMAN_S3_ACCESS_KEY` and
+/// `MINUTEMAN_S3_SECRET_KEY`, along with `MINUTEMAN_S3_REGION`
+/// (`us-east-1` by default). None unless all of them are set. Buckets are
+/// addressed by path, which every S3-compatible service understands.
+pub fn get_s3_config() -> Option<S3Config> {
+    let var =
+        |name: &str|
+            env::var(name)
+                .ok()
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty());
+
+    Some(
+        S3Config {
+            endpoint: parse_base_url(&var("MINUTEMAN_S3_ENDPOINT")?)?,
+            bucket: var("MINUTEMAN_S3_BUCKET")?,
+            region: var("MINUTEMAN_S3_REGION").unwrap_or("us-east-1".to_string()),
+            access_key: var("MINUTEMAN_S3_ACCESS_KEY")?,
+            secret_key: var("MINUTEMAN_S3_SECRET_KEY")?,
+        },
+    )
+}
+
+/// Fails for a `MINUTEMAN_BLOB_STORE` that isn't known, or `s3` without
+/// its settings.
+pub fn validate_blob_store() -> Result<(), String> {
+    match env::var("MINUTEMAN_BLOB_STORE").unwrap_or_default().as_str() {
+        "" => Ok(()),
+        "s3" if get_s3_config().is_none() =>
+            Err("the s3 blob store needs MINUTEMAN_S3_ENDPOINT, MINUTEMAN_S3_BUCKET, MINUTEMAN_S3_ACCESS_KEY and MINUTEMAN_S3_SECRET_KEY".to_string()),
+        backend if BlobBackend::parse(backend).is_none() =>
+            Err(format!("MINUTEMAN_BLOB_STORE has to be rocksdb, fs or s3, not {}", backend)),
+        _ => Ok(()),
+    }
+}
+
+/// Served as `/robots.txt`, set through `MINUTEMAN_ROBOTS_TXT`. Keeps every
+/// crawler out by default.
+pub fn get_robots_txt() -> String {
+    env::var("MINUTEMAN_ROBOTS_TXT")
+        .ok()
+        .filter(|robots| !robots.is_empty())
+        .unwrap_or("User-agent: *\nDisallow: /\n".to_string())
+}
+
+/// Whether `handle_message` keeps the full-text index searches use instead
+/// of scanning, off unless `MINUTEMAN_SEARCH_INDEX` is `1` or `true`.
+/// Existing messages are indexed with `minuteman index-rebuild`.
+pub fn get_search_index() -> bool {
+    env::var("MINUTEMAN_SEARCH_INDEX")
+        .map(|value| value == "1" || value == "true")
+        .unwrap_or(false)
+}
+
+/// Whether every response asks search engines not to index it, off unless
+/// `MINUTEMAN_NOINDEX` is `1` or `true`.
+pub fn get_noindex() -> bool {
+    env::var("MINUTEMAN_NOINDEX")
+        .map(|value| value == "1" || value == "true")
+        .unwrap_or(false)
+}
+
+/// Requests per second and burst each client address gets, set through
+/// `MINUTEMAN_RATE_LIMIT_RPS` and `MINUTEMAN_RATE_LIMIT_BURST`. Zero
+/// requests per second turn the limit off.
+pub fn get_rate_limit() -> Option<(f64, f64)> {
+    let requests_per_second =
+        env::var("MINUTEMAN_RATE_LIMIT_RPS")
+            .ok()
+            .map(|rps| rps.parse::<f64>().ok())
+            .flatten()
+            .unwrap_or(10.0);
+
+    let burst =
+        env::var("MINUTEMAN_RATE_LIMIT_BURST")
+            .ok()
+            .map(|burst| burst.parse::<f64>().ok())
+            .flatten()
+            .unwrap_or(30.0);
+
+    if requests_per_second > 0.0 {
+        Some((requests_per_second, burst.max(1.0)))
+    } else {
+        None
+    }
+}
+
+/// Proxies whose `X-Forwarded-For` (and `X-Forwarded-Proto` and `-Host`)
+/// are believed, comma separated addresses in `MINUTEMAN_TRUSTED_PROXIES`.
+pub fn get_trusted_proxies() -> Vec<IpAddr> {
+    env::var("MINUTEMAN_TRUSTED_PROXIES")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+        .collect()
+}
+
+/// Day listings and exports rendered at the same time, configurable through
+/// `MINUTEMAN_MAX_CONCURRENT_LISTINGS`. Zero lifts the cap.
+pub fn get_max_concurrent_listings() -> usize {
+    env::var("MINUTEMAN_MAX_CONCURRENT_LISTINGS")
+        .ok()
+        .map(|max| max.parse::<usize>().ok())
+        .flatten()
+        .unwrap_or(4)
+}
+
+/// How long one search across all chats may scan before it gives up and
+/// returns what it found so far, `MINUTEMAN_SEARCH_TIME_BUDGET_MS`.
+pub fn get_search_time_budget() -> Duration {
+    Duration::from_millis(
+        env::var("MINUTEMAN_SEARCH_TIME_BUDGET_MS")
+            .ok()
+            .map(|budget| budget.parse::<u64>().ok())
+            .flatten()
+            .unwrap_or(2_000),
+    )
+}
+
+/// Origins allowed to read the json routes from a browser, comma separated
+/// in `MINUTEMAN_CORS_ORIGINS`, `*` allows any. Without it no CORS headers
+/// are sent at all.
+pub fn get_cors_origins() -> Vec<String> {
+    env::var("MINUTEMAN_CORS_ORIGINS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|origin| origin.trim().trim_end_matches('/').to_string())
+        .filter(|origin| !origin.is_empty())
+        .collect()
+}
+
+/// How long browsers may cache a preflight answer, in seconds, configurable
+/// through `MINUTEMAN_CORS_MAX_AGE`.
+pub fn get_cors_max_age() -> u64 {
+    env::var("MINUTEMAN_CORS_MAX_AGE")
+        .ok()
+        .map(|max_age| max_age.parse::<u64>().ok())
+        .flatten()
+        .unwrap_or(600)
+}
+
+/// Whether code blocks tagged with a language get syntax highlighted, off
+/// unless `MINUTEMAN_HIGHLIGHT_CODE` is `1` or `true`. Only available in
+/// builds with the `highlight` feature.
+pub fn get_highlight_code() -> bool {
+    env::var("MINUTEMAN_HIGHLIGHT_CODE")
+        .map(|value| value == "1" || value == "true")
+        .unwrap_or(false)
+}
+
+/// Whether messages sent by the bots themselves end up in the log, off
+/// unless `MINUTEMAN_LOG_OWN_MESSAGES` is `1` or `true`.
+pub fn get_log_own_messages() -> bool {
+    env::var("MINUTEMAN_LOG_OWN_MESSAGES")
+        .map(|value| value == "1" || value == "true")
+        .unwrap_or(false)
+}
+
+/// Whether inline queries to the bots and the results people picked are
+/// recorded, off unless `MINUTEMAN_LOG_INLINE_QUERIES` is `1` or `true`.
+/// They're many and say a lot about who sent them, see `inline_queries`.
+pub fn get_log_inline_queries() -> bool {
+    env::var("MINUTEMAN_LOG_INLINE_QUERIES")
+        .map(|value| value == "1" || value == "true")
+        .unwrap_or(false)
+}
+
+/// Whether EXIF metadata (GPS position, camera) is stripped from JPEG files
+/// before they're stored, on unless `MINUTEMAN_STRIP_EXIF` is `0` or `false`.
+/// Telegram strips photos itself, but not images sent as documents.
+pub fn get_strip_exif() -> bool {
+    env::var("MINUTEMAN_STRIP_EXIF")
+        .map(|value| value != "0" && value != "false")
+        .unwrap_or(true)
+}
+
+/// How often the "latest" page of a chat reloads itself while it shows
+/// today, in seconds through `MINUTEMAN_LIVE_REFRESH_SECS`. Defaults to a
+/// minute, 0 turns reloading off.
+pub fn get_live_refresh_interval() -> Option<u64> {
+    env::var("MINUTEMAN_LIVE_REFRESH_SECS")
+        .ok()
+        .map(|secs| secs.parse::<u64>().ok())
+        .flatten()
+        .or(Some(60))
+        .filter(|secs| *secs > 0)
+}
+
+/// Attempts at a file whose download failed for a passing reason before it's
+/// marked as failed for good, set through `MINUTEMAN_FILE_RETRY_ATTEMPTS`.
+/// Defaults to 5, 0 turns retrying off.
+pub fn get_file_retry_attempts() -> u32 {
+    env::var("MINUTEMAN_FILE_RETRY_ATTEMPTS")
+        .ok()
+        .map(|attempts| attempts.parse::<u32>().ok())
+        .flatten()
+        .unwrap_or(5)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListingOrder {
+    // oldest first, the day reads top to bottom
+    Asc,
+    // newest first
+    Desc,
+}
+
+impl ListingOrder {
+    pub fn parse(
+        order: &str,
+    ) -> Option<Self> {
+        match order {
+            "asc" => Some(ListingOrder::Asc),
+            "desc" => Some(ListingOrder::Desc),
+            _ => None,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ListingOrder::Asc => "asc",
+            ListingOrder::Desc => "desc",
+        }
+    }
+}
+
+/// Order of the rows on html day pages that don't ask for one, `asc` or
+/// `desc` in `MINUTEMAN_LISTING_ORDER`. Oldest first when unset.
+pub fn get_listing_order() -> ListingOrder {
+    env::var("MINUTEMAN_LISTING_ORDER")
+        .ok()
+        .map(|order| ListingOrder::parse(&order))
+        .flatten()
+        .unwrap_or(ListingOrder::Asc)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Pretty,
+    Compact,
+    Json,
+}
+
+/// How log lines are written, `pretty`, `compact` or `json` in
+/// `MINUTEMAN_LOG_FORMAT`. Pretty when unset or unknown.
+pub fn get_log_format() -> LogFormat {
+    match env::var("MINUTEMAN_LOG_FORMAT").unwrap_or_default().as_str() {
+        "compact" => LogFormat::Compact,
+        "json" => LogFormat::Json,
+        _ => LogFormat::Pretty,
+    }
+}
+
+/// Which events are logged, a `RUST_LOG` style directive like
+/// `info,minuteman=debug` in `MINUTEMAN_LOG`, falling back to `RUST_LOG`
+/// and then to `info`.
+pub fn get_log_filter() -> String {
+    env::var("MINUTEMAN_LOG")
+        .or(env::var("RUST_LOG"))
+        .ok()
+        .filter(|directive| !directive.is_empty())
+        .unwrap_or("info".to_string())
+}
+
+/// File logs are written to instead of stdout, set through
+/// `MINUTEMAN_LOG_FILE`.
+pub fn get_log_file() -> Option<String> {
+    env::var("MINUTEMAN_LOG_FILE")
+        .ok()
+        .filter(|path| !path.is_empty())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogRotation {
+    // once the file would grow past this many bytes
+    Size(u64),
+    Daily,
+}
+
+/// When the log file is rotated, `daily` or `size` in `MINUTEMAN_LOG_ROTATE`.
+/// By size is the default, at `MINUTEMAN_LOG_MAX_MB` (64 unless set).
+pub fn get_log_rotation() -> LogRotation {
+    match env::var("MINUTEMAN_LOG_ROTATE").unwrap_or_default().as_str() {
+        "daily" => LogRotation::Daily,
+        _ =>
+            LogRotation::Size(
+                env::var("MINUTEMAN_LOG_MAX_MB")
+                    .ok()
+                    .map(|size| size.parse::<u64>().ok())
+                    .flatten()
+                    .unwrap_or(64)
+                    .max(1) * 1024 * 1024,
+            ),
+    }
+}
+
+/// Number of rotated log files kept besides the current one, configurable
+/// through `MINUTEMAN_LOG_KEEP`.
+pub fn get_log_keep() -> usize {
+    env::var("MINUTEMAN_LOG_KEEP")
+        .ok()
+        .map(|keep| keep.parse::<usize>().ok())
+        .flatten()
+        .unwrap_or(7)
+}
+
+/// Largest file of each kind that's downloaded, in bytes.
+#[derive(Debug, Clone, Copy)]
+pub struct FileSizeLimits {
+    pub photo: i64,
+    pub audio: i64,
+    pub voice: i64,
+    // documents other than animations and videos aren't downloaded yet, these
+    // two are in place for when they are
+    pub document: i64,
+    pub video: i64,
+    pub animation: i64,
+}
+
+fn get_file_size_limit(
+    kind: &str,
+) -> i64 {
+    env::var(format!("MINUTEMAN_MAX_{}_MB", kind))
+        .ok()
+        .map(|size| size.parse::<i64>().ok())
+        .flatten()
+        .map(|size| size * 1024 * 1024)
+        .unwrap_or(MAX_FILE_SIZE)
+}
+
+/// Size limits of downloaded files, set in megabytes per kind through
+/// `MINUTEMAN_MAX_PHOTO_MB`, `MINUTEMAN_MAX_AUDIO_MB`, `MINUTEMAN_MAX_VOICE_MB`,
+/// `MINUTEMAN_MAX_DOCUMENT_MB`, `MINUTEMAN_MAX_VIDEO_MB` and
+/// `MINUTEMAN_MAX_ANIMATION_MB`. Each defaults to `MAX_FILE_SIZE`.
+pub fn get_file_size_limits() -> FileSizeLimits {
+    FileSizeLimits {
+        photo: get_file_size_limit("PHOTO"),
+        audio: get_file_size_limit("AUDIO"),
+        voice: get_file_size_limit("VOICE"),
+        document: get_file_size_limit("DOCUMENT"),
+        video: get_file_size_limit("VIDEO"),
+        animation: get_file_size_limit("ANIMATION"),
+    }
+}
+
+/// Whether one-on-one chats with the bots are logged, off unless
+/// `MINUTEMAN_LOG_PRIVATE_CHATS` is `1` or `true`. People can still opt in
+/// for their own chat with `/start logging`.
+pub fn get_log_private_chats() -> bool {
+    env::var("MINUTEMAN_LOG_PRIVATE_CHATS")
+        .map(|value| value == "1" || value == "true")
+        .unwrap_or(false)
+}
+
+/// Offset from UTC of the timezone the daily digests follow, like `+02:00`
+/// or `-05:30` in `MINUTEMAN_TIMEZONE`. UTC when unset or malformed.
+pub fn get_timezone() -> FixedOffset {
+    let offset =
+        env::var("MINUTEMAN_TIMEZONE")
+            .unwrap_or_default();
+
+    let (sign, offset) =
+        match offset.trim() {
+            offset if offset.starts_with('-') => (-1, &offset[1..]),
+            offset => (1, offset.trim_start_matches('+')),
+        };
+
+    let (hours, minutes) = offset.split_once(':').unwrap_or((offset, "0"));
+
+    match (hours.parse::<i32>(), minutes.parse::<i32>()) {
+        (Ok(hours), Ok(minutes)) if hours < 24 && minutes < 60 =>
+            FixedOffset::east_opt(sign * (hours * 3_600 + minutes * 60))
+                .unwrap_or(FixedOffset::east(0)),
+        _ => FixedOffset::east(0),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BotConfig {
+    pub name: String,
+    pub token: String,
+    // chats the bot logs, every chat it's in when empty
+    pub chats: Vec<String>,
+}
+
+/// Secret named `name`, read from the file `{name}_FILE` points at when
+/// that's set (the way docker and kubernetes mount secrets), from the `name`
+/// env var otherwise. Surrounding whitespace is trimmed, unset and empty
+/// secrets are None.
+pub fn read_secret(
+    name: &str,
+) -> Result<Option<String>, MinutemanError> {
+    let secret =
+        match env::var(format!("{}_FILE", name)) {
+            Ok(path) =>
+                fs::read_to_string(&path)
+                    .map_err(|err|
+                        MinutemanError::Other(
+                            format!("can't read {}_FILE ({}): {}", name, path, err),
+                        )
+                    )?,
+            Err(_) => env::var(name).unwrap_or_default(),
+        };
+
+    let secret = secret.trim();
+
+    match secret.is_empty() {
+        true => Ok(None),
+        false => Ok(Some(secret.to_string())),
+    }
+}
+
+/// Bots run by this process. `MINUTEMAN_BOTS` holds their comma separated
+/// names, each with its token in `MINUTEMAN_BOT_{NAME}_TOKEN` (or the file
+/// `MINUTEMAN_BOT_{NAME}_TOKEN_FILE` points at) and an optional comma
+/// separated chat allowlist in `MINUTEMAN_BOT_{NAME}_CHATS`. Without it
+/// there's a single bot called "default" using `get_telegram_api_token`.
+pub fn get_bots() -> Result<Vec<BotConfig>, MinutemanError> {
+    let names =
+        env::var("MINUTEMAN_BOTS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty())
+            .collect::<Vec<String>>();
+
+    if names.is_empty() {
+        return Ok(
+            vec!(
+                BotConfig {
+                    name: "default".to_string(),
+                    token: get_telegram_api_token()?,
+                    chats: vec!(),
+                },
+            ),
+        );
+    }
+
+    names
+        .into_iter()
+        .map(|name| {
+            let prefix = format!("MINUTEMAN_BOT_{}", name.to_uppercase());
+
+            let token =
+                read_secret(&format!("{}_TOKEN", prefix))?
+                    .ok_or(
+                        MinutemanError::Other(
+                            format!("bot {} has neither {}_TOKEN nor {}_TOKEN_FILE set", name, prefix, prefix),
+                        ),
+                    )?;
+
+            Ok(
+                BotConfig {
+                    name,
+                    token,
+                    chats:
+                    env::var(format!("{}_CHATS", prefix))
+                        .unwrap_or_default()
+                        .split(',')
+                        .map(|chat_id| chat_id.trim().to_string())
+                        .filter(|chat_id| !chat_id.is_empty())
+                        .collect(),
+                },
+            )
+        })
+        .collect()
+}
+
+/// Language of the ui, `en`, `de` or `ru` in `MINUTEMAN_LANG`. Viewers can
+/// pick another one with `?lang=`. English when unset or unknown.
+pub fn get_default_lang() -> Lang {
+    env::var("MINUTEMAN_LANG")
+        .ok()
+        .map(|lang| Lang::parse(&lang))
+        .flatten()
+        .unwrap_or_default()
+}
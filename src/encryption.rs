@@ -0,0 +1,362 @@
+use std::borrow::Cow;
+
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use once_cell::sync::OnceCell;
+use rocksdb::{DBWithThreadMode, MultiThreaded, WriteBatch};
+use sha2::{Digest, Sha256};
+
+use crate::blob_store::{blob_location, fetch_stored, place_blob_in};
+use crate::config::{get_encryption_key, get_old_encryption_key};
+use crate::storage_stats::{FILE_KINDS, prefix_iter};
+
+// encrypted blobs start with it and a version, anything else is a blob
+// stored in plain before encryption was turned on
+const BLOB_MAGIC: &[u8] = b"\0mmenc";
+
+// sealed before blobs were bound to their key, they still open
+const BLOB_VERSION_UNBOUND: u8 = 1;
+
+// the database key is the associated data, a blob copied under another key
+// doesn't open
+const BLOB_VERSION: u8 = 2;
+
+const VERSION_LEN: usize = 1;
+
+const KEY_ID_LEN: usize = 4;
+
+const NONCE_LEN: usize = 24;
+
+const KEY_ID_START: usize = BLOB_MAGIC.len() + VERSION_LEN;
+
+const NONCE_START: usize = KEY_ID_START + KEY_ID_LEN;
+
+const HEADER_LEN: usize = NONCE_START + NONCE_LEN;
+
+// blobs re-encrypted per write batch by `rotate_blobs`
+const ROTATE_BATCH_SIZE: usize = 100;
+
+/// Encrypted with the current key once there is one, so that a wrong key
+/// is noticed at startup instead of on the first file that's requested.
+pub const ENCRYPTION_SENTINEL_KEY: &str = "encryption:sentinel";
+
+const SENTINEL_PLAINTEXT: &[u8] = b"minuteman";
+
+struct BlobKey {
+    // the start of the key's hash, tells which key a blob needs
+    id: [u8; KEY_ID_LEN],
+    cipher: XChaCha20Poly1305,
+}
+
+impl BlobKey {
+    fn new(
+        key: &[u8; 32],
+    ) -> Self {
+        let mut id = [0u8; KEY_ID_LEN];
+
+        id.copy_from_slice(&Sha256::digest(key)[..KEY_ID_LEN]);
+
+        BlobKey {
+            id,
+            cipher: XChaCha20Poly1305::new(Key::from_slice(key)),
+        }
+    }
+}
+
+struct BlobKeys {
+    current: Option<BlobKey>,
+    old: Option<BlobKey>,
+}
+
+static BLOB_KEYS: OnceCell<BlobKeys> = OnceCell::new();
+
+fn blob_keys() -> &'static BlobKeys {
+    BLOB_KEYS.get_or_init(||
+        BlobKeys {
+            current: None,
+            old: None,
+        }
+    )
+}
+
+fn blob_version(
+    blob: &[u8],
+) -> Option<u8> {
+    if blob.len() < HEADER_LEN || !blob.starts_with(BLOB_MAGIC) {
+        return None;
+    }
+
+    Some(blob[BLOB_MAGIC.len()])
+        .filter(|version| *version == BLOB_VERSION_UNBOUND || *version == BLOB_VERSION)
+}
+
+pub fn is_encrypted_blob(
+    blob: &[u8],
+) -> bool {
+    blob_version(blob).is_some()
+}
+
+/// What a blob is stored as under `key`: encrypted with the current key,
+/// or as it is without one. The blob only opens under that same key.
+pub fn seal_blob<'a>(
+    key: &str,
+    blob: &'a [u8],
+) -> Cow<'a, [u8]> {
+    match blob_keys().current {
+        Some(ref blob_key) => Cow::Owned(seal_blob_with(blob_key, key, blob)),
+        None => Cow::Borrowed(blob),
+    }
+}
+
+fn seal_blob_with(
+    blob_key: &BlobKey,
+    key: &str,
+    blob: &[u8],
+) -> Vec<u8> {
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+    // only fails for blobs of hundreds of gigabytes
+    let ciphertext =
+        blob_key.cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: blob,
+                    aad: key.as_bytes(),
+                },
+            )
+            .expect("blob too large to encrypt");
+
+    let mut sealed = Vec::with_capacity(HEADER_LEN + ciphertext.len());
+
+    sealed.extend_from_slice(BLOB_MAGIC);
+    sealed.push(BLOB_VERSION);
+    sealed.extend_from_slice(&blob_key.id);
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+
+    sealed
+}
+
+/// The content of the blob stored under `key`, decrypted with whichever
+/// of the keys it was encrypted with. Blobs stored in plain come back as
+/// they are.
+pub fn open_blob(
+    key: &str,
+    blob: Vec<u8>,
+) -> Result<Vec<u8>, String> {
+    open_blob_with(blob_keys(), key, blob)
+}
+
+fn open_blob_with(
+    keys: &BlobKeys,
+    key: &str,
+    blob: Vec<u8>,
+) -> Result<Vec<u8>, String> {
+    let version =
+        match blob_version(&blob) {
+            Some(version) => version,
+            None => return Ok(blob),
+        };
+
+    let id = &blob[KEY_ID_START..NONCE_START];
+    let nonce = XNonce::from_slice(&blob[NONCE_START..HEADER_LEN]);
+
+    let blob_key =
+        keys.current
+            .iter()
+            .chain(keys.old.iter())
+            .find(|blob_key| blob_key.id == id)
+            .ok_or("encrypted with a key that isn't configured".to_string())?;
+
+    let aad =
+        match version {
+            BLOB_VERSION_UNBOUND => &b""[..],
+            _ => key.as_bytes(),
+        };
+
+    blob_key.cipher
+        .decrypt(
+            nonce,
+            Payload {
+                msg: &blob[HEADER_LEN..],
+                aad,
+            },
+        )
+        .map_err(|_| "doesn't decrypt, it's damaged or belongs to another key".to_string())
+}
+
+/// Loads the configured keys and checks the current one against the
+/// sentinel, which is stored along with the first key. Has to run before
+/// anything reads or writes a blob. A secondary instance can't store the
+/// sentinel, `writable` is false for those.
+pub fn init_encryption(
+    db: &DBWithThreadMode<MultiThreaded>,
+    writable: bool,
+) -> Result<(), String> {
+    let keys =
+        BlobKeys {
+            current: get_encryption_key()?.as_ref().map(BlobKey::new),
+            old: get_old_encryption_key()?.as_ref().map(BlobKey::new),
+        };
+
+    if BLOB_KEYS.set(keys).is_err() {
+        return Err("the encryption keys were already loaded".to_string());
+    }
+
+    let keys = blob_keys();
+
+    let sentinel =
+        db.get(ENCRYPTION_SENTINEL_KEY)
+            .map_err(|err| err.to_string())?;
+
+    match (sentinel, &keys.current) {
+        (Some(sentinel), None) if is_encrypted_blob(&sentinel) =>
+            Err("files in the database are encrypted, MINUTEMAN_ENCRYPTION_KEY isn't set".to_string()),
+        (Some(sentinel), Some(_)) if is_encrypted_blob(&sentinel) => {
+            let plaintext =
+                open_blob(ENCRYPTION_SENTINEL_KEY, sentinel)
+                    .map_err(|_| "MINUTEMAN_ENCRYPTION_KEY isn't the key the database's files are encrypted with".to_string())?;
+
+            match plaintext == SENTINEL_PLAINTEXT {
+                true => Ok(()),
+                false => Err("the encryption sentinel is damaged".to_string()),
+            }
+        }
+        (None, Some(_)) if writable =>
+            db.put(ENCRYPTION_SENTINEL_KEY, seal_blob(ENCRYPTION_SENTINEL_KEY, SENTINEL_PLAINTEXT))
+                .map_err(|err| err.to_string()),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RotateSummary {
+    pub rotated: usize,
+    // already encrypted with the current key
+    pub current: usize,
+    // encrypted with neither key or missing from their store, left alone
+    pub unreadable: usize,
+}
+
+/// Encrypts every blob with the current key: those stored in plain, those
+/// encrypted with the old one and those not bound to their key yet. The
+/// sentinel moves over last, the old key isn't needed anymore afterwards.
+/// Sizes change by the header, the storage counters want a rebuild after
+/// this. Blobs stay in the store they're in.
+pub fn rotate_blobs(
+    db: &DBWithThreadMode<MultiThreaded>,
+) -> Result<RotateSummary, Box<dyn std::error::Error>> {
+    let current_id =
+        match blob_keys().current {
+            Some(ref key) => key.id,
+            None => return Err("MINUTEMAN_ENCRYPTION_KEY isn't set".into()),
+        };
+
+    let mut summary = RotateSummary::default();
+
+    for kind in FILE_KINDS.iter() {
+        let mut batch = WriteBatch::default();
+
+        for (key, value) in prefix_iter(db, &format!("file:{}:", kind)) {
+            let key = String::from_utf8_lossy(&key).to_string();
+
+            let stored =
+                blob_location(&value)
+                    .map_err(Box::<dyn std::error::Error>::from)
+                    .and_then(|location|
+                        fetch_stored(&key, value.to_vec())
+                            .map(|blob| (location, blob))
+                    );
+
+            let (location, blob) =
+                match stored {
+                    Ok(stored) => stored,
+                    Err(err) => {
+                        tracing::warn!(key = %key, error = %err, "can't read blob to rotate");
+
+                        summary.unreadable += 1;
+
+                        continue;
+                    }
+                };
+
+            if blob_version(&blob) == Some(BLOB_VERSION) && blob[KEY_ID_START..NONCE_START] == current_id {
+                summary.current += 1;
+
+                continue;
+            }
+
+            let blob =
+                match open_blob(&key, blob) {
+                    Ok(blob) => blob,
+                    Err(err) => {
+                        tracing::warn!(key = %key, error = %err, "can't decrypt blob to rotate");
+
+                        summary.unreadable += 1;
+
+                        continue;
+                    }
+                };
+
+            batch.put(&key, place_blob_in(location, &key, &blob)?);
+
+            summary.rotated += 1;
+
+            if batch.len() >= ROTATE_BATCH_SIZE {
+                db.write(std::mem::take(&mut batch))?;
+            }
+        }
+
+        db.write(batch)?;
+    }
+
+    db.put(ENCRYPTION_SENTINEL_KEY, seal_blob(ENCRYPTION_SENTINEL_KEY, SENTINEL_PLAINTEXT))?;
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys() -> BlobKeys {
+        BlobKeys {
+            current: Some(BlobKey::new(&[7u8; 32])),
+            old: None,
+        }
+    }
+
+    #[test]
+    fn sealed_blobs_only_open_under_their_key() {
+        let keys = keys();
+        let sealed = seal_blob_with(keys.current.as_ref().unwrap(), "file:photo:1", b"photo");
+
+        assert!(is_encrypted_blob(&sealed));
+        assert_eq!(open_blob_with(&keys, "file:photo:1", sealed.clone()).unwrap(), b"photo");
+        assert!(open_blob_with(&keys, "file:photo:2", sealed).is_err());
+    }
+
+    #[test]
+    fn blobs_sealed_before_binding_still_open() {
+        let keys = keys();
+        let blob_key = keys.current.as_ref().unwrap();
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+        let mut sealed = BLOB_MAGIC.to_vec();
+
+        sealed.push(BLOB_VERSION_UNBOUND);
+        sealed.extend_from_slice(&blob_key.id);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&blob_key.cipher.encrypt(&nonce, &b"photo"[..]).unwrap());
+
+        assert!(is_encrypted_blob(&sealed));
+        assert_eq!(open_blob_with(&keys, "file:photo:1", sealed).unwrap(), b"photo");
+    }
+
+    #[test]
+    fn plain_blobs_pass_through() {
+        assert!(!is_encrypted_blob(b"photo"));
+        assert_eq!(open_blob_with(&keys(), "file:photo:1", b"photo".to_vec()).unwrap(), b"photo");
+    }
+}
@@ -0,0 +1,143 @@
+// markers without a length, everything else between SOI and SOS has one
+fn is_standalone_marker(
+    marker: u8,
+) -> bool {
+    marker == 0x01 || (0xd0..=0xd7).contains(&marker)
+}
+
+const APP1: u8 = 0xe1;
+
+/// Drops the APP1 segments of a JPEG, which hold EXIF (and XMP) metadata
+/// like GPS coordinates and the camera used. The image data isn't touched,
+/// so nothing is recompressed. None when `file` isn't a JPEG this can make
+/// sense of, or has no APP1 segment to begin with.
+pub fn strip_jpeg_metadata(
+    file: &[u8],
+) -> Option<Vec<u8>> {
+    if !file.starts_with(&[0xff, 0xd8]) {
+        return None;
+    }
+
+    let mut out = Vec::with_capacity(file.len());
+    let mut stripped = false;
+    let mut pos = 2;
+
+    out.extend_from_slice(&file[..2]);
+
+    loop {
+        if *file.get(pos)? != 0xff {
+            return None;
+        }
+
+        // any number of fill bytes may precede a marker
+        let mut marker_pos = pos + 1;
+
+        while *file.get(marker_pos)? == 0xff {
+            marker_pos += 1;
+        }
+
+        let marker = file[marker_pos];
+
+        // the entropy coded data and whatever follows is copied as is
+        if marker == 0xda || marker == 0xd9 {
+            out.extend_from_slice(&file[pos..]);
+
+            break;
+        }
+
+        if is_standalone_marker(marker) {
+            out.extend_from_slice(&file[pos..marker_pos + 1]);
+            pos = marker_pos + 1;
+
+            continue;
+        }
+
+        let length = u16::from_be_bytes([*file.get(marker_pos + 1)?, *file.get(marker_pos + 2)?]) as usize;
+
+        if length < 2 {
+            return None;
+        }
+
+        let end = marker_pos + 1 + length;
+
+        if end > file.len() {
+            return None;
+        }
+
+        if marker == APP1 {
+            stripped = true;
+        } else {
+            out.extend_from_slice(&file[pos..end]);
+        }
+
+        pos = end;
+    }
+
+    if stripped {
+        Some(out)
+    } else {
+        None
+    }
+}
+
+/// `strip_jpeg_metadata` for blobs about to be stored: the stripped image
+/// if it still decodes, or None to store the original.
+pub fn strip_image_metadata(
+    file: &[u8],
+) -> Option<Vec<u8>> {
+    let stripped = strip_jpeg_metadata(file)?;
+
+    image::load_from_memory(&stripped)
+        .ok()
+        .map(|_| stripped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // an 8x8 gray JPEG with a JFIF header and an EXIF segment holding GPS
+    // coordinates, the way phone cameras write them
+    const GPS_EXIF_JPEG: &[u8] = include_bytes!("../tests/fixtures/gps_exif.jpg");
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+    }
+
+    #[test]
+    fn strips_the_gps_exif_of_the_fixture() {
+        assert!(contains(GPS_EXIF_JPEG, b"Exif\0\0"));
+
+        let stripped = strip_jpeg_metadata(GPS_EXIF_JPEG).unwrap();
+
+        assert!(!contains(&stripped, b"Exif\0\0"));
+        assert!(!contains(&stripped, &[0xff, APP1]));
+        assert!(contains(&stripped, b"JFIF\0"));
+        assert!(stripped.len() < GPS_EXIF_JPEG.len());
+        assert!(stripped.ends_with(&[0xff, 0xd9]));
+    }
+
+    #[test]
+    fn stripped_fixture_still_decodes() {
+        let stripped = strip_image_metadata(GPS_EXIF_JPEG).unwrap();
+
+        let image = image::load_from_memory(&stripped).unwrap();
+
+        assert_eq!((image.width(), image.height()), (8, 8));
+    }
+
+    #[test]
+    fn leaves_files_without_exif_alone() {
+        let stripped = strip_jpeg_metadata(GPS_EXIF_JPEG).unwrap();
+
+        assert_eq!(strip_jpeg_metadata(&stripped), None);
+        assert_eq!(strip_jpeg_metadata(b"\x89PNG\r\n\x1a\n"), None);
+    }
+
+    #[test]
+    fn gives_up_on_truncated_files() {
+        assert_eq!(strip_jpeg_metadata(&GPS_EXIF_JPEG[..40]), None);
+    }
+}
@@ -1,16 +1,50 @@
 #![feature(backtrace)]
 #![feature(async_closure)]
 #![feature(thread_id_value)]
+#![recursion_limit = "256"]
 
+pub use prelude::API_ERROR_RATE_THRESHOLD;
+pub use prelude::API_HEALTH_MIN_CALLS;
+pub use prelude::API_HEALTH_WINDOW;
+pub use prelude::CATCHUP_LAG_THRESHOLD;
+pub use prelude::CATCHUP_LOG_INTERVAL;
+pub use prelude::DEFAULT_LISTING_LIMIT;
 pub use prelude::get_telegram_api_token;
 pub use prelude::GLOBAL_CSS;
+pub use prelude::INACTIVE_CHAT_DAYS;
+pub use prelude::GLOBAL_CSS_HASH;
 pub use prelude::JOB_SLEEP_INTERVAL;
+pub use prelude::MAX_LISTING_LIMIT;
+pub use prelude::MAX_REQUEST_BODY_SIZE;
+pub use prelude::MAX_RESTART_BACKOFF;
 pub use prelude::MAX_FILE_SIZE;
+pub use prelude::META_CACHE_TTL;
 pub use prelude::MinutemanError;
+pub use prelude::RENDER_CACHE_TTL;
+pub use prelude::RESTART_BACKOFF_RESET;
+pub use prelude::SITEMAP_CHUNK_SIZE;
+pub use prelude::USERNAME_ALIAS_GRACE_PERIOD;
+pub use prelude::VACUUM_MIN_FILE_AGE;
 
 pub mod workers;
 pub mod utils;
 pub mod renderer;
 pub mod prelude;
 pub mod components;
+pub mod blob_store;
 pub mod config;
+pub mod encryption;
+pub mod exif;
+pub mod locales;
+pub mod logging;
+pub mod metrics;
+pub mod privacy;
+pub mod cli;
+pub mod migrations;
+pub mod rate_limit;
+pub mod render_cache;
+pub mod search_index;
+pub mod share;
+pub mod storage;
+pub mod storage_stats;
+pub mod thumbnail;
@@ -0,0 +1,320 @@
+use chrono::Weekday;
+
+/// Language of the ui strings, `MINUTEMAN_LANG` by default and overridden
+/// per viewer through `?lang=`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Lang {
+    En,
+    De,
+    Ru,
+}
+
+impl Default for Lang {
+    fn default() -> Self {
+        Lang::En
+    }
+}
+
+impl Lang {
+    pub fn parse(
+        code: &str,
+    ) -> Option<Self> {
+        match code.to_lowercase().as_str() {
+            "en" => Some(Lang::En),
+            "de" => Some(Lang::De),
+            "ru" => Some(Lang::Ru),
+            _ => None,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Lang::En => "en",
+            Lang::De => "de",
+            Lang::Ru => "ru",
+        }
+    }
+
+    fn table(&self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Lang::En => EN,
+            Lang::De => DE,
+            Lang::Ru => RU,
+        }
+    }
+}
+
+// English is the reference, every key used anywhere has to be in here
+static EN: &[(&str, &str)] = &[
+    ("nav.home", "<- home"),
+    ("nav.groups", "groups"),
+    ("nav.all", "all"),
+    ("nav.storage", "storage"),
+    ("nav.index", "index"),
+    ("nav.latest", "latest"),
+    ("nav.live", "live"),
+    ("nav.info", "info"),
+    ("nav.pins", "pins"),
+    ("nav.media", "media"),
+    ("nav.week", "week"),
+    ("nav.month", "month"),
+    ("nav.search", "search"),
+    ("nav.previous", "previous"),
+    ("nav.next", "next"),
+    ("nav.newer", "newer"),
+    ("nav.older", "older"),
+    ("nav.newer_days", "newer days"),
+    ("nav.older_days", "older days"),
+    ("nav.newer_messages", "newer messages"),
+    ("nav.older_messages", "older messages"),
+    ("nav.all_days", "all days"),
+    ("nav.go", "go"),
+    ("nav.current_names", "current names"),
+    ("nav.historical_names", "names at the time"),
+    ("event.joined", "joined the chat"),
+    ("event.left", "left the chat"),
+    ("event.banned", "was banned"),
+    ("event.unbanned", "was unbanned"),
+    ("event.promoted", "was promoted to admin"),
+    ("event.demoted", "is no longer an admin"),
+    ("event.by", "by"),
+    ("media.no_caption", "Message has no caption."),
+    ("media.too_large", "attachment too large to archive"),
+    ("media.too_big_for_bots", "file exceeded Telegram's 20 MB bot download limit"),
+    ("media.invalid_reference", "file is no longer available from Telegram"),
+    ("media.retrying", "download failed, will retry"),
+    ("media.failed", "download failed"),
+    ("media.sticker_unsupported", "animated sticker, can't be shown here"),
+    ("forward.from", "forwarded from"),
+    ("empty.not_archived", "this chat is not archived"),
+    ("empty.chat", "no messages logged yet"),
+    ("empty.day", "no messages logged for this day"),
+    ("empty.nearest", "nearest logged days:"),
+    ("weekday.mon", "Monday"),
+    ("weekday.tue", "Tuesday"),
+    ("weekday.wed", "Wednesday"),
+    ("weekday.thu", "Thursday"),
+    ("weekday.fri", "Friday"),
+    ("weekday.sat", "Saturday"),
+    ("weekday.sun", "Sunday"),
+];
+
+static DE: &[(&str, &str)] = &[
+    ("nav.home", "<- Start"),
+    ("nav.groups", "Gruppen"),
+    ("nav.all", "alle"),
+    ("nav.storage", "Speicher"),
+    ("nav.index", "Übersicht"),
+    ("nav.latest", "neueste"),
+    ("nav.live", "live"),
+    ("nav.info", "Info"),
+    ("nav.pins", "Angeheftet"),
+    ("nav.media", "Medien"),
+    ("nav.week", "Woche"),
+    ("nav.month", "Monat"),
+    ("nav.search", "Suche"),
+    ("nav.previous", "vorheriger"),
+    ("nav.next", "nächster"),
+    ("nav.newer", "neuere"),
+    ("nav.older", "ältere"),
+    ("nav.newer_days", "neuere Tage"),
+    ("nav.older_days", "ältere Tage"),
+    ("nav.newer_messages", "neuere Nachrichten"),
+    ("nav.older_messages", "ältere Nachrichten"),
+    ("nav.all_days", "alle Tage"),
+    ("nav.go", "los"),
+    ("nav.current_names", "aktuelle Namen"),
+    ("nav.historical_names", "Namen von damals"),
+    ("event.joined", "ist dem Chat beigetreten"),
+    ("event.left", "hat den Chat verlassen"),
+    ("event.banned", "wurde gesperrt"),
+    ("event.unbanned", "wurde entsperrt"),
+    ("event.promoted", "wurde zum Admin ernannt"),
+    ("event.demoted", "ist kein Admin mehr"),
+    ("event.by", "von"),
+    ("media.no_caption", "Nachricht hat keine Bildunterschrift."),
+    ("media.too_large", "Anhang zu groß zum Archivieren"),
+    ("media.too_big_for_bots", "Datei überschreitet Telegrams Downloadlimit von 20 MB für Bots"),
+    ("media.invalid_reference", "Datei ist bei Telegram nicht mehr verfügbar"),
+    ("media.retrying", "Download fehlgeschlagen, wird erneut versucht"),
+    ("media.failed", "Download fehlgeschlagen"),
+    ("media.sticker_unsupported", "animierter Sticker, kann hier nicht angezeigt werden"),
+    ("forward.from", "weitergeleitet von"),
+    ("empty.not_archived", "dieser Chat wird nicht archiviert"),
+    ("empty.chat", "noch keine Nachrichten protokolliert"),
+    ("empty.day", "an diesem Tag wurden keine Nachrichten protokolliert"),
+    ("empty.nearest", "nächste Tage mit Nachrichten:"),
+    ("weekday.mon", "Montag"),
+    ("weekday.tue", "Dienstag"),
+    ("weekday.wed", "Mittwoch"),
+    ("weekday.thu", "Donnerstag"),
+    ("weekday.fri", "Freitag"),
+    ("weekday.sat", "Samstag"),
+    ("weekday.sun", "Sonntag"),
+];
+
+static RU: &[(&str, &str)] = &[
+    ("nav.home", "<- главная"),
+    ("nav.groups", "группы"),
+    ("nav.all", "все"),
+    ("nav.storage", "хранилище"),
+    ("nav.index", "оглавление"),
+    ("nav.latest", "последний"),
+    ("nav.live", "в эфире"),
+    ("nav.info", "инфо"),
+    ("nav.pins", "закреплённые"),
+    ("nav.media", "медиа"),
+    ("nav.week", "неделя"),
+    ("nav.month", "месяц"),
+    ("nav.search", "поиск"),
+    ("nav.previous", "назад"),
+    ("nav.next", "вперёд"),
+    ("nav.newer", "новее"),
+    ("nav.older", "старее"),
+    ("nav.newer_days", "более новые дни"),
+    ("nav.older_days", "более старые дни"),
+    ("nav.newer_messages", "более новые сообщения"),
+    ("nav.older_messages", "более старые сообщения"),
+    ("nav.all_days", "все дни"),
+    ("nav.go", "перейти"),
+    ("nav.current_names", "текущие имена"),
+    ("nav.historical_names", "имена на тот момент"),
+    ("event.joined", "вступил(а) в чат"),
+    ("event.left", "покинул(а) чат"),
+    ("event.banned", "заблокирован(а)"),
+    ("event.unbanned", "разблокирован(а)"),
+    ("event.promoted", "назначен(а) администратором"),
+    ("event.demoted", "больше не администратор"),
+    ("event.by", "кем:"),
+    ("media.no_caption", "У сообщения нет подписи."),
+    ("media.too_large", "вложение слишком большое для архива"),
+    ("media.too_big_for_bots", "файл превышает лимит Telegram в 20 МБ на загрузку ботами"),
+    ("media.invalid_reference", "файл больше не доступен в Telegram"),
+    ("media.retrying", "загрузка не удалась, будет повторена"),
+    ("media.failed", "загрузка не удалась"),
+    ("media.sticker_unsupported", "анимированный стикер, здесь не отображается"),
+    ("forward.from", "переслано от"),
+    ("empty.not_archived", "этот чат не архивируется"),
+    ("empty.chat", "сообщений пока нет"),
+    ("empty.day", "за этот день сообщений нет"),
+    ("empty.nearest", "ближайшие дни с сообщениями:"),
+    ("weekday.mon", "понедельник"),
+    ("weekday.tue", "вторник"),
+    ("weekday.wed", "среда"),
+    ("weekday.thu", "четверг"),
+    ("weekday.fri", "пятница"),
+    ("weekday.sat", "суббота"),
+    ("weekday.sun", "воскресенье"),
+];
+
+fn lookup(
+    table: &'static [(&'static str, &'static str)],
+    key: &str,
+) -> Option<&'static str> {
+    table
+        .iter()
+        .find(|(candidate, _)| *candidate == key)
+        .map(|(_, text)| *text)
+}
+
+/// The ui string `key` in `lang`, the English one where the language lacks
+/// it and the key itself for a key nobody knows.
+pub fn t(
+    lang: Lang,
+    key: &'static str,
+) -> &'static str {
+    translate(lang.table(), key)
+}
+
+fn translate(
+    table: &'static [(&'static str, &'static str)],
+    key: &'static str,
+) -> &'static str {
+    lookup(table, key)
+        .or_else(|| lookup(EN, key))
+        .unwrap_or(key)
+}
+
+pub fn weekday_name(
+    lang: Lang,
+    weekday: Weekday,
+) -> &'static str {
+    t(
+        lang,
+        match weekday {
+            Weekday::Mon => "weekday.mon",
+            Weekday::Tue => "weekday.tue",
+            Weekday::Wed => "weekday.wed",
+            Weekday::Thu => "weekday.thu",
+            Weekday::Fri => "weekday.fri",
+            Weekday::Sat => "weekday.sat",
+            Weekday::Sun => "weekday.sun",
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LANGS: [Lang; 3] = [Lang::En, Lang::De, Lang::Ru];
+
+    #[test]
+    fn every_language_has_every_english_key() {
+        for lang in LANGS {
+            for (key, _) in EN {
+                assert!(lookup(lang.table(), key).is_some(), "{} lacks {}", lang.code(), key);
+            }
+        }
+    }
+
+    #[test]
+    fn no_language_has_keys_english_lacks() {
+        for lang in LANGS {
+            for (key, _) in lang.table() {
+                assert!(lookup(EN, key).is_some(), "{} has unknown key {}", lang.code(), key);
+            }
+        }
+    }
+
+    #[test]
+    fn keys_are_unique() {
+        for lang in LANGS {
+            for (index, (key, _)) in lang.table().iter().enumerate() {
+                assert!(
+                    lang.table()[index + 1..].iter().all(|(other, _)| other != key),
+                    "{} has {} twice",
+                    lang.code(),
+                    key,
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn missing_keys_fall_back_to_english() {
+        static PARTIAL: &[(&str, &str)] = &[
+            ("nav.home", "<- start"),
+        ];
+
+        assert_eq!(translate(PARTIAL, "nav.home"), "<- start");
+        assert_eq!(translate(PARTIAL, "nav.groups"), "groups");
+    }
+
+    #[test]
+    fn unknown_keys_fall_back_to_the_key() {
+        for lang in LANGS {
+            assert_eq!(t(lang, "no.such.key"), "no.such.key");
+        }
+    }
+
+    #[test]
+    fn codes_parse_back() {
+        for lang in LANGS {
+            assert_eq!(Lang::parse(lang.code()), Some(lang));
+            assert_eq!(Lang::parse(&lang.code().to_uppercase()), Some(lang));
+        }
+
+        assert_eq!(Lang::parse("fr"), None);
+    }
+}
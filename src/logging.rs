@@ -0,0 +1,166 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::Mutex;
+
+use chrono::{NaiveDate, Utc};
+use once_cell::sync::OnceCell;
+use tracing_subscriber::{EnvFilter, fmt, Layer, Registry, reload};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+use crate::config::{get_log_file, get_log_filter, get_log_format, get_log_keep, get_log_rotation, LogFormat, LogRotation};
+
+static LOG_FILTER: OnceCell<reload::Handle<EnvFilter, Registry>> = OnceCell::new();
+
+/// The log file, moved to `{path}.1` (and the older ones one further) once
+/// it's due for rotation. `{path}.{keep}` is the oldest one kept.
+pub struct RotatingFile {
+    path: String,
+    rotation: LogRotation,
+    keep: usize,
+    file: File,
+    size: u64,
+    opened_on: NaiveDate,
+}
+
+impl RotatingFile {
+    pub fn open(
+        path: &str,
+        rotation: LogRotation,
+        keep: usize,
+    ) -> io::Result<Self> {
+        let file =
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?;
+
+        let size = file.metadata()?.len();
+
+        Ok(
+            RotatingFile {
+                path: path.to_string(),
+                rotation,
+                keep,
+                file,
+                size,
+                opened_on: Utc::today().naive_utc(),
+            },
+        )
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        // the oldest one falls off the end, a missing one is fine
+        for i in (1..=self.keep).rev() {
+            let from =
+                match i {
+                    1 => self.path.clone(),
+                    _ => format!("{}.{}", self.path, i - 1),
+                };
+
+            if let Err(err) = fs::rename(&from, format!("{}.{}", self.path, i)) {
+                if err.kind() != io::ErrorKind::NotFound {
+                    return Err(err);
+                }
+            }
+        }
+
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        }
+
+        *self = RotatingFile::open(&self.path, self.rotation, self.keep)?;
+
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let due =
+            match self.rotation {
+                // a single line larger than the limit still ends up somewhere
+                LogRotation::Size(max_size) => self.size > 0 && self.size + buf.len() as u64 > max_size,
+                LogRotation::Daily => Utc::today().naive_utc() != self.opened_on,
+            };
+
+        if due {
+            self.rotate()?;
+        }
+
+        let written = self.file.write(buf)?;
+
+        self.size += written as u64;
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Sets up logging as configured, before any worker starts. Json lines
+/// carry the fields of the spans they happened in (the worker, and the
+/// chat and message being ingested) so that they can be filtered on.
+pub fn init_logging() -> Result<(), Box<dyn std::error::Error>> {
+    let (filter, handle) = reload::Layer::new(EnvFilter::try_new(get_log_filter())?);
+
+    let file = get_log_file();
+
+    let writer =
+        match file {
+            Some(ref path) =>
+                BoxMakeWriter::new(
+                    Mutex::new(
+                        RotatingFile::open(path, get_log_rotation(), get_log_keep())?,
+                    ),
+                ),
+            None => BoxMakeWriter::new(io::stdout),
+        };
+
+    // colors are for terminals
+    let ansi = file.is_none();
+
+    let layer: Box<dyn Layer<_> + Send + Sync> =
+        match get_log_format() {
+            LogFormat::Pretty => Box::new(fmt::layer().pretty().with_ansi(ansi).with_writer(writer)),
+            LogFormat::Compact => Box::new(fmt::layer().compact().with_ansi(ansi).with_writer(writer)),
+            LogFormat::Json =>
+                Box::new(
+                    fmt::layer()
+                        .json()
+                        .with_current_span(true)
+                        .with_span_list(true)
+                        .with_writer(writer),
+                ),
+        };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(layer)
+        .try_init()?;
+
+    let _ = LOG_FILTER.set(handle);
+
+    Ok(())
+}
+
+/// Swaps the filter of the running process for `directive`, see
+/// `get_log_filter`.
+pub fn set_log_filter(
+    directive: &str,
+) -> Result<(), String> {
+    let filter =
+        EnvFilter::try_new(directive)
+            .map_err(|err| err.to_string())?;
+
+    LOG_FILTER
+        .get()
+        .ok_or("logging isn't set up".to_string())?
+        .reload(filter)
+        .map_err(|err| err.to_string())
+}
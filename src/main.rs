@@ -1,8 +1,8 @@
 #![feature(backtrace)]
 #![feature(async_closure)]
 #![feature(thread_id_value)]
+#![recursion_limit = "256"]
 
-use std::backtrace::Backtrace;
 use std::collections::HashMap;
 use std::env;
 use std::sync::{Arc, Mutex};
@@ -13,31 +13,251 @@ use pw_telegram_bot_fork::*;
 use rocksdb::{DBAccess, DBWithThreadMode, MultiThreaded, SingleThreaded, ThreadMode};
 use serde::{Deserialize, Serialize};
 use tokio::runtime::Runtime;
+use tracing::Instrument;
 
+use crate::storage::{lock_db, Storage};
+
+pub use prelude::API_ERROR_RATE_THRESHOLD;
+pub use prelude::API_HEALTH_MIN_CALLS;
+pub use prelude::API_HEALTH_WINDOW;
+pub use prelude::CATCHUP_LAG_THRESHOLD;
+pub use prelude::CATCHUP_LOG_INTERVAL;
+pub use prelude::DEFAULT_LISTING_LIMIT;
 pub use prelude::get_telegram_api_token;
 pub use prelude::GLOBAL_CSS;
+pub use prelude::INACTIVE_CHAT_DAYS;
+pub use prelude::GLOBAL_CSS_HASH;
 pub use prelude::JOB_SLEEP_INTERVAL;
+pub use prelude::MAX_LISTING_LIMIT;
+pub use prelude::MAX_REQUEST_BODY_SIZE;
+pub use prelude::MAX_RESTART_BACKOFF;
 pub use prelude::MAX_FILE_SIZE;
+pub use prelude::META_CACHE_TTL;
 pub use prelude::MinutemanError;
+pub use prelude::RENDER_CACHE_TTL;
+pub use prelude::RESTART_BACKOFF_RESET;
+pub use prelude::SITEMAP_CHUNK_SIZE;
+pub use prelude::USERNAME_ALIAS_GRACE_PERIOD;
+pub use prelude::VACUUM_MIN_FILE_AGE;
 
 pub mod workers;
 pub mod utils;
 pub mod renderer;
 pub mod prelude;
 pub mod components;
+pub mod blob_store;
 pub mod config;
+pub mod encryption;
+pub mod exif;
+pub mod locales;
+pub mod logging;
+pub mod metrics;
+pub mod privacy;
+pub mod cli;
+pub mod migrations;
+pub mod rate_limit;
+pub mod render_cache;
+pub mod search_index;
+pub mod share;
+pub mod storage;
+pub mod storage_stats;
+pub mod thumbnail;
+
+fn validate_bots() -> Result<Vec<config::BotConfig>, MinutemanError> {
+    let bots = config::get_bots()?;
+
+    let rt =
+        Runtime::new()
+            .map_err(|err| MinutemanError::Other(err.to_string()))?;
+
+    for bot in bots.iter() {
+        let me =
+            rt.block_on(
+                workers::telegram_handler::validate_bot(
+                    &workers::telegram_handler::Bot::new(bot),
+                ),
+            )?;
+
+        println!(
+            "bot {} logged in as @{}",
+            bot.name,
+            me.username.unwrap_or(me.first_name),
+        );
+    }
+
+    Ok(bots)
+}
+
+/// Only the web server, on a secondary instance of the database of a bot
+/// running elsewhere, see `get_secondary_of`.
+fn run_frontend(
+    primary_path: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let db =
+        Arc::new(
+            Mutex::new(
+                match DBWithThreadMode::<MultiThreaded>::open_secondary(primary_path, &config::get_secondary_dir()) {
+                    Ok(db) => db,
+                    Err(err) => {
+                        eprintln!("can't open the database at {} as a secondary: {}", primary_path, err);
+
+                        std::process::exit(1);
+                    }
+                },
+            ),
+        );
+
+    workers::chat_policy::load_chat_policies(&*lock_db(&db));
+
+    if let Err(err) = encryption::init_encryption(&lock_db(&db), false) {
+        eprintln!("can't start: {}", err);
+
+        std::process::exit(1);
+    }
+
+    // blobs moved out of the database are read from there
+    if let Err(err) = config::validate_blob_store() {
+        eprintln!("can't start: {}", err);
+
+        std::process::exit(1);
+    }
+
+    if let Err(err) = config::validate_public_url() {
+        eprintln!("can't start: {}", err);
+
+        std::process::exit(1);
+    }
+
+    let secondary_db = db.clone();
+
+    thread::spawn(
+        move || {
+            tracing::info!(
+                worker = "secondary",
+                primary = %config::get_secondary_of().unwrap_or_default(),
+                "online",
+            );
+
+            if let Ok(rt) = Runtime::new() {
+                rt.block_on(
+                    workers::secondary::spawn_worker(secondary_db)
+                        .instrument(tracing::info_span!("worker", worker = "secondary")),
+                );
+            }
+        }
+    );
+
+    loop {
+        let db = db.clone();
+
+        let th = thread::spawn(
+            move || {
+                tracing::info!(
+                    worker = "server_handler",
+                    thread = thread::current().id().as_u64(),
+                    "online",
+                );
+
+                if let Ok(rt) = Runtime::new() {
+                    rt.block_on(
+                        workers::server_handler::spawn_worker(
+                            db.clone(),
+                        )
+                            .instrument(tracing::info_span!("worker", worker = "server_handler")),
+                    );
+                }
+            }
+        );
+
+        let thread_id = th.thread().id().as_u64();
+
+        th.join();
+
+        tracing::warn!(
+            worker = "server_handler",
+            thread = thread_id,
+            "died, restarting",
+        );
+    }
+}
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::fmt().pretty().init();
+    logging::init_logging()?;
+
+    metrics::record_process_start();
+
+    let args = env::args().skip(1).collect::<Vec<String>>();
+
+    // these read a database another instance may be running on, they don't
+    // take the lock
+    if cli::is_read_only_command(&args) {
+        return cli::run_read_only(&args);
+    }
+
+    if let Some(primary_path) = config::get_secondary_of() {
+        return run_frontend(&primary_path);
+    }
 
     let mut db =
         Arc::new(
             Mutex::new(
-                rocksdb::DB::open_default("db")
-                    .unwrap(),
+                match rocksdb::DB::open_default("db") {
+                    Ok(db) => db,
+                    // locked by another instance or corrupt, retrying won't help
+                    Err(err) => {
+                        eprintln!("can't open the database at ./db: {}", err);
+
+                        std::process::exit(1);
+                    }
+                },
             ),
         );
 
+    // blobs are read and written from here on, a wrong key has to stop it
+    if let Err(err) = encryption::init_encryption(&lock_db(&db), true) {
+        eprintln!("can't start: {}", err);
+
+        std::process::exit(1);
+    }
+
+    // the migrations may already store blobs
+    if let Err(err) = config::validate_blob_store() {
+        eprintln!("can't start: {}", err);
+
+        std::process::exit(1);
+    }
+
+    // before anything runs that could panic
+    workers::panics::install_panic_hook(db.clone());
+
+    migrations::run_migrations(db.clone())?;
+
+    workers::chat_policy::load_chat_policies(&*lock_db(&db));
+
+    // maintenance subcommands run against the database and exit
+    if !args.is_empty() {
+        return cli::run(db, &args);
+    }
+
+    // a missing or revoked token is reported once here instead of making
+    // the respawning handlers fail over and over
+    let bots =
+        match validate_bots() {
+            Ok(bots) => bots,
+            Err(err) => {
+                eprintln!("can't start: {:?}", err);
+
+                std::process::exit(1);
+            }
+        };
+
+    // links to the archive would quietly go missing otherwise
+    if let Err(err) = config::validate_public_url() {
+        eprintln!("can't start: {}", err);
+
+        std::process::exit(1);
+    }
+
     let server_db = db.clone();
 
     thread::spawn(
@@ -49,16 +269,18 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
                 let th = thread::spawn(
                     move || {
-                        println!(
-                            "[{}] server_handler online",
-                            thread::current().id().as_u64(),
+                        tracing::info!(
+                            worker = "server_handler",
+                            thread = thread::current().id().as_u64(),
+                            "online",
                         );
 
                         if let Ok(rt) = Runtime::new() {
                             rt.block_on(
                                 workers::server_handler::spawn_worker(
                                     db.clone(),
-                                ),
+                                )
+                                    .instrument(tracing::info_span!("worker", worker = "server_handler")),
                             );
                         }
                     }
@@ -68,35 +290,38 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
                 th.join();
 
-                println!(
-                    "[{}] server_handler died, restarting..",
-                    thread_id,
+                tracing::warn!(
+                    worker = "server_handler",
+                    thread = thread_id,
+                    "died, restarting",
                 );
             }
         }
     );
 
-    let telegram_db = db.clone();
+    let user_meta_db = db.clone();
 
     thread::spawn(
         move || {
-            let db = telegram_db.clone();
+            let db = user_meta_db.clone();
 
             loop {
                 let db = db.clone();
 
                 let th = thread::spawn(
                     move || {
-                        println!(
-                            "[{}] telegram_handler online",
-                            thread::current().id().as_u64(),
+                        tracing::info!(
+                            worker = "user_meta_handler",
+                            thread = thread::current().id().as_u64(),
+                            "online",
                         );
 
                         if let Ok(rt) = Runtime::new() {
                             rt.block_on(
-                                workers::telegram_handler::spawn_worker(
+                                workers::user_meta_handler::spawn_worker(
                                     db.clone(),
-                                ),
+                                )
+                                    .instrument(tracing::info_span!("worker", worker = "user_meta_handler")),
                             );
                         }
                     }
@@ -106,13 +331,203 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
                 th.join();
 
-                println!(
-                    "[{}] telegram_handler died, restarting..",
-                    thread_id,
+                tracing::warn!(
+                    worker = "user_meta_handler",
+                    thread = thread_id,
+                    "died, restarting",
                 );
             }
         }
-    ).join().unwrap();
+    );
+
+    if config::get_backup_dir().is_some() {
+        let backup_db = db.clone();
+
+        thread::spawn(
+            move || {
+                let db = backup_db.clone();
+
+                loop {
+                    let db = db.clone();
+
+                    let th = thread::spawn(
+                        move || {
+                            tracing::info!(
+                                worker = "backup_handler",
+                                thread = thread::current().id().as_u64(),
+                                "online",
+                            );
+
+                            if let Ok(rt) = Runtime::new() {
+                                rt.block_on(
+                                    workers::backup_handler::spawn_worker(
+                                        db.clone(),
+                                    )
+                                        .instrument(tracing::info_span!("worker", worker = "backup_handler")),
+                                );
+                            }
+                        }
+                    );
+
+                    let thread_id = th.thread().id().as_u64();
+
+                    th.join();
+
+                    tracing::warn!(
+                        worker = "backup_handler",
+                        thread = thread_id,
+                        "died, restarting",
+                    );
+                }
+            }
+        );
+    }
+
+    if config::get_verify_files_interval().is_some() {
+        let file_verifier_db = db.clone();
+
+        thread::spawn(
+            move || {
+                let db = file_verifier_db.clone();
+
+                loop {
+                    let db = db.clone();
+
+                    let th = thread::spawn(
+                        move || {
+                            tracing::info!(
+                                worker = "file_verifier",
+                                thread = thread::current().id().as_u64(),
+                                "online",
+                            );
+
+                            if let Ok(rt) = Runtime::new() {
+                                rt.block_on(
+                                    workers::file_verifier::spawn_worker(
+                                        db.clone(),
+                                    )
+                                        .instrument(tracing::info_span!("worker", worker = "file_verifier")),
+                                );
+                            }
+                        }
+                    );
+
+                    let thread_id = th.thread().id().as_u64();
+
+                    th.join();
+
+                    tracing::warn!(
+                        worker = "file_verifier",
+                        thread = thread_id,
+                        "died, restarting",
+                    );
+                }
+            }
+        );
+    }
+
+    // also purges chats past their retention override, timers or not
+    {
+        let auto_delete_db = db.clone();
+
+        thread::spawn(
+            move || {
+                let db = auto_delete_db.clone();
+
+                loop {
+                    let db = db.clone();
+
+                    let th = thread::spawn(
+                        move || {
+                            tracing::info!(
+                                worker = "auto_delete",
+                                thread = thread::current().id().as_u64(),
+                                "online",
+                            );
+
+                            if let Ok(rt) = Runtime::new() {
+                                rt.block_on(
+                                    workers::auto_delete::spawn_worker(
+                                        db.clone(),
+                                    )
+                                        .instrument(tracing::info_span!("worker", worker = "auto_delete")),
+                                );
+                            }
+                        }
+                    );
+
+                    let thread_id = th.thread().id().as_u64();
+
+                    th.join();
+
+                    tracing::warn!(
+                        worker = "auto_delete",
+                        thread = thread_id,
+                        "died, restarting",
+                    );
+                }
+            }
+        );
+    }
+
+    // one handler per bot, each with its own update stream
+    let telegram_threads =
+        bots
+            .into_iter()
+            .map(|bot| {
+                let telegram_db = db.clone();
+
+                thread::spawn(
+                    move || {
+                        let db = telegram_db.clone();
+
+                        loop {
+                            let db = db.clone();
+                            let bot = bot.clone();
+                            let name = bot.name.clone();
+
+                            let th = thread::spawn(
+                                move || {
+                                    tracing::info!(
+                                        worker = "telegram_handler",
+                                        bot = %bot.name,
+                                        thread = thread::current().id().as_u64(),
+                                        "online",
+                                    );
+
+                                    let span = tracing::info_span!("worker", worker = "telegram_handler", bot = %bot.name);
+
+                                    if let Ok(rt) = Runtime::new() {
+                                        rt.block_on(
+                                            workers::telegram_handler::spawn_worker(
+                                                db.clone(),
+                                                bot,
+                                            )
+                                                .instrument(span),
+                                        );
+                                    }
+                                }
+                            );
+
+                            let thread_id = th.thread().id().as_u64();
+
+                            th.join();
+
+                            tracing::warn!(
+                                worker = "telegram_handler",
+                                bot = %name,
+                                thread = thread_id,
+                                "died, restarting",
+                            );
+                        }
+                    }
+                )
+            })
+            .collect::<Vec<_>>();
+
+    for th in telegram_threads {
+        th.join().unwrap();
+    }
 
     Ok(())
 }
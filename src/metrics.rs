@@ -0,0 +1,647 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+
+use crate::{API_ERROR_RATE_THRESHOLD, API_HEALTH_MIN_CALLS, API_HEALTH_WINDOW};
+
+#[derive(Debug, Clone, Default)]
+pub struct HttpRouteMetrics {
+    pub requests: u64,
+    pub slow_requests: u64,
+    pub duration_ms: u64,
+    pub response_bytes: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TelegramMetrics {
+    pub updates: u64,
+    // seconds between the newest processed update being sent and us
+    // processing it, stays high while working through a backlog
+    pub lag_seconds: i64,
+    pub last_update_at: Option<i64>,
+    pub deferred_jobs: u64,
+}
+
+static TELEGRAM_METRICS: Lazy<Mutex<TelegramMetrics>> =
+    Lazy::new(|| Mutex::new(TelegramMetrics::default()));
+
+pub fn record_telegram_update(
+    lag_seconds: Option<i64>,
+) -> TelegramMetrics {
+    let mut metrics =
+        match TELEGRAM_METRICS.lock() {
+            Ok(metrics) => metrics,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+    metrics.updates += 1;
+    metrics.last_update_at = Some(chrono::Utc::now().timestamp());
+
+    if let Some(lag_seconds) = lag_seconds {
+        metrics.lag_seconds = lag_seconds.max(0);
+    }
+
+    metrics.clone()
+}
+
+pub fn record_deferred_jobs(
+    deferred_jobs: u64,
+) {
+    let mut metrics =
+        match TELEGRAM_METRICS.lock() {
+            Ok(metrics) => metrics,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+    metrics.deferred_jobs = deferred_jobs;
+}
+
+pub fn telegram_metrics() -> TelegramMetrics {
+    match TELEGRAM_METRICS.lock() {
+        Ok(metrics) => metrics.clone(),
+        Err(poisoned) => poisoned.into_inner().clone(),
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FileRetryMetrics {
+    // files waiting for another download attempt
+    pub queued: u64,
+    pub successes: u64,
+    // attempts that failed, including the last one of a file given up on
+    pub failures: u64,
+    pub given_up: u64,
+}
+
+static FILE_RETRY_METRICS: Lazy<Mutex<FileRetryMetrics>> =
+    Lazy::new(|| Mutex::new(FileRetryMetrics::default()));
+
+fn lock_file_retry_metrics() -> std::sync::MutexGuard<'static, FileRetryMetrics> {
+    match FILE_RETRY_METRICS.lock() {
+        Ok(metrics) => metrics,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+pub fn record_file_retry_queue(
+    queued: u64,
+) {
+    lock_file_retry_metrics().queued = queued;
+}
+
+/// Records a retried download, `given_up` when it was the file's last try.
+pub fn record_file_retry(
+    success: bool,
+    given_up: bool,
+) {
+    let mut metrics = lock_file_retry_metrics();
+
+    match success {
+        true => metrics.successes += 1,
+        false => metrics.failures += 1,
+    }
+
+    if given_up {
+        metrics.given_up += 1;
+    }
+}
+
+pub fn file_retry_metrics() -> FileRetryMetrics {
+    lock_file_retry_metrics().clone()
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BackupMetrics {
+    pub backups: u64,
+    pub failures: u64,
+    pub last_success_at: Option<i64>,
+    pub last_size_bytes: u64,
+    pub last_duration_ms: u64,
+}
+
+static BACKUP_METRICS: Lazy<Mutex<BackupMetrics>> =
+    Lazy::new(|| Mutex::new(BackupMetrics::default()));
+
+/// Records a backup attempt, `result` holds the backup's timestamp and size
+/// when it succeeded.
+pub fn record_backup(
+    result: Option<(i64, u64)>,
+    elapsed: Duration,
+) {
+    let mut metrics =
+        match BACKUP_METRICS.lock() {
+            Ok(metrics) => metrics,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+    match result {
+        Some((timestamp, size)) => {
+            metrics.backups += 1;
+            metrics.last_success_at = Some(timestamp);
+            metrics.last_size_bytes = size;
+            metrics.last_duration_ms = elapsed.as_millis() as u64;
+        }
+        None => metrics.failures += 1,
+    }
+}
+
+/// Seeds the last successful backup from what's on disk, so the health
+/// endpoint doesn't report none after a restart.
+pub fn record_existing_backup(
+    timestamp: i64,
+    size: u64,
+) {
+    let mut metrics =
+        match BACKUP_METRICS.lock() {
+            Ok(metrics) => metrics,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+    if metrics.last_success_at.is_none() {
+        metrics.last_success_at = Some(timestamp);
+        metrics.last_size_bytes = size;
+    }
+}
+
+pub fn backup_metrics() -> BackupMetrics {
+    match BACKUP_METRICS.lock() {
+        Ok(metrics) => metrics.clone(),
+        Err(poisoned) => poisoned.into_inner().clone(),
+    }
+}
+
+// upper bounds of the api call duration histogram, in milliseconds
+const API_DURATION_BUCKETS: [u64; 8] = [50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000];
+
+#[derive(Debug, Clone, Default)]
+pub struct ApiMethodMetrics {
+    pub calls: u64,
+    pub errors: u64,
+    pub duration_ms: u64,
+    // one count per bucket in API_DURATION_BUCKETS, not cumulative
+    pub buckets: [u64; 8],
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ApiHealth {
+    pub calls: usize,
+    pub errors: usize,
+    pub p95_ms: Option<u64>,
+    // too many recent calls failed, optional work is skipped
+    pub degraded: bool,
+}
+
+#[derive(Debug, Default)]
+struct ApiMetrics {
+    methods: BTreeMap<&'static str, ApiMethodMetrics>,
+    // (time, duration in ms, succeeded) of the calls within API_HEALTH_WINDOW
+    recent: VecDeque<(i64, u64, bool)>,
+    degraded: bool,
+}
+
+static API_METRICS: Lazy<Mutex<ApiMetrics>> =
+    Lazy::new(|| Mutex::new(ApiMetrics::default()));
+
+fn api_health_of(
+    metrics: &ApiMetrics,
+) -> ApiHealth {
+    let mut durations =
+        metrics.recent
+            .iter()
+            .map(|(_, duration_ms, _)| *duration_ms)
+            .collect::<Vec<u64>>();
+
+    durations.sort_unstable();
+
+    let calls = durations.len();
+    let errors = metrics.recent.iter().filter(|(_, _, ok)| !ok).count();
+
+    ApiHealth {
+        calls,
+        errors,
+        p95_ms:
+        match calls {
+            0 => None,
+            _ => Some(durations[((calls * 95) / 100).min(calls - 1)]),
+        },
+        degraded:
+        calls >= API_HEALTH_MIN_CALLS
+            && errors as f64 / calls as f64 > API_ERROR_RATE_THRESHOLD,
+    }
+}
+
+/// Records the outcome of one outbound call, `method` being the telegram
+/// method or "file_download" for file fetches.
+pub fn record_api_call(
+    method: &'static str,
+    elapsed: Duration,
+    ok: bool,
+) {
+    let mut metrics =
+        match API_METRICS.lock() {
+            Ok(metrics) => metrics,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+    let duration_ms = elapsed.as_millis() as u64;
+    let now = chrono::Utc::now().timestamp();
+
+    let entry = metrics.methods.entry(method).or_default();
+
+    entry.calls += 1;
+    entry.duration_ms += duration_ms;
+
+    if !ok {
+        entry.errors += 1;
+    }
+
+    if let Some(bucket) = API_DURATION_BUCKETS.iter().position(|bound| duration_ms <= *bound) {
+        entry.buckets[bucket] += 1;
+    }
+
+    metrics.recent.push_back((now, duration_ms, ok));
+
+    while let Some((time, _, _)) = metrics.recent.front() {
+        if now - *time <= API_HEALTH_WINDOW {
+            break;
+        }
+
+        metrics.recent.pop_front();
+    }
+
+    let health = api_health_of(&metrics);
+
+    if health.degraded != metrics.degraded {
+        metrics.degraded = health.degraded;
+
+        if health.degraded {
+            println!(
+                "[telegram_api] {} of {} calls failed in the last {}s, skipping optional downloads until it recovers",
+                health.errors,
+                health.calls,
+                API_HEALTH_WINDOW,
+            );
+        } else {
+            println!("[telegram_api] error rate is back to normal");
+        }
+    }
+}
+
+/// Times a call to telegram and records its outcome.
+pub async fn track_api_call<T, E>(
+    method: &'static str,
+    call: impl Future<Output=Result<T, E>>,
+) -> Result<T, E> {
+    let start = Instant::now();
+    let result = call.await;
+
+    record_api_call(method, start.elapsed(), result.is_ok());
+
+    result
+}
+
+pub fn api_health() -> ApiHealth {
+    let mut metrics =
+        match API_METRICS.lock() {
+            Ok(metrics) => metrics,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+    let now = chrono::Utc::now().timestamp();
+
+    while let Some((time, _, _)) = metrics.recent.front() {
+        if now - *time <= API_HEALTH_WINDOW {
+            break;
+        }
+
+        metrics.recent.pop_front();
+    }
+
+    api_health_of(&metrics)
+}
+
+pub fn api_method_metrics() -> BTreeMap<&'static str, ApiMethodMetrics> {
+    match API_METRICS.lock() {
+        Ok(metrics) => metrics.methods.clone(),
+        Err(poisoned) => poisoned.into_inner().methods.clone(),
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CacheMetrics {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+// keyed by cache name
+static CACHE_METRICS: Lazy<Mutex<BTreeMap<&'static str, CacheMetrics>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+pub fn record_cache_lookup(
+    cache: &'static str,
+    hit: bool,
+) {
+    let mut metrics =
+        match CACHE_METRICS.lock() {
+            Ok(metrics) => metrics,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+    let entry = metrics.entry(cache).or_default();
+
+    if hit {
+        entry.hits += 1;
+    } else {
+        entry.misses += 1;
+    }
+}
+
+pub fn cache_metrics() -> BTreeMap<&'static str, CacheMetrics> {
+    match CACHE_METRICS.lock() {
+        Ok(metrics) => metrics.clone(),
+        Err(poisoned) => poisoned.into_inner().clone(),
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WorkerRestarts {
+    pub restarts: u64,
+    // failed runs in a row, a run that lasted long enough resets it
+    pub consecutive_failures: u32,
+    pub backoff_ms: u64,
+    pub last_restart_at: Option<i64>,
+}
+
+// keyed by worker name
+static WORKER_RESTARTS: Lazy<Mutex<BTreeMap<String, WorkerRestarts>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+pub fn record_worker_restart(
+    worker: &str,
+    consecutive_failures: u32,
+    backoff: Duration,
+) {
+    let mut metrics =
+        match WORKER_RESTARTS.lock() {
+            Ok(metrics) => metrics,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+    let entry = metrics.entry(worker.to_string()).or_default();
+
+    entry.restarts += 1;
+    entry.consecutive_failures = consecutive_failures;
+    entry.backoff_ms = backoff.as_millis() as u64;
+    entry.last_restart_at = Some(chrono::Utc::now().timestamp());
+}
+
+pub fn worker_restarts() -> BTreeMap<String, WorkerRestarts> {
+    match WORKER_RESTARTS.lock() {
+        Ok(metrics) => metrics.clone(),
+        Err(poisoned) => poisoned.into_inner().clone(),
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct IngestStats {
+    pub started_at: Option<i64>,
+    // messages stored since the process started
+    pub messages: u64,
+    // newest message across all chats, seeded from the database on start
+    pub last_message_at: Option<i64>,
+}
+
+static INGEST_STATS: Lazy<Mutex<IngestStats>> =
+    Lazy::new(|| Mutex::new(IngestStats::default()));
+
+fn lock_ingest_stats() -> std::sync::MutexGuard<'static, IngestStats> {
+    match INGEST_STATS.lock() {
+        Ok(stats) => stats,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+pub fn record_process_start() {
+    lock_ingest_stats().started_at = Some(chrono::Utc::now().timestamp());
+}
+
+pub fn record_message_ingested(
+    time: i64,
+) {
+    let mut stats = lock_ingest_stats();
+
+    stats.messages += 1;
+    stats.last_message_at = stats.last_message_at.max(Some(time));
+}
+
+/// Takes the newest message from before the process started, unless one
+/// came in since.
+pub fn seed_last_message_at(
+    time: Option<i64>,
+) {
+    let mut stats = lock_ingest_stats();
+
+    stats.last_message_at = stats.last_message_at.max(time);
+}
+
+pub fn ingest_stats() -> IngestStats {
+    lock_ingest_stats().clone()
+}
+
+static RATE_LIMITED: Lazy<Mutex<BTreeMap<&'static str, u64>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+pub fn record_rate_limited(
+    reason: &'static str,
+) {
+    let mut metrics =
+        match RATE_LIMITED.lock() {
+            Ok(metrics) => metrics,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+    *metrics.entry(reason).or_default() += 1;
+}
+
+// keyed by (route, status)
+static HTTP_METRICS: Lazy<Mutex<BTreeMap<(String, u16), HttpRouteMetrics>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+pub fn record_http_request(
+    route: &str,
+    status: u16,
+    elapsed: Duration,
+    response_bytes: Option<u64>,
+    slow: bool,
+) {
+    let mut metrics =
+        match HTTP_METRICS.lock() {
+            Ok(metrics) => metrics,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+    let entry =
+        metrics
+            .entry((route.to_string(), status))
+            .or_default();
+
+    entry.requests += 1;
+    entry.duration_ms += elapsed.as_millis() as u64;
+    entry.response_bytes += response_bytes.unwrap_or(0);
+
+    if slow {
+        entry.slow_requests += 1;
+    }
+}
+
+/// Renders all counters in the Prometheus text exposition format.
+pub fn render_metrics() -> String {
+    let metrics =
+        match HTTP_METRICS.lock() {
+            Ok(metrics) => metrics.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        };
+
+    let mut out = Vec::<String>::new();
+
+    let counters: [(&str, &str, fn(&HttpRouteMetrics) -> u64); 4] = [
+        ("minuteman_http_requests_total", "Number of handled HTTP requests.", |m| m.requests),
+        ("minuteman_http_slow_requests_total", "Number of HTTP requests over the slow threshold.", |m| m.slow_requests),
+        ("minuteman_http_request_duration_ms_total", "Total time spent handling HTTP requests.", |m| m.duration_ms),
+        ("minuteman_http_response_bytes_total", "Total size of HTTP response bodies with a known length.", |m| m.response_bytes),
+    ];
+
+    for (name, help, value) in counters.iter() {
+        out.push(format!("# HELP {} {}", name, help));
+        out.push(format!("# TYPE {} counter", name));
+
+        for ((route, status), entry) in metrics.iter() {
+            out.push(
+                format!(
+                    "{}{{route=\"{}\",status=\"{}\"}} {}",
+                    name,
+                    route,
+                    status,
+                    value(entry),
+                ),
+            );
+        }
+    }
+
+    let telegram = telegram_metrics();
+
+    out.push("# HELP minuteman_telegram_updates_total Number of processed telegram updates.".to_string());
+    out.push("# TYPE minuteman_telegram_updates_total counter".to_string());
+    out.push(format!("minuteman_telegram_updates_total {}", telegram.updates));
+
+    out.push("# HELP minuteman_telegram_lag_seconds Age of the most recently processed telegram update.".to_string());
+    out.push("# TYPE minuteman_telegram_lag_seconds gauge".to_string());
+    out.push(format!("minuteman_telegram_lag_seconds {}", telegram.lag_seconds));
+
+    out.push("# HELP minuteman_telegram_deferred_jobs Side work postponed while catching up.".to_string());
+    out.push("# TYPE minuteman_telegram_deferred_jobs gauge".to_string());
+    out.push(format!("minuteman_telegram_deferred_jobs {}", telegram.deferred_jobs));
+
+    let file_retry = file_retry_metrics();
+
+    out.push("# HELP minuteman_file_retry_queue Files waiting for another download attempt.".to_string());
+    out.push("# TYPE minuteman_file_retry_queue gauge".to_string());
+    out.push(format!("minuteman_file_retry_queue {}", file_retry.queued));
+
+    out.push("# HELP minuteman_file_retries_total Retried file downloads by outcome.".to_string());
+    out.push("# TYPE minuteman_file_retries_total counter".to_string());
+    out.push(format!("minuteman_file_retries_total{{result=\"success\"}} {}", file_retry.successes));
+    out.push(format!("minuteman_file_retries_total{{result=\"failure\"}} {}", file_retry.failures));
+
+    out.push("# HELP minuteman_file_retries_given_up_total Files marked as failed for good after their last retry.".to_string());
+    out.push("# TYPE minuteman_file_retries_given_up_total counter".to_string());
+    out.push(format!("minuteman_file_retries_given_up_total {}", file_retry.given_up));
+
+    let backup = backup_metrics();
+
+    out.push("# HELP minuteman_backups_total Number of successful database backups.".to_string());
+    out.push("# TYPE minuteman_backups_total counter".to_string());
+    out.push(format!("minuteman_backups_total {}", backup.backups));
+
+    out.push("# HELP minuteman_backup_failures_total Number of failed database backups.".to_string());
+    out.push("# TYPE minuteman_backup_failures_total counter".to_string());
+    out.push(format!("minuteman_backup_failures_total {}", backup.failures));
+
+    out.push("# HELP minuteman_backup_last_success_timestamp_seconds Time of the last successful backup.".to_string());
+    out.push("# TYPE minuteman_backup_last_success_timestamp_seconds gauge".to_string());
+    out.push(format!("minuteman_backup_last_success_timestamp_seconds {}", backup.last_success_at.unwrap_or(0)));
+
+    out.push("# HELP minuteman_backup_size_bytes Size of the last successful backup.".to_string());
+    out.push("# TYPE minuteman_backup_size_bytes gauge".to_string());
+    out.push(format!("minuteman_backup_size_bytes {}", backup.last_size_bytes));
+
+    out.push("# HELP minuteman_backup_duration_ms Time the last successful backup took.".to_string());
+    out.push("# TYPE minuteman_backup_duration_ms gauge".to_string());
+    out.push(format!("minuteman_backup_duration_ms {}", backup.last_duration_ms));
+
+    let api_methods = api_method_metrics();
+
+    out.push("# HELP minuteman_telegram_api_duration_ms Time outbound telegram calls took.".to_string());
+    out.push("# TYPE minuteman_telegram_api_duration_ms histogram".to_string());
+
+    for (method, entry) in api_methods.iter() {
+        let mut cumulative = 0u64;
+
+        for (bound, count) in API_DURATION_BUCKETS.iter().zip(entry.buckets.iter()) {
+            cumulative += count;
+
+            out.push(format!("minuteman_telegram_api_duration_ms_bucket{{method=\"{}\",le=\"{}\"}} {}", method, bound, cumulative));
+        }
+
+        out.push(format!("minuteman_telegram_api_duration_ms_bucket{{method=\"{}\",le=\"+Inf\"}} {}", method, entry.calls));
+        out.push(format!("minuteman_telegram_api_duration_ms_sum{{method=\"{}\"}} {}", method, entry.duration_ms));
+        out.push(format!("minuteman_telegram_api_duration_ms_count{{method=\"{}\"}} {}", method, entry.calls));
+    }
+
+    out.push("# HELP minuteman_telegram_api_errors_total Number of failed outbound telegram calls.".to_string());
+    out.push("# TYPE minuteman_telegram_api_errors_total counter".to_string());
+
+    for (method, entry) in api_methods.iter() {
+        out.push(format!("minuteman_telegram_api_errors_total{{method=\"{}\"}} {}", method, entry.errors));
+    }
+
+    out.push("# HELP minuteman_telegram_api_degraded Whether optional downloads are skipped because of api errors.".to_string());
+    out.push("# TYPE minuteman_telegram_api_degraded gauge".to_string());
+    out.push(format!("minuteman_telegram_api_degraded {}", api_health().degraded as u8));
+
+    let caches = cache_metrics();
+
+    let cache_counters: [(&str, &str, fn(&CacheMetrics) -> u64); 2] = [
+        ("minuteman_cache_hits_total", "Lookups answered from an in-memory cache.", |m| m.hits),
+        ("minuteman_cache_misses_total", "Lookups that had to go to the database.", |m| m.misses),
+    ];
+
+    for (name, help, value) in cache_counters.iter() {
+        out.push(format!("# HELP {} {}", name, help));
+        out.push(format!("# TYPE {} counter", name));
+
+        for (cache, entry) in caches.iter() {
+            out.push(format!("{}{{cache=\"{}\"}} {}", name, cache, value(entry)));
+        }
+    }
+
+    let rate_limited =
+        match RATE_LIMITED.lock() {
+            Ok(metrics) => metrics.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        };
+
+    out.push("# HELP minuteman_http_rate_limited_total Requests turned away with a 429.".to_string());
+    out.push("# TYPE minuteman_http_rate_limited_total counter".to_string());
+
+    for (reason, count) in rate_limited.iter() {
+        out.push(format!("minuteman_http_rate_limited_total{{reason=\"{}\"}} {}", reason, count));
+    }
+
+    out.push(String::new());
+
+    out.join("\n")
+}
@@ -0,0 +1,722 @@
+use std::sync::{Arc, Mutex};
+
+use rocksdb::{DBWithThreadMode, Direction, IteratorMode, MultiThreaded, ReadOptions};
+use serde::Serialize;
+
+use crate::some_or_continue;
+use crate::blob_store::{delete_blob, get_blob, place_blob};
+use crate::renderer::chat_index::chat_days_page;
+use crate::search_index::queue_moved_postings;
+use crate::storage::{lock_db, PendingWrites};
+use crate::storage_stats::{file_counter_keys, message_counter_keys, put_counted, rebuild_storage_stats};
+use crate::utils::get_file_meta;
+use crate::workers::chat_policy::{build_chat_policy_key, ChatPolicy};
+use crate::workers::file_retry::{build_file_retry_key, FileRetry};
+use crate::workers::telegram_handler::{build_chat_activity_key, build_chat_last_activity_key, build_file_key, build_file_meta_key, build_message_key, build_poll_ref_key, FileEntryType, get_chat_last_activity, LogItem, LogItemMediaType, LogItemSpecialType, MessageSlot, parse_message_key, pick_photo_sizes, store_file_meta};
+
+pub const SCHEMA_VERSION_KEY: &str = "schema:version";
+
+// keys looked at per batch before progress is saved and the lock released
+pub const MIGRATION_BATCH_SIZE: usize = 10_000;
+
+pub struct Migration {
+    // the schema version the database is at once this migration is done
+    pub version: u32,
+    pub name: &'static str,
+    // handles one batch starting after the given key and returns the last
+    // key it looked at, or None once there's nothing left to do
+    pub run: fn(&DBWithThreadMode<MultiThreaded>, &[u8]) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>>,
+}
+
+/// All migrations in the order they have to run in. Append only: a
+/// migration's version must never change once it has shipped.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "chat_index_backfill",
+        run: migrate_chat_index_backfill,
+    },
+    Migration {
+        version: 2,
+        name: "chat_ref_backfill",
+        run: migrate_chat_ref_backfill,
+    },
+    Migration {
+        version: 3,
+        name: "storage_stats_rebuild",
+        run: migrate_storage_stats_rebuild,
+    },
+    Migration {
+        version: 4,
+        name: "photo_size_dedup",
+        run: migrate_photo_size_dedup,
+    },
+    Migration {
+        version: 5,
+        name: "chat_activity_index",
+        run: migrate_chat_activity_index,
+    },
+    Migration {
+        version: 6,
+        name: "message_id_keys",
+        run: migrate_message_id_keys,
+    },
+];
+
+/// The version of the last migration, what records are written with.
+pub const SCHEMA_VERSION: u32 = MIGRATIONS[MIGRATIONS.len() - 1].version;
+
+pub fn schema_version() -> u32 {
+    SCHEMA_VERSION
+}
+
+/// Records that carry the schema version they were written with in a
+/// `#[serde(default)] v: u32` field, 0 for rows from before versioning.
+pub trait Versioned {
+    fn version(&self) -> u32;
+
+    fn set_version(&mut self, version: u32);
+}
+
+pub fn build_migration_progress_key(
+    name: &str,
+) -> String {
+    format!(
+        "migration:{}:progress",
+        name,
+    )
+}
+
+/// Serializes a record with its `v` set to the current schema version,
+/// whatever version it was read with.
+pub fn to_versioned_string<T: Versioned + Serialize + Clone>(
+    value: &T,
+) -> serde_json::Result<String> {
+    let mut value = value.clone();
+
+    value.set_version(SCHEMA_VERSION);
+
+    serde_json::to_string(&value)
+}
+
+pub fn stored_schema_version(
+    db: &DBWithThreadMode<MultiThreaded>,
+) -> u32 {
+    db.get(SCHEMA_VERSION_KEY)
+        .ok()
+        .flatten()
+        .map(|version| String::from_utf8(version).ok())
+        .flatten()
+        .map(|version| version.parse::<u32>().ok())
+        .flatten()
+        .unwrap_or(0)
+}
+
+/// Brings the database up to the current schema version. Every batch saves
+/// its progress, so an interrupted run picks up where it stopped.
+pub fn run_migrations(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let stored_version = stored_schema_version(&lock_db(&db));
+
+    for migration in MIGRATIONS.iter().filter(|migration| migration.version > stored_version) {
+        tracing::info!(version = migration.version, name = migration.name, "running migration");
+
+        let progress_key = build_migration_progress_key(migration.name);
+
+        loop {
+            let dbi = lock_db(&db);
+
+            let progress =
+                dbi.get(&progress_key)?
+                    .unwrap_or_default();
+
+            match (migration.run)(&dbi, &progress)? {
+                Some(last_key) => {
+                    dbi.put(&progress_key, &last_key)?;
+
+                    tracing::info!(name = migration.name, at = %String::from_utf8_lossy(&last_key), "migration progress");
+                }
+                None => {
+                    dbi.delete(&progress_key)?;
+                    dbi.put(SCHEMA_VERSION_KEY, migration.version.to_string())?;
+
+                    break;
+                }
+            }
+        }
+
+        tracing::info!(version = migration.version, name = migration.name, "migration done");
+    }
+
+    Ok(())
+}
+
+// walks a batch of `chat:{chat_id}:{slot}` log items after `progress` and
+// returns the last key seen, None once past the last one
+fn for_each_log_item<F>(
+    db: &DBWithThreadMode<MultiThreaded>,
+    progress: &[u8],
+    mut f: F,
+) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>>
+    where F: FnMut(&str, MessageSlot, &LogItem) -> Result<(), Box<dyn std::error::Error>> {
+    let mut opts = ReadOptions::default();
+
+    opts.set_iterate_upper_bound(b"chat:\x7f".to_vec());
+
+    let start =
+        match progress.is_empty() {
+            true => b"chat:".to_vec(),
+            false => progress.to_vec(),
+        };
+
+    let iter =
+        db.iterator_opt(
+            IteratorMode::From(&start, Direction::Forward),
+            opts,
+        );
+
+    let mut last_key = None;
+
+    for (i, (key, val)) in iter.enumerate() {
+        if i >= MIGRATION_BATCH_SIZE {
+            return Ok(last_key);
+        }
+
+        last_key = Some(key.to_vec());
+
+        // the progress key itself was handled by the previous batch
+        if *key == *progress {
+            continue;
+        }
+
+        let key = some_or_continue!(std::str::from_utf8(&key).ok());
+
+        // skips chat:meta:* and friends, which share the prefix
+        let (chat_id, slot) = some_or_continue!(parse_message_key(key));
+        let log_item = some_or_continue!(serde_json::from_slice::<LogItem>(&val).ok());
+
+        f(chat_id, slot, &log_item)?;
+    }
+
+    Ok(None)
+}
+
+// day index for log items stored before chat_index existed, this used to
+// be run by hand through examples/minuteman.rs
+fn migrate_chat_index_backfill(
+    db: &DBWithThreadMode<MultiThreaded>,
+    progress: &[u8],
+) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+    for_each_log_item(
+        db,
+        progress,
+        |chat_id, slot, _| {
+            db.put(
+                format!("chat_index:{}:{}", chat_id, slot.time / 86_400),
+                &b"\0",
+            )?;
+
+            Ok(())
+        },
+    )
+}
+
+// message id lookups for log items stored before chat_ref existed, taken
+// from the message kept in the log item itself
+fn migrate_chat_ref_backfill(
+    db: &DBWithThreadMode<MultiThreaded>,
+    progress: &[u8],
+) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+    for_each_log_item(
+        db,
+        progress,
+        |chat_id, slot, log_item| {
+            let message =
+                match log_item.source() {
+                    Some(message) => message,
+                    None => return Ok(()),
+                };
+
+            let message_ref_key =
+                format!(
+                    "chat_ref:{}:{}",
+                    chat_id,
+                    message.id.to_string(),
+                );
+
+            if db.get(&message_ref_key)?.is_none() {
+                db.put(
+                    &message_ref_key,
+                    slot.time.to_string(),
+                )?;
+            }
+
+            Ok(())
+        },
+    )
+}
+
+// seeds the storage counters for archives from before they were kept, a
+// rebuild starts from scratch so running it again after an interruption
+// is fine
+fn migrate_storage_stats_rebuild(
+    db: &DBWithThreadMode<MultiThreaded>,
+    _progress: &[u8],
+) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+    rebuild_storage_stats(db)?;
+
+    Ok(None)
+}
+
+// photos used to be stored in every size telegram sent. This keeps the
+// largest, makes the smallest one filling the listing its thumbnail unless
+// it has one already and drops the other sizes, the way `process_files`
+// stores photos now
+fn migrate_photo_size_dedup(
+    db: &DBWithThreadMode<MultiThreaded>,
+    progress: &[u8],
+) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+    for_each_log_item(
+        db,
+        progress,
+        |chat_id, slot, log_item| {
+            let files =
+                match log_item {
+                    LogItem::Media { files, media_type: LogItemMediaType::Image { .. }, .. } if files.len() > 1 => files,
+                    _ => return Ok(()),
+                };
+
+            let message_key = build_message_key(chat_id, slot);
+
+            let metas =
+                files
+                    .iter()
+                    .map(|file_id| get_file_meta(db, file_id))
+                    .collect::<Vec<_>>();
+
+            // without dimensions the last size wins, the one the listing
+            // used to show
+            let sizes =
+                metas
+                    .iter()
+                    .map(|meta|
+                        meta.as_ref()
+                            .map(|meta| (meta.width.unwrap_or(0) as i64, meta.height.unwrap_or(0) as i64))
+                            .unwrap_or((0, 0))
+                    )
+                    .collect::<Vec<(i64, i64)>>();
+
+            let (original, thumb) =
+                match pick_photo_sizes(&sizes) {
+                    Some(picked) => picked,
+                    None => return Ok(()),
+                };
+
+            let original_id = &files[original];
+            let thumb_key = build_file_key(FileEntryType::Thumb, original_id);
+
+            let mut thumb_file_id = None;
+
+            if let (Some(thumb), None) = (thumb, db.get(&thumb_key)?) {
+                if let Some(blob) = get_blob(db, &build_file_key(FileEntryType::Chat, &files[thumb]))? {
+                    put_counted(
+                        db,
+                        &thumb_key,
+                        place_blob(&thumb_key, &blob)?,
+                        &file_counter_keys("thumb", Some(&message_key)),
+                    )?;
+
+                    if let Some(mut meta) = metas[original].clone() {
+                        meta.thumb_width = sizes[thumb].0.try_into().ok().filter(|width| *width > 0);
+                        meta.thumb_height = sizes[thumb].1.try_into().ok().filter(|height| *height > 0);
+
+                        store_file_meta(db, original_id, &meta)?;
+                    }
+
+                    thumb_file_id = Some(files[thumb].clone());
+                }
+            }
+
+            for (i, file_id) in files.iter().enumerate() {
+                if i == original {
+                    continue;
+                }
+
+                // files re-sent in another message point their meta at that one
+                let shared =
+                    metas[i]
+                        .as_ref()
+                        .map(|meta| meta.message_key.as_ref())
+                        .flatten()
+                        .map(|key| *key != message_key)
+                        .unwrap_or(false);
+
+                if shared {
+                    continue;
+                }
+
+                delete_blob(
+                    db,
+                    &build_file_key(FileEntryType::Chat, file_id),
+                    &file_counter_keys("chat", Some(&message_key)),
+                )?;
+
+                delete_blob(
+                    db,
+                    &build_file_key(FileEntryType::Thumb, file_id),
+                    &file_counter_keys("thumb", Some(&message_key)),
+                )?;
+
+                db.delete(build_file_meta_key(file_id))?;
+            }
+
+            let mut log_item = log_item.clone();
+
+            if let LogItem::Media { ref mut files, media_type: LogItemMediaType::Image { thumb_file_id: ref mut thumb, .. }, .. } = log_item {
+                *files = vec!(original_id.clone());
+                *thumb = thumb_file_id;
+            }
+
+            put_counted(
+                db,
+                &message_key,
+                to_versioned_string(&log_item)?,
+                &message_counter_keys(chat_id),
+            )?;
+
+            Ok(())
+        },
+    )
+}
+
+// the index the chats overview pages through, for chats from before it
+// existed. Chats from before their last activity was kept get the start of
+// their latest day, the way the overview used to guess it
+fn migrate_chat_activity_index(
+    db: &DBWithThreadMode<MultiThreaded>,
+    progress: &[u8],
+) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+    let mut opts = ReadOptions::default();
+
+    opts.set_iterate_upper_bound(b"chat_rel:\xff".to_vec());
+
+    let start =
+        match progress.is_empty() {
+            true => b"chat_rel:".to_vec(),
+            false => progress.to_vec(),
+        };
+
+    let iter =
+        db.iterator_opt(
+            IteratorMode::From(&start, Direction::Forward),
+            opts,
+        );
+
+    for (i, (key, _)) in iter.enumerate() {
+        if i >= MIGRATION_BATCH_SIZE {
+            return Ok(Some(key.to_vec()));
+        }
+
+        let chat_id = some_or_continue!(std::str::from_utf8(&key).ok().map(|key| key.trim_start_matches("chat_rel:")));
+
+        let last_activity =
+            match get_chat_last_activity(db, chat_id) {
+                Some(last_activity) => last_activity,
+                None => {
+                    let day = some_or_continue!(chat_days_page(db, chat_id, &None, 1).0.first().copied());
+
+                    db.put(build_chat_last_activity_key(chat_id), (day * 86_400).to_string())?;
+
+                    day * 86_400
+                }
+            };
+
+        // policies are only loaded once migrations are done
+        let archived =
+            db.get(build_chat_policy_key(chat_id))?
+                .map(|policy| serde_json::from_slice::<ChatPolicy>(&policy).ok())
+                .flatten()
+                .map(|policy| policy.bot_removed_at.is_some())
+                .unwrap_or(false);
+
+        db.put(build_chat_activity_key(chat_id, last_activity, archived), b"\0")?;
+    }
+
+    Ok(None)
+}
+
+// log items used to be keyed by their timestamp alone, so a message took
+// the place of another one from the same second. This moves them to keys
+// with their message id, along with what points at the key: the search
+// postings, the pin index, poll refs, file metas and queued file retries.
+// `chat_ref:` holds the timestamp and stays as it is. Items without a
+// message of their own, like membership changes, get id 0
+fn migrate_message_id_keys(
+    db: &DBWithThreadMode<MultiThreaded>,
+    progress: &[u8],
+) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+    for_each_log_item(
+        db,
+        progress,
+        |chat_id, slot, log_item| {
+            if slot.message_id.is_some() {
+                return Ok(());
+            }
+
+            let moved =
+                MessageSlot::new(
+                    slot.time,
+                    log_item.source().map(|message| i64::from(message.id)).unwrap_or(0),
+                );
+
+            let message_key = build_message_key(chat_id, slot);
+            let moved_key = build_message_key(chat_id, moved);
+
+            let value =
+                match db.get(&message_key)? {
+                    Some(value) => value,
+                    None => return Ok(()),
+                };
+
+            // pointers go first, a run interrupted before the item moved
+            // finds them right already
+            for file_id in log_item.file_ids() {
+                if let Some(mut meta) = get_file_meta(db, &file_id) {
+                    if meta.message_key.as_deref() == Some(message_key.as_str()) {
+                        meta.message_key = Some(moved_key.clone());
+
+                        store_file_meta(db, &file_id, &meta)?;
+                    }
+                }
+
+                let retry_key = build_file_retry_key(&file_id);
+
+                if let Some(retry) = db.get(&retry_key)? {
+                    let mut retry = some_or_continue!(serde_json::from_slice::<FileRetry>(&retry).ok());
+
+                    if retry.message_key == message_key {
+                        retry.message_key = moved_key.clone();
+
+                        db.put(&retry_key, serde_json::to_string(&retry)?)?;
+                    }
+                }
+            }
+
+            // the value moves as it is, the message counters only go by
+            // its size
+            let mut writes = PendingWrites::new();
+
+            writes.delete(&message_key);
+            writes.put(&moved_key, &value);
+
+            queue_moved_postings(db, &mut writes, chat_id, slot, moved, log_item)?;
+
+            let pin_key = format!("chat_pins:{}:{}", chat_id, slot);
+
+            if db.get(&pin_key)?.is_some() {
+                writes.delete(&pin_key);
+                writes.put(&format!("chat_pins:{}:{}", chat_id, moved), b"\0");
+            }
+
+            if let LogItem::Special { special_type: LogItemSpecialType::Poll { ref id, .. }, .. } = log_item {
+                let poll_ref_key = build_poll_ref_key(id);
+
+                if db.get(&poll_ref_key)?.as_deref() == Some(message_key.as_bytes()) {
+                    writes.put(&poll_ref_key, &moved_key);
+                }
+            }
+
+            writes.commit(db)
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workers::telegram_handler::{ChatMeta, UserMeta};
+
+    #[test]
+    fn rows_from_before_versioning_are_version_0() {
+        let log_item =
+            serde_json::from_str::<LogItem>(r#"{"redacted": {"time": 1, "redacted_at": 2}}"#)
+                .unwrap();
+
+        assert_eq!(log_item.version(), 0);
+    }
+
+    #[test]
+    fn versioned_records_carry_the_current_version() {
+        let log_item =
+            serde_json::from_str::<LogItem>(r#"{"redacted": {"time": 1, "redacted_at": 2}}"#)
+                .unwrap();
+
+        let stored = to_versioned_string(&log_item).unwrap();
+
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&stored).unwrap()["redacted"]["v"],
+            SCHEMA_VERSION,
+        );
+        assert_eq!(serde_json::from_str::<LogItem>(&stored).unwrap().version(), SCHEMA_VERSION);
+
+        // the version of a chat goes into whichever kind of chat it is
+        let chat = ChatMeta::User(UserMeta { v: 0, ..UserMeta::default() });
+
+        let stored = to_versioned_string(&chat).unwrap();
+
+        assert_eq!(serde_json::from_str::<ChatMeta>(&stored).unwrap().version(), SCHEMA_VERSION);
+    }
+
+    fn open_db(name: &str) -> DBWithThreadMode<MultiThreaded> {
+        let path =
+            std::env::temp_dir()
+                .join(format!("minuteman-test-{}-{}", name, std::process::id()));
+
+        let _ = std::fs::remove_dir_all(&path);
+
+        DBWithThreadMode::<MultiThreaded>::open_default(path).unwrap()
+    }
+
+    #[test]
+    fn chats_get_an_activity_entry_even_from_before_it_was_kept() {
+        let db = open_db("migrate-chat-activity");
+
+        // one with its last activity, one that only has days
+        db.put("chat_rel:-1002629100", b"\0").unwrap();
+        db.put(build_chat_last_activity_key("-1002629100"), "1600000100").unwrap();
+
+        db.put("chat_rel:-1002629101", b"\0").unwrap();
+        db.put("chat_index:-1002629101:18000", b"\0").unwrap();
+        db.put("chat_index:-1002629101:18001", b"\0").unwrap();
+
+        db.put(
+            build_chat_policy_key("-1002629101"),
+            serde_json::to_string(&ChatPolicy { bot_removed_at: Some(1), ..ChatPolicy::default() }).unwrap(),
+        ).unwrap();
+
+        assert_eq!(migrate_chat_activity_index(&db, b"").unwrap(), None);
+
+        assert!(db.get(build_chat_activity_key("-1002629100", 1_600_000_100, false)).unwrap().is_some());
+        assert!(db.get(build_chat_activity_key("-1002629101", 18_001 * 86_400, true)).unwrap().is_some());
+        assert_eq!(get_chat_last_activity(&db, "-1002629101"), Some(18_001 * 86_400));
+    }
+
+    #[test]
+    fn log_items_move_to_keys_with_their_message_id() {
+        use std::collections::BTreeMap;
+
+        use pw_telegram_bot_fork::{MessageId, MessageKind};
+
+        use crate::search_index::{build_posting_key, update_postings};
+        use crate::utils::resolve_message_ref;
+        use crate::workers::telegram_handler::{FileMeta, InterMessage, LogItemMembershipType};
+
+        let db = open_db("migrate-message-id-keys");
+
+        let chat_id = "-1002599000";
+        let time = 1_600_000_000;
+
+        let source = |id: i64|
+            Some(
+                InterMessage {
+                    id: MessageId::new(id),
+                    from: None,
+                    date: time,
+                    chat: ChatMeta::User(UserMeta::default()),
+                    forward: None,
+                    reply_to_message: None,
+                    edit_date: None,
+                    kind: MessageKind::Text { data: String::new(), entities: vec![] },
+                    via_bot: None,
+                    author_signature: None,
+                    untyped_kind: None,
+                    custom_emoji_ids: BTreeMap::new(),
+                },
+            );
+
+        let photo =
+            LogItem::Media {
+                user_id: Some("10".to_string()),
+                time,
+                received_at: time,
+                caption: Some("hello world".to_string()),
+                media_type: LogItemMediaType::Image { width: 8, height: 8, thumb_file_id: None },
+                files: vec!["photo-file".to_string()],
+                via_bot: None,
+                author_signature: None,
+                source: source(41),
+                v: 5,
+            };
+
+        let pin =
+            LogItem::Pin {
+                user_id: Some("10".to_string()),
+                time: time + 1,
+                received_at: time + 1,
+                message: None,
+                message_id: "41".to_string(),
+                source: source(42),
+                v: 5,
+            };
+
+        let join =
+            LogItem::Membership {
+                user_id: Some("11".to_string()),
+                time: time + 2,
+                received_at: time + 2,
+                membership_type: LogItemMembershipType::Joined,
+                admin_id: None,
+                source: None,
+                v: 5,
+            };
+
+        // the way they were stored before the message id was in the key
+        for (offset, item) in [(0, &photo), (1, &pin), (2, &join)] {
+            db.put(build_message_key(chat_id, time + offset), to_versioned_string(item).unwrap()).unwrap();
+        }
+
+        db.put(format!("chat_ref:{}:41", chat_id), time.to_string()).unwrap();
+        db.put(format!("chat_pins:{}:{}", chat_id, time + 1), b"\0").unwrap();
+
+        update_postings(&db, chat_id, MessageSlot::from(time), None, Some(&photo)).unwrap();
+
+        store_file_meta(
+            &db,
+            "photo-file",
+            &FileMeta { message_key: Some(build_message_key(chat_id, time)), ..FileMeta::default() },
+        ).unwrap();
+
+        // references find them before the migration ran
+        assert_eq!(resolve_message_ref(&db, chat_id, "41"), Some(MessageSlot::from(time)));
+
+        assert_eq!(migrate_message_id_keys(&db, b"").unwrap(), None);
+
+        let photo_slot = MessageSlot::new(time, 41);
+
+        for legacy in [time, time + 1, time + 2] {
+            assert!(db.get(build_message_key(chat_id, legacy)).unwrap().is_none());
+        }
+
+        assert!(db.get(build_message_key(chat_id, photo_slot)).unwrap().is_some());
+        assert!(db.get(build_message_key(chat_id, MessageSlot::new(time + 1, 42))).unwrap().is_some());
+        assert!(db.get(build_message_key(chat_id, MessageSlot::new(time + 2, 0))).unwrap().is_some());
+
+        assert_eq!(resolve_message_ref(&db, chat_id, "41"), Some(photo_slot));
+
+        assert!(db.get(build_posting_key(chat_id, "hello", MessageSlot::from(time))).unwrap().is_none());
+        assert!(db.get(build_posting_key(chat_id, "hello", photo_slot)).unwrap().is_some());
+
+        assert!(db.get(format!("chat_pins:{}:{}", chat_id, time + 1)).unwrap().is_none());
+        assert!(db.get(format!("chat_pins:{}:{}:0000000042", chat_id, time + 1)).unwrap().is_some());
+
+        assert_eq!(
+            get_file_meta(&db, "photo-file").unwrap().message_key,
+            Some(build_message_key(chat_id, photo_slot)),
+        );
+
+        // a second run has nothing left to move
+        assert_eq!(migrate_message_id_keys(&db, b"").unwrap(), None);
+        assert!(db.get(build_message_key(chat_id, photo_slot)).unwrap().is_some());
+    }
+}
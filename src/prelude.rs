@@ -1,15 +1,85 @@
-use std::env;
+use crate::config::read_secret;
 
 pub const GLOBAL_CSS: &str = include_str!("./assets/global.css");
 
-pub const MAX_FILE_SIZE: i64 = 1024 * 1024 * 256; // 50 MB
+// computed at compile time, changes whenever the stylesheet does
+pub const GLOBAL_CSS_HASH: u64 = fnv1a_hash(GLOBAL_CSS.as_bytes());
+
+// largest file of any kind that's downloaded, unless configured otherwise
+// per kind, see `get_file_size_limits`
+pub const MAX_FILE_SIZE: i64 = 1024 * 1024 * 256; // 256 MB
 
 pub static JOB_SLEEP_INTERVAL: u64 = 2_000u64;
 
-#[inline(always)]
-pub fn get_telegram_api_token() -> String {
-    env::var("TELEGRAM_API_TOKEN")
-        .unwrap()
+// restarts of a failing worker back off up to this many milliseconds, and
+// a run lasting this many seconds counts as recovered
+pub const MAX_RESTART_BACKOFF: u64 = 5 * 60 * 1_000;
+pub const RESTART_BACKOFF_RESET: u64 = 10 * 60;
+
+pub const DEFAULT_LISTING_LIMIT: usize = 2_000;
+
+pub const MAX_LISTING_LIMIT: usize = 20_000;
+
+// no route takes more than a small form or json document
+pub const MAX_REQUEST_BODY_SIZE: u64 = 64 * 1024;
+
+// updates older than this put the telegram handler into catch-up mode,
+// which postpones profile picture downloads until it's caught up
+pub const CATCHUP_LAG_THRESHOLD: i64 = 5 * 60;
+
+// how often (in updates) progress is logged while catching up
+pub const CATCHUP_LOG_INTERVAL: u64 = 100;
+
+// chats without a message for this many days are folded away on the
+// chats overview
+pub const INACTIVE_CHAT_DAYS: i64 = 90;
+
+// how long a chat's previous @username keeps resolving after a rename
+pub const USERNAME_ALIAS_GRACE_PERIOD: i64 = 86400 * 30;
+
+// file blobs younger than this are never vacuumed, their message may
+// still be on its way into the database
+pub const VACUUM_MIN_FILE_AGE: i64 = 86400;
+
+// how long a cached user or chat meta record is trusted, writes through the
+// telegram handler invalidate it right away anyway
+pub const META_CACHE_TTL: u64 = 5 * 60;
+
+// cached pages of finished days are invalidated on writes, this only bounds
+// how long renamed users keep their old name on them
+pub const RENDER_CACHE_TTL: u64 = 60 * 60;
+
+// telegram api calls are judged over this window, when more than the given
+// share of them failed the bot skips optional downloads until it recovers
+pub const API_HEALTH_WINDOW: i64 = 5 * 60;
+pub const API_ERROR_RATE_THRESHOLD: f64 = 0.5;
+pub const API_HEALTH_MIN_CALLS: usize = 10;
+
+// search engines don't take more than 50k urls per sitemap file
+pub const SITEMAP_CHUNK_SIZE: usize = 50_000;
+
+pub const fn fnv1a_hash(bytes: &[u8]) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        hash ^= bytes[i] as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+        i += 1;
+    }
+
+    hash
+}
+
+/// Token of the default bot, read from the file `TELEGRAM_API_TOKEN_FILE`
+/// points at or from `TELEGRAM_API_TOKEN`.
+pub fn get_telegram_api_token() -> Result<String, MinutemanError> {
+    read_secret("TELEGRAM_API_TOKEN")?
+        .ok_or(
+            MinutemanError::Other(
+                "neither TELEGRAM_API_TOKEN nor TELEGRAM_API_TOKEN_FILE is set".to_string(),
+            ),
+        )
 }
 
 #[derive(Debug)]
@@ -0,0 +1,231 @@
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::Sha256;
+
+use crate::config::{get_admin_token, get_anonymize_secret, get_anonymized_chats, get_require_auth};
+use crate::workers::chat_policy::{get_chat_policy, is_private_chat_id, logs_private_chat};
+
+/// Who is looking at a page. Admins always see real identities, everybody
+/// else gets pseudonyms in anonymized chats.
+#[derive(Debug, Clone, Default)]
+pub struct Viewer {
+    pub admin: bool,
+    // chat a valid share token grants access to, on top of whatever the
+    // viewer could see without it
+    pub share: Option<String>,
+}
+
+/// Rejection for requests the viewer isn't allowed to make, answered with
+/// a 403.
+#[derive(Debug)]
+pub struct AccessDenied;
+
+impl warp::reject::Reject for AccessDenied {}
+
+impl Viewer {
+    pub fn from_credentials(
+        authorization: Option<String>,
+        cookie: Option<String>,
+    ) -> Self {
+        let admin_token =
+            match get_admin_token() {
+                Some(token) => token,
+                None => return Viewer::default(),
+            };
+
+        let bearer =
+            authorization
+                .as_ref()
+                .map(|value| value.strip_prefix("Bearer "))
+                .flatten()
+                .map(|token| token.to_string());
+
+        Viewer {
+            admin:
+            bearer
+                .into_iter()
+                .chain(cookie)
+                .any(|token| constant_time_eq(token.as_bytes(), admin_token.as_bytes())),
+            share: None,
+        }
+    }
+
+    /// Lets the viewer see the chat of a verified share token as well,
+    /// admins see everything anyway.
+    pub fn with_share(
+        mut self,
+        chat_id: Option<String>,
+    ) -> Self {
+        if !self.admin {
+            self.share = chat_id;
+        }
+
+        self
+    }
+
+    /// Whether the viewer may see pages that aren't scoped to a chat. A
+    /// share token never takes away what the public can see.
+    pub fn can_browse(&self) -> bool {
+        self.admin || !get_require_auth()
+    }
+
+    pub fn can_see_chat(
+        &self,
+        chat_id: &str,
+    ) -> bool {
+        // private chats logged before they had to be opted into
+        if !self.admin && is_private_chat_id(chat_id) && !logs_private_chat(chat_id) {
+            return false;
+        }
+
+        self.can_browse() || self.share.as_deref() == Some(chat_id)
+    }
+
+    /// Whether user identities in the given chat have to be hidden.
+    pub fn anonymize_chat(
+        &self,
+        chat_id: &str,
+    ) -> bool {
+        !self.admin && is_chat_anonymized(chat_id)
+    }
+
+    /// User pages and avatars aren't scoped to a chat, so they're admin only
+    /// as soon as any chat is anonymized.
+    pub fn can_see_users(&self) -> bool {
+        self.admin || (self.can_browse() && get_anonymized_chats().is_empty())
+    }
+}
+
+/// Header the JSON admin endpoints take the `csrf_token` in, forms send it
+/// as their `csrf` field instead.
+pub const CSRF_HEADER: &str = "x-minuteman-csrf";
+
+/// Token the admin forms carry so that other sites can't submit them with
+/// the admin cookie, derived from the admin token.
+pub fn csrf_token() -> Option<String> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(get_admin_token()?.as_bytes()).ok()?;
+
+    mac.update(b"csrf");
+
+    Some(
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect(),
+    )
+}
+
+/// Whether a request to an admin endpoint came with the `csrf_token`.
+pub fn verify_csrf(
+    csrf: Option<&str>,
+) -> bool {
+    match (csrf_token(), csrf) {
+        (Some(expected), Some(csrf)) => constant_time_eq(expected.as_bytes(), csrf.as_bytes()),
+        _ => false,
+    }
+}
+
+pub(crate) fn constant_time_eq(
+    a: &[u8],
+    b: &[u8],
+) -> bool {
+    a.len() == b.len()
+        && a.iter()
+        .zip(b.iter())
+        .fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub fn is_chat_anonymized(
+    chat_id: &str,
+) -> bool {
+    get_chat_policy(chat_id).anonymize
+        || get_anonymized_chats()
+        .iter()
+        .any(|anonymized| anonymized == "*" || anonymized == chat_id)
+}
+
+/// Stable pseudonym for a user id, e.g. `user-4f2a9c`. Without a configured
+/// secret every user collapses into the same pseudonym rather than leaking
+/// something that could be reversed.
+pub fn pseudonym(
+    user_id: &str,
+) -> String {
+    let secret =
+        match get_anonymize_secret() {
+            Some(secret) => secret,
+            None => return "user-anonymous".to_string(),
+        };
+
+    let mut mac =
+        match Hmac::<Sha256>::new_from_slice(secret.as_bytes()) {
+            Ok(mac) => mac,
+            Err(_) => return "user-anonymous".to_string(),
+        };
+
+    mac.update(user_id.as_bytes());
+
+    let digest = mac.finalize().into_bytes();
+
+    format!(
+        "user-{}",
+        digest[..3]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>(),
+    )
+}
+
+/// Rewrites a serialized `LogItem` so that it carries pseudonyms instead of
+/// user ids: drops the raw telegram message, replaces every `user_id` with a
+/// `user` pseudonym and redacts contact details.
+pub fn anonymize_log_item_json(
+    value: &mut Value,
+) {
+    match value {
+        Value::Object(map) => {
+            map.remove("source");
+            map.remove("author_signature");
+
+            for key in ["user_id", "via_bot", "admin_id"] {
+                if let Some(user_id) = map.remove(key) {
+                    let pseudonym =
+                        match user_id {
+                            Value::String(ref id) => Value::String(pseudonym(id)),
+                            Value::Number(ref id) => Value::String(pseudonym(&id.to_string())),
+                            _ => Value::Null,
+                        };
+
+                    map.insert(
+                        match key {
+                            "user_id" => "user".to_string(),
+                            "admin_id" => "admin".to_string(),
+                            _ => "via_bot".to_string(),
+                        },
+                        pseudonym,
+                    );
+                }
+            }
+
+            for key in ["phone_number", "first_name", "last_name", "vcard"] {
+                if map.contains_key(key) {
+                    map.insert(key.to_string(), Value::String("[redacted]".to_string()));
+                }
+            }
+
+            if map.contains_key("textmention") {
+                map.insert("textmention".to_string(), Value::Null);
+            }
+
+            for (_, child) in map.iter_mut() {
+                anonymize_log_item_json(child);
+            }
+        }
+        Value::Array(items) => {
+            for item in items.iter_mut() {
+                anonymize_log_item_json(item);
+            }
+        }
+        _ => {}
+    }
+}
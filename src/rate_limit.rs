@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Mutex, MutexGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use once_cell::sync::Lazy;
+
+use crate::config::{get_max_concurrent_listings, get_public_url, get_rate_limit, get_trusted_proxies, parse_base_url};
+use crate::metrics::record_rate_limited;
+
+/// Rejection for requests turned away by the rate limiter or the listing
+/// concurrency cap, answered with a 429 and `Retry-After`.
+#[derive(Debug)]
+pub struct RateLimited {
+    pub reason: &'static str,
+    pub retry_after: u64,
+}
+
+impl warp::reject::Reject for RateLimited {}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+// buckets that refilled completely are dropped once there are this many
+const MAX_BUCKETS: usize = 10_000;
+
+static BUCKETS: Lazy<Mutex<HashMap<IpAddr, Bucket>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+static ACTIVE_LISTINGS: AtomicUsize = AtomicUsize::new(0);
+
+fn lock_buckets() -> MutexGuard<'static, HashMap<IpAddr, Bucket>> {
+    match BUCKETS.lock() {
+        Ok(buckets) => buckets,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Address a request came from. `X-Forwarded-For` is only believed when the
+/// peer is a trusted proxy, the client is the rightmost entry that isn't one.
+pub fn client_ip(
+    peer: Option<SocketAddr>,
+    forwarded_for: Option<String>,
+) -> Option<IpAddr> {
+    let peer = peer?.ip();
+    let trusted = get_trusted_proxies();
+
+    if !trusted.contains(&peer) {
+        return Some(peer);
+    }
+
+    forwarded_for
+        .as_deref()
+        .unwrap_or_default()
+        .split(',')
+        .rev()
+        .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+        .find(|ip| !trusted.contains(ip))
+        .or(Some(peer))
+}
+
+/// Address the archive was reached at, for absolute links in responses:
+/// the configured public url, or what a trusted proxy says in
+/// `X-Forwarded-Proto` and `X-Forwarded-Host`. The `Host` header alone
+/// isn't believed, None leaves the links relative.
+pub fn request_base_url(
+    peer: Option<SocketAddr>,
+    forwarded_proto: Option<String>,
+    forwarded_host: Option<String>,
+) -> Option<String> {
+    if let Some(public_url) = get_public_url() {
+        return Some(public_url);
+    }
+
+    if !get_trusted_proxies().contains(&peer?.ip()) {
+        return None;
+    }
+
+    // proxies in a chain each append theirs, the first one is the client's
+    let first = |value: String| value.split(',').next().unwrap_or_default().trim().to_string();
+
+    parse_base_url(
+        &format!(
+            "{}://{}",
+            first(forwarded_proto?).to_lowercase(),
+            first(forwarded_host?),
+        ),
+    )
+        // a host, no path
+        .filter(|url| url.matches('/').count() == 2)
+}
+
+/// Takes a token from the address' bucket, which holds `burst` of them and
+/// refills at `requests_per_second`. Fails with the rejection to answer
+/// with when the bucket is empty.
+pub fn take_token(
+    ip: IpAddr,
+) -> Result<(), RateLimited> {
+    let (requests_per_second, burst) =
+        match get_rate_limit() {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+
+    let mut buckets = lock_buckets();
+    let now = Instant::now();
+
+    if buckets.len() >= MAX_BUCKETS {
+        buckets.retain(|_, bucket|
+            bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * requests_per_second < burst
+        );
+    }
+
+    let bucket =
+        buckets
+            .entry(ip)
+            .or_insert(
+                Bucket {
+                    tokens: burst,
+                    updated: now,
+                },
+            );
+
+    bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * requests_per_second).min(burst);
+    bucket.updated = now;
+
+    if bucket.tokens < 1.0 {
+        record_rate_limited("rate");
+
+        return Err(
+            RateLimited {
+                reason: "rate",
+                retry_after: ((1.0 - bucket.tokens) / requests_per_second).ceil().max(1.0) as u64,
+            },
+        );
+    }
+
+    bucket.tokens -= 1.0;
+
+    Ok(())
+}
+
+/// One of the `MINUTEMAN_MAX_CONCURRENT_LISTINGS` slots for rendering a day
+/// listing or export, given back when dropped.
+pub struct ListingSlot;
+
+impl Drop for ListingSlot {
+    fn drop(&mut self) {
+        ACTIVE_LISTINGS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub fn acquire_listing_slot() -> Result<ListingSlot, RateLimited> {
+    let max = get_max_concurrent_listings();
+
+    let acquired =
+        ACTIVE_LISTINGS.fetch_update(
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+            |active| if max == 0 || active < max { Some(active + 1) } else { None },
+        );
+
+    match acquired {
+        Ok(_) => Ok(ListingSlot),
+        Err(_) => {
+            record_rate_limited("concurrency");
+
+            Err(
+                RateLimited {
+                    reason: "concurrency",
+                    retry_after: 1,
+                },
+            )
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+
+use crate::RENDER_CACHE_TTL;
+use crate::config::get_render_cache_size;
+use crate::metrics::record_cache_lookup;
+
+#[derive(Debug, Clone)]
+pub struct CachedPage {
+    pub content_type: String,
+    pub body: Vec<u8>,
+}
+
+struct CacheEntry {
+    chat_id: String,
+    page: CachedPage,
+    rendered_at: Instant,
+    used: u64,
+}
+
+/// Rendered pages of days that are over, least recently used ones are
+/// dropped first once `capacity` bytes are taken up. Pages still expire
+/// after `RENDER_CACHE_TTL` so renamed users and chats show up eventually.
+struct RenderCache {
+    capacity: usize,
+    size: usize,
+    entries: HashMap<String, CacheEntry>,
+    // last use -> key, least recently used first
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    // bumped on every invalidation, a page rendered while its chat was
+    // written to is thrown away instead of cached
+    generations: HashMap<String, u64>,
+}
+
+impl RenderCache {
+    fn remove(
+        &mut self,
+        key: &str,
+    ) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.used);
+            self.size -= entry.page.body.len();
+        }
+    }
+
+    fn generation(
+        &self,
+        chat_id: &str,
+    ) -> u64 {
+        self.generations
+            .get(chat_id)
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+static RENDER_CACHE: Lazy<Mutex<RenderCache>> =
+    Lazy::new(||
+        Mutex::new(
+            RenderCache {
+                capacity: get_render_cache_size(),
+                size: 0,
+                entries: HashMap::new(),
+                order: BTreeMap::new(),
+                tick: 0,
+                generations: HashMap::new(),
+            },
+        )
+    );
+
+fn lock_cache() -> MutexGuard<'static, RenderCache> {
+    match RENDER_CACHE.lock() {
+        Ok(cache) => cache,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+pub fn render_cache_enabled() -> bool {
+    lock_cache().capacity > 0
+}
+
+/// Current generation of a chat's cached pages, to be taken before
+/// rendering and handed back to `store_page`.
+pub fn page_generation(
+    chat_id: &str,
+) -> u64 {
+    lock_cache().generation(chat_id)
+}
+
+pub fn get_cached_page(
+    key: &str,
+) -> Option<CachedPage> {
+    let mut cache = lock_cache();
+
+    if cache.capacity == 0 {
+        return None;
+    }
+
+    let expired =
+        cache.entries
+            .get(key)
+            .map(|entry| entry.rendered_at.elapsed() > Duration::from_secs(RENDER_CACHE_TTL));
+
+    match expired {
+        Some(false) => {}
+        Some(true) => {
+            cache.remove(key);
+            record_cache_lookup("render", false);
+
+            return None;
+        }
+        None => {
+            record_cache_lookup("render", false);
+
+            return None;
+        }
+    }
+
+    cache.tick += 1;
+
+    let tick = cache.tick;
+    let entry = cache.entries.get_mut(key)?;
+    let previous = entry.used;
+
+    entry.used = tick;
+
+    let page = entry.page.clone();
+
+    cache.order.remove(&previous);
+    cache.order.insert(tick, key.to_string());
+
+    record_cache_lookup("render", true);
+
+    Some(page)
+}
+
+/// Caches a rendered page unless its chat was invalidated since
+/// `generation` was taken, or it's too big to ever fit.
+pub fn store_page(
+    key: &str,
+    chat_id: &str,
+    generation: u64,
+    page: CachedPage,
+) {
+    let mut cache = lock_cache();
+
+    if page.body.len() > cache.capacity || cache.generation(chat_id) != generation {
+        return;
+    }
+
+    cache.remove(key);
+
+    while cache.size + page.body.len() > cache.capacity {
+        let oldest =
+            match cache.order.values().next().cloned() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+
+        cache.remove(&oldest);
+    }
+
+    cache.tick += 1;
+
+    let tick = cache.tick;
+
+    cache.size += page.body.len();
+    cache.order.insert(tick, key.to_string());
+
+    cache.entries.insert(
+        key.to_string(),
+        CacheEntry {
+            chat_id: chat_id.to_string(),
+            page,
+            rendered_at: Instant::now(),
+            used: tick,
+        },
+    );
+}
+
+/// Drops every cached page, for a process that learns about writes only
+/// once it caught up with another one's database.
+pub fn clear_render_cache() {
+    let mut cache = lock_cache();
+
+    for generation in cache.generations.values_mut() {
+        *generation += 1;
+    }
+
+    cache.entries.clear();
+    cache.order.clear();
+    cache.size = 0;
+}
+
+/// Drops every cached page of a chat. Has to be called whenever something
+/// a finished day shows changes: late or backdated log items, redactions,
+/// rewrites, the first message of a new day (it moves the "next" links)
+/// and title changes.
+pub fn invalidate_chat_pages(
+    chat_id: &str,
+) {
+    let mut cache = lock_cache();
+
+    *cache.generations.entry(chat_id.to_string()).or_default() += 1;
+
+    if cache.capacity == 0 {
+        return;
+    }
+
+    let keys =
+        cache.entries
+            .iter()
+            .filter(|(_, entry)| entry.chat_id == chat_id)
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<String>>();
+
+    for key in keys.iter() {
+        cache.remove(key);
+    }
+}
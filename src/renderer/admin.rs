@@ -0,0 +1,386 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use chrono::NaiveDateTime;
+use rocksdb::{DBWithThreadMode, Direction, IteratorMode, MultiThreaded, ReadOptions};
+use serde::Deserialize;
+use serde_json::json;
+use warp::http::{header, Response, StatusCode};
+use warp::hyper::Body;
+use warp::Reply;
+
+use crate::MinutemanError;
+use crate::components::chat_event::format_timer;
+use crate::components::header::HeaderBar;
+use crate::components::page::{Page, Theme};
+use crate::config::get_log_filter;
+use crate::logging::set_log_filter;
+use crate::privacy::{csrf_token, is_chat_anonymized, verify_csrf, Viewer};
+use crate::renderer::error::error_page;
+use crate::renderer::storage::format_bytes;
+use crate::storage::{lock_db, ReadStore, Storage};
+use crate::storage_stats::{build_storage_counter_key, get_storage_counter};
+use crate::utils::{escape_html, find_latest_chat_day, NameCache};
+use crate::workers::chat_policy::{ChatPolicy, ChatPolicyChange, find_chat_policy_changes, get_chat_policy, set_chat_policy};
+
+fn find_chat_ids(
+    db: &impl ReadStore,
+) -> Vec<String> {
+    let mut opts = ReadOptions::default();
+
+    opts.set_iterate_upper_bound(b"chat_rel:\xff".to_vec());
+
+    db.iterator_opt(IteratorMode::From(b"chat_rel:", Direction::Forward), opts)
+        .filter_map(|(key, _)| String::from_utf8(key.to_vec()).ok())
+        .filter_map(|key| key.strip_prefix("chat_rel:").map(|chat_id| chat_id.to_string()))
+        .filter(|chat_id| !chat_id.contains(':'))
+        .collect()
+}
+
+fn policy_form(
+    chat_id: &str,
+    policy: &ChatPolicy,
+    csrf: &str,
+) -> String {
+    let toggle = |field: &str, enabled: bool, label: &str| {
+        format!(
+            "<form method=\"post\" action=\"/admin/chat/{}/policy\">\
+                <input type=\"hidden\" name=\"csrf\" value=\"{}\"/>\
+                <input type=\"hidden\" name=\"{}\" value=\"{}\"/>\
+                <button type=\"submit\">{}</button>\
+            </form>",
+            escape_html(chat_id),
+            csrf,
+            field,
+            if enabled { "0" } else { "1" },
+            label,
+        )
+    };
+
+    let retention =
+        format!(
+            "<form method=\"post\" action=\"/admin/chat/{}/policy\">\
+                <input type=\"hidden\" name=\"csrf\" value=\"{}\"/>\
+                <input type=\"number\" name=\"retention\" min=\"0\" value=\"{}\"/> days \
+                <button type=\"submit\">keep</button>\
+            </form>",
+            escape_html(chat_id),
+            csrf,
+            policy.retention.map(|seconds| (seconds / 86_400).to_string()).unwrap_or_default(),
+        );
+
+    format!(
+        "{}{}{}{}",
+        toggle("logging", policy.logging, if policy.logging { "stop logging" } else { "start logging" }),
+        toggle("anonymize", policy.anonymize, if policy.anonymize { "stop anonymizing" } else { "anonymize" }),
+        toggle("digest", policy.digest, if policy.digest { "stop digest" } else { "daily digest" }),
+        retention,
+    )
+}
+
+fn format_retention(
+    retention: Option<i64>,
+) -> String {
+    retention
+        .map(format_timer)
+        .unwrap_or("forever".to_string())
+}
+
+/// The fields a policy change touched, as `field before → after`.
+fn describe_policy_change(
+    change: &ChatPolicyChange,
+) -> String {
+    let (before, after) = (&change.before, &change.after);
+
+    let mut fields = Vec::<String>::new();
+
+    let mut flag = |name: &str, before: bool, after: bool| {
+        if before != after {
+            fields.push(format!("{} {} → {}", name, before, after));
+        }
+    };
+
+    flag("logging", before.logging, after.logging);
+    flag("anonymized", before.anonymize, after.anonymize);
+    flag("digest", before.digest, after.digest);
+    flag("private opt-in", before.private_opt_in, after.private_opt_in);
+    flag("archived", before.bot_removed_at.is_some(), after.bot_removed_at.is_some());
+
+    if before.retention != after.retention {
+        fields.push(
+            format!(
+                "retention {} → {}",
+                format_retention(before.retention),
+                format_retention(after.retention),
+            ),
+        );
+    }
+
+    fields.join(", ")
+}
+
+/// `GET /admin`, every known chat with its policy, last activity and the
+/// storage it takes up, followed by the latest policy changes.
+pub async fn admin(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    viewer: Viewer,
+    theme: Theme,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let csrf =
+        match csrf_token() {
+            Some(csrf) if viewer.admin => csrf,
+            _ =>
+                return Ok(
+                    error_page(
+                        StatusCode::FORBIDDEN,
+                        "admin token required",
+                        theme,
+                    ),
+                ),
+        };
+
+    let dbi = lock_db(&db);
+
+    let view = dbi.read_view();
+
+    let mut names = NameCache::new(&view);
+
+    let mut out =
+        vec!(
+            "<div class=\"info\"><table class=\"info admin\"><tbody>".to_string(),
+            "<tr>\
+                <td class=\"label\">chat</td>\
+                <td class=\"label\">logging</td>\
+                <td class=\"label\">anonymized</td>\
+                <td class=\"label\">retention</td>\
+                <td class=\"label\">last active</td>\
+                <td class=\"label\">storage</td>\
+                <td class=\"label\"></td>\
+            </tr>".to_string(),
+        );
+
+    for chat_id in find_chat_ids(&view) {
+        let policy = get_chat_policy(&chat_id);
+
+        let bytes =
+            get_storage_counter(&view, &build_storage_counter_key("chat_messages", &chat_id)).bytes
+                + get_storage_counter(&view, &build_storage_counter_key("chat_files", &chat_id)).bytes;
+
+        out.push(
+            format!(
+                "<tr>\
+                    <td><a href=\"/chat/{}/info\">{}</a></td>\
+                    <td>{}</td>\
+                    <td>{}</td>\
+                    <td>{}</td>\
+                    <td>{}</td>\
+                    <td>{}</td>\
+                    <td class=\"actions\">{}</td>\
+                </tr>",
+                escape_html(&chat_id),
+                escape_html(&names.chat_name(&chat_id)),
+                if policy.logging { "on" } else { "<b>off</b>" },
+                match (policy.anonymize, is_chat_anonymized(&chat_id)) {
+                    (true, _) => "yes",
+                    (false, true) => "yes <span class=\"note\">(config)</span>",
+                    _ => "no",
+                },
+                format_retention(policy.retention),
+                find_latest_chat_day(&view, &chat_id).unwrap_or("-".to_string()),
+                format_bytes(bytes),
+                policy_form(&chat_id, &policy, &csrf),
+            ),
+        );
+    }
+
+    out.push("</tbody></table>".to_string());
+
+    out.push("<h3>policy changes</h3><ul class=\"history\">".to_string());
+
+    let changes = find_chat_policy_changes(&view, 50);
+
+    for change in changes.iter() {
+        let time =
+            NaiveDateTime::from_timestamp_opt(change.time, 0)
+                .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+                .unwrap_or_default();
+
+        out.push(
+            format!(
+                "<li><span class=\"time\">{}</span> {}: {}</li>",
+                time,
+                escape_html(&names.chat_name(&change.chat_id)),
+                describe_policy_change(change),
+            ),
+        );
+    }
+
+    if changes.is_empty() {
+        out.push("<li><span class=\"note\">No changes recorded.</span></li>".to_string());
+    }
+
+    out.push("</ul></div>".to_string());
+
+    Ok(
+        warp::reply::html(
+            Page::new("admin")
+                .with_theme(theme)
+                .with_header(
+                    HeaderBar::new()
+                        .with_link(
+                            "<- home",
+                            Some("/".into()),
+                        )
+                        .with_title("admin")
+                        .with_link(
+                            "storage",
+                            Some("/admin/storage".into()),
+                        )
+                        .with_link(
+                            "status",
+                            Some("/admin/status".into()),
+                        )
+                        .with_link(
+                            "inline",
+                            Some("/admin/inline".into()),
+                        ),
+                )
+                .with_body(out.join(""))
+                .render(),
+        ).into_response(),
+    )
+}
+
+/// `POST /admin/chat/{chat_id}/policy`, takes `logging`, `anonymize` and
+/// `digest` as `0` or `1` and `retention` in days, empty or `0` keeping
+/// messages for good. Fields left out keep their value.
+pub async fn update_chat_policy(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    chat_id: String,
+    form: HashMap<String, String>,
+    viewer: Viewer,
+    theme: Theme,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if !viewer.admin || !verify_csrf(form.get("csrf").map(String::as_str)) {
+        return Ok(
+            error_page(
+                StatusCode::FORBIDDEN,
+                "admin token required",
+                theme,
+            ),
+        );
+    }
+
+    let mut policy = get_chat_policy(&chat_id);
+
+    if let Some(logging) = form.get("logging") {
+        policy.logging = logging == "1";
+    }
+
+    if let Some(anonymize) = form.get("anonymize") {
+        policy.anonymize = anonymize == "1";
+    }
+
+    if let Some(digest) = form.get("digest") {
+        policy.digest = digest == "1";
+    }
+
+    if let Some(retention) = form.get("retention") {
+        policy.retention =
+            retention
+                .trim()
+                .parse::<i64>()
+                .ok()
+                .filter(|days| *days > 0)
+                .and_then(|days| days.checked_mul(86_400));
+    }
+
+    {
+        let dbi = lock_db(&db);
+
+        set_chat_policy(&dbi, &chat_id, policy.clone())
+            .map_err(|err|
+                warp::reject::custom(
+                    MinutemanError::DBError(
+                        format!("{:?}", err),
+                    ),
+                )
+            )?;
+    }
+
+    tracing::info!(chat_id = %chat_id, policy = ?policy, "chat policy changed");
+
+    Ok(
+        Response::builder()
+            .status(StatusCode::SEE_OTHER)
+            .header(header::LOCATION, "/admin")
+            .body(Body::empty())
+            .unwrap(),
+    )
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LogFilterRequest {
+    // a `RUST_LOG` style directive, the configured one when left out
+    pub directive: Option<String>,
+    // the admin page's `csrf_token`, unless sent as the `CSRF_HEADER`
+    #[serde(default)]
+    pub csrf: Option<String>,
+}
+
+/// `POST /admin/log-filter`, changes which events are logged without a
+/// restart. Takes the CSRF token in the body or the `CSRF_HEADER`.
+pub async fn update_log_filter(
+    request: LogFilterRequest,
+    csrf: Option<String>,
+    viewer: Viewer,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if !viewer.admin || !verify_csrf(request.csrf.as_deref().or(csrf.as_deref())) {
+        return Ok(
+            warp::reply::with_status(
+                warp::reply::json(
+                    &json!({
+                        "status": "admin token required",
+                        "error": true,
+                        "data": null
+                    }),
+                ),
+                StatusCode::FORBIDDEN,
+            ),
+        );
+    }
+
+    let directive = request.directive.unwrap_or_else(get_log_filter);
+
+    if let Err(err) = set_log_filter(&directive) {
+        return Ok(
+            warp::reply::with_status(
+                warp::reply::json(
+                    &json!({
+                        "status": err,
+                        "error": true,
+                        "data": null
+                    }),
+                ),
+                StatusCode::BAD_REQUEST,
+            ),
+        );
+    }
+
+    tracing::info!(directive = %directive, "log filter changed");
+
+    Ok(
+        warp::reply::with_status(
+            warp::reply::json(
+                &json!({
+                    "status": "ok",
+                    "error": false,
+                    "data": {
+                        "directive": directive
+                    }
+                }),
+            ),
+            StatusCode::OK,
+        ),
+    )
+}
@@ -0,0 +1,59 @@
+use warp::http::{header, HeaderValue, Response, StatusCode};
+use warp::hyper::Body;
+
+use crate::{GLOBAL_CSS, GLOBAL_CSS_HASH};
+use crate::config::get_version;
+
+pub fn global_css_etag() -> String {
+    format!("\"{:016x}\"", GLOBAL_CSS_HASH)
+}
+
+pub fn global_css_url() -> String {
+    format!(
+        "/assets/global.css?v={}",
+        get_version(),
+    )
+}
+
+pub fn stylesheet_link() -> String {
+    format!(
+        "<link rel=\"stylesheet\" type=\"text/css\" href=\"{}\">",
+        global_css_url(),
+    )
+}
+
+pub async fn global_css(
+    if_none_match: Option<String>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let etag = global_css_etag();
+
+    let builder =
+        Response::builder()
+            .header(
+                header::CACHE_CONTROL,
+                HeaderValue::from_static("public, max-age=31536000, immutable"),
+            )
+            .header(
+                header::ETAG,
+                &etag,
+            );
+
+    if if_none_match.as_deref() == Some(etag.as_str()) {
+        return Ok(
+            builder
+                .status(StatusCode::NOT_MODIFIED)
+                .body(Body::empty())
+                .unwrap(),
+        );
+    }
+
+    Ok(
+        builder
+            .header(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("text/css; charset=utf-8"),
+            )
+            .body(Body::from(GLOBAL_CSS))
+            .unwrap(),
+    )
+}
@@ -0,0 +1,601 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use chrono::{Datelike, DateTime, NaiveDate, NaiveDateTime, Utc, Weekday};
+use rocksdb::{DBWithThreadMode, Direction, IteratorMode, MultiThreaded, ReadOptions};
+use serde_json::{json, Value};
+use warp::http::{header, Response, StatusCode};
+use warp::hyper::Body;
+use warp::hyper::body::to_bytes;
+use warp::Reply;
+
+use crate::{MinutemanError, ok_or_continue, some_or_continue};
+use crate::components::header::HeaderBar;
+use crate::components::page::{Page, Theme};
+use crate::privacy::{pseudonym, Viewer};
+use crate::rate_limit::acquire_listing_slot;
+use crate::render_cache::{CachedPage, get_cached_page, page_generation, render_cache_enabled, store_page};
+use crate::renderer::chat_info::find_chat_meta_history;
+use crate::renderer::chat_listing::{day_time_bounds, pin_snippet};
+use crate::renderer::error::error_page;
+use crate::storage::{lock_db, ReadStore, Storage};
+use crate::utils::{escape_html, format_chat_day, message_permalink, NameCache, resolve_chat_name};
+use crate::workers::telegram_handler::{ChatMetaChange, LogItem, LogItemMediaType, LogItemMembershipType, parse_message_key};
+
+const TOP_POSTERS: usize = 5;
+
+/// A week (`2024-W05`, ISO numbering) or a month (`2024-02`) of a chat.
+#[derive(Debug, Clone)]
+pub struct DigestPeriod {
+    pub kind: &'static str,
+    pub label: String,
+    pub start: NaiveDate,
+    // first day after the period
+    pub end: NaiveDate,
+}
+
+impl DigestPeriod {
+    pub fn parse(
+        kind: &str,
+        value: &str,
+    ) -> Option<Self> {
+        match kind {
+            "week" => {
+                let (year, week) = value.split_once("-W")?;
+
+                let year = year.parse::<i32>().ok()?;
+                let week = week.parse::<u32>().ok()?;
+
+                let start = NaiveDate::from_isoywd_opt(year, week, Weekday::Mon)?;
+
+                Some(
+                    DigestPeriod {
+                        kind: "week",
+                        label: format!("{}-W{:02}", year, week),
+                        start,
+                        end: start + chrono::Duration::days(7),
+                    },
+                )
+            }
+            "month" => {
+                let start = NaiveDate::parse_from_str(&format!("{}-01", value), "%Y-%m-%d").ok()?;
+
+                let end =
+                    match start.month() {
+                        12 => NaiveDate::from_ymd_opt(start.year() + 1, 1, 1)?,
+                        month => NaiveDate::from_ymd_opt(start.year(), month + 1, 1)?,
+                    };
+
+                Some(
+                    DigestPeriod {
+                        kind: "month",
+                        label: start.format("%Y-%m").to_string(),
+                        start,
+                        end,
+                    },
+                )
+            }
+            _ => None,
+        }
+    }
+
+    /// The period containing `date`.
+    fn containing(
+        kind: &'static str,
+        date: NaiveDate,
+    ) -> Option<Self> {
+        match kind {
+            "week" => {
+                let week = date.iso_week();
+
+                DigestPeriod::parse("week", &format!("{}-W{:02}", week.year(), week.week()))
+            }
+            _ => DigestPeriod::parse("month", &date.format("%Y-%m").to_string()),
+        }
+    }
+
+    fn previous(&self) -> Option<Self> {
+        DigestPeriod::containing(self.kind, self.start.pred_opt()?)
+    }
+
+    fn next(&self) -> Option<Self> {
+        DigestPeriod::containing(self.kind, self.end)
+    }
+
+    fn url(
+        &self,
+        chat_id: &str,
+    ) -> String {
+        format!("/chat/{}/{}/{}", chat_id, self.kind, self.label)
+    }
+
+    /// Past periods never change anymore, unless something is backdated or
+    /// redacted, which invalidates the chat's cached pages anyway.
+    fn is_over(&self) -> bool {
+        self.end <= Utc::today().naive_utc()
+    }
+}
+
+/// Digest page of the week or month that is running right now.
+pub fn current_period_url(
+    chat_id: &str,
+    kind: &'static str,
+) -> Option<String> {
+    DigestPeriod::containing(kind, Utc::today().naive_utc())
+        .map(|period| period.url(chat_id))
+}
+
+#[derive(Debug, Default)]
+pub struct Digest {
+    pub messages: u64,
+    pub media: u64,
+    pub photos: u64,
+    // user id -> messages and media sent
+    pub posters: HashMap<String, u64>,
+    // day -> messages and media sent
+    pub days: BTreeMap<i64, u64>,
+    // (time, user id, message id, pinned text)
+    pub pins: Vec<(i64, Option<String>, String, Option<String>)>,
+    pub memberships: Vec<(i64, Option<String>, LogItemMembershipType)>,
+}
+
+impl Digest {
+    pub fn top_posters(&self) -> Vec<(String, u64)> {
+        let mut posters =
+            self.posters
+                .iter()
+                .map(|(user_id, count)| (user_id.clone(), *count))
+                .collect::<Vec<(String, u64)>>();
+
+        posters.sort_by(|(a_id, a), (b_id, b)| b.cmp(a).then(a_id.cmp(b_id)));
+        posters.truncate(TOP_POSTERS);
+
+        posters
+    }
+}
+
+/// Aggregates the log items between `time_start` (inclusive) and `time_end`
+/// (exclusive) in one scan bounded by their keys. Whatever `skip_user` sent
+/// doesn't count.
+pub fn build_digest(
+    db: &impl ReadStore,
+    chat_id: &str,
+    time_start: i64,
+    time_end: i64,
+    skip_user: Option<&str>,
+) -> Digest {
+    let mut opts = ReadOptions::default();
+
+    let lower_bound = format!("chat:{}:{}", chat_id, time_start).as_bytes().to_vec();
+    let upper_bound = format!("chat:{}:{}", chat_id, time_end).as_bytes().to_vec();
+
+    opts.set_iterate_lower_bound(lower_bound.clone());
+    opts.set_iterate_upper_bound(upper_bound);
+
+    let mut digest = Digest::default();
+
+    let iter =
+        db.iterator_opt(
+            IteratorMode::From(&lower_bound, Direction::Forward),
+            opts,
+        );
+
+    for (key, val) in iter {
+        let key = ok_or_continue!(String::from_utf8(key.to_vec()));
+        let (_, slot) = some_or_continue!(parse_message_key(&key));
+        let timestamp = slot.time;
+
+        let (media_type, user_id) =
+            match ok_or_continue!(serde_json::from_slice::<LogItem>(&val)) {
+                LogItem::Message { user_id, .. } => (None, user_id),
+                LogItem::Media { user_id, media_type, .. } => (Some(media_type), user_id),
+                LogItem::Pin { user_id, message_id, message, .. } => {
+                    digest.pins.push((timestamp, user_id, message_id, message));
+                    continue;
+                }
+                LogItem::Membership { user_id, membership_type, .. } => {
+                    digest.memberships.push((timestamp, user_id, membership_type));
+                    continue;
+                }
+                _ => continue,
+            };
+
+        if user_id.is_some() && user_id.as_deref() == skip_user {
+            continue;
+        }
+
+        match media_type {
+            Some(LogItemMediaType::Image { .. }) => {
+                digest.media += 1;
+                digest.photos += 1;
+            }
+            Some(_) => digest.media += 1,
+            None => digest.messages += 1,
+        }
+
+        if let Some(user_id) = user_id {
+            *digest.posters.entry(user_id).or_default() += 1;
+        }
+
+        *digest.days.entry(timestamp / 86_400).or_default() += 1;
+    }
+
+    digest
+}
+
+fn format_time(
+    timestamp: i64,
+) -> String {
+    NaiveDateTime::from_timestamp_opt(timestamp, 0)
+        .map(|time| DateTime::<Utc>::from_utc(time, Utc))
+        .map(|time| time.format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_default()
+}
+
+fn day_link(
+    chat_id: &str,
+    timestamp: i64,
+) -> String {
+    let day = format_chat_day(timestamp / 86_400).unwrap_or_default();
+
+    format!("<a href=\"/chat/{}/{}\">{}</a>", chat_id, day, day)
+}
+
+pub async fn chat_digest(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    chat_id: String,
+    kind: &'static str,
+    period_query: String,
+    viewer: Viewer,
+    theme: Theme,
+) -> Result<Response<Body>, warp::Rejection> {
+    let (period, out_format) =
+        match period_query.strip_suffix(".json") {
+            Some(period) => (period, "json"),
+            None => (period_query.as_str(), "html"),
+        };
+
+    let period =
+        match DigestPeriod::parse(kind, period) {
+            Some(period) => period,
+            None => return Ok(error_page(StatusCode::BAD_REQUEST, &format!("invalid {}", kind), theme)),
+        };
+
+    let anonymize = viewer.anonymize_chat(&chat_id);
+
+    let cache_key =
+        Some(format!("{}/{}/{}.{}?anonymize={}&theme={}", chat_id, kind, period.label, out_format, anonymize, theme.name()))
+            .filter(|_| render_cache_enabled() && period.is_over());
+
+    if let Some(page) = cache_key.as_deref().map(get_cached_page).flatten() {
+        return Ok(
+            Response::builder()
+                .header(header::CONTENT_TYPE, page.content_type)
+                .header("x-minuteman-cache", "hit")
+                .body(Body::from(page.body))
+                .unwrap(),
+        );
+    }
+
+    let generation = page_generation(&chat_id);
+
+    let response =
+        render_chat_digest(db, &chat_id, &period, out_format, anonymize, theme)
+            .await?;
+
+    let cache_key =
+        match cache_key {
+            Some(cache_key) if response.status() == StatusCode::OK => cache_key,
+            _ => return Ok(response),
+        };
+
+    let content_type =
+        response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .map(|content_type| content_type.to_str().ok())
+            .flatten()
+            .unwrap_or("text/html; charset=utf-8")
+            .to_string();
+
+    let body =
+        to_bytes(response.into_body())
+            .await
+            .map_err(|err|
+                warp::reject::custom(
+                    MinutemanError::Other(
+                        format!("{:?}", err),
+                    ),
+                )
+            )?
+            .to_vec();
+
+    store_page(
+        &cache_key,
+        &chat_id,
+        generation,
+        CachedPage {
+            content_type: content_type.clone(),
+            body: body.clone(),
+        },
+    );
+
+    Ok(
+        Response::builder()
+            .header(header::CONTENT_TYPE, content_type)
+            .header("x-minuteman-cache", "miss")
+            .body(Body::from(body))
+            .unwrap(),
+    )
+}
+
+async fn render_chat_digest(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    chat_id: &str,
+    period: &DigestPeriod,
+    out_format: &'static str,
+    anonymize: bool,
+    theme: Theme,
+) -> Result<Response<Body>, warp::Rejection> {
+    let _slot = acquire_listing_slot().map_err(warp::reject::custom)?;
+
+    let dbi = lock_db(&db);
+
+    let view = dbi.read_view();
+
+    let (time_start, _) = day_time_bounds(period.start);
+    let (time_end, _) = day_time_bounds(period.end);
+
+    let time_start = time_start.parse::<i64>().unwrap_or_default();
+    let time_end = time_end.parse::<i64>().unwrap_or_default();
+
+    let digest = build_digest(&view, chat_id, time_start, time_end, None);
+
+    let meta_changes =
+        find_chat_meta_history(&view, chat_id)
+            .into_iter()
+            .filter(|entry| entry.time >= time_start && entry.time < time_end)
+            .collect::<Vec<_>>();
+
+    let mut names =
+        NameCache::new(&view)
+            .with_anonymized(anonymize);
+
+    let user_ref = |user_id: &str| -> Value {
+        if anonymize {
+            json!({ "user": pseudonym(user_id) })
+        } else {
+            json!({ "user_id": user_id })
+        }
+    };
+
+    if out_format == "json" {
+        return Ok(
+            warp::reply::json(
+                &json!({
+                    "status": "ok",
+                    "error": false,
+                    "data": {
+                        "chat_id": chat_id,
+                        "chat_name": resolve_chat_name(&view, chat_id),
+                        "period": period.label,
+                        "start": period.start.format("%Y-%m-%d").to_string(),
+                        "end": period.end.pred().format("%Y-%m-%d").to_string(),
+                        "messages": digest.messages,
+                        "media": digest.media,
+                        "photos": digest.photos,
+                        "active_users": digest.posters.len(),
+                        "top_posters": digest.top_posters()
+                            .iter()
+                            .map(|(user_id, count)| {
+                                let mut poster = user_ref(user_id);
+
+                                poster["name"] = json!(names.user(user_id, false));
+                                poster["count"] = json!(count);
+
+                                poster
+                            })
+                            .collect::<Vec<Value>>(),
+                        "days": digest.days
+                            .iter()
+                            .map(|(day, count)|
+                                json!({
+                                    "day": format_chat_day(*day),
+                                    "count": count,
+                                })
+                            )
+                            .collect::<Vec<Value>>(),
+                        "pins": digest.pins
+                            .iter()
+                            .map(|(time, user_id, _, message)| {
+                                let mut pin = user_id.as_deref().map(user_ref).unwrap_or(json!({}));
+
+                                pin["time"] = json!(time);
+                                pin["message"] = json!(message);
+                                pin["permalink"] = json!(message_permalink(chat_id, *time));
+
+                                pin
+                            })
+                            .collect::<Vec<Value>>(),
+                        "memberships": digest.memberships
+                            .iter()
+                            .map(|(time, user_id, membership_type)| {
+                                let mut membership = user_id.as_deref().map(user_ref).unwrap_or(json!({}));
+
+                                membership["time"] = json!(time);
+                                membership["type"] = json!(membership_type.code());
+
+                                membership
+                            })
+                            .collect::<Vec<Value>>(),
+                        "chat_changes": meta_changes
+                            .iter()
+                            .map(|entry|
+                                json!({
+                                    "time": entry.time,
+                                    "change": entry.change,
+                                })
+                            )
+                            .collect::<Vec<Value>>(),
+                    },
+                }),
+            ).into_response(),
+        );
+    }
+
+    let chat_name = resolve_chat_name(&view, chat_id);
+
+    let mut out =
+        vec!(
+            "<div class=\"info\"><table class=\"info\"><tbody>".to_string(),
+            format!(
+                "<tr><td class=\"label\">period</td><td>{} to {}</td></tr>",
+                period.start.format("%Y-%m-%d"),
+                period.end.pred().format("%Y-%m-%d"),
+            ),
+            format!("<tr><td class=\"label\">messages</td><td>{}</td></tr>", digest.messages),
+            format!("<tr><td class=\"label\">media</td><td>{} ({} photo(s))</td></tr>", digest.media, digest.photos),
+            format!("<tr><td class=\"label\">active users</td><td>{}</td></tr>", digest.posters.len()),
+            "</tbody></table>".to_string(),
+        );
+
+    if !digest.posters.is_empty() {
+        out.push("<h3>top posters</h3><ol>".to_string());
+
+        for (user_id, count) in digest.top_posters() {
+            out.push(
+                format!(
+                    "<li>{} <span class=\"note\">{} message(s)</span></li>",
+                    escape_html(&names.user(&user_id, false)),
+                    count,
+                ),
+            );
+        }
+
+        out.push("</ol>".to_string());
+    }
+
+    if !digest.days.is_empty() {
+        out.push("<h3>days</h3><ul class=\"history\">".to_string());
+
+        for (day, count) in digest.days.iter() {
+            out.push(
+                format!(
+                    "<li>{} <span class=\"note\">{} message(s)</span></li>",
+                    day_link(chat_id, day * 86_400),
+                    count,
+                ),
+            );
+        }
+
+        out.push("</ul>".to_string());
+    }
+
+    if !digest.pins.is_empty() {
+        out.push("<h3>pins</h3><ul class=\"history\">".to_string());
+
+        for (time, user_id, message_id, message) in digest.pins.iter() {
+            out.push(
+                format!(
+                    "<li><span class=\"time\"><a href=\"{}\">{}</a></span> {} pinned {}</li>",
+                    message_permalink(chat_id, *time).unwrap_or_default(),
+                    format_time(*time),
+                    escape_html(
+                        &user_id
+                            .as_deref()
+                            .map(|user_id| names.user(user_id, false))
+                            .unwrap_or("Unknown".to_string()),
+                    ),
+                    pin_snippet(&view, chat_id, message_id, message),
+                ),
+            );
+        }
+
+        out.push("</ul>".to_string());
+    }
+
+    if !digest.memberships.is_empty() {
+        out.push("<h3>members</h3><ul class=\"history\">".to_string());
+
+        for (time, user_id, membership_type) in digest.memberships.iter() {
+            out.push(
+                format!(
+                    "<li><span class=\"time\"><a href=\"{}\">{}</a></span> {} {}</li>",
+                    message_permalink(chat_id, *time).unwrap_or_default(),
+                    format_time(*time),
+                    escape_html(
+                        &user_id
+                            .as_deref()
+                            .map(|user_id| names.user(user_id, false))
+                            .unwrap_or("Unknown".to_string()),
+                    ),
+                    membership_type.code(),
+                ),
+            );
+        }
+
+        out.push("</ul>".to_string());
+    }
+
+    if !meta_changes.is_empty() {
+        out.push("<h3>chat changes</h3><ul class=\"history\">".to_string());
+
+        for entry in meta_changes.iter() {
+            let change =
+                match entry.change {
+                    ChatMetaChange::Title { ref title } =>
+                        format!("title: <b>{}</b>", escape_html(title)),
+                    ChatMetaChange::Photo { .. } =>
+                        "new photo".to_string(),
+                    ChatMetaChange::DeletePhoto =>
+                        "photo removed".to_string(),
+                    ChatMetaChange::AutoDeleteTimer { .. } =>
+                        "auto-delete timer changed".to_string(),
+                };
+
+            out.push(
+                format!(
+                    "<li><span class=\"time\">{}</span> {} <span class=\"note\">({})</span></li>",
+                    format_time(entry.time),
+                    change,
+                    day_link(chat_id, entry.time),
+                ),
+            );
+        }
+
+        out.push("</ul>".to_string());
+    }
+
+    out.push("</div>".to_string());
+
+    let base_url = period.url(chat_id);
+
+    Ok(
+        warp::reply::html(
+            Page::new(format!("{} - {}", &chat_name, &period.label))
+                .with_theme(theme)
+                .with_header(
+                    HeaderBar::new()
+                        .with_link(
+                            "<- index",
+                            Some(format!("/chat/{}", chat_id)),
+                        )
+                        .with_title(&chat_name)
+                        .with_link(
+                            &format!("previous {}", period.kind),
+                            period.previous().map(|previous| previous.url(chat_id)),
+                        )
+                        .with_active(&period.label)
+                        .with_link(
+                            &format!("next {}", period.kind),
+                            period.next()
+                                .filter(|next| next.start <= Utc::today().naive_utc())
+                                .map(|next| next.url(chat_id)),
+                        )
+                        .with_format_link(&base_url, "json"),
+                )
+                .with_body(out.join(""))
+                .render(),
+        ).into_response()
+    )
+}
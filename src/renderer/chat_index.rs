@@ -1,109 +1,326 @@
 use std::sync::{Arc, Mutex};
 
-use chrono::{DateTime, NaiveDateTime, Utc};
+use chrono::{Datelike, DateTime, NaiveDateTime, Utc};
 use rocksdb::{DBWithThreadMode, Direction, IteratorMode, MultiThreaded, ReadOptions};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use warp::http::StatusCode;
+use warp::Reply;
 
-use crate::{GLOBAL_CSS, MinutemanError, ok_or_continue};
+use crate::{ok_or_continue, some_or_continue};
 use crate::components::header::HeaderBar;
+use crate::components::page::{Page, Theme};
 use crate::config::get_version;
-use crate::utils::resolve_chat_name;
+use crate::locales::{Lang, t, weekday_name};
+use crate::renderer::chat_digest::current_period_url;
+use crate::renderer::chat_listing::{ListingCursor, ListingPage};
+use crate::renderer::error::{error_json, error_page};
+use crate::storage::{lock_db, ReadStore, Storage};
+use crate::utils::{format_chat_day, is_archived_chat, resolve_chat_name};
 use crate::workers::telegram_handler::ChatMeta;
 
+// a year of days per page
+const DEFAULT_INDEX_LIMIT: usize = 366;
+
+const MAX_INDEX_LIMIT: usize = 5_000;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct IndexQuery {
+    pub limit: Option<usize>,
+    // days strictly before this one (days since epoch)
+    pub page: Option<String>,
+    // days strictly after this one (days since epoch)
+    pub after: Option<String>,
+}
+
+impl IndexQuery {
+    pub fn index_cursor(&self) -> Option<ListingCursor<i64>> {
+        let page =
+            self.page
+                .as_ref()
+                .map(|page| page.parse::<i64>().ok())
+                .flatten()
+                .map(ListingCursor::Older);
+
+        let after =
+            self.after
+                .as_ref()
+                .map(|after| after.parse::<i64>().ok())
+                .flatten()
+                .map(ListingCursor::Newer);
+
+        page.or(after)
+    }
+
+    pub fn index_limit(&self) -> usize {
+        self.limit
+            .unwrap_or(DEFAULT_INDEX_LIMIT)
+            .clamp(1, MAX_INDEX_LIMIT)
+    }
+}
+
+/// One page of the days on which something was logged in a chat, newest
+/// first. Like `chat_listing_iter` it seeks to the cursor instead of
+/// skipping over the days in front of it, so deep pages cost the same as
+/// the first one and stay stable while new days are appended.
+pub fn chat_days_page(
+    db: &impl ReadStore,
+    chat_id: &str,
+    cursor: &Option<ListingCursor<i64>>,
+    limit: usize,
+) -> (Vec<i64>, ListingPage) {
+    let mut opts = ReadOptions::default();
+
+    let mut lower_bound = format!("chat_index:{}:", &chat_id);
+    let mut upper_bound = format!("chat_index:{}:\x7f", &chat_id);
+
+    match cursor {
+        // the upper bound is exclusive already
+        Some(ListingCursor::Older(day)) =>
+            upper_bound = format!("chat_index:{}:{}", &chat_id, day),
+        // the lower bound isn't, start right behind the cursor's key
+        Some(ListingCursor::Newer(day)) =>
+            lower_bound = format!("chat_index:{}:{}\x00", &chat_id, day),
+        None => {}
+    }
+
+    opts.set_iterate_upper_bound(upper_bound.as_bytes().to_vec());
+    opts.set_iterate_lower_bound(lower_bound.as_bytes().to_vec());
+
+    let forward = matches!(cursor, Some(ListingCursor::Newer(_)));
+
+    let iter =
+        if forward {
+            db.iterator_opt(
+                IteratorMode::From(lower_bound.as_bytes(), Direction::Forward),
+                opts,
+            )
+        } else {
+            db.iterator_opt(
+                IteratorMode::From(upper_bound.as_bytes(), Direction::Reverse),
+                opts,
+            )
+        };
+
+    let mut page =
+        ListingPage {
+            has_newer: matches!(cursor, Some(ListingCursor::Older(_))),
+            has_older: forward,
+            ..ListingPage::default()
+        };
+
+    let mut days = Vec::<i64>::new();
+
+    for (key, _) in iter {
+        let key = ok_or_continue!(String::from_utf8(key.to_vec()));
+        let key = key.split(':').collect::<Vec<&str>>();
+
+        if key.len() != 3 {
+            continue;
+        }
+
+        let day = ok_or_continue!(key[key.len() - 1].parse::<i64>());
+
+        if days.len() == limit {
+            if forward {
+                page.has_newer = true;
+            } else {
+                page.has_older = true;
+            }
+
+            break;
+        }
+
+        days.push(day);
+    }
+
+    if forward {
+        days.reverse();
+    }
+
+    page.first = days.first().map(|day| day.to_string());
+    page.last = days.last().map(|day| day.to_string());
+
+    (days, page)
+}
+
+fn index_page_url(
+    chat_id: &str,
+    cursor_param: &str,
+    cursor: &str,
+    query: &IndexQuery,
+) -> String {
+    let mut url =
+        format!(
+            "/chat/{}?{}={}",
+            chat_id,
+            cursor_param,
+            cursor,
+        );
+
+    if let Some(limit) = query.limit {
+        url.push_str(&format!("&limit={}", limit));
+    }
+
+    url
+}
+
 pub async fn chat_index(
     db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
     chat_id: String,
+    out_format: &'static str,
+    query: IndexQuery,
+    lang: Lang,
+    raw_query: String,
+    theme: Theme,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let dbi =
-        db.lock()
-            .map_err(|err|
-                warp::reject::custom(
-                    MinutemanError::LockError(
-                        format!("{:?}", err),
-                    ),
-                )
-            )?;
-
-    let mut out =
-        vec!(
-            "<!DOCTYPE html><html lang=\"en\">".to_string(),
-            "<style type=\"text/css\">".to_string(),
-            GLOBAL_CSS.to_string(),
-            "</style>".to_string(),
-            "<head><title>channel index</title></head><body>".to_string(),
-            "<div class=\"index\"><ul>".to_string(),
-        );
+    let dbi = lock_db(&db);
 
-    let mut opts = ReadOptions::default();
+    let view = dbi.read_view();
 
-    let lower_bound = format!("chat_index:{}:", &chat_id).as_bytes().to_vec();
-    let upper_bound = format!("chat_index:{}:\x7f", &chat_id).as_bytes().to_vec();
+    if !is_archived_chat(&view, &chat_id) {
+        return Ok(
+            match out_format {
+                "json" => error_json(StatusCode::NOT_FOUND, "chat not archived"),
+                _ => error_page(StatusCode::NOT_FOUND, t(lang, "empty.not_archived"), theme),
+            },
+        );
+    }
 
-    opts.set_iterate_upper_bound(upper_bound.clone());
-    opts.set_iterate_lower_bound(lower_bound.clone());
+    let (days, page) =
+        chat_days_page(
+            &view,
+            &chat_id,
+            &query.index_cursor(),
+            query.index_limit(),
+        );
 
-    let mut iter =
-        dbi.iterator_opt(
-            IteratorMode::From(&upper_bound, Direction::Reverse),
-            opts,
+    if out_format == "json" {
+        return Ok(
+            warp::reply::json(
+                &json!({
+                    "status": "ok",
+                    "error": false,
+                    "data": Value::Array(
+                        days
+                            .iter()
+                            .filter_map(|day| format_chat_day(*day))
+                            .map(Value::String)
+                            .collect(),
+                    ),
+                    "next": page.older_cursor(),
+                    "prev": page.newer_cursor(),
+                }),
+            ).into_response(),
         );
+    }
 
     let chat_name =
         resolve_chat_name(
-            &dbi,
+            &view,
             &chat_id,
         );
 
-    out.push(
-        format!(
-            "<div class=\"navigation\"><span class=\"title\">{}</span> | <span class=\"nolink\">index</span> | <a href=\"/chat/{}/latest\">latest</a></div>",
-            &chat_name,
-            &chat_id,
-        ),
-    );
+    let mut out = Vec::<String>::new();
 
-    let mut i = 0;
-
-    for (key, _) in iter {
-        let key = key.to_vec();
-        let key = String::from_utf8(key).unwrap();
-        let key = key.split(":").collect::<Vec<&str>>();
+    // known chats whose messages were all removed since, or that only ever
+    // had an update without one
+    if days.is_empty() && !page.has_newer && !page.has_older {
+        out.push(
+            format!(
+                "<div class=\"empty\"><p>{}</p></div>",
+                t(lang, "empty.chat"),
+            ),
+        );
+    }
 
-        if key.len() != 3 {
-            continue;
-        }
+    out.push("<div class=\"index\"><ul>".to_string());
 
-        let day = key[key.len() - 1];
+    for (i, day) in days.into_iter().enumerate() {
+        let weekday =
+            some_or_continue!(NaiveDateTime::from_timestamp_opt(day * 86_400, 0))
+                .weekday();
 
-        let day = ok_or_continue!(day.parse::<i64>()) * 86_400;
-        let day = NaiveDateTime::from_timestamp(day, 0);
-        let day: DateTime<Utc> = DateTime::from_utc(day, Utc);
-        let day = day.format("%Y-%m-%d");
+        let day = some_or_continue!(format_chat_day(day));
 
         out.push(
             format!(
-                "<li><a href=\"/chat/{}/{}\">{}</a>{}</li>",
+                "<li><a href=\"/chat/{}/{}\">{}</a> <span class=\"weekday\">{}</span>{}</li>",
                 &chat_id,
                 &day,
                 &day,
-                if i == 0 {
+                weekday_name(lang, weekday),
+                // only the newest day of the whole chat is the latest one
+                if i == 0 && !page.has_newer {
                     format!(
-                        " (<a href=\"/chat/{}/latest\">latest</a>)",
+                        " (<a href=\"/chat/{}/latest\">{}</a>)",
                         &chat_id,
+                        t(lang, "nav.latest"),
                     )
                 } else {
                     "".to_string()
                 },
             ),
         );
-
-        i += 1;
     }
 
-    out.push("</ul></div></body></html>".to_string());
+    out.push("</ul></div>".to_string());
 
     Ok(
         warp::reply::html(
-            out.join(""),
-        ),
+            Page::new(format!("{} - index", &chat_name))
+                .with_theme(theme)
+                .with_lang(lang)
+                .with_query(&raw_query)
+                .with_header(
+                    HeaderBar::new()
+                        .with_link(
+                            t(lang, "nav.home"),
+                            Some("/".into()),
+                        )
+                        .with_chat_title(&chat_id, &chat_name)
+                        .with_active(t(lang, "nav.index"))
+                        .with_link(
+                            t(lang, "nav.info"),
+                            Some(format!("/chat/{}/info", &chat_id)),
+                        )
+                        .with_link(
+                            t(lang, "nav.pins"),
+                            Some(format!("/chat/{}/pins", &chat_id)),
+                        )
+                        .with_link(
+                            t(lang, "nav.media"),
+                            Some(format!("/chat/{}/media", &chat_id)),
+                        )
+                        .with_link(
+                            t(lang, "nav.week"),
+                            current_period_url(&chat_id, "week"),
+                        )
+                        .with_link(
+                            t(lang, "nav.month"),
+                            current_period_url(&chat_id, "month"),
+                        )
+                        .with_link(
+                            t(lang, "nav.latest"),
+                            Some(format!("/chat/{}/latest", &chat_id)),
+                        )
+                        .with_link(
+                            t(lang, "nav.newer_days"),
+                            page.newer_cursor()
+                                .map(|cursor| index_page_url(&chat_id, "after", &cursor, &query)),
+                        )
+                        .with_link(
+                            t(lang, "nav.older_days"),
+                            page.older_cursor()
+                                .map(|cursor| index_page_url(&chat_id, "page", &cursor, &query)),
+                        )
+                        .with_format_link(
+                            &format!("/chat/{}/index", &chat_id),
+                            "json",
+                        ),
+                )
+                .with_body(out.join(""))
+                .render(),
+        ).into_response()
     )
 }
@@ -0,0 +1,351 @@
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use rocksdb::{DBWithThreadMode, Direction, IteratorMode, MultiThreaded, ReadOptions};
+use serde_json::json;
+use warp::Reply;
+
+use crate::{ok_or_continue, some_or_continue};
+use crate::components::chat_event::format_timer;
+use crate::components::header::HeaderBar;
+use crate::components::page::{Page, Theme};
+use crate::privacy::Viewer;
+use crate::storage::{get_chat_meta, lock_db, ReadStore, Storage};
+use crate::utils::{escape_html, format_chat_day, NameCache, resolve_chat_name};
+use crate::workers::member_counts::find_member_counts;
+use crate::workers::telegram_handler::{ChatMeta, ChatMetaChange, ChatMetaHistoryEntry};
+
+// member count snapshots the sparkline on the info page covers
+const MEMBER_COUNT_SPARKLINE_DAYS: usize = 30;
+
+pub fn find_chat_meta_history(
+    db: &impl ReadStore,
+    chat_id: &str,
+) -> Vec<ChatMetaHistoryEntry> {
+    let mut opts = ReadOptions::default();
+
+    let lower_bound = format!("chat:meta_history:{}:", &chat_id).as_bytes().to_vec();
+    let upper_bound = format!("chat:meta_history:{}:\x7f", &chat_id).as_bytes().to_vec();
+
+    opts.set_iterate_upper_bound(upper_bound.clone());
+    opts.set_iterate_lower_bound(lower_bound.clone());
+
+    let iter =
+        db.iterator_opt(
+            IteratorMode::From(&lower_bound, Direction::Forward),
+            opts,
+        );
+
+    let mut entries = Vec::<ChatMetaHistoryEntry>::new();
+
+    for (_, val) in iter {
+        entries.push(
+            ok_or_continue!(serde_json::from_slice::<ChatMetaHistoryEntry>(&val)),
+        );
+    }
+
+    entries
+}
+
+/// The member counts as a line, scaled between the lowest and the highest
+/// of them.
+fn render_sparkline(
+    counts: &[i64],
+) -> String {
+    let (width, height) = (120.0, 20.0);
+
+    let min = counts.iter().copied().min().unwrap_or(0);
+    let max = counts.iter().copied().max().unwrap_or(0);
+
+    let step = width / (counts.len().max(2) - 1) as f64;
+
+    let points =
+        counts
+            .iter()
+            .enumerate()
+            .map(|(i, count)| {
+                let y =
+                    match max > min {
+                        true => height - (count - min) as f64 / (max - min) as f64 * height,
+                        false => height / 2.0,
+                    };
+
+                format!("{:.1},{:.1}", i as f64 * step, y)
+            })
+            .collect::<Vec<String>>()
+            .join(" ");
+
+    format!(
+        "<svg class=\"sparkline\" width=\"{}\" height=\"{}\" viewBox=\"0 -1 {} {}\"><polyline fill=\"none\" stroke=\"currentColor\" points=\"{}\"/></svg>",
+        width,
+        height + 2.0,
+        width,
+        height + 2.0,
+        points,
+    )
+}
+
+pub async fn chat_info(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    chat_id: String,
+    viewer: Viewer,
+    theme: Theme,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let dbi = lock_db(&db);
+
+    let view = dbi.read_view();
+
+    let chat_name =
+        resolve_chat_name(
+            &view,
+            &chat_id,
+        );
+
+    let chat_meta = get_chat_meta(&view, &chat_id);
+
+    let mut out =
+        vec!(
+            "<div class=\"info\"><table class=\"info\"><tbody>".to_string(),
+        );
+
+    let mut rows = vec!(
+        ("id", chat_id.clone()),
+        ("name", escape_html(&chat_name)),
+    );
+
+    if let Some(ref meta) = chat_meta {
+        rows.push(
+            (
+                "type",
+                match meta {
+                    ChatMeta::User(_) => "private",
+                    ChatMeta::Group(_) => "group",
+                    ChatMeta::SuperGroup(_) => "supergroup",
+                    ChatMeta::Channel(_) => "channel",
+                    ChatMeta::Unknown(_) => "unknown",
+                }.to_string(),
+            ),
+        );
+
+        if let Some(title) = meta.title() {
+            rows.push(("title", escape_html(&title)));
+        }
+
+        // anyone with the link can join, that's not for every reader
+        if let Some(invite_link) = meta.invite_link().filter(|_| viewer.admin) {
+            rows.push(
+                (
+                    "invite link",
+                    format!(
+                        "<a href=\"{}\">{}</a>",
+                        escape_html(&invite_link),
+                        escape_html(&invite_link),
+                    ),
+                ),
+            );
+        }
+    }
+
+    let member_counts = find_member_counts(&view, &chat_id);
+
+    if let Some((_, latest)) = member_counts.last() {
+        let recent =
+            member_counts
+                .iter()
+                .rev()
+                .take(MEMBER_COUNT_SPARKLINE_DAYS)
+                .rev()
+                .map(|(_, snapshot)| snapshot.count)
+                .collect::<Vec<i64>>();
+
+        rows.push(
+            (
+                "members",
+                format!(
+                    "{}{} <a class=\"note\" href=\"/chat/{}/members.json\">json</a>",
+                    latest.count,
+                    if recent.len() > 1 {
+                        format!(" {}", render_sparkline(&recent))
+                    } else {
+                        String::new()
+                    },
+                    chat_id,
+                ),
+            ),
+        );
+    }
+
+    for (label, value) in rows {
+        out.push(
+            format!(
+                "<tr><td class=\"label\">{}</td><td>{}</td></tr>",
+                label,
+                value,
+            ),
+        );
+    }
+
+    let history = find_chat_meta_history(&view, &chat_id);
+
+    // the latest timer is the chat's own idea of how long its messages
+    // should be kept
+    let auto_delete =
+        history
+            .iter()
+            .rev()
+            .find_map(|entry|
+                match entry.change {
+                    ChatMetaChange::AutoDeleteTimer { seconds } => Some(seconds),
+                    _ => None,
+                }
+            )
+            .filter(|seconds| *seconds > 0);
+
+    if let Some(seconds) = auto_delete {
+        out.push(
+            format!(
+                "<tr class=\"warning\"><td class=\"label\">auto-delete</td><td>messages are deleted after {} in telegram</td></tr>",
+                format_timer(seconds),
+            ),
+        );
+    }
+
+    out.push("</tbody></table>".to_string());
+
+    let mut names =
+        NameCache::new(&view)
+            .with_anonymized(viewer.anonymize_chat(&chat_id));
+
+    out.push("<h3>history</h3><ul class=\"history\">".to_string());
+
+    let mut photos = Vec::<String>::new();
+
+    for entry in history.iter() {
+        let time = some_or_continue!(NaiveDateTime::from_timestamp_opt(entry.time, 0));
+        let time: DateTime<Utc> = DateTime::from_utc(time, Utc);
+
+        let by =
+            entry.user_id
+                .as_ref()
+                .map(|user_id|
+                    format!(
+                        " <span class=\"note\">by {}</span>",
+                        escape_html(&names.user(user_id, false)),
+                    )
+                )
+                .unwrap_or_default();
+
+        let change =
+            match entry.change {
+                ChatMetaChange::Title { ref title } =>
+                    format!("title: <b>{}</b>", escape_html(title)),
+                ChatMetaChange::Photo { file_id: Some(ref file_id) } => {
+                    photos.push(file_id.clone());
+
+                    format!(
+                        "new photo <a href=\"/file/video_thumb/{}?fallback=1\">(view)</a>",
+                        file_id,
+                    )
+                }
+                ChatMetaChange::Photo { file_id: None } =>
+                    "new photo (not stored)".to_string(),
+                ChatMetaChange::DeletePhoto =>
+                    "photo removed".to_string(),
+                ChatMetaChange::AutoDeleteTimer { seconds: 0 } =>
+                    "auto-delete turned off".to_string(),
+                ChatMetaChange::AutoDeleteTimer { seconds } =>
+                    format!("auto-delete: <b>{}</b>", format_timer(seconds)),
+            };
+
+        out.push(
+            format!(
+                "<li><span class=\"time\">{}</span> {}{}</li>",
+                time.format("%Y-%m-%d %H:%M:%S"),
+                change,
+                by,
+            ),
+        );
+    }
+
+    if history.is_empty() {
+        out.push("<li><span class=\"note\">No changes recorded.</span></li>".to_string());
+    }
+
+    out.push("</ul>".to_string());
+
+    if !photos.is_empty() {
+        out.push("<h3>photos</h3><div class=\"gallery\">".to_string());
+
+        for file_id in photos.iter().rev() {
+            out.push(
+                format!(
+                    "<a class=\"tile\" href=\"/file/video_thumb/{}?fallback=1\"><img src=\"/file/video_thumb/{}?fallback=1\" loading=\"lazy\"/></a>",
+                    file_id,
+                    file_id,
+                ),
+            );
+        }
+
+        out.push("</div>".to_string());
+    }
+
+    out.push("</div>".to_string());
+
+    Ok(
+        warp::reply::html(
+            Page::new(format!("{} - info", &chat_name))
+                .with_theme(theme)
+                .with_header(
+                    HeaderBar::new()
+                        .with_link(
+                            "<- home",
+                            Some("/".into()),
+                        )
+                        .with_title(&chat_name)
+                        .with_link(
+                            "index",
+                            Some(format!("/chat/{}", &chat_id)),
+                        )
+                        .with_active("info")
+                        .with_link(
+                            "latest",
+                            Some(format!("/chat/{}/latest", &chat_id)),
+                        ),
+                )
+                .with_body(out.join(""))
+                .render(),
+        ),
+    )
+}
+
+/// Every member count snapshot of a chat, for stats tooling.
+pub async fn chat_member_counts(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    chat_id: String,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let dbi = lock_db(&db);
+
+    let view = dbi.read_view();
+
+    let counts =
+        find_member_counts(&view, &chat_id)
+            .into_iter()
+            .map(|(day, snapshot)|
+                json!({
+                    "day": format_chat_day(day),
+                    "count": snapshot.count,
+                    "time": snapshot.time,
+                })
+            )
+            .collect::<Vec<_>>();
+
+    Ok(
+        warp::reply::json(
+            &json!({
+                "status": "ok",
+                "error": false,
+                "data": counts,
+            }),
+        ).into_response(),
+    )
+}
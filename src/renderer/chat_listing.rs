@@ -1,421 +1,2721 @@
+use std::collections::{BTreeMap, HashSet};
 use std::ops::Add;
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
-use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime, Timelike, Utc};
 use rocksdb::{DBWithThreadMode, Direction, IteratorMode, MultiThreaded, ReadOptions};
+use serde::Deserialize;
 use serde_json::{json, Value};
+use warp::http::{header, Response, StatusCode};
+use warp::hyper::Body;
+use warp::hyper::body::to_bytes;
 use warp::Reply;
 
-use crate::{GLOBAL_CSS, MinutemanError, ok_or_continue, ok_or_return, ok_or_return_none, some_or_continue};
+use crate::{DEFAULT_LISTING_LIMIT, MAX_LISTING_LIMIT, MinutemanError, ok_or_continue, ok_or_return, ok_or_return_none, some_or_continue};
+use crate::components::chat_event::chat_event_text;
+use crate::components::contact::{contact_summary, render_contact};
+use crate::components::forward::{FORWARD_GROUP_WINDOW, forward_origin, ForwardOrigin, render_forward_banner};
 use crate::components::header::{HeaderBar, HeaderItem};
-use crate::utils::{find_latest_chat_day, resolve_chat_name, resolve_user};
-use crate::workers::telegram_handler::{LogItem, LogItemMediaType, LogItemMembershipType, UserMeta};
+use crate::components::location::{location_summary, render_location};
+use crate::components::mark::{mark_html, search_terms};
+use crate::components::message_text::render_collapsed_message_text;
+use crate::components::page::{Page, Theme};
+use crate::components::poll::{poll_summary, render_poll};
+use crate::components::special::{render_special_action, special_action};
+use crate::config::{get_collapse_chars, get_timezone, get_collapse_lines, get_enable_debug_views, get_listing_order, get_live_refresh_interval, ListingOrder};
+use crate::locales::{Lang, t};
+use crate::rate_limit::acquire_listing_slot;
+use crate::render_cache::{CachedPage, get_cached_page, page_generation, render_cache_enabled, store_page};
+use crate::renderer::chat_info::find_chat_meta_history;
+use crate::renderer::error::{error_json, error_page};
+use crate::storage::{get_user_meta, lock_db, ReadStore, Storage};
+use crate::utils::{encode_query_value, escape_html, find_chat_days, find_raw_messages, find_latest_chat_day, format_chat_day, get_file_failure, get_file_meta, is_archived_chat, message_permalink, NameCache, resolve_chat_name, resolve_message_ref, resolve_user_meta};
+use crate::privacy::{anonymize_log_item_json, Viewer};
+use crate::workers::reprocess::log_item_variant;
+use crate::workers::sticker_sets::get_sticker_set;
+use crate::workers::telegram_handler::{build_message_key, ChatMetaChange, FileFailureKind, LogItem, LogItemChatType, LogItemMediaType, LogItemMembershipType, LogItemMessageEntity, LogItemMessageEntityKind, MessageSlot, parse_message_key, UserMeta};
 
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ListingQuery {
+    pub limit: Option<usize>,
+    pub cursor: Option<String>,
+    pub after: Option<String>,
+    // "historical" renders nicks as they were when the message was sent
+    pub names: Option<String>,
+    // admins only: dump the stored raw message under unsupported items
+    pub raw: Option<u8>,
+    // renders long messages in full instead of collapsing them
+    pub full: Option<u8>,
+    // search query whose words are highlighted, set by search result links
+    pub q: Option<String>,
+    // shows the key, message id, variant and size each row is stored with.
+    // Not carried over to other pages
+    pub debug: Option<u8>,
+    // txt only: a separator line wherever the hour changes
+    pub hours: Option<u8>,
+    // "asc" or "desc", see `listing_order`
+    pub order: Option<String>,
+    // json only: names of the users the rows mention, under "users"
+    pub resolve: Option<u8>,
+    // json only: file urls with scheme and host
+    pub absolute: Option<u8>,
+    // json only: 2 wraps the rows in an envelope, see `json_envelope`
+    pub v: Option<u8>,
+}
+
+/// Where a page starts, a message slot for listings and a day for the
+/// chat index.
+#[derive(Debug, Clone)]
+pub enum ListingCursor<T = MessageSlot> {
+    // messages strictly older than the given slot
+    Older(T),
+    // messages strictly newer than the given slot
+    Newer(T),
+}
+
+impl ListingQuery {
+    pub fn listing_cursor(&self) -> Option<ListingCursor> {
+        let cursor =
+            self.cursor
+                .as_deref()
+                .map(MessageSlot::parse)
+                .flatten()
+                .map(ListingCursor::Older);
+
+        let after =
+            self.after
+                .as_deref()
+                .map(MessageSlot::parse)
+                .flatten()
+                .map(ListingCursor::Newer);
+
+        cursor.or(after)
+    }
+
+    pub fn historical_names(&self) -> bool {
+        self.names.as_deref() == Some("historical")
+    }
+
+    pub fn full_messages(&self) -> bool {
+        self.full.unwrap_or(0) != 0
+    }
+
+    pub fn hour_separators(&self) -> bool {
+        self.hours.unwrap_or(0) != 0
+    }
+
+    pub fn search_terms(&self) -> Vec<String> {
+        self.q
+            .as_deref()
+            .map(search_terms)
+            .unwrap_or_default()
+    }
+
+    /// Whether the debug rendering was asked for and may be shown. Needs
+    /// `MINUTEMAN_ENABLE_DEBUG_VIEWS`, and the admin token when auth is on.
+    pub fn debug_view(
+        &self,
+        viewer: &Viewer,
+    ) -> bool {
+        self.debug.unwrap_or(0) != 0 && get_enable_debug_views() && viewer.can_browse()
+    }
+
+    /// The order asked for, otherwise oldest first on html pages (see
+    /// `get_listing_order`) and newest first in json and txt, which is
+    /// what those have always been.
+    pub fn listing_order(
+        &self,
+        format: ListingFormat,
+    ) -> ListingOrder {
+        self.order
+            .as_deref()
+            .map(ListingOrder::parse)
+            .flatten()
+            .unwrap_or(
+                match format {
+                    ListingFormat::Html => get_listing_order(),
+                    _ => ListingOrder::Desc,
+                },
+            )
+    }
+
+    pub fn resolve_users(&self) -> bool {
+        self.resolve.unwrap_or(0) != 0
+    }
+
+    /// What file urls are prefixed with for `?absolute=1`, the base of the
+    /// request (see `request_base_url`). They stay relative without one.
+    pub fn base_url(
+        &self,
+        request_base_url: Option<String>,
+    ) -> Option<String> {
+        request_base_url.filter(|_| self.absolute.unwrap_or(0) != 0)
+    }
+
+    /// Whether json listings come as `{status, error, date, data, order,
+    /// next}` (and `users` for `?resolve=1`), asked for with `?v=2` or
+    /// `?resolve=1`, a bare array has no room for the users. Without either
+    /// they're the array of rows the endpoint has always returned, paged by
+    /// `limit` and `cursor` like the html.
+    pub fn json_envelope(&self) -> bool {
+        self.v.unwrap_or(1) >= 2 || self.resolve_users()
+    }
+
+    pub fn listing_limit(&self) -> usize {
+        self.limit
+            .unwrap_or(DEFAULT_LISTING_LIMIT)
+            .clamp(1, MAX_LISTING_LIMIT)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ListingPage {
+    // cursor of the newest rendered message
+    pub first: Option<String>,
+    // cursor of the oldest rendered message
+    pub last: Option<String>,
+    pub has_newer: bool,
+    pub has_older: bool,
+}
+
+impl ListingPage {
+    pub fn older_cursor(&self) -> Option<String> {
+        if self.has_older {
+            self.last.clone()
+        } else {
+            None
+        }
+    }
+
+    pub fn newer_cursor(&self) -> Option<String> {
+        if self.has_newer {
+            self.first.clone()
+        } else {
+            None
+        }
+    }
+}
+
+/// Iterates over the messages of a chat between `time_start` and `time_end`
+/// in `order`, calling `cb` for at most `limit` of them.
+///
+/// Keys are `chat:{chat_id}:{timestamp}:{message_id}`, so cursors are message
+/// slots and stay valid while new messages are appended at the newer end of
+/// the range. Cursors of a bare timestamp, as older links and keys have them,
+/// stand for the whole second.
 pub fn chat_listing_iter(
-    dbi: &DBWithThreadMode<MultiThreaded>,
+    dbi: &impl ReadStore,
     chat_id: &str,
     time_start: &str,
     time_end: &str,
-    mut cb: impl FnMut(&str, &[u8]) -> (),
-) {
+    cursor: &Option<ListingCursor>,
+    limit: usize,
+    order: ListingOrder,
+    mut cb: impl FnMut(MessageSlot, &[u8]) -> (),
+) -> ListingPage {
     let mut opts = ReadOptions::default();
 
-    let lower_bound = format!("chat:{}:{}", &chat_id, time_start).as_bytes().to_vec();
-    let upper_bound = format!("chat:{}:{}", &chat_id, time_end).as_bytes().to_vec();
+    let mut lower_bound = format!("chat:{}:{}", &chat_id, time_start);
+    let mut upper_bound = format!("chat:{}:{}", &chat_id, time_end);
+
+    let mut page = ListingPage::default();
+
+    // there's only something past the cursor if it cuts into the range.
+    // Both bounds leave out the cursor's key and the keys it's a prefix of,
+    // ';' sorts right after the ':' of those
+    match cursor {
+        Some(ListingCursor::Older(slot)) => {
+            let cursor_bound = build_message_key(chat_id, *slot);
+
+            if cursor_bound < upper_bound {
+                upper_bound = cursor_bound;
+
+                page.has_newer = true;
+            }
+        }
+        Some(ListingCursor::Newer(slot)) => {
+            let cursor_bound = format!("{};", build_message_key(chat_id, *slot));
+
+            if cursor_bound > lower_bound {
+                lower_bound = cursor_bound;
+
+                page.has_older = true;
+            }
+        }
+        None => {}
+    }
+
+    let lower_bound = lower_bound.as_bytes().to_vec();
+    let upper_bound = upper_bound.as_bytes().to_vec();
 
     opts.set_iterate_upper_bound(upper_bound.clone());
     opts.set_iterate_lower_bound(lower_bound.clone());
 
-    let mut iter =
-        dbi.iterator_opt(
-            IteratorMode::From(&upper_bound, Direction::Reverse),
-            opts,
-        );
+    // the walk starts at the cursor, walking towards newer messages has to
+    // go forward from it or we'd always end up on the newest page of the
+    // day. Without one it starts at the end the order starts at
+    let forward =
+        match cursor {
+            Some(ListingCursor::Newer(_)) => true,
+            Some(ListingCursor::Older(_)) => false,
+            None => order == ListingOrder::Asc,
+        };
+
+    let iter =
+        if forward {
+            dbi.iterator_opt(
+                IteratorMode::From(&lower_bound, Direction::Forward),
+                opts,
+            )
+        } else {
+            dbi.iterator_opt(
+                IteratorMode::From(&upper_bound, Direction::Reverse),
+                opts,
+            )
+        };
+
+    // rows walked in their order go out as they're read, a page walked
+    // against it is held back and turned around. That's at most `limit`
+    // rows, never the whole day
+    let streaming = forward == (order == ListingOrder::Asc);
+
+    let mut held = Vec::<(MessageSlot, Box<[u8]>)>::new();
+    let mut count = 0;
 
     for (key, val) in iter {
         let key = key.to_vec();
         let key = String::from_utf8(key).unwrap();
-        let key = key.split(":").collect::<Vec<&str>>();
 
-        if key.len() != 3 {
-            continue;
+        let slot =
+            match parse_message_key(&key) {
+                Some((_, slot)) => slot,
+                None => continue,
+            };
+
+        if count == limit {
+            if forward {
+                page.has_newer = true;
+            } else {
+                page.has_older = true;
+            }
+
+            break;
+        }
+
+        count += 1;
+
+        // walking forward the rows only get newer, and older otherwise
+        if forward {
+            page.first = Some(slot.to_string());
+            page.last.get_or_insert(slot.to_string());
+        } else {
+            page.first.get_or_insert(slot.to_string());
+            page.last = Some(slot.to_string());
         }
 
-        let timestamp = key[key.len() - 1];
+        if streaming {
+            cb(slot, &val[..]);
+        } else {
+            held.push((slot, val));
+        }
+    }
 
+    for (slot, val) in held.iter().rev() {
         cb(
-            timestamp,
-            &val,
+            *slot,
+            &val[..],
         );
     }
+
+    page
 }
 
-pub async fn chat_listing(
-    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
-    chat_id: String,
-    date_query: String,
-) -> Result<impl warp::Reply, warp::Rejection> {
-    let dbi =
-        db.lock()
-            .map_err(|err|
-                warp::reject::custom(
-                    MinutemanError::LockError(
-                        format!("{:?}", err),
-                    ),
-                )
-            )?;
+fn listing_page_url(
+    chat_id: &str,
+    date_query: &str,
+    cursor_param: &str,
+    cursor: &str,
+    query: &ListingQuery,
+) -> String {
+    let mut url =
+        format!(
+            "/chat/{}/{}?{}={}",
+            chat_id,
+            date_query,
+            cursor_param,
+            cursor,
+        );
 
-    let mut out_format = "html";
+    if let Some(limit) = query.limit {
+        url.push_str(&format!("&limit={}", limit));
+    }
 
-    let date =
-        if date_query.starts_with("latest") {
-            match find_latest_chat_day(&dbi, &chat_id) {
-                Some(day) => day,
-                None =>
-                    return Ok(
-                        warp::reply::html(
-                            format!(
-                                "<!DOCTYPE html><html lang=\"en\"><style>{}</style><body>{}",
-                                GLOBAL_CSS,
-                                HeaderBar::new()
-                                    .with_link(
-                                        "<- home",
-                                        Some("/".into()),
-                                    )
-                                    .with_title(
-                                        resolve_chat_name(
-                                            &dbi,
-                                            &chat_id,
-                                        )
-                                    )
-                                    .with_link(
-                                        "index",
-                                        Some(format!("/chat/{}", chat_id)),
-                                    )
-                                    .with_link(
-                                        "latest",
-                                        Some(format!("/chat/{}/latest", chat_id)),
-                                    )
-                                    .to_string(),
-                            ),
-                        ).into_response(),
-                    ),
-            }
-        } else {
-            date_query.clone()
-        };
+    if query.historical_names() {
+        url.push_str("&names=historical");
+    }
+
+    if query.raw.unwrap_or(0) != 0 {
+        url.push_str("&raw=1");
+    }
+
+    if query.full_messages() {
+        url.push_str("&full=1");
+    }
 
-    if date_query.ends_with(".json") {
-        out_format = "json";
+    if let Some(order) = query.order.as_deref().map(ListingOrder::parse).flatten() {
+        url.push_str(&format!("&order={}", order.code()));
     }
 
-    let chat_name =
-        resolve_chat_name(
-            &dbi,
-            &chat_id,
+    if let Some(q) = query.q.as_deref().filter(|q| !q.trim().is_empty()) {
+        url.push_str(&format!("&q={}", encode_query_value(q)));
+    }
+
+    url
+}
+
+fn with_listing_page_links(
+    header: HeaderBar,
+    chat_id: &str,
+    date_query: &str,
+    page: &ListingPage,
+    query: &ListingQuery,
+    lang: Lang,
+) -> HeaderBar {
+    header
+        .with_link(
+            t(lang, "nav.newer_messages"),
+            page.newer_cursor()
+                .map(|cursor|
+                    listing_page_url(chat_id, date_query, "after", &cursor, query)
+                ),
+        )
+        .with_link(
+            t(lang, "nav.older_messages"),
+            page.older_cursor()
+                .map(|cursor|
+                    listing_page_url(chat_id, date_query, "cursor", &cursor, query)
+                ),
+        )
+}
+
+/// Key bounds of a day as unix timestamps, which all have the same number
+/// of digits and therefore compare correctly as strings.
+pub fn day_time_bounds(
+    date: NaiveDate,
+) -> (String, String) {
+    let time_start =
+        NaiveDateTime::new(
+            date,
+            NaiveTime::from_hms(0, 0, 0),
         );
 
-    let time =
-        NaiveDate::parse_from_str(
-            &date,
-            "%Y-%m-%d",
+    let time_end =
+        NaiveDateTime::new(
+            date.add(chrono::Duration::days(1)),
+            NaiveTime::from_hms(0, 0, 0),
         );
 
-    if time.is_err() {
-        return if out_format == "html" {
-            Ok(
-                warp::reply::html(
-                    "invalid date".to_string(),
-                ).into_response()
-            )
-        } else {
-            Ok(
-                warp::reply::json(
-                    &json!({
-                            "status": format!("invalid date (got {})", &date),
-                            "error": true,
-                            "data": null
-                        }),
-                ).into_response()
-            )
-        }
-    }
+    (
+        time_start.timestamp().to_string(),
+        time_end.timestamp().to_string(),
+    )
+}
 
-    let time_start = {
-        let time =
-            NaiveDateTime::new(
-                time.unwrap(),
-                NaiveTime::from_hms(0, 0, 0),
-            );
+// number of logged days around the current one offered in the jump form
+const JUMP_DAY_COUNT: usize = 30;
 
-        let time_start =
-            time.timestamp_millis()
-                .to_string();
+fn day_jump_form(
+    chat_id: &str,
+    date: &str,
+    chat_days: &Vec<i64>,
+    current_day: i64,
+    lang: Lang,
+) -> String {
+    let mut nearest_days = chat_days.clone();
 
-        time_start.trim_end_matches(|c: char| c == '0').to_string()
-    };
+    nearest_days.sort_by_key(|day| (day - current_day).abs());
+    nearest_days.truncate(JUMP_DAY_COUNT);
+    nearest_days.sort_by(|a, b| b.cmp(a));
 
-    let time_end = {
-        let time =
-            NaiveDateTime::new(
-                time.unwrap().add(chrono::Duration::days(1)),
-                NaiveTime::from_hms(0, 0, 0),
-            );
+    let options =
+        nearest_days
+            .iter()
+            .filter_map(|day| format_chat_day(*day))
+            .map(|day|
+                format!(
+                    "<option value=\"{}\"{}>{}</option>",
+                    &day,
+                    if day == date { " selected" } else { "" },
+                    &day,
+                )
+            )
+            .collect::<Vec<String>>()
+            .join("");
 
-        let time_start =
-            time.timestamp_millis()
-                .to_string();
+    format!(
+        "<form class=\"jump\" action=\"/chat/{}/jump\" method=\"get\">\
+            <select name=\"date\">{}</select> \
+            <input type=\"submit\" value=\"{}\"/>\
+        </form> | <a href=\"/chat/{}\">{}</a>",
+        chat_id,
+        options,
+        t(lang, "nav.go"),
+        chat_id,
+        t(lang, "nav.all_days"),
+    )
+}
 
-        time_start.trim_end_matches(|c: char| c == '0').to_string()
-    };
+#[derive(Debug, Clone, Deserialize)]
+pub struct JumpQuery {
+    pub date: String,
+}
 
-    if out_format == "json" {
-        let mut out = Vec::<Value>::new();
-
-        chat_listing_iter(
-            &dbi,
-            &chat_id,
-            &time_start,
-            &time_end,
-            |timestamp, val| {
-                if let Ok(val) = String::from_utf8(
-                    val.to_vec(),
-                ) {
-                    let val =
-                        &serde_json::from_str::<LogItem>(
-                            &val,
-                        )
-                            .ok()
-                            .map(|mut val|
-                                match val {
-                                    LogItem::Media { ref mut files, ref media_type, .. } => {
-                                        match media_type {
-                                            LogItemMediaType::Image { .. }
-                                            | LogItemMediaType::Sticker { .. } => {
-                                                files
-                                                    .iter_mut()
-                                                    .for_each(|file| {
-                                                        *file = format!("/file/image/{}", file)
-                                                    });
-
-                                                val
-                                            },
-                                            _ => val,
-                                        }
-                                    }
-                                    _ => val,
-                                }
-                            )
-                            .map(|val| serde_json::to_value(val).ok())
-                            .flatten();
+/// Muted "via @somebot" / author signature suffix rendered after the nick,
+/// plus a badge when the author is a bot.
+fn nick_attribution(
+    names: &mut NameCache<impl ReadStore>,
+    user_id: &Option<String>,
+    via_bot: &Option<String>,
+    author_signature: &Option<String>,
+) -> String {
+    let mut out = String::new();
 
-                    if let Some(val) = val {
-                        out.push(val.clone());
-                    }
-                }
-            },
+    match user_id.as_deref().map(|user_id| names.bot_badge(user_id)).flatten() {
+        Some("own") => out.push_str(" <span class=\"bot own\">logger</span>"),
+        Some(_) => out.push_str(" <span class=\"bot\">bot</span>"),
+        None => {}
+    }
+
+    if let Some(signature) = author_signature {
+        out.push_str(
+            &format!(
+                " <span class=\"note\">({})</span>",
+                escape_html(signature),
+            ),
         );
+    }
 
-        return Ok(
-            warp::reply::json(
-                &Value::Array(out),
-            ).into_response(),
+    if let Some(bot_id) = via_bot {
+        out.push_str(
+            &format!(
+                " <span class=\"note\">via @{}</span>",
+                escape_html(&names.user(bot_id, false)),
+            ),
         );
     }
 
-    let mut out =
-        vec!(
-            "<!DOCTYPE html><html lang=\"en\">".to_string(),
-            "<style type=\"text/css\">".to_string(),
-            GLOBAL_CSS.to_string(),
-            "</style>".to_string(),
+    out
+}
+
+const PIN_SNIPPET_LENGTH: usize = 120;
+
+/// Quoted snippet of a pinned message, linked to the message itself when it
+/// was logged (pins of messages older than the log stay plain text).
+pub fn pin_snippet(
+    db: &impl ReadStore,
+    chat_id: &str,
+    message_id: &str,
+    message: &Option<String>,
+) -> String {
+    format_pin_snippet(
+        message,
+        resolve_message_ref(db, chat_id, message_id)
+            .map(|slot| message_permalink(chat_id, slot.time))
+            .flatten()
+            .as_deref(),
+    )
+}
+
+/// `pin_snippet` with the permalink of the pinned message looked up.
+pub fn format_pin_snippet(
+    message: &Option<String>,
+    permalink: Option<&str>,
+) -> String {
+    let snippet =
+        match message {
+            Some(message) if message.chars().count() > PIN_SNIPPET_LENGTH =>
+                format!(
+                    "\"{}…\"",
+                    escape_html(
+                        &message
+                            .chars()
+                            .take(PIN_SNIPPET_LENGTH)
+                            .collect::<String>(),
+                    ),
+                ),
+            Some(message) =>
+                format!("\"{}\"", escape_html(message)),
+            None => "a message".to_string(),
+        };
+
+    match permalink {
+        Some(permalink) =>
             format!(
-                "<head><title>{} - {}</title></head><body>",
-                &chat_name,
-                &date,
+                "<a href=\"{}\">{}</a>",
+                permalink,
+                snippet,
             ),
+        None => snippet,
+    }
+}
+
+pub async fn chat_jump(
+    chat_id: String,
+    query: JumpQuery,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    // only let well-formed dates through so the redirect can't be abused,
+    // days without messages simply render as an empty day
+    let target =
+        match NaiveDate::parse_from_str(&query.date, "%Y-%m-%d") {
+            Ok(date) => format!("/chat/{}/{}", chat_id, date.format("%Y-%m-%d")),
+            Err(_) => format!("/chat/{}", chat_id),
+        };
+
+    Ok(
+        warp::redirect::see_other(
+            target
+                .parse::<warp::http::Uri>()
+                .map_err(|err|
+                    warp::reject::custom(
+                        MinutemanError::ParseError(
+                            format!("{:?}", err),
+                        ),
+                    )
+                )?,
+        ),
+    )
+}
+
+/// Inline player of an animation.
+/// GIFs are shown as they are, the MP4s telegram makes of them as a
+/// looping muted video that only loads once played.
+fn render_animation(
+    file_id: &str,
+    thumb_file_id: Option<&str>,
+    mime_type: Option<&str>,
+) -> String {
+    if mime_type == Some("image/gif") {
+        return format!(
+            "<img src=\"/file/image/{}?fallback=1\" style=\"max-height: 300px; max-width: 300px;\" loading=\"lazy\"/>",
+            file_id,
         );
+    }
 
-    out.push(
-        HeaderBar::new()
-            .with_link(
-                "<- home",
-                Some("/".into()),
-            )
-            .with_title(format!("{} - {}", &chat_name, &date))
-            .with_link(
-                "index",
-                Some(format!("/chat/{}", chat_id)),
-            )
-            .with_link("previous", None)
-            .with_link("next", None)
-            .with_link(
-                "latest",
-                Some(format!("/chat/{}/latest", chat_id)),
+    format!(
+        "<video{} style=\"max-height: 300px; max-width: 300px;\" preload=\"none\" loop muted playsinline controls><source src=\"/file/video/{}\" type=\"{}\"/></video>",
+        thumb_file_id
+            .map(|thumb| format!(" poster=\"/file/video_thumb/{}?fallback=1\"", thumb))
+            .unwrap_or_default(),
+        file_id,
+        escape_html(mime_type.unwrap_or("video/mp4")),
+    )
+}
+
+/// The sticker's emoji and a link to add its set, titled once the set's
+/// metadata was fetched and by the set's name until then.
+fn render_sticker_label(
+    emoji: Option<&str>,
+    set_name: Option<&str>,
+    set_title: Option<&str>,
+) -> String {
+    let set =
+        set_name.map(|set_name|
+            format!(
+                "<a href=\"https://t.me/addstickers/{}\">{}</a>",
+                encode_query_value(set_name),
+                escape_html(set_title.unwrap_or(set_name)),
             )
-            .into()
-    );
+        );
 
-    out.push(
-        "<div class=\"log\"><table class=\"log\"><tbody>".to_string(),
-    );
+    [emoji.map(escape_html), set]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<String>>()
+        .join(" ")
+}
 
-    let mut i = 0;
+/// Locale key of the note shown instead of a file that wasn't archived.
+fn failure_note(
+    kind: FileFailureKind,
+) -> &'static str {
+    match kind {
+        FileFailureKind::TooLarge => "media.too_large",
+        FileFailureKind::TooBigForBots => "media.too_big_for_bots",
+        FileFailureKind::InvalidReference => "media.invalid_reference",
+        FileFailureKind::RateLimited | FileFailureKind::Network => "media.retrying",
+        FileFailureKind::Other => "media.failed",
+    }
+}
 
-    chat_listing_iter(
-        &dbi,
-        &chat_id,
-        &time_start,
-        &time_end,
-        |timestamp, val| {
-            let day =
-                ok_or_return!(
-                    timestamp.parse::<i64>(),
-                );
+/// Where a row came from in the database: its key, the telegram message
+/// id, the log item variant and the size of the stored json, plus a link
+/// to the row alone as json.
+fn render_debug_row(
+    chat_id: &str,
+    date: &str,
+    slot: MessageSlot,
+    log_item: &LogItem,
+    size: usize,
+) -> String {
+    // the json link lists the row alone, from the slot right after it
+    let next =
+        match slot.message_id {
+            Some(message_id) => MessageSlot::new(slot.time, message_id + 1),
+            None => MessageSlot::from(slot.time + 1),
+        };
 
-            let day_opt = NaiveDateTime::from_timestamp_opt(day, 0);
+    format!(
+        "<tr class=\"debug\">\
+            <td class=\"time\"></td>\
+            <td class=\"nick\"></td>\
+            <td class=\"content\"><code>{}</code> message {} · {} · {} bytes · <a href=\"/chat/{}/{}.json?cursor={}&limit=1\">json</a></td>\
+        </tr>",
+        escape_html(&build_message_key(chat_id, slot)),
+        log_item
+            .source()
+            .map(|source| source.id.to_string())
+            .unwrap_or("-".to_string()),
+        log_item_variant(log_item),
+        size,
+        escape_html(chat_id),
+        escape_html(date),
+        next,
+    )
+}
 
-            if day_opt.is_none() {
-                return;
-            }
+// whether a listed file was archived and can be shown as an image, going
+// by what was stored
+fn is_stored_image(
+    file: &ListingFile,
+) -> bool {
+    if file.failure.is_some() {
+        return false;
+    }
 
-            let day: DateTime<Utc> = DateTime::from_utc(day_opt.unwrap(), Utc);
-            let day = day.format("%H:%M:%S").to_string();
+    let mime_type =
+        file.stored_mime_type
+            .as_deref()
+            .map(|mime_type| mime_type.starts_with("image/"))
+            .unwrap_or(false);
 
-            let msg =
-                if let Some(msg) = String::from_utf8(val.to_vec())
-                    .ok()
-                    .map(|s| serde_json::from_str::<LogItem>(&s).ok())
-                    .flatten() {
-                    msg
-                } else {
-                    return;
-                };
+    let extension =
+        file.stored_extension
+            .as_deref()
+            .map(|extension| ["jpg", "jpeg", "png", "gif", "webp"].contains(&extension))
+            .unwrap_or(false);
 
+    mime_type || extension
+}
 
-            match msg {
-                LogItem::Message { ref text, ref user_id, .. } => {
-                    let username =
-                        if let Some(user_id) = user_id {
-                            resolve_user(
-                                &dbi,
-                                user_id,
-                                false,
-                            )
-                        } else {
-                            "Unknown".to_string()
-                        };
+/// Snippet and image path a link preview of the given log item shows.
+fn link_preview(
+    entry: &ListingEntry,
+) -> (Option<String>, Option<String>) {
+    let snippet = |text: &str| {
+        let mut snippet = text.chars().take(200).collect::<String>();
 
-                    out.push(
-                        format!(
-                            "<tr class=\"message\">\
-                            <td class=\"time\">\
-                                <a>{}</a>\
-                            <td>\
-                            <td class=\"nick\">{}</td>\
-                            <td class=\"content\">{}</td>\
-                        </tr>",
-                            day,
-                            &username,
-                            text,
-                        )
-                    );
-                },
-                LogItem::Media { ref files, ref user_id, ref caption, .. } => {
-                    let file_uris =
-                        files
-                            .iter()
-                            .last()
-                            .map(|file| format!("/file/image/{}", file))
-                            .map(|file| format!("<img src=\"{}\" style=\"max-height: 300px; max-width: 300px;\" loading=\"lazy\"/>", file))
-                            .map(|file| vec!(file))
-                            .unwrap_or(vec!());
-
-                    let username =
-                        if let Some(user_id) = user_id {
-                            resolve_user(
-                                &dbi,
-                                user_id,
-                                false,
-                            )
-                        } else {
-                            "Unknown".to_string()
-                        };
-
-                    let media_caption =
-                        if let Some(caption) = caption {
-                            &*caption
-                        } else {
-                            "<span class=\"note\">Message has no caption.</span>"
-                        };
-
-                    out.push(
-                        format!(
-                            "<tr class=\"message action\">\
-                            <td class=\"time\">\
-                                <a>{}</a>\
-                            <td>\
-                            <td class=\"nick\">{}</td>\
-                            <td class=\"content\">{}</td>\
-                        </tr>",
-                            day,
-                            &username,
-                            format!(
-                                "{} <br/> {}",
-                                &media_caption,
-                                file_uris.join(" "),
-                            ),
-                        )
-                    );
-                },
-                LogItem::Membership { ref user_id, ref membership_type, .. } => {
-                    dbg!(&user_id, &membership_type);
-
-                    let username =
-                        if let Some(user_id) = user_id {
-                            resolve_user(
-                                &dbi,
-                                user_id,
-                                false,
-                            )
-                        } else {
-                            "Unknown".to_string()
-                        };
+        if snippet.len() < text.len() {
+            snippet.push('…');
+        }
 
-                    out.push(
-                        format!(
-                            "<tr class=\"{}\">\
-                            <td class=\"time\">\
-                                <a>{}</a>\
-                            <td>\
-                            <td class=\"nick\">{}</td>\
-                            <td class=\"content\"><span class=\"reason\">{}</span></td>\
-                        </tr>",
-                            match membership_type {
-                                LogItemMembershipType::Joined => "join",
-                                LogItemMembershipType::Left => "leave",
-                            },
-                            day,
-                            &username,
-                            match membership_type {
-                                LogItemMembershipType::Joined => "joined the chat",
-                                LogItemMembershipType::Left => "left the chat",
-                            },
-                        )
-                    );
+        snippet
+    };
+
+    match entry.item {
+        LogItem::Message { ref text, .. } =>
+            (Some(snippet(text)), None),
+        LogItem::Media { ref caption, .. } =>
+            (
+                caption.as_deref().map(snippet),
+                entry.file
+                    .as_ref()
+                    .filter(|file| is_stored_image(file))
+                    .map(|file| format!("/file/image/{}", file.file_id)),
+            ),
+        _ => (None, None),
+    }
+}
+
+/// Links to the hour breaks of a day page, hours without a row on it are
+/// there but muted.
+fn render_hour_strip(
+    hours: &[bool; 24],
+) -> String {
+    let hours =
+        hours
+            .iter()
+            .enumerate()
+            .map(|(hour, listed)|
+                match listed {
+                    true => format!("<a href=\"#h{:02}\">{:02}</a>", hour, hour),
+                    false => format!("<span class=\"note\">{:02}</span>", hour),
                 }
-                _ => {}
-            }
+            )
+            .collect::<Vec<String>>()
+            .join(" ");
 
-            i += 1;
-        },
-    );
+    format!(
+        "<div class=\"hours\">{}</div>",
+        hours,
+    )
+}
 
-    out.push("</ul></div></body></html>".to_string());
+/// The day a listing shows, sent along with every format. What clients of
+/// `latest` go by to know which day they got.
+pub const DATE_HEADER: &str = "x-minuteman-date";
 
-    Ok(
-        warp::reply::html(
-            out.join(""),
-        ).into_response()
+/// Key of a day page in the render cache, None for pages that can still
+/// change: "latest", today and anything that isn't a date.
+fn listing_cache_key(
+    chat_id: &str,
+    date_query: &str,
+    query: &ListingQuery,
+    viewer: &Viewer,
+    lang: Lang,
+    base_url: Option<&str>,
+    raw_query: &str,
+    theme: Theme,
+) -> Option<String> {
+    let date =
+        date_query
+            .trim_end_matches(".json")
+            .trim_end_matches(".txt");
+
+    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()?;
+
+    // highlighted pages are one-offs from the search results
+    if date >= Utc::today().naive_utc() || !query.search_terms().is_empty() {
+        return None;
+    }
+
+    if query.debug_view(viewer) {
+        return None;
+    }
+
+    Some(
+        format!(
+            "{}/{}?limit={}&cursor={:?}&names={}&anonymize={}&admin={}&share={:?}&raw={}&full={}&hours={}&order={}&resolve={}&base={:?}&lang={}&v={}&theme={}&query={:?}",
+            chat_id,
+            date_query,
+            query.listing_limit(),
+            query.listing_cursor(),
+            query.historical_names(),
+            viewer.anonymize_chat(chat_id),
+            // forwards link their origin only for viewers who can see it
+            viewer.admin,
+            viewer.share,
+            viewer.admin && query.raw.unwrap_or(0) != 0,
+            query.full_messages(),
+            query.hour_separators(),
+            query.listing_order(ListingFormat::from_date_query(date_query)).code(),
+            query.resolve_users(),
+            base_url,
+            lang.code(),
+            query.json_envelope(),
+            theme.name(),
+            raw_query,
+        ),
     )
 }
+
+pub async fn chat_listing(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    chat_id: String,
+    date_query: String,
+    query: ListingQuery,
+    viewer: Viewer,
+    lang: Lang,
+    request_base_url: Option<String>,
+    raw_query: String,
+    theme: Theme,
+) -> Result<Response<Body>, warp::Rejection> {
+    let base_url = query.base_url(request_base_url);
+
+    let cache_key =
+        if render_cache_enabled() {
+            listing_cache_key(&chat_id, &date_query, &query, &viewer, lang, base_url.as_deref(), &raw_query, theme)
+        } else {
+            None
+        };
+
+    let cache_key =
+        match cache_key {
+            Some(cache_key) => cache_key,
+            None =>
+                return render_chat_listing(db, chat_id, date_query, query, viewer, lang, base_url, raw_query, theme)
+                    .await
+                    .map(Reply::into_response),
+        };
+
+    // only actual dates are cached, the date is the one asked for
+    let date =
+        date_query
+            .trim_end_matches(".json")
+            .trim_end_matches(".txt")
+            .to_string();
+
+    if let Some(page) = get_cached_page(&cache_key) {
+        return Ok(
+            Response::builder()
+                .header(header::CONTENT_TYPE, page.content_type)
+                .header(DATE_HEADER, date)
+                .header("x-minuteman-cache", "hit")
+                .body(Body::from(page.body))
+                .unwrap(),
+        );
+    }
+
+    let generation = page_generation(&chat_id);
+
+    let response =
+        render_chat_listing(db, chat_id.clone(), date_query, query, viewer, lang, base_url, raw_query, theme)
+            .await?
+            .into_response();
+
+    if response.status() != StatusCode::OK {
+        return Ok(response);
+    }
+
+    let content_type =
+        response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .map(|content_type| content_type.to_str().ok())
+            .flatten()
+            .unwrap_or("text/html; charset=utf-8")
+            .to_string();
+
+    let body =
+        to_bytes(response.into_body())
+            .await
+            .map_err(|err|
+                warp::reject::custom(
+                    MinutemanError::Other(
+                        format!("{:?}", err),
+                    ),
+                )
+            )?
+            .to_vec();
+
+    store_page(
+        &cache_key,
+        &chat_id,
+        generation,
+        CachedPage {
+            content_type: content_type.clone(),
+            body: body.clone(),
+        },
+    );
+
+    Ok(
+        Response::builder()
+            .header(header::CONTENT_TYPE, content_type)
+            .header(DATE_HEADER, date)
+            .header("x-minuteman-cache", "miss")
+            .body(Body::from(body))
+            .unwrap(),
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListingFormat {
+    Html,
+    Json,
+    Txt,
+}
+
+impl ListingFormat {
+    pub fn from_date_query(
+        date_query: &str,
+    ) -> Self {
+        if date_query.ends_with(".json") {
+            ListingFormat::Json
+        } else if date_query.ends_with(".txt") {
+            ListingFormat::Txt
+        } else {
+            ListingFormat::Html
+        }
+    }
+}
+
+/// The file behind a media row, as far as the listing cares.
+#[derive(Debug, Clone)]
+pub struct ListingFile {
+    pub file_id: String,
+    // set when it wasn't archived and hasn't been since, see `get_files`
+    pub failure: Option<FileFailureKind>,
+    // what was stored beats what telegram announced
+    pub stored_mime_type: Option<String>,
+    // extension of telegram's path of the file, tells sticker formats apart
+    pub stored_extension: Option<String>,
+}
+
+/// A row of a day listing along with what it shows from elsewhere in the
+/// database. Whatever its format doesn't show isn't looked up.
+#[derive(Debug, Clone)]
+pub struct ListingEntry {
+    // what the row is stored and listed under
+    pub timestamp: i64,
+    pub slot: MessageSlot,
+    // of the stored json
+    pub size: usize,
+    pub item: LogItem,
+    // the sender's name as of the row, None without a sender
+    pub username: Option<String>,
+    // html after the nick, see `nick_attribution`
+    pub attribution: String,
+    pub forward: Option<ForwardOrigin>,
+    pub file: Option<ListingFile>,
+    pub sticker_set_title: Option<String>,
+    pub pin_permalink: Option<String>,
+    pub event_text: Option<String>,
+    // name of the admin behind a membership change
+    pub admin: Option<String>,
+    // contact card html, the user is linked when they're known
+    pub contact: Option<String>,
+    // admins only: the raw messages behind an unsupported row
+    pub raw: Vec<Value>,
+}
+
+/// A page of a chat's day with everything rendering it needs, read by
+/// `load_day_listing`.
+#[derive(Debug, Clone)]
+pub struct DayListing {
+    pub chat_id: String,
+    pub chat_name: String,
+    // the day "latest" resolved to
+    pub date: String,
+    // days since the epoch, like `chat_index`
+    pub day: i64,
+    pub format: ListingFormat,
+    pub order: ListingOrder,
+    // today's page followed as "latest"
+    pub live: bool,
+    pub anonymize: bool,
+    pub entries: Vec<ListingEntry>,
+    pub page: ListingPage,
+    // the logged days of the chat, newest first. Html only, like the rest
+    pub chat_days: Vec<i64>,
+    pub prev: Option<String>,
+    pub next: Option<String>,
+    // not for anonymized chats
+    pub chat_photo: Option<String>,
+    // json with `?resolve=1` only, by user id. Not for anonymized chats,
+    // their ids are pseudonyms
+    pub users: BTreeMap<String, UserMeta>,
+    pub name_lookups: usize,
+}
+
+/// How a day listing is rendered, from the query and the config.
+#[derive(Debug, Clone)]
+pub struct ListingOptions {
+    pub lang: Lang,
+    pub debug: bool,
+    pub terms: Vec<String>,
+    // both 0 renders messages in full
+    pub collapse_chars: usize,
+    pub collapse_lines: usize,
+    pub hour_separators: bool,
+    pub refresh: Option<u64>,
+    // json only, see `ListingQuery::base_url`
+    pub base_url: Option<String>,
+    pub resolve_users: bool,
+    // html only, the server picks it from the request
+    pub theme: Theme,
+    // html only, the request's query string for the footer's theme and lang
+    // links
+    pub query: String,
+}
+
+impl ListingOptions {
+    pub fn new(
+        query: &ListingQuery,
+        viewer: &Viewer,
+        lang: Lang,
+        live: bool,
+        base_url: Option<String>,
+    ) -> Self {
+        let terms = query.search_terms();
+
+        // json and txt always carry the full text, this only applies to html.
+        // Highlighted matches mustn't end up folded away.
+        let (collapse_chars, collapse_lines) =
+            if query.full_messages() || !terms.is_empty() {
+                (0, 0)
+            } else {
+                (get_collapse_chars(), get_collapse_lines())
+            };
+
+        ListingOptions {
+            lang,
+            debug: query.debug_view(viewer),
+            terms,
+            collapse_chars,
+            collapse_lines,
+            hour_separators: query.hour_separators(),
+            refresh: get_live_refresh_interval().filter(|_| live),
+            base_url,
+            resolve_users: query.resolve_users(),
+            theme: Theme::Auto,
+            query: String::new(),
+        }
+    }
+}
+
+/// Reads a page of a chat's day and looks up what its rows show in
+/// `format`. Rendering it doesn't need the database anymore.
+pub fn load_day_listing(
+    db: &impl ReadStore,
+    chat_id: &str,
+    date: NaiveDate,
+    format: ListingFormat,
+    query: &ListingQuery,
+    viewer: &Viewer,
+    live: bool,
+) -> DayListing {
+    let anonymize = viewer.anonymize_chat(chat_id);
+
+    let show_raw = viewer.admin && query.raw.unwrap_or(0) != 0;
+
+    let html = format == ListingFormat::Html;
+
+    let (time_start, time_end) = day_time_bounds(date);
+
+    let order = query.listing_order(format);
+
+    // the live page is about the newest messages whatever the order, it
+    // reads up to them instead of from the start of the day
+    let cursor =
+        match (query.listing_cursor(), order) {
+            (None, ListingOrder::Asc) if live =>
+                time_end
+                    .parse::<i64>()
+                    .ok()
+                    .map(|time_end| ListingCursor::Older(MessageSlot::from(time_end))),
+            (cursor, _) => cursor,
+        };
+
+    let mut names =
+        NameCache::new(db)
+            .with_historical_names(query.historical_names())
+            .with_anonymized(anonymize);
+
+    let mut entries = Vec::<ListingEntry>::new();
+
+    let page = chat_listing_iter(
+        db,
+        chat_id,
+        &time_start,
+        &time_end,
+        &cursor,
+        query.listing_limit(),
+        order,
+        |slot, val| {
+            let timestamp = slot.time;
+
+            let item =
+                ok_or_return!(
+                    serde_json::from_slice::<LogItem>(val),
+                );
+
+            // anonymized items lose their raw message, this is all that's
+            // left of the forward there
+            let forward =
+                match format {
+                    ListingFormat::Txt => None,
+                    _ =>
+                        item.source()
+                            .map(|source| source.forward.as_ref())
+                            .flatten()
+                            .map(|forward| forward_origin(db, forward, viewer, anonymize)),
+                };
+
+            // json carries ids, the names are for people
+            let username =
+                match format {
+                    ListingFormat::Json => None,
+                    _ =>
+                        item.user_id()
+                            .map(|user_id| names.user_at(user_id, timestamp)),
+                };
+
+            let mut entry =
+                ListingEntry {
+                    timestamp,
+                    slot,
+                    size: val.len(),
+                    item,
+                    username,
+                    attribution: String::new(),
+                    forward,
+                    file: None,
+                    sticker_set_title: None,
+                    pin_permalink: None,
+                    event_text: None,
+                    admin: None,
+                    contact: None,
+                    raw: vec!(),
+                };
+
+            match entry.item {
+                LogItem::Chat { ref chat_type, .. } if format != ListingFormat::Json =>
+                    entry.event_text = Some(chat_event_text(chat_type, &mut names)),
+                LogItem::Membership { admin_id: Some(ref admin_id), .. } if format != ListingFormat::Json =>
+                    entry.admin = Some(names.user_at(admin_id, timestamp)),
+                _ if !html => {}
+                LogItem::Message { ref user_id, ref via_bot, ref author_signature, .. } =>
+                    entry.attribution =
+                        nick_attribution(
+                            &mut names,
+                            user_id,
+                            via_bot,
+                            // signatures are the admin's real name
+                            if anonymize { &None } else { author_signature },
+                        ),
+                LogItem::Media { ref files, ref media_type, ref user_id, ref via_bot, ref author_signature, .. } => {
+                    entry.attribution =
+                        nick_attribution(
+                            &mut names,
+                            user_id,
+                            via_bot,
+                            if anonymize { &None } else { author_signature },
+                        );
+
+                    entry.file =
+                        files
+                            .last()
+                            .map(|file_id| {
+                                let meta = get_file_meta(db, file_id);
+
+                                ListingFile {
+                                    file_id: file_id.clone(),
+                                    // a file that failed once may have been stored since
+                                    failure:
+                                    get_file_failure(db, file_id)
+                                        .filter(|_| meta.is_none())
+                                        .map(|failure| failure.kind),
+                                    stored_extension:
+                                    meta.as_ref()
+                                        .map(|meta| meta.file_path.as_deref())
+                                        .flatten()
+                                        .map(|file_path| file_path.rsplit_once('.'))
+                                        .flatten()
+                                        .map(|(_, extension)| extension.to_lowercase()),
+                                    stored_mime_type: meta.map(|meta| meta.mime_type).flatten(),
+                                }
+                            });
+
+                    if let LogItemMediaType::Sticker { set_name: Some(ref set_name), .. } = media_type {
+                        entry.sticker_set_title =
+                            get_sticker_set(db, set_name)
+                                .map(|set| set.title)
+                                .flatten();
+                    }
+                }
+                LogItem::Pin { ref message_id, .. } =>
+                    entry.pin_permalink =
+                        resolve_message_ref(db, chat_id, message_id)
+                            .map(|slot| message_permalink(chat_id, slot.time))
+                            .flatten(),
+                LogItem::Special { ref special_type, ref source, .. } => {
+                    let vcard_url =
+                        source
+                            .as_ref()
+                            .map(|source| format!("/chat/{}/contact/{}.vcf", chat_id, source.id));
+
+                    entry.contact = render_contact(db, special_type, vcard_url, anonymize);
+                }
+                LogItem::Unimplemented(_, _, _, ref source) if show_raw => {
+                    entry.raw = find_raw_messages(db, chat_id, timestamp);
+
+                    // older entries only have the copy inside the log item
+                    if entry.raw.is_empty() {
+                        entry.raw.extend(
+                            source
+                                .as_ref()
+                                .map(|source| serde_json::to_value(source).ok())
+                                .flatten(),
+                        );
+                    }
+                }
+                _ => {}
+            }
+
+            entries.push(entry);
+        },
+    );
+
+    let day =
+        NaiveDateTime::new(
+            date,
+            NaiveTime::from_hms(0, 0, 0),
+        ).timestamp() / 86_400;
+
+    let chat_days =
+        match html {
+            true => find_chat_days(db, chat_id),
+            false => vec!(),
+        };
+
+    // previews would show what pseudonyms hide
+    let chat_photo =
+        match html && !anonymize {
+            true =>
+                find_chat_meta_history(db, chat_id)
+                    .iter()
+                    .rev()
+                    .find_map(|entry|
+                        match entry.change {
+                            ChatMetaChange::Photo { file_id: Some(ref file_id) } =>
+                                Some(format!("/file/video_thumb/{}", file_id)),
+                            _ => None,
+                        }
+                    ),
+            false => None,
+        };
+
+    let mut users = BTreeMap::<String, UserMeta>::new();
+
+    if format == ListingFormat::Json && query.resolve_users() && !anonymize {
+        let user_ids =
+            entries
+                .iter()
+                .flat_map(|entry|
+                    match entry.item {
+                        LogItem::Message { ref via_bot, .. }
+                        | LogItem::Media { ref via_bot, .. } => [entry.item.user_id(), via_bot.as_ref()],
+                        LogItem::Membership { ref admin_id, .. } => [entry.item.user_id(), admin_id.as_ref()],
+                        _ => [entry.item.user_id(), None],
+                    }
+                )
+                .flatten()
+                .cloned()
+                .collect::<HashSet<String>>();
+
+        // users without a meta record are left out
+        for user_id in user_ids.into_iter() {
+            if let Some(user) = get_user_meta(db, &user_id) {
+                users.insert(user_id, user);
+            }
+        }
+    }
+
+    DayListing {
+        chat_id: chat_id.to_string(),
+        chat_name: resolve_chat_name(db, chat_id),
+        date: date.format("%Y-%m-%d").to_string(),
+        day,
+        format,
+        order,
+        live,
+        anonymize,
+        entries,
+        page,
+        prev:
+        chat_days
+            .iter()
+            .find(|chat_day| **chat_day < day)
+            .map(|chat_day| format_chat_day(*chat_day))
+            .flatten(),
+        next:
+        chat_days
+            .iter()
+            .rev()
+            .find(|chat_day| **chat_day > day)
+            .map(|chat_day| format_chat_day(*chat_day))
+            .flatten(),
+        chat_days,
+        chat_photo,
+        users,
+        name_lookups: names.lookups(),
+    }
+}
+
+/// What every `?v=2` json listing is wrapped in, whether there's a day to
+/// show or not. Only "ok" isn't an error.
+fn listing_envelope(
+    status: &str,
+    // the day "latest" resolved to
+    date: Option<&str>,
+    data: Value,
+    order: ListingOrder,
+    next: Option<String>,
+) -> Value {
+    json!({
+        "status": status,
+        "error": status != "ok",
+        "date": date,
+        "data": data,
+        "order": order.code(),
+        "next": next,
+    })
+}
+
+/// The json of a day listing in the `?v=2` envelope, the log items as
+/// they're stored with their file urls, times and forward origin added.
+/// The `data` of it is what's served without the envelope, see
+/// `ListingQuery::json_envelope`.
+pub fn render_day_json(
+    listing: &DayListing,
+    options: &ListingOptions,
+) -> Value {
+    // file urls are relative unless `?absolute=1`
+    let base_url = options.base_url.as_deref().unwrap_or_default();
+
+    let data =
+        listing.entries
+            .iter()
+            .filter_map(|entry| {
+                let item = &entry.item;
+
+                // thumbnails and chat photos aren't rewritten in place,
+                // they're only listed here
+                let file_urls = item.file_urls();
+
+                // the stored size of a photo and its thumbnail, which is
+                // served for every photo whether telegram sent one or not
+                let photo =
+                    match *item {
+                        LogItem::Media { ref files, media_type: LogItemMediaType::Image { ref thumb_file_id, .. }, .. } =>
+                            files
+                                .last()
+                                .map(|file_id|
+                                    json!({
+                                        "original": {
+                                            "file_id": file_id,
+                                            "url": format!("{}/file/image/{}", base_url, file_id),
+                                        },
+                                        "thumb": {
+                                            "file_id": thumb_file_id,
+                                            "url": format!("{}/file/thumb/{}", base_url, file_id),
+                                        },
+                                    })
+                                ),
+                        _ => None,
+                    };
+
+                let mut item = item.clone();
+
+                if let LogItem::Media { ref mut files, media_type: LogItemMediaType::Image { .. } | LogItemMediaType::Sticker { .. }, .. } = item {
+                    files
+                        .iter_mut()
+                        .for_each(|file| {
+                            *file = format!("{}/file/image/{}", base_url, file)
+                        });
+                }
+
+                let mut val = serde_json::to_value(&item).ok()?;
+
+                // next to the fields of the item, `{"media": {...}}`
+                let fields =
+                    val.as_object_mut()
+                        .map(|variant| variant.values_mut().next())
+                        .flatten()
+                        .map(Value::as_object_mut)
+                        .flatten();
+
+                if let Some(fields) = fields {
+                    // what days and cursors go by, and the two times it may
+                    // stand for
+                    fields.insert("timestamp".to_string(), json!(entry.timestamp));
+                    fields.insert("received_at".to_string(), json!(entry.item.received_at()));
+                    fields.insert("original_time".to_string(), json!(entry.item.original_time()));
+
+                    if !file_urls.is_empty() {
+                        fields.insert(
+                            "file_urls".to_string(),
+                            file_urls
+                                .into_iter()
+                                .map(|(file_id, url)| (file_id, Value::String(format!("{}{}", base_url, url))))
+                                .collect::<serde_json::Map<String, Value>>()
+                                .into(),
+                        );
+                    }
+
+                    if let Some(photo) = photo {
+                        fields.insert("photo".to_string(), photo);
+                    }
+
+                    if let Some(ref origin) = entry.forward {
+                        fields.insert(
+                            "forward".to_string(),
+                            serde_json::to_value(origin).unwrap_or(Value::Null),
+                        );
+                    }
+                }
+
+                if listing.anonymize {
+                    anonymize_log_item_json(&mut val);
+                }
+
+                Some(val)
+            })
+            .collect::<Vec<Value>>();
+
+    // the page after this one in its order, passed as `cursor=` for desc
+    // and as `after=` for asc
+    let next =
+        match listing.order {
+            ListingOrder::Desc => listing.page.older_cursor(),
+            ListingOrder::Asc => listing.page.newer_cursor(),
+        };
+
+    let mut out =
+        listing_envelope(
+            "ok",
+            Some(&listing.date),
+            Value::Array(data),
+            listing.order,
+            next,
+        );
+
+    if options.resolve_users {
+        out["users"] =
+            listing.users
+                .iter()
+                .map(|(user_id, user)|
+                    (
+                        user_id.clone(),
+                        json!({
+                            "name": resolve_user_meta(user),
+                            "username": user.username,
+                        }),
+                    )
+                )
+                .collect::<serde_json::Map<String, Value>>()
+                .into();
+    }
+
+    out
+}
+
+/// A day listing as plain text, one line per row.
+pub fn render_day_txt(
+    listing: &DayListing,
+    options: &ListingOptions,
+) -> String {
+    let mut lines = Vec::<String>::new();
+
+    let mut last_hour: Option<u32> = None;
+
+    for entry in listing.entries.iter() {
+        let time = some_or_continue!(NaiveDateTime::from_timestamp_opt(entry.timestamp, 0));
+
+        let hour = time.hour();
+
+        let time = time.format("%H:%M:%S").to_string();
+
+        let username = entry.username.as_deref().unwrap_or("Unknown");
+
+        lines.push(
+            match entry.item {
+                LogItem::Message { ref text, .. } =>
+                    format!("[{}] <{}> {}", time, username, text),
+                LogItem::Media { ref caption, ref files, .. } =>
+                    format!(
+                        "[{}] * {} sent {} file(s){}",
+                        time,
+                        username,
+                        files.len(),
+                        caption
+                            .as_ref()
+                            .map(|caption| format!(": {}", caption))
+                            .unwrap_or_default(),
+                    ),
+                LogItem::Membership { ref membership_type, .. } =>
+                    format!(
+                        "[{}] *** {} {}{}",
+                        time,
+                        username,
+                        match membership_type {
+                            LogItemMembershipType::Joined => "joined the chat",
+                            LogItemMembershipType::Left => "left the chat",
+                            LogItemMembershipType::Banned => "was banned",
+                            LogItemMembershipType::Unbanned => "was unbanned",
+                            LogItemMembershipType::Promoted => "was promoted to admin",
+                            LogItemMembershipType::Demoted => "is no longer an admin",
+                        },
+                        entry.admin
+                            .as_ref()
+                            .map(|admin| format!(" by {}", admin))
+                            .unwrap_or_default(),
+                    ),
+                LogItem::Redacted { .. } =>
+                    format!("[{}] *** message redacted", time),
+                LogItem::Chat { .. } =>
+                    format!(
+                        "[{}] *** {} {}",
+                        time,
+                        username,
+                        entry.event_text.as_deref().unwrap_or_default(),
+                    ),
+                LogItem::Special { ref special_type, .. } =>
+                    format!(
+                        "[{}] * {} {}",
+                        time,
+                        username,
+                        some_or_continue!(
+                            poll_summary(special_type)
+                                .or_else(|| location_summary(special_type))
+                                .or_else(|| contact_summary(special_type, listing.anonymize))
+                                .or_else(|| special_action(special_type)),
+                        ),
+                    ),
+                LogItem::Pin { ref message, .. } =>
+                    format!(
+                        "[{}] *** {} pinned: {}",
+                        time,
+                        username,
+                        message
+                            .as_ref()
+                            .map(|message| format!("\"{}\"", message))
+                            .unwrap_or("a message".to_string()),
+                    ),
+                LogItem::Unimplemented(..) => continue,
+            },
+        );
+
+        // the line only just got in, the separator goes above it
+        if options.hour_separators && last_hour != Some(hour) {
+            lines.insert(lines.len() - 1, format!("--- {:02}:00 ---", hour));
+
+            last_hour = Some(hour);
+        }
+    }
+
+    lines.join("\n")
+}
+
+/// The table row of a listing entry, None for what the listing doesn't
+/// show. `time` is what the time column shows.
+fn render_entry_row(
+    listing: &DayListing,
+    entry: &ListingEntry,
+    time: &str,
+    options: &ListingOptions,
+) -> Option<String> {
+    let lang = options.lang;
+
+    // names are telegram's, anyone can put markup into them
+    let username = || escape_html(entry.username.as_deref().unwrap_or("Unknown"));
+
+    let (class, nick, content) =
+        match entry.item {
+            LogItem::Message { ref text, ref entities, .. } =>
+                (
+                    "message",
+                    format!("{}{}", username(), entry.attribution),
+                    mark_html(
+                        &render_collapsed_message_text(
+                            text,
+                            &if listing.anonymize {
+                                // text mentions link to the user's profile
+                                entities
+                                    .iter()
+                                    .filter(|entity| !matches!(entity.kind, LogItemMessageEntityKind::TextMention(_)))
+                                    .cloned()
+                                    .collect::<Vec<LogItemMessageEntity>>()
+                            } else {
+                                entities.clone()
+                            },
+                            options.collapse_chars,
+                            options.collapse_lines,
+                        ),
+                        &options.terms,
+                    ),
+                ),
+            LogItem::Media { ref media_type, ref caption, .. } => {
+                let file =
+                    entry.file
+                        .as_ref()
+                        .map(|file|
+                            match (media_type, file.failure) {
+                                // there's no blob behind these, see `get_files`
+                                (_, Some(failure)) =>
+                                    format!(
+                                        "<span class=\"note\">{}</span>",
+                                        t(lang, failure_note(failure)),
+                                    ),
+                                // animated stickers are lottie files, the label below
+                                // is all there is to show of them
+                                (LogItemMediaType::Sticker { .. }, None) if file.stored_extension.as_deref() == Some("tgs") =>
+                                    format!(
+                                        "<span class=\"note\">{}</span>",
+                                        t(lang, "media.sticker_unsupported"),
+                                    ),
+                                (LogItemMediaType::Sticker { .. }, None) if file.stored_extension.as_deref() == Some("webm") =>
+                                    render_animation(&file.file_id, None, Some("video/webm")),
+                                (LogItemMediaType::Animation { ref thumb_file_id, ref mime_type, .. }, None) =>
+                                    render_animation(
+                                        &file.file_id,
+                                        thumb_file_id.as_deref(),
+                                        file.stored_mime_type
+                                            .as_ref()
+                                            .or(mime_type.as_ref())
+                                            .map(String::as_str),
+                                    ),
+                                (_, None) =>
+                                    format!(
+                                        "<a href=\"/file/image/{}\"><img src=\"/file/thumb/{}?fallback=1\" style=\"max-height: 300px; max-width: 300px;\" loading=\"lazy\"/></a>",
+                                        file.file_id,
+                                        file.file_id,
+                                    ),
+                            }
+                        )
+                        .unwrap_or_default();
+
+                let media_caption =
+                    if let LogItemMediaType::Sticker { ref emoji, ref set_name } = media_type {
+                        render_sticker_label(
+                            emoji.as_deref(),
+                            set_name.as_deref(),
+                            entry.sticker_set_title.as_deref(),
+                        )
+                    } else if let Some(caption) = caption {
+                        caption.to_string()
+                    } else {
+                        format!("<span class=\"note\">{}</span>", t(lang, "media.no_caption"))
+                    };
+
+                (
+                    "message action",
+                    format!("{}{}", username(), entry.attribution),
+                    format!(
+                        "{} <br/> {}",
+                        mark_html(&media_caption, &options.terms),
+                        file,
+                    ),
+                )
+            }
+            LogItem::Membership { ref membership_type, .. } =>
+                (
+                    match membership_type {
+                        LogItemMembershipType::Joined
+                        | LogItemMembershipType::Unbanned
+                        | LogItemMembershipType::Promoted => "join",
+                        LogItemMembershipType::Left
+                        | LogItemMembershipType::Banned
+                        | LogItemMembershipType::Demoted => "leave",
+                    },
+                    username(),
+                    format!(
+                        "<span class=\"reason\">{}{}</span>",
+                        match membership_type {
+                            LogItemMembershipType::Joined => t(lang, "event.joined"),
+                            LogItemMembershipType::Left => t(lang, "event.left"),
+                            LogItemMembershipType::Banned => t(lang, "event.banned"),
+                            LogItemMembershipType::Unbanned => t(lang, "event.unbanned"),
+                            LogItemMembershipType::Promoted => t(lang, "event.promoted"),
+                            LogItemMembershipType::Demoted => t(lang, "event.demoted"),
+                        },
+                        entry.admin
+                            .as_ref()
+                            .map(|admin| format!(" ({} {})", t(lang, "event.by"), escape_html(admin)))
+                            .unwrap_or_default(),
+                    ),
+                ),
+            LogItem::Pin { ref message, .. } =>
+                (
+                    "pin",
+                    username(),
+                    format!(
+                        "<span class=\"reason\">pinned: {}</span>",
+                        format_pin_snippet(message, entry.pin_permalink.as_deref()),
+                    ),
+                ),
+            LogItem::Unimplemented(ref label, ..) =>
+                (
+                    "unsupported",
+                    escape_html(entry.username.as_deref().unwrap_or_default()),
+                    format!(
+                        "<span class=\"reason\">unsupported message ({})</span>{}",
+                        escape_html(label),
+                        entry.raw
+                            .iter()
+                            .map(|raw|
+                                format!(
+                                    "<pre class=\"raw\">{}</pre>",
+                                    escape_html(
+                                        &serde_json::to_string_pretty(raw)
+                                            .unwrap_or_default(),
+                                    ),
+                                )
+                            )
+                            .collect::<String>(),
+                    ),
+                ),
+            LogItem::Special { ref special_type, .. } => {
+                let (class, content) =
+                    render_poll(special_type)
+                        .map(|poll| ("message poll", poll))
+                        .or_else(||
+                            render_location(special_type)
+                                .map(|location| ("location", format!("<span class=\"reason\">{}</span>", location)))
+                        )
+                        .or_else(||
+                            entry.contact
+                                .clone()
+                                .map(|contact| ("message contact", contact))
+                        )
+                        .or_else(||
+                            render_special_action(special_type)
+                                .map(|action| ("action", action))
+                        )?;
+
+                (class, username(), content)
+            }
+            LogItem::Chat { ref chat_type, .. } =>
+                (
+                    // retention is something readers of the log should not
+                    // miss
+                    match chat_type {
+                        LogItemChatType::AutoDeleteTimerChanged { .. } => "chat auto-delete",
+                        _ => "chat",
+                    },
+                    username(),
+                    format!(
+                        "<span class=\"reason\">{}</span>",
+                        escape_html(entry.event_text.as_deref().unwrap_or_default()),
+                    ),
+                ),
+            LogItem::Redacted { .. } =>
+                (
+                    "redacted",
+                    String::new(),
+                    "<span class=\"reason\">message redacted</span>".to_string(),
+                ),
+        };
+
+    Some(
+        format!(
+            "<tr class=\"{}\">\
+                <td class=\"time\">\
+                    <a class=\"time-anchor\" id=\"{}\"></a>\
+                    <a href=\"#{}\">{}</a>\
+                </td>\
+                <td class=\"nick\">{}</td>\
+                <td class=\"content\">{}</td>\
+            </tr>",
+            class,
+            entry.timestamp,
+            entry.timestamp,
+            time,
+            nick,
+            content,
+        ),
+    )
+}
+
+/// A day listing as a page, with the hour strip above the rows and the
+/// page links around them.
+pub fn render_day_html(
+    listing: &DayListing,
+    query: &ListingQuery,
+    options: &ListingOptions,
+) -> String {
+    let lang = options.lang;
+
+    let chat_id = &listing.chat_id;
+    let chat_name = &listing.chat_name;
+    let date = &listing.date;
+
+    let mut rows = Vec::<String>::new();
+
+    let mut message_count = 0;
+
+    // origin and time of the last row if it was a forward
+    let mut last_forward: Option<(&ForwardOrigin, i64)> = None;
+
+    // hours of the day that got rows, for the strip above the listing
+    let mut hours = [false; 24];
+    let mut last_hour: Option<u32> = None;
+
+    for entry in listing.entries.iter() {
+        let listed = some_or_continue!(NaiveDateTime::from_timestamp_opt(entry.timestamp, 0));
+
+        // the hour the row is listed under, which is what the page's day
+        // goes by too
+        let hour = listed.hour();
+
+        if last_hour != Some(hour) {
+            rows.push(
+                format!(
+                    "<tr class=\"hour-break\" id=\"h{:02}\">\
+                        <td class=\"time\"></td>\
+                        <td class=\"nick\"></td>\
+                        <td class=\"content\">— {:02}:00 —</td>\
+                    </tr>",
+                    hour,
+                    hour,
+                )
+            );
+
+            hours[hour as usize] = true;
+            last_hour = Some(hour);
+        }
+
+        // forwards are listed under when they were originally sent but
+        // shown with when they got here, the banner has the original
+        let time =
+            match NaiveDateTime::from_timestamp_opt(entry.item.received_at(), 0) {
+                Some(received) if received.date() != listed.date() =>
+                    received.format("%Y-%m-%d %H:%M:%S").to_string(),
+                Some(received) => received.format("%H:%M:%S").to_string(),
+                None => listed.format("%H:%M:%S").to_string(),
+            };
+
+        if matches!(entry.item, LogItem::Message { .. } | LogItem::Media { .. }) {
+            message_count += 1;
+        }
+
+        match entry.forward {
+            Some(ref origin) => {
+                // a run of forwards from the same origin gets one banner
+                let grouped =
+                    last_forward
+                        .map(|(last, last_time)|
+                            last.same_origin(origin) && (entry.timestamp - last_time).abs() <= FORWARD_GROUP_WINDOW
+                        )
+                        .unwrap_or(false);
+
+                if !grouped {
+                    rows.push(
+                        format!(
+                            "<tr class=\"forward\">\
+                                <td class=\"time\"></td>\
+                                <td class=\"nick\"></td>\
+                                <td class=\"content\">{}</td>\
+                            </tr>",
+                            render_forward_banner(origin, lang),
+                        )
+                    );
+                }
+
+                last_forward = Some((origin, entry.timestamp));
+            }
+            None => last_forward = None,
+        }
+
+        rows.extend(render_entry_row(listing, entry, &time, options));
+
+        if options.debug {
+            rows.push(render_debug_row(chat_id, date, entry.slot, &entry.item, entry.size));
+        }
+    }
+
+    let header =
+        HeaderBar::new()
+            .with_link(
+                t(lang, "nav.home"),
+                Some("/".into()),
+            )
+            .with_chat_title(chat_id, format!("{} - {}", chat_name, date))
+            .with_link(
+                t(lang, "nav.index"),
+                Some(format!("/chat/{}", chat_id)),
+            )
+            .with_link(
+                t(lang, "nav.previous"),
+                listing.prev
+                    .as_ref()
+                    .map(|day| format!("/chat/{}/{}", chat_id, day)),
+            )
+            .with_link(
+                t(lang, "nav.next"),
+                listing.next
+                    .as_ref()
+                    .map(|day| format!("/chat/{}/{}", chat_id, day)),
+            )
+            .with_right_item(
+                HeaderItem::Raw {
+                    html: day_jump_form(chat_id, date, &listing.chat_days, listing.day, lang),
+                },
+            );
+
+    // page links point at the resolved date so that "latest" rolling over
+    // to a new day doesn't invalidate the cursor
+    let header =
+        with_listing_page_links(
+            header,
+            chat_id,
+            date,
+            &listing.page,
+            query,
+            lang,
+        )
+            .with_link(
+                t(lang, "nav.latest"),
+                Some(format!("/chat/{}/latest", chat_id)),
+            )
+            .with_link(
+                if query.historical_names() {
+                    t(lang, "nav.current_names")
+                } else {
+                    t(lang, "nav.historical_names")
+                },
+                Some(
+                    if query.historical_names() {
+                        format!("/chat/{}/{}", chat_id, date)
+                    } else {
+                        format!("/chat/{}/{}?names=historical", chat_id, date)
+                    },
+                ),
+            )
+            .with_format_links(
+                &format!("/chat/{}/{}", chat_id, date),
+            );
+
+    let header =
+        match listing.live {
+            true =>
+                header.with_right_item(
+                    HeaderItem::Raw {
+                        html: format!("<span class=\"live\">{}</span>", t(lang, "nav.live")),
+                    },
+                ),
+            false => header,
+        };
+
+    let footer =
+        with_listing_page_links(
+            HeaderBar::new(),
+            chat_id,
+            date,
+            &listing.page,
+            query,
+            lang,
+        );
+
+    let title = format!("{} - {}", chat_name, date);
+
+    let mut html_page =
+        Page::new(title.clone())
+            .with_theme(options.theme)
+            .with_lang(options.lang)
+            .with_query(&options.query);
+
+    // previews would show what pseudonyms hide
+    if !listing.anonymize {
+        let chat_photo = listing.chat_photo.clone();
+
+        // text and image of the newest listed item, on permalinks the
+        // linked one
+        let preview =
+            match listing.order {
+                ListingOrder::Desc => listing.entries.first(),
+                ListingOrder::Asc => listing.entries.last(),
+            }
+                .map(link_preview);
+
+        let (description, image) =
+            match preview {
+                Some((text, image)) if query.cursor.is_some() =>
+                    (
+                        text.unwrap_or_else(|| format!("{} on {}", chat_name, date)),
+                        image.or(chat_photo),
+                    ),
+                _ =>
+                    (
+                        format!("{} message(s) on {}", message_count, date),
+                        chat_photo,
+                    ),
+            };
+
+        html_page = html_page.with_preview(title, description, image);
+    }
+
+    if let Some(interval) = options.refresh {
+        html_page = html_page.with_refresh(interval);
+    }
+
+    let html_page = html_page.with_header(header);
+
+    let html_page =
+        match listing.entries.is_empty() {
+            true => html_page.with_body(render_empty_day(listing, lang)),
+            false =>
+                html_page
+                    .with_body(render_hour_strip(&hours))
+                    .with_body("<div class=\"log\"><table class=\"log\"><tbody>")
+                    .with_body(rows.join(""))
+                    .with_body("</tbody></table></div>"),
+        };
+
+    html_page
+        .with_footer(footer)
+        .render()
+}
+
+/// Stands in for the table of a day page without rows, with links to the
+/// logged days around it.
+fn render_empty_day(
+    listing: &DayListing,
+    lang: Lang,
+) -> String {
+    let nearest =
+        [&listing.prev, &listing.next]
+            .into_iter()
+            .flatten()
+            .map(|day|
+                format!(
+                    "<a href=\"/chat/{}/{}\">{}</a>",
+                    escape_html(&listing.chat_id),
+                    day,
+                    day,
+                )
+            )
+            .collect::<Vec<String>>();
+
+    format!(
+        "<div class=\"empty\"><p>{}</p>{}</div>",
+        t(lang, "empty.day"),
+        match nearest.is_empty() {
+            true => String::new(),
+            false => format!("<p>{} {}</p>", t(lang, "empty.nearest"), nearest.join(" · ")),
+        },
+    )
+}
+
+/// The latest page of a chat that has nothing logged yet.
+fn render_empty_chat(
+    chat_id: &str,
+    chat_name: &str,
+    format: ListingFormat,
+    query: &ListingQuery,
+    lang: Lang,
+    theme: Theme,
+) -> Response<Body> {
+    match format {
+        ListingFormat::Json if query.json_envelope() =>
+            warp::reply::json(
+                &listing_envelope(
+                    "ok",
+                    None,
+                    json!([]),
+                    query.listing_order(format),
+                    None,
+                ),
+            ).into_response(),
+        ListingFormat::Json =>
+            warp::reply::json(&json!([])).into_response(),
+        ListingFormat::Txt =>
+            warp::reply::with_header(
+                String::new(),
+                "content-type",
+                "text/plain; charset=utf-8",
+            ).into_response(),
+        ListingFormat::Html =>
+            warp::reply::html(
+                Page::new(chat_name)
+                    .with_theme(theme)
+                    .with_lang(lang)
+                    .with_header(
+                        HeaderBar::new()
+                            .with_link(
+                                t(lang, "nav.home"),
+                                Some("/".into()),
+                            )
+                            .with_chat_title(chat_id, chat_name)
+                            .with_link(
+                                t(lang, "nav.index"),
+                                Some(format!("/chat/{}", chat_id)),
+                            )
+                            .with_link(
+                                t(lang, "nav.latest"),
+                                Some(format!("/chat/{}/latest", chat_id)),
+                            ),
+                    )
+                    .with_body(
+                        format!(
+                            "<div class=\"empty\"><p>{}</p></div>",
+                            t(lang, "empty.chat"),
+                        ),
+                    )
+                    .render(),
+            ).into_response(),
+    }
+}
+
+/// What unknown chat ids get instead of an empty listing.
+fn render_not_archived(
+    format: ListingFormat,
+    lang: Lang,
+    theme: Theme,
+) -> Response<Body> {
+    match format {
+        ListingFormat::Json => error_json(StatusCode::NOT_FOUND, "chat not archived"),
+        ListingFormat::Txt =>
+            warp::reply::with_status(
+                warp::reply::with_header(
+                    "chat not archived",
+                    "content-type",
+                    "text/plain; charset=utf-8",
+                ),
+                StatusCode::NOT_FOUND,
+            ).into_response(),
+        ListingFormat::Html => error_page(StatusCode::NOT_FOUND, t(lang, "empty.not_archived"), theme),
+    }
+}
+
+// a 400 in every format, the date is from the path and goes out escaped
+fn render_invalid_date(
+    date: &str,
+    format: ListingFormat,
+    query: &ListingQuery,
+    theme: Theme,
+) -> Response<Body> {
+    let mut response =
+        match format {
+            ListingFormat::Txt =>
+                warp::reply::with_header(
+                    format!("invalid date (got {})", date),
+                    "content-type",
+                    "text/plain; charset=utf-8",
+                ).into_response(),
+            ListingFormat::Html =>
+                warp::reply::html(
+                    Page::new("invalid date")
+                        .with_theme(theme)
+                        .with_body(
+                            format!(
+                                "<div class=\"log\">invalid date (got {})</div>",
+                                escape_html(date),
+                            ),
+                        )
+                        .render(),
+                ).into_response(),
+            ListingFormat::Json if query.json_envelope() =>
+                warp::reply::json(
+                    &listing_envelope(
+                        &format!("invalid date (got {})", date),
+                        None,
+                        Value::Null,
+                        query.listing_order(format),
+                        None,
+                    ),
+                ).into_response(),
+            // what it has always been, the one listing error with a body
+            ListingFormat::Json =>
+                warp::reply::json(
+                    &json!({
+                        "status": format!("invalid date (got {})", date),
+                        "error": true,
+                        "data": null
+                    }),
+                ).into_response(),
+        };
+
+    *response.status_mut() = StatusCode::BAD_REQUEST;
+
+    response
+}
+
+async fn render_chat_listing(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    chat_id: String,
+    date_query: String,
+    query: ListingQuery,
+    viewer: Viewer,
+    lang: Lang,
+    base_url: Option<String>,
+    raw_query: String,
+    theme: Theme,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    // cached pages don't get here, they don't need a slot
+    let _slot = acquire_listing_slot().map_err(warp::reject::custom)?;
+
+    let dbi = lock_db(&db);
+
+    let view = dbi.read_view();
+
+    let format = ListingFormat::from_date_query(&date_query);
+
+    if !is_archived_chat(&view, &chat_id) {
+        return Ok(render_not_archived(format, lang, theme));
+    }
+
+    let date =
+        if date_query.starts_with("latest") {
+            match find_latest_chat_day(&view, &chat_id) {
+                Some(day) => day,
+                None =>
+                    return Ok(
+                        render_empty_chat(&chat_id, &resolve_chat_name(&view, &chat_id), format, &query, lang, theme),
+                    ),
+            }
+        } else {
+            date_query
+                .trim_end_matches(".json")
+                .trim_end_matches(".txt")
+                .to_string()
+        };
+
+    let day =
+        match NaiveDate::parse_from_str(&date, "%Y-%m-%d") {
+            Ok(day) => day,
+            Err(_) => return Ok(render_invalid_date(&date, format, &query, theme)),
+        };
+
+    // "latest" on today's page keeps up with the chat by itself, on an older
+    // day it's the same as that day's page. Today is the configured
+    // timezone's, the one the people reading it are in
+    let live =
+        date_query.starts_with("latest")
+            && query.cursor.is_none()
+            && day == Utc::now().with_timezone(&get_timezone()).date().naive_local();
+
+    let options =
+        ListingOptions {
+            theme,
+            query: raw_query,
+            ..ListingOptions::new(&query, &viewer, lang, live, base_url)
+        };
+
+    let render_start = Instant::now();
+
+    let listing = load_day_listing(&view, &chat_id, day, format, &query, &viewer, live);
+
+    drop(view);
+    drop(dbi);
+
+    let response =
+        match format {
+            ListingFormat::Json => {
+                let out = render_day_json(&listing, &options);
+
+                match query.json_envelope() {
+                    true => warp::reply::json(&out).into_response(),
+                    false => warp::reply::json(&out["data"]).into_response(),
+                }
+            }
+            ListingFormat::Txt =>
+                warp::reply::with_header(
+                    render_day_txt(&listing, &options),
+                    "content-type",
+                    "text/plain; charset=utf-8",
+                ).into_response(),
+            ListingFormat::Html =>
+                warp::reply::html(render_day_html(&listing, &query, &options)).into_response(),
+        };
+
+    // which day "latest" turned out to be, in every format
+    let response = warp::reply::with_header(response, DATE_HEADER, listing.date.as_str()).into_response();
+
+    if format == ListingFormat::Html {
+        tracing::debug!(
+            chat_id = %chat_id,
+            date = %listing.date,
+            rows = listing.entries.len(),
+            elapsed_ms = render_start.elapsed().as_millis() as u64,
+            name_lookups = listing.name_lookups,
+            "rendered day listing"
+        );
+    }
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workers::telegram_handler::LogItemTextMention;
+
+    // 2020-09-13 12:26:40 UTC
+    const TIME: i64 = 1_600_000_000;
+
+    fn message(text: &str, entities: Vec<LogItemMessageEntity>) -> LogItem {
+        LogItem::Message {
+            user_id: Some("1001".to_string()),
+            time: TIME,
+            received_at: TIME,
+            text: text.to_string(),
+            entities,
+            via_bot: None,
+            author_signature: None,
+            source: None,
+            v: 0,
+        }
+    }
+
+    fn entry(timestamp: i64, item: LogItem) -> ListingEntry {
+        ListingEntry {
+            timestamp,
+            slot: MessageSlot::from(timestamp),
+            size: 0,
+            item,
+            username: Some("Alice".to_string()),
+            attribution: String::new(),
+            forward: None,
+            file: None,
+            sticker_set_title: None,
+            pin_permalink: None,
+            event_text: None,
+            admin: None,
+            contact: None,
+            raw: vec![],
+        }
+    }
+
+    fn forwarded(timestamp: i64, channel_id: &str, name: &str) -> ListingEntry {
+        ListingEntry {
+            forward: Some(ForwardOrigin {
+                kind: "channel",
+                id: Some(channel_id.to_string()),
+                name: name.to_string(),
+                username: None,
+                date: TIME - 86_400,
+                archive_url: None,
+            }),
+            ..entry(timestamp, message("forwarded", vec![]))
+        }
+    }
+
+    fn listing(entries: Vec<ListingEntry>) -> DayListing {
+        DayListing {
+            chat_id: "-1001".to_string(),
+            chat_name: "Chat".to_string(),
+            date: "2020-09-13".to_string(),
+            day: TIME / 86_400,
+            format: ListingFormat::Html,
+            order: ListingOrder::Asc,
+            live: false,
+            anonymize: false,
+            entries,
+            page: ListingPage::default(),
+            chat_days: vec![],
+            prev: None,
+            next: None,
+            chat_photo: None,
+            users: BTreeMap::new(),
+            name_lookups: 0,
+        }
+    }
+
+    fn options() -> ListingOptions {
+        ListingOptions {
+            lang: Lang::En,
+            debug: false,
+            terms: vec![],
+            collapse_chars: 0,
+            collapse_lines: 0,
+            hour_separators: false,
+            refresh: None,
+            base_url: None,
+            resolve_users: false,
+            theme: Theme::Auto,
+            query: String::new(),
+        }
+    }
+
+    fn text_mention() -> LogItemMessageEntity {
+        LogItemMessageEntity {
+            offset: 0,
+            length: 3,
+            kind: LogItemMessageEntityKind::TextMention(LogItemTextMention::User {
+                id: "1002".to_string(),
+                name: Some("Bob".to_string()),
+            }),
+        }
+    }
+
+    #[test]
+    fn html_escapes_message_text() {
+        let listing = listing(vec![entry(TIME, message("<b>\"hi\"</b> & bye", vec![]))]);
+
+        let html = render_day_html(&listing, &ListingQuery::default(), &options());
+
+        assert!(html.contains("&lt;b&gt;"));
+        assert!(!html.contains("<b>\"hi\"</b>"));
+    }
+
+    #[test]
+    fn html_escapes_the_chat_name_in_title_and_header() {
+        let mut listing = listing(vec![entry(TIME, message("hi", vec![]))]);
+
+        listing.chat_name = "<script>alert(1)</script>".to_string();
+
+        let html = render_day_html(&listing, &ListingQuery::default(), &options());
+
+        assert!(!html.contains("<script>"));
+        assert!(html.contains("<title>&lt;script&gt;alert(1)&lt;/script&gt;"));
+        assert!(html.contains("&lt;script&gt;alert(1)&lt;/script&gt; - 2020-09-13</span>"));
+    }
+
+    #[test]
+    fn html_escapes_nicks() {
+        let mut entry = entry(TIME, message("hi", vec![]));
+
+        entry.username = Some("<img onerror=alert(1)>".to_string());
+
+        let html = render_day_html(&listing(vec![entry]), &ListingQuery::default(), &options());
+
+        assert!(html.contains("<td class=\"nick\">&lt;img onerror=alert(1)&gt;</td>"));
+        assert!(!html.contains("<img onerror"));
+    }
+
+    #[test]
+    fn html_groups_forwards_from_the_same_origin() {
+        let listing =
+            listing(vec![
+                forwarded(TIME, "-1002", "Channel A"),
+                forwarded(TIME + 10, "-1002", "Channel A"),
+                forwarded(TIME + 20, "-1003", "Channel B"),
+                entry(TIME + 30, message("in between", vec![])),
+                forwarded(TIME + 40, "-1003", "Channel B"),
+            ]);
+
+        let html = render_day_html(&listing, &ListingQuery::default(), &options());
+
+        assert_eq!(html.matches("<tr class=\"forward\">").count(), 3);
+    }
+
+    #[test]
+    fn html_leaves_out_text_mentions_of_anonymized_chats() {
+        let mut listing = listing(vec![entry(TIME, message("Bob says hi", vec![text_mention()]))]);
+
+        let html = render_day_html(&listing, &ListingQuery::default(), &options());
+
+        assert!(html.contains("href=\"/user/1002\""));
+
+        listing.anonymize = true;
+
+        let html = render_day_html(&listing, &ListingQuery::default(), &options());
+
+        assert!(!html.contains("/user/1002"));
+        assert!(html.contains("Bob says hi"));
+    }
+
+    #[test]
+    fn json_wraps_rows_in_the_envelope() {
+        let mut listing = listing(vec![entry(TIME, message("Bob says hi", vec![text_mention()]))]);
+
+        listing.order = ListingOrder::Desc;
+
+        let out = render_day_json(&listing, &options());
+
+        assert_eq!(out["status"], "ok");
+        assert_eq!(out["error"], false);
+        assert_eq!(out["date"], "2020-09-13");
+        assert_eq!(out["order"], "desc");
+        assert_eq!(out["next"], Value::Null);
+        assert_eq!(out["data"][0]["message"]["timestamp"], TIME);
+        assert_eq!(out["data"][0]["message"]["user_id"], "1001");
+        assert_eq!(out["data"][0]["message"]["entities"][0]["kind"]["textmention"]["id"], "1002");
+    }
+
+    #[test]
+    fn json_of_anonymized_chats_has_no_text_mentions_or_user_ids() {
+        let mut listing = listing(vec![entry(TIME, message("Bob says hi", vec![text_mention()]))]);
+
+        listing.anonymize = true;
+
+        let out = render_day_json(&listing, &options());
+        let message = &out["data"][0]["message"];
+
+        assert_eq!(message["entities"][0]["kind"]["textmention"], Value::Null);
+        assert_eq!(message.get("user_id"), None);
+        assert!(message["user"].is_string());
+    }
+
+    #[test]
+    fn txt_has_a_line_per_row_and_separates_hours() {
+        let listing =
+            listing(vec![
+                entry(TIME, message("<b>hi</b>", vec![])),
+                ListingEntry {
+                    admin: Some("Bob".to_string()),
+                    ..entry(
+                        TIME + 3_600,
+                        LogItem::Membership {
+                            user_id: Some("1001".to_string()),
+                            time: TIME + 3_600,
+                            received_at: TIME + 3_600,
+                            membership_type: LogItemMembershipType::Banned,
+                            admin_id: Some("1002".to_string()),
+                            source: None,
+                            v: 0,
+                        },
+                    )
+                },
+            ]);
+
+        let options =
+            ListingOptions {
+                hour_separators: true,
+                ..options()
+            };
+
+        assert_eq!(
+            render_day_txt(&listing, &options),
+            "--- 12:00 ---\n\
+             [12:26:40] <Alice> <b>hi</b>\n\
+             --- 13:00 ---\n\
+             [13:26:40] *** Alice was banned by Bob",
+        );
+    }
+
+    #[test]
+    fn link_previews_only_use_archived_images() {
+        let media = |file: ListingFile| {
+            ListingEntry {
+                file: Some(file),
+                ..entry(
+                    TIME,
+                    LogItem::Media {
+                        user_id: Some("1001".to_string()),
+                        time: TIME,
+                        received_at: TIME,
+                        caption: Some("a photo".to_string()),
+                        media_type: LogItemMediaType::Document {
+                            file_name: None,
+                            mime_type: None,
+                        },
+                        files: vec!["file-a".to_string()],
+                        via_bot: None,
+                        author_signature: None,
+                        source: None,
+                        v: 0,
+                    },
+                )
+            }
+        };
+
+        let file =
+            ListingFile {
+                file_id: "file-a".to_string(),
+                failure: None,
+                stored_mime_type: Some("image/jpeg".to_string()),
+                stored_extension: None,
+            };
+
+        assert_eq!(
+            link_preview(&media(file.clone())),
+            (Some("a photo".to_string()), Some("/file/image/file-a".to_string())),
+        );
+
+        let failed =
+            ListingFile {
+                failure: Some(FileFailureKind::TooLarge),
+                ..file.clone()
+            };
+
+        assert_eq!(link_preview(&media(failed)).1, None);
+
+        let video =
+            ListingFile {
+                stored_mime_type: Some("video/mp4".to_string()),
+                ..file.clone()
+            };
+
+        assert_eq!(link_preview(&media(video)).1, None);
+
+        let by_extension =
+            ListingFile {
+                stored_mime_type: None,
+                stored_extension: Some("webp".to_string()),
+                ..file
+            };
+
+        assert_eq!(link_preview(&media(by_extension)).1, Some("/file/image/file-a".to_string()));
+    }
+
+    #[test]
+    fn stickers_the_browser_cant_show_keep_their_emoji_and_set() {
+        let sticker = |extension: &str| {
+            ListingEntry {
+                file: Some(ListingFile {
+                    file_id: "sticker-file".to_string(),
+                    failure: None,
+                    stored_mime_type: None,
+                    stored_extension: Some(extension.to_string()),
+                }),
+                ..entry(
+                    TIME,
+                    LogItem::Media {
+                        user_id: Some("1001".to_string()),
+                        time: TIME,
+                        received_at: TIME,
+                        caption: None,
+                        media_type: LogItemMediaType::Sticker {
+                            emoji: Some("🎉".to_string()),
+                            set_name: Some("PremiumStickers".to_string()),
+                        },
+                        files: vec!["sticker-file".to_string()],
+                        via_bot: None,
+                        author_signature: None,
+                        source: None,
+                        v: 0,
+                    },
+                )
+            }
+        };
+
+        let html = render_day_html(&listing(vec![sticker("tgs")]), &ListingQuery::default(), &options());
+
+        assert!(html.contains(t(Lang::En, "media.sticker_unsupported")));
+        assert!(html.contains("🎉 <a href=\"https://t.me/addstickers/PremiumStickers\">PremiumStickers</a>"));
+        assert!(!html.contains("/file/thumb/sticker-file"));
+
+        let html = render_day_html(&listing(vec![sticker("webm")]), &ListingQuery::default(), &options());
+
+        assert!(html.contains("<source src=\"/file/video/sticker-file\" type=\"video/webm\"/>"));
+        assert!(html.contains("https://t.me/addstickers/PremiumStickers"));
+    }
+
+    #[test]
+    fn empty_days_link_the_nearest_logged_days() {
+        let mut empty = listing(vec![]);
+
+        empty.prev = Some("2020-09-10".to_string());
+        empty.next = Some("2020-09-15".to_string());
+
+        let html = render_day_html(&empty, &ListingQuery::default(), &options());
+
+        assert!(html.contains(t(Lang::En, "empty.day")));
+        assert!(html.contains("<a href=\"/chat/-1001/2020-09-10\">2020-09-10</a> · <a href=\"/chat/-1001/2020-09-15\">2020-09-15</a>"));
+        assert!(!html.contains("<table class=\"log\">"));
+        assert!(html.ends_with("</html>"));
+
+        // without logged days around it there's nothing to point at
+        let html = render_day_html(&listing(vec![]), &ListingQuery::default(), &options());
+
+        assert!(html.contains(t(Lang::En, "empty.day")));
+        assert!(!html.contains(t(Lang::En, "empty.nearest")));
+    }
+
+    #[tokio::test]
+    async fn invalid_dates_go_back_escaped() {
+        let response = render_invalid_date("<script>", ListingFormat::Html, &ListingQuery::default(), Theme::Auto);
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let html = to_bytes(response.into_body()).await.unwrap();
+        let html = String::from_utf8_lossy(&html);
+
+        assert!(html.contains("invalid date (got &lt;script&gt;)"));
+        assert!(!html.contains("<script>"));
+    }
+
+    #[test]
+    fn viewers_who_see_different_chats_get_their_own_cached_pages() {
+        let key =
+            |viewer: &Viewer, date_query: &str|
+                listing_cache_key("-1001", date_query, &ListingQuery::default(), viewer, Lang::En, None, "", Theme::Auto);
+
+        let public = key(&Viewer::default(), "2020-09-13").unwrap();
+        let admin = key(&Viewer { admin: true, share: None }, "2020-09-13").unwrap();
+        let shared = key(&Viewer::default().with_share(Some("-1001".to_string())), "2020-09-13").unwrap();
+        let other_share = key(&Viewer::default().with_share(Some("-1002".to_string())), "2020-09-13").unwrap();
+
+        assert_ne!(public, admin);
+        assert_ne!(public, shared);
+        assert_ne!(admin, shared);
+        assert_ne!(shared, other_share);
+        assert_eq!(key(&Viewer::default(), "2020-09-13"), Some(public));
+
+        // pages that can still change aren't cached
+        assert_eq!(key(&Viewer::default(), "latest"), None);
+        assert_eq!(key(&Viewer::default(), &Utc::today().naive_utc().format("%Y-%m-%d").to_string()), None);
+    }
+}
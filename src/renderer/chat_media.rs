@@ -0,0 +1,280 @@
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use rocksdb::{DBWithThreadMode, MultiThreaded};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use warp::Reply;
+
+use crate::{some_or_continue, some_or_return};
+use crate::components::header::HeaderBar;
+use crate::components::page::{Page, Theme};
+use crate::config::ListingOrder;
+use crate::privacy::{pseudonym, Viewer};
+use crate::rate_limit::acquire_listing_slot;
+use crate::renderer::chat_listing::{chat_listing_iter, day_time_bounds};
+use crate::storage::{lock_db, ReadStore, Storage};
+use crate::utils::{find_chat_days, format_chat_day, NameCache, resolve_chat_name};
+use crate::workers::telegram_handler::{build_message_key, LogItem, LogItemMediaType, parse_message_key};
+
+const DEFAULT_MEDIA_LIMIT: usize = 200;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MediaQuery {
+    // only show days strictly before this one (YYYY-MM-DD)
+    pub before: Option<String>,
+    pub limit: Option<usize>,
+}
+
+pub struct MediaEntry {
+    pub file_id: String,
+    pub media_type: &'static str,
+    pub time: i64,
+    pub user_id: Option<String>,
+    pub message_key: String,
+    pub day: String,
+}
+
+fn collect_day_media(
+    dbi: &impl ReadStore,
+    chat_id: &str,
+    day: i64,
+    entries: &mut Vec<MediaEntry>,
+) {
+    let date = some_or_return!(format_chat_day(day));
+    let date = some_or_return!(NaiveDate::parse_from_str(&date, "%Y-%m-%d").ok());
+
+    let (time_start, time_end) = day_time_bounds(date);
+
+    chat_listing_iter(
+        dbi,
+        chat_id,
+        &time_start,
+        &time_end,
+        &None,
+        usize::MAX,
+        ListingOrder::Desc,
+        |slot, val| {
+            let item = some_or_return!(serde_json::from_slice::<LogItem>(val).ok());
+
+            if let LogItem::Media { ref files, ref media_type, ref user_id, time, .. } = item {
+                let (media_type, file_id) =
+                    match media_type {
+                        LogItemMediaType::Image { .. } =>
+                            ("image", some_or_return!(files.last().cloned())),
+                        LogItemMediaType::Video { ref thumb_file_id, .. }
+                        | LogItemMediaType::Animation { ref thumb_file_id, .. } =>
+                            ("video", some_or_return!(thumb_file_id.clone())),
+                        _ => return,
+                    };
+
+                entries.push(
+                    MediaEntry {
+                        file_id,
+                        media_type,
+                        time,
+                        user_id: user_id.clone(),
+                        message_key: build_message_key(chat_id, slot),
+                        day: date.format("%Y-%m-%d").to_string(),
+                    },
+                );
+            }
+        },
+    );
+}
+
+pub async fn chat_media(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    chat_id: String,
+    date: Option<String>,
+    out_format: &'static str,
+    query: MediaQuery,
+    viewer: Viewer,
+    raw_query: String,
+    theme: Theme,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let _slot = acquire_listing_slot().map_err(warp::reject::custom)?;
+
+    let anonymize = viewer.anonymize_chat(&chat_id);
+
+    let dbi = lock_db(&db);
+
+    let view = dbi.read_view();
+
+    let limit = query.limit.unwrap_or(DEFAULT_MEDIA_LIMIT).max(1);
+
+    let parse_day = |date: &str| -> Option<i64> {
+        NaiveDate::parse_from_str(date, "%Y-%m-%d")
+            .ok()
+            .map(|date| date.and_hms(0, 0, 0).timestamp() / 86_400)
+    };
+
+    // either a single day, or as many whole days as it takes to fill a page
+    let days =
+        match date {
+            Some(ref date) =>
+                parse_day(date)
+                    .map(|day| vec!(day))
+                    .unwrap_or_default(),
+            None => {
+                let before =
+                    query.before
+                        .as_ref()
+                        .map(|before| parse_day(before))
+                        .flatten();
+
+                find_chat_days(&view, &chat_id)
+                    .into_iter()
+                    .filter(|day| before.map(|before| *day < before).unwrap_or(true))
+                    .collect()
+            }
+        };
+
+    let mut entries = Vec::<MediaEntry>::new();
+    let mut next = None;
+
+    for (i, day) in days.iter().enumerate() {
+        if entries.len() >= limit {
+            next = format_chat_day(days[i - 1]);
+
+            break;
+        }
+
+        collect_day_media(
+            &view,
+            &chat_id,
+            *day,
+            &mut entries,
+        );
+    }
+
+    if out_format == "json" {
+        return Ok(
+            warp::reply::json(
+                &json!({
+                    "status": "ok",
+                    "error": false,
+                    "data": Value::Array(
+                        entries
+                            .iter()
+                            .map(|entry|
+                                if anonymize {
+                                    json!({
+                                        "file_id": entry.file_id,
+                                        "type": entry.media_type,
+                                        "time": entry.time,
+                                        "user": entry.user_id.as_deref().map(pseudonym),
+                                        "message_key": entry.message_key,
+                                    })
+                                } else {
+                                    json!({
+                                        "file_id": entry.file_id,
+                                        "type": entry.media_type,
+                                        "time": entry.time,
+                                        "user_id": entry.user_id,
+                                        "message_key": entry.message_key,
+                                    })
+                                }
+                            )
+                            .collect(),
+                    ),
+                    "next": next,
+                }),
+            ).into_response(),
+        );
+    }
+
+    let chat_name =
+        resolve_chat_name(
+            &view,
+            &chat_id,
+        );
+
+    let mut names =
+        NameCache::new(&view)
+            .with_anonymized(anonymize);
+
+    let mut out =
+        vec!(
+            "<div class=\"gallery\">".to_string(),
+        );
+
+    for entry in entries.iter() {
+        let (_, slot) = some_or_continue!(parse_message_key(&entry.message_key));
+
+        let time = some_or_continue!(NaiveDateTime::from_timestamp_opt(entry.time, 0));
+        let time: DateTime<Utc> = DateTime::from_utc(time, Utc);
+
+        let username =
+            entry.user_id
+                .as_ref()
+                .map(|user_id| names.user(user_id, false))
+                .unwrap_or("Unknown".to_string());
+
+        out.push(
+            format!(
+                "<a class=\"tile {}\" href=\"/chat/{}/{}?cursor={}#{}\" title=\"{} at {}\">\
+                    <img src=\"/file/{}/{}?fallback=1\" loading=\"lazy\"/>\
+                </a>",
+                entry.media_type,
+                &chat_id,
+                &entry.day,
+                slot.time + 1,
+                slot.time,
+                &username,
+                time.format("%Y-%m-%d %H:%M:%S"),
+                match entry.media_type {
+                    "video" => "video_thumb",
+                    _ => "thumb",
+                },
+                &entry.file_id,
+            ),
+        );
+    }
+
+    if entries.is_empty() {
+        out.push("<span class=\"note\">No media in this range.</span>".to_string());
+    }
+
+    out.push("</div>".to_string());
+
+    let base_url =
+        match date {
+            Some(ref date) => format!("/chat/{}/{}/media", &chat_id, date),
+            None => format!("/chat/{}/media", &chat_id),
+        };
+
+    let navigation =
+        HeaderBar::new()
+            .with_link(
+                "<- home",
+                Some("/".into()),
+            )
+            .with_title(format!("{} - media", &chat_name))
+            .with_link(
+                "index",
+                Some(format!("/chat/{}", &chat_id)),
+            )
+            .with_link(
+                "newest",
+                Some(format!("/chat/{}/media", &chat_id)),
+            )
+            .with_link(
+                "older",
+                next
+                    .as_ref()
+                    .map(|next| format!("/chat/{}/media?before={}", &chat_id, next)),
+            )
+            .with_format_link(&base_url, "json");
+
+    Ok(
+        warp::reply::html(
+            Page::new(format!("{} - media", &chat_name))
+                .with_theme(theme)
+                .with_query(&raw_query)
+                .with_header(navigation)
+                .with_body(out.join(""))
+                .render(),
+        ).into_response()
+    )
+}
@@ -0,0 +1,157 @@
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use rocksdb::{DBWithThreadMode, Direction, IteratorMode, MultiThreaded, ReadOptions};
+
+use crate::{ok_or_continue, some_or_continue};
+use crate::components::header::HeaderBar;
+use crate::components::page::{Page, Theme};
+use crate::privacy::Viewer;
+use crate::renderer::chat_listing::pin_snippet;
+use crate::storage::{lock_db, ReadStore, Storage};
+use crate::utils::{message_permalink, NameCache, resolve_chat_name};
+use crate::workers::telegram_handler::{build_message_key, find_message_slot, LogItem, MessageSlot};
+
+/// Returns the slots of all pin events in a chat, oldest first.
+pub fn find_chat_pins(
+    db: &impl ReadStore,
+    chat_id: &str,
+) -> Vec<MessageSlot> {
+    let mut opts = ReadOptions::default();
+
+    let lower_bound = format!("chat_pins:{}:", &chat_id).as_bytes().to_vec();
+    let upper_bound = format!("chat_pins:{}:\x7f", &chat_id).as_bytes().to_vec();
+
+    opts.set_iterate_upper_bound(upper_bound.clone());
+    opts.set_iterate_lower_bound(lower_bound.clone());
+
+    let iter =
+        db.iterator_opt(
+            IteratorMode::From(&lower_bound, Direction::Forward),
+            opts,
+        );
+
+    let mut pins = Vec::<MessageSlot>::new();
+
+    for (key, _) in iter {
+        let key = ok_or_continue!(String::from_utf8(key.to_vec()));
+
+        // `chat_pins:{chat_id}:{slot}`, the slot may have a message id
+        let slot = some_or_continue!(key.splitn(3, ':').nth(2));
+
+        pins.push(
+            some_or_continue!(MessageSlot::parse(slot)),
+        );
+    }
+
+    // keys sort lexicographically, not numerically
+    pins.sort_unstable();
+
+    pins
+}
+
+pub async fn chat_pins(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    chat_id: String,
+    viewer: Viewer,
+    theme: Theme,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let dbi = lock_db(&db);
+
+    let view = dbi.read_view();
+
+    let chat_name =
+        resolve_chat_name(
+            &view,
+            &chat_id,
+        );
+
+    let mut names =
+        NameCache::new(&view)
+            .with_anonymized(viewer.anonymize_chat(&chat_id));
+
+    let mut rows = Vec::<String>::new();
+
+    for slot in find_chat_pins(&view, &chat_id) {
+        let slot = some_or_continue!(find_message_slot(&view, &chat_id, slot));
+        let timestamp = slot.time;
+
+        let item =
+            some_or_continue!(
+                view.get(build_message_key(&chat_id, slot))
+                    .ok()
+                    .flatten(),
+            );
+
+        let (user_id, message, message_id) =
+            match ok_or_continue!(serde_json::from_slice::<LogItem>(&item)) {
+                LogItem::Pin { user_id, message, message_id, .. } =>
+                    (user_id, message, message_id),
+                _ => continue,
+            };
+
+        let time = some_or_continue!(NaiveDateTime::from_timestamp_opt(timestamp, 0));
+        let time: DateTime<Utc> = DateTime::from_utc(time, Utc);
+
+        let username =
+            user_id
+                .as_ref()
+                .map(|user_id| names.user(user_id, false))
+                .unwrap_or("Unknown".to_string());
+
+        rows.push(
+            format!(
+                "<tr class=\"pin\">\
+                    <td class=\"time\"><a href=\"{}\">{}</a></td>\
+                    <td class=\"nick\">{}</td>\
+                    <td class=\"content\"><span class=\"reason\">pinned: {}</span></td>\
+                </tr>",
+                message_permalink(&chat_id, timestamp).unwrap_or_default(),
+                time.format("%Y-%m-%d %H:%M"),
+                &username,
+                pin_snippet(
+                    &view,
+                    &chat_id,
+                    &message_id,
+                    &message,
+                ),
+            ),
+        );
+    }
+
+    let body =
+        if rows.is_empty() {
+            "<div class=\"info\"><span class=\"note\">Nothing has been pinned yet.</span></div>".to_string()
+        } else {
+            format!(
+                "<table class=\"log\"><tbody>{}</tbody></table>",
+                rows.join(""),
+            )
+        };
+
+    Ok(
+        warp::reply::html(
+            Page::new(format!("{} - pins", &chat_name))
+                .with_theme(theme)
+                .with_header(
+                    HeaderBar::new()
+                        .with_link(
+                            "<- home",
+                            Some("/".into()),
+                        )
+                        .with_title(&chat_name)
+                        .with_link(
+                            "index",
+                            Some(format!("/chat/{}", &chat_id)),
+                        )
+                        .with_active("pins")
+                        .with_link(
+                            "latest",
+                            Some(format!("/chat/{}/latest", &chat_id)),
+                        ),
+                )
+                .with_body(body)
+                .render(),
+        ),
+    )
+}
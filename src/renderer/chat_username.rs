@@ -0,0 +1,73 @@
+use std::sync::{Arc, Mutex};
+
+use rocksdb::{DBWithThreadMode, MultiThreaded};
+use warp::filters::path::Tail;
+use warp::http::{Response, StatusCode};
+use warp::hyper::Body;
+use warp::Reply;
+
+use crate::MinutemanError;
+use crate::components::page::Theme;
+use crate::renderer::error::error_page;
+use crate::storage::lock_db;
+use crate::utils::resolve_chat_username;
+
+/// Redirects `/chat/@username/...` to the id-based url so that permalinks
+/// never depend on a username that can change.
+pub async fn chat_by_username(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    chat_ref: String,
+    tail: Tail,
+    query: String,
+    theme: Theme,
+) -> Result<Response<Body>, warp::Rejection> {
+    let username =
+        match chat_ref.strip_prefix('@') {
+            Some(username) if !username.is_empty() => username,
+            _ => return Err(warp::reject::not_found()),
+        };
+
+    // answer unknown names here instead of rejecting, otherwise the id-based
+    // routes would happily render an empty chat called "@whatever"
+    let chat_id = {
+        let dbi = lock_db(&db);
+
+        match resolve_chat_username(&*dbi, username) {
+            Some(chat_id) => chat_id,
+            None =>
+                return Ok(
+                    error_page(
+                        StatusCode::NOT_FOUND,
+                        &format!("no chat known as @{}", username),
+                        theme,
+                    ),
+                ),
+        }
+    };
+
+    let mut target = format!("/chat/{}", chat_id);
+
+    if !tail.as_str().is_empty() {
+        target.push('/');
+        target.push_str(tail.as_str());
+    }
+
+    if !query.is_empty() {
+        target.push('?');
+        target.push_str(&query);
+    }
+
+    Ok(
+        warp::redirect::temporary(
+            target
+                .parse::<warp::http::Uri>()
+                .map_err(|err|
+                    warp::reject::custom(
+                        MinutemanError::ParseError(
+                            format!("{:?}", err),
+                        ),
+                    )
+                )?,
+        ).into_response(),
+    )
+}
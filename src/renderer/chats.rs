@@ -1,92 +1,422 @@
 use std::sync::{Arc, Mutex};
 
+use chrono::Utc;
 use rocksdb::{DBWithThreadMode, Direction, IteratorMode, MultiThreaded, ReadOptions};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use warp::Reply;
 
-use crate::{GLOBAL_CSS, MinutemanError};
+use crate::INACTIVE_CHAT_DAYS;
+use crate::components::avatar::chat_avatar;
 use crate::components::header::HeaderBar;
-use crate::config::get_version;
-use crate::utils::resolve_chat_name;
+use crate::components::page::{Page, Theme};
+use crate::config::{get_admin_token, get_bots, get_version};
+use crate::locales::{Lang, t};
+use crate::privacy::Viewer;
+use crate::renderer::chat_index::chat_days_page;
+use crate::storage::{get_chat_meta, lock_db, ReadStore, Storage};
+use crate::utils::{escape_html, format_chat_day, NameCache};
+use crate::workers::chat_policy::{get_chat_policy, is_private_chat_id, logs_private_chat};
+use crate::workers::telegram_handler::{build_chat_bot_key, ChatMeta, get_chat_last_activity};
+
+const DEFAULT_CHATS_LIMIT: usize = 200;
+
+const MAX_CHATS_LIMIT: usize = 5_000;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChatsQuery {
+    // "activity" (default) or "name", which lists chats in the order of
+    // their ids the way the overview did before it was sorted
+    pub sort: Option<String>,
+    pub limit: Option<usize>,
+    // chats after the one with this id, in the current sort order
+    pub page: Option<String>,
+    // chats before the one with this id, in the current sort order
+    pub before: Option<String>,
+}
+
+impl ChatsQuery {
+    fn sort_by_name(&self) -> bool {
+        self.sort.as_deref() == Some("name")
+    }
+
+    fn chats_limit(&self) -> usize {
+        self.limit
+            .unwrap_or(DEFAULT_CHATS_LIMIT)
+            .clamp(1, MAX_CHATS_LIMIT)
+    }
+}
+
+fn chats_page_url(
+    base_url: &str,
+    cursor_param: &str,
+    cursor: &str,
+    query: &ChatsQuery,
+) -> String {
+    let mut url =
+        format!(
+            "{}?{}={}",
+            base_url,
+            cursor_param,
+            cursor,
+        );
+
+    if query.sort_by_name() {
+        url.push_str("&sort=name");
+    }
+
+    if let Some(limit) = query.limit {
+        url.push_str(&format!("&limit={}", limit));
+    }
+
+    url
+}
+
+struct ChatsEntry {
+    chat_id: String,
+    // day of the last activity
+    last_active: Option<i64>,
+    // when the bot was removed from the chat, see `ChatPolicy`
+    bot_removed_at: Option<i64>,
+}
+
+/// Up to `limit` of the chats the overview lists after (or before, going
+/// `Direction::Reverse`) `cursor`, nearest first, as `(cursor, chat_id)`.
+/// Cursors are the keys under `prefix` without it, the chat id is their
+/// last part.
+fn seek_chats(
+    db: &impl ReadStore,
+    prefix: &str,
+    cursor: Option<&str>,
+    direction: Direction,
+    limit: usize,
+    list_all: bool,
+) -> Vec<(String, String)> {
+    let mut opts = ReadOptions::default();
+
+    let lower_bound = prefix.as_bytes().to_vec();
+    let upper_bound = [prefix.as_bytes(), b"\xff"].concat();
+
+    opts.set_iterate_lower_bound(lower_bound.clone());
+    opts.set_iterate_upper_bound(upper_bound.clone());
+
+    let start =
+        match (cursor, &direction) {
+            (Some(cursor), _) => format!("{}{}", prefix, cursor).into_bytes(),
+            (None, Direction::Forward) => lower_bound,
+            (None, Direction::Reverse) => upper_bound,
+        };
+
+    db.iterator_opt(IteratorMode::From(&start, direction), opts)
+        // the chat the cursor names was on the previous page
+        .filter(|(key, _)| **key != *start)
+        .filter_map(|(key, _)| String::from_utf8(key[prefix.len()..].to_vec()).ok())
+        .filter_map(|cursor| {
+            let chat_id = cursor.rsplit(':').next()?.to_string();
+
+            if !list_all && is_private_chat_id(&chat_id) {
+                return None;
+            }
+
+            // left over from before private chats had to be opted into
+            if is_private_chat_id(&chat_id) && !logs_private_chat(&chat_id) {
+                return None;
+            }
+
+            Some((cursor, chat_id))
+        })
+        .take(limit)
+        .collect()
+}
 
 pub async fn chats(
     db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
     listing_type: &'static str,
+    out_format: &'static str,
+    query: ChatsQuery,
+    viewer: Viewer,
+    lang: Lang,
+    raw_query: String,
+    theme: Theme,
 ) -> Result<impl warp::Reply, warp::Rejection> {
     let list_all = listing_type == "all";
 
-    let dbi =
-        db.lock()
-            .map_err(|err|
-                warp::reject::custom(
-                    MinutemanError::LockError(
-                        format!("{:?}", err),
-                    ),
-                )
-            )?;
+    let base_url = if list_all { "/all" } else { "/" };
 
-    let mut out =
-        vec!(
-            "<!DOCTYPE html><html lang=\"en\">".to_string(),
-            "<style type=\"text/css\">".to_string(),
-            GLOBAL_CSS.to_string(),
-            "</style>".to_string(),
-            "<head><title>channel index</title></head><body>".to_string(),
-            HeaderBar::new()
-                .with_title(
-                    format!("minuteman {}", get_version()),
-                )
-                .to_string(),
-            "<div class=\"channels\"><ul>".to_string(),
-        );
+    let dbi = lock_db(&db);
 
-    let mut opts = ReadOptions::default();
+    let view = dbi.read_view();
 
-    let lower_bound = b"chat_rel:".to_vec();
+    let mut names = NameCache::new(&view);
 
-    opts.set_iterate_upper_bound(b"chat_rel:\xff".to_vec());
+    // which bot logs a chat is only worth showing when there's a choice
+    let show_bots = get_bots().map(|bots| bots.len() > 1).unwrap_or(false);
 
-    let mut iter =
-        dbi.iterator_opt(
-            IteratorMode::From(&lower_bound, Direction::Forward),
-            opts,
-        );
+    let today = Utc::now().timestamp() / 86_400;
 
-    for (key, _) in iter {
-        let key = key.to_vec();
-        let key = String::from_utf8(key).unwrap();
-        let key = key.split(":").collect::<Vec<&str>>();
+    // chats the bot was removed from are archived, however recent
+    let is_active =
+        |entry: &ChatsEntry|
+            entry.bot_removed_at.is_none()
+                && entry.last_active
+                    .map(|day| today - day < INACTIVE_CHAT_DAYS)
+                    .unwrap_or(false);
 
-        if key.len() != 2 {
-            continue;
-        }
+    // pages continue from the key of the chat the cursor names rather than
+    // from an offset, so only the chats on the page are read
+    let prefix =
+        match query.sort_by_name() {
+            true => "chat_rel:",
+            false => "chat_activity:",
+        };
 
-        let key = key[key.len() - 1];
+    let limit = query.chats_limit();
 
-        if !list_all && !key.starts_with(&['-']) {
-            continue;
-        }
+    let has_more =
+        |cursor: &str, direction: Direction|
+            !seek_chats(&view, prefix, Some(cursor), direction, 1, list_all).is_empty();
+
+    let (page, newer_cursor, older_cursor) =
+        match (&query.page, &query.before) {
+            (None, Some(before)) => {
+                let mut page = seek_chats(&view, prefix, Some(before), Direction::Reverse, limit + 1, list_all);
+
+                let more = page.len() > limit;
+
+                page.truncate(limit);
+                page.reverse();
+
+                let newer = page.first().map(|(cursor, _)| cursor.clone()).filter(|_| more);
+
+                let older =
+                    page.last()
+                        .map(|(cursor, _)| cursor.clone())
+                        .or_else(|| Some(before.clone()))
+                        .filter(|cursor| has_more(cursor, Direction::Forward));
+
+                (page, newer, older)
+            }
+            (page_cursor, _) => {
+                let mut page = seek_chats(&view, prefix, page_cursor.as_deref(), Direction::Forward, limit + 1, list_all);
+
+                let more = page.len() > limit;
+
+                page.truncate(limit);
+
+                let newer =
+                    page.first()
+                        .map(|(cursor, _)| cursor.clone())
+                        .or_else(|| page_cursor.clone())
+                        .filter(|_| page_cursor.is_some())
+                        .filter(|cursor| has_more(cursor, Direction::Reverse));
+
+                let older = page.last().map(|(cursor, _)| cursor.clone()).filter(|_| more);
+
+                (page, newer, older)
+            }
+        };
+
+    let page =
+        page
+            .into_iter()
+            .map(|(_, chat_id)|
+                ChatsEntry {
+                    // chats logged before the activity key existed need the index
+                    last_active:
+                        get_chat_last_activity(&view, &chat_id)
+                            .map(|time| time / 86_400)
+                            .or_else(|| chat_days_page(&view, &chat_id, &None, 1).0.first().copied()),
+                    bot_removed_at: get_chat_policy(&chat_id).bot_removed_at,
+                    chat_id,
+                }
+            )
+            .collect::<Vec<ChatsEntry>>();
+
+    if out_format == "json" {
+        let data =
+            page
+                .iter()
+                .map(|entry|
+                    json!({
+                        "id": entry.chat_id,
+                        "name": names.chat_name(&entry.chat_id),
+                        "last_active": entry.last_active.map(format_chat_day).flatten(),
+                        "bot_removed": entry.bot_removed_at.map(|time| format_chat_day(time / 86_400)).flatten(),
+                        "bot": view.get(build_chat_bot_key(&entry.chat_id))
+                            .ok()
+                            .flatten()
+                            .map(|name| String::from_utf8(name).ok())
+                            .flatten(),
+                    })
+                )
+                .collect::<Vec<Value>>();
+
+        return Ok(
+            warp::reply::json(
+                &json!({
+                    "status": "ok",
+                    "error": false,
+                    "data": Value::Array(data),
+                    "next": older_cursor,
+                    "prev": newer_cursor,
+                }),
+            ).into_response(),
+        );
+    }
+
+    let mut active = Vec::<String>::new();
+    let mut inactive = Vec::<String>::new();
+
+    for entry in page.iter() {
+        let key = entry.chat_id.as_str();
 
         let chat_name =
-            resolve_chat_name(
-                &dbi,
+            names.chat_name(
                 &key,
             );
 
-        out.push(
+        let bot_name =
+            view.get(build_chat_bot_key(key))
+                .ok()
+                .flatten()
+                .map(|name| String::from_utf8(name).ok())
+                .flatten()
+                .filter(|_| show_bots)
+                .map(|name| format!(" <span class=\"note\">via {}</span>", escape_html(&name)))
+                .unwrap_or_default();
+
+        let chat_type =
+            match get_chat_meta(&view, key) {
+                Some(ChatMeta::User(_)) => "private",
+                Some(ChatMeta::Group(_)) => "group",
+                Some(ChatMeta::SuperGroup(_)) => "supergroup",
+                Some(ChatMeta::Channel(_)) => "channel",
+                _ => "unknown",
+            };
+
+        let item =
             format!(
-                "<li><a href=\"/chat/{}/latest\">{}</a> (<a href=\"/chat/{}\">index</a> | <a href=\"/chat/{}/latest\">latest</a>)</li>",
+                "<li>{} <a href=\"/chat/{}/latest\">{}</a> (<a href=\"/chat/{}\">{}</a> | <a href=\"/chat/{}/latest\">{}</a>) <span class=\"note\">{}, {}</span>{}</li>",
+                chat_avatar(key),
                 &key,
-                &chat_name,
+                escape_html(&chat_name),
                 &key,
+                t(lang, "nav.index"),
                 &key,
+                t(lang, "nav.latest"),
+                chat_type,
+                match entry.bot_removed_at.map(|time| format_chat_day(time / 86_400)).flatten() {
+                    Some(day) => format!("archived (bot removed on {})", day),
+                    None =>
+                        entry.last_active
+                            .map(format_chat_day)
+                            .flatten()
+                            .map(|day| format!("last active {}", day))
+                            .unwrap_or("no activity".to_string()),
+                },
+                bot_name,
+            );
+
+        if is_active(entry) {
+            active.push(item);
+        } else {
+            inactive.push(item);
+        }
+    }
+
+    let mut out =
+        vec!(
+            format!(
+                "<div class=\"channels\"><p class=\"note\">sort by {}</p><ul>",
+                if query.sort_by_name() {
+                    "<a href=\"?sort=activity\">activity</a> | <b>id</b>"
+                } else {
+                    "<b>activity</b> | <a href=\"?sort=name\">id</a>"
+                },
             ),
         );
+
+    out.extend(active);
+
+    out.push("</ul>".to_string());
+
+    if !inactive.is_empty() {
+        out.push(
+            format!(
+                "<details class=\"inactive\"><summary>{} chat(s) without activity in {} days or archived</summary><ul>",
+                inactive.len(),
+                INACTIVE_CHAT_DAYS,
+            ),
+        );
+
+        out.extend(inactive);
+
+        out.push("</ul></details>".to_string());
     }
 
-    out.push("</ul></div></body></html>".to_string());
+    out.push("</div>".to_string());
+
+    let header =
+        if list_all {
+            HeaderBar::new()
+                .with_title(
+                    format!("minuteman {}", get_version()),
+                )
+                .with_link(
+                    t(lang, "nav.groups"),
+                    Some("/".into()),
+                )
+                .with_active(t(lang, "nav.all"))
+        } else {
+            HeaderBar::new()
+                .with_title(
+                    format!("minuteman {}", get_version()),
+                )
+                .with_active(t(lang, "nav.groups"))
+                .with_link(
+                    t(lang, "nav.all"),
+                    Some("/all".into()),
+                )
+        };
+
+    let header =
+        match viewer.admin {
+            true => header.with_link(t(lang, "nav.storage"), Some("/admin/storage".into())),
+            false => header,
+        };
+
+    let header =
+        match get_admin_token().is_none() || viewer.admin {
+            true => header.with_link(t(lang, "nav.search"), Some("/search".into())),
+            false => header,
+        };
+
+    let header =
+        header
+            .with_link(
+                t(lang, "nav.newer"),
+                newer_cursor
+                    .map(|cursor| chats_page_url(base_url, "before", &cursor, &query)),
+            )
+            .with_link(
+                t(lang, "nav.older"),
+                older_cursor
+                    .map(|cursor| chats_page_url(base_url, "page", &cursor, &query)),
+            )
+            .with_format_link(
+                if list_all { "/all" } else { "/chats" },
+                "json",
+            );
 
     Ok(
         warp::reply::html(
-            out.join(""),
-        ),
+            Page::new("chats")
+                .with_theme(theme)
+                .with_lang(lang)
+                .with_query(&raw_query)
+                .with_header(header)
+                .with_body(out.join(""))
+                .render(),
+        ).into_response()
     )
 }
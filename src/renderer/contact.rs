@@ -0,0 +1,83 @@
+use std::sync::{Arc, Mutex};
+
+use rocksdb::{DBWithThreadMode, MultiThreaded};
+use warp::http::StatusCode;
+use warp::Reply;
+
+use crate::components::contact::contact_vcard;
+use crate::components::page::Theme;
+use crate::privacy::Viewer;
+use crate::renderer::error::error_page;
+use crate::storage::{lock_db, ReadStore, Storage};
+use crate::utils::resolve_message_ref;
+use crate::workers::telegram_handler::{build_message_key, LogItem};
+
+/// `/chat/{chat_id}/contact/{message_id}.vcf`, the shared contact as a
+/// vCard to import into an address book.
+pub async fn contact_vcard_file(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    chat_id: String,
+    file_name: String,
+    viewer: Viewer,
+    theme: Theme,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let message_id =
+        match file_name.strip_suffix(".vcf").filter(|id| id.parse::<i64>().is_ok()) {
+            Some(message_id) => message_id.to_string(),
+            None => return Err(warp::reject::not_found()),
+        };
+
+    // anonymized chats don't hand out contact details
+    if viewer.anonymize_chat(&chat_id) {
+        return Ok(
+            error_page(
+                StatusCode::FORBIDDEN,
+                "contacts of this chat aren't shared",
+                theme,
+            ),
+        );
+    }
+
+    let dbi = lock_db(&db);
+
+    let view = dbi.read_view();
+
+    let vcard =
+        resolve_message_ref(&view, &chat_id, &message_id)
+            .map(|slot|
+                view.get(build_message_key(&chat_id, slot))
+                    .ok()
+                    .flatten()
+            )
+            .flatten()
+            .map(|val| serde_json::from_slice::<LogItem>(&val).ok())
+            .flatten()
+            .map(|log_item|
+                match log_item {
+                    LogItem::Special { ref special_type, .. } => contact_vcard(special_type),
+                    _ => None,
+                }
+            )
+            .flatten();
+
+    Ok(
+        match vcard {
+            Some(vcard) =>
+                warp::reply::with_header(
+                    warp::reply::with_header(
+                        vcard,
+                        "content-type",
+                        "text/vcard; charset=utf-8",
+                    ),
+                    "content-disposition",
+                    format!("attachment; filename=\"contact-{}.vcf\"", message_id),
+                ).into_response(),
+            None =>
+                error_page(
+                    StatusCode::NOT_FOUND,
+                    "no contact was shared with this message",
+                    theme,
+                ),
+        },
+    )
+}
@@ -0,0 +1,59 @@
+use serde_json::json;
+use warp::http::{Response, StatusCode};
+use warp::hyper::Body;
+use warp::Reply;
+
+use crate::components::header::HeaderBar;
+use crate::components::page::{Page, Theme};
+use crate::utils::escape_html;
+
+pub fn error_page(
+    status: StatusCode,
+    message: &str,
+    theme: Theme,
+) -> Response<Body> {
+    let reason =
+        status
+            .canonical_reason()
+            .unwrap_or("error");
+
+    warp::reply::with_status(
+        warp::reply::html(
+            Page::new(format!("{} {}", status.as_u16(), reason))
+                .with_theme(theme)
+                .with_header(
+                    HeaderBar::new()
+                        .with_link(
+                            "<- home",
+                            Some("/".into()),
+                        )
+                        .with_title(format!("{} {}", status.as_u16(), reason)),
+                )
+                .with_body(
+                    format!(
+                        "<div class=\"error\"><p>{}</p></div>",
+                        escape_html(message),
+                    ),
+                )
+                .render(),
+        ),
+        status,
+    ).into_response()
+}
+
+/// `error_page` for json endpoints, the usual envelope without data.
+pub fn error_json(
+    status: StatusCode,
+    message: &str,
+) -> Response<Body> {
+    warp::reply::with_status(
+        warp::reply::json(
+            &json!({
+                "status": message,
+                "error": true,
+                "data": null,
+            }),
+        ),
+        status,
+    ).into_response()
+}
@@ -1,14 +1,20 @@
 use std::num::NonZeroU16;
 use std::sync::{Arc, Mutex};
 
-use image::ImageFormat;
 use rocksdb::{DBWithThreadMode, MultiThreaded};
-use warp::http::{header, HeaderValue};
+use serde::Deserialize;
+use warp::http::{header, HeaderValue, Method};
 use warp::http::Response;
 use warp::hyper::Body;
 use warp::Reply;
 
-use crate::MinutemanError;
+use crate::config::BlobBackend;
+use crate::blob_store::{blob_exists, blob_location, resolve_blob_async, stream_blob};
+use crate::privacy::Viewer;
+use crate::storage::{lock_db, ReadStore, Storage};
+use crate::thumbnail::{generate_thumbnail, THUMBNAIL_MIME_TYPE};
+use crate::utils::{escape_html, get_file_corruption, get_file_failure, get_file_meta, guess_mime_type, resolve_chat_name};
+use crate::workers::telegram_handler::{build_file_key, FileEntryType, FileMeta, store_file_meta};
 
 #[derive(Debug, Eq, PartialEq)]
 pub enum FileRequestType {
@@ -17,6 +23,8 @@ pub enum FileRequestType {
     Document,
     Video,
     VideoThumb,
+    ChatPhoto,
+    Thumb,
     Unknown,
 }
 
@@ -28,109 +36,428 @@ impl From<String> for FileRequestType {
             "document" => FileRequestType::Document,
             "video" => FileRequestType::Video,
             "video_thumb" => FileRequestType::VideoThumb,
+            "chat_photo" => FileRequestType::ChatPhoto,
+            "thumb" => FileRequestType::Thumb,
             _ => FileRequestType::Unknown,
         }
     }
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct FileQuery {
+    // serve a placeholder image instead of a 404 or a corrupt blob
+    pub fallback: Option<u8>,
+}
+
+fn placeholder_image(
+    reason: Option<&str>,
+) -> Response<Body> {
+    let reason =
+        reason
+            .map(|reason|
+                format!(
+                    "<text x=\"50%\" y=\"62%\" font-size=\"10\" text-anchor=\"middle\" fill=\"#7a7a7a\">{}</text>",
+                    escape_html(reason),
+                )
+            )
+            .unwrap_or_default();
+
+    let svg =
+        format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"300\" height=\"150\" viewBox=\"0 0 300 150\">\
+                <rect width=\"300\" height=\"150\" fill=\"#eeeeee\"/>\
+                <text x=\"50%\" y=\"48%\" font-size=\"14\" text-anchor=\"middle\" fill=\"#444444\">media unavailable</text>\
+                {}\
+            </svg>",
+            reason,
+        );
+
+    // short lifetime so that a later backfill of the file shows up
+    Response::builder()
+        .header(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("image/svg+xml"),
+        )
+        .header(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("public, max-age=300"),
+        )
+        .body(Body::from(svg))
+        .unwrap()
+}
+
+// answered rather than rejected: a rejection would lose to the other routes'
+// method rejections and turn HEAD on a missing file into a 405
+fn file_not_found() -> Response<Body> {
+    Response::builder()
+        .status(warp::http::status::StatusCode::NOT_FOUND)
+        .body(Body::from("File not found"))
+        .unwrap()
+}
+
+/// Stand-in for a chat without a stored photo: the first letter of its name
+/// on a color derived from the chat id.
+fn initial_placeholder(
+    chat_id: &str,
+    chat_name: &str,
+) -> Response<Body> {
+    let initial =
+        chat_name
+            .chars()
+            .find(|c| c.is_alphanumeric())
+            .map(|c| c.to_uppercase().to_string())
+            .unwrap_or("?".to_string());
+
+    let hue =
+        chat_id
+            .bytes()
+            .fold(0u32, |hue, byte| (hue * 31 + byte as u32) % 360);
+
+    let svg =
+        format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"64\" height=\"64\" viewBox=\"0 0 64 64\">\
+                <rect width=\"64\" height=\"64\" fill=\"hsl({}, 45%, 55%)\"/>\
+                <text x=\"50%\" y=\"50%\" dy=\".35em\" font-size=\"32\" font-family=\"sans-serif\" text-anchor=\"middle\" fill=\"#ffffff\">{}</text>\
+            </svg>",
+            hue,
+            escape_html(&initial),
+        );
+
+    // the chat may get a photo any time
+    Response::builder()
+        .header(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("image/svg+xml"),
+        )
+        .header(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("public, max-age=300"),
+        )
+        .body(Body::from(svg))
+        .unwrap()
+}
+
+// `resolve_blob_async` for the database value of `key`, if any
+async fn resolve_unlocked(
+    key: String,
+    value: Option<Vec<u8>>,
+) -> Option<Vec<u8>> {
+    resolve_blob_async(key, value?).await.ok()
+}
+
+/// Headers of a stored file, the same for GET and HEAD. Ranges aren't
+/// supported, the etag is the blob's hash when it's known.
+fn file_response(
+    content_type: &str,
+    size: u64,
+    sha256: Option<&str>,
+) -> warp::http::response::Builder {
+    let builder =
+        Response::builder()
+            .header(
+                header::CONTENT_TYPE,
+                HeaderValue::from_str(content_type)
+                    .unwrap_or(
+                        HeaderValue::from_static("application/octet-stream"),
+                    ),
+            )
+            .header(
+                header::CONTENT_LENGTH,
+                size,
+            )
+            .header(
+                header::ACCEPT_RANGES,
+                HeaderValue::from_static("none"),
+            );
+
+    match sha256 {
+        Some(sha256) => builder.header(header::ETAG, format!("\"{}\"", sha256)),
+        None => builder,
+    }
+}
+
 pub async fn get_file(
+    method: Method,
     db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
     file_request_type: String,
     file_id: String,
+    query: FileQuery,
+    viewer: Viewer,
 ) -> Result<impl warp::Reply, warp::Rejection> {
+    let fallback = query.fallback.unwrap_or(0) != 0;
+
+    let file_entry_type = FileEntryType::from_url_kind(&file_request_type);
+
     let file_request_type: FileRequestType = file_request_type.into();
 
-    if file_request_type == FileRequestType::Unknown {
+    // avatars would give away who's behind a pseudonym
+    if file_request_type == FileRequestType::User && !viewer.can_see_users() {
         return Ok(
-            Response::builder()
-                .status(warp::http::status::StatusCode::NOT_FOUND)
-                .body(Body::from("Unknown file request type"))
-                .unwrap(),
+            if fallback {
+                placeholder_image(None)
+            } else {
+                file_not_found()
+            },
         );
     }
 
-    let dbi =
-        db.lock()
-            .map_err(|err|
-                         warp::reject::custom(
-                             MinutemanError::LockError(
-                                 format!("{:?}", err),
-                             ),
-                         ),
-            )?;
-
-    let file_key =
-        format!(
-            "file:{}:{}",
+    let file_entry_type =
+        match file_entry_type {
+            Some(file_entry_type) => file_entry_type,
+            None =>
+                return Ok(
+                    Response::builder()
+                        .status(warp::http::status::StatusCode::NOT_FOUND)
+                        .body(Body::from("Unknown file request type"))
+                        .unwrap(),
+                ),
+        };
+
+    let is_image =
+        match file_request_type {
+            FileRequestType::User |
+            FileRequestType::Image |
+            FileRequestType::VideoThumb |
+            FileRequestType::ChatPhoto |
+            FileRequestType::Thumb => true,
+            _ => false,
+        };
+
+    let file_key = build_file_key(file_entry_type, &file_id);
+
+    // what the answer needs from the database is read up front, the blob is
+    // fetched and sent after letting go of the lock
+    let (file_meta, corruption, failure, chat_name, stored, original) = {
+        let dbi = lock_db(&db);
+
+        let view = dbi.read_view();
+
+        let stored =
+            view.get(&file_key)
+                .ok()
+                .flatten();
+
+        // files stored before thumbnails were generated at ingest get theirs
+        // made on the fly, from the original
+        let original =
+            match (&stored, &file_request_type) {
+                (None, FileRequestType::Thumb) =>
+                    view.get(build_file_key(FileEntryType::Chat, &file_id))
+                        .ok()
+                        .flatten(),
+                _ => None,
+            };
+
+        // avatars are rendered for every chat, photo or not
+        let chat_name =
             match file_request_type {
-                FileRequestType::User => "user",
-                FileRequestType::VideoThumb => "video_thumb",
-                _ => "chat",
-            },
-            file_id,
-        );
+                FileRequestType::ChatPhoto => Some(resolve_chat_name(&view, &file_id)),
+                _ => None,
+            };
+
+        (
+            get_file_meta(&view, &file_id),
+            get_file_corruption(&view, &file_id),
+            get_file_failure(&view, &file_id),
+            chat_name,
+            stored,
+            original,
+        )
+    };
 
-    let file_key = file_key.as_bytes();
+    // the meta record has everything a HEAD request needs, the blob is only
+    // looked for, not read. Thumbnails share their original's record,
+    // corrupt files get a placeholder, files without a record are sniffed
+    // and missing blobs are a 404 or a placeholder, those take the long way
+    // and lose the body in the server's `strip_head_body`
+    if method == Method::HEAD && file_request_type != FileRequestType::Thumb {
+        let meta =
+            file_meta
+                .as_ref()
+                .filter(|_| corruption.is_none());
 
-    let file =
-        dbi
-            .get(file_key)
-            .ok()
-            .flatten();
+        if let (Some(meta), Some(value)) = (meta, &stored) {
+            let exists =
+                match blob_exists(file_key.clone(), value).await {
+                    Ok(exists) => exists,
+                    Err(err) => {
+                        tracing::warn!(file_id = %file_id, error = %err, "can't check blob");
+
+                        false
+                    }
+                };
+
+            if let (true, Some(mime_type)) = (exists, &meta.mime_type) {
+                return Ok(
+                    file_response(mime_type, meta.size, meta.sha256.as_deref())
+                        .body(Body::empty())
+                        .unwrap(),
+                );
+            }
+        }
+    }
+
+    // blobs kept outside of the database (videos and the like) go out a
+    // chunk at a time as they're read from their store, as long as the meta
+    // record has their type and size. A blob that can't be read is missing
+    let streamed =
+        match (&stored, &file_meta, &corruption) {
+            (Some(value), Some(meta), None) if file_request_type != FileRequestType::Thumb => {
+                let content_type =
+                    meta.mime_type
+                        .clone()
+                        .or(Some("application/octet-stream".to_string()).filter(|_| !is_image));
+
+                match (blob_location(value), content_type) {
+                    (Ok(backend), Some(content_type)) if backend != BlobBackend::RocksDb => Some((backend, content_type, meta)),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+
+    let stored =
+        match streamed {
+            Some((backend, content_type, meta)) =>
+                match stream_blob(backend, file_key.clone()).await {
+                    Ok(Some(blob)) =>
+                        return Ok(
+                            file_response(&content_type, meta.size, meta.sha256.as_deref())
+                                .body(Body::wrap_stream(blob))
+                                .unwrap(),
+                        ),
+                    Ok(None) => None,
+                    Err(err) => {
+                        tracing::warn!(file_id = %file_id, error = %err, "can't stream blob");
+
+                        None
+                    }
+                },
+            None => stored,
+        };
+
+    // a blob that doesn't decrypt is as good as missing
+    let file = resolve_unlocked(file_key, stored).await;
+
+    let (file, thumbnail) =
+        match (file, &file_request_type) {
+            (None, FileRequestType::Thumb) => {
+                let original = resolve_unlocked(build_file_key(FileEntryType::Chat, &file_id), original).await;
+
+                match original.as_deref().map(generate_thumbnail).flatten() {
+                    Some((thumbnail, _, _)) => (Some(thumbnail), true),
+                    None => (original, false),
+                }
+            }
+            (file, FileRequestType::Thumb) => {
+                let stored = file.is_some();
+
+                (file, stored)
+            }
+            (file, _) => (file, false),
+        };
 
     if file.is_none() {
-        return Err(
-            warp::reject::not_found(),
+        if let Some(chat_name) = chat_name {
+            return Ok(
+                initial_placeholder(
+                    &file_id,
+                    &chat_name,
+                ),
+            );
+        }
+
+        if fallback {
+            return Ok(
+                placeholder_image(
+                    failure
+                        .as_ref()
+                        .map(|failure| failure.reason.as_str()),
+                ),
+            );
+        }
+
+        return Ok(file_not_found());
+    }
+
+    // flagged by the file verifier, whatever is stored isn't worth serving
+    if let Some(corruption) = corruption {
+        return Ok(
+            placeholder_image(
+                Some(&format!("stored file is corrupt: {}", corruption.reason)),
+            ),
         );
     }
 
+    // files with a meta record are looked after by the file verifier, only
+    // legacy ones are decoded here before going out as an image
+    if fallback && is_image && file_meta.is_none() && !thumbnail {
+        if let Some(ref file) = file {
+            if image::load_from_memory(file).is_err() {
+                return Ok(
+                    placeholder_image(
+                        Some("stored file is corrupt"),
+                    ),
+                );
+            }
+        }
+    }
+
     match file {
-        None =>
-            Err(
-                warp::reject::not_found(),
-            ),
-        Some(file) =>
-            Ok(
-                Response::builder()
-                    .header(
-                        header::CONTENT_TYPE,
-                        HeaderValue::from_static(
-                            match file_request_type {
-                                FileRequestType::User |
-                                FileRequestType::Image |
-                                FileRequestType::VideoThumb => {
-                                    image::guess_format(
-                                        file.as_slice(),
-                                    )
-                                        .ok()
-                                        .map(|format|
-                                            match format {
-                                                ImageFormat::Avif => Some("image/avif"),
-                                                ImageFormat::Jpeg => Some("image/jpeg"),
-                                                ImageFormat::Png => Some("image/png"),
-                                                ImageFormat::Gif => Some("image/gif"),
-                                                ImageFormat::WebP => Some("image/webp"),
-                                                ImageFormat::Tiff => Some("image/tiff"),
-                                                ImageFormat::Tga => Some("image/x-tga"),
-                                                ImageFormat::Dds => Some("image/vnd-ms.dds"),
-                                                ImageFormat::Bmp => Some("image/bmp"),
-                                                ImageFormat::Ico => Some("image/x-icon"),
-                                                ImageFormat::Hdr => Some("image/vnd.radiance"),
-                                                ImageFormat::OpenExr => Some("image/x-exr"),
-                                                _ => None,
-                                            }
-                                        )
-                                        .flatten()
-                                        .unwrap_or("application/octet-stream")
-                                }
-                                FileRequestType::Document => "application/octet-stream",
-                                FileRequestType::Video => "application/octet-stream",
-                                FileRequestType::Unknown => "application/octet-stream",
-                            }
-                        ),
+        None => Ok(file_not_found()),
+        Some(file) => {
+            // the meta record is the original's, thumbnails are always JPEGs.
+            // legacy entries don't have a metadata record, sniff those
+            let content_type =
+                Some(THUMBNAIL_MIME_TYPE.to_string())
+                    .filter(|_| thumbnail)
+                    .or(
+                        file_meta
+                            .as_ref()
+                            .map(|meta| meta.mime_type.clone())
+                            .flatten()
                     )
+                    .or_else(||
+                        if is_image {
+                            guess_mime_type(
+                                file.as_slice(),
+                            )
+                                .map(|mime| mime.to_string())
+                        } else {
+                            None
+                        }
+                    )
+                    .unwrap_or("application/octet-stream".to_string());
+
+            // the sniff is kept so that it happens once per file, later
+            // requests (and HEAD ones) go by the record. Thumbnails aren't
+            // the blob the record would describe
+            if file_meta.is_none() && file_request_type != FileRequestType::Thumb {
+                let mut meta = FileMeta::from_bytes(&file);
+
+                // documents are served as octet-streams whatever they look like
+                if !is_image {
+                    meta.mime_type = None;
+                }
+
+                if let Err(err) = store_file_meta(&lock_db(&db), &file_id, &meta) {
+                    tracing::warn!(file_id = %file_id, error = %err, "can't store file meta");
+                }
+            }
+
+            // a generated thumbnail isn't the blob the meta record hashed
+            let sha256 =
+                file_meta
+                    .as_ref()
+                    .filter(|_| file_request_type != FileRequestType::Thumb)
+                    .map(|meta| meta.sha256.as_deref())
+                    .flatten();
+
+            Ok(
+                file_response(&content_type, file.len() as u64, sha256)
                     .body(Body::from(file))
                     .unwrap(),
-            ),
+            )
+        }
     }
 }
@@ -0,0 +1,59 @@
+use serde_json::{json, Map, Value};
+
+use crate::CATCHUP_LAG_THRESHOLD;
+use crate::config::get_version;
+use crate::metrics::{api_health, backup_metrics, telegram_metrics, worker_restarts};
+
+pub async fn health() -> Result<impl warp::Reply, warp::Rejection> {
+    let telegram = telegram_metrics();
+    let backup = backup_metrics();
+    let api = api_health();
+
+    let workers =
+        worker_restarts()
+            .into_iter()
+            .map(|(worker, restarts)|
+                (
+                    worker,
+                    json!({
+                        "restarts": restarts.restarts,
+                        "consecutive_failures": restarts.consecutive_failures,
+                        "backoff_ms": restarts.backoff_ms,
+                        "last_restart_at": restarts.last_restart_at,
+                    }),
+                )
+            )
+            .collect::<Map<String, Value>>();
+
+    Ok(
+        warp::reply::json(
+            &json!({
+                "status": "ok",
+                "error": false,
+                "data": {
+                    "version": get_version(),
+                    "telegram": {
+                        "updates": telegram.updates,
+                        "lag_seconds": telegram.lag_seconds,
+                        "catching_up": telegram.lag_seconds > CATCHUP_LAG_THRESHOLD,
+                        "last_update_at": telegram.last_update_at,
+                        "deferred_jobs": telegram.deferred_jobs,
+                    },
+                    // outbound calls within API_HEALTH_WINDOW
+                    "telegram_api": {
+                        "calls": api.calls,
+                        "errors": api.errors,
+                        "p95_ms": api.p95_ms,
+                        "degraded": api.degraded,
+                    },
+                    "backup": {
+                        "last_success_at": backup.last_success_at,
+                        "failures": backup.failures,
+                    },
+                    // only workers that had to be restarted show up
+                    "workers": workers,
+                },
+            }),
+        ),
+    )
+}
@@ -0,0 +1,237 @@
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use rocksdb::{DBWithThreadMode, MultiThreaded};
+use serde::Deserialize;
+use serde_json::json;
+use warp::http::StatusCode;
+use warp::Reply;
+
+use crate::components::header::HeaderBar;
+use crate::components::page::{Page, Theme};
+use crate::config::get_log_inline_queries;
+use crate::privacy::Viewer;
+use crate::renderer::error::error_page;
+use crate::storage::{lock_db, Storage};
+use crate::utils::{escape_html, format_chat_day};
+use crate::workers::inline_queries::{find_inline_items, inline_query_stats};
+
+const DEFAULT_INLINE_DAYS: i64 = 30;
+
+const MAX_INLINE_DAYS: i64 = 365;
+
+// queries in the top list
+const INLINE_TOP_QUERIES: usize = 50;
+
+// most recent queries listed one by one
+const INLINE_RECENT: usize = 50;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct InlineQueriesQuery {
+    // how far back the page goes
+    pub days: Option<i64>,
+}
+
+impl InlineQueriesQuery {
+    pub fn days(&self) -> i64 {
+        self.days
+            .unwrap_or(DEFAULT_INLINE_DAYS)
+            .max(1)
+            .min(MAX_INLINE_DAYS)
+    }
+}
+
+fn format_time(
+    time: i64,
+) -> String {
+    NaiveDateTime::from_timestamp_opt(time, 0)
+        .map(|time| DateTime::<Utc>::from_utc(time, Utc))
+        .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_default()
+}
+
+/// What people type into the bots' inline mode and which results they
+/// pick, see `get_log_inline_queries`.
+pub async fn inline_queries(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    out_format: &'static str,
+    query: InlineQueriesQuery,
+    viewer: Viewer,
+    theme: Theme,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if !viewer.admin {
+        return Ok(
+            error_page(
+                StatusCode::FORBIDDEN,
+                "admin token required",
+                theme,
+            ),
+        );
+    }
+
+    let dbi = lock_db(&db);
+
+    let view = dbi.read_view();
+
+    let days = query.days();
+
+    let items = find_inline_items(&view, Utc::now().timestamp() - days * 86_400);
+
+    drop(view);
+    drop(dbi);
+
+    let stats = inline_query_stats(&items, INLINE_TOP_QUERIES);
+
+    let recent = &items[..items.len().min(INLINE_RECENT)];
+
+    if out_format == "json" {
+        return Ok(
+            warp::reply::json(
+                &json!({
+                    "status": "ok",
+                    "error": false,
+                    "data": {
+                        "logging": get_log_inline_queries(),
+                        "days": days,
+                        "queries": stats.queries,
+                        "chosen": stats.chosen,
+                        "users": stats.users,
+                        "top_queries": stats.top_queries
+                            .iter()
+                            .map(|(query, typed, chosen)|
+                                json!({
+                                    "query": query,
+                                    "queries": typed,
+                                    "chosen": chosen,
+                                })
+                            )
+                            .collect::<Vec<_>>(),
+                        "per_day": stats.per_day
+                            .iter()
+                            .map(|(day, (typed, chosen))|
+                                json!({
+                                    "day": format_chat_day(*day),
+                                    "queries": typed,
+                                    "chosen": chosen,
+                                })
+                            )
+                            .collect::<Vec<_>>(),
+                        "recent": recent,
+                    },
+                }),
+            ).into_response(),
+        );
+    }
+
+    let mut out =
+        vec!(
+            "<div class=\"info\">".to_string(),
+        );
+
+    if !get_log_inline_queries() {
+        out.push(
+            "<p class=\"note\">inline queries aren't being recorded, set MINUTEMAN_LOG_INLINE_QUERIES to start</p>"
+                .to_string(),
+        );
+    }
+
+    out.push(
+        format!(
+            "<table class=\"info\"><tbody>\
+                <tr><td class=\"label\">last</td><td>{} day(s)</td></tr>\
+                <tr><td class=\"label\">queries</td><td>{}</td></tr>\
+                <tr><td class=\"label\">results picked</td><td>{}</td></tr>\
+                <tr><td class=\"label\">users</td><td>{}</td></tr>\
+            </tbody></table>",
+            days,
+            stats.queries,
+            stats.chosen,
+            stats.users,
+        ),
+    );
+
+    out.push("<h3>top queries</h3><table class=\"info\"><tbody>".to_string());
+
+    out.push(
+        "<tr><td class=\"label\">query</td><td class=\"label\">queries</td><td class=\"label\">picked</td></tr>"
+            .to_string(),
+    );
+
+    for (query, typed, chosen) in stats.top_queries.iter() {
+        out.push(
+            format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td></tr>",
+                escape_html(query),
+                typed,
+                chosen,
+            ),
+        );
+    }
+
+    out.push("</tbody></table>".to_string());
+
+    out.push("<h3>per day</h3><table class=\"info\"><tbody>".to_string());
+
+    out.push(
+        "<tr><td class=\"label\">day</td><td class=\"label\">queries</td><td class=\"label\">picked</td></tr>"
+            .to_string(),
+    );
+
+    for (day, (typed, chosen)) in stats.per_day.iter().rev() {
+        out.push(
+            format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td></tr>",
+                format_chat_day(*day).unwrap_or_default(),
+                typed,
+                chosen,
+            ),
+        );
+    }
+
+    out.push("</tbody></table>".to_string());
+
+    out.push("<h3>recent</h3><table class=\"info\"><tbody>".to_string());
+
+    out.push(
+        "<tr><td class=\"label\">time</td><td class=\"label\">user</td><td class=\"label\">bot</td><td class=\"label\">query</td><td class=\"label\">picked</td></tr>"
+            .to_string(),
+    );
+
+    for item in recent.iter() {
+        out.push(
+            format!(
+                "<tr><td>{}</td><td><a href=\"/user/{}\">{}</a></td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                format_time(item.time),
+                escape_html(&item.user_id),
+                escape_html(&item.user_id),
+                escape_html(&item.bot),
+                escape_html(&item.query),
+                item.result_id
+                    .as_deref()
+                    .map(escape_html)
+                    .unwrap_or("-".to_string()),
+            ),
+        );
+    }
+
+    out.push("</tbody></table></div>".to_string());
+
+    let navigation =
+        HeaderBar::new()
+            .with_link(
+                "<- admin",
+                Some("/admin".into()),
+            )
+            .with_title("inline queries")
+            .with_format_link("/admin/inline", "json");
+
+    Ok(
+        warp::reply::html(
+            Page::new("inline queries")
+                .with_theme(theme)
+                .with_header(navigation)
+                .with_body(out.join(""))
+                .render(),
+        ).into_response()
+    )
+}
@@ -0,0 +1,11 @@
+use crate::metrics::render_metrics;
+
+pub async fn metrics() -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(
+        warp::reply::with_header(
+            render_metrics(),
+            "content-type",
+            "text/plain; version=0.0.4",
+        ),
+    )
+}
@@ -1,4 +1,23 @@
 pub mod chats;
+pub mod chat_digest;
 pub mod chat_index;
 pub mod chat_listing;
 pub mod get_file;
+pub mod assets;
+pub mod admin;
+pub mod metrics;
+pub mod chat_media;
+pub mod chat_info;
+pub mod error;
+pub mod chat_username;
+pub mod chat_pins;
+pub mod contact;
+pub mod user_info;
+pub mod redact;
+pub mod robots;
+pub mod search;
+pub mod share;
+pub mod health;
+pub mod storage;
+pub mod status;
+pub mod inline_queries;
@@ -0,0 +1,86 @@
+use std::sync::{Arc, Mutex};
+
+use rocksdb::{DBWithThreadMode, MultiThreaded};
+use serde_json::json;
+use warp::http::StatusCode;
+
+use crate::MinutemanError;
+use crate::privacy::Viewer;
+use crate::storage::lock_db;
+use crate::utils::redact_message;
+
+pub async fn redact(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    chat_id: String,
+    message_id: String,
+    viewer: Viewer,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if !viewer.admin {
+        return Ok(
+            warp::reply::with_status(
+                warp::reply::json(
+                    &json!({
+                        "status": "admin token required",
+                        "error": true,
+                        "data": null
+                    }),
+                ),
+                StatusCode::FORBIDDEN,
+            ),
+        );
+    }
+
+    let dbi = lock_db(&db);
+
+    let redacted =
+        redact_message(
+            &dbi,
+            &chat_id,
+            &message_id,
+        )
+            .map_err(|err|
+                warp::reject::custom(
+                    MinutemanError::DBError(
+                        format!("{:?}", err),
+                    ),
+                )
+            )?;
+
+    println!(
+        "[redact] message {} in {}: {:?}",
+        &message_id,
+        &chat_id,
+        redacted,
+    );
+
+    Ok(
+        match redacted {
+            Some(timestamp) =>
+                warp::reply::with_status(
+                    warp::reply::json(
+                        &json!({
+                            "status": "ok",
+                            "error": false,
+                            "data": {
+                                "chat_id": chat_id,
+                                "message_id": message_id,
+                                "time": timestamp,
+                            }
+                        }),
+                    ),
+                    StatusCode::OK,
+                ),
+            None =>
+                warp::reply::with_status(
+                    warp::reply::json(
+                        &json!({
+                            "status": "message not found",
+                            "error": true,
+                            "data": null
+                        }),
+                    ),
+                    StatusCode::NOT_FOUND,
+                ),
+        },
+    )
+}
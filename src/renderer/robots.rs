@@ -0,0 +1,190 @@
+use std::sync::{Arc, Mutex};
+
+use chrono::Utc;
+use rocksdb::{DBWithThreadMode, Direction, IteratorMode, MultiThreaded, ReadOptions};
+use warp::Reply;
+use warp::http::StatusCode;
+
+use crate::SITEMAP_CHUNK_SIZE;
+use crate::config::{get_noindex, get_public_url, get_robots_txt};
+use crate::privacy::is_chat_anonymized;
+use crate::storage::{lock_db, ReadStore, Storage};
+use crate::utils::{find_chat_days, format_chat_day};
+
+pub async fn robots_txt() -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(
+        warp::reply::with_header(
+            get_robots_txt(),
+            "content-type",
+            "text/plain; charset=utf-8",
+        ),
+    )
+}
+
+/// Every page a search engine may index with its last modification date:
+/// the index and day pages of group chats that aren't anonymized, in the
+/// same order on every call so that sitemap chunks stay stable.
+fn sitemap_urls(
+    db: &impl ReadStore,
+) -> Vec<(String, String)> {
+    let mut opts = ReadOptions::default();
+
+    let lower_bound = b"chat_rel:".to_vec();
+
+    opts.set_iterate_upper_bound(b"chat_rel:\xff".to_vec());
+
+    let iter =
+        db.iterator_opt(
+            IteratorMode::From(&lower_bound, Direction::Forward),
+            opts,
+        );
+
+    let today = Utc::now().timestamp() / 86_400;
+
+    let mut urls = Vec::<(String, String)>::new();
+
+    for (key, _) in iter {
+        let key = String::from_utf8(key.to_vec()).unwrap_or_default();
+        let key = key.split(':').collect::<Vec<&str>>();
+
+        if key.len() != 2 {
+            continue;
+        }
+
+        let chat_id = key[1];
+
+        if !chat_id.starts_with('-') || is_chat_anonymized(chat_id) {
+            continue;
+        }
+
+        let days = find_chat_days(db, chat_id);
+
+        // a day page is final once the day is over, apart from late messages
+        let lastmod = |day: i64| format_chat_day(day.min(today));
+
+        if let Some(latest) = days.first().map(|day| lastmod(*day)).flatten() {
+            urls.push((format!("/chat/{}", chat_id), latest));
+        }
+
+        for day in days.iter().rev() {
+            if let (Some(date), Some(lastmod)) = (format_chat_day(*day), lastmod(*day + 1)) {
+                urls.push((format!("/chat/{}/{}", chat_id, date), lastmod));
+            }
+        }
+    }
+
+    urls
+}
+
+fn xml_reply(
+    body: String,
+) -> warp::reply::Response {
+    warp::reply::with_header(
+        body,
+        "content-type",
+        "application/xml; charset=utf-8",
+    ).into_response()
+}
+
+fn sitemap_unavailable() -> warp::reply::Response {
+    warp::reply::with_status(
+        "sitemaps need MINUTEMAN_PUBLIC_URL and are off in noindex mode",
+        StatusCode::NOT_FOUND,
+    ).into_response()
+}
+
+/// `/sitemap.xml`, an index of the `/sitemap/{n}.xml` chunks.
+pub async fn sitemap_index(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let public_url =
+        match get_public_url() {
+            Some(url) if !get_noindex() => url,
+            _ => return Ok(sitemap_unavailable()),
+        };
+
+    let dbi = lock_db(&db);
+
+    let view = dbi.read_view();
+
+    let urls = sitemap_urls(&view);
+
+    let mut out =
+        vec!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>".to_string(),
+            "<sitemapindex xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">".to_string(),
+        );
+
+    for (chunk, urls) in urls.chunks(SITEMAP_CHUNK_SIZE).enumerate() {
+        let lastmod =
+            urls.iter()
+                .map(|(_, lastmod)| lastmod)
+                .max()
+                .cloned()
+                .unwrap_or_default();
+
+        out.push(
+            format!(
+                "<sitemap><loc>{}/sitemap/{}.xml</loc><lastmod>{}</lastmod></sitemap>",
+                public_url,
+                chunk,
+                lastmod,
+            ),
+        );
+    }
+
+    out.push("</sitemapindex>".to_string());
+
+    Ok(xml_reply(out.join("\n")))
+}
+
+/// `/sitemap/{n}.xml`, the n-th `SITEMAP_CHUNK_SIZE` urls.
+pub async fn sitemap_chunk(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    chunk: String,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let public_url =
+        match get_public_url() {
+            Some(url) if !get_noindex() => url,
+            _ => return Ok(sitemap_unavailable()),
+        };
+
+    let chunk =
+        chunk
+            .strip_suffix(".xml")
+            .map(|chunk| chunk.parse::<usize>().ok())
+            .flatten()
+            .ok_or_else(warp::reject::not_found)?;
+
+    let dbi = lock_db(&db);
+
+    let view = dbi.read_view();
+
+    let urls = sitemap_urls(&view);
+
+    let urls =
+        urls.chunks(SITEMAP_CHUNK_SIZE)
+            .nth(chunk)
+            .ok_or_else(warp::reject::not_found)?;
+
+    let mut out =
+        vec!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>".to_string(),
+            "<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">".to_string(),
+        );
+
+    for (path, lastmod) in urls.iter() {
+        out.push(
+            format!(
+                "<url><loc>{}{}</loc><lastmod>{}</lastmod></url>",
+                public_url,
+                path,
+                lastmod,
+            ),
+        );
+    }
+
+    out.push("</urlset>".to_string());
+
+    Ok(xml_reply(out.join("\n")))
+}
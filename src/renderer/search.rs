@@ -0,0 +1,648 @@
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use chrono::{Duration, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use rocksdb::{DBWithThreadMode, Direction, IteratorMode, MultiThreaded, ReadOptions};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use warp::http::StatusCode;
+use warp::Reply;
+
+use crate::{ok_or_continue, some_or_continue};
+use crate::components::header::HeaderBar;
+use crate::components::mark::{mark_matches, search_terms};
+use crate::components::page::{Page, Theme};
+use crate::config::{get_admin_token, get_search_index, get_search_time_budget};
+use crate::privacy::Viewer;
+use crate::rate_limit::acquire_listing_slot;
+use crate::renderer::error::error_page;
+use crate::search_index::{find_postings, query_tokens, searchable_text};
+use crate::storage::{lock_db, ReadStore, Storage};
+use crate::utils::{encode_query_value, escape_html, format_chat_day, message_permalink, NameCache};
+use crate::workers::telegram_handler::{build_message_key, LogItem, MessageSlot, parse_message_key};
+
+// searched when no range is given
+const DEFAULT_SEARCH_DAYS: i64 = 30;
+
+const MAX_SEARCH_DAYS: i64 = 366;
+
+const DEFAULT_SEARCH_LIMIT: usize = 100;
+
+const MAX_SEARCH_LIMIT: usize = 500;
+
+// characters of context on either side of a match
+const SNIPPET_CONTEXT: usize = 80;
+
+// messages shown on either side of a result when it's expanded
+const CONTEXT_MESSAGES: usize = 5;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SearchQuery {
+    pub q: Option<String>,
+    // first day searched (YYYY-MM-DD)
+    pub from: Option<String>,
+    // last day searched (YYYY-MM-DD), inclusive
+    pub to: Option<String>,
+    pub limit: Option<usize>,
+}
+
+impl SearchQuery {
+    /// First and last day of the search, defaulting to the last
+    /// `DEFAULT_SEARCH_DAYS` and never spanning more than `MAX_SEARCH_DAYS`.
+    fn date_range(&self) -> Result<(NaiveDate, NaiveDate), String> {
+        let parse = |date: &Option<String>|
+            date.as_deref()
+                .filter(|date| !date.is_empty())
+                .map(|date|
+                    NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                        .map_err(|_| format!("invalid date (got {})", date))
+                )
+                .transpose();
+
+        let to =
+            parse(&self.to)?
+                .unwrap_or(Utc::now().naive_utc().date());
+
+        let from =
+            parse(&self.from)?
+                .unwrap_or(to - Duration::days(DEFAULT_SEARCH_DAYS - 1));
+
+        if from > to {
+            return Err("the range starts after it ends".to_string());
+        }
+
+        if (to - from).num_days() >= MAX_SEARCH_DAYS {
+            return Err(format!("searches can't span more than {} days", MAX_SEARCH_DAYS));
+        }
+
+        Ok((from, to))
+    }
+
+    fn search_limit(&self) -> usize {
+        self.limit
+            .unwrap_or(DEFAULT_SEARCH_LIMIT)
+            .clamp(1, MAX_SEARCH_LIMIT)
+    }
+}
+
+pub struct SearchHit {
+    pub time: i64,
+    pub slot: MessageSlot,
+    pub snippet: String,
+    pub permalink: Option<String>,
+}
+
+pub struct SearchResults {
+    // chat id and its hits, newest first
+    pub chats: Vec<(String, Vec<SearchHit>)>,
+    // the time budget ran out or the result cap was hit, there may be more
+    pub partial: bool,
+}
+
+/// Part of `text` around the first occurrence of `needle` (lowercase), None
+/// when there is none.
+fn match_snippet(
+    text: &str,
+    needle: &str,
+) -> Option<String> {
+    // lowercasing can turn one character into several, `origin` maps each
+    // lowered character back to the one it came from in `text`
+    let mut lowered = Vec::<char>::new();
+    let mut origin = Vec::<usize>::new();
+
+    for (index, c) in text.chars().enumerate() {
+        for lower in c.to_lowercase() {
+            lowered.push(lower);
+            origin.push(index);
+        }
+    }
+
+    let needle = needle.chars().collect::<Vec<char>>();
+
+    if needle.is_empty() {
+        return None;
+    }
+
+    let found = (0..lowered.len()).find(|&start| lowered[start..].starts_with(&needle))?;
+
+    let position = origin[found];
+    let match_end = origin[found + needle.len() - 1] + 1;
+
+    let chars = text.chars().collect::<Vec<char>>();
+
+    let start = position.saturating_sub(SNIPPET_CONTEXT);
+    let end = (match_end + SNIPPET_CONTEXT).min(chars.len());
+
+    let mut snippet = chars[start..end].iter().collect::<String>();
+
+    if start > 0 {
+        snippet.insert(0, '…');
+    }
+
+    if end < chars.len() {
+        snippet.push('…');
+    }
+
+    Some(snippet)
+}
+
+/// Scans the messages of every chat the viewer may see between `from` and
+/// `to` for `q`, case insensitive, only looking at the messages the
+/// full-text index lists for its words when it's enabled. Stops early once `limit` hits were found
+/// or the search took longer than `get_search_time_budget`.
+pub fn search_chats(
+    db: &impl ReadStore,
+    q: &str,
+    from: NaiveDate,
+    to: NaiveDate,
+    limit: usize,
+    viewer: &Viewer,
+) -> SearchResults {
+    let started = Instant::now();
+    let budget = get_search_time_budget();
+
+    let needle = q.to_lowercase();
+
+    let time_start = NaiveDateTime::new(from, NaiveTime::from_hms(0, 0, 0)).timestamp();
+    let time_end = NaiveDateTime::new(to + Duration::days(1), NaiveTime::from_hms(0, 0, 0)).timestamp();
+
+    let mut results =
+        SearchResults {
+            chats: Vec::new(),
+            partial: false,
+        };
+
+    let mut hit_count = 0;
+
+    let mut opts = ReadOptions::default();
+
+    let lower_bound = b"chat_rel:".to_vec();
+
+    opts.set_iterate_upper_bound(b"chat_rel:\xff".to_vec());
+
+    let chat_ids =
+        db.iterator_opt(
+            IteratorMode::From(&lower_bound, Direction::Forward),
+            opts,
+        )
+            .filter_map(|(key, _)| String::from_utf8(key.to_vec()).ok())
+            .filter_map(|key| key.split(':').nth(1).map(|chat_id| chat_id.to_string()))
+            .filter(|chat_id| viewer.can_see_chat(chat_id))
+            .collect::<Vec<String>>();
+
+    // queries without a single indexable word have to scan
+    let tokens = query_tokens(q);
+    let use_index = get_search_index() && !tokens.is_empty();
+
+    'chats: for chat_id in chat_ids {
+        // the index only narrows it down to the messages containing every
+        // word, the phrase itself is checked on the message below
+        let candidates: Box<dyn Iterator<Item=(MessageSlot, Vec<u8>)> + '_> =
+            if use_index {
+                let slots =
+                    match find_postings(db, &chat_id, &tokens, time_start, time_end, started + budget) {
+                        Some(slots) => slots,
+                        None => {
+                            results.partial = true;
+
+                            break 'chats;
+                        }
+                    };
+
+                Box::new(
+                    slots
+                        .into_iter()
+                        .filter_map(|slot|
+                            db.get(build_message_key(&chat_id, slot))
+                                .ok()
+                                .flatten()
+                                .map(|val| (slot, val))
+                        ),
+                )
+            } else {
+                let mut opts = ReadOptions::default();
+
+                let lower_bound = format!("chat:{}:{}", &chat_id, time_start).as_bytes().to_vec();
+                let upper_bound = format!("chat:{}:{}", &chat_id, time_end).as_bytes().to_vec();
+
+                opts.set_iterate_upper_bound(upper_bound.clone());
+                opts.set_iterate_lower_bound(lower_bound);
+
+                Box::new(
+                    db.iterator_opt(
+                        IteratorMode::From(&upper_bound, Direction::Reverse),
+                        opts,
+                    )
+                        .filter_map(|(key, val)| {
+                            let key = String::from_utf8(key.to_vec()).ok()?;
+                            let (_, slot) = parse_message_key(&key)?;
+
+                            Some((slot, val.to_vec()))
+                        }),
+                )
+            };
+
+        let mut hits = Vec::<SearchHit>::new();
+
+        for (slot, val) in candidates {
+            if started.elapsed() > budget || hit_count >= limit {
+                results.partial = true;
+
+                if !hits.is_empty() {
+                    results.chats.push((chat_id.clone(), hits));
+                }
+
+                break 'chats;
+            }
+
+            let item = ok_or_continue!(serde_json::from_slice::<LogItem>(&val));
+            let text = some_or_continue!(searchable_text(&item));
+            let snippet = some_or_continue!(match_snippet(&text, &needle));
+
+            hits.push(
+                SearchHit {
+                    time: slot.time,
+                    slot,
+                    snippet,
+                    permalink: message_permalink(&chat_id, slot.time),
+                },
+            );
+
+            hit_count += 1;
+        }
+
+        if !hits.is_empty() {
+            results.chats.push((chat_id, hits));
+        }
+    }
+
+    results
+}
+
+/// Permalink of a result that keeps the query, so the day page highlights
+/// it as well.
+fn result_link(
+    permalink: &str,
+    q: &str,
+) -> String {
+    match permalink.split_once('#') {
+        Some((url, anchor)) => format!("{}&q={}#{}", url, encode_query_value(q), anchor),
+        None => format!("{}&q={}", permalink, encode_query_value(q)),
+    }
+}
+
+fn context_line<S: ReadStore>(
+    item: &LogItem,
+    names: &mut NameCache<S>,
+) -> Option<String> {
+    let (user_id, time, text) =
+        match item {
+            LogItem::Message { user_id, time, text, .. } =>
+                (user_id, *time, text.clone()),
+            LogItem::Media { user_id, time, caption, .. } =>
+                (user_id, *time, format!("[file] {}", caption.as_deref().unwrap_or_default())),
+            _ => return None,
+        };
+
+    let nick =
+        user_id
+            .as_ref()
+            .map(|user_id| names.user_at(user_id, time))
+            .unwrap_or("Unknown".to_string());
+
+    let time =
+        NaiveDateTime::from_timestamp_opt(time, 0)
+            .map(|time| time.format("%H:%M").to_string())
+            .unwrap_or_default();
+
+    Some(format!("[{}] <{}> {}", time, nick, text))
+}
+
+/// The message at `slot` with up to `CONTEXT_MESSAGES` messages on either
+/// side, as plain text lines in chat order. Neighbours are found through
+/// the key order, so it's two short seeks no matter the chat size.
+fn message_context<S: ReadStore>(
+    db: &S,
+    chat_id: &str,
+    slot: MessageSlot,
+    names: &mut NameCache<S>,
+) -> Vec<(MessageSlot, String)> {
+    let neighbours = |lower_bound: String, upper_bound: String, direction: Direction| {
+        let mut opts = ReadOptions::default();
+
+        opts.set_iterate_lower_bound(lower_bound.as_bytes().to_vec());
+        opts.set_iterate_upper_bound(upper_bound.as_bytes().to_vec());
+
+        let from =
+            match direction {
+                Direction::Forward => lower_bound,
+                Direction::Reverse => upper_bound,
+            };
+
+        db.iterator_opt(IteratorMode::From(from.as_bytes(), direction), opts)
+            .filter_map(|(key, val)| {
+                let key = String::from_utf8(key.to_vec()).ok()?;
+                let (_, slot) = parse_message_key(&key)?;
+
+                Some((slot, serde_json::from_slice::<LogItem>(&val).ok()?))
+            })
+            .filter(|(_, item)| matches!(item, LogItem::Message { .. } | LogItem::Media { .. }))
+            .take(CONTEXT_MESSAGES)
+            .collect::<Vec<(MessageSlot, LogItem)>>()
+    };
+
+    let message_key = build_message_key(chat_id, slot);
+
+    let mut items =
+        neighbours(
+            format!("chat:{}:0", chat_id),
+            message_key.clone(),
+            Direction::Reverse,
+        );
+
+    items.reverse();
+
+    if let Some(item) = db.get(&message_key).ok().flatten() {
+        if let Ok(item) = serde_json::from_slice::<LogItem>(&item) {
+            items.push((slot, item));
+        }
+    }
+
+    // ';' sorts right after ':', this skips the message and, for a slot
+    // without message id, the ones of its second it's a prefix of
+    items.extend(
+        neighbours(
+            format!("{};", message_key),
+            format!("chat:{}:\x7f", chat_id),
+            Direction::Forward,
+        ),
+    );
+
+    items
+        .iter()
+        .filter_map(|(slot, item)| Some((*slot, context_line(item, names)?)))
+        .collect()
+}
+
+fn search_form(
+    q: &str,
+    from: &str,
+    to: &str,
+) -> String {
+    format!(
+        "<form class=\"search\" action=\"/search\" method=\"get\">\
+            <input type=\"search\" name=\"q\" value=\"{}\" placeholder=\"text or url\"/> \
+            from <input type=\"date\" name=\"from\" value=\"{}\"/> \
+            to <input type=\"date\" name=\"to\" value=\"{}\"/> \
+            <input type=\"submit\" value=\"search\"/>\
+        </form>",
+        escape_html(q),
+        escape_html(from),
+        escape_html(to),
+    )
+}
+
+fn search_error(
+    out_format: &'static str,
+    status: StatusCode,
+    message: &str,
+    theme: Theme,
+) -> warp::reply::Response {
+    if out_format == "json" {
+        return warp::reply::with_status(
+            warp::reply::json(
+                &json!({
+                    "status": message,
+                    "error": true,
+                    "data": null
+                }),
+            ),
+            status,
+        ).into_response();
+    }
+
+    error_page(status, message, theme)
+}
+
+/// `GET /search`, finds messages across every logged chat. Exposes all
+/// chats at once, so it's admin only as soon as an admin token is set.
+pub async fn search(
+    out_format: &'static str,
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    query: SearchQuery,
+    viewer: Viewer,
+    raw_query: String,
+    theme: Theme,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if get_admin_token().is_some() && !viewer.admin {
+        return Ok(search_error(out_format, StatusCode::FORBIDDEN, "admin token required", theme));
+    }
+
+    let (from, to) =
+        match query.date_range() {
+            Ok(range) => range,
+            Err(err) => return Ok(search_error(out_format, StatusCode::BAD_REQUEST, &err, theme)),
+        };
+
+    let q =
+        query.q
+            .as_deref()
+            .map(|q| q.trim())
+            .filter(|q| !q.is_empty());
+
+    let from_date = from.format("%Y-%m-%d").to_string();
+    let to_date = to.format("%Y-%m-%d").to_string();
+
+    let header =
+        HeaderBar::new()
+            .with_link(
+                "<- home",
+                Some("/".into()),
+            )
+            .with_title("search");
+
+    let q =
+        match q {
+            Some(q) => q,
+            None if out_format == "json" =>
+                return Ok(search_error(out_format, StatusCode::BAD_REQUEST, "missing query (q)", theme)),
+            None =>
+                return Ok(
+                    warp::reply::html(
+                        Page::new("search")
+                            .with_theme(theme)
+                            .with_query(&raw_query)
+                            .with_header(header)
+                            .with_body(
+                                format!(
+                                    "<div class=\"search\">{}</div>",
+                                    search_form("", &from_date, &to_date),
+                                ),
+                            )
+                            .render(),
+                    ).into_response()
+                ),
+        };
+
+    let _slot = acquire_listing_slot().map_err(warp::reject::custom)?;
+
+    let dbi = lock_db(&db);
+
+    let view = dbi.read_view();
+
+    let results =
+        search_chats(
+            &view,
+            q,
+            from,
+            to,
+            query.search_limit(),
+            &viewer,
+        );
+
+    let mut names = NameCache::new(&view);
+
+    if out_format == "json" {
+        let data =
+            results.chats
+                .iter()
+                .map(|(chat_id, hits)|
+                    json!({
+                        "chat_id": chat_id,
+                        "chat_name": names.chat_name(chat_id),
+                        "results": Value::Array(
+                            hits
+                                .iter()
+                                .map(|hit|
+                                    json!({
+                                        "time": hit.time,
+                                        "day": format_chat_day(hit.time / 86_400),
+                                        "snippet": hit.snippet,
+                                        "permalink": hit.permalink,
+                                    })
+                                )
+                                .collect(),
+                        ),
+                    })
+                )
+                .collect::<Vec<Value>>();
+
+        return Ok(
+            warp::reply::json(
+                &json!({
+                    "status": "ok",
+                    "error": false,
+                    "data": Value::Array(data),
+                    "from": from_date,
+                    "to": to_date,
+                    "partial": results.partial,
+                }),
+            ).into_response(),
+        );
+    }
+
+    let terms = search_terms(q);
+
+    let mut out =
+        vec!(
+            "<div class=\"search\">".to_string(),
+            search_form(q, &from_date, &to_date),
+        );
+
+    if results.partial {
+        out.push(
+            "<p class=\"note\">only part of the range was searched, narrow it down to see everything</p>".to_string(),
+        );
+    }
+
+    if results.chats.is_empty() {
+        out.push("<p class=\"note\">nothing found</p>".to_string());
+    }
+
+    for (chat_id, hits) in results.chats.iter() {
+        out.push(
+            format!(
+                "<h3><a href=\"/chat/{}\">{}</a> <span class=\"note\">{} result(s)</span></h3><ul>",
+                chat_id,
+                escape_html(&names.chat_name(chat_id)),
+                hits.len(),
+            ),
+        );
+
+        let mut context_names =
+            NameCache::new(&view)
+                .with_anonymized(viewer.anonymize_chat(chat_id));
+
+        for hit in hits.iter() {
+            let context =
+                message_context(&view, chat_id, hit.slot, &mut context_names)
+                    .into_iter()
+                    .map(|(slot, line)|
+                        format!(
+                            "<li{}>{}</li>",
+                            if slot == hit.slot { " class=\"hit\"" } else { "" },
+                            mark_matches(&line, &terms),
+                        )
+                    )
+                    .collect::<String>();
+
+            out.push(
+                format!(
+                    "<li><a href=\"{}\">{}</a> {}\
+                        <details class=\"context\"><summary>&plusmn;{} messages</summary><ul>{}</ul></details>\
+                    </li>",
+                    escape_html(
+                        &hit.permalink
+                            .as_deref()
+                            .map(|permalink| result_link(permalink, q))
+                            .unwrap_or("#".to_string()),
+                    ),
+                    format_chat_day(hit.time / 86_400).unwrap_or_default(),
+                    mark_matches(&hit.snippet, &terms),
+                    CONTEXT_MESSAGES,
+                    context,
+                ),
+            );
+        }
+
+        out.push("</ul>".to_string());
+    }
+
+    out.push("</div>".to_string());
+
+    Ok(
+        warp::reply::html(
+            Page::new(format!("search - {}", q))
+                .with_theme(theme)
+                .with_query(&raw_query)
+                .with_header(header)
+                .with_body(out.join(""))
+                .render(),
+        ).into_response()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snippets_follow_characters_that_grow_when_lowercased() {
+        let text = format!("{}x", "İ".repeat(100));
+
+        let snippet = match_snippet(&text, "x").unwrap();
+
+        assert!(snippet.starts_with('…'));
+        assert!(snippet.ends_with("İx"));
+        assert_eq!(snippet.chars().count(), SNIPPET_CONTEXT + 2);
+
+        let text = format!("{}Ünïcode{}", "İ".repeat(3), "y".repeat(100));
+
+        let snippet = match_snippet(&text, "ünïcode").unwrap();
+
+        assert!(snippet.starts_with("İİİÜnïcode"));
+        assert!(snippet.ends_with('…'));
+
+        assert!(match_snippet("İİİ", "x").is_none());
+    }
+}
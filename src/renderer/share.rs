@@ -0,0 +1,143 @@
+use std::sync::{Arc, Mutex};
+
+use chrono::Utc;
+use rocksdb::{DBWithThreadMode, MultiThreaded};
+use serde::Deserialize;
+use serde_json::json;
+use warp::http::StatusCode;
+
+use crate::config::get_public_url;
+use crate::privacy::{verify_csrf, Viewer};
+use crate::share::{create_share, revoke_share};
+use crate::storage::lock_db;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ShareRequest {
+    pub chat_id: String,
+    // unix timestamp the link stops working at
+    pub expires: i64,
+    // the admin page's `csrf_token`, unless sent as the `CSRF_HEADER`
+    #[serde(default)]
+    pub csrf: Option<String>,
+}
+
+fn admin_required() -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(
+        warp::reply::json(
+            &json!({
+                "status": "admin token required",
+                "error": true,
+                "data": null
+            }),
+        ),
+        StatusCode::FORBIDDEN,
+    )
+}
+
+/// `POST /admin/share`, hands out a link that shows one chat until
+/// `expires`. Takes the CSRF token in the body or the `CSRF_HEADER`.
+pub async fn create_share_link(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    request: ShareRequest,
+    csrf: Option<String>,
+    viewer: Viewer,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if !viewer.admin || !verify_csrf(request.csrf.as_deref().or(csrf.as_deref())) {
+        return Ok(admin_required());
+    }
+
+    if request.expires <= Utc::now().timestamp() {
+        return Ok(
+            warp::reply::with_status(
+                warp::reply::json(
+                    &json!({
+                        "status": "expires has to be in the future",
+                        "error": true,
+                        "data": null
+                    }),
+                ),
+                StatusCode::BAD_REQUEST,
+            ),
+        );
+    }
+
+    let dbi = lock_db(&db);
+
+    let (share, token) =
+        create_share(
+            &dbi,
+            &request.chat_id,
+            request.expires,
+        ).map_err(warp::reject::custom)?;
+
+    tracing::info!(id = %share.id, chat_id = %share.chat_id, expires = share.expires, "share link created");
+
+    Ok(
+        warp::reply::with_status(
+            warp::reply::json(
+                &json!({
+                    "status": "ok",
+                    "error": false,
+                    "data": {
+                        "id": share.id,
+                        "chat_id": share.chat_id,
+                        "expires": share.expires,
+                        // relative without a public url
+                        "url": format!("{}/chat/{}/latest?share={}", get_public_url().unwrap_or_default(), share.chat_id, token),
+                    }
+                }),
+            ),
+            StatusCode::OK,
+        ),
+    )
+}
+
+/// `DELETE /admin/share/{id}`, the link stops working right away.
+pub async fn revoke_share_link(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    id: String,
+    viewer: Viewer,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if !viewer.admin {
+        return Ok(admin_required());
+    }
+
+    let dbi = lock_db(&db);
+
+    let revoked =
+        revoke_share(
+            &dbi,
+            &id,
+        ).map_err(warp::reject::custom)?;
+
+    Ok(
+        match revoked {
+            Some(share) =>
+                warp::reply::with_status(
+                    warp::reply::json(
+                        &json!({
+                            "status": "ok",
+                            "error": false,
+                            "data": {
+                                "id": share.id,
+                                "chat_id": share.chat_id,
+                                "revoked_at": share.revoked_at,
+                            }
+                        }),
+                    ),
+                    StatusCode::OK,
+                ),
+            None =>
+                warp::reply::with_status(
+                    warp::reply::json(
+                        &json!({
+                            "status": "share not found",
+                            "error": true,
+                            "data": null
+                        }),
+                    ),
+                    StatusCode::NOT_FOUND,
+                ),
+        },
+    )
+}
@@ -0,0 +1,250 @@
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use rocksdb::{DBWithThreadMode, MultiThreaded};
+use serde_json::json;
+use warp::http::StatusCode;
+use warp::Reply;
+
+use crate::components::header::HeaderBar;
+use crate::components::page::{Page, Theme};
+use crate::config::{get_heartbeat_stale_after, get_version};
+use crate::metrics::{telegram_metrics, worker_restarts};
+use crate::privacy::Viewer;
+use crate::renderer::error::error_page;
+use crate::renderer::storage::format_bytes;
+use crate::storage::{lock_db, Storage};
+use crate::storage_stats::approximate_db_size;
+use crate::utils::escape_html;
+use crate::workers::heartbeat::find_worker_states;
+use crate::workers::ingest_errors::find_ingest_errors;
+
+// most recent ingest errors shown, the cli goes back further
+const STATUS_INGEST_ERRORS: usize = 20;
+
+fn format_time(
+    time: Option<i64>,
+) -> String {
+    time
+        .map(|time| NaiveDateTime::from_timestamp_opt(time, 0))
+        .flatten()
+        .map(|time| DateTime::<Utc>::from_utc(time, Utc))
+        .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or("never".to_string())
+}
+
+/// Which workers are alive, how often they had to be restarted and what
+/// they last failed with.
+pub async fn status(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    out_format: &'static str,
+    viewer: Viewer,
+    theme: Theme,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if !viewer.admin {
+        return Ok(
+            error_page(
+                StatusCode::FORBIDDEN,
+                "admin token required",
+                theme,
+            ),
+        );
+    }
+
+    let dbi = lock_db(&db);
+
+    let view = dbi.read_view();
+
+    let db_path = dbi.path().display().to_string();
+    let db_size = approximate_db_size(&dbi);
+
+    let telegram = telegram_metrics();
+    let restarts = worker_restarts();
+
+    let now = Utc::now().timestamp();
+    let stale_after = get_heartbeat_stale_after().as_secs() as i64;
+
+    // (state, restarts since start, stale)
+    let workers =
+        find_worker_states(&view)
+            .into_iter()
+            .map(|state| {
+                let restarts =
+                    restarts
+                        .get(&state.worker)
+                        .map(|restarts| restarts.restarts)
+                        .unwrap_or(0);
+
+                let stale =
+                    state.heartbeat_at
+                        .map(|heartbeat_at| now - heartbeat_at > stale_after)
+                        .unwrap_or(true);
+
+                (state, restarts, stale)
+            })
+            .collect::<Vec<_>>();
+
+    let ingest_errors = find_ingest_errors(&view, STATUS_INGEST_ERRORS);
+
+    if out_format == "json" {
+        return Ok(
+            warp::reply::json(
+                &json!({
+                    "status": "ok",
+                    "error": false,
+                    "data": {
+                        "version": get_version(),
+                        "db_path": db_path,
+                        "db_size": db_size,
+                        "lag_seconds": telegram.lag_seconds,
+                        "stale_after_seconds": stale_after,
+                        "workers": workers
+                            .iter()
+                            .map(|(state, restarts, stale)|
+                                json!({
+                                    "worker": state.worker,
+                                    "heartbeat_at": state.heartbeat_at,
+                                    "stale": stale,
+                                    "restarts": restarts,
+                                    "last_error": state.last_error
+                                        .as_ref()
+                                        .map(|error| json!({
+                                            "at": error.at,
+                                            "error": error.error,
+                                        })),
+                                })
+                            )
+                            .collect::<Vec<_>>(),
+                        "ingest_errors": ingest_errors
+                            .iter()
+                            .map(|sample|
+                                json!({
+                                    "time": sample.time,
+                                    "stage": sample.stage,
+                                    "chat_id": sample.chat_id,
+                                    "message_id": sample.message_id,
+                                    "error": sample.error,
+                                })
+                            )
+                            .collect::<Vec<_>>(),
+                    },
+                }),
+            ).into_response(),
+        );
+    }
+
+    let mut out =
+        vec!(
+            "<div class=\"info\"><table class=\"info\"><tbody>".to_string(),
+            format!(
+                "<tr><td class=\"label\">version</td><td>{}</td></tr>",
+                escape_html(&get_version()),
+            ),
+            format!(
+                "<tr><td class=\"label\">database</td><td>{} <span class=\"note\">{} (approximate)</span></td></tr>",
+                escape_html(&db_path),
+                format_bytes(db_size),
+            ),
+            format!(
+                "<tr><td class=\"label\">update lag</td><td>{}s</td></tr>",
+                telegram.lag_seconds,
+            ),
+            "</tbody></table>".to_string(),
+        );
+
+    out.push("<h3>workers</h3><table class=\"info\"><tbody>".to_string());
+
+    out.push(
+        "<tr><td class=\"label\">worker</td><td class=\"label\">heartbeat</td><td class=\"label\">restarts</td><td class=\"label\">last error</td></tr>"
+            .to_string(),
+    );
+
+    for (state, restarts, stale) in workers.iter() {
+        out.push(
+            format!(
+                "<tr><td>{}</td><td>{}{}</td><td>{}</td><td>{}</td></tr>",
+                escape_html(&state.worker),
+                format_time(state.heartbeat_at),
+                if *stale {
+                    format!(
+                        " <span class=\"stale\">stale, older than {}s</span>",
+                        stale_after,
+                    )
+                } else {
+                    "".to_string()
+                },
+                restarts,
+                state.last_error
+                    .as_ref()
+                    .map(|error|
+                        format!(
+                            "{} <span class=\"note\">{}</span>",
+                            escape_html(&error.error),
+                            format_time(Some(error.at)),
+                        )
+                    )
+                    .unwrap_or("-".to_string()),
+            ),
+        );
+    }
+
+    out.push("</tbody></table>".to_string());
+
+    out.push("<h3>ingest errors</h3>".to_string());
+
+    if ingest_errors.is_empty() {
+        out.push("<p class=\"note\">none recorded</p>".to_string());
+    } else {
+        out.push("<table class=\"info\"><tbody>".to_string());
+
+        out.push(
+            "<tr><td class=\"label\">time</td><td class=\"label\">stage</td><td class=\"label\">message</td><td class=\"label\">error</td></tr>"
+                .to_string(),
+        );
+
+        for sample in ingest_errors.iter() {
+            out.push(
+                format!(
+                    "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                    format_time(Some(sample.time)),
+                    escape_html(&sample.stage),
+                    match (&sample.chat_id, &sample.message_id) {
+                        (Some(chat_id), Some(message_id)) =>
+                            format!(
+                                "<a href=\"/chat/{}/info\">{}</a> #{}",
+                                escape_html(chat_id),
+                                escape_html(chat_id),
+                                escape_html(message_id),
+                            ),
+                        (Some(chat_id), None) => escape_html(chat_id),
+                        _ => "-".to_string(),
+                    },
+                    escape_html(&sample.error),
+                ),
+            );
+        }
+
+        out.push("</tbody></table>".to_string());
+    }
+
+    out.push("</div>".to_string());
+
+    let navigation =
+        HeaderBar::new()
+            .with_link(
+                "<- home",
+                Some("/".into()),
+            )
+            .with_title("status")
+            .with_format_link("/admin/status", "json");
+
+    Ok(
+        warp::reply::html(
+            Page::new("status")
+                .with_theme(theme)
+                .with_header(navigation)
+                .with_body(out.join(""))
+                .render(),
+        ).into_response()
+    )
+}
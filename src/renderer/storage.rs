@@ -0,0 +1,239 @@
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use rocksdb::{DBWithThreadMode, MultiThreaded};
+use serde_json::json;
+use warp::http::StatusCode;
+use warp::Reply;
+
+use crate::components::header::HeaderBar;
+use crate::components::page::{Page, Theme};
+use crate::config::get_search_index;
+use crate::privacy::Viewer;
+use crate::renderer::error::error_page;
+use crate::search_index::search_index_counter_key;
+use crate::storage::{lock_db, ReadStore, Storage};
+use crate::storage_stats::{approximate_db_size, build_storage_counter_key, FILE_KINDS, find_storage_counters, get_storage_counter, STORAGE_STATS_REBUILT_KEY, StorageCounter};
+use crate::utils::{escape_html, NameCache};
+
+pub fn format_bytes(
+    bytes: u64,
+) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", value, units[unit]),
+    }
+}
+
+pub async fn storage(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    out_format: &'static str,
+    viewer: Viewer,
+    theme: Theme,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if !viewer.admin {
+        return Ok(
+            error_page(
+                StatusCode::FORBIDDEN,
+                "admin token required",
+                theme,
+            ),
+        );
+    }
+
+    let dbi = lock_db(&db);
+
+    let view = dbi.read_view();
+
+    let db_size = approximate_db_size(&dbi);
+
+    let messages = find_storage_counters(&view, "chat_messages");
+    let chat_files = find_storage_counters(&view, "chat_files");
+
+    // chats sorted by everything they take up, biggest first
+    let mut chats =
+        messages
+            .iter()
+            .map(|(chat_id, counter)|
+                (
+                    chat_id.clone(),
+                    counter.clone(),
+                    chat_files
+                        .iter()
+                        .find(|(id, _)| id == chat_id)
+                        .map(|(_, counter)| counter.clone())
+                        .unwrap_or_default(),
+                )
+            )
+            .collect::<Vec<(String, StorageCounter, StorageCounter)>>();
+
+    chats.sort_by_key(|(_, messages, files)| std::cmp::Reverse(messages.bytes + files.bytes));
+
+    let files =
+        FILE_KINDS
+            .iter()
+            .map(|kind| (*kind, get_storage_counter(&view, &build_storage_counter_key("files", kind))))
+            .collect::<Vec<(&str, StorageCounter)>>();
+
+    let orphaned = get_storage_counter(&view, &build_storage_counter_key("orphaned_files", "all"));
+
+    let search_index = get_storage_counter(&view, &search_index_counter_key());
+
+    let rebuilt_at =
+        view.get(STORAGE_STATS_REBUILT_KEY)
+            .ok()
+            .flatten()
+            .map(|time| String::from_utf8(time).ok())
+            .flatten()
+            .map(|time| time.parse::<i64>().ok())
+            .flatten();
+
+    if out_format == "json" {
+        return Ok(
+            warp::reply::json(
+                &json!({
+                    "status": "ok",
+                    "error": false,
+                    "data": {
+                        "db_size": db_size,
+                        "chats": chats
+                            .iter()
+                            .map(|(chat_id, messages, files)|
+                                json!({
+                                    "chat_id": chat_id,
+                                    "messages": messages.count,
+                                    "message_bytes": messages.bytes,
+                                    "files": files.count,
+                                    "file_bytes": files.bytes,
+                                })
+                            )
+                            .collect::<Vec<_>>(),
+                        "files": files
+                            .iter()
+                            .map(|(kind, counter)|
+                                json!({
+                                    "type": kind,
+                                    "count": counter.count,
+                                    "bytes": counter.bytes,
+                                })
+                            )
+                            .collect::<Vec<_>>(),
+                        "orphaned_files": {
+                            "count": orphaned.count,
+                            "bytes": orphaned.bytes,
+                        },
+                        "search_index": {
+                            "enabled": get_search_index(),
+                            "postings": search_index.count,
+                            "bytes": search_index.bytes,
+                        },
+                        "rebuilt_at": rebuilt_at,
+                    },
+                }),
+            ).into_response(),
+        );
+    }
+
+    let mut names = NameCache::new(&view);
+
+    let mut out =
+        vec!(
+            "<div class=\"info\"><table class=\"info\"><tbody>".to_string(),
+            format!(
+                "<tr><td class=\"label\">database</td><td>{} (approximate)</td></tr>",
+                format_bytes(db_size),
+            ),
+        );
+
+    for (kind, counter) in files.iter() {
+        out.push(
+            format!(
+                "<tr><td class=\"label\">{} files</td><td>{} in {} blobs</td></tr>",
+                kind,
+                format_bytes(counter.bytes),
+                counter.count,
+            ),
+        );
+    }
+
+    let rebuilt_note =
+        rebuilt_at
+            .map(|time| NaiveDateTime::from_timestamp_opt(time, 0))
+            .flatten()
+            .map(|time| DateTime::<Utc>::from_utc(time, Utc))
+            .map(|time| format!("as of {}", time.format("%Y-%m-%d %H:%M:%S")))
+            .unwrap_or("never counted, run <code>minuteman stats --rebuild</code>".to_string());
+
+    out.push(
+        format!(
+            "<tr><td class=\"label\">orphaned files</td><td>{} in {} blobs <span class=\"note\">{}</span></td></tr>",
+            format_bytes(orphaned.bytes),
+            orphaned.count,
+            rebuilt_note,
+        ),
+    );
+
+    out.push(
+        format!(
+            "<tr><td class=\"label\">search index</td><td>{} in {} postings <span class=\"note\">{}</span></td></tr>",
+            format_bytes(search_index.bytes),
+            search_index.count,
+            if get_search_index() { "kept up to date" } else { "disabled" },
+        ),
+    );
+
+    out.push("</tbody></table>".to_string());
+
+    out.push("<h3>chats</h3><table class=\"info\"><tbody>".to_string());
+
+    out.push(
+        "<tr><td class=\"label\">chat</td><td class=\"label\">messages</td><td class=\"label\">files</td><td class=\"label\">total</td></tr>"
+            .to_string(),
+    );
+
+    for (chat_id, messages, files) in chats.iter() {
+        out.push(
+            format!(
+                "<tr><td><a href=\"/chat/{}/info\">{}</a></td><td>{} ({})</td><td>{} ({})</td><td>{}</td></tr>",
+                chat_id,
+                escape_html(&names.chat_name(chat_id)),
+                messages.count,
+                format_bytes(messages.bytes),
+                files.count,
+                format_bytes(files.bytes),
+                format_bytes(messages.bytes + files.bytes),
+            ),
+        );
+    }
+
+    out.push("</tbody></table></div>".to_string());
+
+    let navigation =
+        HeaderBar::new()
+            .with_link(
+                "<- home",
+                Some("/".into()),
+            )
+            .with_title("storage")
+            .with_format_link("/admin/storage", "json");
+
+    Ok(
+        warp::reply::html(
+            Page::new("storage")
+                .with_theme(theme)
+                .with_header(navigation)
+                .with_body(out.join(""))
+                .render(),
+        ).into_response()
+    )
+}
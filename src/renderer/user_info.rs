@@ -0,0 +1,145 @@
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use rocksdb::{DBWithThreadMode, MultiThreaded};
+use warp::http::StatusCode;
+use warp::Reply;
+
+use crate::some_or_continue;
+use crate::components::header::HeaderBar;
+use crate::components::page::{Page, Theme};
+use crate::privacy::Viewer;
+use crate::renderer::error::error_page;
+use crate::storage::{get_user_meta, lock_db, Storage};
+use crate::utils::{escape_html, find_user_meta_history, resolve_user, resolve_user_meta};
+
+pub async fn user_info(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    user_id: String,
+    viewer: Viewer,
+    theme: Theme,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if !viewer.can_see_users() {
+        return Ok(
+            error_page(
+                StatusCode::NOT_FOUND,
+                "user profiles aren't public on this archive",
+                theme,
+            ),
+        );
+    }
+
+    let dbi = lock_db(&db);
+
+    let view = dbi.read_view();
+
+    let user_name =
+        resolve_user(
+            &view,
+            &user_id,
+            false,
+        );
+
+    let user_meta = get_user_meta(&view, &user_id);
+
+    let mut out =
+        vec!(
+            "<div class=\"info\">".to_string(),
+            format!(
+                "<img class=\"avatar\" src=\"/file/user/{}?fallback=1\"/>",
+                &user_id,
+            ),
+            "<table class=\"info\"><tbody>".to_string(),
+        );
+
+    let mut rows = vec!(
+        ("id", user_id.clone()),
+    );
+
+    if let Some(ref meta) = user_meta {
+        rows.push(
+            (
+                "name",
+                escape_html(
+                    &format!(
+                        "{} {}",
+                        meta.first_name,
+                        meta.last_name.clone().unwrap_or_default(),
+                    ).trim(),
+                ),
+            ),
+        );
+
+        if let Some(ref username) = meta.username {
+            rows.push(("username", format!("@{}", escape_html(username))));
+        }
+
+        if meta.is_bot {
+            rows.push(("bot", "yes".to_string()));
+        }
+    }
+
+    for (label, value) in rows {
+        out.push(
+            format!(
+                "<tr><td class=\"label\">{}</td><td>{}</td></tr>",
+                label,
+                value,
+            ),
+        );
+    }
+
+    out.push("</tbody></table>".to_string());
+
+    out.push("<h3>previous names</h3><ul class=\"history\">".to_string());
+
+    let history = find_user_meta_history(&view, &user_id);
+
+    // newest first, each record was in use until it got replaced
+    for (replaced, meta) in history.iter().rev() {
+        let time = some_or_continue!(NaiveDateTime::from_timestamp_opt(*replaced, 0));
+        let time: DateTime<Utc> = DateTime::from_utc(time, Utc);
+
+        out.push(
+            format!(
+                "<li><b>{}</b>{} <span class=\"note\">until {}</span></li>",
+                escape_html(&resolve_user_meta(meta)),
+                meta.username
+                    .as_ref()
+                    .map(|_|
+                        format!(
+                            " ({} {})",
+                            escape_html(&meta.first_name),
+                            escape_html(&meta.last_name.clone().unwrap_or_default()),
+                        )
+                    )
+                    .unwrap_or_default(),
+                time.format("%Y-%m-%d %H:%M"),
+            ),
+        );
+    }
+
+    if history.is_empty() {
+        out.push("<li><span class=\"note\">No name changes recorded.</span></li>".to_string());
+    }
+
+    out.push("</ul></div>".to_string());
+
+    Ok(
+        warp::reply::html(
+            Page::new(format!("{} - user", &user_name))
+                .with_theme(theme)
+                .with_header(
+                    HeaderBar::new()
+                        .with_link(
+                            "<- home",
+                            Some("/".into()),
+                        )
+                        .with_title(&user_name)
+                        .with_active("profile"),
+                )
+                .with_body(out.join(""))
+                .render(),
+        ).into_response(),
+    )
+}
@@ -0,0 +1,453 @@
+use std::collections::{BTreeSet, HashSet};
+use std::time::Instant;
+
+use rocksdb::{DBIteratorWithThreadMode, DBWithThreadMode, Direction, IteratorMode, MultiThreaded, ReadOptions, WriteBatch};
+
+use crate::{ok_or_continue, some_or_continue};
+use crate::storage::{PendingWrites, ReadStore};
+use crate::storage_stats::{build_storage_counter_key, prefix_iter, StorageCounter};
+use crate::workers::telegram_handler::{LogItem, LogItemMessageEntityKind, MessageSlot, parse_message_key};
+
+// longer words are cut, nobody searches for them anyway and they'd only
+// blow up the keys
+const MAX_TOKEN_CHARS: usize = 64;
+
+/// Counter of the posting keys, bytes being the size of the keys.
+pub fn search_index_counter_key() -> String {
+    build_storage_counter_key("search_index", "all")
+}
+
+/// Posting of `token` for the log item at `chat:{chat_id}:{slot}`. Log
+/// items are keyed by chat and slot alone, so that's all a posting needs to
+/// find its message again.
+pub fn build_posting_key(
+    chat_id: &str,
+    token: &str,
+    slot: MessageSlot,
+) -> String {
+    format!(
+        "ft:{}:{}:{}",
+        chat_id,
+        token,
+        slot,
+    )
+}
+
+// tokens may start with any utf-8 byte, `prefix_iter`'s \x7f bound would
+// skip everything that isn't ascii
+fn posting_iter<'a, S: ReadStore>(
+    db: &'a S,
+    prefix: &str,
+) -> DBIteratorWithThreadMode<'a, DBWithThreadMode<MultiThreaded>> {
+    let mut opts = ReadOptions::default();
+
+    let lower_bound = prefix.as_bytes().to_vec();
+
+    let mut upper_bound = lower_bound.clone();
+    upper_bound.push(0xff);
+
+    opts.set_iterate_lower_bound(lower_bound.clone());
+    opts.set_iterate_upper_bound(upper_bound);
+
+    db.iterator_opt(IteratorMode::From(&lower_bound, Direction::Forward), opts)
+}
+
+/// Text a log item can be found by: message text along with the targets of
+/// its text links, or a media caption.
+pub fn searchable_text(
+    item: &LogItem,
+) -> Option<String> {
+    match item {
+        LogItem::Message { text, entities, .. } => {
+            let mut out = text.clone();
+
+            for entity in entities.iter() {
+                if let LogItemMessageEntityKind::TextLink(url) = &entity.kind {
+                    out.push('\n');
+                    out.push_str(url);
+                }
+            }
+
+            Some(out)
+        }
+        LogItem::Media { caption, .. } =>
+            caption.clone(),
+        _ => None,
+    }
+}
+
+// scripts written without spaces between words
+fn is_cjk(
+    c: char,
+) -> bool {
+    matches!(
+        c as u32,
+        0x3040..=0x30ff // hiragana, katakana
+        | 0x3400..=0x4dbf // cjk extension a
+        | 0x4e00..=0x9fff // cjk unified ideographs
+        | 0xac00..=0xd7af // hangul syllables
+        | 0xf900..=0xfaff // cjk compatibility ideographs
+        | 0x20000..=0x2fa1f // cjk extensions b and up
+    )
+}
+
+fn push_token(
+    tokens: &mut BTreeSet<String>,
+    word: &[char],
+) {
+    if word.len() < 2 {
+        return;
+    }
+
+    tokens.insert(
+        word.iter()
+            .take(MAX_TOKEN_CHARS)
+            .collect::<String>(),
+    );
+}
+
+/// Lowercased words of `text`, split at anything that isn't alphanumeric.
+/// Runs of CJK characters become overlapping bigrams (or the character on
+/// its own), which is crude but finds most words without a dictionary.
+/// Single letters aren't worth indexing.
+pub fn tokenize(
+    text: &str,
+) -> BTreeSet<String> {
+    let mut tokens = BTreeSet::<String>::new();
+
+    let mut word = Vec::<char>::new();
+    let mut cjk_run = Vec::<char>::new();
+
+    let flush_cjk = |tokens: &mut BTreeSet<String>, run: &mut Vec<char>| {
+        match run.len() {
+            0 => {}
+            1 => {
+                tokens.insert(run[0].to_string());
+            }
+            _ => {
+                for pair in run.windows(2) {
+                    tokens.insert(pair.iter().collect());
+                }
+            }
+        }
+
+        run.clear();
+    };
+
+    for c in text.chars().flat_map(|c| c.to_lowercase()) {
+        if is_cjk(c) {
+            push_token(&mut tokens, &word);
+            word.clear();
+
+            cjk_run.push(c);
+        } else if c.is_alphanumeric() {
+            flush_cjk(&mut tokens, &mut cjk_run);
+
+            word.push(c);
+        } else {
+            push_token(&mut tokens, &word);
+            word.clear();
+
+            flush_cjk(&mut tokens, &mut cjk_run);
+        }
+    }
+
+    push_token(&mut tokens, &word);
+    flush_cjk(&mut tokens, &mut cjk_run);
+
+    tokens
+}
+
+/// The tokens of a query the index can answer. A CJK character only has a
+/// posting of its own where it stood alone, within a longer run it's part
+/// of bigrams, so a query's lone ones are left to the check against the
+/// text. Queries left without tokens have to scan.
+pub fn query_tokens(
+    q: &str,
+) -> BTreeSet<String> {
+    let mut tokens = tokenize(q);
+
+    tokens.retain(|token| {
+        let mut chars = token.chars();
+
+        !matches!((chars.next(), chars.next()), (Some(c), None) if is_cjk(c))
+    });
+
+    tokens
+}
+
+fn item_tokens(
+    item: Option<&LogItem>,
+) -> BTreeSet<String> {
+    item.map(searchable_text)
+        .flatten()
+        .map(|text| tokenize(&text))
+        .unwrap_or_default()
+}
+
+/// Moves the postings of the log item at `slot` from what `previous`
+/// contained to what `current` does, either may be None when there is no
+/// log item (anymore). Postings are added and removed in one batch along
+/// with the index size counter.
+pub fn update_postings(
+    db: &DBWithThreadMode<MultiThreaded>,
+    chat_id: &str,
+    slot: MessageSlot,
+    previous: Option<&LogItem>,
+    current: Option<&LogItem>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut writes = PendingWrites::new();
+
+    queue_postings(db, &mut writes, chat_id, slot, previous, current)?;
+
+    writes.commit(db)
+}
+
+/// `update_postings` queued on `writes`, for when the postings have to be
+/// stored along with the log item itself.
+pub fn queue_postings(
+    db: &DBWithThreadMode<MultiThreaded>,
+    writes: &mut PendingWrites,
+    chat_id: &str,
+    slot: MessageSlot,
+    previous: Option<&LogItem>,
+    current: Option<&LogItem>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let previous = item_tokens(previous);
+    let current = item_tokens(current);
+
+    if previous == current {
+        return Ok(());
+    }
+
+    // the counter goes by the size of the keys, the values are empty
+    let counter_key = search_index_counter_key();
+
+    for token in previous.difference(&current) {
+        let key = build_posting_key(chat_id, token, slot);
+
+        // redactions get here whether the message was ever indexed or not
+        if db.get(&key)?.is_none() {
+            continue;
+        }
+
+        writes.delete(&key);
+        writes.adjust_counter(&counter_key, Some(key.len()), None);
+    }
+
+    for token in current.difference(&previous) {
+        let key = build_posting_key(chat_id, token, slot);
+
+        writes.put(&key, b"\0");
+        writes.adjust_counter(&counter_key, None, Some(key.len()));
+    }
+
+    Ok(())
+}
+
+/// Moves the postings `item` has at `from` over to `to`, queued on `writes`
+/// along with the index size counter. Tokens that weren't indexed aren't
+/// added, the index may be off or still being rebuilt.
+pub fn queue_moved_postings(
+    db: &DBWithThreadMode<MultiThreaded>,
+    writes: &mut PendingWrites,
+    chat_id: &str,
+    from: MessageSlot,
+    to: MessageSlot,
+    item: &LogItem,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let counter_key = search_index_counter_key();
+
+    for token in item_tokens(Some(item)).iter() {
+        let previous = build_posting_key(chat_id, token, from);
+
+        if db.get(&previous)?.is_none() {
+            continue;
+        }
+
+        let key = build_posting_key(chat_id, token, to);
+
+        writes.delete(&previous);
+        writes.put(&key, b"\0");
+        writes.adjust_counter(&counter_key, Some(previous.len()), Some(key.len()));
+    }
+
+    Ok(())
+}
+
+/// Slots of the log items in `chat_id` between `time_start`
+/// (inclusive) and `time_end` (exclusive) that contain every one of
+/// `tokens`, newest first. The matches still have to be verified against
+/// the actual text, a phrase's words may be all over the message. None
+/// when `deadline` passed before every posting was read, common words can
+/// have a lot of them.
+pub fn find_postings(
+    db: &impl ReadStore,
+    chat_id: &str,
+    tokens: &BTreeSet<String>,
+    time_start: i64,
+    time_end: i64,
+    deadline: Instant,
+) -> Option<Vec<MessageSlot>> {
+    let mut matches: Option<HashSet<MessageSlot>> = None;
+
+    for token in tokens.iter() {
+        let prefix = format!("ft:{}:{}:", chat_id, token);
+
+        let mut opts = ReadOptions::default();
+
+        // unix timestamps all have the same number of digits, so the range
+        // can be seeked to directly
+        let lower_bound = format!("{}{}", prefix, time_start).as_bytes().to_vec();
+        let upper_bound = format!("{}{}", prefix, time_end).as_bytes().to_vec();
+
+        opts.set_iterate_lower_bound(lower_bound.clone());
+        opts.set_iterate_upper_bound(upper_bound);
+
+        let mut found = HashSet::<MessageSlot>::new();
+
+        for (key, _) in db.iterator_opt(IteratorMode::From(&lower_bound, Direction::Forward), opts) {
+            if Instant::now() > deadline {
+                return None;
+            }
+
+            let key = ok_or_continue!(String::from_utf8(key.to_vec()));
+
+            found.insert(
+                some_or_continue!(MessageSlot::parse(&key[prefix.len()..])),
+            );
+        }
+
+        matches =
+            Some(
+                match matches {
+                    Some(matches) => matches.intersection(&found).copied().collect(),
+                    None => found,
+                },
+            );
+
+        if matches.as_ref().map(|matches| matches.is_empty()).unwrap_or(false) {
+            break;
+        }
+    }
+
+    let mut matches = matches.unwrap_or_default().into_iter().collect::<Vec<MessageSlot>>();
+
+    matches.sort_unstable_by(|a, b| b.cmp(a));
+
+    Some(matches)
+}
+
+/// Recounts the posting keys into the index size counter.
+pub fn recount_search_index(
+    db: &DBWithThreadMode<MultiThreaded>,
+) -> Result<StorageCounter, Box<dyn std::error::Error>> {
+    let mut counter = StorageCounter::default();
+
+    for (key, _) in posting_iter(db, "ft:") {
+        counter.adjust(None, Some(key.len()));
+    }
+
+    db.put(search_index_counter_key(), serde_json::to_string(&counter)?)?;
+
+    Ok(counter)
+}
+
+/// Throws away the postings of one chat (or all of them) and indexes its
+/// stored log items again. Returns the number of log items indexed.
+pub fn rebuild_search_index(
+    db: &DBWithThreadMode<MultiThreaded>,
+    chat_id: Option<&str>,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let (posting_prefix, message_prefix) =
+        match chat_id {
+            Some(chat_id) => (format!("ft:{}:", chat_id), format!("chat:{}:", chat_id)),
+            None => ("ft:".to_string(), "chat:".to_string()),
+        };
+
+    let stale =
+        posting_iter(db, &posting_prefix)
+            .map(|(key, _)| key)
+            .collect::<Vec<Box<[u8]>>>();
+
+    for keys in stale.chunks(10_000) {
+        let mut batch = WriteBatch::default();
+
+        for key in keys.iter() {
+            batch.delete(key);
+        }
+
+        db.write(batch)?;
+    }
+
+    let mut indexed = 0usize;
+    let mut batch = WriteBatch::default();
+
+    for (key, val) in prefix_iter(db, &message_prefix) {
+        let key = ok_or_continue!(String::from_utf8(key.to_vec()));
+
+        // only log items, not chat:meta:* and friends
+        let (chat_id, slot) = some_or_continue!(parse_message_key(&key));
+        let item = ok_or_continue!(serde_json::from_slice::<LogItem>(&val));
+
+        for token in item_tokens(Some(&item)).iter() {
+            batch.put(build_posting_key(chat_id, token, slot), &b"\0");
+        }
+
+        indexed += 1;
+
+        if batch.len() >= 10_000 {
+            db.write(std::mem::take(&mut batch))?;
+        }
+    }
+
+    db.write(batch)?;
+
+    recount_search_index(db)?;
+
+    Ok(indexed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn open_db(name: &str) -> DBWithThreadMode<MultiThreaded> {
+        let path =
+            std::env::temp_dir()
+                .join(format!("minuteman-test-{}-{}", name, std::process::id()));
+
+        let _ = std::fs::remove_dir_all(&path);
+
+        DBWithThreadMode::<MultiThreaded>::open_default(path).unwrap()
+    }
+
+    fn tokens(words: &[&str]) -> BTreeSet<String> {
+        words.iter().map(|word| word.to_string()).collect()
+    }
+
+    #[test]
+    fn lone_cjk_characters_are_left_to_the_text_check() {
+        assert_eq!(tokenize("東京 東"), tokens(&["東", "東京"]));
+
+        assert_eq!(query_tokens("東"), tokens(&[]));
+        assert_eq!(query_tokens("東 tokyo"), tokens(&["tokyo"]));
+        assert_eq!(query_tokens("東京"), tokens(&["東京"]));
+    }
+
+    #[test]
+    fn postings_give_up_past_the_deadline() {
+        let db = open_db("search-index-deadline");
+
+        let slot = MessageSlot::new(1_600_000_000, 1);
+
+        db.put(build_posting_key("-1002631000", "hello", slot), b"\0").unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(60);
+
+        assert_eq!(find_postings(&db, "-1002631000", &tokens(&["hello"]), 1_500_000_000, 1_700_000_000, deadline), Some(vec![slot]));
+        assert_eq!(find_postings(&db, "-1002631000", &tokens(&["hello"]), 1_500_000_000, 1_700_000_000, Instant::now() - Duration::from_secs(1)), None);
+    }
+}
@@ -0,0 +1,307 @@
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use rocksdb::{DBWithThreadMode, MultiThreaded};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::MinutemanError;
+use crate::config::get_share_secret;
+use crate::privacy::{constant_time_eq, Viewer};
+use crate::storage::ReadStore;
+use crate::utils::get_file_meta;
+
+/// A share link handed out through `POST /admin/share`, kept so that it can
+/// be revoked before it expires.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShareToken {
+    pub id: String,
+    pub chat_id: String,
+    pub expires: i64,
+    pub created_at: i64,
+    #[serde(default)]
+    pub revoked_at: Option<i64>,
+}
+
+pub fn build_share_key(
+    id: &str,
+) -> String {
+    format!(
+        "share:{}",
+        id,
+    )
+}
+
+fn sign(
+    secret: &str,
+    id: &str,
+    chat_id: &str,
+    expires: i64,
+) -> Option<String> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).ok()?;
+
+    mac.update(format!("{}:{}:{}", id, chat_id, expires).as_bytes());
+
+    Some(
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect(),
+    )
+}
+
+/// Stores a new share token for the chat and returns it, formatted as
+/// `{id}.{chat_id}.{expires}.{signature}`. Needs `MINUTEMAN_SHARE_SECRET`.
+pub fn create_share(
+    db: &DBWithThreadMode<MultiThreaded>,
+    chat_id: &str,
+    expires: i64,
+) -> Result<(ShareToken, String), MinutemanError> {
+    let secret =
+        get_share_secret()
+            .ok_or_else(|| MinutemanError::Other("MINUTEMAN_SHARE_SECRET isn't set".to_string()))?;
+
+    let now = Utc::now();
+
+    let id =
+        Sha256::digest(format!("{}:{}:{}", chat_id, expires, now.timestamp_nanos()).as_bytes())[..8]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+
+    let signature =
+        sign(&secret, &id, chat_id, expires)
+            .ok_or_else(|| MinutemanError::Other("invalid share secret".to_string()))?;
+
+    let share =
+        ShareToken {
+            id: id.clone(),
+            chat_id: chat_id.to_string(),
+            expires,
+            created_at: now.timestamp(),
+            revoked_at: None,
+        };
+
+    db.put(
+        build_share_key(&id),
+        serde_json::to_string(&share).map_err(|err| MinutemanError::ParseError(format!("{:?}", err)))?,
+    ).map_err(|err| MinutemanError::DBError(format!("{:?}", err)))?;
+
+    let token = format!("{}.{}.{}.{}", id, chat_id, expires, signature);
+
+    Ok((share, token))
+}
+
+pub fn get_share(
+    db: &impl ReadStore,
+    id: &str,
+) -> Option<ShareToken> {
+    db.get(build_share_key(id))
+        .ok()
+        .flatten()
+        .map(|share| serde_json::from_slice::<ShareToken>(&share).ok())
+        .flatten()
+}
+
+/// Marks a share token revoked, returns it or None when it doesn't exist.
+pub fn revoke_share(
+    db: &DBWithThreadMode<MultiThreaded>,
+    id: &str,
+) -> Result<Option<ShareToken>, MinutemanError> {
+    let mut share =
+        match get_share(db, id) {
+            Some(share) => share,
+            None => return Ok(None),
+        };
+
+    share.revoked_at.get_or_insert(Utc::now().timestamp());
+
+    db.put(
+        build_share_key(id),
+        serde_json::to_string(&share).map_err(|err| MinutemanError::ParseError(format!("{:?}", err)))?,
+    ).map_err(|err| MinutemanError::DBError(format!("{:?}", err)))?;
+
+    Ok(Some(share))
+}
+
+/// The chat a share token grants access to, None unless the signature
+/// matches, the token hasn't expired and it's stored and not revoked.
+/// Tokens with characters `create_share` doesn't use are turned away first.
+pub fn verify_share(
+    db: &impl ReadStore,
+    token: &str,
+) -> Option<String> {
+    let well_formed =
+        !token.is_empty()
+            && token
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-');
+
+    if !well_formed {
+        return None;
+    }
+
+    let secret = get_share_secret()?;
+
+    let parts = token.split('.').collect::<Vec<&str>>();
+
+    if parts.len() != 4 {
+        return None;
+    }
+
+    let (id, chat_id, expires, signature) = (parts[0], parts[1], parts[2].parse::<i64>().ok()?, parts[3]);
+
+    let expected = sign(&secret, id, chat_id, expires)?;
+
+    if !constant_time_eq(expected.as_bytes(), signature.as_bytes()) || expires <= Utc::now().timestamp() {
+        return None;
+    }
+
+    let share = get_share(db, id)?;
+
+    if share.revoked_at.is_some() || share.chat_id != chat_id || share.expires != expires {
+        return None;
+    }
+
+    Some(share.chat_id)
+}
+
+/// Whether the viewer may request the given path. A share token adds the
+/// pages of its chat and the files its messages refer to, everything else
+/// needs `Viewer::can_browse`.
+pub fn can_access_path(
+    db: &impl ReadStore,
+    path: &str,
+    viewer: &Viewer,
+) -> bool {
+    let segments =
+        path.trim_start_matches('/')
+            .split('/')
+            .collect::<Vec<&str>>();
+
+    match segments.as_slice() {
+        ["assets", ..] | ["robots.txt"] => true,
+        // the username routes only redirect to the id based ones
+        ["chat", chat_ref, ..] if chat_ref.starts_with('@') => true,
+        ["chat", chat_id, ..] => viewer.can_see_chat(chat_id),
+        ["file", "user", ..] => viewer.can_browse(),
+        ["file", "chat_photo", chat_id, ..] => viewer.can_see_chat(chat_id),
+        ["file", _, file_id, ..] =>
+            viewer.can_browse()
+                || viewer.share
+                .as_ref()
+                .map(|chat_id|
+                    get_file_meta(db, file_id)
+                        .map(|meta| meta.message_key)
+                        .flatten()
+                        .map(|key| key.starts_with(&format!("chat:{}:", chat_id)))
+                        .unwrap_or(false)
+                )
+                .unwrap_or(false),
+        _ => viewer.can_browse(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::workers::telegram_handler::{FileMeta, store_file_meta};
+
+    use super::*;
+
+    fn open_db(name: &str) -> DBWithThreadMode<MultiThreaded> {
+        std::env::set_var("MINUTEMAN_SHARE_SECRET", "share-test-secret");
+
+        let path =
+            std::env::temp_dir()
+                .join(format!("minuteman-test-{}-{}", name, std::process::id()));
+
+        let _ = std::fs::remove_dir_all(&path);
+
+        DBWithThreadMode::<MultiThreaded>::open_default(path).unwrap()
+    }
+
+    #[test]
+    fn signatures_depend_on_every_part_and_the_secret() {
+        let signature = sign("secret", "id", "-1001", 100).unwrap();
+
+        assert_eq!(signature.len(), 64);
+        assert_eq!(sign("secret", "id", "-1001", 100), Some(signature.clone()));
+
+        for other in [
+            sign("other", "id", "-1001", 100),
+            sign("secret", "di", "-1001", 100),
+            sign("secret", "id", "-1002", 100),
+            sign("secret", "id", "-1001", 101),
+        ] {
+            assert_ne!(other, Some(signature.clone()));
+        }
+    }
+
+    #[test]
+    fn created_tokens_verify_until_revoked() {
+        let db = open_db("share-verify");
+
+        let (share, token) = create_share(&db, "-1001", Utc::now().timestamp() + 3600).unwrap();
+
+        assert_eq!(verify_share(&db, &token), Some("-1001".to_string()));
+
+        assert_eq!(revoke_share(&db, &share.id).unwrap().map(|share| share.revoked_at.is_some()), Some(true));
+        assert_eq!(verify_share(&db, &token), None);
+
+        assert!(revoke_share(&db, "unknown").unwrap().is_none());
+    }
+
+    #[test]
+    fn tampered_expired_and_malformed_tokens_dont_verify() {
+        let db = open_db("share-tampered");
+
+        let (_, token) = create_share(&db, "-1001", Utc::now().timestamp() + 3600).unwrap();
+
+        let parts = token.split('.').collect::<Vec<&str>>();
+
+        // another chat under the same signature
+        assert_eq!(verify_share(&db, &format!("{}.-1002.{}.{}", parts[0], parts[2], parts[3])), None);
+        assert_eq!(verify_share(&db, &token[..token.len() - 1]), None);
+        assert_eq!(verify_share(&db, &format!("{}.extra", token)), None);
+
+        for token in ["", "a;b", &format!("{} ", token), &format!("{}%0d", token), "<script>"] {
+            assert_eq!(verify_share(&db, token), None, "{}", token);
+        }
+
+        // signed and stored, but past its expiry
+        let (_, expired) = create_share(&db, "-1001", Utc::now().timestamp() - 1).unwrap();
+
+        assert_eq!(verify_share(&db, &expired), None);
+    }
+
+    #[test]
+    fn share_viewers_reach_their_chat_and_its_files_on_top_of_the_public() {
+        let db = open_db("share-paths");
+
+        let meta =
+            |message_key: &str|
+                FileMeta {
+                    message_key: Some(message_key.to_string()),
+                    ..FileMeta::default()
+                };
+
+        store_file_meta(&db, "shared-file", &meta("chat:-1001:2020-09-13:1")).unwrap();
+        store_file_meta(&db, "other-file", &meta("chat:-1002:2020-09-13:1")).unwrap();
+
+        let viewer = Viewer::default().with_share(Some("-1001".to_string()));
+
+        for path in ["/chat/-1001/2020-09-13", "/chat/@someone", "/file/image/shared-file", "/file/chat_photo/-1001", "/assets/main.css", "/robots.txt"] {
+            assert!(can_access_path(&db, path, &viewer), "{}", path);
+        }
+
+        // whatever the public gets, with or without `MINUTEMAN_REQUIRE_AUTH`
+        for path in ["/chat/-1002/2020-09-13", "/file/image/other-file", "/file/image/unknown", "/file/user/1", "/file/chat_photo/-1002", "/search", "/"] {
+            assert_eq!(can_access_path(&db, path, &viewer), can_access_path(&db, path, &Viewer::default()), "{}", path);
+        }
+
+        let admin = Viewer { admin: true, share: None };
+
+        assert!(can_access_path(&db, "/chat/-1002/2020-09-13", &admin));
+        assert!(can_access_path(&db, "/file/image/other-file", &admin));
+    }
+}
@@ -0,0 +1,496 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use rocksdb::{DBIteratorWithThreadMode, DBWithThreadMode, IteratorMode, MultiThreaded, Options, ReadOptions, SnapshotWithThreadMode, WriteBatch};
+use serde::de::DeserializeOwned;
+
+use crate::META_CACHE_TTL;
+use crate::config::get_meta_cache_size;
+use crate::metrics::record_cache_lookup;
+use crate::storage_stats::{get_storage_counter, StorageCounter};
+use crate::workers::telegram_handler::{ChatMeta, UserMeta};
+
+/// The reads the lookup helpers need, served either by the live database
+/// or by a `ReadView` pinned to one point in time.
+pub trait ReadStore {
+    fn get<K: AsRef<[u8]>>(
+        &self,
+        key: K,
+    ) -> Result<Option<Vec<u8>>, rocksdb::Error>;
+
+    fn iterator_opt<'b>(
+        &'b self,
+        mode: IteratorMode,
+        opts: ReadOptions,
+    ) -> DBIteratorWithThreadMode<'b, DBWithThreadMode<MultiThreaded>>;
+}
+
+impl ReadStore for DBWithThreadMode<MultiThreaded> {
+    fn get<K: AsRef<[u8]>>(
+        &self,
+        key: K,
+    ) -> Result<Option<Vec<u8>>, rocksdb::Error> {
+        DBWithThreadMode::get(self, key)
+    }
+
+    fn iterator_opt<'b>(
+        &'b self,
+        mode: IteratorMode,
+        opts: ReadOptions,
+    ) -> DBIteratorWithThreadMode<'b, DBWithThreadMode<MultiThreaded>> {
+        DBWithThreadMode::iterator_opt(self, mode, opts)
+    }
+}
+
+// secondaries can't iterate over a snapshot, rocksdb hands out iterators
+// that are empty but for their error. A process opens either the bot's
+// database or a secondary of it, see `open_secondary`
+static SECONDARY: AtomicBool = AtomicBool::new(false);
+
+/// A consistent view of the database for the duration of one request: every
+/// get and iteration sees the data as it was when the view was taken, no
+/// matter what the telegram handler writes in the meantime. The snapshot is
+/// released when the view is dropped.
+///
+/// On a secondary the view reads the database as it is. It only changes on
+/// `catch_up`, which takes the same lock as whoever holds the view.
+pub struct ReadView<'a> {
+    db: &'a DBWithThreadMode<MultiThreaded>,
+    snapshot: Option<SnapshotWithThreadMode<'a, DBWithThreadMode<MultiThreaded>>>,
+}
+
+impl<'a> ReadStore for ReadView<'a> {
+    fn get<K: AsRef<[u8]>>(
+        &self,
+        key: K,
+    ) -> Result<Option<Vec<u8>>, rocksdb::Error> {
+        match self.snapshot {
+            Some(ref snapshot) => snapshot.get(key),
+            None => self.db.get(key),
+        }
+    }
+
+    fn iterator_opt<'b>(
+        &'b self,
+        mode: IteratorMode,
+        opts: ReadOptions,
+    ) -> DBIteratorWithThreadMode<'b, DBWithThreadMode<MultiThreaded>> {
+        match self.snapshot {
+            Some(ref snapshot) => snapshot.iterator_opt(mode, opts),
+            None => self.db.iterator_opt(mode, opts),
+        }
+    }
+}
+
+pub trait Storage {
+    fn read_view(&self) -> ReadView<'_>;
+
+    /// Opens the database at `primary_path`, which another process may have
+    /// open for writing, as a secondary instance. It doesn't take the lock
+    /// and never writes, `secondary_path` only holds its own info log.
+    ///
+    /// A secondary sees the primary as it was when it was opened and stays
+    /// there until `catch_up`, see there.
+    fn open_secondary(
+        primary_path: &str,
+        secondary_path: &str,
+    ) -> Result<Self, rocksdb::Error> where Self: Sized;
+
+    /// Replays what the primary wrote since the secondary was opened or
+    /// last caught up, its write-ahead log included, so nothing the bot
+    /// stored before the call is missing afterwards. Reads in between stay
+    /// on the older state, callers that want current data call this right
+    /// before reading, and not while they hold a `ReadView`, see there.
+    /// Fails on a primary.
+    fn catch_up(&self) -> Result<(), rocksdb::Error>;
+}
+
+impl Storage for DBWithThreadMode<MultiThreaded> {
+    fn read_view(&self) -> ReadView<'_> {
+        ReadView {
+            db: self,
+            snapshot:
+                match SECONDARY.load(Ordering::Relaxed) {
+                    true => None,
+                    false => Some(self.snapshot()),
+                },
+        }
+    }
+
+    fn open_secondary(
+        primary_path: &str,
+        secondary_path: &str,
+    ) -> Result<Self, rocksdb::Error> {
+        let mut opts = Options::default();
+
+        // the primary deletes files once they're compacted away, a secondary
+        // has to hold on to every one it may still read
+        opts.set_max_open_files(-1);
+
+        SECONDARY.store(true, Ordering::Relaxed);
+
+        DBWithThreadMode::open_as_secondary(&opts, primary_path, secondary_path)
+    }
+
+    fn catch_up(&self) -> Result<(), rocksdb::Error> {
+        self.try_catch_up_with_primary()
+    }
+}
+
+/// Locks the shared database. The mutex only serializes access, rocksdb
+/// keeps itself consistent, so a task that panicked while holding it doesn't
+/// leave anything behind that the next one can't use.
+pub fn lock_db(
+    db: &Mutex<DBWithThreadMode<MultiThreaded>>,
+) -> MutexGuard<'_, DBWithThreadMode<MultiThreaded>> {
+    match db.lock() {
+        Ok(dbi) => dbi,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+#[derive(Debug)]
+struct PendingPut {
+    key: String,
+    // None deletes the key
+    value: Option<Vec<u8>>,
+    counter_keys: Vec<String>,
+}
+
+/// Writes that only make sense together, like a log item and the file blobs
+/// it refers to. Nothing reaches the database before `commit`, which applies
+/// all of them, storage counters included, as one atomic batch.
+#[derive(Debug, Default)]
+pub struct PendingWrites {
+    puts: Vec<PendingPut>,
+    // counters that don't go by the size of a value, like the search index's
+    adjustments: Vec<(String, Option<usize>, Option<usize>)>,
+}
+
+impl PendingWrites {
+    pub fn new() -> Self {
+        PendingWrites::default()
+    }
+
+    pub fn put<V: AsRef<[u8]>>(
+        &mut self,
+        key: &str,
+        value: V,
+    ) {
+        self.put_counted(key, value, &[]);
+    }
+
+    /// Queues a put that moves the given storage counters along with it,
+    /// like `put_counted`.
+    pub fn put_counted<V: AsRef<[u8]>>(
+        &mut self,
+        key: &str,
+        value: V,
+        counter_keys: &[String],
+    ) {
+        self.puts.push(
+            PendingPut {
+                key: key.to_string(),
+                value: Some(value.as_ref().to_vec()),
+                counter_keys: counter_keys.to_vec(),
+            },
+        );
+    }
+
+    pub fn delete(
+        &mut self,
+        key: &str,
+    ) {
+        self.puts.push(
+            PendingPut {
+                key: key.to_string(),
+                value: None,
+                counter_keys: vec!(),
+            },
+        );
+    }
+
+    /// Queues a `StorageCounter::adjust` of the counter at `counter_key`.
+    pub fn adjust_counter(
+        &mut self,
+        counter_key: &str,
+        previous_len: Option<usize>,
+        len: Option<usize>,
+    ) {
+        self.adjustments.push((counter_key.to_string(), previous_len, len));
+    }
+
+    /// The value last queued for `key`, if any.
+    pub fn value(
+        &self,
+        key: &str,
+    ) -> Option<&[u8]> {
+        self.puts
+            .iter()
+            .rev()
+            .find(|put| put.key == key)
+            .map(|put| put.value.as_deref())
+            .flatten()
+    }
+
+    pub fn extend(
+        &mut self,
+        other: PendingWrites,
+    ) {
+        self.puts.extend(other.puts);
+        self.adjustments.extend(other.adjustments);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.puts.is_empty() && self.adjustments.is_empty()
+    }
+
+    /// Applies every queued write at once, or none of them when it fails.
+    pub fn commit(
+        self,
+        db: &DBWithThreadMode<MultiThreaded>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut batch = WriteBatch::default();
+
+        // a key written twice only counts once, as it was last written
+        let mut lens = HashMap::<String, Option<usize>>::new();
+        let mut counters = HashMap::<String, StorageCounter>::new();
+
+        for put in self.puts {
+            let previous_len =
+                match lens.get(&put.key) {
+                    Some(len) => *len,
+                    None => db.get(&put.key)?.map(|previous| previous.len()),
+                };
+
+            let len = put.value.as_ref().map(|value| value.len());
+
+            for counter_key in put.counter_keys.iter() {
+                counters
+                    .entry(counter_key.clone())
+                    .or_insert_with(|| get_storage_counter(db, counter_key))
+                    .adjust(previous_len, len);
+            }
+
+            lens.insert(put.key.clone(), len);
+
+            match put.value {
+                Some(value) => batch.put(&put.key, &value),
+                None => batch.delete(&put.key),
+            }
+        }
+
+        for (counter_key, previous_len, len) in self.adjustments.iter() {
+            counters
+                .entry(counter_key.clone())
+                .or_insert_with(|| get_storage_counter(db, counter_key))
+                .adjust(*previous_len, *len);
+        }
+
+        for (key, counter) in counters.iter() {
+            batch.put(key, serde_json::to_string(counter)?);
+        }
+
+        db.write(batch)?;
+
+        Ok(())
+    }
+}
+
+struct CacheEntry<T> {
+    value: T,
+    fetched_at: Instant,
+    used: u64,
+}
+
+/// Least recently used cache for deserialized records, keyed by their
+/// database key. Entries expire after `META_CACHE_TTL` even when nothing
+/// invalidated them.
+pub struct MetaCache<T> {
+    name: &'static str,
+    capacity: usize,
+    entries: HashMap<String, CacheEntry<T>>,
+    // last use -> key, least recently used first
+    order: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+impl<T: Clone> MetaCache<T> {
+    pub fn new(
+        name: &'static str,
+        capacity: usize,
+    ) -> Self {
+        MetaCache {
+            name,
+            capacity,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    pub fn get(
+        &mut self,
+        key: &str,
+    ) -> Option<T> {
+        let expired =
+            self.entries
+                .get(key)?
+                .fetched_at
+                .elapsed() > Duration::from_secs(META_CACHE_TTL);
+
+        if expired {
+            self.remove(key);
+
+            return None;
+        }
+
+        self.tick += 1;
+
+        let entry = self.entries.get_mut(key)?;
+
+        self.order.remove(&entry.used);
+        self.order.insert(self.tick, key.to_string());
+
+        entry.used = self.tick;
+
+        Some(entry.value.clone())
+    }
+
+    pub fn insert(
+        &mut self,
+        key: String,
+        value: T,
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.remove(&key);
+
+        while self.entries.len() >= self.capacity {
+            let oldest =
+                match self.order.keys().next().copied() {
+                    Some(oldest) => oldest,
+                    None => break,
+                };
+
+            if let Some(key) = self.order.remove(&oldest) {
+                self.entries.remove(&key);
+            }
+        }
+
+        self.tick += 1;
+
+        self.order.insert(self.tick, key.clone());
+
+        self.entries.insert(
+            key,
+            CacheEntry {
+                value,
+                fetched_at: Instant::now(),
+                used: self.tick,
+            },
+        );
+    }
+
+    pub fn remove(
+        &mut self,
+        key: &str,
+    ) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.used);
+        }
+    }
+}
+
+static USER_META_CACHE: Lazy<Mutex<MetaCache<UserMeta>>> =
+    Lazy::new(|| Mutex::new(MetaCache::new("user_meta", get_meta_cache_size())));
+
+static CHAT_META_CACHE: Lazy<Mutex<MetaCache<ChatMeta>>> =
+    Lazy::new(|| Mutex::new(MetaCache::new("chat_meta", get_meta_cache_size())));
+
+fn lock_cache<T>(
+    cache: &'static Lazy<Mutex<MetaCache<T>>>,
+) -> MutexGuard<'static, MetaCache<T>> {
+    match cache.lock() {
+        Ok(cache) => cache,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+fn cached_meta<T: Clone + DeserializeOwned>(
+    cache: &'static Lazy<Mutex<MetaCache<T>>>,
+    db: &impl ReadStore,
+    key: String,
+) -> Option<T> {
+    {
+        let mut cache = lock_cache(cache);
+
+        if cache.capacity > 0 {
+            if let Some(value) = cache.get(&key) {
+                record_cache_lookup(cache.name, true);
+
+                return Some(value);
+            }
+
+            record_cache_lookup(cache.name, false);
+        }
+    }
+
+    let value =
+        db.get(&key)
+            .ok()
+            .flatten()
+            .map(|v| serde_json::from_slice::<T>(&v).ok())
+            .flatten()?;
+
+    // renderers hold the database lock for their whole request and writers
+    // invalidate while holding it, so this can't put back a stale record
+    lock_cache(cache).insert(key, value.clone());
+
+    Some(value)
+}
+
+/// `user:meta:{user_id}`, served from memory when it was read recently.
+pub fn get_user_meta(
+    db: &impl ReadStore,
+    user_id: &str,
+) -> Option<UserMeta> {
+    cached_meta(
+        &USER_META_CACHE,
+        db,
+        format!("user:meta:{}", user_id),
+    )
+}
+
+/// `chat:meta:{chat_id}`, served from memory when it was read recently.
+pub fn get_chat_meta(
+    db: &impl ReadStore,
+    chat_id: &str,
+) -> Option<ChatMeta> {
+    cached_meta(
+        &CHAT_META_CACHE,
+        db,
+        format!("chat:meta:{}", chat_id),
+    )
+}
+
+/// Has to be called after writing `user:meta:{user_id}`, before the
+/// database lock is released.
+pub fn invalidate_user_meta(
+    user_id: &str,
+) {
+    lock_cache(&USER_META_CACHE).remove(&format!("user:meta:{}", user_id));
+}
+
+/// Has to be called after writing `chat:meta:{chat_id}`, before the
+/// database lock is released.
+pub fn invalidate_chat_meta(
+    chat_id: &str,
+) {
+    lock_cache(&CHAT_META_CACHE).remove(&format!("chat:meta:{}", chat_id));
+}
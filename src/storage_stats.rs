@@ -0,0 +1,275 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::Utc;
+use rocksdb::{DBWithThreadMode, Direction, IteratorMode, MultiThreaded, ReadOptions};
+use serde::{Deserialize, Serialize};
+
+use crate::{ok_or_continue, some_or_continue};
+use crate::search_index::recount_search_index;
+use crate::storage::ReadStore;
+use crate::workers::telegram_handler::{build_file_meta_key, ChatMetaChange, ChatMetaHistoryEntry, FileMeta, LogItem, parse_message_key};
+
+// file blob kinds as they appear in `file:{kind}:{id}` keys
+pub const FILE_KINDS: [&str; 5] = ["chat", "video_thumb", "thumb", "user", "chat_photo"];
+
+pub const STORAGE_STATS_REBUILT_KEY: &str = "stats:rebuilt_at";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StorageCounter {
+    pub count: u64,
+    pub bytes: u64,
+}
+
+impl StorageCounter {
+    /// Moves the counter from a value of `previous_len` bytes (None if there
+    /// was none) to one of `len` bytes (None if it's gone now).
+    pub fn adjust(
+        &mut self,
+        previous_len: Option<usize>,
+        len: Option<usize>,
+    ) {
+        self.count =
+            (self.count + len.is_some() as u64)
+                .saturating_sub(previous_len.is_some() as u64);
+
+        self.bytes =
+            (self.bytes + len.unwrap_or(0) as u64)
+                .saturating_sub(previous_len.unwrap_or(0) as u64);
+    }
+}
+
+/// Rolling counters, kept up to date at ingest:
+///
+/// - `stats:chat_messages:{chat_id}` stored log items of a chat
+/// - `stats:chat_files:{chat_id}` file blobs downloaded for a chat's messages
+/// - `stats:files:{kind}` file blobs by kind
+/// - `stats:orphaned_files:all` blobs no log item references, only known
+///   as of the last rebuild
+pub fn build_storage_counter_key(
+    scope: &str,
+    id: &str,
+) -> String {
+    format!(
+        "stats:{}:{}",
+        scope,
+        id,
+    )
+}
+
+pub fn get_storage_counter(
+    db: &impl ReadStore,
+    key: &str,
+) -> StorageCounter {
+    db.get(key)
+        .ok()
+        .flatten()
+        .map(|counter| serde_json::from_slice::<StorageCounter>(&counter).ok())
+        .flatten()
+        .unwrap_or_default()
+}
+
+/// Stored counterpart of `StorageCounter::adjust`.
+pub fn adjust_storage_counter(
+    db: &DBWithThreadMode<MultiThreaded>,
+    key: &str,
+    previous_len: Option<usize>,
+    len: Option<usize>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if previous_len == len {
+        return Ok(());
+    }
+
+    let mut counter = get_storage_counter(db, key);
+
+    counter.adjust(previous_len, len);
+
+    db.put(key, serde_json::to_string(&counter)?)?;
+
+    Ok(())
+}
+
+/// Puts a value and moves the given counters along with it.
+pub fn put_counted<V: AsRef<[u8]>>(
+    db: &DBWithThreadMode<MultiThreaded>,
+    key: &str,
+    value: V,
+    counter_keys: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let previous_len = db.get(key)?.map(|previous| previous.len());
+    let len = value.as_ref().len();
+
+    db.put(key, value)?;
+
+    for counter_key in counter_keys.iter() {
+        adjust_storage_counter(db, counter_key, previous_len, Some(len))?;
+    }
+
+    Ok(())
+}
+
+/// Deletes a value and moves the given counters along with it.
+pub fn delete_counted(
+    db: &DBWithThreadMode<MultiThreaded>,
+    key: &str,
+    counter_keys: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let previous_len = db.get(key)?.map(|previous| previous.len());
+
+    db.delete(key)?;
+
+    for counter_key in counter_keys.iter() {
+        adjust_storage_counter(db, counter_key, previous_len, None)?;
+    }
+
+    Ok(())
+}
+
+/// Counters to move for a log item of the given chat.
+pub fn message_counter_keys(
+    chat_id: &str,
+) -> Vec<String> {
+    vec!(
+        build_storage_counter_key("chat_messages", chat_id),
+    )
+}
+
+/// Counters to move for a file blob of the given kind, attributed to the
+/// chat of the message it was downloaded for when there is one.
+pub fn file_counter_keys(
+    kind: &str,
+    message_key: Option<&str>,
+) -> Vec<String> {
+    let mut keys =
+        vec!(
+            build_storage_counter_key("files", kind),
+        );
+
+    if let Some(chat_id) = message_key.map(|key| key.split(':').nth(1)).flatten() {
+        keys.push(build_storage_counter_key("chat_files", chat_id));
+    }
+
+    keys
+}
+
+/// Approximate size of the database on disk: sst files plus memtables.
+pub fn approximate_db_size(
+    db: &DBWithThreadMode<MultiThreaded>,
+) -> u64 {
+    ["rocksdb.total-sst-files-size", "rocksdb.cur-size-all-mem-tables"]
+        .iter()
+        .filter_map(|property| db.property_int_value(property).ok().flatten())
+        .sum()
+}
+
+/// Iterates every key starting with `prefix`.
+pub fn prefix_iter<'a, S: ReadStore>(
+    db: &'a S,
+    prefix: &str,
+) -> impl Iterator<Item=(Box<[u8]>, Box<[u8]>)> + 'a {
+    let mut opts = ReadOptions::default();
+
+    let lower_bound = prefix.as_bytes().to_vec();
+    let upper_bound = format!("{}\x7f", prefix).as_bytes().to_vec();
+
+    opts.set_iterate_lower_bound(lower_bound.clone());
+    opts.set_iterate_upper_bound(upper_bound);
+
+    db.iterator_opt(IteratorMode::From(&lower_bound, Direction::Forward), opts)
+}
+
+/// Throws away all counters and recomputes them from what is stored,
+/// including the orphaned blob count ingest can't keep track of. Returns
+/// the number of counters written.
+pub fn rebuild_storage_stats(
+    db: &DBWithThreadMode<MultiThreaded>,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let stale =
+        prefix_iter(db, "stats:")
+            .map(|(key, _)| key)
+            .collect::<Vec<Box<[u8]>>>();
+
+    for key in stale.iter() {
+        db.delete(key)?;
+    }
+
+    let mut counters = HashMap::<String, StorageCounter>::new();
+    let mut referenced = HashSet::<String>::new();
+
+    let mut add = |key: String, len: usize| {
+        let counter = counters.entry(key).or_default();
+
+        counter.count += 1;
+        counter.bytes += len as u64;
+    };
+
+    for (key, val) in prefix_iter(db, "chat:") {
+        let key = ok_or_continue!(String::from_utf8(key.to_vec()));
+
+        // only log items, not chat:meta:* and friends
+        let (chat_id, _) = some_or_continue!(parse_message_key(&key));
+
+        add(build_storage_counter_key("chat_messages", chat_id), val.len());
+
+        if let Ok(log_item) = serde_json::from_slice::<LogItem>(&val) {
+            referenced.extend(log_item.file_ids());
+        }
+    }
+
+    for (_, val) in prefix_iter(db, "chat:meta_history:") {
+        if let Ok(ChatMetaHistoryEntry { change: ChatMetaChange::Photo { file_id: Some(file_id) }, .. }) = serde_json::from_slice(&val) {
+            referenced.insert(file_id);
+        }
+    }
+
+    for kind in FILE_KINDS.iter() {
+        let prefix = format!("file:{}:", kind);
+
+        for (key, val) in prefix_iter(db, &prefix) {
+            let key = ok_or_continue!(String::from_utf8(key.to_vec()));
+            let file_id = key.trim_start_matches(&prefix);
+
+            let message_key =
+                db.get(build_file_meta_key(file_id))?
+                    .map(|meta| serde_json::from_slice::<FileMeta>(&meta).ok())
+                    .flatten()
+                    .map(|meta| meta.message_key)
+                    .flatten();
+
+            for counter_key in file_counter_keys(kind, message_key.as_deref()) {
+                add(counter_key, val.len());
+            }
+
+            // profile pictures belong to users and chats, not to messages
+            if *kind != "user" && *kind != "chat_photo" && !referenced.contains(file_id) {
+                add(build_storage_counter_key("orphaned_files", "all"), val.len());
+            }
+        }
+    }
+
+    for (key, counter) in counters.iter() {
+        db.put(key, serde_json::to_string(counter)?)?;
+    }
+
+    recount_search_index(db)?;
+
+    db.put(STORAGE_STATS_REBUILT_KEY, Utc::now().timestamp().to_string())?;
+
+    Ok(counters.len())
+}
+
+/// All counters of one scope as `(id, counter)` pairs.
+pub fn find_storage_counters(
+    db: &impl ReadStore,
+    scope: &str,
+) -> Vec<(String, StorageCounter)> {
+    let prefix = format!("stats:{}:", scope);
+
+    prefix_iter(db, &prefix)
+        .filter_map(|(key, val)| {
+            let key = String::from_utf8(key.to_vec()).ok()?;
+            let counter = serde_json::from_slice::<StorageCounter>(&val).ok()?;
+
+            Some((key.trim_start_matches(&prefix).to_string(), counter))
+        })
+        .collect()
+}
@@ -0,0 +1,33 @@
+use std::io::Cursor;
+
+use image::codecs::jpeg::JpegEncoder;
+
+// longest side of a thumbnail, twice what the listing shows them at
+pub const THUMBNAIL_SIZE: u32 = 600;
+
+const THUMBNAIL_QUALITY: u8 = 80;
+
+/// Content type of what `generate_thumbnail` produces.
+pub const THUMBNAIL_MIME_TYPE: &str = "image/jpeg";
+
+/// A JPEG of `file` scaled to fit `THUMBNAIL_SIZE`, along with its width and
+/// height. None for anything that isn't an image the `image` crate decodes.
+pub fn generate_thumbnail(
+    file: &[u8],
+) -> Option<(Vec<u8>, u32, u32)> {
+    // cheap check first, documents can be anything
+    image::guess_format(file).ok()?;
+
+    let image = image::load_from_memory(file).ok()?;
+
+    // transparency has nowhere to go in a JPEG
+    let thumbnail = image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE).to_rgb8();
+
+    let mut out = Cursor::new(Vec::<u8>::new());
+
+    JpegEncoder::new_with_quality(&mut out, THUMBNAIL_QUALITY)
+        .encode_image(&thumbnail)
+        .ok()?;
+
+    Some((out.into_inner(), thumbnail.width(), thumbnail.height()))
+}
@@ -1,7 +1,20 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Cursor;
+
 use chrono::{DateTime, NaiveDateTime, Utc};
+use image::ImageFormat;
 use rocksdb::{DBWithThreadMode, Direction, IteratorMode, MultiThreaded, ReadOptions};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
 
-use crate::workers::telegram_handler::{ChatMeta, UserMeta};
+use crate::blob_store::{delete_blob, resolve_blob};
+use crate::migrations::{SCHEMA_VERSION, to_versioned_string};
+use crate::privacy::pseudonym;
+use crate::render_cache::invalidate_chat_pages;
+use crate::search_index::update_postings;
+use crate::storage::{get_chat_meta, get_user_meta, ReadStore};
+use crate::storage_stats::{delete_counted, file_counter_keys, message_counter_keys, put_counted};
+use crate::workers::telegram_handler::{build_chat_by_username_key, build_chat_username_alias_key, build_file_corrupt_key, build_file_failure_key, build_file_key, build_file_meta_key, build_message_key, build_raw_message_key, ChatMeta, find_bot_user_ids, find_message_slot, ChatUsernameAlias, FileCorruption, FileEntryType, FileFailure, FileMeta, LogItem, LogItemMediaType, MessageSlot, parse_message_key, UserMeta};
 
 #[macro_export]
 macro_rules! ok_or_continue {
@@ -110,6 +123,25 @@ macro_rules! respawning_threaded_async {
     };
 }
 
+pub fn escape_html(
+    text: &str,
+) -> String {
+    let mut out = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '&' => out.push_str("&amp;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+
+    out
+}
+
 pub fn resolve_user_meta(
     user: &UserMeta,
 ) -> String {
@@ -158,23 +190,11 @@ pub fn resolve_user_meta_with_id(
 }
 
 pub fn resolve_user(
-    db: &DBWithThreadMode<MultiThreaded>,
+    db: &impl ReadStore,
     user_id: &str,
     with_id: bool,
 ) -> String {
-    db.get(
-        format!("user:meta:{}", user_id)
-            .as_bytes(),
-    )
-        .ok()
-        .flatten()
-        .map(|v|
-            serde_json::from_slice::<UserMeta>(
-                &v,
-            )
-                .ok()
-        )
-        .flatten()
+    get_user_meta(db, user_id)
         .map(|user|
                  if with_id {
                      resolve_user_meta_with_id(
@@ -191,23 +211,24 @@ pub fn resolve_user(
         )
 }
 
-pub fn resolve_chat_name(
-    db: &DBWithThreadMode<MultiThreaded>,
+/// Whether the chat is archived at all. `chat_rel:` comes with its first
+/// logged message, `chat:meta:` with the first update the bot saw of it.
+pub fn is_archived_chat(
+    db: &impl ReadStore,
     chat_id: &str,
-) -> String {
-    db.get(
-        format!("chat:meta:{}", chat_id)
-            .as_bytes(),
-    )
+) -> bool {
+    db.get(format!("chat_rel:{}", chat_id))
         .ok()
         .flatten()
-        .map(|v|
-            serde_json::from_slice::<ChatMeta>(
-                &v,
-            )
-                .ok()
-        )
-        .flatten()
+        .is_some()
+        || get_chat_meta(db, chat_id).is_some()
+}
+
+pub fn resolve_chat_name(
+    db: &impl ReadStore,
+    chat_id: &str,
+) -> String {
+    get_chat_meta(db, chat_id)
         .map(|meta|
             match meta {
                 ChatMeta::User(user) =>
@@ -298,12 +319,236 @@ pub fn resolve_chat_name(
         )
         // try treating it as username
         .unwrap_or_else(
-            || resolve_user(&db, chat_id, true),
+            || resolve_user(db, chat_id, true),
         )
 }
 
+/// Returns the records a user's meta replaced, keyed by when they were
+/// replaced, oldest first. Each record was current up until its timestamp.
+pub fn find_user_meta_history(
+    db: &impl ReadStore,
+    user_id: &str,
+) -> Vec<(i64, UserMeta)> {
+    let mut opts = ReadOptions::default();
+
+    let lower_bound = format!("user:meta_history:{}:", &user_id).as_bytes().to_vec();
+    let upper_bound = format!("user:meta_history:{}:\x7f", &user_id).as_bytes().to_vec();
+
+    opts.set_iterate_upper_bound(upper_bound.clone());
+    opts.set_iterate_lower_bound(lower_bound.clone());
+
+    let iter =
+        db.iterator_opt(
+            IteratorMode::From(&lower_bound, Direction::Forward),
+            opts,
+        );
+
+    let mut history = Vec::<(i64, UserMeta)>::new();
+
+    for (key, val) in iter {
+        let key = ok_or_continue!(String::from_utf8(key.to_vec()));
+        let time = ok_or_continue!(some_or_continue!(key.split(':').last()).parse::<i64>());
+
+        history.push(
+            (
+                time,
+                ok_or_continue!(serde_json::from_slice::<UserMeta>(&val)),
+            ),
+        );
+    }
+
+    history.sort_by_key(|(time, _)| *time);
+
+    history
+}
+
+/// Per-request memo for user and chat name lookups, so rendering a page
+/// resolves every id at most once instead of once per row.
+pub struct NameCache<'a, S: ReadStore = DBWithThreadMode<MultiThreaded>> {
+    db: &'a S,
+    users: HashMap<(String, bool), String>,
+    chats: HashMap<String, String>,
+    histories: HashMap<String, Vec<(i64, UserMeta)>>,
+    // user ids of the logging bots, read on first use
+    own_bots: Option<Vec<String>>,
+    historical: bool,
+    anonymized: bool,
+    lookups: usize,
+}
+
+impl<'a, S: ReadStore> NameCache<'a, S> {
+    pub fn new(
+        db: &'a S,
+    ) -> Self {
+        NameCache {
+            db,
+            users: HashMap::new(),
+            chats: HashMap::new(),
+            histories: HashMap::new(),
+            own_bots: None,
+            historical: false,
+            anonymized: false,
+            lookups: 0,
+        }
+    }
+
+    /// Resolves every user to its pseudonym instead of its name.
+    pub fn with_anonymized(
+        mut self,
+        anonymized: bool,
+    ) -> Self {
+        self.anonymized = anonymized;
+
+        self
+    }
+
+    /// Makes `user_at` resolve names as they were at the given time instead
+    /// of the current ones.
+    pub fn with_historical_names(
+        mut self,
+        historical: bool,
+    ) -> Self {
+        self.historical = historical;
+
+        self
+    }
+
+    pub fn user(
+        &mut self,
+        user_id: &str,
+        with_id: bool,
+    ) -> String {
+        if self.anonymized {
+            return pseudonym(user_id);
+        }
+
+        let key = (user_id.to_string(), with_id);
+
+        if let Some(name) = self.users.get(&key) {
+            return name.clone();
+        }
+
+        self.lookups += 1;
+
+        let name =
+            resolve_user(
+                self.db,
+                user_id,
+                with_id,
+            );
+
+        self.users.insert(key, name.clone());
+
+        name
+    }
+
+    pub fn user_at(
+        &mut self,
+        user_id: &str,
+        time: i64,
+    ) -> String {
+        if !self.historical || self.anonymized {
+            return self.user(user_id, false);
+        }
+
+        if !self.histories.contains_key(user_id) {
+            self.lookups += 1;
+
+            self.histories.insert(
+                user_id.to_string(),
+                find_user_meta_history(self.db, user_id),
+            );
+        }
+
+        let history = &self.histories[user_id];
+
+        // the first record replaced after `time` is the one that was current
+        let index = history.partition_point(|(replaced, _)| *replaced <= time);
+
+        match history.get(index) {
+            Some((_, meta)) => resolve_user_meta(meta),
+            None => self.user(user_id, false),
+        }
+    }
+
+    pub fn chat_name(
+        &mut self,
+        chat_id: &str,
+    ) -> String {
+        if let Some(name) = self.chats.get(chat_id) {
+            return name.clone();
+        }
+
+        self.lookups += 1;
+
+        let name =
+            resolve_chat_name(
+                self.db,
+                chat_id,
+            );
+
+        self.chats.insert(chat_id.to_string(), name.clone());
+
+        name
+    }
+
+    /// Badge for a bot's nick: "own" for the bots doing the logging, "bot"
+    /// for any other bot, None for people.
+    pub fn bot_badge(
+        &mut self,
+        user_id: &str,
+    ) -> Option<&'static str> {
+        if self.own_bots.is_none() {
+            self.lookups += 1;
+            self.own_bots = Some(find_bot_user_ids(self.db));
+        }
+
+        if self.own_bots.iter().flatten().any(|id| id == user_id) {
+            return Some("own");
+        }
+
+        get_user_meta(self.db, user_id)
+            .filter(|meta| meta.is_bot)
+            .map(|_| "bot")
+    }
+
+    /// Number of lookups that actually hit the database.
+    pub fn lookups(&self) -> usize {
+        self.lookups
+    }
+}
+
+/// Resolves `@username` (or a bare username) to a chat id, falling back to
+/// previous usernames that are still within their grace period.
+pub fn resolve_chat_username(
+    db: &impl ReadStore,
+    username: &str,
+) -> Option<String> {
+    if let Some(chat_id) =
+    db.get(build_chat_by_username_key(username))
+        .ok()
+        .flatten()
+        .map(|v| String::from_utf8(v).ok())
+        .flatten() {
+        return Some(chat_id);
+    }
+
+    let alias =
+        db.get(build_chat_username_alias_key(username))
+            .ok()
+            .flatten()
+            .map(|v| serde_json::from_slice::<ChatUsernameAlias>(&v).ok())
+            .flatten()?;
+
+    if alias.expires < Utc::now().timestamp() {
+        return None;
+    }
+
+    Some(alias.chat_id)
+}
+
 pub fn find_latest_chat_day(
-    db: &DBWithThreadMode<MultiThreaded>,
+    db: &impl ReadStore,
     chat_id: &str,
 ) -> Option<String> {
     let mut opts = ReadOptions::default();
@@ -347,3 +592,518 @@ pub fn find_latest_chat_day(
         })
         .flatten()
 }
+
+pub fn format_chat_day(
+    day: i64,
+) -> Option<String> {
+    let day = NaiveDateTime::from_timestamp_opt(day * 86_400, 0)?;
+    let day: DateTime<Utc> = DateTime::from_utc(day, Utc);
+
+    Some(
+        day
+            .format("%Y-%m-%d")
+            .to_string(),
+    )
+}
+
+/// Looks up where a telegram message id was logged in a chat through the
+/// `chat_ref` index, which holds its timestamp. Messages stored before the
+/// message id was part of the key are found under their timestamp alone.
+pub fn resolve_message_ref(
+    db: &impl ReadStore,
+    chat_id: &str,
+    message_id: &str,
+) -> Option<MessageSlot> {
+    let timestamp =
+        db.get(
+            format!("chat_ref:{}:{}", chat_id, message_id)
+                .as_bytes(),
+        )
+            .ok()
+            .flatten()
+            .map(|v| String::from_utf8(v).ok())
+            .flatten()
+            .map(|v| v.parse::<i64>().ok())
+            .flatten()?;
+
+    let slot = MessageSlot::new(timestamp, message_id.parse().ok()?);
+
+    Some(find_message_slot(db, chat_id, slot).unwrap_or(slot))
+}
+
+/// Replaces a logged message with a `LogItem::Redacted` tombstone (keeping
+/// its slot so the day stays intact) and deletes the files that only this
+/// message referenced. Returns the message's timestamp, or None when the
+/// message isn't in the archive.
+///
+/// Stickers are left alone, the same sticker file is shared by every message
+/// that uses it.
+pub fn redact_message(
+    db: &DBWithThreadMode<MultiThreaded>,
+    chat_id: &str,
+    message_id: &str,
+) -> Result<Option<i64>, Box<dyn std::error::Error>> {
+    let slot =
+        match resolve_message_ref(db, chat_id, message_id) {
+            Some(slot) => slot,
+            None => return Ok(None),
+        };
+
+    let timestamp = slot.time;
+
+    let message_key = build_message_key(chat_id, slot);
+
+    let log_item =
+        match db.get(&message_key)? {
+            Some(log_item) => serde_json::from_slice::<LogItem>(&log_item)?,
+            None => return Ok(None),
+        };
+
+    if let LogItem::Pin { .. } = log_item {
+        db.delete(format!("chat_pins:{}:{}", chat_id, slot))?;
+    }
+
+    put_counted(
+        db,
+        &message_key,
+        to_versioned_string(
+            &LogItem::Redacted {
+                time: timestamp,
+                redacted_at: Utc::now().timestamp(),
+                v: SCHEMA_VERSION,
+            },
+        )?,
+        &message_counter_keys(chat_id),
+    )?;
+
+    // the stored raw message would otherwise bring it back on reprocessing
+    db.delete(build_raw_message_key(chat_id, timestamp, message_id))?;
+
+    update_postings(db, chat_id, slot, Some(&log_item), None)?;
+
+    delete_message_files(db, &message_key, &log_item)?;
+
+    invalidate_chat_pages(chat_id);
+
+    Ok(Some(timestamp))
+}
+
+/// Deletes the file blobs a log item references unless another message
+/// re-used them. Stickers are never deleted, see `redact_message`.
+pub fn delete_message_files(
+    db: &DBWithThreadMode<MultiThreaded>,
+    message_key: &str,
+    log_item: &LogItem,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut files = Vec::<String>::new();
+
+    if let LogItem::Media { files: ref media_files, ref media_type, .. } = log_item {
+        match media_type {
+            LogItemMediaType::Sticker { .. } => {}
+            LogItemMediaType::Video { thumb_file_id: Some(ref thumb), .. }
+            | LogItemMediaType::VideoNote { thumb_file_id: Some(ref thumb), .. }
+            | LogItemMediaType::Animation { thumb_file_id: Some(ref thumb), .. } => {
+                files.extend(media_files.iter().cloned());
+                files.push(thumb.clone());
+            }
+            _ => files.extend(media_files.iter().cloned()),
+        }
+    }
+
+    for file_id in files.iter() {
+        // files re-sent in another message point their meta at that one
+        let shared =
+            get_file_meta(db, file_id)
+                .map(|meta| meta.message_key)
+                .flatten()
+                .map(|key| key != message_key)
+                .unwrap_or(false);
+
+        if shared {
+            continue;
+        }
+
+        delete_blob(
+            db,
+            &build_file_key(FileEntryType::Chat, file_id),
+            &file_counter_keys("chat", Some(message_key)),
+        )?;
+
+        delete_blob(
+            db,
+            &build_file_key(FileEntryType::VideoThumb, file_id),
+            &file_counter_keys("video_thumb", Some(message_key)),
+        )?;
+        db.delete(build_file_meta_key(file_id))?;
+    }
+
+    Ok(())
+}
+
+/// Deletes every stored message written by one of the given users, along
+/// with their files. Days left without any message are dropped from the
+/// chat index so they don't show up as empty pages. Returns the number of
+/// deleted messages, or with `dry_run` the number that would be.
+pub fn purge_user_messages(
+    db: &DBWithThreadMode<MultiThreaded>,
+    user_ids: &HashSet<String>,
+    dry_run: bool,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut opts = ReadOptions::default();
+
+    opts.set_iterate_lower_bound(b"chat:".to_vec());
+    opts.set_iterate_upper_bound(b"chat:\x7f".to_vec());
+
+    let mut doomed = Vec::<(String, String, MessageSlot, LogItem)>::new();
+
+    for (key, val) in db.iterator_opt(IteratorMode::From(b"chat:", Direction::Forward), opts) {
+        let key = ok_or_continue!(String::from_utf8(key.to_vec()));
+
+        // only log items, not chat:meta:* and friends
+        let (chat_id, slot) = some_or_continue!(parse_message_key(&key));
+        let chat_id = chat_id.to_string();
+
+        let item = ok_or_continue!(serde_json::from_slice::<LogItem>(&val));
+
+        let ignored =
+            item.user_id()
+                .map(|user_id| user_ids.contains(user_id))
+                .unwrap_or(false);
+
+        if ignored {
+            doomed.push((key.clone(), chat_id, slot, item));
+        }
+    }
+
+    if !dry_run {
+        delete_log_items(db, &doomed)?;
+    }
+
+    Ok(doomed.len())
+}
+
+/// Deletes the messages a chat logged from `since` until before `until`,
+/// along with their files, the way `purge_user_messages` does. Returns the
+/// number of deleted messages.
+pub fn purge_chat_messages(
+    db: &DBWithThreadMode<MultiThreaded>,
+    chat_id: &str,
+    since: i64,
+    until: i64,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    if since >= until {
+        return Ok(0);
+    }
+
+    let mut opts = ReadOptions::default();
+
+    let lower_bound = build_message_key(chat_id, since);
+    let upper_bound = build_message_key(chat_id, until);
+
+    opts.set_iterate_lower_bound(lower_bound.as_bytes().to_vec());
+    opts.set_iterate_upper_bound(upper_bound.as_bytes().to_vec());
+
+    let mut doomed = Vec::<(String, String, MessageSlot, LogItem)>::new();
+
+    for (key, val) in db.iterator_opt(IteratorMode::From(lower_bound.as_bytes(), Direction::Forward), opts) {
+        let key = ok_or_continue!(String::from_utf8(key.to_vec()));
+
+        let (_, slot) = some_or_continue!(parse_message_key(&key));
+
+        // keys sort as strings, a shorter timestamp may sneak in
+        if slot.time < since || slot.time >= until {
+            continue;
+        }
+
+        let item = ok_or_continue!(serde_json::from_slice::<LogItem>(&val));
+
+        doomed.push((key.clone(), chat_id.to_string(), slot, item));
+    }
+
+    delete_log_items(db, &doomed)?;
+
+    Ok(doomed.len())
+}
+
+// deletes `(key, chat_id, slot, item)` log items with their files and
+// postings, and drops days left without any message from the chat index so
+// they don't show up as empty pages
+fn delete_log_items(
+    db: &DBWithThreadMode<MultiThreaded>,
+    doomed: &[(String, String, MessageSlot, LogItem)],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut days = HashSet::<(String, i64)>::new();
+
+    for (key, chat_id, slot, item) in doomed.iter() {
+        delete_counted(db, key, &message_counter_keys(chat_id))?;
+
+        delete_message_files(db, key, item)?;
+
+        update_postings(db, chat_id, *slot, Some(item), None)?;
+
+        days.insert((chat_id.clone(), slot.time / 86_400));
+    }
+
+    for (chat_id, day) in days {
+        invalidate_chat_pages(&chat_id);
+
+        let mut opts = ReadOptions::default();
+
+        let lower_bound = build_message_key(&chat_id, day * 86_400);
+        let upper_bound = build_message_key(&chat_id, (day + 1) * 86_400);
+
+        opts.set_iterate_lower_bound(lower_bound.as_bytes().to_vec());
+        opts.set_iterate_upper_bound(upper_bound.as_bytes().to_vec());
+
+        let empty =
+            db.iterator_opt(IteratorMode::From(lower_bound.as_bytes(), Direction::Forward), opts)
+                .next()
+                .is_none();
+
+        if empty {
+            db.delete(format!("chat_index:{}:{}", chat_id, day))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Raw messages stored next to the unimplemented log item at `timestamp`.
+pub fn find_raw_messages(
+    db: &impl ReadStore,
+    chat_id: &str,
+    timestamp: i64,
+) -> Vec<Value> {
+    let mut opts = ReadOptions::default();
+
+    let lower_bound = format!("chat_raw:{}:{}:", chat_id, timestamp).as_bytes().to_vec();
+    let upper_bound = format!("chat_raw:{}:{}:\x7f", chat_id, timestamp).as_bytes().to_vec();
+
+    opts.set_iterate_upper_bound(upper_bound.clone());
+    opts.set_iterate_lower_bound(lower_bound.clone());
+
+    db.iterator_opt(IteratorMode::From(&lower_bound, Direction::Forward), opts)
+        .filter_map(|(_, val)| serde_json::from_slice::<Value>(&val).ok())
+        .collect()
+}
+
+/// Percent-encodes everything but unreserved characters, for user input
+/// put into query strings.
+pub fn encode_query_value(
+    value: &str,
+) -> String {
+    let mut out = String::with_capacity(value.len());
+
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' =>
+                out.push(byte as char),
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    out
+}
+
+/// Link to a single logged message: the listing page of its day, starting
+/// right at the message.
+pub fn message_permalink(
+    chat_id: &str,
+    timestamp: i64,
+) -> Option<String> {
+    Some(
+        format!(
+            "/chat/{}/{}?cursor={}#{}",
+            chat_id,
+            format_chat_day(timestamp / 86_400)?,
+            timestamp + 1,
+            timestamp,
+        ),
+    )
+}
+
+/// Returns the days (since start of epoch) on which something was logged
+/// in the given chat, newest first.
+pub fn find_chat_days(
+    db: &impl ReadStore,
+    chat_id: &str,
+) -> Vec<i64> {
+    let mut opts = ReadOptions::default();
+
+    let lower_bound = format!("chat_index:{}:", &chat_id).as_bytes().to_vec();
+    let upper_bound = format!("chat_index:{}:\x7f", &chat_id).as_bytes().to_vec();
+
+    opts.set_iterate_upper_bound(upper_bound.clone());
+    opts.set_iterate_lower_bound(lower_bound.clone());
+
+    let iter =
+        db.iterator_opt(
+            IteratorMode::From(&upper_bound, Direction::Reverse),
+            opts,
+        );
+
+    let mut days = Vec::<i64>::new();
+
+    for (key, _) in iter {
+        let key = ok_or_continue!(String::from_utf8(key.to_vec()));
+        let key = key.split(':').collect::<Vec<&str>>();
+
+        if key.len() != 3 {
+            continue;
+        }
+
+        days.push(
+            ok_or_continue!(key[key.len() - 1].parse::<i64>()),
+        );
+    }
+
+    days
+}
+
+pub fn image_format_mime_type(
+    format: ImageFormat,
+) -> Option<&'static str> {
+    match format {
+        ImageFormat::Avif => Some("image/avif"),
+        ImageFormat::Jpeg => Some("image/jpeg"),
+        ImageFormat::Png => Some("image/png"),
+        ImageFormat::Gif => Some("image/gif"),
+        ImageFormat::WebP => Some("image/webp"),
+        ImageFormat::Tiff => Some("image/tiff"),
+        ImageFormat::Tga => Some("image/x-tga"),
+        ImageFormat::Dds => Some("image/vnd-ms.dds"),
+        ImageFormat::Bmp => Some("image/bmp"),
+        ImageFormat::Ico => Some("image/x-icon"),
+        ImageFormat::Hdr => Some("image/vnd.radiance"),
+        ImageFormat::OpenExr => Some("image/x-exr"),
+        _ => None,
+    }
+}
+
+/// Sniffs the mime type of an image blob from its magic bytes.
+pub fn guess_mime_type(
+    file: &[u8],
+) -> Option<&'static str> {
+    image::guess_format(file)
+        .ok()
+        .map(image_format_mime_type)
+        .flatten()
+}
+
+/// Reads the dimensions from the image header without decoding the image.
+pub fn guess_image_dimensions(
+    file: &[u8],
+) -> Option<(u32, u32)> {
+    image::io::Reader::new(Cursor::new(file))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()
+}
+
+/// Hex encoded sha256 of a file blob.
+pub fn hash_file(
+    file: &[u8],
+) -> String {
+    Sha256::digest(file)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+pub fn get_file_corruption(
+    db: &impl ReadStore,
+    file_id: &str,
+) -> Option<FileCorruption> {
+    db.get(build_file_corrupt_key(file_id))
+        .ok()
+        .flatten()
+        .map(|v| serde_json::from_slice::<FileCorruption>(&v).ok())
+        .flatten()
+}
+
+pub fn get_file_failure(
+    db: &impl ReadStore,
+    file_id: &str,
+) -> Option<FileFailure> {
+    db.get(
+        build_file_failure_key(file_id)
+            .as_bytes(),
+    )
+        .ok()
+        .flatten()
+        .map(|v|
+            serde_json::from_slice::<FileFailure>(
+                &v,
+            )
+                .ok()
+        )
+        .flatten()
+}
+
+pub fn get_file_meta(
+    db: &impl ReadStore,
+    file_id: &str,
+) -> Option<FileMeta> {
+    db.get(
+        build_file_meta_key(file_id)
+            .as_bytes(),
+    )
+        .ok()
+        .flatten()
+        .map(|v|
+            serde_json::from_slice::<FileMeta>(
+                &v,
+            )
+                .ok()
+        )
+        .flatten()
+}
+
+/// Sniffs every stored blob that doesn't have a `file:meta:` record yet and
+/// writes one, returning the number of records written.
+pub fn backfill_file_meta(
+    db: &DBWithThreadMode<MultiThreaded>,
+) -> usize {
+    let mut opts = ReadOptions::default();
+
+    let lower_bound = b"file:".to_vec();
+
+    opts.set_iterate_upper_bound(b"file:\x7f".to_vec());
+
+    let iter =
+        db.iterator_opt(
+            IteratorMode::From(&lower_bound, Direction::Forward),
+            opts,
+        );
+
+    let mut written = 0;
+
+    for (key, val) in iter {
+        let key = ok_or_continue!(String::from_utf8(key.to_vec()));
+        let key = key.split(':').collect::<Vec<&str>>();
+
+        if key.len() != 3 || !matches!(key[1], "chat" | "user" | "video_thumb") {
+            continue;
+        }
+
+        let file_id = key[2];
+
+        if get_file_meta(db, file_id).is_some() {
+            continue;
+        }
+
+        let meta = FileMeta::from_bytes(&ok_or_continue!(resolve_blob(&format!("file:{}:{}", key[1], file_id), val.to_vec())));
+
+        ok_or_continue!(
+            db.put(
+                build_file_meta_key(file_id),
+                ok_or_continue!(serde_json::to_string(&meta)),
+            ),
+        );
+
+        written += 1;
+    }
+
+    written
+}
@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::Utc;
+use rocksdb::{DBWithThreadMode, MultiThreaded};
+
+use crate::ok_or_continue;
+use crate::config::get_honor_auto_delete;
+use crate::storage::lock_db;
+use crate::storage_stats::prefix_iter;
+use crate::utils::purge_chat_messages;
+use crate::workers::chat_policy::find_retention_chats;
+use crate::workers::telegram_handler::{ChatMetaChange, ChatMetaHistoryEntry};
+
+// telegram's shortest timer is a day, an hour late is close enough
+const AUTO_DELETE_INTERVAL: Duration = Duration::from_secs(3_600);
+
+/// The auto-delete timer each chat has on right now, as when it was set
+/// and how many seconds messages are kept. Chats that turned it off again
+/// aren't in it.
+pub fn find_auto_delete_timers(
+    db: &DBWithThreadMode<MultiThreaded>,
+) -> HashMap<String, (i64, i64)> {
+    let mut timers = HashMap::<String, (i64, i64)>::new();
+
+    for (key, val) in prefix_iter(db, "chat:meta_history:") {
+        let key = ok_or_continue!(String::from_utf8(key.to_vec()));
+        let parts = key.split(':').collect::<Vec<&str>>();
+
+        if parts.len() != 4 {
+            continue;
+        }
+
+        let entry = ok_or_continue!(serde_json::from_slice::<ChatMetaHistoryEntry>(&val));
+
+        let seconds =
+            match entry.change {
+                ChatMetaChange::AutoDeleteTimer { seconds } => seconds,
+                _ => continue,
+            };
+
+        let later =
+            timers
+                .get(parts[2])
+                .map(|(time, _)| entry.time >= *time)
+                .unwrap_or(true);
+
+        if later {
+            timers.insert(parts[2].to_string(), (entry.time, seconds));
+        }
+    }
+
+    timers.retain(|_, (_, seconds)| *seconds > 0);
+
+    timers
+}
+
+/// Deletes what chats with an auto-delete timer logged since they set it
+/// and telegram has deleted by now. Messages from before the timer are
+/// kept, telegram doesn't apply it to them either. Returns the number of
+/// deleted messages.
+pub fn purge_auto_deleted(
+    db: &DBWithThreadMode<MultiThreaded>,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let now = Utc::now().timestamp();
+
+    let mut deleted = 0;
+
+    for (chat_id, (since, seconds)) in find_auto_delete_timers(db) {
+        deleted += purge_chat_messages(db, &chat_id, since, now - seconds)?;
+    }
+
+    Ok(deleted)
+}
+
+/// Deletes everything older than their retention override from the chats
+/// that have one, whatever their auto-delete timer. Returns the number of
+/// deleted messages.
+pub fn purge_past_retention(
+    db: &DBWithThreadMode<MultiThreaded>,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let now = Utc::now().timestamp();
+
+    let mut deleted = 0;
+
+    for (chat_id, seconds) in find_retention_chats() {
+        deleted += purge_chat_messages(db, &chat_id, 0, now - seconds)?;
+    }
+
+    Ok(deleted)
+}
+
+pub async fn spawn_worker(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+) {
+    loop {
+        let result = {
+            let dbi = lock_db(&db);
+
+            match get_honor_auto_delete() {
+                true => purge_auto_deleted(&dbi),
+                false => Ok(0),
+            }
+                .and_then(|deleted| Ok(deleted + purge_past_retention(&dbi)?))
+        };
+
+        match result {
+            Ok(0) => {}
+            Ok(deleted) => tracing::info!(deleted, "deleted messages past their chat's auto-delete timer or retention"),
+            Err(err) => {
+                dbg!(err);
+            }
+        }
+
+        tokio::time::sleep(AUTO_DELETE_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations::to_versioned_string;
+    use crate::workers::telegram_handler::{build_chat_meta_history_key, build_message_key, LogItem};
+
+    const DAY: i64 = 86_400;
+
+    fn open_db(name: &str) -> DBWithThreadMode<MultiThreaded> {
+        let path =
+            std::env::temp_dir()
+                .join(format!("minuteman-test-{}-{}", name, std::process::id()));
+
+        let _ = std::fs::remove_dir_all(&path);
+
+        DBWithThreadMode::<MultiThreaded>::open_default(path).unwrap()
+    }
+
+    fn put_timer(db: &DBWithThreadMode<MultiThreaded>, chat_id: &str, time: i64, seconds: i64) {
+        let entry =
+            ChatMetaHistoryEntry {
+                time,
+                user_id: None,
+                change: ChatMetaChange::AutoDeleteTimer { seconds },
+                v: 0,
+            };
+
+        db.put(build_chat_meta_history_key(chat_id, time), to_versioned_string(&entry).unwrap()).unwrap();
+    }
+
+    fn put_message(db: &DBWithThreadMode<MultiThreaded>, chat_id: &str, time: i64) {
+        let log_item =
+            LogItem::Redacted {
+                time,
+                redacted_at: time,
+                v: 0,
+            };
+
+        db.put(build_message_key(chat_id, time), to_versioned_string(&log_item).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn only_the_latest_timer_of_a_chat_counts() {
+        let db = open_db("auto-delete-timers");
+        let now = Utc::now().timestamp();
+
+        put_timer(&db, "-1", now - 10 * DAY, DAY);
+        put_timer(&db, "-2", now - 10 * DAY, DAY);
+        put_timer(&db, "-2", now - 5 * DAY, 0);
+        put_timer(&db, "-3", now - 10 * DAY, 0);
+        put_timer(&db, "-3", now - 5 * DAY, 7 * DAY);
+
+        let timers = find_auto_delete_timers(&db);
+
+        assert_eq!(timers.get("-1"), Some(&(now - 10 * DAY, DAY)));
+        assert_eq!(timers.get("-2"), None);
+        assert_eq!(timers.get("-3"), Some(&(now - 5 * DAY, 7 * DAY)));
+    }
+
+    #[test]
+    fn messages_logged_under_the_timer_go_once_it_ran_out() {
+        let db = open_db("auto-delete-purge");
+        let now = Utc::now().timestamp();
+
+        put_timer(&db, "-1", now - 10 * DAY, DAY);
+
+        // before the timer, under it but expired, under it and still fresh
+        put_message(&db, "-1", now - 11 * DAY);
+        put_message(&db, "-1", now - 5 * DAY);
+        put_message(&db, "-1", now - 3600);
+
+        // no timer at all
+        put_message(&db, "-2", now - 5 * DAY);
+
+        assert_eq!(purge_auto_deleted(&db).unwrap(), 1);
+
+        assert!(db.get(build_message_key("-1", now - 11 * DAY)).unwrap().is_some());
+        assert!(db.get(build_message_key("-1", now - 5 * DAY)).unwrap().is_none());
+        assert!(db.get(build_message_key("-1", now - 3600)).unwrap().is_some());
+        assert!(db.get(build_message_key("-2", now - 5 * DAY)).unwrap().is_some());
+    }
+
+    #[test]
+    fn a_retention_override_purges_without_a_timer() {
+        use crate::workers::chat_policy::{ChatPolicy, set_chat_policy};
+
+        let db = open_db("auto-delete-retention");
+        let now = Utc::now().timestamp();
+
+        set_chat_policy(&db, "-1002627000", ChatPolicy { retention: Some(7 * DAY), ..ChatPolicy::default() }).unwrap();
+
+        put_message(&db, "-1002627000", now - 30 * DAY);
+        put_message(&db, "-1002627000", now - 8 * DAY);
+        put_message(&db, "-1002627000", now - DAY);
+
+        assert_eq!(purge_past_retention(&db).unwrap(), 2);
+
+        assert!(db.get(build_message_key("-1002627000", now - 30 * DAY)).unwrap().is_none());
+        assert!(db.get(build_message_key("-1002627000", now - 8 * DAY)).unwrap().is_none());
+        assert!(db.get(build_message_key("-1002627000", now - DAY)).unwrap().is_some());
+
+        set_chat_policy(&db, "-1002627000", ChatPolicy::default()).unwrap();
+    }
+}
@@ -0,0 +1,134 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rocksdb::{DBWithThreadMode, MultiThreaded};
+use rocksdb::backup::{BackupEngine, BackupEngineInfo, BackupEngineOptions, RestoreOptions};
+
+use crate::config::{get_backup_dir, get_backup_interval, get_backup_keep};
+use crate::metrics::{backup_metrics, record_backup, record_existing_backup};
+use crate::storage::lock_db;
+
+/// Returns the backups in `backup_dir`, oldest first.
+pub fn list_backups(
+    backup_dir: &str,
+) -> Result<Vec<BackupEngineInfo>, Box<dyn std::error::Error>> {
+    let engine =
+        BackupEngine::open(
+            &BackupEngineOptions::default(),
+            backup_dir,
+        )?;
+
+    Ok(engine.get_backup_info())
+}
+
+/// Creates an incremental backup of the database in `backup_dir` and purges
+/// all but the newest `keep` backups. The database lock is held while the
+/// files are copied; after the first backup only new files are.
+pub fn create_backup(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    backup_dir: &str,
+    keep: usize,
+) -> Result<BackupEngineInfo, Box<dyn std::error::Error>> {
+    let started = Instant::now();
+
+    let mut engine =
+        BackupEngine::open(
+            &BackupEngineOptions::default(),
+            backup_dir,
+        )?;
+
+    let result = {
+        let dbi = lock_db(&db);
+
+        engine.create_new_backup_flush(&dbi, true)
+    };
+
+    if let Err(err) = result {
+        record_backup(None, started.elapsed());
+
+        return Err(err.into());
+    }
+
+    engine.purge_old_backups(keep)?;
+
+    let info =
+        engine.get_backup_info()
+            .pop()
+            .ok_or("backup missing right after creating it")?;
+
+    record_backup(
+        Some((info.timestamp, info.size)),
+        started.elapsed(),
+    );
+
+    Ok(info)
+}
+
+/// Restores a backup, the newest one unless `backup_id` is given, into a new
+/// database directory at `to`.
+pub fn restore_backup(
+    backup_dir: &str,
+    to: &str,
+    backup_id: Option<u32>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut engine =
+        BackupEngine::open(
+            &BackupEngineOptions::default(),
+            backup_dir,
+        )?;
+
+    let opts = RestoreOptions::default();
+
+    match backup_id {
+        Some(backup_id) => engine.restore_from_backup(to, to, &opts, backup_id)?,
+        None => engine.restore_from_latest_backup(to, to, &opts)?,
+    }
+
+    Ok(())
+}
+
+pub async fn spawn_worker(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+) {
+    let backup_dir =
+        match get_backup_dir() {
+            Some(backup_dir) => backup_dir,
+            None => return,
+        };
+
+    if let Some(latest) = list_backups(&backup_dir).ok().map(|mut backups| backups.pop()).flatten() {
+        record_existing_backup(latest.timestamp, latest.size);
+    }
+
+    loop {
+        // counted from the last successful backup, so restarts don't keep
+        // pushing the next one out
+        let due =
+            backup_metrics()
+                .last_success_at
+                .map(|last| last + get_backup_interval().as_secs() as i64 - chrono::Utc::now().timestamp())
+                .unwrap_or(0)
+                .max(0);
+
+        tokio::time::sleep(Duration::from_secs(due as u64)).await;
+
+        let started = Instant::now();
+
+        match create_backup(db.clone(), &backup_dir, get_backup_keep()) {
+            Ok(info) =>
+                println!(
+                    "backup {} created in {}ms ({} bytes, {} files)",
+                    info.backup_id,
+                    started.elapsed().as_millis(),
+                    info.size,
+                    info.num_files,
+                ),
+            Err(err) => {
+                dbg!(err);
+
+                // don't retry a failing backup in a tight loop
+                tokio::time::sleep(get_backup_interval()).await;
+            }
+        }
+    }
+}
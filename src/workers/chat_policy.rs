@@ -0,0 +1,254 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use once_cell::sync::Lazy;
+use rocksdb::{DBWithThreadMode, Direction, IteratorMode, MultiThreaded, ReadOptions};
+use serde::{Deserialize, Serialize};
+
+use crate::ok_or_continue;
+use crate::config::get_log_private_chats;
+use crate::storage::ReadStore;
+use crate::workers::telegram_handler::{get_chat_last_activity, move_chat_activity};
+
+/// How a chat is logged, managed on the admin page. Chats without a record
+/// are logged and shown as configured.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChatPolicy {
+    // messages aren't stored while this is off
+    pub logging: bool,
+    // anonymized on top of `MINUTEMAN_ANONYMIZE_CHATS`
+    #[serde(default)]
+    pub anonymize: bool,
+    // the bot posts a summary of every day into the chat, see `/digest`
+    #[serde(default)]
+    pub digest: bool,
+    // the person on the other end of a private chat asked for it to be
+    // logged through `/start logging`
+    #[serde(default)]
+    pub private_opt_in: bool,
+    // when the bot was removed from the chat, it's archived from then on.
+    // Cleared once the bot is added back
+    #[serde(default)]
+    pub bot_removed_at: Option<i64>,
+    // seconds messages are kept before they're purged, whatever the chat's
+    // auto-delete timer says. Kept for good when unset
+    #[serde(default)]
+    pub retention: Option<i64>,
+}
+
+impl Default for ChatPolicy {
+    fn default() -> Self {
+        ChatPolicy {
+            logging: true,
+            anonymize: false,
+            digest: false,
+            private_opt_in: false,
+            bot_removed_at: None,
+            retention: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatPolicyChange {
+    pub chat_id: String,
+    pub time: i64,
+    pub before: ChatPolicy,
+    pub after: ChatPolicy,
+}
+
+// every policy is kept in memory, there's one per chat at most and the
+// telegram handler checks it for every message
+static CHAT_POLICIES: Lazy<Mutex<HashMap<String, ChatPolicy>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn lock_policies() -> MutexGuard<'static, HashMap<String, ChatPolicy>> {
+    match CHAT_POLICIES.lock() {
+        Ok(policies) => policies,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+pub fn build_chat_policy_key(
+    chat_id: &str,
+) -> String {
+    format!(
+        "chat_policy:{}",
+        chat_id,
+    )
+}
+
+// tells apart changes of the same second, see `build_chat_policy_audit_key`
+static AUDIT_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// `chat_policy_audit:{ts}:{n}:{chat_id}` holds a policy change. Older
+/// changes are under `chat_policy_audit:{ts}:{chat_id}`, a second's change
+/// of a chat replaced the one before it.
+pub fn build_chat_policy_audit_key(
+    time: i64,
+    n: u64,
+    chat_id: &str,
+) -> String {
+    format!(
+        "chat_policy_audit:{}:{:06}:{}",
+        time,
+        n % 1_000_000,
+        chat_id,
+    )
+}
+
+/// Reads every stored policy into memory, has to run once at startup.
+pub fn load_chat_policies(
+    db: &impl ReadStore,
+) {
+    let mut opts = ReadOptions::default();
+
+    opts.set_iterate_lower_bound(b"chat_policy:".to_vec());
+    opts.set_iterate_upper_bound(b"chat_policy:\x7f".to_vec());
+
+    let mut policies = lock_policies();
+
+    for (key, val) in db.iterator_opt(IteratorMode::From(b"chat_policy:", Direction::Forward), opts) {
+        let key = ok_or_continue!(String::from_utf8(key.to_vec()));
+        let policy = ok_or_continue!(serde_json::from_slice::<ChatPolicy>(&val));
+
+        policies.insert(key.trim_start_matches("chat_policy:").to_string(), policy);
+    }
+}
+
+pub fn get_chat_policy(
+    chat_id: &str,
+) -> ChatPolicy {
+    lock_policies()
+        .get(chat_id)
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Private chats have the id of the user, groups and channels negative ones.
+pub fn is_private_chat_id(
+    chat_id: &str,
+) -> bool {
+    !chat_id.starts_with('-')
+}
+
+/// Whether messages of a private chat are stored and shown, either because
+/// all of them are or because its user opted in.
+pub fn logs_private_chat(
+    chat_id: &str,
+) -> bool {
+    get_log_private_chats() || get_chat_policy(chat_id).private_opt_in
+}
+
+/// Chats that asked for a daily digest.
+pub fn find_digest_chats() -> Vec<String> {
+    lock_policies()
+        .iter()
+        .filter(|(_, policy)| policy.digest)
+        .map(|(chat_id, _)| chat_id.clone())
+        .collect()
+}
+
+/// Chats with a retention override, along with how many seconds their
+/// messages are kept.
+pub fn find_retention_chats() -> Vec<(String, i64)> {
+    lock_policies()
+        .iter()
+        .filter_map(|(chat_id, policy)| Some((chat_id.clone(), policy.retention?)))
+        .collect()
+}
+
+/// Stores a chat's policy along with an audit log entry. It applies to the
+/// next message right away, no restart needed.
+pub fn set_chat_policy(
+    db: &DBWithThreadMode<MultiThreaded>,
+    chat_id: &str,
+    policy: ChatPolicy,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let before = get_chat_policy(chat_id);
+
+    if before == policy {
+        return Ok(());
+    }
+
+    let was_archived = before.bot_removed_at.is_some();
+    let archived = policy.bot_removed_at.is_some();
+
+    let time = chrono::Utc::now().timestamp();
+
+    db.put(
+        build_chat_policy_key(chat_id),
+        serde_json::to_string(&policy)?,
+    )?;
+
+    db.put(
+        build_chat_policy_audit_key(time, AUDIT_SEQUENCE.fetch_add(1, Ordering::Relaxed), chat_id),
+        serde_json::to_string(
+            &ChatPolicyChange {
+                chat_id: chat_id.to_string(),
+                time,
+                before,
+                after: policy.clone(),
+            },
+        )?,
+    )?;
+
+    // the chats overview lists archived chats after the others
+    if let (Some(last_activity), true) = (get_chat_last_activity(db, chat_id), was_archived != archived) {
+        move_chat_activity(db, chat_id, Some((last_activity, was_archived)), (last_activity, archived))?;
+    }
+
+    lock_policies().insert(chat_id.to_string(), policy);
+
+    Ok(())
+}
+
+/// The most recent policy changes across all chats, newest first.
+pub fn find_chat_policy_changes(
+    db: &impl ReadStore,
+    limit: usize,
+) -> Vec<ChatPolicyChange> {
+    let mut opts = ReadOptions::default();
+
+    opts.set_iterate_lower_bound(b"chat_policy_audit:".to_vec());
+    opts.set_iterate_upper_bound(b"chat_policy_audit:\x7f".to_vec());
+
+    db.iterator_opt(IteratorMode::From(b"chat_policy_audit:\x7f", Direction::Reverse), opts)
+        .filter_map(|(_, val)| serde_json::from_slice::<ChatPolicyChange>(&val).ok())
+        .take(limit)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_db(name: &str) -> DBWithThreadMode<MultiThreaded> {
+        let path =
+            std::env::temp_dir()
+                .join(format!("minuteman-test-{}-{}", name, std::process::id()));
+
+        let _ = std::fs::remove_dir_all(&path);
+
+        DBWithThreadMode::<MultiThreaded>::open_default(path).unwrap()
+    }
+
+    #[test]
+    fn changes_within_a_second_are_all_audited() {
+        let db = open_db("chat-policy-audit");
+
+        for digest in [true, false, true] {
+            set_chat_policy(&db, "-1002627001", ChatPolicy { digest, ..ChatPolicy::default() }).unwrap();
+        }
+
+        let changes =
+            find_chat_policy_changes(&db, 10)
+                .into_iter()
+                .filter(|change| change.chat_id == "-1002627001")
+                .map(|change| change.after.digest)
+                .collect::<Vec<bool>>();
+
+        assert_eq!(changes, [true, false, true]);
+    }
+}
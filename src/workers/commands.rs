@@ -0,0 +1,231 @@
+use std::sync::{Arc, Mutex};
+
+use pw_telegram_bot_fork::MessageKind;
+use rocksdb::{DBWithThreadMode, MultiThreaded};
+
+use crate::config::{get_admin_user_ids, get_log_private_chats};
+use crate::storage::lock_db;
+use crate::workers::data_export::request_data_export;
+use crate::workers::chat_policy::{ChatPolicy, get_chat_policy, logs_private_chat, set_chat_policy};
+use crate::workers::ignore_list::{find_user_by_username_or_id, ignore_user, unignore_user};
+use crate::workers::telegram_handler::{Bot, ChatMeta, InterMessage};
+
+pub fn is_admin_user(
+    user_id: &str,
+) -> bool {
+    get_admin_user_ids()
+        .iter()
+        .any(|admin| admin == user_id)
+}
+
+/// Splits `/command@botname args` into the bare command and its arguments.
+pub fn parse_command(
+    text: &str,
+) -> Option<(String, Vec<String>)> {
+    if !text.starts_with('/') {
+        return None;
+    }
+
+    let mut parts = text.split_whitespace();
+
+    let command =
+        parts.next()?
+            .trim_start_matches('/')
+            .split('@')
+            .next()?
+            .to_lowercase();
+
+    Some((command, parts.map(|part| part.to_string()).collect()))
+}
+
+/// The user a command is aimed at: the author of the replied-to message, or
+/// the first argument (id or @username) resolved through the stored user meta.
+fn command_target(
+    db: &DBWithThreadMode<MultiThreaded>,
+    message: &InterMessage,
+    args: &[String],
+) -> Option<(String, String)> {
+    if let Some(from) =
+    message.reply_to_message
+        .as_ref()
+        .map(|reply| reply.from.clone())
+        .flatten() {
+        return Some((from.id.clone(), from.username.unwrap_or(from.first_name)));
+    }
+
+    let wanted = args.first()?;
+
+    find_user_by_username_or_id(db, &[wanted.clone()])
+        .into_iter()
+        .next()
+        .map(|user| (user.id.clone(), user.username.unwrap_or(user.first_name)))
+        .or_else(||
+            // unknown users can still be ignored by id
+            wanted
+                .parse::<i64>()
+                .ok()
+                .map(|id| (id.to_string(), id.to_string()))
+        )
+}
+
+/// `/digest on` and `/digest off` turn the chat's daily digest on and off,
+/// without an argument the current setting is shown.
+fn digest_command(
+    db: &DBWithThreadMode<MultiThreaded>,
+    chat_id: &str,
+    args: &[String],
+) -> Result<String, Box<dyn std::error::Error>> {
+    let policy = get_chat_policy(chat_id);
+
+    let digest =
+        match args.first().map(|arg| arg.to_lowercase()).as_deref() {
+            Some("on") => true,
+            Some("off") => false,
+            _ =>
+                return Ok(
+                    format!(
+                        "The daily digest is {}. Usage: /digest on or /digest off",
+                        if policy.digest { "on" } else { "off" },
+                    ),
+                ),
+        };
+
+    set_chat_policy(
+        db,
+        chat_id,
+        ChatPolicy {
+            digest,
+            ..policy
+        },
+    )?;
+
+    match digest {
+        true => Ok("A summary of each day will be posted here shortly after midnight.".to_string()),
+        false => Ok("No more daily summaries.".to_string()),
+    }
+}
+
+/// `/start` in a private chat tells whether it's logged, `/start logging`
+/// opts into it and `/start nologging` out again. Messages are only ever
+/// visible to the admins and whoever they share the chat with.
+fn start_command(
+    db: &DBWithThreadMode<MultiThreaded>,
+    chat_id: &str,
+    args: &[String],
+) -> Result<String, Box<dyn std::error::Error>> {
+    let policy = get_chat_policy(chat_id);
+
+    let private_opt_in =
+        match args.first().map(|arg| arg.to_lowercase()).as_deref() {
+            Some("logging") => true,
+            Some("nologging") => false,
+            _ if get_log_private_chats() =>
+                return Ok("Messages sent to me are logged.".to_string()),
+            _ =>
+                return Ok(
+                    format!(
+                        "Messages sent to me are {}. Send /start logging to have them logged, /start nologging to stop.",
+                        if logs_private_chat(chat_id) { "logged" } else { "not logged" },
+                    ),
+                ),
+        };
+
+    set_chat_policy(
+        db,
+        chat_id,
+        ChatPolicy {
+            private_opt_in,
+            ..policy
+        },
+    )?;
+
+    match private_opt_in {
+        true => Ok("Messages sent to me from now on are logged.".to_string()),
+        false if get_log_private_chats() => Ok("Messages sent to me are logged for everybody, that can't be turned off here.".to_string()),
+        false => Ok("Messages sent to me aren't logged anymore.".to_string()),
+    }
+}
+
+/// Handles admin bot commands. Returns true when the message was a command
+/// this handler took care of.
+pub async fn handle_command(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    bot: &Bot,
+    message: &InterMessage,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let text =
+        match message.kind {
+            MessageKind::Text { ref data, .. } => data,
+            _ => return Ok(false),
+        };
+
+    let (command, args) =
+        match parse_command(text) {
+            Some(command) => command,
+            None => return Ok(false),
+        };
+
+    // the one command anybody can use, about their own private chat
+    if command == "start" {
+        if let ChatMeta::User(_) = message.chat {
+            let reply = start_command(&lock_db(&db), &message.chat.id(), &args)?;
+
+            bot.send_message(&message.chat.id(), reply).await?;
+
+            return Ok(true);
+        }
+
+        return Ok(false);
+    }
+
+    // anybody may ask for what's stored about them, in private
+    if command == "mydata" {
+        if let (ChatMeta::User(_), Some(from)) = (&message.chat, &message.from) {
+            let reply = request_data_export(&lock_db(&db), bot, &from.id, &message.chat.id())?;
+
+            bot.send_message(&message.chat.id(), reply).await?;
+
+            return Ok(true);
+        }
+
+        return Ok(false);
+    }
+
+    if command != "ignore" && command != "unignore" && command != "digest" {
+        return Ok(false);
+    }
+
+    let from =
+        match message.from {
+            Some(ref from) if is_admin_user(&from.id) => from,
+            // non-admins don't get an answer, no need to advertise the command
+            _ => return Ok(false),
+        };
+
+    let reply = {
+        let db = lock_db(&db);
+
+        if command == "digest" {
+            digest_command(&db, &message.chat.id(), &args)?
+        } else {
+            match command_target(&db, message, &args) {
+                Some((user_id, name)) if command == "ignore" => {
+                    ignore_user(&db, &user_id, Some(from.id.clone()))?;
+
+                    format!("Ignoring {}, their messages won't be logged anymore.", name)
+                }
+                Some((user_id, name)) => {
+                    unignore_user(&db, &user_id)?;
+
+                    format!("No longer ignoring {}.", name)
+                }
+                None =>
+                    format!("Usage: reply to a message with /{} or use /{} @username", command, command),
+            }
+        }
+    };
+
+    bot.send_message(&message.chat.id(), reply).await?;
+
+    Ok(true)
+}
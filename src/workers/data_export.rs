@@ -0,0 +1,437 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{NaiveDateTime, Utc};
+use rocksdb::{DBWithThreadMode, Direction, IteratorMode, MultiThreaded, ReadOptions};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::task::JoinHandle;
+
+use crate::{ok_or_continue, some_or_continue};
+use crate::blob_store::resolve_blob_async;
+use crate::storage::{get_user_meta, lock_db, PendingWrites, ReadStore};
+use crate::storage_stats::prefix_iter;
+use crate::utils::{find_user_meta_history, resolve_chat_name};
+use crate::workers::telegram_handler::{Bot, build_file_key, FileEntryType, LogItem, parse_message_key};
+
+// log item keys looked at per step, the database lock is let go in between
+const EXPORT_SCAN_BATCH_SIZE: usize = 5_000;
+
+// bots may upload up to 50 MB. A part is sent before its items would take
+// up more than this, what's left is for the user's meta that goes along
+const EXPORT_PART_SIZE: usize = 45 * 1024 * 1024;
+
+// a user gets one export a day
+const EXPORT_COOLDOWN: i64 = 86_400;
+
+// failed attempts at sending an export before it's given up, telegram
+// refuses them for good once the user blocked the bot
+const EXPORT_MAX_FAILURES: u32 = 5;
+
+// how often the exports of a bot are checked for work
+const EXPORT_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// `export:state:{user_id}` holds the latest `/mydata` request of a user
+/// and how far along putting it together is.
+pub fn build_export_state_key(
+    user_id: &str,
+) -> String {
+    format!(
+        "export:state:{}",
+        user_id,
+    )
+}
+
+/// `export:item:{user_id}:{n}` holds a log item found for an export that
+/// wasn't sent yet.
+pub fn build_export_item_key(
+    user_id: &str,
+    n: usize,
+) -> String {
+    format!(
+        "export:item:{}:{:010}",
+        user_id,
+        n,
+    )
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportState {
+    pub user_id: String,
+    // the private chat the files go to, through the bot that was asked
+    pub chat_id: String,
+    pub bot: String,
+    pub requested_at: i64,
+    // the last log item key looked at, None before the first step
+    pub scanned_to: Option<String>,
+    pub scan_done: bool,
+    // found so far, sent or not
+    pub items: usize,
+    // found since the last part was sent, and their size
+    pub pending_items: usize,
+    pub pending_size: usize,
+    pub parts_sent: usize,
+    pub failures: u32,
+    pub finished_at: Option<i64>,
+}
+
+pub fn get_export_state(
+    db: &impl ReadStore,
+    user_id: &str,
+) -> Option<ExportState> {
+    db.get(build_export_state_key(user_id))
+        .ok()
+        .flatten()
+        .map(|state| serde_json::from_slice::<ExportState>(&state).ok())
+        .flatten()
+}
+
+fn format_time(
+    time: i64,
+) -> String {
+    NaiveDateTime::from_timestamp_opt(time, 0)
+        .map(|time| time.format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_default()
+}
+
+/// Queues an export of everything stored about `user_id`, to be sent to
+/// `chat_id` by `bot`. Returns the reply to the `/mydata` command.
+pub fn request_data_export(
+    db: &DBWithThreadMode<MultiThreaded>,
+    bot: &Bot,
+    user_id: &str,
+    chat_id: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let now = Utc::now().timestamp();
+
+    if let Some(state) = get_export_state(db, user_id) {
+        if state.finished_at.is_none() {
+            return Ok("Your data is still being put together, it will arrive here shortly.".to_string());
+        }
+
+        if now - state.requested_at < EXPORT_COOLDOWN {
+            return Ok(
+                format!(
+                    "You can ask for your data once a day, the next time after {} UTC.",
+                    format_time(state.requested_at + EXPORT_COOLDOWN),
+                ),
+            );
+        }
+    }
+
+    let state =
+        ExportState {
+            user_id: user_id.to_string(),
+            chat_id: chat_id.to_string(),
+            bot: bot.name.clone(),
+            requested_at: now,
+            scanned_to: None,
+            scan_done: false,
+            items: 0,
+            pending_items: 0,
+            pending_size: 0,
+            parts_sent: 0,
+            failures: 0,
+            finished_at: None,
+        };
+
+    db.put(build_export_state_key(user_id), serde_json::to_string(&state)?)?;
+
+    Ok("Putting together everything stored about you, it will arrive here as one or more JSON files.".to_string())
+}
+
+// looks at the next `EXPORT_SCAN_BATCH_SIZE` log items and keeps the ones
+// the user wrote, along with where the scan got to. Stops early and
+// returns true at an item that doesn't fit into the part anymore
+fn export_step(
+    db: &DBWithThreadMode<MultiThreaded>,
+    state: &mut ExportState,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let progress =
+        state.scanned_to
+            .clone()
+            .unwrap_or("chat:".to_string())
+            .into_bytes();
+
+    let mut opts = ReadOptions::default();
+
+    opts.set_iterate_upper_bound(b"chat:\x7f".to_vec());
+
+    let iter =
+        db.iterator_opt(
+            IteratorMode::From(&progress, Direction::Forward),
+            opts,
+        );
+
+    let mut writes = PendingWrites::new();
+    let mut scanned = 0;
+    let mut part_full = false;
+
+    state.scan_done = true;
+
+    for (key, val) in iter {
+        if key.as_ref() == progress.as_slice() {
+            continue;
+        }
+
+        if scanned >= EXPORT_SCAN_BATCH_SIZE {
+            state.scan_done = false;
+
+            break;
+        }
+
+        scanned += 1;
+
+        let key = ok_or_continue!(String::from_utf8(key.to_vec()));
+
+        let previous = state.scanned_to.replace(key.clone());
+
+        // skips chat:meta:* and friends, only chat:{chat_id}:{slot} are log items
+        let (chat_id, _) = some_or_continue!(parse_message_key(&key));
+
+        let item = ok_or_continue!(serde_json::from_slice::<LogItem>(&val));
+
+        if item.user_id() != Some(&state.user_id) {
+            continue;
+        }
+
+        let exported =
+            serde_json::to_vec(
+                &json!({
+                    "chat_id": chat_id,
+                    "chat_name": resolve_chat_name(db, chat_id),
+                    "key": key,
+                    "item": item,
+                }),
+            )?;
+
+        // left for the next part, the scan picks it up again. A part holds
+        // at least one item however large
+        if state.pending_items > 0 && state.pending_size + exported.len() > EXPORT_PART_SIZE {
+            state.scanned_to = previous;
+            state.scan_done = false;
+            part_full = true;
+
+            break;
+        }
+
+        state.pending_size += exported.len();
+
+        writes.put(&build_export_item_key(&state.user_id, state.items), exported);
+
+        state.items += 1;
+        state.pending_items += 1;
+    }
+
+    writes.put(&build_export_state_key(&state.user_id), serde_json::to_string(state)?);
+
+    writes.commit(db)?;
+
+    Ok(part_full)
+}
+
+fn build_export_part(
+    db: &DBWithThreadMode<MultiThreaded>,
+    state: &ExportState,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let items =
+        prefix_iter(db, &format!("export:item:{}:", state.user_id))
+            .filter_map(|(_, item)| serde_json::from_slice::<Value>(&item).ok())
+            .collect::<Vec<Value>>();
+
+    let mut part =
+        json!({
+            "user_id": state.user_id,
+            "requested_at": state.requested_at,
+            "part": state.parts_sent + 1,
+            "last": state.scan_done,
+            "user": get_user_meta(db, &state.user_id),
+            "items": items,
+        });
+
+    // the rest of what's kept about the user goes with the first part
+    if state.parts_sent == 0 {
+        part["meta_history"] =
+            find_user_meta_history(db, &state.user_id)
+                .into_iter()
+                .map(|(time, meta)| json!({"replaced_at": time, "meta": meta}))
+                .collect::<Value>();
+    }
+
+    // compact, so that the part is about the size of its items
+    Ok(serde_json::to_vec(&part)?)
+}
+
+// forgets the items of a part once telegram took it. A restart in between
+// sends the part again
+fn finish_part(
+    db: &DBWithThreadMode<MultiThreaded>,
+    state: &mut ExportState,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let keys =
+        prefix_iter(db, &format!("export:item:{}:", state.user_id))
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>();
+
+    for key in keys.iter() {
+        db.delete(key)?;
+    }
+
+    state.parts_sent += 1;
+    state.pending_items = 0;
+    state.pending_size = 0;
+
+    db.put(build_export_state_key(&state.user_id), serde_json::to_string(state)?)?;
+
+    Ok(())
+}
+
+async fn run_export(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    bot: &Bot,
+    mut state: ExportState,
+) -> Result<(), Box<dyn std::error::Error>> {
+    loop {
+        let part_full =
+            match state.scan_done {
+                false => export_step(&lock_db(&db), &mut state)?,
+                true => false,
+            };
+
+        // a user without any items still gets their meta
+        let part_due =
+            part_full
+                || (state.scan_done && (state.pending_items > 0 || state.parts_sent == 0));
+
+        if part_due {
+            let part = build_export_part(&lock_db(&db), &state)?;
+
+            bot.send_document(
+                &state.chat_id,
+                &format!("mydata-{}-{}.json", state.user_id, state.parts_sent + 1),
+                part,
+            ).await?;
+
+            finish_part(&lock_db(&db), &mut state)?;
+        }
+
+        if state.scan_done {
+            break;
+        }
+
+        tokio::task::yield_now().await;
+    }
+
+    let avatar_key = build_file_key(FileEntryType::User, &state.user_id);
+
+    let avatar = lock_db(&db).get(&avatar_key)?;
+
+    // fetched from its store with the database free
+    let avatar =
+        match avatar {
+            Some(value) => Some(resolve_blob_async(avatar_key, value).await?),
+            None => None,
+        };
+
+    if let Some(avatar) = avatar {
+        bot.send_document(&state.chat_id, "avatar.jpg", avatar).await?;
+    }
+
+    bot.send_message(
+        &state.chat_id,
+        format!(
+            "That's everything: {} message(s) and event(s) in {} file(s). Files you sent aren't included, the items name them.",
+            state.items,
+            state.parts_sent,
+        ),
+    ).await?;
+
+    state.finished_at = Some(Utc::now().timestamp());
+
+    lock_db(&db)
+        .put(build_export_state_key(&state.user_id), serde_json::to_string(&state)?)?;
+
+    Ok(())
+}
+
+// counts a failed attempt at an export, and gives it up after too many
+fn record_export_failure(
+    db: &DBWithThreadMode<MultiThreaded>,
+    user_id: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut state =
+        match get_export_state(db, user_id) {
+            Some(state) => state,
+            None => return Ok(()),
+        };
+
+    state.failures += 1;
+
+    if state.failures >= EXPORT_MAX_FAILURES {
+        state.finished_at = Some(Utc::now().timestamp());
+
+        let keys =
+            prefix_iter(db, &format!("export:item:{}:", user_id))
+                .map(|(key, _)| key)
+                .collect::<Vec<_>>();
+
+        for key in keys.iter() {
+            db.delete(key)?;
+        }
+    }
+
+    db.put(build_export_state_key(user_id), serde_json::to_string(&state)?)?;
+
+    Ok(())
+}
+
+/// Puts together and sends the exports requested through `bot` that
+/// aren't finished, picking up where they were left off.
+pub async fn process_data_exports(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    bot: &Bot,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let pending =
+        prefix_iter(&*lock_db(&db), "export:state:")
+            .filter_map(|(_, state)| serde_json::from_slice::<ExportState>(&state).ok())
+            .filter(|state| state.bot == bot.name && state.finished_at.is_none())
+            .collect::<Vec<ExportState>>();
+
+    for state in pending.into_iter() {
+        let user_id = state.user_id.clone();
+
+        if let Err(err) = run_export(db.clone(), bot, state).await {
+            dbg!(err);
+
+            record_export_failure(&lock_db(&db), &user_id)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Works off the `/mydata` requests of a bot until dropped.
+pub struct DataExporter(JoinHandle<()>);
+
+impl Drop for DataExporter {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+pub fn spawn_data_exporter(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    bot: Bot,
+) -> DataExporter {
+    DataExporter(
+        tokio::spawn(async move {
+            loop {
+                if let Err(err) = process_data_exports(db.clone(), &bot).await {
+                    dbg!(err);
+                }
+
+                tokio::time::sleep(EXPORT_CHECK_INTERVAL).await;
+            }
+        }),
+    )
+}
@@ -0,0 +1,183 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, Timelike, Utc};
+use rocksdb::{DBWithThreadMode, MultiThreaded};
+use tokio::task::JoinHandle;
+
+use crate::config::{get_public_url, get_timezone};
+use crate::privacy::is_chat_anonymized;
+use crate::renderer::chat_digest::build_digest;
+use crate::storage::{lock_db, ReadStore, Storage};
+use crate::utils::NameCache;
+use crate::workers::chat_policy::find_digest_chats;
+use crate::workers::telegram_handler::{Bot, build_chat_bot_key};
+
+// how long after local midnight the digests go out, late messages of the
+// day that just ended still make it in
+const DIGEST_DELAY_SECONDS: u32 = 5 * 60;
+
+const DIGEST_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// `digest_sent:{chat_id}:{day}` marks the digest of a (local) day as taken
+/// care of, whether there was anything to post or not.
+pub fn build_digest_sent_key(
+    chat_id: &str,
+    day: &str,
+) -> String {
+    format!(
+        "digest_sent:{}:{}",
+        chat_id,
+        day,
+    )
+}
+
+/// Unix timestamps of the start and end of a day in the given timezone.
+fn local_day_bounds(
+    day: NaiveDate,
+    offset: FixedOffset,
+) -> (i64, i64) {
+    let start =
+        NaiveDateTime::new(day, NaiveTime::from_hms(0, 0, 0)).timestamp()
+            - offset.local_minus_utc() as i64;
+
+    (start, start + 86_400)
+}
+
+/// Text of the digest message, None when nothing was said all day.
+pub fn compose_digest(
+    db: &impl ReadStore,
+    chat_id: &str,
+    day: NaiveDate,
+    offset: FixedOffset,
+    skip_user: Option<&str>,
+) -> Option<String> {
+    let (time_start, time_end) = local_day_bounds(day, offset);
+
+    let digest = build_digest(db, chat_id, time_start, time_end, skip_user);
+
+    if digest.messages + digest.media == 0 {
+        return None;
+    }
+
+    let mut names =
+        NameCache::new(db)
+            .with_anonymized(is_chat_anonymized(chat_id));
+
+    let mut lines =
+        vec!(
+            format!(
+                "Summary of {}: {} message(s) from {} member(s), {} photo(s).",
+                day.format("%Y-%m-%d"),
+                digest.messages + digest.media,
+                digest.posters.len(),
+                digest.photos,
+            ),
+        );
+
+    let top_posters =
+        digest.top_posters()
+            .iter()
+            .map(|(user_id, count)| format!("{} ({})", names.user(user_id, false), count))
+            .collect::<Vec<String>>();
+
+    if !top_posters.is_empty() {
+        lines.push(format!("Most active: {}", top_posters.join(", ")));
+    }
+
+    // day pages are UTC days, close enough to the local one
+    if let Some(public_url) = get_public_url() {
+        lines.push(format!("{}/chat/{}/{}", public_url, chat_id, day.format("%Y-%m-%d")));
+    }
+
+    Some(lines.join("\n"))
+}
+
+/// Posts yesterday's digest into every chat that asked for one and that
+/// this bot logs, once the configured delay after local midnight passed.
+/// Each digest is marked as sent before it goes out, so a restart never
+/// posts it twice (a failed send isn't retried either).
+pub async fn post_digests(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    bot: &Bot,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let offset = get_timezone();
+    let now = Utc::now().with_timezone(&offset);
+
+    if now.time().num_seconds_from_midnight() < DIGEST_DELAY_SECONDS {
+        return Ok(());
+    }
+
+    let day = now.date().naive_local().pred();
+    let day_label = day.format("%Y-%m-%d").to_string();
+
+    for chat_id in find_digest_chats() {
+        let text = {
+            let dbi = lock_db(&db);
+
+            let chat_bot =
+                dbi.get(build_chat_bot_key(&chat_id))?
+                    .map(|name| String::from_utf8(name).ok())
+                    .flatten();
+
+            // chats are summarized by the bot logging them
+            if chat_bot.as_deref() != Some(&bot.name) {
+                continue;
+            }
+
+            let sent_key = build_digest_sent_key(&chat_id, &day_label);
+
+            if dbi.get(&sent_key)?.is_some() {
+                continue;
+            }
+
+            // the bot's own messages include yesterday's digest
+            let text =
+                compose_digest(
+                    &dbi.read_view(),
+                    &chat_id,
+                    day,
+                    offset,
+                    bot.user_id.as_deref(),
+                );
+
+            dbi.put(&sent_key, Utc::now().timestamp().to_string())?;
+
+            text
+        };
+
+        if let Some(text) = text {
+            if let Err(err) = bot.send_message(&chat_id, text).await {
+                dbg!(err);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks for digests to post every minute until dropped.
+pub struct DigestScheduler(JoinHandle<()>);
+
+impl Drop for DigestScheduler {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+pub fn spawn_digest_scheduler(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    bot: Bot,
+) -> DigestScheduler {
+    DigestScheduler(
+        tokio::spawn(async move {
+            loop {
+                if let Err(err) = post_digests(db.clone(), &bot).await {
+                    dbg!(err);
+                }
+
+                tokio::time::sleep(DIGEST_CHECK_INTERVAL).await;
+            }
+        }),
+    )
+}
@@ -0,0 +1,307 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::Utc;
+use rocksdb::{DBWithThreadMode, MultiThreaded};
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+
+use crate::blob_store::place_blob;
+use crate::config::{get_file_retry_attempts, get_strip_exif};
+use crate::exif::strip_image_metadata;
+use crate::metrics::{record_file_retry, record_file_retry_queue};
+use crate::render_cache::invalidate_chat_pages;
+use crate::storage::lock_db;
+use crate::storage_stats::{file_counter_keys, prefix_iter, put_counted};
+use crate::thumbnail::generate_thumbnail;
+use crate::workers::telegram_handler::{Bot, build_file_failure_key, build_file_key, build_file_meta_key, FileEntryType, FileMeta, get_file_capped, store_file_error, store_file_failure, store_file_too_large};
+
+// wait before the first retry, doubled with every attempt after it
+const FILE_RETRY_BASE_DELAY: i64 = 60;
+
+// how often the queue is checked for files that are due
+const FILE_RETRY_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// `retry:file:{file_id}` marks a chat file whose download failed for a
+/// reason that may pass, see `FileFailureKind::is_retryable`.
+pub fn build_file_retry_key(
+    file_id: &str,
+) -> String {
+    format!(
+        "retry:file:{}",
+        file_id,
+    )
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileRetry {
+    // the message the file belongs to, for the storage counters
+    pub message_key: String,
+    pub attempts: u32,
+    pub next_attempt: i64,
+    // file ids only work with the bot that got them
+    pub bot: String,
+    pub max_size: i64,
+    pub mime_type: Option<String>,
+    pub file_name: Option<String>,
+}
+
+impl FileRetry {
+    pub fn new(
+        message_key: &str,
+        bot: &str,
+        max_size: i64,
+        mime_type: Option<String>,
+        file_name: Option<String>,
+    ) -> Self {
+        FileRetry {
+            message_key: message_key.to_string(),
+            attempts: 0,
+            next_attempt: Utc::now().timestamp() + FILE_RETRY_BASE_DELAY,
+            bot: bot.to_string(),
+            max_size,
+            mime_type,
+            file_name,
+        }
+    }
+}
+
+/// Queues another download of a file unless retrying is turned off.
+pub fn queue_file_retry(
+    db: &DBWithThreadMode<MultiThreaded>,
+    file_id: &str,
+    retry: &FileRetry,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if get_file_retry_attempts() == 0 {
+        return Ok(());
+    }
+
+    db.put(build_file_retry_key(file_id), serde_json::to_string(retry)?)?;
+
+    Ok(())
+}
+
+fn find_file_retries(
+    db: &DBWithThreadMode<MultiThreaded>,
+) -> Vec<(String, FileRetry)> {
+    prefix_iter(db, "retry:file:")
+        .filter_map(|(key, retry)| {
+            let file_id =
+                std::str::from_utf8(&key)
+                    .ok()?
+                    .trim_start_matches("retry:file:")
+                    .to_string();
+
+            Some((file_id, serde_json::from_slice::<FileRetry>(&retry).ok()?))
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FileRetrySummary {
+    pub attempted: usize,
+    pub stored: usize,
+    pub given_up: usize,
+    // still queued after the pass
+    pub queued: usize,
+}
+
+// stores a file the way `process_files` would have the first time
+fn store_retried_file(
+    db: &Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    bot: &Bot,
+    file_id: &str,
+    file_path: &str,
+    file: &[u8],
+    retry: &FileRetry,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let stripped =
+        match get_strip_exif() {
+            true => strip_image_metadata(file),
+            false => None,
+        };
+
+    let exif_stripped = stripped.is_some();
+
+    let file = stripped.as_deref().unwrap_or(file);
+
+    let mut meta =
+        FileMeta::from_bytes(file)
+            .with_mime_type(retry.mime_type.clone())
+            .with_file_name(retry.file_name.clone())
+            .with_file_path(file_path)
+            .with_message_key(Some(retry.message_key.clone()))
+            .with_stored_at(Utc::now().timestamp())
+            .with_bot(&bot.name);
+
+    meta.exif_stripped = exif_stripped;
+
+    let file_key = build_file_key(FileEntryType::Chat, file_id);
+    let thumb_key = build_file_key(FileEntryType::Thumb, file_id);
+
+    // blobs are stored before the database is locked for their keys
+    let blob = place_blob(&file_key, file)?;
+
+    let thumb_blob =
+        match generate_thumbnail(file) {
+            Some((thumbnail, width, height)) => {
+                meta.thumb_width = Some(width);
+                meta.thumb_height = Some(height);
+
+                Some(place_blob(&thumb_key, &thumbnail)?)
+            }
+            None => None,
+        };
+
+    let db = lock_db(&db);
+
+    put_counted(
+        &db,
+        &file_key,
+        blob,
+        &file_counter_keys("chat", Some(&retry.message_key)),
+    )?;
+
+    if let Some(thumb_blob) = thumb_blob {
+        put_counted(
+            &db,
+            &thumb_key,
+            thumb_blob,
+            &file_counter_keys("thumb", Some(&retry.message_key)),
+        )?;
+    }
+
+    db.put(build_file_meta_key(file_id), serde_json::to_string(&meta)?)?;
+    db.delete(build_file_failure_key(file_id))?;
+
+    // chat:{chat_id}:{slot}
+    if let Some(chat_id) = retry.message_key.split(':').nth(1) {
+        invalidate_chat_pages(chat_id);
+    }
+
+    Ok(())
+}
+
+/// Downloads the queued files of `bots` again. Only those that are due
+/// unless `force`, which tries every one of them right away.
+pub async fn retry_files(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    bots: &[Bot],
+    force: bool,
+) -> Result<FileRetrySummary, Box<dyn std::error::Error>> {
+    let max_attempts = get_file_retry_attempts();
+    let now = Utc::now().timestamp();
+
+    let retries = find_file_retries(&lock_db(&db));
+
+    let mut summary = FileRetrySummary::default();
+
+    for (file_id, retry) in retries.into_iter() {
+        if !force && retry.next_attempt > now {
+            continue;
+        }
+
+        // queued by a bot that's configured elsewhere, or not anymore
+        let bot =
+            match bots.iter().find(|bot| bot.name == retry.bot) {
+                Some(bot) => bot,
+                None => continue,
+            };
+
+        summary.attempted += 1;
+
+        let attempts = retry.attempts + 1;
+
+        let result =
+            match bot.get_file_path_by_id(&file_id).await {
+                Ok(file_path) =>
+                    get_file_capped(bot, &file_path, retry.max_size)
+                        .await
+                        .map(|file| file.map(|file| (file_path, file))),
+                Err(err) => Err(err),
+            };
+
+        let retry_key = build_file_retry_key(&file_id);
+
+        match result {
+            Ok(Some((file_path, file))) => {
+                store_retried_file(&db, bot, &file_id, &file_path, &file, &retry)?;
+                lock_db(&db).delete(&retry_key)?;
+
+                record_file_retry(true, false);
+                summary.stored += 1;
+            }
+            Ok(None) => {
+                store_file_too_large(db.clone(), &file_id, None, retry.max_size);
+                lock_db(&db).delete(&retry_key)?;
+
+                record_file_retry(false, true);
+                summary.given_up += 1;
+            }
+            Err(err) if err.kind.is_retryable() && attempts < max_attempts => {
+                let retry =
+                    FileRetry {
+                        attempts,
+                        next_attempt: Utc::now().timestamp() + FILE_RETRY_BASE_DELAY * 2i64.pow(attempts),
+                        ..retry
+                    };
+
+                store_file_error(db.clone(), &file_id, &err);
+                lock_db(&db).put(&retry_key, serde_json::to_string(&retry)?)?;
+
+                record_file_retry(false, false);
+            }
+            // out of attempts, or something retrying won't fix
+            Err(err) => {
+                match err.kind.is_retryable() {
+                    true =>
+                        store_file_failure(
+                            db.clone(),
+                            &file_id,
+                            &format!("download failed after {} attempts: {}", attempts, err),
+                        ),
+                    false => store_file_error(db.clone(), &file_id, &err),
+                }
+
+                lock_db(&db).delete(&retry_key)?;
+
+                record_file_retry(false, true);
+                summary.given_up += 1;
+            }
+        }
+    }
+
+    summary.queued = find_file_retries(&lock_db(&db)).len();
+
+    record_file_retry_queue(summary.queued as u64);
+
+    Ok(summary)
+}
+
+/// Works off the retry queue of a bot until dropped.
+pub struct FileRetrier(JoinHandle<()>);
+
+impl Drop for FileRetrier {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+pub fn spawn_file_retrier(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    bot: Bot,
+) -> FileRetrier {
+    FileRetrier(
+        tokio::spawn(async move {
+            let bots = [bot];
+
+            loop {
+                if let Err(err) = retry_files(db.clone(), &bots, false).await {
+                    dbg!(err);
+                }
+
+                tokio::time::sleep(FILE_RETRY_CHECK_INTERVAL).await;
+            }
+        }),
+    )
+}
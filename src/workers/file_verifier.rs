@@ -0,0 +1,298 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rocksdb::{DBWithThreadMode, Direction, IteratorMode, MultiThreaded, ReadOptions};
+
+use crate::{JOB_SLEEP_INTERVAL, ok_or_continue};
+use crate::blob_store::{place_blob, resolve_blob};
+use crate::config::{get_bots, get_strip_exif, get_verify_files_interval};
+use crate::exif::strip_image_metadata;
+use crate::storage::lock_db;
+use crate::storage_stats::{file_counter_keys, put_counted};
+use crate::utils::{get_file_corruption, get_file_meta, hash_file};
+use crate::workers::telegram_handler::{Bot, build_file_corrupt_key, FileCorruption, FileMeta, find_bot, get_file, store_file_meta};
+
+pub const VERIFY_FILES_PROGRESS_KEY: &str = "job:verify_files:progress";
+
+// blobs checked per pass before progress is saved and the lock released
+pub const VERIFY_FILES_BATCH_SIZE: usize = 100;
+
+#[derive(Debug, Clone)]
+pub struct CorruptFile {
+    pub kind: String,
+    pub file_id: String,
+    pub reason: String,
+}
+
+/// Returns why a blob can't be trusted anymore, None if it looks fine.
+pub fn verify_file(
+    kind: &str,
+    file: &[u8],
+    meta: Option<&FileMeta>,
+) -> Option<String> {
+    if let Some(meta) = meta {
+        if (file.len() as u64) < meta.size {
+            return Some(format!("truncated: {} of {} bytes", file.len(), meta.size));
+        }
+
+        if (file.len() as u64) != meta.size {
+            return Some(format!("size changed: {} bytes, expected {}", file.len(), meta.size));
+        }
+
+        if let Some(ref sha256) = meta.sha256 {
+            if *sha256 != hash_file(file) {
+                return Some("checksum mismatch".to_string());
+            }
+        }
+    }
+
+    // thumbnails and profile pictures are always images, chat files only
+    // when telegram said so
+    let is_image =
+        kind != "chat"
+            || meta
+            .map(|meta| meta.mime_type.as_deref())
+            .flatten()
+            .map(|mime_type| mime_type == "image/jpeg" || mime_type == "image/png")
+            .unwrap_or(false);
+
+    if is_image && image::load_from_memory(file).is_err() {
+        return Some("image doesn't decode".to_string());
+    }
+
+    None
+}
+
+/// Verifies up to `limit` blobs after the stored progress key, marking the
+/// corrupt ones with `file:corrupt:{id}` and clearing the marker of those
+/// that check out again. Returns the corrupt files found and whether the
+/// end of the file keyspace was reached.
+pub fn verify_files_batch(
+    db: &DBWithThreadMode<MultiThreaded>,
+    limit: usize,
+) -> Result<(Vec<CorruptFile>, bool), Box<dyn std::error::Error>> {
+    let progress =
+        db.get(VERIFY_FILES_PROGRESS_KEY)?
+            .unwrap_or(b"file:".to_vec());
+
+    let mut opts = ReadOptions::default();
+
+    opts.set_iterate_upper_bound(b"file:\x7f".to_vec());
+
+    let iter =
+        db.iterator_opt(
+            IteratorMode::From(&progress, Direction::Forward),
+            opts,
+        );
+
+    let mut corrupt = Vec::<CorruptFile>::new();
+    let mut checked = 0usize;
+    let mut last_key = None;
+
+    for (key, file) in iter {
+        if *key == *progress {
+            continue;
+        }
+
+        if checked >= limit {
+            break;
+        }
+
+        last_key = Some(key.to_vec());
+
+        let key = ok_or_continue!(String::from_utf8(key.to_vec()));
+        let parts = key.splitn(3, ':').collect::<Vec<&str>>();
+
+        // file:meta:, file:failed: and file:corrupt: aren't blobs
+        if parts.len() != 3 || !["chat", "video_thumb", "user"].contains(&parts[1]) {
+            continue;
+        }
+
+        checked += 1;
+
+        let (kind, file_id) = (parts[1], parts[2]);
+        let meta = get_file_meta(db, file_id);
+
+        let verified =
+            match resolve_blob(&key, file.to_vec()) {
+                Ok(file) => verify_file(kind, &file, meta.as_ref()),
+                Err(err) => Some(err.to_string()),
+            };
+
+        match verified {
+            Some(reason) => {
+                db.put(
+                    build_file_corrupt_key(file_id),
+                    serde_json::to_string(
+                        &FileCorruption {
+                            reason: reason.clone(),
+                            time: chrono::Utc::now().timestamp(),
+                        },
+                    )?,
+                )?;
+
+                corrupt.push(
+                    CorruptFile {
+                        kind: kind.to_string(),
+                        file_id: file_id.to_string(),
+                        reason,
+                    },
+                );
+            }
+            None => {
+                if get_file_corruption(db, file_id).is_some() {
+                    db.delete(build_file_corrupt_key(file_id))?;
+                }
+            }
+        }
+    }
+
+    match last_key {
+        Some(ref last_key) if checked >= limit => {
+            db.put(VERIFY_FILES_PROGRESS_KEY, last_key)?;
+
+            Ok((corrupt, false))
+        }
+        _ => {
+            db.delete(VERIFY_FILES_PROGRESS_KEY)?;
+
+            Ok((corrupt, true))
+        }
+    }
+}
+
+/// Downloads a corrupt file again from the path telegram gave us for it.
+/// Those paths expire, so this only works for recently stored files.
+pub async fn redownload_file(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    bots: &[Bot],
+    corrupt: &CorruptFile,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let meta =
+        match get_file_meta(&*lock_db(&db), &corrupt.file_id) {
+            Some(meta) => meta,
+            None => return Ok(false),
+        };
+
+    let file_path =
+        match meta.file_path {
+            Some(ref file_path) => file_path.clone(),
+            None => return Ok(false),
+        };
+
+    // the path only works with the token of the bot that asked for it
+    let bot =
+        match find_bot(bots, meta.bot.as_deref()) {
+            Some(bot) => bot,
+            None => return Ok(false),
+        };
+
+    let file = get_file(bot, &file_path).await?;
+
+    // the copy stored in the first place had its metadata stripped as well
+    let stripped =
+        match corrupt.kind == "chat" && get_strip_exif() {
+            true => strip_image_metadata(&file),
+            false => None,
+        };
+
+    let exif_stripped = stripped.is_some();
+
+    let file = stripped.unwrap_or(file);
+
+    let mut fresh_meta =
+        FileMeta::from_bytes(&file)
+            .with_mime_type(meta.mime_type.clone())
+            .with_file_name(meta.file_name.clone())
+            .with_file_path(&file_path)
+            .with_message_key(meta.message_key.clone())
+            .with_stored_at(chrono::Utc::now().timestamp())
+            .with_bot(&bot.name);
+
+    fresh_meta.exif_stripped = exif_stripped;
+
+    if verify_file(&corrupt.kind, &file, Some(&fresh_meta)).is_some() {
+        return Ok(false);
+    }
+
+    let file_key = format!("file:{}:{}", corrupt.kind, corrupt.file_id);
+
+    let blob = place_blob(&file_key, &file)?;
+
+    let db = lock_db(&db);
+
+    put_counted(
+        &db,
+        &file_key,
+        blob,
+        &file_counter_keys(&corrupt.kind, meta.message_key.as_deref()),
+    )?;
+
+    store_file_meta(&db, &corrupt.file_id, &fresh_meta)?;
+
+    db.delete(build_file_corrupt_key(&corrupt.file_id))?;
+
+    Ok(true)
+}
+
+/// Low priority background verification: one small batch at a time with a
+/// pause in between, and a longer pause after every complete pass.
+pub async fn spawn_worker(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+) {
+    let interval =
+        match get_verify_files_interval() {
+            Some(interval) => interval,
+            None => return,
+        };
+
+    let bots =
+        get_bots()
+            .unwrap_or_default()
+            .iter()
+            .map(Bot::new)
+            .collect::<Vec<Bot>>();
+
+    loop {
+        let result = {
+            let dbi = lock_db(&db);
+
+            verify_files_batch(&dbi, VERIFY_FILES_BATCH_SIZE)
+        };
+
+        let (corrupt, done) =
+            match result {
+                Ok(result) => result,
+                Err(err) => {
+                    dbg!(err);
+
+                    (vec!(), true)
+                }
+            };
+
+        for corrupt in corrupt.iter() {
+            println!(
+                "file:{}:{} is corrupt ({})",
+                corrupt.kind,
+                corrupt.file_id,
+                corrupt.reason,
+            );
+
+            match redownload_file(db.clone(), &bots, corrupt).await {
+                Ok(true) => println!("file:{}:{} downloaded again", corrupt.kind, corrupt.file_id),
+                Ok(false) => {}
+                Err(err) => {
+                    dbg!(err);
+                }
+            }
+        }
+
+        tokio::time::sleep(
+            if done {
+                interval
+            } else {
+                Duration::from_millis(JOB_SLEEP_INTERVAL)
+            },
+        ).await;
+    }
+}
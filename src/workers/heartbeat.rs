@@ -0,0 +1,144 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::Utc;
+use rocksdb::{DBWithThreadMode, MultiThreaded};
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+
+use crate::ok_or_continue;
+use crate::storage::{lock_db, ReadStore};
+use crate::storage_stats::prefix_iter;
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// `state:{worker}_heartbeat` holds when a worker was last seen running.
+pub fn build_heartbeat_key(
+    worker: &str,
+) -> String {
+    format!(
+        "state:{}_heartbeat",
+        worker,
+    )
+}
+
+/// `state:{worker}_last_error` holds the error a worker's last failed run
+/// ended with.
+pub fn build_last_error_key(
+    worker: &str,
+) -> String {
+    format!(
+        "state:{}_last_error",
+        worker,
+    )
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkerError {
+    pub at: i64,
+    pub error: String,
+}
+
+pub fn record_heartbeat(
+    db: &DBWithThreadMode<MultiThreaded>,
+    worker: &str,
+) -> Result<(), rocksdb::Error> {
+    db.put(
+        build_heartbeat_key(worker),
+        Utc::now().timestamp().to_string(),
+    )
+}
+
+pub fn record_last_error(
+    db: &DBWithThreadMode<MultiThreaded>,
+    worker: &str,
+    error: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    db.put(
+        build_last_error_key(worker),
+        serde_json::to_string(
+            &WorkerError {
+                at: Utc::now().timestamp(),
+                error: error.to_string(),
+            },
+        )?,
+    )?;
+
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct WorkerState {
+    pub worker: String,
+    pub heartbeat_at: Option<i64>,
+    pub last_error: Option<WorkerError>,
+}
+
+/// Every worker that ever wrote a heartbeat or an error, sorted by name.
+pub fn find_worker_states(
+    db: &impl ReadStore,
+) -> Vec<WorkerState> {
+    let mut states = Vec::<WorkerState>::new();
+
+    for (key, value) in prefix_iter(db, "state:") {
+        let key = ok_or_continue!(String::from_utf8(key.to_vec()));
+        let value = ok_or_continue!(String::from_utf8(value.to_vec()));
+
+        let key = key.trim_start_matches("state:");
+
+        let (worker, heartbeat_at, last_error) =
+            if let Some(worker) = key.strip_suffix("_heartbeat") {
+                (worker, value.parse::<i64>().ok(), None)
+            } else if let Some(worker) = key.strip_suffix("_last_error") {
+                (worker, None, serde_json::from_str::<WorkerError>(&value).ok())
+            } else {
+                continue;
+            };
+
+        match states.iter_mut().find(|state| state.worker == worker) {
+            Some(state) => {
+                state.heartbeat_at = state.heartbeat_at.or(heartbeat_at);
+                state.last_error = state.last_error.take().or(last_error);
+            }
+            None =>
+                states.push(
+                    WorkerState {
+                        worker: worker.to_string(),
+                        heartbeat_at,
+                        last_error,
+                    },
+                ),
+        }
+    }
+
+    states.sort_by(|a, b| a.worker.cmp(&b.worker));
+
+    states
+}
+
+/// Writes the heartbeat of a worker every 30 seconds until dropped. Held
+/// by a worker's run, so the heartbeat stops along with it.
+pub struct Heartbeat(JoinHandle<()>);
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+pub fn spawn_heartbeat(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    worker: String,
+) -> Heartbeat {
+    Heartbeat(
+        tokio::spawn(async move {
+            loop {
+                if let Err(err) = record_heartbeat(&lock_db(&db), &worker) {
+                    dbg!(err);
+                }
+
+                tokio::time::sleep(HEARTBEAT_INTERVAL).await;
+            }
+        }),
+    )
+}
@@ -0,0 +1,141 @@
+use std::collections::HashSet;
+
+use rocksdb::{DBWithThreadMode, Direction, IteratorMode, MultiThreaded, ReadOptions};
+use serde::{Deserialize, Serialize};
+
+use crate::config::get_ignored_users;
+use crate::ok_or_continue;
+use crate::workers::telegram_handler::UserMeta;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IgnoredUser {
+    pub added_by: Option<String>,
+    pub time: i64,
+}
+
+pub fn build_ignored_user_key(
+    user_id: &str,
+) -> String {
+    format!(
+        "ignored_user:{}",
+        user_id,
+    )
+}
+
+/// Whether messages from this user are dropped before anything is stored or
+/// downloaded, either through the config or through `/ignore`.
+pub fn is_user_ignored(
+    db: &DBWithThreadMode<MultiThreaded>,
+    user: &UserMeta,
+) -> bool {
+    let configured = get_ignored_users();
+
+    if configured.contains(&user.id)
+        || user.username
+        .as_ref()
+        .map(|username| configured.contains(&username.to_lowercase()))
+        .unwrap_or(false) {
+        return true;
+    }
+
+    db.get(build_ignored_user_key(&user.id))
+        .ok()
+        .flatten()
+        .is_some()
+}
+
+pub fn ignore_user(
+    db: &DBWithThreadMode<MultiThreaded>,
+    user_id: &str,
+    added_by: Option<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    db.put(
+        build_ignored_user_key(user_id),
+        serde_json::to_string(
+            &IgnoredUser {
+                added_by,
+                time: chrono::Utc::now().timestamp(),
+            },
+        )?,
+    )?;
+
+    Ok(())
+}
+
+pub fn unignore_user(
+    db: &DBWithThreadMode<MultiThreaded>,
+    user_id: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    db.delete(build_ignored_user_key(user_id))?;
+
+    Ok(())
+}
+
+/// Ids of every ignored user known to the database. Configured usernames
+/// are resolved through the stored user meta.
+pub fn find_ignored_user_ids(
+    db: &DBWithThreadMode<MultiThreaded>,
+) -> HashSet<String> {
+    let mut ids = HashSet::<String>::new();
+
+    let mut opts = ReadOptions::default();
+
+    opts.set_iterate_lower_bound(b"ignored_user:".to_vec());
+    opts.set_iterate_upper_bound(b"ignored_user:\x7f".to_vec());
+
+    for (key, _) in db.iterator_opt(IteratorMode::From(b"ignored_user:", Direction::Forward), opts) {
+        let key = ok_or_continue!(String::from_utf8(key.to_vec()));
+
+        ids.insert(key.trim_start_matches("ignored_user:").to_string());
+    }
+
+    let configured = get_ignored_users();
+
+    if configured.is_empty() {
+        return ids;
+    }
+
+    for user in find_user_by_username_or_id(db, &configured) {
+        ids.insert(user.id);
+    }
+
+    ids.extend(
+        configured
+            .into_iter()
+            .filter(|user| user.parse::<i64>().is_ok()),
+    );
+
+    ids
+}
+
+/// Looks up stored user meta by id or (case insensitive) username.
+pub fn find_user_by_username_or_id(
+    db: &DBWithThreadMode<MultiThreaded>,
+    users: &[String],
+) -> Vec<UserMeta> {
+    let mut found = Vec::<UserMeta>::new();
+
+    let mut opts = ReadOptions::default();
+
+    opts.set_iterate_lower_bound(b"user:meta:".to_vec());
+    opts.set_iterate_upper_bound(b"user:meta:\x7f".to_vec());
+
+    for (_, val) in db.iterator_opt(IteratorMode::From(b"user:meta:", Direction::Forward), opts) {
+        let user = ok_or_continue!(serde_json::from_slice::<UserMeta>(&val));
+
+        let matches =
+            users.iter().any(|wanted|
+                wanted == &user.id
+                    || user.username
+                    .as_ref()
+                    .map(|username| username.to_lowercase() == wanted.trim_start_matches('@').to_lowercase())
+                    .unwrap_or(false)
+            );
+
+        if matches {
+            found.push(user);
+        }
+    }
+
+    found
+}
@@ -0,0 +1,157 @@
+use std::error::Error;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use chrono::Utc;
+use rocksdb::{DBWithThreadMode, MultiThreaded};
+use serde::{Deserialize, Serialize};
+
+use crate::ok_or_continue;
+use crate::storage::ReadStore;
+use crate::storage_stats::prefix_iter;
+
+// older samples are pruned once there are more than this
+pub const MAX_INGEST_ERRORS: usize = 500;
+
+// tells apart errors logged within the same second
+static INGEST_ERROR_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// `error:{ts}:{n}` holds a failure of the ingest path, oldest first.
+pub fn build_ingest_error_key(
+    time: i64,
+    n: u64,
+) -> String {
+    format!(
+        "error:{}:{:06}",
+        time,
+        n % 1_000_000,
+    )
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IngestError {
+    pub time: i64,
+    // file_download, serialization, db_write, user_meta or store
+    pub stage: String,
+    pub chat_id: Option<String>,
+    pub message_id: Option<String>,
+    pub error: String,
+}
+
+/// Blanks out everything between double quotes. Debug output quotes the
+/// strings it contains, message texts among them, and the error log isn't
+/// supposed to become a second copy of the chats.
+fn redact_quoted(
+    text: &str,
+) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for c in text.chars() {
+        if quoted {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                quoted = false;
+                out.push_str("[redacted]\"");
+            }
+
+            continue;
+        }
+
+        if c == '"' {
+            quoted = true;
+        }
+
+        out.push(c);
+    }
+
+    if quoted {
+        out.push_str("[redacted]");
+    }
+
+    out
+}
+
+/// The error and its sources, outermost first.
+fn error_chain(
+    err: &(dyn Error + 'static),
+) -> String {
+    let mut chain = vec!(err.to_string());
+    let mut source = err.source();
+
+    while let Some(err) = source {
+        chain.push(err.to_string());
+        source = err.source();
+    }
+
+    chain.join(": ")
+}
+
+pub fn record_ingest_error(
+    db: &DBWithThreadMode<MultiThreaded>,
+    stage: &str,
+    chat_id: Option<&str>,
+    message_id: Option<&str>,
+    err: &(dyn Error + 'static),
+) -> Result<(), Box<dyn Error>> {
+    let sample =
+        IngestError {
+            time: Utc::now().timestamp(),
+            stage: stage.to_string(),
+            chat_id: chat_id.map(|chat_id| chat_id.to_string()),
+            message_id: message_id.map(|message_id| message_id.to_string()),
+            error: redact_quoted(&error_chain(err)),
+        };
+
+    db.put(
+        build_ingest_error_key(sample.time, INGEST_ERROR_SEQUENCE.fetch_add(1, Ordering::Relaxed)),
+        serde_json::to_string(&sample)?,
+    )?;
+
+    prune_ingest_errors(db)?;
+
+    Ok(())
+}
+
+// panics share the prefix, see `build_panic_key`
+fn is_panic_key(
+    key: &[u8],
+) -> bool {
+    key.starts_with(b"error:panic:")
+}
+
+fn prune_ingest_errors(
+    db: &DBWithThreadMode<MultiThreaded>,
+) -> Result<(), rocksdb::Error> {
+    let keys =
+        prefix_iter(db, "error:")
+            .map(|(key, _)| key)
+            .filter(|key| !is_panic_key(key))
+            .collect::<Vec<Box<[u8]>>>();
+
+    for key in keys.iter().take(keys.len().saturating_sub(MAX_INGEST_ERRORS)) {
+        db.delete(key)?;
+    }
+
+    Ok(())
+}
+
+/// The `limit` most recent ingest errors, newest first.
+pub fn find_ingest_errors(
+    db: &impl ReadStore,
+    limit: usize,
+) -> Vec<IngestError> {
+    let mut samples = Vec::<IngestError>::new();
+
+    for (_, value) in prefix_iter(db, "error:").filter(|(key, _)| !is_panic_key(key)) {
+        samples.push(ok_or_continue!(serde_json::from_slice::<IngestError>(&value)));
+    }
+
+    samples.reverse();
+    samples.truncate(limit);
+
+    samples
+}
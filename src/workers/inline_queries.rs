@@ -0,0 +1,162 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use chrono::Utc;
+use rocksdb::{DBWithThreadMode, MultiThreaded};
+use serde::{Deserialize, Serialize};
+
+use crate::ok_or_continue;
+use crate::storage::ReadStore;
+use crate::storage_stats::prefix_iter;
+use crate::workers::ignore_list::is_user_ignored;
+use crate::workers::telegram_handler::{Bot, UserMeta};
+
+/// `inline:{user_id}:{ts}` holds an inline query sent to one of the bots,
+/// or the result picked for it. The keyspace is apart from the chats so
+/// that listings, search and the storage counters never see it.
+pub fn build_inline_key(
+    user_id: &str,
+    time: i64,
+) -> String {
+    format!(
+        "inline:{}:{}",
+        user_id,
+        time,
+    )
+}
+
+/// An inline query as telegram sends it, the fork has no type for the
+/// chosen results.
+#[derive(Debug, Clone, Deserialize)]
+pub struct InlineUpdate {
+    pub from: pw_telegram_bot_fork::User,
+    pub query: String,
+    // chosen results only
+    #[serde(default)]
+    pub result_id: Option<String>,
+    // queries only, where the query was typed: "sender", "private", "group",
+    // "supergroup" or "channel"
+    #[serde(default)]
+    pub chat_type: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InlineLogItem {
+    pub user_id: String,
+    pub time: i64,
+    pub bot: String,
+    pub query: String,
+    // set once the user picked a result, it's stored over the query typed
+    // in the same second
+    pub result_id: Option<String>,
+    pub chat_type: Option<String>,
+}
+
+/// Records an `inline_query` or `chosen_inline_result` update. Every
+/// keystroke makes a query, the last one of each second is kept.
+pub fn handle_inline_update(
+    db: &DBWithThreadMode<MultiThreaded>,
+    bot: &Bot,
+    update: &InlineUpdate,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if is_user_ignored(db, &UserMeta::from(&update.from)) {
+        return Ok(());
+    }
+
+    // neither kind carries a date
+    let time = Utc::now().timestamp();
+
+    let item =
+        InlineLogItem {
+            user_id: update.from.id.to_string(),
+            time,
+            bot: bot.name.clone(),
+            query: update.query.clone(),
+            result_id: update.result_id.clone(),
+            chat_type: update.chat_type.clone(),
+        };
+
+    db.put(
+        build_inline_key(&item.user_id, time),
+        serde_json::to_string(&item)?,
+    )?;
+
+    Ok(())
+}
+
+/// Recorded inline queries and results since `since`, newest first.
+pub fn find_inline_items(
+    db: &impl ReadStore,
+    since: i64,
+) -> Vec<InlineLogItem> {
+    let mut items = Vec::<InlineLogItem>::new();
+
+    // keyed by user first, they're sorted by time afterwards
+    for (_, value) in prefix_iter(db, "inline:") {
+        let item = ok_or_continue!(serde_json::from_slice::<InlineLogItem>(&value));
+
+        if item.time >= since {
+            items.push(item);
+        }
+    }
+
+    items.sort_by(|a, b| b.time.cmp(&a.time));
+
+    items
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct InlineQueryStats {
+    pub queries: usize,
+    pub chosen: usize,
+    pub users: usize,
+    // (query, times typed, times a result was picked), most used first
+    pub top_queries: Vec<(String, usize, usize)>,
+    // day since the epoch -> (queries, chosen results)
+    pub per_day: BTreeMap<i64, (usize, usize)>,
+}
+
+/// Sums up `items`. Queries are compared trimmed and lowercased.
+pub fn inline_query_stats(
+    items: &[InlineLogItem],
+    top: usize,
+) -> InlineQueryStats {
+    let mut stats = InlineQueryStats::default();
+
+    let mut queries = HashMap::<String, (usize, usize)>::new();
+    let mut users = HashSet::<&str>::new();
+
+    for item in items.iter() {
+        let chosen = item.result_id.is_some();
+
+        let query = queries.entry(item.query.trim().to_lowercase()).or_default();
+        let day = stats.per_day.entry(item.time / 86_400).or_default();
+
+        if chosen {
+            stats.chosen += 1;
+            query.1 += 1;
+            day.1 += 1;
+        } else {
+            stats.queries += 1;
+            query.0 += 1;
+            day.0 += 1;
+        }
+
+        users.insert(&item.user_id);
+    }
+
+    stats.users = users.len();
+
+    let mut top_queries =
+        queries
+            .into_iter()
+            .filter(|(query, _)| !query.is_empty())
+            .map(|(query, (typed, chosen))| (query, typed, chosen))
+            .collect::<Vec<_>>();
+
+    top_queries.sort_by(|a, b| (b.1 + b.2, &a.0).cmp(&(a.1 + a.2, &b.0)));
+    top_queries.truncate(top);
+
+    stats.top_queries = top_queries;
+
+    stats
+}
@@ -0,0 +1,143 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::Utc;
+use rocksdb::{DBWithThreadMode, MultiThreaded};
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+
+use crate::storage::{lock_db, ReadStore};
+use crate::storage_stats::prefix_iter;
+use crate::workers::telegram_handler::Bot;
+
+// pause between two getChatMemberCount calls, a bot in many chats spreads
+// its daily round over a while
+const MEMBER_COUNT_API_INTERVAL: Duration = Duration::from_secs(5);
+
+// how often the chats are checked for a missing snapshot of the day
+const MEMBER_COUNT_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// `chat_members_count:{chat_id}:{day}` holds a chat's member count on a
+/// day (days since the epoch, like `chat_index`).
+pub fn build_member_count_key(
+    chat_id: &str,
+    day: i64,
+) -> String {
+    format!(
+        "chat_members_count:{}:{}",
+        chat_id,
+        day,
+    )
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MemberCountSnapshot {
+    pub count: i64,
+    pub time: i64,
+}
+
+/// The member count snapshots of a chat along with their day, oldest first.
+pub fn find_member_counts(
+    db: &impl ReadStore,
+    chat_id: &str,
+) -> Vec<(i64, MemberCountSnapshot)> {
+    let prefix = format!("chat_members_count:{}:", chat_id);
+
+    prefix_iter(db, &prefix)
+        .filter_map(|(key, val)| {
+            let day =
+                std::str::from_utf8(&key)
+                    .ok()?
+                    .trim_start_matches(&prefix)
+                    .parse::<i64>()
+                    .ok()?;
+
+            let snapshot = serde_json::from_slice::<MemberCountSnapshot>(&val).ok()?;
+
+            Some((day, snapshot))
+        })
+        .collect()
+}
+
+// groups, supergroups and channels the bot logs that have no snapshot today
+fn find_chats_due(
+    db: &DBWithThreadMode<MultiThreaded>,
+    bot: &Bot,
+    day: i64,
+) -> Vec<String> {
+    prefix_iter(db, "chat_bot:")
+        .filter(|(_, name)| **name == *bot.name.as_bytes())
+        .filter_map(|(key, _)| String::from_utf8(key.to_vec()).ok())
+        .map(|key| key.trim_start_matches("chat_bot:").to_string())
+        .filter(|chat_id| chat_id.starts_with('-'))
+        .filter(|chat_id|
+            db.get(build_member_count_key(chat_id, day))
+                .ok()
+                .flatten()
+                .is_none()
+        )
+        .collect()
+}
+
+async fn take_member_counts(
+    db: &Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    bot: &Bot,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let day = Utc::now().timestamp() / 86_400;
+
+    let chats = find_chats_due(&*lock_db(&db), bot, day);
+
+    for chat_id in chats.iter() {
+        // chats the bot was removed from answer with an error every day,
+        // which is just skipped
+        match bot.get_chat_member_count(chat_id).await {
+            Ok(Some(count)) => {
+                let snapshot =
+                    MemberCountSnapshot {
+                        count,
+                        time: Utc::now().timestamp(),
+                    };
+
+                lock_db(&db).put(
+                    build_member_count_key(chat_id, day),
+                    serde_json::to_string(&snapshot)?,
+                )?;
+            }
+            Ok(None) => {}
+            Err(err) => {
+                dbg!(chat_id, err);
+            }
+        }
+
+        tokio::time::sleep(MEMBER_COUNT_API_INTERVAL).await;
+    }
+
+    Ok(())
+}
+
+/// Records the member count of every chat the bot logs once a day, until
+/// dropped.
+pub struct MemberCountPoller(JoinHandle<()>);
+
+impl Drop for MemberCountPoller {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+pub fn spawn_member_count_poller(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    bot: Bot,
+) -> MemberCountPoller {
+    MemberCountPoller(
+        tokio::spawn(async move {
+            loop {
+                if let Err(err) = take_member_counts(&db, &bot).await {
+                    dbg!(err);
+                }
+
+                tokio::time::sleep(MEMBER_COUNT_CHECK_INTERVAL).await;
+            }
+        }),
+    )
+}
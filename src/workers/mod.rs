@@ -1,2 +1,22 @@
 pub mod telegram_handler;
 pub mod server_handler;
+pub mod user_meta_handler;
+pub mod ignore_list;
+pub mod commands;
+pub mod reprocess;
+pub mod backup_handler;
+pub mod vacuum;
+pub mod file_verifier;
+pub mod chat_policy;
+pub mod digest;
+pub mod restart;
+pub mod heartbeat;
+pub mod ingest_errors;
+pub mod sticker_sets;
+pub mod member_counts;
+pub mod file_retry;
+pub mod panics;
+pub mod inline_queries;
+pub mod secondary;
+pub mod data_export;
+pub mod auto_delete;
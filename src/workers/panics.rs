@@ -0,0 +1,291 @@
+use std::backtrace::Backtrace;
+use std::cell::RefCell;
+use std::panic::PanicInfo;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, TryLockError};
+use std::thread;
+
+use chrono::Utc;
+use once_cell::sync::OnceCell;
+use rocksdb::{DBWithThreadMode, MultiThreaded};
+use serde::{Deserialize, Serialize};
+
+use crate::ok_or_continue;
+use crate::storage::{lock_db, ReadStore};
+use crate::storage_stats::prefix_iter;
+
+static PANIC_DB: OnceCell<Arc<Mutex<DBWithThreadMode<MultiThreaded>>>> = OnceCell::new();
+
+// threads panicking within the same second get keys of their own
+static PANIC_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// `error:panic:{ts}:{n}` holds a panic of any thread, along with what was
+/// being worked on when it happened. Older panics are under
+/// `error:panic:{ts}`, which sorts right before the ones of that second.
+pub fn build_panic_key(
+    time: i64,
+    n: u64,
+) -> String {
+    format!(
+        "error:panic:{}:{:06}",
+        time,
+        n % 1_000_000,
+    )
+}
+
+/// `panic:update:{bot}:{update_id}` marks an update that made the handler
+/// panic, it's skipped when telegram sends it again after the restart.
+pub fn build_panicked_update_key(
+    bot: &str,
+    update_id: i64,
+) -> String {
+    format!(
+        "panic:update:{}:{}",
+        bot,
+        update_id,
+    )
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PanicContext {
+    pub bot: Option<String>,
+    pub update_id: Option<i64>,
+    pub chat_id: Option<String>,
+    pub message_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PanicRecord {
+    pub time: i64,
+    pub thread: String,
+    pub message: String,
+    pub location: Option<String>,
+    pub backtrace: String,
+    pub context: PanicContext,
+}
+
+thread_local! {
+    // the telegram handler runs its update loop on one thread, so whatever
+    // it's at is known to the panic hook
+    static PANIC_CONTEXT: RefCell<PanicContext> = RefCell::new(PanicContext::default());
+}
+
+/// Forgets the update the thread was working on once dropped.
+pub struct PanicContextGuard;
+
+impl Drop for PanicContextGuard {
+    fn drop(&mut self) {
+        PANIC_CONTEXT.with(|context| *context.borrow_mut() = PanicContext::default());
+    }
+}
+
+/// Remembers the update the current thread works on until the returned
+/// guard is dropped.
+pub fn enter_update(
+    bot: &str,
+    update_id: i64,
+) -> PanicContextGuard {
+    PANIC_CONTEXT.with(|context|
+        *context.borrow_mut() =
+            PanicContext {
+                bot: Some(bot.to_string()),
+                update_id: Some(update_id),
+                chat_id: None,
+                message_id: None,
+            }
+    );
+
+    PanicContextGuard
+}
+
+/// Adds the message being stored to the context of the current update.
+pub fn set_panic_message(
+    chat_id: &str,
+    message_id: &str,
+) {
+    PANIC_CONTEXT.with(|context| {
+        let mut context = context.borrow_mut();
+
+        context.chat_id = Some(chat_id.to_string());
+        context.message_id = Some(message_id.to_string());
+    });
+}
+
+pub fn is_panicked_update(
+    db: &impl ReadStore,
+    bot: &str,
+    update_id: i64,
+) -> bool {
+    db.get(build_panicked_update_key(bot, update_id))
+        .ok()
+        .flatten()
+        .is_some()
+}
+
+fn store_panic(
+    db: &DBWithThreadMode<MultiThreaded>,
+    key: &str,
+    record: &PanicRecord,
+) {
+    let result =
+        serde_json::to_string(record)
+            .map_err(|err| err.to_string())
+            .and_then(|record_json|
+                db.put(key, record_json)
+                    .map_err(|err| err.to_string())
+            );
+
+    if let Err(err) = result {
+        eprintln!("can't store panic: {}", err);
+    }
+
+    if let (Some(ref bot), Some(update_id)) = (&record.context.bot, record.context.update_id) {
+        if let Err(err) = db.put(build_panicked_update_key(bot, update_id), record.time.to_string()) {
+            eprintln!("can't mark update {} as panicked: {}", update_id, err);
+        }
+    }
+}
+
+fn panic_message(
+    info: &PanicInfo,
+) -> String {
+    info.payload()
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or(info.payload().downcast_ref::<String>().cloned())
+        .unwrap_or("unknown panic".to_string())
+}
+
+fn on_panic(
+    info: &PanicInfo,
+) {
+    let current = thread::current();
+
+    let record =
+        PanicRecord {
+            time: Utc::now().timestamp(),
+            thread:
+            current
+                .name()
+                .map(|name| name.to_string())
+                .unwrap_or(format!("{}", current.id().as_u64())),
+            message: panic_message(info),
+            location: info.location().map(|location| location.to_string()),
+            backtrace: Backtrace::force_capture().to_string(),
+            context: PANIC_CONTEXT.with(|context| context.borrow().clone()),
+        };
+
+    tracing::error!(
+        thread = %record.thread,
+        location = record.location.as_deref().unwrap_or("?"),
+        bot = record.context.bot.as_deref().unwrap_or("-"),
+        update_id = record.context.update_id.unwrap_or(0),
+        chat_id = record.context.chat_id.as_deref().unwrap_or("-"),
+        message_id = record.context.message_id.as_deref().unwrap_or("-"),
+        backtrace = %record.backtrace,
+        "panicked: {}",
+        record.message,
+    );
+
+    let db =
+        match PANIC_DB.get() {
+            Some(db) => db.clone(),
+            None => return,
+        };
+
+    let key = build_panic_key(record.time, PANIC_SEQUENCE.fetch_add(1, Ordering::Relaxed));
+
+    // the panicking thread may hold the lock itself, it lets go of it while
+    // unwinding
+    let blocked =
+        match db.try_lock() {
+            Ok(dbi) => {
+                store_panic(&dbi, &key, &record);
+                false
+            }
+            Err(TryLockError::Poisoned(poisoned)) => {
+                store_panic(&poisoned.into_inner(), &key, &record);
+                false
+            }
+            Err(TryLockError::WouldBlock) => true,
+        };
+
+    if blocked {
+        thread::spawn(move || {
+            store_panic(&lock_db(&db), &key, &record);
+        });
+    }
+}
+
+/// Records every panic in the database and the log, instead of only on
+/// stderr. The default hook's output is gone along with it.
+pub fn install_panic_hook(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+) {
+    let _ = PANIC_DB.set(db);
+
+    std::panic::set_hook(Box::new(on_panic));
+}
+
+/// The `limit` most recent panics, newest first.
+pub fn find_panics(
+    db: &impl ReadStore,
+    limit: usize,
+) -> Vec<PanicRecord> {
+    let mut records = Vec::<PanicRecord>::new();
+
+    for (_, value) in prefix_iter(db, "error:panic:") {
+        records.push(ok_or_continue!(serde_json::from_slice::<PanicRecord>(&value)));
+    }
+
+    records.reverse();
+    records.truncate(limit);
+
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_db(name: &str) -> DBWithThreadMode<MultiThreaded> {
+        let path =
+            std::env::temp_dir()
+                .join(format!("minuteman-test-{}-{}", name, std::process::id()));
+
+        let _ = std::fs::remove_dir_all(&path);
+
+        DBWithThreadMode::<MultiThreaded>::open_default(path).unwrap()
+    }
+
+    fn record(time: i64, message: &str) -> PanicRecord {
+        PanicRecord {
+            time,
+            thread: "test".to_string(),
+            message: message.to_string(),
+            location: None,
+            backtrace: String::new(),
+            context: PanicContext::default(),
+        }
+    }
+
+    #[test]
+    fn panics_within_a_second_are_all_kept() {
+        let db = open_db("panics-same-second");
+
+        // stored the way panics were before they had a sequence number
+        db.put("error:panic:1600000000", serde_json::to_string(&record(1_600_000_000, "old")).unwrap()).unwrap();
+
+        store_panic(&db, &build_panic_key(1_600_000_000, 7), &record(1_600_000_000, "first"));
+        store_panic(&db, &build_panic_key(1_600_000_000, 8), &record(1_600_000_000, "second"));
+        store_panic(&db, &build_panic_key(1_600_000_001, 9), &record(1_600_000_001, "third"));
+
+        let messages =
+            find_panics(&db, 10)
+                .into_iter()
+                .map(|record| record.message)
+                .collect::<Vec<String>>();
+
+        assert_eq!(messages, vec!["third", "second", "first", "old"]);
+    }
+}
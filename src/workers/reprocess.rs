@@ -0,0 +1,419 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use rocksdb::{DBWithThreadMode, Direction, IteratorMode, MultiThreaded, ReadOptions};
+
+use crate::config::get_search_index;
+use crate::migrations::to_versioned_string;
+use crate::search_index::update_postings;
+use crate::some_or_continue;
+use crate::storage::{lock_db, PendingWrites};
+use crate::storage_stats::{message_counter_keys, put_counted};
+use crate::workers::telegram_handler::{build_log_item, build_message_key, build_poll_ref_key, find_message_slot, InterMessage, LogItem, LogItemMediaType, LogItemSpecialType, MessageSlot};
+
+#[derive(Debug, Clone, Default)]
+pub struct ReprocessSummary {
+    pub scanned: usize,
+    pub unchanged: usize,
+    pub rewritten: usize,
+    // raw messages whose log item is gone or was redacted
+    pub skipped: usize,
+    // (old variant, new variant) -> count, for rewritten items only
+    pub changes: BTreeMap<(&'static str, &'static str), usize>,
+}
+
+pub fn log_item_variant(
+    log_item: &LogItem,
+) -> &'static str {
+    match log_item {
+        LogItem::Message { .. } => "Message",
+        LogItem::Media { .. } => "Media",
+        LogItem::Special { .. } => "Special",
+        LogItem::Membership { .. } => "Membership",
+        LogItem::Chat { .. } => "Chat",
+        LogItem::Pin { .. } => "Pin",
+        LogItem::Unimplemented(..) => "Unimplemented",
+        LogItem::Redacted { .. } => "Redacted",
+    }
+}
+
+/// Returns the stored raw messages of a chat as `(timestamp, message)`
+/// pairs, limited to `from..=to` when given.
+pub fn find_chat_raw_messages(
+    db: &DBWithThreadMode<MultiThreaded>,
+    chat_id: &str,
+    from: Option<i64>,
+    to: Option<i64>,
+) -> Vec<(i64, InterMessage)> {
+    let mut opts = ReadOptions::default();
+
+    let lower_bound = format!("chat_raw:{}:", chat_id).as_bytes().to_vec();
+    let upper_bound = format!("chat_raw:{}:\x7f", chat_id).as_bytes().to_vec();
+
+    opts.set_iterate_upper_bound(upper_bound.clone());
+    opts.set_iterate_lower_bound(lower_bound.clone());
+
+    let mut messages = Vec::<(i64, InterMessage)>::new();
+
+    // timestamps aren't zero padded, so the range is checked per key
+    for (key, val) in db.iterator_opt(IteratorMode::From(&lower_bound, Direction::Forward), opts) {
+        let key = some_or_continue!(std::str::from_utf8(&key).ok());
+
+        let timestamp =
+            some_or_continue!(
+                key.split(':')
+                    .nth(2)
+                    .map(|timestamp| timestamp.parse::<i64>().ok())
+                    .flatten()
+            );
+
+        if from.map(|from| timestamp < from).unwrap_or(false)
+            || to.map(|to| timestamp > to).unwrap_or(false) {
+            continue;
+        }
+
+        let message = some_or_continue!(serde_json::from_slice::<InterMessage>(&val).ok());
+
+        messages.push((timestamp, message));
+    }
+
+    messages
+}
+
+// carries over what only the original download could produce: the files
+// are not fetched again, so a rebuilt item keeps the ones already stored
+fn preserve_files(
+    previous: &LogItem,
+    log_item: &mut LogItem,
+) {
+    let (previous_files, previous_media_type) =
+        match previous {
+            LogItem::Media { files, media_type, .. } => (files, media_type),
+            _ => return,
+        };
+
+    if let LogItem::Media { files, media_type, .. } = log_item {
+        if files.is_empty() {
+            *files = previous_files.clone();
+        }
+
+        match (media_type, previous_media_type) {
+            (
+                LogItemMediaType::Video { thumb_file_id, .. },
+                LogItemMediaType::Video { thumb_file_id: previous_thumb, .. },
+            )
+            | (
+                LogItemMediaType::VideoNote { thumb_file_id, .. },
+                LogItemMediaType::VideoNote { thumb_file_id: previous_thumb, .. },
+            )
+            | (
+                LogItemMediaType::Animation { thumb_file_id, .. },
+                LogItemMediaType::Animation { thumb_file_id: previous_thumb, .. },
+            )
+            | (
+                LogItemMediaType::Image { thumb_file_id, .. },
+                LogItemMediaType::Image { thumb_file_id: previous_thumb, .. },
+            ) => {
+                if thumb_file_id.is_none() {
+                    *thumb_file_id = previous_thumb.clone();
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Runs the stored raw messages of a chat through the current log item
+/// mapping again and rewrites the entries that come out different. Nothing
+/// is fetched from telegram, entries without a raw message are never
+/// touched, and running it twice changes nothing the second time.
+pub async fn reprocess_chat(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    chat_id: &str,
+    from: Option<i64>,
+    to: Option<i64>,
+    dry_run: bool,
+) -> Result<ReprocessSummary, Box<dyn std::error::Error>> {
+    let messages = {
+        let dbi = lock_db(&db);
+
+        find_chat_raw_messages(
+            &dbi,
+            chat_id,
+            from,
+            to,
+        )
+    };
+
+    let mut summary = ReprocessSummary::default();
+
+    for (timestamp, message) in messages.iter() {
+        summary.scanned += 1;
+
+        let slot = MessageSlot::new(*timestamp, i64::from(message.id));
+
+        // items from before message ids were part of the key are rewritten
+        // where they are
+        let (slot, previous) = {
+            let dbi = lock_db(&db);
+
+            let slot = find_message_slot(&*dbi, chat_id, slot).unwrap_or(slot);
+
+            let previous =
+                dbi.get(build_message_key(chat_id, slot))?
+                    .map(|previous| serde_json::from_slice::<LogItem>(&previous).ok())
+                    .flatten();
+
+            (slot, previous)
+        };
+
+        let message_key = build_message_key(chat_id, slot);
+
+        let previous =
+            match previous {
+                Some(LogItem::Redacted { .. }) | None => {
+                    summary.skipped += 1;
+
+                    continue;
+                }
+                Some(previous) => previous,
+            };
+
+        let files =
+            match previous {
+                LogItem::Media { ref files, .. } => files.clone(),
+                _ => vec!(),
+            };
+
+        // without an api nothing gets downloaded, so nothing is queued
+        let mut log_item =
+            build_log_item(
+                db.clone(),
+                None,
+                message,
+                &files,
+                &mut PendingWrites::new(),
+            ).await;
+
+        preserve_files(&previous, &mut log_item);
+
+        let previous_value = serde_json::to_string(&previous)?;
+        let log_item_value = serde_json::to_string(&log_item)?;
+
+        if previous_value == log_item_value {
+            summary.unchanged += 1;
+
+            continue;
+        }
+
+        summary.rewritten += 1;
+
+        *summary.changes
+            .entry((log_item_variant(&previous), log_item_variant(&log_item)))
+            .or_default() += 1;
+
+        if dry_run {
+            continue;
+        }
+
+        let dbi = lock_db(&db);
+
+        put_counted(
+            &dbi,
+            &message_key,
+            to_versioned_string(&log_item)?,
+            &message_counter_keys(chat_id),
+        )?;
+
+        // the same secondary keys handle_message writes for these kinds
+
+        if get_search_index() {
+            update_postings(
+                &dbi,
+                chat_id,
+                slot,
+                Some(&previous),
+                Some(&log_item),
+            )?;
+        }
+
+        if let LogItem::Special { special_type: LogItemSpecialType::Poll { ref id, .. }, .. } = log_item {
+            dbi.put(
+                build_poll_ref_key(id),
+                &message_key,
+            )?;
+        }
+
+        if let LogItem::Pin { .. } = log_item {
+            dbi.put(
+                format!("chat_pins:{}:{}", chat_id, slot),
+                &b"\0",
+            )?;
+        }
+    }
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use pw_telegram_bot_fork::{Update, UpdateKind};
+    use serde_json::Value;
+
+    use crate::workers::telegram_handler::build_raw_message_key;
+
+    use super::*;
+
+    const CHAT_ID: &str = "-1001234567890";
+
+    fn open_db(name: &str) -> Arc<Mutex<DBWithThreadMode<MultiThreaded>>> {
+        let path =
+            std::env::temp_dir()
+                .join(format!("minuteman-test-{}-{}", name, std::process::id()));
+
+        let _ = std::fs::remove_dir_all(&path);
+
+        Arc::new(Mutex::new(DBWithThreadMode::<MultiThreaded>::open_default(path).unwrap()))
+    }
+
+    /// The message of a captured update under tests/fixtures/updates, read
+    /// the way `run` reads it.
+    fn captured_message(raw_update: &str) -> InterMessage {
+        let raw_update = serde_json::from_str::<Value>(raw_update).unwrap();
+        let raw_message = raw_update["message"].clone();
+
+        match serde_json::from_value::<Update>(raw_update).unwrap().kind {
+            UpdateKind::Message(message) => InterMessage::from(&message).with_raw(&raw_message),
+            kind => panic!("not a message: {:?}", kind),
+        }
+    }
+
+    /// What the live path makes of the message, the golden item reprocessing
+    /// has to arrive at.
+    fn golden_log_item(db: &Arc<Mutex<DBWithThreadMode<MultiThreaded>>>, message: &InterMessage, files: &Vec<String>) -> LogItem {
+        futures::executor::block_on(
+            build_log_item(db.clone(), None, message, files, &mut PendingWrites::new()),
+        )
+    }
+
+    fn put_raw(db: &Arc<Mutex<DBWithThreadMode<MultiThreaded>>>, timestamp: i64, message: &InterMessage) {
+        lock_db(&db)
+            .put(
+                build_raw_message_key(CHAT_ID, timestamp, &message.id.to_string()),
+                serde_json::to_string(message).unwrap(),
+            )
+            .unwrap();
+    }
+
+    fn put_log_item(db: &Arc<Mutex<DBWithThreadMode<MultiThreaded>>>, timestamp: i64, log_item: &LogItem) {
+        lock_db(&db)
+            .put(
+                build_message_key(CHAT_ID, timestamp),
+                to_versioned_string(log_item).unwrap(),
+            )
+            .unwrap();
+    }
+
+    fn stored(db: &Arc<Mutex<DBWithThreadMode<MultiThreaded>>>, timestamp: i64) -> Option<Value> {
+        lock_db(&db)
+            .get(build_message_key(CHAT_ID, timestamp))
+            .unwrap()
+            .map(|value| serde_json::from_slice::<Value>(&value).unwrap())
+    }
+
+    fn value(log_item: &LogItem) -> Value {
+        serde_json::from_str(&to_versioned_string(log_item).unwrap()).unwrap()
+    }
+
+    fn reprocess(db: &Arc<Mutex<DBWithThreadMode<MultiThreaded>>>, dry_run: bool) -> ReprocessSummary {
+        futures::executor::block_on(reprocess_chat(db.clone(), CHAT_ID, None, None, dry_run)).unwrap()
+    }
+
+    #[test]
+    fn stored_raw_updates_reprocess_into_the_golden_log_items() {
+        let db = open_db("reprocess-golden");
+
+        let custom_emoji = captured_message(include_str!("../../tests/fixtures/updates/custom_emoji.json"));
+        let unknown_entity = captured_message(include_str!("../../tests/fixtures/updates/unknown_entity.json"));
+        let premium_sticker = captured_message(include_str!("../../tests/fixtures/updates/premium_sticker.json"));
+        let video_sticker = captured_message(include_str!("../../tests/fixtures/updates/video_sticker.json"));
+
+        let sticker_files = vec!["sticker-file".to_string()];
+
+        let golden_custom_emoji = golden_log_item(&db, &custom_emoji, &vec![]);
+        let golden_unknown_entity = golden_log_item(&db, &unknown_entity, &vec![]);
+        let golden_premium_sticker = golden_log_item(&db, &premium_sticker, &sticker_files);
+
+        // all of the captures share a date, each gets its own second here
+        let (t_custom_emoji, t_unknown_entity, t_premium_sticker, t_video_sticker, t_without_raw) =
+            (1_600_000_000, 1_600_000_001, 1_600_000_002, 1_600_000_003, 1_600_000_004);
+
+        // archived before custom emoji were mapped
+        put_raw(&db, t_custom_emoji, &custom_emoji);
+        put_log_item(&db, t_custom_emoji, &LogItem::Unimplemented("custom_emoji".to_string(), Some("1001".to_string()), 1_600_000_000, None));
+
+        // already mapped the way the current code maps them
+        put_raw(&db, t_unknown_entity, &unknown_entity);
+        put_log_item(&db, t_unknown_entity, &golden_unknown_entity);
+
+        // its file can't be downloaded again
+        put_raw(&db, t_premium_sticker, &premium_sticker);
+        put_log_item(&db, t_premium_sticker, &golden_premium_sticker);
+
+        // the log item is gone, only the raw message is left
+        put_raw(&db, t_video_sticker, &video_sticker);
+
+        // no raw counterpart, reprocessing doesn't know about it
+        let without_raw = LogItem::Unimplemented("proximity_alert_triggered".to_string(), None, 1_600_000_004, None);
+
+        put_log_item(&db, t_without_raw, &without_raw);
+
+        let summary = reprocess(&db, true);
+
+        assert_eq!((summary.scanned, summary.unchanged, summary.rewritten, summary.skipped), (4, 2, 1, 1));
+        assert_eq!(summary.changes.get(&("Unimplemented", "Message")), Some(&1));
+        assert_eq!(summary.changes.len(), 1);
+
+        // a dry run only counts
+        assert!(matches!(
+            serde_json::from_value::<LogItem>(stored(&db, t_custom_emoji).unwrap()).unwrap(),
+            LogItem::Unimplemented(..)
+        ));
+
+        let summary = reprocess(&db, false);
+
+        assert_eq!((summary.scanned, summary.unchanged, summary.rewritten, summary.skipped), (4, 2, 1, 1));
+
+        assert_eq!(stored(&db, t_custom_emoji), Some(value(&golden_custom_emoji)));
+        assert_eq!(stored(&db, t_unknown_entity), Some(value(&golden_unknown_entity)));
+        assert_eq!(stored(&db, t_premium_sticker), Some(value(&golden_premium_sticker)));
+        assert_eq!(stored(&db, t_video_sticker), None);
+        assert_eq!(stored(&db, t_without_raw), Some(value(&without_raw)));
+
+        match serde_json::from_value::<LogItem>(stored(&db, t_premium_sticker).unwrap()).unwrap() {
+            LogItem::Media { files, .. } => assert_eq!(files, sticker_files),
+            _ => panic!("not media"),
+        }
+
+        // and a second run has nothing left to do
+        let summary = reprocess(&db, false);
+
+        assert_eq!((summary.scanned, summary.unchanged, summary.rewritten, summary.skipped), (4, 3, 0, 1));
+        assert!(summary.changes.is_empty());
+    }
+
+    #[test]
+    fn reprocessing_keeps_files_it_cannot_download_again() {
+        let db = open_db("reprocess-files");
+
+        let sticker = captured_message(include_str!("../../tests/fixtures/updates/video_sticker.json"));
+        let golden = golden_log_item(&db, &sticker, &vec!["sticker-file".to_string()]);
+
+        // rebuilt without anything downloaded
+        let mut rebuilt = golden_log_item(&db, &sticker, &vec![]);
+
+        preserve_files(&golden, &mut rebuilt);
+
+        assert_eq!(value(&rebuilt), value(&golden));
+    }
+}
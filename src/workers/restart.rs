@@ -0,0 +1,76 @@
+use std::fmt;
+use std::time::Duration;
+
+use crate::{MAX_RESTART_BACKOFF, RESTART_BACKOFF_RESET};
+use crate::config::get_restart_backoff_base;
+use crate::metrics::record_worker_restart;
+
+/// An error retrying won't fix, like a revoked bot token or a port that's
+/// taken. Workers give up on it and take the process down with them.
+#[derive(Debug)]
+pub struct FatalError(pub String);
+
+impl fmt::Display for FatalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for FatalError {}
+
+/// Ends the process over an error of `worker` that restarting can't fix.
+pub fn exit_fatal(
+    worker: &str,
+    err: &FatalError,
+) -> ! {
+    eprintln!("{} can't continue: {}", worker, err);
+
+    std::process::exit(1);
+}
+
+/// Delay before the restart following `failures` failed runs in a row,
+/// doubling from `base` up to `MAX_RESTART_BACKOFF`.
+fn restart_delay(
+    base: Duration,
+    failures: u32,
+) -> Duration {
+    base.saturating_mul(2u32.saturating_pow(failures.min(16)))
+        .min(Duration::from_millis(MAX_RESTART_BACKOFF))
+}
+
+/// Spaces out the restarts of a worker so that a persistent failure doesn't
+/// turn into a tight loop. A run that lasted `RESTART_BACKOFF_RESET` counts
+/// as recovered and starts over at the base delay.
+pub struct RestartBackoff {
+    worker: String,
+    failures: u32,
+}
+
+impl RestartBackoff {
+    pub fn new(
+        worker: impl Into<String>,
+    ) -> Self {
+        Self {
+            worker: worker.into(),
+            failures: 0,
+        }
+    }
+
+    /// Waits before restarting after a run that lasted `ran_for`.
+    pub async fn wait(
+        &mut self,
+        ran_for: Duration,
+    ) {
+        if ran_for >= Duration::from_secs(RESTART_BACKOFF_RESET) {
+            self.failures = 0;
+        }
+
+        let delay = restart_delay(get_restart_backoff_base(), self.failures);
+
+        self.failures = self.failures.saturating_add(1);
+
+        record_worker_restart(&self.worker, self.failures, delay);
+
+        tokio::time::sleep(delay).await;
+    }
+}
@@ -0,0 +1,32 @@
+use std::sync::{Arc, Mutex};
+
+use rocksdb::{DBWithThreadMode, MultiThreaded};
+
+use crate::config::get_catch_up_interval;
+use crate::render_cache::clear_render_cache;
+use crate::storage::{lock_db, Storage};
+use crate::workers::chat_policy::load_chat_policies;
+
+/// Keeps a secondary instance close to the database of the bot it was
+/// opened on, see `Storage::catch_up`. The writes that would invalidate
+/// cached pages and policies happened in the bot's process, so they're
+/// dropped or reloaded every time. User and chat names follow once their
+/// cache entries expire.
+pub async fn spawn_worker(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+) {
+    loop {
+        tokio::time::sleep(get_catch_up_interval()).await;
+
+        let dbi = lock_db(&db);
+
+        if let Err(err) = dbi.catch_up() {
+            dbg!(err);
+
+            continue;
+        }
+
+        load_chat_policies(&*dbi);
+        clear_render_cache();
+    }
+}
@@ -1,11 +1,28 @@
 use std::convert::Infallible;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::Instant;
 
+use chrono::Utc;
 use rocksdb::{DBWithThreadMode, MultiThreaded};
-use warp::{Error, Filter};
+use serde::Deserialize;
+use warp::{Error, Filter, Rejection, Reply};
+use warp::filters::path::FullPath;
+use warp::http::{header, Method, Response, StatusCode};
+use warp::hyper::Body;
+use warp::hyper::body::HttpBody;
 
-use crate::{JOB_SLEEP_INTERVAL, renderer};
+use crate::{MAX_REQUEST_BODY_SIZE, MinutemanError, renderer};
+use crate::components::page::Theme;
+use crate::config::{get_cors_max_age, get_cors_origins, get_default_lang, get_noindex, get_secondary_of, get_slow_request_threshold};
+use crate::locales::Lang;
+use crate::metrics::{record_http_request, seed_last_message_at};
+use crate::privacy::{AccessDenied, CSRF_HEADER, Viewer};
+use crate::rate_limit::{client_ip, RateLimited, request_base_url, take_token};
+use crate::share::{can_access_path, verify_share};
+use crate::storage::lock_db;
+use crate::workers::heartbeat::{record_last_error, spawn_heartbeat};
+use crate::workers::restart::{exit_fatal, FatalError, RestartBackoff};
+use crate::workers::telegram_handler::get_last_message_at;
 
 fn with_db(
     db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
@@ -13,54 +30,951 @@ fn with_db(
     warp::any().map(move || db.clone())
 }
 
+#[derive(Debug, Clone, Default, Deserialize)]
+struct ShareQuery {
+    share: Option<String>,
+}
+
+/// Share token from `?share=`, or from the cookie a share link leaves.
+fn with_share_token() -> impl Filter<Extract=(Option<String>, ), Error=Infallible> + Clone {
+    warp::query::<ShareQuery>()
+        .or(warp::any().map(ShareQuery::default))
+        .unify()
+        .and(warp::cookie::optional::<String>("minuteman_share"))
+        .map(|query: ShareQuery, cookie: Option<String>| query.share.or(cookie))
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct LangQuery {
+    lang: Option<String>,
+}
+
+/// Language from `?lang=`, or from the cookie picking one leaves, or the
+/// configured default.
+fn with_lang() -> impl Filter<Extract=(Lang, ), Error=Infallible> + Clone {
+    warp::query::<LangQuery>()
+        .or(warp::any().map(LangQuery::default))
+        .unify()
+        .and(warp::cookie::optional::<String>("minuteman_lang"))
+        .map(|query: LangQuery, cookie: Option<String>|
+            query.lang
+                .as_deref()
+                .map(Lang::parse)
+                .flatten()
+                .or(cookie.as_deref().map(Lang::parse).flatten())
+                .unwrap_or_else(get_default_lang)
+        )
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct ThemeQuery {
+    theme: Option<String>,
+}
+
+/// Color scheme from `?theme=`, or from the cookie picking one leaves, or
+/// auto.
+fn with_theme() -> impl Filter<Extract=(Theme, ), Error=Infallible> + Clone {
+    warp::query::<ThemeQuery>()
+        .or(warp::any().map(ThemeQuery::default))
+        .unify()
+        .and(warp::cookie::optional::<String>("minuteman_theme"))
+        .map(|query: ThemeQuery, cookie: Option<String>|
+            query.theme
+                .as_deref()
+                .map(Theme::parse)
+                .flatten()
+                .or(cookie.as_deref().map(Theme::parse).flatten())
+                .unwrap_or(Theme::Auto)
+        )
+}
+
+/// The query string as it came, empty without one.
+fn with_raw_query() -> impl Filter<Extract=(String, ), Error=Infallible> + Clone {
+    warp::query::raw()
+        .or(warp::any().map(String::new))
+        .unify()
+}
+
+fn with_viewer(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+) -> impl Filter<Extract=(Viewer, ), Error=Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(warp::cookie::optional::<String>("minuteman_admin"))
+        .and(with_share_token())
+        .and(with_db(db))
+        .map(|authorization, cookie, share: Option<String>, db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>| {
+            let chat_id =
+                share
+                    .map(|token| verify_share(&*lock_db(&db), &token))
+                    .flatten();
+
+            Viewer::from_credentials(authorization, cookie).with_share(chat_id)
+        })
+}
+
+/// Turns away requests for pages the viewer may not see, see
+/// `can_access_path`.
+fn with_access(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+) -> impl Filter<Extract=(), Error=Rejection> + Clone {
+    warp::path::full()
+        .and(with_viewer(db.clone()))
+        .and(with_db(db))
+        .and_then(|path: FullPath, viewer: Viewer, db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>| async move {
+            let allowed = can_access_path(&*lock_db(&db), path.as_str(), &viewer);
+
+            if allowed {
+                Ok(())
+            } else {
+                Err(warp::reject::custom(AccessDenied))
+            }
+        })
+        .untuple_one()
+}
+
+/// Base of absolute links in responses, see `request_base_url`.
+fn with_base_url() -> impl Filter<Extract=(Option<String>, ), Error=Rejection> + Clone {
+    warp::addr::remote()
+        .and(warp::header::optional::<String>("x-forwarded-proto"))
+        .and(warp::header::optional::<String>("x-forwarded-host"))
+        .map(request_base_url)
+}
+
+fn with_rate_limit() -> impl Filter<Extract=(), Error=Rejection> + Clone {
+    warp::addr::remote()
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .and_then(|peer, forwarded_for| async move {
+            match client_ip(peer, forwarded_for) {
+                Some(ip) => take_token(ip).map_err(warp::reject::custom),
+                None => Ok(()),
+            }
+        })
+        .untuple_one()
+}
+
+/// Has to go in front of every route that reads a request body.
+pub fn with_body_limit() -> impl Filter<Extract=(), Error=Rejection> + Clone {
+    warp::body::content_length_limit(MAX_REQUEST_BODY_SIZE)
+}
+
 fn with_listing_type<T: Clone + Send>(
     listing_type: T,
 ) -> impl Filter<Extract=(T, ), Error=Infallible> + Clone {
     warp::any().map(move || listing_type.clone())
 }
 
-async fn run(
+async fn handle_rejection(
+    err: Rejection,
+    theme: Theme,
+) -> Result<Response<Body>, Infallible> {
+    if let Some(limited) = err.find::<RateLimited>() {
+        let mut response =
+            renderer::error::error_page(
+                StatusCode::TOO_MANY_REQUESTS,
+                "too many requests, slow down",
+                theme,
+            );
+
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, header::HeaderValue::from(limited.retry_after));
+
+        return Ok(response);
+    }
+
+    if err.find::<AccessDenied>().is_some() {
+        return Ok(
+            renderer::error::error_page(
+                StatusCode::FORBIDDEN,
+                "you don't have access to this page",
+                theme,
+            ),
+        );
+    }
+
+    let (status, message) =
+        if err.is_not_found() {
+            (StatusCode::NOT_FOUND, "not found".to_string())
+        } else if let Some(err) = err.find::<MinutemanError>() {
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("{:?}", err))
+        } else if err.find::<warp::reject::InvalidQuery>().is_some() {
+            (StatusCode::BAD_REQUEST, "invalid query string".to_string())
+        } else if err.find::<warp::reject::PayloadTooLarge>().is_some() {
+            (StatusCode::PAYLOAD_TOO_LARGE, "request body too large".to_string())
+        } else if err.find::<warp::reject::LengthRequired>().is_some() {
+            (StatusCode::LENGTH_REQUIRED, "content-length required".to_string())
+        } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
+            (StatusCode::METHOD_NOT_ALLOWED, "method not allowed".to_string())
+        } else {
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("{:?}", err))
+        };
+
+    Ok(
+        renderer::error::error_page(
+            status,
+            &message,
+            theme,
+        ),
+    )
+}
+
+/// Adds `X-Robots-Tag: noindex` in noindex mode, which also covers files
+/// and json that can't carry the meta tag.
+fn with_robots_tag(
+    mut response: Response<Body>,
+) -> Response<Body> {
+    if get_noindex() {
+        response
+            .headers_mut()
+            .insert("x-robots-tag", header::HeaderValue::from_static("noindex"));
+    }
+
+    response
+}
+
+/// The json routes, the only ones CORS applies to.
+fn is_api_path(
+    path: &str,
+) -> bool {
+    path.ends_with(".json") || path.starts_with("/api/")
+}
+
+/// Value of `Access-Control-Allow-Origin` for a request from `origin`, None
+/// when it isn't allowed or CORS isn't configured.
+fn allowed_origin(
+    origin: Option<&str>,
+) -> Option<String> {
+    let origin = origin?;
+    let allowed = get_cors_origins();
+
+    if allowed.iter().any(|allowed| allowed == "*") {
+        Some("*".to_string())
+    } else if allowed.iter().any(|allowed| allowed == origin) {
+        Some(origin.to_string())
+    } else {
+        None
+    }
+}
+
+fn with_cors(
+    origin: Option<String>,
+    path: FullPath,
+    mut response: Response<Body>,
+) -> Response<Body> {
+    if !is_api_path(path.as_str()) {
+        return response;
+    }
+
+    let allowed =
+        match allowed_origin(origin.as_deref()) {
+            Some(allowed) => allowed,
+            None => return response,
+        };
+
+    let headers = response.headers_mut();
+
+    if let Ok(allowed) = header::HeaderValue::from_str(&allowed) {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, allowed);
+    }
+
+    headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, header::HeaderValue::from_static("GET, HEAD"));
+    headers.insert(header::ACCESS_CONTROL_MAX_AGE, header::HeaderValue::from(get_cors_max_age()));
+    // scripts elsewhere only see the headers they're shown
+    headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, header::HeaderValue::from_static(renderer::chat_listing::DATE_HEADER));
+    headers.append(header::VARY, header::HeaderValue::from_static("Origin"));
+
+    response
+}
+
+/// Keeps the token of a share link around as a cookie, so that the links on
+/// the shared pages work without carrying it along. Only tokens that
+/// `verify_share` accepts make it into the cookie.
+fn with_share_cookie(
+    db: &Mutex<DBWithThreadMode<MultiThreaded>>,
+    query: &str,
+    mut response: Response<Body>,
+) -> Response<Body> {
+    if !response.status().is_success() {
+        return response;
+    }
+
+    let token =
+        match query.split('&').find_map(|param| param.strip_prefix("share=")) {
+            Some(token) if !token.is_empty() => token,
+            _ => return response,
+        };
+
+    let verified = verify_share(&*lock_db(db), token).is_some();
+
+    if !verified {
+        return response;
+    }
+
+    let max_age =
+        token
+            .split('.')
+            .nth(2)
+            .map(|expires| expires.parse::<i64>().ok())
+            .flatten()
+            .map(|expires| expires - Utc::now().timestamp())
+            .unwrap_or(0)
+            .max(0);
+
+    let cookie = format!("minuteman_share={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax", token, max_age);
+
+    if let Ok(cookie) = header::HeaderValue::from_str(&cookie) {
+        response
+            .headers_mut()
+            .append(header::SET_COOKIE, cookie);
+    }
+
+    response
+}
+
+/// Remembers a language picked through `?lang=` in a cookie.
+fn with_lang_cookie(
+    query: &str,
+    mut response: Response<Body>,
+) -> Response<Body> {
+    let lang =
+        match query.split('&').find_map(|param| param.strip_prefix("lang=")).map(Lang::parse).flatten() {
+            Some(lang) => lang,
+            None => return response,
+        };
+
+    let cookie = format!("minuteman_lang={}; Path=/; Max-Age=31536000; SameSite=Lax", lang.code());
+
+    if let Ok(cookie) = header::HeaderValue::from_str(&cookie) {
+        response
+            .headers_mut()
+            .append(header::SET_COOKIE, cookie);
+    }
+
+    response
+}
+
+/// Remembers a theme picked through `?theme=` in a cookie.
+fn with_theme_cookie(
+    query: &str,
+    mut response: Response<Body>,
+) -> Response<Body> {
+    let theme =
+        match query.split('&').find_map(|param| param.strip_prefix("theme=")).map(Theme::parse).flatten() {
+            Some(theme) => theme,
+            None => return response,
+        };
+
+    let cookie = format!("minuteman_theme={}; Path=/; Max-Age=31536000; SameSite=Lax", theme.name());
+
+    if let Ok(cookie) = header::HeaderValue::from_str(&cookie) {
+        response
+            .headers_mut()
+            .append(header::SET_COOKIE, cookie);
+    }
+
+    response
+}
+
+/// HEAD responses carry the headers a GET would get and no body. hyper
+/// leaves it out anyway, dropping it here keeps its length.
+fn strip_head_body(
+    method: Method,
+    response: Response<Body>,
+) -> Response<Body> {
+    if method != Method::HEAD {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+
+    if !parts.headers.contains_key(header::CONTENT_LENGTH) {
+        if let Some(size) = body.size_hint().exact() {
+            parts.headers.insert(header::CONTENT_LENGTH, header::HeaderValue::from(size));
+        }
+    }
+
+    Response::from_parts(parts, Body::empty())
+}
+
+fn log_request(
+    start: Instant,
+    method: Method,
+    path: FullPath,
+    response: Response<Body>,
+) -> Response<Body> {
+    let elapsed = start.elapsed();
+    let status = response.status().as_u16();
+
+    let size =
+        response
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .map(|len| len.to_str().ok())
+            .flatten()
+            .map(|len| len.parse::<u64>().ok())
+            .flatten()
+            .or_else(|| response.body().size_hint().exact());
+
+    let segments =
+        path.as_str()
+            .trim_start_matches('/')
+            .split('/')
+            .collect::<Vec<&str>>();
+
+    let route =
+        match segments[0] {
+            "" => "index",
+            segment => segment,
+        };
+
+    let chat_id =
+        if route == "chat" {
+            segments.get(1).map(|id| *id)
+        } else {
+            None
+        };
+
+    let slow = elapsed >= get_slow_request_threshold();
+
+    if slow {
+        tracing::warn!(
+            method = %method,
+            path = path.as_str(),
+            status,
+            size = ?size,
+            elapsed_ms = elapsed.as_millis() as u64,
+            chat_id = chat_id.unwrap_or("-"),
+            "slow request"
+        );
+    } else {
+        tracing::info!(
+            method = %method,
+            path = path.as_str(),
+            status,
+            size = ?size,
+            elapsed_ms = elapsed.as_millis() as u64,
+            "request"
+        );
+    }
+
+    record_http_request(
+        route,
+        status,
+        elapsed,
+        size,
+        slow,
+    );
+
+    response
+}
+
+/// Every route of the web server along with rate limits, access checks,
+/// error pages, cookies, CORS headers and request logging.
+fn routes(
     db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> impl Filter<Extract=(Response<Body>, ), Error=Rejection> + Clone + Send + Sync + 'static {
     let default =
         warp::path::end()
             .and(with_db(db.clone()))
             .and(with_listing_type("groups"))
+            .and(with_listing_type("html"))
+            .or(
+                warp::path("chats.json")
+                    .and(warp::path::end())
+                    .and(with_db(db.clone()))
+                    .and(with_listing_type("groups"))
+                    .and(with_listing_type("json")),
+            )
+            .unify()
+            .and(warp::query::<renderer::chats::ChatsQuery>())
+            .and(with_viewer(db.clone()))
+            .and(with_lang())
+            .and(with_raw_query())
+            .and(with_theme())
             .and_then(renderer::chats::chats);
 
     let default_all =
         warp::path("all")
+            .and(warp::path::end())
             .and(with_db(db.clone()))
             .and(with_listing_type("all"))
+            .and(with_listing_type("html"))
+            .or(
+                warp::path("all.json")
+                    .and(warp::path::end())
+                    .and(with_db(db.clone()))
+                    .and(with_listing_type("all"))
+                    .and(with_listing_type("json")),
+            )
+            .unify()
+            .and(warp::query::<renderer::chats::ChatsQuery>())
+            .and(with_viewer(db.clone()))
+            .and(with_lang())
+            .and(with_raw_query())
+            .and(with_theme())
             .and_then(renderer::chats::chats);
 
+    // only matches `@username` refs, everything else falls through to the
+    // id-based routes below
+    let chat_by_username =
+        warp::path("chat")
+            .and(with_db(db.clone()))
+            .and(
+                warp::path::param::<String>()
+                    .and_then(|chat_ref: String| async move {
+                        if chat_ref.starts_with('@') {
+                            Ok(chat_ref)
+                        } else {
+                            Err(warp::reject::not_found())
+                        }
+                    }),
+            )
+            .and(warp::path::tail())
+            .and(with_raw_query())
+            .and(with_theme())
+            .and_then(renderer::chat_username::chat_by_username);
+
     let chat_index =
         warp::path("chat")
             .and(with_db(db.clone()))
             .and(warp::path::param())
+            .and(
+                warp::path("index.json")
+                    .map(|| "json")
+                    .or(warp::any().map(|| "html"))
+                    .unify(),
+            )
+            .and(warp::query::<renderer::chat_index::IndexQuery>())
+            .and(with_lang())
+            .and(with_raw_query())
+            .and(with_theme())
             .and_then(renderer::chat_index::chat_index);
 
+    let media_format =
+        warp::path("media")
+            .map(|| "html")
+            .or(
+                warp::path("media.json")
+                    .map(|| "json"),
+            )
+            .unify();
+
+    let chat_media =
+        warp::path("chat")
+            .and(with_db(db.clone()))
+            .and(warp::path::param())
+            .and(warp::any().map(|| None::<String>))
+            .and(media_format.clone())
+            .and(warp::path::end())
+            .and(warp::query::<renderer::chat_media::MediaQuery>())
+            .and(with_viewer(db.clone()))
+            .and(with_raw_query())
+            .and(with_theme())
+            .and_then(renderer::chat_media::chat_media);
+
+    let chat_day_media =
+        warp::path("chat")
+            .and(with_db(db.clone()))
+            .and(warp::path::param())
+            .and(warp::path::param::<String>().map(Some))
+            .and(media_format.clone())
+            .and(warp::path::end())
+            .and(warp::query::<renderer::chat_media::MediaQuery>())
+            .and(with_viewer(db.clone()))
+            .and(with_raw_query())
+            .and(with_theme())
+            .and_then(renderer::chat_media::chat_media);
+
+    let chat_info =
+        warp::path("chat")
+            .and(with_db(db.clone()))
+            .and(warp::path::param())
+            .and(warp::path("info"))
+            .and(warp::path::end())
+            .and(with_viewer(db.clone()))
+            .and(with_theme())
+            .and_then(renderer::chat_info::chat_info);
+
+    let chat_member_counts =
+        warp::path("chat")
+            .and(with_db(db.clone()))
+            .and(warp::path::param())
+            .and(warp::path("members.json"))
+            .and(warp::path::end())
+            .and_then(renderer::chat_info::chat_member_counts);
+
+    let chat_pins =
+        warp::path("chat")
+            .and(with_db(db.clone()))
+            .and(warp::path::param())
+            .and(warp::path("pins"))
+            .and(warp::path::end())
+            .and(with_viewer(db.clone()))
+            .and(with_theme())
+            .and_then(renderer::chat_pins::chat_pins);
+
+    let chat_week =
+        warp::path("chat")
+            .and(with_db(db.clone()))
+            .and(warp::path::param())
+            .and(warp::path("week").map(|| "week"))
+            .and(warp::path::param())
+            .and(warp::path::end())
+            .and(with_viewer(db.clone()))
+            .and(with_theme())
+            .and_then(renderer::chat_digest::chat_digest);
+
+    let chat_month =
+        warp::path("chat")
+            .and(with_db(db.clone()))
+            .and(warp::path::param())
+            .and(warp::path("month").map(|| "month"))
+            .and(warp::path::param())
+            .and(warp::path::end())
+            .and(with_viewer(db.clone()))
+            .and(with_theme())
+            .and_then(renderer::chat_digest::chat_digest);
+
+    let chat_contact =
+        warp::path("chat")
+            .and(with_db(db.clone()))
+            .and(warp::path::param())
+            .and(warp::path("contact"))
+            .and(warp::path::param())
+            .and(warp::path::end())
+            .and(with_viewer(db.clone()))
+            .and(with_theme())
+            .and_then(renderer::contact::contact_vcard_file);
+
+    let chat_jump =
+        warp::path("chat")
+            .and(warp::path::param())
+            .and(warp::path("jump"))
+            .and(warp::path::end())
+            .and(warp::query::<renderer::chat_listing::JumpQuery>())
+            .and_then(renderer::chat_listing::chat_jump);
+
     let chat_listing =
         warp::path("chat")
             .and(with_db(db.clone()))
             .and(warp::path::param())
             .and(warp::path::param())
+            .and(warp::query::<renderer::chat_listing::ListingQuery>())
+            .and(with_viewer(db.clone()))
+            .and(with_lang())
+            .and(with_base_url())
+            .and(with_raw_query())
+            .and(with_theme())
             .and_then(renderer::chat_listing::chat_listing);
 
+    let user_info =
+        warp::path("user")
+            .and(with_db(db.clone()))
+            .and(warp::path::param())
+            .and(warp::path::end())
+            .and(with_viewer(db.clone()))
+            .and(with_theme())
+            .and_then(renderer::user_info::user_info);
+
     let get_file =
-        warp::path("file")
+        warp::method()
+            .and(warp::path("file"))
             .and(with_db(db.clone()))
             .and(warp::path::param())
             .and(warp::path::param())
+            .and(warp::query::<renderer::get_file::FileQuery>())
+            .and(with_viewer(db.clone()))
             .and_then(renderer::get_file::get_file);
 
+    let global_css =
+        warp::path("assets")
+            .and(warp::path("global.css"))
+            .and(warp::path::end())
+            .and(warp::header::optional::<String>("if-none-match"))
+            .and_then(renderer::assets::global_css);
+
+    let health =
+        warp::path("health")
+            .and(warp::path::end())
+            .and_then(renderer::health::health);
+
+    let metrics =
+        warp::path("metrics")
+            .and(warp::path::end())
+            .and_then(renderer::metrics::metrics);
+
+    let robots_txt =
+        warp::path("robots.txt")
+            .and(warp::path::end())
+            .and_then(renderer::robots::robots_txt);
+
+    let sitemap_index =
+        warp::path("sitemap.xml")
+            .and(with_db(db.clone()))
+            .and(warp::path::end())
+            .and_then(renderer::robots::sitemap_index);
+
+    let sitemap_chunk =
+        warp::path("sitemap")
+            .and(with_db(db.clone()))
+            .and(warp::path::param())
+            .and(warp::path::end())
+            .and_then(renderer::robots::sitemap_chunk);
+
+    let search =
+        warp::path("search")
+            .map(|| "html")
+            .or(
+                warp::path("search.json")
+                    .map(|| "json"),
+            )
+            .unify()
+            .and(warp::path::end())
+            .and(with_db(db.clone()))
+            .and(warp::query::<renderer::search::SearchQuery>())
+            .and(with_viewer(db.clone()))
+            .and(with_raw_query())
+            .and(with_theme())
+            .and_then(renderer::search::search);
+
+    let storage =
+        warp::path("admin")
+            .and(with_db(db.clone()))
+            .and(
+                warp::path("storage")
+                    .map(|| "html")
+                    .or(
+                        warp::path("storage.json")
+                            .map(|| "json"),
+                    )
+                    .unify(),
+            )
+            .and(warp::path::end())
+            .and(with_viewer(db.clone()))
+            .and(with_theme())
+            .and_then(renderer::storage::storage);
+
+    let status =
+        warp::path("admin")
+            .and(with_db(db.clone()))
+            .and(
+                warp::path("status")
+                    .map(|| "html")
+                    .or(
+                        warp::path("status.json")
+                            .map(|| "json"),
+                    )
+                    .unify(),
+            )
+            .and(warp::path::end())
+            .and(with_viewer(db.clone()))
+            .and(with_theme())
+            .and_then(renderer::status::status);
+
+    let inline_queries =
+        warp::path("admin")
+            .and(with_db(db.clone()))
+            .and(
+                warp::path("inline")
+                    .map(|| "html")
+                    .or(
+                        warp::path("inline.json")
+                            .map(|| "json"),
+                    )
+                    .unify(),
+            )
+            .and(warp::path::end())
+            .and(warp::query::<renderer::inline_queries::InlineQueriesQuery>())
+            .and(with_viewer(db.clone()))
+            .and(with_theme())
+            .and_then(renderer::inline_queries::inline_queries);
+
+    let redact =
+        warp::delete()
+            .and(warp::path("api"))
+            .and(warp::path("v1"))
+            .and(warp::path("chats"))
+            .and(with_db(db.clone()))
+            .and(warp::path::param())
+            .and(warp::path("messages"))
+            .and(warp::path::param())
+            .and(warp::path::end())
+            .and(with_viewer(db.clone()))
+            .and_then(renderer::redact::redact);
+
+    let admin =
+        warp::path("admin")
+            .and(warp::path::end())
+            .and(with_db(db.clone()))
+            .and(with_viewer(db.clone()))
+            .and(with_theme())
+            .and_then(renderer::admin::admin);
+
+    let chat_policy =
+        warp::post()
+            .and(warp::path("admin"))
+            .and(warp::path("chat"))
+            .and(with_db(db.clone()))
+            .and(warp::path::param())
+            .and(warp::path("policy"))
+            .and(warp::path::end())
+            .and(with_body_limit())
+            .and(warp::body::form())
+            .and(with_viewer(db.clone()))
+            .and(with_theme())
+            .and_then(renderer::admin::update_chat_policy);
+
+    let share_create =
+        warp::post()
+            .and(warp::path("admin"))
+            .and(warp::path("share"))
+            .and(warp::path::end())
+            .and(with_db(db.clone()))
+            .and(with_body_limit())
+            .and(warp::body::json())
+            .and(warp::header::optional::<String>(CSRF_HEADER))
+            .and(with_viewer(db.clone()))
+            .and_then(renderer::share::create_share_link);
+
+    let share_revoke =
+        warp::delete()
+            .and(warp::path("admin"))
+            .and(warp::path("share"))
+            .and(with_db(db.clone()))
+            .and(warp::path::param())
+            .and(warp::path::end())
+            .and(with_viewer(db.clone()))
+            .and_then(renderer::share::revoke_share_link);
+
+    let log_filter =
+        warp::post()
+            .and(warp::path("admin"))
+            .and(warp::path("log-filter"))
+            .and(warp::path::end())
+            .and(with_body_limit())
+            .and(warp::body::json())
+            .and(warp::header::optional::<String>(CSRF_HEADER))
+            .and(with_viewer(db.clone()))
+            .and_then(renderer::admin::update_log_filter);
+
+    // HEAD is answered by the same handlers, `strip_head_body` leaves out
+    // the body
     let routes =
         warp::get()
+            .or(warp::head())
+            .unify()
             .and(default)
+            .or(global_css)
+            .or(robots_txt)
+            .or(sitemap_index)
+            .or(sitemap_chunk)
             .or(default_all)
             .or(get_file)
+            .or(user_info)
+            .or(chat_by_username)
+            .or(chat_jump)
+            .or(chat_info)
+            .or(chat_member_counts)
+            .or(chat_pins)
+            .or(chat_week)
+            .or(chat_month)
+            .or(chat_contact)
+            .or(chat_media)
+            .or(chat_day_media)
             .or(chat_listing)
-            .or(chat_index);
+            .or(chat_index)
+            .or(search)
+            .or(admin)
+            .or(storage)
+            .or(status)
+            .or(inline_queries)
+            .or(chat_policy)
+            .or(redact)
+            .or(share_create)
+            .or(share_revoke)
+            .or(log_filter);
+
+    // answered without touching the handlers, with_cors adds the headers
+    let cors_preflight =
+        warp::options()
+            .and(warp::path::full())
+            .and_then(|path: FullPath| async move {
+                if !get_cors_origins().is_empty() && is_api_path(path.as_str()) {
+                    Ok(StatusCode::NO_CONTENT)
+                } else {
+                    Err(warp::reject::not_found())
+                }
+            });
+
+    // health checks and metric scrapes aren't limited
+    let routes =
+        metrics
+            .or(health)
+            .or(cors_preflight)
+            .or(
+                with_rate_limit()
+                    .and(with_access(db.clone()))
+                    .and(routes),
+            );
+
+    // recover before logging so that rejections show up with the status
+    // code the client actually got
+    warp::any()
+        .map(Instant::now)
+        .and(warp::method())
+        .and(warp::path::full())
+        .and(
+            warp::method()
+                .and(
+                    warp::header::optional::<String>("origin")
+                        .and(warp::path::full())
+                        .and(
+                            with_raw_query()
+                                .and(with_db(db.clone()))
+                                .and(
+                                    with_theme()
+                                        .and(
+                                            routes
+                                                .map(|reply| Ok(Reply::into_response(reply)))
+                                                .recover(|err| async move { Ok::<_, Infallible>(Err(err)) })
+                                                .unify(),
+                                        )
+                                        .and_then(|theme, response: Result<Response<Body>, Rejection>| async move {
+                                            match response {
+                                                Ok(response) => Ok(response),
+                                                Err(err) => handle_rejection(err, theme).await,
+                                            }
+                                        })
+                                        .map(with_robots_tag),
+                                )
+                                .map(|query: String, db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>, response|
+                                    with_theme_cookie(&query, with_lang_cookie(&query, with_share_cookie(&db, &query, response)))
+                                ),
+                        )
+                        .map(with_cors),
+                )
+                .map(strip_head_body),
+        )
+        .map(log_request)
+}
+
+async fn run(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // a port somebody else holds stays taken, restarting won't help
+    let (_, server) =
+        warp::serve(routes(db.clone()))
+            .try_bind_ephemeral(([0, 0, 0, 0], 12525))
+            .map_err(|err| FatalError(format!("can't listen on port 12525: {}", err)))?;
+
+    // a frontend can't write to the bot's database
+    let _heartbeat =
+        match get_secondary_of() {
+            Some(_) => None,
+            None => Some(spawn_heartbeat(db.clone(), "server_handler".to_string())),
+        };
+
+    seed_last_message_at(get_last_message_at(&*lock_db(&db)));
 
     println!("Ain't gonna need to tell the truth, tell no lies");
     println!("Everything you think, do, and say");
@@ -68,9 +982,7 @@ async fn run(
     println!("▪");
     println!("listening on port 2525");
 
-    warp::serve(routes)
-        .run(([0, 0, 0, 0], 12525))
-        .await;
+    server.await;
 
     Ok(())
 }
@@ -78,17 +990,753 @@ async fn run(
 pub async fn spawn_worker(
     db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
 ) {
+    let mut backoff = RestartBackoff::new("server_handler");
+
     loop {
+        let started = Instant::now();
+
         if let Err(err) = run(
             db.clone(),
         ).await {
+            if let Err(err) = record_last_error(&lock_db(&db), "server_handler", &err.to_string()) {
+                dbg!(err);
+            }
+
+            if let Some(fatal) = err.downcast_ref::<FatalError>() {
+                exit_fatal("server_handler", fatal);
+            }
+
             dbg!(err);
         }
 
-        tokio::time::sleep(
-            Duration::from_millis(
-                JOB_SLEEP_INTERVAL,
-            ),
-        ).await;
+        backoff.wait(started.elapsed()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALLOWED_ORIGIN: &str = "https://allowed.example";
+
+    // the variable is process wide, every test here sets the same origins
+    fn test_routes(
+        name: &str,
+    ) -> (Arc<Mutex<DBWithThreadMode<MultiThreaded>>>, impl Filter<Extract=(Response<Body>, ), Error=Rejection> + Clone) {
+        std::env::set_var("MINUTEMAN_CORS_ORIGINS", ALLOWED_ORIGIN);
+
+        let path =
+            std::env::temp_dir()
+                .join(format!("minuteman-test-{}-{}", name, std::process::id()));
+
+        let _ = std::fs::remove_dir_all(&path);
+
+        let db = Arc::new(Mutex::new(DBWithThreadMode::<MultiThreaded>::open_default(path).unwrap()));
+
+        (db.clone(), routes(db))
+    }
+
+    /// GET and HEAD responses for `path`.
+    async fn get_and_head(
+        routes: &(impl Filter<Extract=(Response<Body>, ), Error=Rejection> + Clone + 'static),
+        path: &str,
+    ) -> (Response<warp::hyper::body::Bytes>, Response<warp::hyper::body::Bytes>) {
+        (
+            warp::test::request().path(path).reply(routes).await,
+            warp::test::request().method("HEAD").path(path).reply(routes).await,
+        )
+    }
+
+    fn content_length(
+        response: &Response<warp::hyper::body::Bytes>,
+    ) -> usize {
+        response.headers()[header::CONTENT_LENGTH]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap()
+    }
+
+    fn has_vary_origin(
+        headers: &header::HeaderMap,
+    ) -> bool {
+        headers
+            .get_all(header::VARY)
+            .iter()
+            .any(|value| value == "Origin")
+    }
+
+    #[tokio::test]
+    async fn json_routes_answer_allowed_origins() {
+        let (_, routes) = test_routes("cors-allowed");
+
+        let response =
+            warp::test::request()
+                .path("/chats.json")
+                .header("origin", ALLOWED_ORIGIN)
+                .reply(&routes)
+                .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], ALLOWED_ORIGIN);
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_METHODS], "GET, HEAD");
+        assert!(has_vary_origin(response.headers()));
+
+        // html pages don't take part in CORS
+        let response =
+            warp::test::request()
+                .path("/")
+                .header("origin", ALLOWED_ORIGIN)
+                .reply(&routes)
+                .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+    }
+
+    #[tokio::test]
+    async fn json_routes_leave_out_other_origins() {
+        let (_, routes) = test_routes("cors-disallowed");
+
+        let response =
+            warp::test::request()
+                .path("/chats.json")
+                .header("origin", "https://other.example")
+                .reply(&routes)
+                .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+        assert!(response.headers().get(header::ACCESS_CONTROL_ALLOW_METHODS).is_none());
+        assert!(!has_vary_origin(response.headers()));
+    }
+
+    #[tokio::test]
+    async fn preflights_get_no_content_with_the_cors_headers() {
+        let (_, routes) = test_routes("cors-preflight");
+
+        let response =
+            warp::test::request()
+                .method("OPTIONS")
+                .path("/chats.json")
+                .header("origin", ALLOWED_ORIGIN)
+                .header("access-control-request-method", "GET")
+                .reply(&routes)
+                .await;
+
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(response.body().is_empty());
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], ALLOWED_ORIGIN);
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_METHODS], "GET, HEAD");
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_MAX_AGE], get_cors_max_age().to_string().as_str());
+        assert!(has_vary_origin(response.headers()));
+
+        // and from anywhere else without them
+        let response =
+            warp::test::request()
+                .method("OPTIONS")
+                .path("/chats.json")
+                .header("origin", "https://other.example")
+                .header("access-control-request-method", "GET")
+                .reply(&routes)
+                .await;
+
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(response.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+    }
+
+    #[tokio::test]
+    async fn head_on_a_file_has_the_headers_of_get_and_no_body() {
+        use crate::blob_store::place_blob;
+        use crate::workers::telegram_handler::{build_file_key, FileEntryType, FileMeta, store_file_meta};
+
+        let (db, routes) = test_routes("head-file");
+
+        let blob = b"minuteman test file".to_vec();
+
+        {
+            let dbi = lock_db(&db);
+
+            // one with a meta record, answered without reading the blob, and
+            // one stored before those were kept
+            for file_id in ["with-meta", "without-meta"] {
+                let key = build_file_key(FileEntryType::Chat, file_id);
+
+                dbi.put(&key, place_blob(&key, &blob).unwrap()).unwrap();
+            }
+
+            let meta =
+                FileMeta {
+                    mime_type: Some("text/plain".to_string()),
+                    size: blob.len() as u64,
+                    ..FileMeta::default()
+                };
+
+            store_file_meta(&dbi, "with-meta", &meta).unwrap();
+        }
+
+        for file_id in ["with-meta", "without-meta"] {
+            let (get, head) = get_and_head(&routes, &format!("/file/document/{}", file_id)).await;
+
+            assert_eq!(get.status(), StatusCode::OK, "{}", file_id);
+            assert_eq!(head.status(), StatusCode::OK, "{}", file_id);
+            assert_eq!(get.body().as_ref(), blob.as_slice());
+            assert!(head.body().is_empty(), "{}", file_id);
+            assert_eq!(content_length(&head), blob.len(), "{}", file_id);
+            assert_eq!(head.headers().get(header::CONTENT_TYPE), get.headers().get(header::CONTENT_TYPE));
+        }
+    }
+
+    #[tokio::test]
+    async fn head_on_a_missing_file_is_not_found() {
+        let (_, routes) = test_routes("head-missing-file");
+
+        let (get, head) = get_and_head(&routes, "/file/document/missing").await;
+
+        assert_eq!(get.status(), StatusCode::NOT_FOUND);
+        assert_eq!(head.status(), StatusCode::NOT_FOUND);
+        assert!(head.body().is_empty());
+        assert_eq!(content_length(&head), get.body().len());
+    }
+
+    #[tokio::test]
+    async fn head_on_a_page_has_the_length_of_get_and_no_body() {
+        let (_, routes) = test_routes("head-page");
+
+        for path in ["/", "/chats.json"] {
+            let (get, head) = get_and_head(&routes, path).await;
+
+            assert_eq!(get.status(), StatusCode::OK, "{}", path);
+            assert_eq!(head.status(), StatusCode::OK, "{}", path);
+            assert!(!get.body().is_empty(), "{}", path);
+            assert!(head.body().is_empty(), "{}", path);
+            assert_eq!(content_length(&head), get.body().len(), "{}", path);
+            assert_eq!(head.headers().get(header::CONTENT_TYPE), get.headers().get(header::CONTENT_TYPE));
+        }
+    }
+
+    #[tokio::test]
+    async fn large_files_come_out_whole_after_the_lock_is_released() {
+        use crate::blob_store::{place_blob_in, use_test_blob_dir};
+        use crate::config::BlobBackend;
+        use crate::workers::telegram_handler::{build_file_key, FileEntryType, FileMeta, store_file_meta};
+
+        use_test_blob_dir();
+
+        let (db, routes) = test_routes("large-file");
+
+        // a few megabytes that don't repeat, so that a misplaced chunk shows
+        let blob =
+            (0..6 * 1024 * 1024u32)
+                .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+                .collect::<Vec<u8>>();
+
+        {
+            let dbi = lock_db(&db);
+
+            // one in the database, one streamed from a directory
+            for (file_id, backend) in [("inline", BlobBackend::RocksDb), ("external", BlobBackend::Fs)] {
+                let key = build_file_key(FileEntryType::Chat, file_id);
+
+                dbi.put(&key, place_blob_in(backend, &key, &blob).unwrap()).unwrap();
+
+                let meta =
+                    FileMeta {
+                        mime_type: Some("video/mp4".to_string()),
+                        size: blob.len() as u64,
+                        ..FileMeta::default()
+                    };
+
+                store_file_meta(&dbi, file_id, &meta).unwrap();
+            }
+        }
+
+        for file_id in ["inline", "external"] {
+            let response =
+                warp::test::request()
+                    .path(&format!("/file/video/{}", file_id))
+                    .filter(&routes)
+                    .await
+                    .unwrap();
+
+            // the body is read with the database free for other requests
+            assert!(db.try_lock().is_ok(), "{}", file_id);
+
+            assert_eq!(response.status(), StatusCode::OK, "{}", file_id);
+            assert_eq!(response.headers()[header::CONTENT_TYPE], "video/mp4");
+            assert_eq!(response.headers()[header::CONTENT_LENGTH], blob.len().to_string().as_str());
+
+            let body = warp::hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+            assert_eq!(body.len(), blob.len(), "{}", file_id);
+            assert!(body.as_ref() == blob.as_slice(), "{}", file_id);
+        }
+    }
+
+    #[tokio::test]
+    async fn head_on_a_file_whose_blob_is_gone_is_not_found() {
+        use crate::blob_store::{place_blob_in, use_test_blob_dir, FsBlobStore};
+        use crate::config::BlobBackend;
+        use crate::workers::telegram_handler::{build_file_key, FileEntryType, FileMeta, store_file_meta};
+
+        let dir = use_test_blob_dir();
+
+        let (db, routes) = test_routes("head-missing-blob");
+
+        {
+            let dbi = lock_db(&db);
+
+            let meta =
+                FileMeta {
+                    mime_type: Some("image/png".to_string()),
+                    size: 4,
+                    ..FileMeta::default()
+                };
+
+            // a record without a blob, and one pointing at a deleted file
+            store_file_meta(&dbi, "no-blob", &meta).unwrap();
+
+            let key = build_file_key(FileEntryType::Chat, "gone-blob");
+
+            dbi.put(&key, place_blob_in(BlobBackend::Fs, &key, b"blob").unwrap()).unwrap();
+
+            std::fs::remove_file(FsBlobStore { dir }.path(&key).unwrap()).unwrap();
+
+            store_file_meta(&dbi, "gone-blob", &meta).unwrap();
+        }
+
+        for path in ["/file/image/no-blob", "/file/image/gone-blob"] {
+            let (get, head) = get_and_head(&routes, path).await;
+
+            assert_eq!(get.status(), StatusCode::NOT_FOUND, "{}", path);
+            assert_eq!(head.status(), StatusCode::NOT_FOUND, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_chats_are_not_found_in_every_format() {
+        let (_, routes) = test_routes("unknown-chat");
+
+        for path in [
+            "/chat/-1009/2020-09-13",
+            "/chat/-1009/2020-09-13.json",
+            "/chat/-1009/2020-09-13.txt",
+            "/chat/-1009/latest",
+            "/chat/-1009/latest.json",
+            "/chat/-1009/latest.txt",
+            "/chat/-1009",
+            "/chat/-1009/index.json",
+        ] {
+            let response = warp::test::request().path(path).reply(&routes).await;
+
+            let body = String::from_utf8_lossy(response.body());
+
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", path);
+
+            if path.ends_with(".json") || path.ends_with(".txt") {
+                assert!(body.contains("chat not archived"), "{}: {}", path, body);
+            } else {
+                assert!(body.starts_with("<!DOCTYPE html>"), "{}", path);
+                assert!(body.contains("this chat is not archived"), "{}", path);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn share_cookies_are_only_set_for_tokens_that_verify() {
+        std::env::set_var("MINUTEMAN_SHARE_SECRET", "share-test-secret");
+
+        let (db, routes) = test_routes("share-cookie");
+
+        lock_db(&db).put("chat_rel:-1001", b"\0").unwrap();
+
+        let (_, token) = crate::share::create_share(&lock_db(&db), "-1001", Utc::now().timestamp() + 3600).unwrap();
+
+        let cookie = |response: &Response<warp::hyper::body::Bytes>| {
+            response
+                .headers()
+                .get_all(header::SET_COOKIE)
+                .iter()
+                .filter_map(|cookie| cookie.to_str().ok())
+                .find(|cookie| cookie.starts_with("minuteman_share="))
+                .map(|cookie| cookie.to_string())
+        };
+
+        let response = warp::test::request().path(&format!("/chat/-1001/latest?share={}", token)).reply(&routes).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(cookie(&response).unwrap().starts_with(&format!("minuteman_share={};", token)));
+
+        for forged in [format!("{}0", token), "x;Domain=evil.example".to_string(), "abc".to_string()] {
+            let response = warp::test::request().path(&format!("/chat/-1001/latest?share={}", forged)).reply(&routes).await;
+
+            assert_eq!(cookie(&response), None, "{}", forged);
+        }
+    }
+
+    #[tokio::test]
+    async fn latest_of_a_chat_without_messages_is_a_whole_page() {
+        let (db, routes) = test_routes("empty-chat");
+
+        // known from an update without a message
+        lock_db(&db).put("chat_rel:-1001", b"\0").unwrap();
+
+        let response = warp::test::request().path("/chat/-1001/latest").reply(&routes).await;
+
+        let html = String::from_utf8(response.body().to_vec()).unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(html.starts_with("<!DOCTYPE html><html lang=\"en\">"));
+        assert!(html.contains("</head><body"));
+        assert!(html.ends_with("</div></body></html>"));
+        assert!(html.contains("<div class=\"empty\"><p>no messages logged yet</p></div>"));
+        assert!(html.contains("href=\"/chat/-1001\""));
+        assert!(html.contains("href=\"/chat/-1001/latest\""));
+
+        let response = warp::test::request().path("/chat/-1001/latest.json").reply(&routes).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body().as_ref(), b"[]");
+
+        let response = warp::test::request().path("/chat/-1001/latest.txt").reply(&routes).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.body().is_empty());
+    }
+
+    #[tokio::test]
+    async fn resolved_day_json_comes_with_its_users() {
+        let (db, routes) = test_routes("resolve-users");
+
+        {
+            let dbi = lock_db(&db);
+
+            dbi.put("chat_rel:-1001", b"\0").unwrap();
+            dbi.put("chat:-1001:1600000000", r#"{"message": {"user_id": "2666001", "time": 1600000000, "received_at": 1600000000, "text": "hi", "entities": [], "source": null}}"#).unwrap();
+            dbi.put("user:meta:2666001", r#"{"id": "2666001", "first_name": "Alice", "last_name": null, "username": "alice", "is_bot": false, "language_code": null}"#).unwrap();
+        }
+
+        let response = warp::test::request().path("/chat/-1001/2020-09-13.json?resolve=1").reply(&routes).await;
+
+        let json = serde_json::from_slice::<serde_json::Value>(response.body()).unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(json["data"].as_array().map(|data| data.len()), Some(1));
+        assert_eq!(json["users"]["2666001"]["name"], "alice");
+        assert_eq!(json["users"]["2666001"]["username"], "alice");
+
+        // without it the rows are the bare array they've always been
+        let response = warp::test::request().path("/chat/-1001/2020-09-13.json").reply(&routes).await;
+
+        let json = serde_json::from_slice::<serde_json::Value>(response.body()).unwrap();
+
+        assert_eq!(json.as_array().map(|data| data.len()), Some(1));
+    }
+
+    #[tokio::test]
+    async fn latest_sends_the_day_it_resolved_to() {
+        use crate::workers::telegram_handler::build_chat_index_key;
+
+        let (db, routes) = test_routes("latest-date");
+
+        {
+            let dbi = lock_db(&db);
+
+            dbi.put("chat_rel:-1001", b"\0").unwrap();
+            dbi.put("chat:-1001:1600000000", r#"{"message": {"user_id": "1001", "time": 1600000000, "received_at": 1600000000, "text": "hi", "entities": [], "source": null}}"#).unwrap();
+            dbi.put(build_chat_index_key("-1001", 1_600_000_000), b"\0").unwrap();
+        }
+
+        for path in ["/chat/-1001/latest", "/chat/-1001/latest.json", "/chat/-1001/latest.txt", "/chat/-1001/2020-09-13.json"] {
+            let response =
+                warp::test::request()
+                    .path(path)
+                    .header("origin", ALLOWED_ORIGIN)
+                    .reply(&routes)
+                    .await;
+
+            assert_eq!(response.status(), StatusCode::OK, "{}", path);
+            assert_eq!(response.headers()[renderer::chat_listing::DATE_HEADER], "2020-09-13", "{}", path);
+        }
+
+        let response =
+            warp::test::request()
+                .path("/chat/-1001/latest.json")
+                .header("origin", ALLOWED_ORIGIN)
+                .reply(&routes)
+                .await;
+
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_EXPOSE_HEADERS], renderer::chat_listing::DATE_HEADER);
+    }
+
+    #[tokio::test]
+    async fn invalid_dates_are_bad_requests_in_every_format() {
+        let (db, routes) = test_routes("invalid-date");
+
+        lock_db(&db).put("chat_rel:-1005766", b"\0").unwrap();
+
+        for path in [
+            "/chat/-1005766/2020-13-45",
+            "/chat/-1005766/2020-13-45.json",
+            "/chat/-1005766/2020-13-45.txt",
+            "/chat/-1005766/2020-13-45.json?v=2",
+        ] {
+            let response = warp::test::request().path(path).reply(&routes).await;
+
+            let body = String::from_utf8_lossy(response.body());
+
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", path);
+            assert!(body.contains("invalid date (got 2020-13-45)"), "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn cached_day_pages_are_kept_apart_per_viewer_and_dropped_on_writes() {
+        use crate::render_cache::invalidate_chat_pages;
+        use crate::workers::telegram_handler::build_chat_index_key;
+
+        std::env::set_var("MINUTEMAN_SHARE_SECRET", "share-test-secret");
+
+        // the cache is process wide, the chat is this test's own
+        let (db, routes) = test_routes("cached-day");
+
+        let token = {
+            let dbi = lock_db(&db);
+
+            dbi.put("chat_rel:-1006606", b"\0").unwrap();
+            dbi.put("chat:-1006606:1600000000", r#"{"message": {"user_id": "1001", "time": 1600000000, "received_at": 1600000000, "text": "hi", "entities": [], "source": null}}"#).unwrap();
+            dbi.put(build_chat_index_key("-1006606", 1_600_000_000), b"\0").unwrap();
+
+            crate::share::create_share(&dbi, "-1006606", Utc::now().timestamp() + 3600).unwrap().1
+        };
+
+        let cache = |cookie: Option<String>| {
+            let request = warp::test::request().path("/chat/-1006606/2020-09-13.json");
+
+            let request =
+                match cookie {
+                    Some(cookie) => request.header("cookie", cookie),
+                    None => request,
+                };
+
+            async {
+                let response = request.reply(&routes).await;
+
+                assert_eq!(response.status(), StatusCode::OK);
+
+                response.headers()["x-minuteman-cache"].to_str().unwrap().to_string()
+            }
+        };
+
+        let share_cookie = Some(format!("minuteman_share={}", token));
+
+        assert_eq!(cache(None).await, "miss");
+        assert_eq!(cache(None).await, "hit");
+
+        // same url, but a share link's viewer doesn't get the public page
+        assert_eq!(cache(share_cookie.clone()).await, "miss");
+        assert_eq!(cache(share_cookie.clone()).await, "hit");
+
+        invalidate_chat_pages("-1006606");
+
+        assert_eq!(cache(None).await, "miss");
+        assert_eq!(cache(share_cookie).await, "miss");
+    }
+
+    #[tokio::test]
+    async fn chats_pages_seek_from_their_cursors_with_archived_chats_last() {
+        use crate::workers::chat_policy::{ChatPolicy, set_chat_policy};
+        use crate::workers::telegram_handler::record_chat_activity;
+
+        let (db, routes) = test_routes("chats-pages");
+
+        let now = Utc::now().timestamp();
+
+        {
+            let dbi = lock_db(&db);
+
+            // the archived chat had the latest message, and the lowest id
+            for (chat_id, hours_ago) in [("-1002629000", 0), ("-1002629001", 1), ("-1002629002", 2), ("-1002629003", 3), ("-1002629004", 4)] {
+                dbi.put(format!("chat_rel:{}", chat_id), b"\0").unwrap();
+
+                record_chat_activity(&dbi, chat_id, now - hours_ago * 3_600).unwrap();
+            }
+
+            set_chat_policy(&dbi, "-1002629000", ChatPolicy { bot_removed_at: Some(now), ..ChatPolicy::default() }).unwrap();
+        }
+
+        let page = |path: String| {
+            let routes = routes.clone();
+
+            async move {
+                let response = warp::test::request().path(&path).reply(&routes).await;
+
+                assert_eq!(response.status(), StatusCode::OK, "{}", path);
+
+                let json = serde_json::from_slice::<serde_json::Value>(response.body()).unwrap();
+
+                let ids =
+                    json["data"]
+                        .as_array()
+                        .unwrap()
+                        .iter()
+                        .map(|chat| chat["id"].as_str().unwrap().to_string())
+                        .collect::<Vec<String>>();
+
+                (ids, json["prev"].as_str().map(String::from), json["next"].as_str().map(String::from))
+            }
+        };
+
+        let (first, prev, next) = page("/chats.json?limit=2".to_string()).await;
+
+        assert_eq!(first, ["-1002629001", "-1002629002"]);
+        assert_eq!(prev, None);
+
+        let (second, prev, next) = page(format!("/chats.json?limit=2&page={}", next.unwrap())).await;
+
+        assert_eq!(second, ["-1002629003", "-1002629004"]);
+
+        let (last, _, end) = page(format!("/chats.json?limit=2&page={}", next.unwrap())).await;
+
+        assert_eq!(last, ["-1002629000"]);
+        assert_eq!(end, None);
+
+        let (back, prev, _) = page(format!("/chats.json?limit=2&before={}", prev.unwrap())).await;
+
+        assert_eq!(back, first);
+        assert_eq!(prev, None);
+
+        // by id, archived or not
+        let (by_id, _, next) = page("/chats.json?limit=2&sort=name".to_string()).await;
+
+        assert_eq!(by_id, ["-1002629000", "-1002629001"]);
+        assert_eq!(next.as_deref(), Some("-1002629001"));
+
+        // bringing the chat back moves it up with its activity
+        set_chat_policy(&lock_db(&db), "-1002629000", ChatPolicy::default()).unwrap();
+
+        let (first, _, _) = page("/chats.json?limit=2".to_string()).await;
+
+        assert_eq!(first, ["-1002629000", "-1002629001"]);
+    }
+
+    #[tokio::test]
+    async fn rendered_pages_are_balanced_documents() {
+        use crate::components::page::tests::{assert_balanced, assert_one_document};
+        use crate::workers::telegram_handler::{build_chat_index_key, build_message_key, MessageSlot, record_chat_activity};
+
+        let (db, routes) = test_routes("balanced-pages");
+
+        {
+            let dbi = lock_db(&db);
+
+            dbi.put("chat_rel:-1002576000", b"\0").unwrap();
+            dbi.put(build_chat_index_key("-1002576000", 1_600_000_000), b"\0").unwrap();
+
+            record_chat_activity(&dbi, "-1002576000", 1_600_000_120).unwrap();
+
+            for (slot, item) in [
+                (MessageSlot::new(1_600_000_000, 1), r#"{"message": {"user_id": "1001", "time": 1600000000, "received_at": 1600000000, "text": "<b>not bold</b> & <i>", "entities": [], "source": null}}"#),
+                (MessageSlot::new(1_600_000_060, 2), r#"{"membership": {"user_id": "1002", "time": 1600000060, "received_at": 1600000060, "type": "joined", "source": null}}"#),
+                (MessageSlot::new(1_600_000_120, 3), r#"{"pin": {"user_id": "1001", "time": 1600000120, "received_at": 1600000120, "message": "<b>not bold</b>", "message_id": "1", "source": null}}"#),
+            ] {
+                dbi.put(build_message_key("-1002576000", slot), item).unwrap();
+            }
+
+            dbi.put(format!("chat_pins:-1002576000:{}", MessageSlot::new(1_600_000_000, 1)), b"\0").unwrap();
+        }
+
+        for path in ["/", "/chat/-1002576000", "/chat/-1002576000/2020-09-13"] {
+            let response = warp::test::request().path(path).reply(&routes).await;
+
+            assert_eq!(response.status(), StatusCode::OK, "{}", path);
+
+            let html = String::from_utf8_lossy(response.body());
+
+            assert_balanced(&html);
+            assert_one_document(&html);
+        }
+
+        // the text made it onto the day, escaped
+        let html = warp::test::request().path("/chat/-1002576000/2020-09-13").reply(&routes).await;
+
+        assert!(String::from_utf8_lossy(html.body()).contains("&lt;b&gt;not bold&lt;/b&gt;"));
+    }
+
+    #[tokio::test]
+    async fn share_links_need_the_csrf_token() {
+        use crate::privacy::csrf_token;
+
+        // the variable is process wide, every test here sets the same token
+        std::env::set_var("MINUTEMAN_ADMIN_TOKEN", "admin-test-token");
+        std::env::set_var("MINUTEMAN_SHARE_SECRET", "share-test-secret");
+
+        let (_, routes) = test_routes("share-csrf");
+
+        let csrf = csrf_token().unwrap();
+        let expires = Utc::now().timestamp() + 3600;
+
+        let create = |body: serde_json::Value, header: Option<&str>| {
+            let request =
+                warp::test::request()
+                    .method("POST")
+                    .path("/admin/share")
+                    .header("cookie", "minuteman_admin=admin-test-token")
+                    .json(&body);
+
+            let request =
+                match header {
+                    Some(csrf) => request.header(CSRF_HEADER, csrf),
+                    None => request,
+                };
+
+            let routes = routes.clone();
+
+            async move { request.reply(&routes).await.status() }
+        };
+
+        assert_eq!(create(serde_json::json!({"chat_id": "-1002626000", "expires": expires}), None).await, StatusCode::FORBIDDEN);
+        assert_eq!(create(serde_json::json!({"chat_id": "-1002626000", "expires": expires, "csrf": "wrong"}), None).await, StatusCode::FORBIDDEN);
+        assert_eq!(create(serde_json::json!({"chat_id": "-1002626000", "expires": expires}), Some("wrong")).await, StatusCode::FORBIDDEN);
+
+        assert_eq!(create(serde_json::json!({"chat_id": "-1002626000", "expires": expires, "csrf": csrf}), None).await, StatusCode::OK);
+        assert_eq!(create(serde_json::json!({"chat_id": "-1002626000", "expires": expires}), Some(&csrf)).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn log_filter_changes_need_the_csrf_token() {
+        use crate::privacy::csrf_token;
+
+        std::env::set_var("MINUTEMAN_ADMIN_TOKEN", "admin-test-token");
+
+        let (_, routes) = test_routes("log-filter-csrf");
+
+        let csrf = csrf_token().unwrap();
+
+        let update = |body: serde_json::Value, header: Option<&str>| {
+            let request =
+                warp::test::request()
+                    .method("POST")
+                    .path("/admin/log-filter")
+                    .header("cookie", "minuteman_admin=admin-test-token")
+                    .json(&body);
+
+            let request =
+                match header {
+                    Some(csrf) => request.header(CSRF_HEADER, csrf),
+                    None => request,
+                };
+
+            let routes = routes.clone();
+
+            async move { request.reply(&routes).await.status() }
+        };
+
+        assert_eq!(update(serde_json::json!({"directive": "debug"}), None).await, StatusCode::FORBIDDEN);
+        assert_eq!(update(serde_json::json!({"directive": "debug", "csrf": "wrong"}), None).await, StatusCode::FORBIDDEN);
+
+        // past the check, logging just isn't set up in tests
+        assert_eq!(update(serde_json::json!({"directive": "debug", "csrf": csrf}), None).await, StatusCode::BAD_REQUEST);
+        assert_eq!(update(serde_json::json!({"directive": "debug"}), Some(&csrf)).await, StatusCode::BAD_REQUEST);
     }
 }
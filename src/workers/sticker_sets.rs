@@ -0,0 +1,126 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::Utc;
+use rocksdb::{DBWithThreadMode, MultiThreaded};
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+
+use crate::storage::{lock_db, ReadStore};
+use crate::storage_stats::prefix_iter;
+use crate::workers::telegram_handler::Bot;
+
+// one getStickerSet call per tick at most, a chat catching up on a backlog
+// of stickers doesn't turn into a burst of api calls
+const STICKER_SET_FETCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// `sticker_set:{name}` holds what `getStickerSet` said about a set.
+pub fn build_sticker_set_key(
+    name: &str,
+) -> String {
+    format!(
+        "sticker_set:{}",
+        name,
+    )
+}
+
+/// `queue:sticker_set:{name}` marks a set whose metadata wasn't fetched yet.
+pub fn build_sticker_set_queue_key(
+    name: &str,
+) -> String {
+    format!(
+        "queue:sticker_set:{}",
+        name,
+    )
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StickerSet {
+    pub name: String,
+    // None when telegram doesn't know the set (anymore)
+    pub title: Option<String>,
+    pub fetched_at: i64,
+}
+
+pub fn get_sticker_set(
+    db: &impl ReadStore,
+    name: &str,
+) -> Option<StickerSet> {
+    db.get(build_sticker_set_key(name))
+        .ok()
+        .flatten()
+        .map(|set| serde_json::from_slice::<StickerSet>(&set).ok())
+        .flatten()
+}
+
+/// Queues fetching the metadata of a set unless that's been done already.
+pub fn queue_sticker_set(
+    db: &DBWithThreadMode<MultiThreaded>,
+    name: &str,
+) -> Result<(), rocksdb::Error> {
+    if db.get(build_sticker_set_key(name))?.is_some() {
+        return Ok(());
+    }
+
+    db.put(build_sticker_set_queue_key(name), "")
+}
+
+async fn fetch_next_sticker_set(
+    db: &Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    bot: &Bot,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let queued =
+        prefix_iter(&*lock_db(&db), "queue:sticker_set:")
+            .next()
+            .map(|(key, _)| String::from_utf8(key.to_vec()).ok())
+            .flatten();
+
+    let key =
+        match queued {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+
+    let name = key.trim_start_matches("queue:sticker_set:");
+
+    let set =
+        StickerSet {
+            name: name.to_string(),
+            title: bot.get_sticker_set_title(name).await?,
+            fetched_at: Utc::now().timestamp(),
+        };
+
+    let db = lock_db(&db);
+
+    db.put(build_sticker_set_key(name), serde_json::to_string(&set)?)?;
+    db.delete(&key)?;
+
+    Ok(())
+}
+
+/// Works off the queued sticker sets until dropped, away from the update
+/// stream so ingestion never waits on them.
+pub struct StickerSetFetcher(JoinHandle<()>);
+
+impl Drop for StickerSetFetcher {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+pub fn spawn_sticker_set_fetcher(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    bot: Bot,
+) -> StickerSetFetcher {
+    StickerSetFetcher(
+        tokio::spawn(async move {
+            loop {
+                if let Err(err) = fetch_next_sticker_set(&db, &bot).await {
+                    dbg!(err);
+                }
+
+                tokio::time::sleep(STICKER_SET_FETCH_INTERVAL).await;
+            }
+        }),
+    )
+}
@@ -1,111 +1,622 @@
+use std::collections::BTreeMap;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::Instant;
 
 use futures::StreamExt;
 use pw_telegram_bot_fork::*;
 use pw_telegram_bot_fork::{Api, GetUserProfilePhotos, Message, MessageEntityKind, MessageKind, MessageText, PhotoSize, PollType, ToFileRef, ToMessageId, UpdateKind, User};
-use rocksdb::{DBWithThreadMode, MultiThreaded};
+use rocksdb::{DBWithThreadMode, Direction, IteratorMode, MultiThreaded, ReadOptions};
 use serde::{Deserialize, Serialize};
 
-use crate::{get_telegram_api_token, JOB_SLEEP_INTERVAL, MAX_FILE_SIZE, ok_or_continue, ok_or_return_none, some_or_return_none};
+use crate::{CATCHUP_LAG_THRESHOLD, CATCHUP_LOG_INTERVAL, MinutemanError, ok_or_continue, ok_or_return_none, some_or_continue, some_or_return_none, USERNAME_ALIAS_GRACE_PERIOD};
+use crate::config::{BotConfig, get_file_size_limits, get_log_inline_queries, get_log_own_messages, get_search_index, get_strip_exif, get_telegram_api_url};
+use crate::blob_store::{delete_blob_pointer, delete_external_blob, place_blob, resolve_blob, resolve_blob_async};
+use crate::exif::strip_image_metadata;
+use crate::metrics::{api_health, record_deferred_jobs, record_message_ingested, record_telegram_update, telegram_metrics, track_api_call};
+use crate::migrations::{SCHEMA_VERSION, to_versioned_string, Versioned};
+use crate::render_cache::invalidate_chat_pages;
+use crate::search_index::queue_postings;
+use crate::storage::{invalidate_chat_meta, invalidate_user_meta, lock_db, PendingWrites, ReadStore};
+use crate::storage_stats::{file_counter_keys, message_counter_keys, prefix_iter, put_counted};
+use crate::thumbnail::{generate_thumbnail, THUMBNAIL_SIZE};
+use crate::utils::{encode_query_value, get_file_meta, guess_image_dimensions, guess_mime_type, hash_file, resolve_user_meta};
+use crate::workers::commands::handle_command;
+use crate::workers::data_export::spawn_data_exporter;
+use crate::workers::digest::spawn_digest_scheduler;
+use crate::workers::file_retry::{FileRetry, queue_file_retry, spawn_file_retrier};
+use crate::workers::chat_policy::{ChatPolicy, get_chat_policy, is_private_chat_id, logs_private_chat, set_chat_policy};
+use crate::workers::heartbeat::{record_last_error, spawn_heartbeat};
+use crate::workers::ignore_list::is_user_ignored;
+use crate::workers::panics::{enter_update, is_panicked_update, set_panic_message};
+use crate::workers::ingest_errors::record_ingest_error;
+use crate::workers::inline_queries::{handle_inline_update, InlineUpdate};
+use crate::workers::restart::{exit_fatal, FatalError, RestartBackoff};
+use crate::workers::member_counts::spawn_member_count_poller;
+use crate::workers::sticker_sets::{queue_sticker_set, spawn_sticker_set_fetcher};
+
+// seconds a `getUpdates` call waits for something to happen
+const UPDATES_POLL_TIMEOUT: i64 = 30;
+
+// telegram leaves `chat_member` out unless it's asked for explicitly
+const ALLOWED_UPDATES: [&str; 7] =
+    ["message", "edited_message", "channel_post", "edited_channel_post", "poll", "chat_member", "my_chat_member"];
+
+// only asked for when they're logged, see `get_log_inline_queries`
+const INLINE_UPDATES: [&str; 2] = ["inline_query", "chosen_inline_result"];
+
+// how many seconds after its date a membership change may be moved to when
+// another item has the slot
+const MEMBERSHIP_SLOT_SPREAD: i64 = 5;
+
+/// One of the configured bots, handed to everything that talks to telegram
+/// on its behalf. File paths telegram hands out only work with the token of
+/// the bot that asked for them.
+#[derive(Clone)]
+pub struct Bot {
+    pub name: String,
+    pub api: Api,
+    // telegram user id of the bot, known once `getMe` answered
+    pub user_id: Option<String>,
+    token: String,
+    chats: Vec<String>,
+}
 
-pub fn build_file_url(
-    file_path: &str,
+impl Bot {
+    pub fn new(
+        config: &BotConfig,
+    ) -> Self {
+        Bot {
+            name: config.name.clone(),
+            api: Api::new(&config.token),
+            user_id: None,
+            token: config.token.clone(),
+            chats: config.chats.clone(),
+        }
+    }
+
+    pub fn with_user_id(
+        self,
+        user_id: &str,
+    ) -> Self {
+        let mut bot = self;
+
+        bot.user_id = Some(user_id.to_string());
+
+        bot
+    }
+
+    /// Whether the bot's allowlist lets it log the chat.
+    pub fn logs_chat(
+        &self,
+        chat_id: &str,
+    ) -> bool {
+        self.chats.is_empty() || self.chats.iter().any(|id| id == chat_id)
+    }
+
+    /// Posts a plain text message into a chat.
+    pub async fn send_message(
+        &self,
+        chat_id: &str,
+        text: String,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        track_api_call(
+            "sendMessage",
+            self.api.send(
+                SendMessage::new(
+                    ChatId::new(chat_id.parse::<i64>()?),
+                    text,
+                ),
+            ),
+        ).await?;
+
+        Ok(())
+    }
+
+    /// Uploads `data` as a document named `file_name`, through the bot api
+    /// directly like `get_sticker_set_title`.
+    pub async fn send_document(
+        &self,
+        chat_id: &str,
+        file_name: &str,
+        data: Vec<u8>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let url =
+            format!(
+                "{}bot{}/sendDocument",
+                get_telegram_api_url(),
+                self.token,
+            );
+
+        let form =
+            reqwest::multipart::Form::new()
+                .text("chat_id", chat_id.to_string())
+                .part(
+                    "document",
+                    reqwest::multipart::Part::bytes(data)
+                        .file_name(file_name.to_string()),
+                );
+
+        let response =
+            track_api_call(
+                "sendDocument",
+                async {
+                    reqwest::Client::new()
+                        .post(&url)
+                        .multipart(form)
+                        .send()
+                        .await?
+                        .bytes()
+                        .await
+                },
+            ).await?;
+
+        let response = serde_json::from_slice::<serde_json::Value>(&response)?;
+
+        if response.get("ok").map(|ok| ok.as_bool()).flatten() != Some(true) {
+            return Err(
+                response
+                    .get("description")
+                    .map(|description| description.as_str())
+                    .flatten()
+                    .unwrap_or("telegram didn't take the document")
+                    .into(),
+            );
+        }
+
+        Ok(())
+    }
+
+    pub fn build_file_url(
+        &self,
+        file_path: &str,
+    ) -> String {
+        format!(
+            "{}file/bot{}/{}",
+            get_telegram_api_url(),
+            self.token,
+            file_path,
+        )
+    }
+
+    /// Title of a sticker set, None when telegram doesn't know the set. The
+    /// fork has no `getStickerSet`, this goes to the bot api directly.
+    pub async fn get_sticker_set_title(
+        &self,
+        name: &str,
+    ) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let url =
+            format!(
+                "{}bot{}/getStickerSet?name={}",
+                get_telegram_api_url(),
+                self.token,
+                encode_query_value(name),
+            );
+
+        let response =
+            track_api_call(
+                "getStickerSet",
+                async {
+                    reqwest::get(&url).await?.bytes().await
+                },
+            ).await?;
+
+        let response = serde_json::from_slice::<serde_json::Value>(&response)?;
+
+        Ok(
+            response
+                .get("result")
+                .map(|set| set.get("title"))
+                .flatten()
+                .map(|title| title.as_str())
+                .flatten()
+                .map(|title| title.to_string()),
+        )
+    }
+
+    /// Number of members of a chat, through the bot api directly like
+    /// `get_sticker_set_title`. None when telegram answers without one.
+    pub async fn get_chat_member_count(
+        &self,
+        chat_id: &str,
+    ) -> Result<Option<i64>, Box<dyn std::error::Error>> {
+        let url =
+            format!(
+                "{}bot{}/getChatMemberCount?chat_id={}",
+                get_telegram_api_url(),
+                self.token,
+                encode_query_value(chat_id),
+            );
+
+        let response =
+            track_api_call(
+                "getChatMemberCount",
+                async {
+                    reqwest::get(&url).await?.bytes().await
+                },
+            ).await?;
+
+        let response = serde_json::from_slice::<serde_json::Value>(&response)?;
+
+        Ok(
+            response
+                .get("result")
+                .map(|count| count.as_i64())
+                .flatten(),
+        )
+    }
+
+    /// `get_file_path` for a bare file id, which the fork has no file
+    /// reference for. Errors are classified the same way.
+    pub async fn get_file_path_by_id(
+        &self,
+        file_id: &str,
+    ) -> Result<String, FileError> {
+        let url =
+            format!(
+                "{}bot{}/getFile?file_id={}",
+                get_telegram_api_url(),
+                self.token,
+                encode_query_value(file_id),
+            );
+
+        let response =
+            track_api_call(
+                "getFile",
+                async {
+                    reqwest::get(&url).await?.bytes().await
+                },
+            )
+                .await
+                .map_err(FileError::from_download)?;
+
+        let response =
+            serde_json::from_slice::<serde_json::Value>(&response)
+                .map_err(|err| FileError::from_api(&err.to_string()))?;
+
+        // failed calls come with a description like "Bad Request: file is
+        // too big" instead of a result
+        if let Some(description) = response.get("description").filter(|_| response.get("result").is_none()) {
+            return Err(
+                FileError::from_api(description.as_str().unwrap_or_default()),
+            );
+        }
+
+        response
+            .get("result")
+            .map(|file| file.get("file_path"))
+            .flatten()
+            .map(|file_path| file_path.as_str())
+            .flatten()
+            .map(|file_path| file_path.to_string())
+            .ok_or(
+                FileError {
+                    kind: FileFailureKind::InvalidReference,
+                    message: "telegram returned no file path".to_string(),
+                },
+            )
+    }
+
+    /// Long-polls for the updates after `offset`. The fork's stream neither
+    /// asks for `chat_member` updates nor knows them, so the updates are
+    /// handed out raw.
+    pub async fn get_updates(
+        &self,
+        offset: i64,
+    ) -> Result<Vec<serde_json::Value>, Box<dyn std::error::Error>> {
+        let mut allowed_updates = ALLOWED_UPDATES.to_vec();
+
+        if get_log_inline_queries() {
+            allowed_updates.extend(INLINE_UPDATES);
+        }
+
+        let url =
+            format!(
+                "{}bot{}/getUpdates?offset={}&timeout={}&allowed_updates={}",
+                get_telegram_api_url(),
+                self.token,
+                offset,
+                UPDATES_POLL_TIMEOUT,
+                encode_query_value(&serde_json::to_string(&allowed_updates)?),
+            );
+
+        // not tracked, every call would count as a slow one
+        let response = reqwest::get(&url).await?.bytes().await?;
+
+        let mut response = serde_json::from_slice::<serde_json::Value>(&response)?;
+
+        match response.get_mut("result").map(|result| result.take()) {
+            Some(serde_json::Value::Array(updates)) => Ok(updates),
+            _ =>
+                Err(
+                    response
+                        .get("description")
+                        .map(|description| description.as_str())
+                        .flatten()
+                        .unwrap_or("telegram returned no updates")
+                        .into(),
+                ),
+        }
+    }
+}
+
+/// Checks the bot's token with a `getMe` call, returning the bot's own user.
+pub async fn validate_bot(
+    bot: &Bot,
+) -> Result<UserMeta, MinutemanError> {
+    let me =
+        track_api_call(
+            "getMe",
+            bot.api.send(GetMe),
+        )
+            .await
+            .map_err(|err|
+                MinutemanError::TelegramError(
+                    format!("bot {} failed to authenticate: {:?}", bot.name, err),
+                )
+            )?;
+
+    Ok(me.into())
+}
+
+/// `state:bot_identity:{bot_name}` holds the user meta of a bot as `getMe`
+/// last reported it.
+pub fn build_bot_identity_key(
+    bot_name: &str,
+) -> String {
+    format!(
+        "state:bot_identity:{}",
+        bot_name,
+    )
+}
+
+pub fn store_bot_identity(
+    db: &DBWithThreadMode<MultiThreaded>,
+    bot_name: &str,
+    me: &UserMeta,
+) -> Result<(), Box<dyn std::error::Error>> {
+    db.put(
+        build_bot_identity_key(bot_name),
+        serde_json::to_string(me)?,
+    )?;
+
+    Ok(())
+}
+
+/// User ids of every bot that ever ran against this database.
+pub fn find_bot_user_ids(
+    db: &impl ReadStore,
+) -> Vec<String> {
+    prefix_iter(db, "state:bot_identity:")
+        .filter_map(|(_, val)| serde_json::from_slice::<UserMeta>(&val).ok())
+        .map(|me| me.id)
+        .collect()
+}
+
+/// `chat_bot:{chat_id}` holds the name of the bot logging the chat.
+pub fn build_chat_bot_key(
+    chat_id: &str,
 ) -> String {
     format!(
-        "https://api.telegram.org/file/bot{}/{}",
-        get_telegram_api_token(),
-        file_path,
+        "chat_bot:{}",
+        chat_id,
     )
 }
 
+/// The bot with the given name, or the first configured one when it's gone
+/// or wasn't recorded.
+pub fn find_bot<'a>(
+    bots: &'a [Bot],
+    name: Option<&str>,
+) -> Option<&'a Bot> {
+    name
+        .map(|name| bots.iter().find(|bot| bot.name == name))
+        .flatten()
+        .or(bots.first())
+}
+
+/// The bot logging the chat, see `find_bot`.
+pub fn find_chat_bot<'a>(
+    db: &DBWithThreadMode<MultiThreaded>,
+    bots: &'a [Bot],
+    chat_id: &str,
+) -> Option<&'a Bot> {
+    let name =
+        db.get(build_chat_bot_key(chat_id))
+            .ok()
+            .flatten()
+            .map(|name| String::from_utf8(name).ok())
+            .flatten();
+
+    find_bot(bots, name.as_deref())
+}
+
 pub async fn get_file(
+    bot: &Bot,
+    file_path: &str,
+) -> Result<Vec<u8>, FileError> {
+    get_file_capped(bot, file_path, i64::MAX)
+        .await?
+        .ok_or(
+            FileError {
+                kind: FileFailureKind::TooLarge,
+                message: "file too large".to_string(),
+            },
+        )
+}
+
+/// `get_file` giving up once more than `max_size` bytes came in, which is
+/// None.
+pub async fn get_file_capped(
+    bot: &Bot,
     file_path: &str,
-) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-    let url = build_file_url(file_path);
-    let mut response = reqwest::get(&url).await?;
+    max_size: i64,
+) -> Result<Option<Vec<u8>>, FileError> {
+    let url = bot.build_file_url(file_path);
 
-    let mut out = response.bytes_stream();
+    track_api_call(
+        "file_download",
+        async {
+            // an expired path answers with a 404 page, not a file
+            let response =
+                reqwest::get(&url)
+                    .await?
+                    .error_for_status()?;
 
-    let mut buffer = Vec::new();
+            let mut out = response.bytes_stream();
 
-    while let Some(chunk) = out.next().await {
-        buffer.extend(chunk?);
-    }
+            let mut buffer = Vec::new();
+
+            while let Some(chunk) = out.next().await {
+                buffer.extend(chunk?);
+
+                if buffer.len() as i64 > max_size {
+                    return Ok(None);
+                }
+            }
 
-    Ok(buffer)
+            Ok::<Option<Vec<u8>>, reqwest::Error>(Some(buffer))
+        },
+    )
+        .await
+        .map_err(FileError::from_download)
 }
 
 pub async fn get_file_path(
-    api: &Api,
+    bot: &Bot,
     file: &impl ToFileRef,
-) -> Option<String> {
-    api.send(
-        GetFile::new(
-            file,
+) -> Result<String, FileError> {
+    track_api_call(
+        "getFile",
+        bot.api.send(
+            GetFile::new(
+                file,
+            ),
         ),
     )
         .await
-        .ok()
-        .map(|file| file.file_path)
-        .flatten()
+        .map_err(|err| FileError::from_api(&format!("{:?}", err)))?
+        .file_path
+        .ok_or(
+            FileError {
+                kind: FileFailureKind::InvalidReference,
+                message: "telegram returned no file path".to_string(),
+            },
+        )
+}
+
+/// A file of a message along with the size limit of its kind. The path is
+/// an error for files that aren't fetched: announced as too large by
+/// telegram, or ones `getFile` failed on.
+pub struct FileRef {
+    pub file_id: String,
+    pub file_path: Result<String, FileError>,
+    pub file_size: Option<i64>,
+    pub max_size: i64,
+    // set on the photo size kept as the thumbnail of the stored one
+    pub thumb_of: Option<String>,
+}
+
+async fn file_ref(
+    bot: &Bot,
+    file: &impl ToFileRef,
+    file_id: &str,
+    file_size: Option<i64>,
+    max_size: i64,
+) -> Option<FileRef> {
+    let too_large = file_size.map(|size| size > max_size).unwrap_or(false);
+
+    // telegram leaves the size out now and then, those files are downloaded
+    // and given up on once they cross the limit
+    let file_path =
+        match too_large {
+            true =>
+                Err(
+                    FileError {
+                        kind: FileFailureKind::TooLarge,
+                        message: "too large to archive".to_string(),
+                    },
+                ),
+            false => get_file_path(bot, file).await,
+        };
+
+    Some(
+        FileRef {
+            file_id: file_id.to_string(),
+            file_path,
+            file_size,
+            max_size,
+            thumb_of: None,
+        },
+    )
 }
 
 pub async fn extract_file_paths(
-    api: &Api,
+    bot: &Bot,
     message: &InterMessage,
-) -> Vec<(String, String)> {
-    let mut file_refs = Vec::<(String, String)>::new();
+) -> Vec<FileRef> {
+    let limits = get_file_size_limits();
+
+    let mut file_refs = Vec::<FileRef>::new();
 
     match message.kind {
         MessageKind::Audio { ref data, .. } => {
-            match data.file_size {
-                Some(x) if x <= MAX_FILE_SIZE => {}
-                Some(x) if x > MAX_FILE_SIZE => return file_refs,
-                _ => return file_refs,
-            }
-
-            file_refs.push(
-                (
-                    data.file_id.clone(),
-                    match get_file_path(&api, &data).await {
-                        Some(x) => x,
-                        None => return file_refs,
-                    },
-                ));
+            file_refs.extend(
+                file_ref(bot, &data, &data.file_id, data.file_size, limits.audio).await,
+            );
         }
         MessageKind::Voice { ref data, .. } => {
-            match data.file_size {
-                Some(x) if x <= MAX_FILE_SIZE => {}
-                Some(x) if x > MAX_FILE_SIZE => return file_refs,
-                _ => return file_refs,
-            }
-
-            file_refs.push(
-                (
-                    data.file_id.clone(),
-                    match get_file_path(&api, &data).await {
-                        Some(x) => x,
-                        None => return file_refs,
-                    },
-                ));
+            file_refs.extend(
+                file_ref(bot, &data, &data.file_id, data.file_size, limits.voice).await,
+            );
+        }
+        MessageKind::Document { ref data, .. } if is_animation_document(data.mime_type.as_deref()) => {
+            file_refs.extend(
+                file_ref(bot, &data, &data.file_id, data.file_size, limits.animation).await,
+            );
         }
         MessageKind::Photo { ref data, .. } => {
-            for photo in data {
-                match photo.file_size {
-                    Some(x) if x <= MAX_FILE_SIZE => {}
-                    Some(x) if x > MAX_FILE_SIZE => continue,
-                    _ => continue,
+            let fitting =
+                data
+                    .iter()
+                    .filter(|photo| photo.file_size.map(|size| size <= limits.photo).unwrap_or(true))
+                    .collect::<Vec<&PhotoSize>>();
+
+            let sizes =
+                fitting
+                    .iter()
+                    .map(|photo| (photo.width, photo.height))
+                    .collect::<Vec<(i64, i64)>>();
+
+            match pick_photo_sizes(&sizes) {
+                Some((original, thumb)) => {
+                    let original = fitting[original];
+
+                    file_refs.extend(
+                        file_ref(bot, &original, &original.file_id, original.file_size, limits.photo).await,
+                    );
+
+                    if let Some(thumb) = thumb.map(|thumb| fitting[thumb]) {
+                        file_refs.extend(
+                            file_ref(bot, &thumb, &thumb.file_id, thumb.file_size, limits.photo)
+                                .await
+                                .map(|file_ref|
+                                    FileRef {
+                                        thumb_of: Some(original.file_id.clone()),
+                                        ..file_ref
+                                    }
+                                ),
+                        );
+                    }
                 }
+                // only the largest size is marked as too large to archive
+                None if !data.is_empty() => {
+                    let photo = &find_biggest_photo(data);
 
-                file_refs.push(
-                    (
-                        photo.file_id.clone(),
-                        match get_file_path(&api, &photo).await {
-                            Some(x) => x,
-                            None => continue,
-                        },
-                    ));
+                    file_refs.extend(
+                        file_ref(bot, &photo, &photo.file_id, photo.file_size, limits.photo).await,
+                    );
+                }
+                None => {}
             }
         }
         // this doesn't implement support for videos on purpose
@@ -117,26 +628,63 @@ pub async fn extract_file_paths(
     file_refs
 }
 
+/// Telegram sends GIFs as animations, along with a document for clients
+/// that don't know those. The fork only parses the document, so animations
+/// are told apart by their mime type: actual GIFs, or the silent MP4s
+/// telegram converts them to. An MP4 sent as a file looks the same.
+pub fn is_animation_document(
+    mime_type: Option<&str>,
+) -> bool {
+    matches!(mime_type, Some("image/gif") | Some("video/mp4"))
+}
+
+/// Downloads the message's files, along with the ids of the ones that
+/// weren't (which get a failure marker saying why). The last field of a
+/// download is the file it's the thumbnail of, if it's one.
 pub async fn get_files(
     db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
-    api: &Api,
+    bot: &Bot,
     message: &InterMessage,
-) -> Vec<(String, Vec<u8>)> {
-    let mut files = Vec::<(String, Vec<u8>)>::new();
+) -> (Vec<(String, String, Vec<u8>, Option<String>)>, Vec<String>) {
+    let mut files = Vec::<(String, String, Vec<u8>, Option<String>)>::new();
+    let mut failed = Vec::<String>::new();
+
+    for file_ref in extract_file_paths(bot, message).await {
+        let FileRef { file_id, file_path, file_size, max_size, thumb_of } = file_ref;
+
+        let file_path =
+            match file_path {
+                Ok(file_path) => file_path,
+                // the thumbnail gets generated from the original instead
+                Err(_) if thumb_of.is_some() => continue,
+                Err(err) if err.kind == FileFailureKind::TooLarge => {
+                    store_file_too_large(db.clone(), &file_id, file_size, max_size);
+                    failed.push(file_id);
+
+                    continue;
+                }
+                Err(err) => {
+                    store_file_error(db.clone(), &file_id, &err);
+                    queue_retry_if_transient(&db, bot, message, &file_id, max_size, &err);
+                    failed.push(file_id);
+
+                    continue;
+                }
+            };
+
+        let file_key =
+            build_file_key(
+                FileEntryType::User,
+                &file_id,
+            );
 
-    for (file_id, file_path) in extract_file_paths(&api, message).await {
-        let file = {
-            let db = db.lock().unwrap();
+        let stored = {
+            let db = lock_db(&db);
 
             if db.key_may_exist(&file_id) {
                 println!("{} already exists, returning from db", &file_id);
 
-                db.get(
-                    build_file_key(
-                        FileEntryType::User,
-                        &file_id,
-                    ),
-                )
+                db.get(&file_key)
                     .ok()
                     .flatten()
             } else {
@@ -144,13 +692,34 @@ pub async fn get_files(
             }
         };
 
+        // fetched from its store with the database free
+        let file =
+            match stored {
+                Some(value) => resolve_blob_async(file_key, value).await.ok(),
+                None => None,
+            };
+
         let file =
             if file.is_none() {
-                get_file(
-                    &file_path,
-                )
-                    .await
-                    .ok()
+                match get_file_capped(bot, &file_path, max_size).await {
+                    Ok(Some(file)) => Some(file),
+                    // the thumbnail gets generated from the original instead
+                    Ok(None) if thumb_of.is_some() => continue,
+                    Ok(None) => {
+                        store_file_too_large(db.clone(), &file_id, None, max_size);
+                        failed.push(file_id);
+
+                        continue;
+                    }
+                    Err(_) if thumb_of.is_some() => continue,
+                    Err(err) => {
+                        store_file_error(db.clone(), &file_id, &err);
+                        queue_retry_if_transient(&db, bot, message, &file_id, max_size, &err);
+                        failed.push(file_id);
+
+                        continue;
+                    }
+                }
             } else {
                 file
             };
@@ -159,13 +728,48 @@ pub async fn get_files(
             files.push(
                 (
                     file_id,
+                    file_path,
                     entry,
+                    thumb_of,
                 ),
             );
         });
     }
 
-    files
+    (files, failed)
+}
+
+// failures that may pass get a few more tries in the background, see
+// `retry_files`
+fn queue_retry_if_transient(
+    db: &Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    bot: &Bot,
+    message: &InterMessage,
+    file_id: &str,
+    max_size: i64,
+    err: &FileError,
+) {
+    if !err.kind.is_retryable() {
+        return;
+    }
+
+    let (mime_type, file_name) = message_file_details(message);
+
+    let retry =
+        FileRetry::new(
+            &build_message_key(
+                &message_chat_id(message),
+                message_slot(message),
+            ),
+            &bot.name,
+            max_size,
+            mime_type,
+            file_name,
+        );
+
+    if let Err(err) = queue_file_retry(&lock_db(&db), file_id, &retry) {
+        dbg!(err);
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -176,6 +780,9 @@ pub struct UserMeta {
     pub username: Option<String>,
     pub is_bot: bool,
     pub language_code: Option<String>,
+    // the schema version it was written with, see `to_versioned_string`
+    #[serde(default)]
+    pub v: u32,
 }
 
 impl Default for UserMeta {
@@ -187,6 +794,7 @@ impl Default for UserMeta {
             username: None,
             is_bot: false,
             language_code: None,
+            v: SCHEMA_VERSION,
         }
     }
 }
@@ -241,6 +849,7 @@ impl From<User> for UserMeta {
             username: user.username,
             is_bot: user.is_bot,
             language_code: user.language_code,
+            v: SCHEMA_VERSION,
         }
     }
 }
@@ -257,6 +866,8 @@ pub struct GroupMeta {
     pub title: String,
     pub all_members_are_administrators: bool,
     pub invite_link: Option<String>,
+    #[serde(default)]
+    pub v: u32,
 }
 
 impl From<Group> for GroupMeta {
@@ -266,6 +877,7 @@ impl From<Group> for GroupMeta {
             title: group.title,
             all_members_are_administrators: group.all_members_are_administrators,
             invite_link: group.invite_link,
+            v: SCHEMA_VERSION,
         }
     }
 }
@@ -282,6 +894,8 @@ pub struct SuperGroupMeta {
     pub title: String,
     pub username: Option<String>,
     pub invite_link: Option<String>,
+    #[serde(default)]
+    pub v: u32,
 }
 
 impl From<Supergroup> for SuperGroupMeta {
@@ -291,6 +905,7 @@ impl From<Supergroup> for SuperGroupMeta {
             title: group.title,
             username: group.username,
             invite_link: group.invite_link,
+            v: SCHEMA_VERSION,
         }
     }
 }
@@ -307,6 +922,8 @@ pub struct ChannelMeta {
     pub title: String,
     pub username: Option<String>,
     pub invite_link: Option<String>,
+    #[serde(default)]
+    pub v: u32,
 }
 
 impl From<Channel> for ChannelMeta {
@@ -316,6 +933,7 @@ impl From<Channel> for ChannelMeta {
             title: chan.title,
             username: chan.username,
             invite_link: chan.invite_link,
+            v: SCHEMA_VERSION,
         }
     }
 }
@@ -337,6 +955,8 @@ pub struct RawChatMeta {
     pub invite_link: Option<String>,
     pub language_code: Option<String>,
     pub all_members_are_administrators: Option<bool>,
+    #[serde(default)]
+    pub v: u32,
 }
 
 impl From<RawChat> for RawChatMeta {
@@ -351,6 +971,7 @@ impl From<RawChat> for RawChatMeta {
             invite_link: raw_chat.invite_link,
             language_code: raw_chat.language_code,
             all_members_are_administrators: raw_chat.all_members_are_administrators,
+            v: SCHEMA_VERSION,
         }
     }
 }
@@ -370,6 +991,48 @@ pub enum ChatMeta {
     Unknown(RawChatMeta),
 }
 
+impl Versioned for UserMeta {
+    fn version(&self) -> u32 {
+        self.v
+    }
+
+    fn set_version(&mut self, version: u32) {
+        self.v = version;
+    }
+}
+
+impl Versioned for ChannelMeta {
+    fn version(&self) -> u32 {
+        self.v
+    }
+
+    fn set_version(&mut self, version: u32) {
+        self.v = version;
+    }
+}
+
+impl Versioned for ChatMeta {
+    fn version(&self) -> u32 {
+        match self {
+            ChatMeta::User(user) => user.v,
+            ChatMeta::Group(group) => group.v,
+            ChatMeta::SuperGroup(group) => group.v,
+            ChatMeta::Channel(channel) => channel.v,
+            ChatMeta::Unknown(raw_chat) => raw_chat.v,
+        }
+    }
+
+    fn set_version(&mut self, version: u32) {
+        match self {
+            ChatMeta::User(user) => user.v = version,
+            ChatMeta::Group(group) => group.v = version,
+            ChatMeta::SuperGroup(group) => group.v = version,
+            ChatMeta::Channel(channel) => channel.v = version,
+            ChatMeta::Unknown(raw_chat) => raw_chat.v = version,
+        }
+    }
+}
+
 impl ChatMeta {
     pub fn id(&self) -> String {
         match self {
@@ -380,48 +1043,226 @@ impl ChatMeta {
             ChatMeta::Unknown(raw_chat) => raw_chat.id.clone(),
         }
     }
-}
 
-impl From<MessageChat> for ChatMeta {
-    fn from(chat: MessageChat) -> Self {
-        match chat {
-            MessageChat::Private(user) => ChatMeta::User(user.into()),
-            MessageChat::Group(group) => ChatMeta::Group(group.into()),
-            MessageChat::Supergroup(group) => ChatMeta::SuperGroup(group.into()),
-            MessageChat::Unknown(raw_chat) => ChatMeta::Unknown(raw_chat.into()),
+    pub fn title(&self) -> Option<String> {
+        match self {
+            ChatMeta::User(_) => None,
+            ChatMeta::Group(group) => Some(group.title.clone()),
+            ChatMeta::SuperGroup(group) => Some(group.title.clone()),
+            ChatMeta::Channel(channel) => Some(channel.title.clone()),
+            ChatMeta::Unknown(raw_chat) => raw_chat.title.clone(),
         }
     }
-}
-
-impl From<&MessageChat> for ChatMeta {
-    fn from(chat: &MessageChat) -> Self {
-        chat.clone().into()
-    }
-}
 
-impl From<Channel> for ChatMeta {
-    fn from(chan: Channel) -> Self {
-        ChatMeta::Channel(chan.into())
+    pub fn username(&self) -> Option<String> {
+        match self {
+            ChatMeta::User(user) => user.username.clone(),
+            ChatMeta::Group(_) => None,
+            ChatMeta::SuperGroup(group) => group.username.clone(),
+            ChatMeta::Channel(channel) => channel.username.clone(),
+            ChatMeta::Unknown(raw_chat) => raw_chat.username.clone(),
+        }
     }
-}
 
-impl From<&Channel> for ChatMeta {
-    fn from(chan: &Channel) -> Self {
-        ChatMeta::Channel(chan.clone().into())
+    pub fn invite_link(&self) -> Option<String> {
+        match self {
+            ChatMeta::User(_) => None,
+            ChatMeta::Group(group) => group.invite_link.clone(),
+            ChatMeta::SuperGroup(group) => group.invite_link.clone(),
+            ChatMeta::Channel(channel) => channel.invite_link.clone(),
+            ChatMeta::Unknown(raw_chat) => raw_chat.invite_link.clone(),
+        }
     }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum ForwardFromMeta {
-    User {
-        user: UserMeta,
+#[serde(rename_all = "lowercase")]
+pub enum ChatMetaChange {
+    Title {
+        title: String,
     },
-    Channel {
-        channel: ChannelMeta,
-        message_id: i64,
+    Photo {
+        file_id: Option<String>,
     },
-    ChannelHiddenUser {
-        sender_name: String,
+    DeletePhoto,
+    // 0 turns auto-delete off
+    AutoDeleteTimer {
+        seconds: i64,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMetaHistoryEntry {
+    pub time: i64,
+    pub user_id: Option<String>,
+    pub change: ChatMetaChange,
+    #[serde(default)]
+    pub v: u32,
+}
+
+impl Versioned for ChatMetaHistoryEntry {
+    fn version(&self) -> u32 {
+        self.v
+    }
+
+    fn set_version(&mut self, version: u32) {
+        self.v = version;
+    }
+}
+
+pub fn build_chat_meta_history_key(
+    chat_id: &str,
+    time: i64,
+) -> String {
+    format!(
+        "chat:meta_history:{}:{}",
+        chat_id,
+        time,
+    )
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatUsernameAlias {
+    pub chat_id: String,
+    pub expires: i64,
+}
+
+pub fn normalize_username(
+    username: &str,
+) -> String {
+    username
+        .trim_start_matches('@')
+        .to_lowercase()
+}
+
+pub fn build_chat_by_username_key(
+    username: &str,
+) -> String {
+    format!(
+        "chat_by_username:{}",
+        normalize_username(username),
+    )
+}
+
+pub fn build_chat_username_alias_key(
+    username: &str,
+) -> String {
+    format!(
+        "chat_by_username_alias:{}",
+        normalize_username(username),
+    )
+}
+
+/// Points `chat_by_username:{name}` at the chat and, when the username
+/// changed, keeps the previous one around as an alias for a while so old
+/// links keep working.
+pub fn update_chat_username_index(
+    db: &DBWithThreadMode<MultiThreaded>,
+    chat_id: &str,
+    previous: Option<&ChatMeta>,
+    current: &ChatMeta,
+    time: i64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let previous_username =
+        previous
+            .map(|meta| meta.username())
+            .flatten()
+            .map(|username| normalize_username(&username));
+
+    let current_username =
+        current
+            .username()
+            .map(|username| normalize_username(&username));
+
+    if let Some(ref username) = current_username {
+        db.put(
+            build_chat_by_username_key(username),
+            chat_id,
+        )?;
+
+        db.delete(
+            build_chat_username_alias_key(username),
+        )?;
+    }
+
+    if previous_username == current_username {
+        return Ok(());
+    }
+
+    if let Some(ref username) = previous_username {
+        let username_key = build_chat_by_username_key(username);
+
+        // only demote the old name if nobody else took it in the meantime
+        if db.get(&username_key)?.as_deref() == Some(chat_id.as_bytes()) {
+            db.delete(&username_key)?;
+        }
+
+        db.put(
+            build_chat_username_alias_key(username),
+            serde_json::to_string(
+                &ChatUsernameAlias {
+                    chat_id: chat_id.to_string(),
+                    expires: time + USERNAME_ALIAS_GRACE_PERIOD,
+                },
+            )?,
+        )?;
+    }
+
+    Ok(())
+}
+
+impl From<MessageChat> for ChatMeta {
+    fn from(chat: MessageChat) -> Self {
+        match chat {
+            MessageChat::Private(user) => ChatMeta::User(user.into()),
+            MessageChat::Group(group) => ChatMeta::Group(group.into()),
+            MessageChat::Supergroup(group) => ChatMeta::SuperGroup(group.into()),
+            MessageChat::Unknown(raw_chat) => ChatMeta::Unknown(raw_chat.into()),
+        }
+    }
+}
+
+impl From<&MessageChat> for ChatMeta {
+    fn from(chat: &MessageChat) -> Self {
+        chat.clone().into()
+    }
+}
+
+impl From<Chat> for ChatMeta {
+    fn from(chat: Chat) -> Self {
+        match chat {
+            Chat::Private(user) => ChatMeta::User(user.into()),
+            Chat::Group(group) => ChatMeta::Group(group.into()),
+            Chat::Supergroup(group) => ChatMeta::SuperGroup(group.into()),
+            Chat::Channel(chan) => ChatMeta::Channel(chan.into()),
+            Chat::Unknown(raw_chat) => ChatMeta::Unknown(raw_chat.into()),
+        }
+    }
+}
+
+impl From<Channel> for ChatMeta {
+    fn from(chan: Channel) -> Self {
+        ChatMeta::Channel(chan.into())
+    }
+}
+
+impl From<&Channel> for ChatMeta {
+    fn from(chan: &Channel) -> Self {
+        ChatMeta::Channel(chan.clone().into())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ForwardFromMeta {
+    User {
+        user: UserMeta,
+    },
+    Channel {
+        channel: ChannelMeta,
+        message_id: i64,
+    },
+    ChannelHiddenUser {
+        sender_name: String,
     },
     HiddenGroupAdmin {
         chat_id: String,
@@ -493,7 +1334,119 @@ pub struct InterMessage {
     pub forward: Option<ForwardMeta>,
     pub reply_to_message: Option<Box<InterMessage>>,
     pub edit_date: Option<i64>,
+    #[serde(serialize_with = "serialize_message_kind")]
     pub kind: MessageKind,
+    #[serde(default)]
+    pub via_bot: Option<UserMeta>,
+    #[serde(default)]
+    pub author_signature: Option<String>,
+    // the payload of a kind the fork only has `MessageKind::Unknown` for
+    #[serde(default)]
+    pub untyped_kind: Option<UntypedKind>,
+    // custom emoji ids of the text's entities by their offset, ordered so
+    // that the stored raw message serializes the same every time
+    #[serde(default)]
+    pub custom_emoji_ids: BTreeMap<i64, String>,
+}
+
+// the fork derives `Serialize` for entities, but only reads them back the
+// way telegram sends them. Stored raw messages have to go out that way too,
+// or they don't deserialize for reprocessing
+fn serialize_message_kind<S: serde::Serializer>(
+    kind: &MessageKind,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let mut value = serde_json::to_value(kind).map_err(serde::ser::Error::custom)?;
+
+    raw_entities(&mut value);
+
+    value.serialize(serializer)
+}
+
+fn raw_entities(
+    value: &mut serde_json::Value,
+) {
+    let entity =
+        match value {
+            serde_json::Value::Array(values) => return values.iter_mut().for_each(raw_entities),
+            serde_json::Value::Object(map) => match (map.get("offset"), map.get("length"), map.get("kind")) {
+                (Some(offset), Some(length), Some(kind)) => raw_entity(offset, length, kind),
+                _ => return map.values_mut().for_each(raw_entities),
+            },
+            _ => return,
+        };
+
+    if let Some(entity) = entity {
+        *value = entity;
+    }
+}
+
+fn raw_entity(
+    offset: &serde_json::Value,
+    length: &serde_json::Value,
+    kind: &serde_json::Value,
+) -> Option<serde_json::Value> {
+    let (type_, url, user) =
+        match kind {
+            // unit variants by name, `BotCommand` is `bot_command`
+            serde_json::Value::String(name) => {
+                let type_ =
+                    name.chars()
+                        .enumerate()
+                        .map(|(i, c)|
+                            if c.is_uppercase() && i > 0 {
+                                format!("_{}", c.to_lowercase())
+                            } else {
+                                c.to_lowercase().to_string()
+                            }
+                        )
+                        .collect::<String>();
+
+                (type_, None, None)
+            }
+            serde_json::Value::Object(variant) => {
+                if let Some(url) = variant.get("TextLink") {
+                    ("text_link".to_string(), Some(url.clone()), None)
+                } else if let Some(user) = variant.get("TextMention") {
+                    ("text_mention".to_string(), None, Some(user.clone()))
+                } else {
+                    return variant.get("Unknown").cloned();
+                }
+            }
+            _ => return None,
+        };
+
+    Some(
+        serde_json::json!({
+            "type": type_,
+            "offset": offset,
+            "length": length,
+            "url": url,
+            "user": user,
+        }),
+    )
+}
+
+/// Message kinds the fork doesn't parse, keyed the way telegram sends them.
+pub const UNTYPED_KINDS: &[&str] = &[
+    "dice",
+    "game",
+    "successful_payment",
+    "video_chat_started",
+    "video_chat_ended",
+    "video_chat_participants_invited",
+    // what video chats were called before bot api 5.4
+    "voice_chat_started",
+    "voice_chat_ended",
+    "voice_chat_participants_invited",
+    "message_auto_delete_timer_changed",
+];
+
+/// A message kind from `UNTYPED_KINDS` as it was in the update's json.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UntypedKind {
+    pub key: String,
+    pub payload: serde_json::Value,
 }
 
 impl From<Message> for InterMessage {
@@ -518,7 +1471,82 @@ impl From<Message> for InterMessage {
                 .map(|val| Box::new(val)),
             edit_date: msg.edit_date,
             kind: msg.kind,
+            // the fork doesn't deserialize these, see `with_raw`
+            via_bot: None,
+            author_signature: None,
+            untyped_kind: None,
+            custom_emoji_ids: BTreeMap::new(),
+        }
+    }
+}
+
+impl InterMessage {
+    /// Fills in what the fork drops from the message, read from the
+    /// message's json as it came with the update. Replies are filled from
+    /// the `reply_to_message` in it.
+    pub fn with_raw(
+        self,
+        raw: &serde_json::Value,
+    ) -> Self {
+        let mut msg = self;
+
+        msg.via_bot =
+            raw
+                .get("via_bot")
+                .cloned()
+                .map(|user| serde_json::from_value::<User>(user).ok())
+                .flatten()
+                .map(|user| user.into());
+
+        msg.author_signature =
+            raw
+                .get("author_signature")
+                .map(|signature| signature.as_str())
+                .flatten()
+                .map(|signature| signature.to_string());
+
+        msg.custom_emoji_ids =
+            raw
+                .get("entities")
+                .map(|entities| entities.as_array())
+                .flatten()
+                .map(|entities|
+                    entities
+                        .iter()
+                        .filter(|entity| entity.get("type").map(|type_| type_ == "custom_emoji").unwrap_or(false))
+                        .filter_map(|entity|
+                            Some((
+                                entity.get("offset")?.as_i64()?,
+                                entity.get("custom_emoji_id")?.as_str()?.to_string(),
+                            ))
+                        )
+                        .collect()
+                )
+                .unwrap_or_default();
+
+        if let MessageKind::Unknown { .. } = msg.kind {
+            msg.untyped_kind =
+                UNTYPED_KINDS
+                    .iter()
+                    .find_map(|key|
+                        raw
+                            .get(key)
+                            .map(|payload|
+                                UntypedKind {
+                                    key: key.to_string(),
+                                    payload: payload.clone(),
+                                }
+                            )
+                    );
+        }
+
+        if let Some(reply_raw) = raw.get("reply_to_message") {
+            msg.reply_to_message =
+                msg.reply_to_message
+                    .map(|reply| Box::new(reply.with_raw(reply_raw)));
         }
+
+        msg
     }
 }
 
@@ -551,6 +1579,11 @@ impl From<ChannelPost> for InterMessage {
                 .map(|val| Box::new(val)),
             edit_date: msg.edit_date,
             kind: msg.kind,
+            // the fork doesn't deserialize these, see `with_raw`
+            via_bot: None,
+            author_signature: None,
+            untyped_kind: None,
+            custom_emoji_ids: BTreeMap::new(),
         }
     }
 }
@@ -582,6 +1615,40 @@ pub enum FileEntryType {
     Chat,
     VideoThumb,
     User,
+    // the current photo of a chat, keyed by the chat id
+    ChatPhoto,
+    // scaled down copy of an image in `file:chat:`, same file id
+    Thumb,
+}
+
+impl FileEntryType {
+    /// Where the blobs behind a `/file/{kind}/{id}` url are stored. Images,
+    /// documents and videos all live in `file:chat:`, the kind only decides
+    /// how they're served. Thumbnails and the photos of chat photo changes
+    /// share `file:video_thumb:`.
+    pub fn from_url_kind(
+        kind: &str,
+    ) -> Option<Self> {
+        match kind {
+            "image" | "document" | "video" => Some(FileEntryType::Chat),
+            "video_thumb" => Some(FileEntryType::VideoThumb),
+            "user" => Some(FileEntryType::User),
+            "chat_photo" => Some(FileEntryType::ChatPhoto),
+            "thumb" => Some(FileEntryType::Thumb),
+            _ => None,
+        }
+    }
+}
+
+pub fn build_file_url(
+    kind: &str,
+    file_id: &str,
+) -> String {
+    format!(
+        "/file/{}/{}",
+        kind,
+        file_id,
+    )
 }
 
 pub fn build_file_key(
@@ -592,1145 +1659,5166 @@ pub fn build_file_key(
         FileEntryType::Chat => format!("file:chat:{}", file_id),
         FileEntryType::VideoThumb => format!("file:video_thumb:{}", file_id),
         FileEntryType::User => format!("file:user:{}", file_id),
+        FileEntryType::ChatPhoto => format!("file:chat_photo:{}", file_id),
+        FileEntryType::Thumb => format!("file:thumb:{}", file_id),
     }
 }
 
-pub fn find_biggest_photo(
-    photos: &Vec<PhotoSize>,
-) -> PhotoSize {
-    let mut photo: Option<&PhotoSize> = None;
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FileMeta {
+    pub mime_type: Option<String>,
+    pub size: u64,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub file_name: Option<String>,
+    // path of the file on the telegram file server
+    pub file_path: Option<String>,
+    // key of the log item the file belongs to
+    pub message_key: Option<String>,
+    // when the blob was downloaded, unknown for older files
+    #[serde(default)]
+    pub stored_at: Option<i64>,
+    // hex encoded sha256 of the blob as it was stored
+    #[serde(default)]
+    pub sha256: Option<String>,
+    // bot the file path belongs to, the default one for older files
+    #[serde(default)]
+    pub bot: Option<String>,
+    // EXIF metadata was removed from the blob before storing it
+    #[serde(default)]
+    pub exif_stripped: bool,
+    // dimensions of the `file:thumb:` generated from an image
+    #[serde(default)]
+    pub thumb_width: Option<u32>,
+    #[serde(default)]
+    pub thumb_height: Option<u32>,
+}
 
-    let mut last_size = 0;
+impl FileMeta {
+    /// Builds a record by sniffing the blob, which is all we can do for
+    /// files stored before metadata was recorded.
+    pub fn from_bytes(
+        file: &[u8],
+    ) -> Self {
+        let dimensions = guess_image_dimensions(file);
+
+        FileMeta {
+            mime_type: guess_mime_type(file).map(|mime| mime.to_string()),
+            size: file.len() as u64,
+            width: dimensions.map(|(width, _)| width),
+            height: dimensions.map(|(_, height)| height),
+            sha256: Some(hash_file(file)),
+            ..Default::default()
+        }
+    }
 
-    for size in photos.iter() {
-        if last_size == 0 {
-            photo = Some(size);
-            last_size = size.width * size.height;
+    /// Prefers the mime type reported by telegram over the sniffed one.
+    pub fn with_mime_type(
+        self,
+        mime_type: Option<String>,
+    ) -> Self {
+        let mut meta = self;
 
-            continue;
+        if mime_type.is_some() {
+            meta.mime_type = mime_type;
         }
 
-        if size.width * size.height > last_size {
-            photo = Some(size);
-            last_size = size.width * size.height;
-        }
+        meta
     }
 
-    photo.unwrap().clone()
-}
+    pub fn with_file_name(
+        self,
+        file_name: Option<String>,
+    ) -> Self {
+        let mut meta = self;
 
-pub async fn process_user_profile_picture(
-    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
-    api: &Api,
-    user: &UserMeta,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let user_profile_photos =
-        api.send(
-            GetUserProfilePhotos::new(
-                &user,
-            ),
-        ).await?;
+        meta.file_name = file_name;
 
-    if user_profile_photos.photos.len() == 0 {
-        return Ok(());
+        meta
     }
 
-    if let Some(photo_sizes) = user_profile_photos.photos.first() {
-        let photo =
-            find_biggest_photo(
-                &photo_sizes,
-            );
+    pub fn with_file_path(
+        self,
+        file_path: &str,
+    ) -> Self {
+        let mut meta = self;
 
-        if let Some(file_path) = get_file_path(&api, &photo).await {
-            let file =
-                get_file(
-                    &file_path,
-                ).await?;
+        meta.file_path = Some(file_path.to_string());
 
-            // check image integrity
-            if image::load_from_memory(&file).is_ok() {
-                let db = db.lock().unwrap();
+        meta
+    }
 
-                db.put(
-                    build_file_key(
-                        FileEntryType::User,
-                        &user.id.to_string(),
-                    ),
-                    &file,
-                )?;
-            }
-        }
+    pub fn with_message_key(
+        self,
+        message_key: Option<String>,
+    ) -> Self {
+        let mut meta = self;
+
+        meta.message_key = message_key;
+
+        meta
     }
 
-    Ok(())
-}
+    pub fn with_stored_at(
+        self,
+        stored_at: i64,
+    ) -> Self {
+        let mut meta = self;
 
-pub async fn process_user_meta(
-    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
-    user: &UserMeta,
-) -> Result<UserMeta, Box<dyn std::error::Error>> {
-    let user = user.clone();
+        meta.stored_at = Some(stored_at);
 
-    let user_meta =
-        UserMeta {
-            id: user.id.to_string(),
-            first_name: user.first_name,
-            last_name: user.last_name,
-            username: user.username,
-            is_bot: user.is_bot,
-            language_code: user.language_code,
-        };
+        meta
+    }
 
-    let db = db.lock().unwrap();
+    pub fn with_bot(
+        self,
+        bot: &str,
+    ) -> Self {
+        let mut meta = self;
 
-    db.put(
-        format!("user:meta:{}", user.id),
-        &serde_json::to_string(&user_meta)?,
-    )?;
+        meta.bot = Some(bot.to_string());
 
-    Ok(user_meta)
+        meta
+    }
 }
 
-pub async fn process_user(
-    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
-    api: &Api,
-    user: &UserMeta,
+pub fn build_file_meta_key(
+    file_id: &str,
+) -> String {
+    format!("file:meta:{}", file_id)
+}
+
+pub fn store_file_meta(
+    db: &DBWithThreadMode<MultiThreaded>,
+    file_id: &str,
+    meta: &FileMeta,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    process_user_profile_picture(db.clone(), api, user).await;
-    process_user_meta(db.clone(), user).await;
+    db.put(
+        build_file_meta_key(file_id),
+        &serde_json::to_string(meta)?,
+    )?;
 
     Ok(())
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "lowercase")]
-pub enum LogItemMediaType {
-    Image {
-        width: i64,
-        height: i64,
-    },
-    Video {
-        duration: i64,
-        width: i64,
-        height: i64,
-        thumb_file_id: Option<String>,
-        mime_type: Option<String>,
-    },
-    Audio {
-        duration: i64,
-        performer: Option<String>,
-        title: Option<String>,
-        mime_type: Option<String>,
-    },
-    Voice {
-        duration: i64,
-        mime_type: Option<String>,
-    },
-    VideoNote {
-        duration: i64,
-        thumb_file_id: Option<String>,
-    },
-    Document {
-        file_name: Option<String>,
-        mime_type: Option<String>,
-    },
-    Sticker {
-        emoji: Option<String>,
-        set_name: Option<String>,
-    },
+/// What kind of failure kept a file from being archived. Only the network
+/// and rate limits may go away by themselves, see `is_retryable`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileFailureKind {
+    // over the limit for its kind, see `get_file_size_limits`
+    TooLarge,
+    // over the 20 MB telegram lets bots download
+    TooBigForBots,
+    // a file id telegram doesn't know (anymore), or an expired path
+    InvalidReference,
+    RateLimited,
+    Network,
+    // corrupt downloads, and markers from before failures were told apart
+    Other,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "lowercase")]
-pub enum LogItemMembershipType {
-    Left,
-    Joined,
+impl Default for FileFailureKind {
+    fn default() -> Self {
+        FileFailureKind::Other
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "lowercase")]
-pub struct LogItemSpecialTypeLocation {
-    pub longitude: f32,
-    pub latitude: f32,
+impl FileFailureKind {
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, FileFailureKind::RateLimited | FileFailureKind::Network)
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "lowercase")]
-pub struct LogItemSpecialTypePollOption {
-    pub text: String,
-    pub voter_count: i64,
+/// A failed `getFile` call or download.
+#[derive(Debug)]
+pub struct FileError {
+    pub kind: FileFailureKind,
+    pub message: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "lowercase")]
-pub enum LogItemMessageEntityKind {
-    Mention,
-    Hashtag,
-    BotCommand,
-    Url,
-    Email,
-    Bold,
-    Italic,
-    Code,
-    Pre,
-    TextLink(String),
-    //TextMention(User),
-    TextMention(String),
-    Unknown,
+impl FileError {
+    /// Tells the failure apart by what the bot api said, the fork only has
+    /// it as text.
+    pub fn from_api(
+        message: &str,
+    ) -> Self {
+        let lowercase = message.to_lowercase();
+
+        let kind =
+            if lowercase.contains("file is too big") {
+                FileFailureKind::TooBigForBots
+            } else if lowercase.contains("too many requests") || lowercase.contains("retry after") {
+                FileFailureKind::RateLimited
+            } else if lowercase.contains("file_id") || lowercase.contains("file reference") || lowercase.contains("not found") {
+                FileFailureKind::InvalidReference
+            } else if lowercase.contains("bad request") {
+                FileFailureKind::Other
+            } else {
+                FileFailureKind::Network
+            };
+
+        FileError {
+            kind,
+            message: message.to_string(),
+        }
+    }
+
+    // the url has the bot token in it, it stays out of the stored reason
+    fn from_download(
+        err: reqwest::Error,
+    ) -> Self {
+        let kind =
+            match err.status().map(|status| status.as_u16()) {
+                Some(404) => FileFailureKind::InvalidReference,
+                Some(429) => FileFailureKind::RateLimited,
+                Some(status) if status < 500 => FileFailureKind::Other,
+                _ => FileFailureKind::Network,
+            };
+
+        FileError {
+            kind,
+            message: err.without_url().to_string(),
+        }
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "lowercase")]
-pub struct LogItemSpecialTypePollMessageEntity {
-    pub offset: i64,
-    pub length: i64,
-    pub kind: LogItemMessageEntityKind,
+impl std::fmt::Display for FileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
 }
 
+impl std::error::Error for FileError {}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "lowercase")]
-pub enum LogItemSpecialType {
-    Contact {
-        user_id: Option<i64>,
-        phone_number: String,
-        first_name: String,
-        last_name: Option<String>,
-    },
-    Location {
-        latitude: f32,
-        longitude: f32,
-    },
-    Venue {
-        location: LogItemSpecialTypeLocation,
-        title: String,
-        address: String,
-        foursquare_id: Option<String>,
-    },
-    Poll {
-        id: String,
-        question: String,
-        options: Vec<LogItemSpecialTypePollOption>,
-        total_voter_count: i64,
-        is_closed: bool,
-        is_anonymous: bool,
-        poll_type: PollType,
-        allows_multiple_answers: bool,
-        correct_option_id: Option<i64>,
-        explanation: Option<String>,
-        explanation_entities: Option<Vec<LogItemSpecialTypePollMessageEntity>>,
-        open_period: Option<i64>,
-        close_date: Option<i64>,
-    },
-    PinnnedMessage,
+pub struct FileFailure {
+    pub reason: String,
+    pub time: i64,
+    #[serde(default)]
+    pub kind: FileFailureKind,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "lowercase")]
-pub enum LogItemChatType {
-    NewTitle {
-        title: String,
-    },
-    NewPhoto {
-        file_id: Option<String>,
-    },
-    DeletePhoto,
+pub fn build_file_failure_key(
+    file_id: &str,
+) -> String {
+    format!("file:failed:{}", file_id)
 }
 
+// left by the file verifier on blobs that no longer match what was stored
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct LogItemMessageEntity {
-    pub offset: i64,
-    pub length: i64,
-    pub kind: LogItemMessageEntityKind,
+pub struct FileCorruption {
+    pub reason: String,
+    pub time: i64,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "lowercase")]
-pub enum LogItem {
-    Message {
-        user_id: Option<String>,
-        time: i64,
-        text: String,
-        entities: Vec<LogItemMessageEntity>,
-        source: Option<InterMessage>,
-    },
-    Media {
-        user_id: Option<String>,
-        time: i64,
-        caption: Option<String>,
-        #[serde(rename = "type")]
-        media_type: LogItemMediaType,
-        files: Vec<String>,
-        source: Option<InterMessage>,
-    },
-    Special {
-        user_id: Option<String>,
-        time: i64,
-        #[serde(rename = "type")]
-        special_type: LogItemSpecialType,
-        source: Option<InterMessage>,
-    },
-    Membership {
-        user_id: Option<String>,
-        time: i64,
-        #[serde(rename = "type")]
-        membership_type: LogItemMembershipType,
-        source: Option<InterMessage>,
-    },
-    Chat {
-        user_id: Option<String>,
-        time: i64,
-        #[serde(rename = "type")]
-        chat_type: LogItemChatType,
-        source: Option<InterMessage>,
-    },
-    Pin {
-        user_id: Option<String>,
-        time: i64,
-        message: Option<String>,
-        message_id: String,
-        source: Option<InterMessage>,
-    },
-    Unimplemented(String, Option<String>, i64, Option<InterMessage>),
+pub fn build_file_corrupt_key(
+    file_id: &str,
+) -> String {
+    format!("file:corrupt:{}", file_id)
 }
 
-async fn process_files(
+pub fn store_file_failure(
     db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
-    api: &Api,
-    message: &InterMessage,
-) -> Result<Vec<String>, Box<dyn std::error::Error>> {
-    let file_refs =
-        get_files(
-            db.clone(),
-            api,
-            message,
-        ).await;
-
-    for (file_id, file) in file_refs.iter() {
-        let db = db.lock().unwrap();
-
-        db.put(
-            build_file_key(
-                FileEntryType::Chat,
-                &file_id.to_string(),
-            ),
-            &file,
-        )?;
-    }
+    file_id: &str,
+    reason: &str,
+) {
+    put_file_failure(
+        db,
+        file_id,
+        FileFailure {
+            reason: reason.to_string(),
+            time: chrono::Utc::now().timestamp(),
+            kind: FileFailureKind::Other,
+        },
+    );
+}
 
-    Ok(
-        file_refs
-            .iter()
-            .map(|(file_id, _)|
-                file_id.to_string()
-            )
-            .collect(),
-    )
+/// Marks a file whose `getFile` call or download failed, along with the
+/// kind of failure.
+pub fn store_file_error(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    file_id: &str,
+    err: &FileError,
+) {
+    put_file_failure(
+        db,
+        file_id,
+        FileFailure {
+            reason: format!("download failed: {}", err),
+            time: chrono::Utc::now().timestamp(),
+            kind: err.kind,
+        },
+    );
 }
 
-pub async fn process_photosize(
+/// Marks a file as not archived because of its size, `file_size` is None
+/// when telegram didn't say and the download crossed `max_size`.
+pub fn store_file_too_large(
     db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
-    api: &Api,
-    photo_size: &PhotoSize,
-    file_id: Option<&str>,
-) -> Option<String> {
-    let file_key =
-        build_file_key(
-            FileEntryType::VideoThumb,
-            file_id
-                .unwrap_or(
-                    &photo_size
-                        .file_id
-                        .to_string(),
-                ),
-        );
+    file_id: &str,
+    file_size: Option<i64>,
+    max_size: i64,
+) {
+    let reason =
+        match file_size {
+            Some(file_size) =>
+                format!("too large to archive: {} bytes, the limit is {}", file_size, max_size),
+            None =>
+                format!("too large to archive: more than the limit of {} bytes", max_size),
+        };
 
-    {
-        let db = db.lock().unwrap();
+    put_file_failure(
+        db,
+        file_id,
+        FileFailure {
+            reason,
+            time: chrono::Utc::now().timestamp(),
+            kind: FileFailureKind::TooLarge,
+        },
+    );
+}
 
-        if db.key_may_exist(&file_key) {
-            println!("{} already exists, not trying to fetch", file_key);
+fn put_file_failure(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    file_id: &str,
+    failure: FileFailure,
+) {
+    let db = lock_db(&db);
 
-            return Some(photo_size.file_id.clone());
+    if let Ok(failure) = serde_json::to_string(&failure) {
+        if let Err(err) = db.put(build_file_failure_key(file_id), failure) {
+            dbg!(err);
         }
     }
+}
 
-    if let Some(file_path) = get_file_path(&api, &photo_size).await {
-        let file =
-            match get_file(
-                &file_path,
-            ).await {
-                Ok(file) => file,
-                Err(_) => return None,
-            };
-
-        // check image integrity
-        if image::load_from_memory(&file).is_ok() {
-            let db = db.lock().unwrap();
+/// Mime type and file name telegram reported for the message's attachment.
+pub fn message_file_details(
+    message: &InterMessage,
+) -> (Option<String>, Option<String>) {
+    match message.kind {
+        MessageKind::Audio { ref data, .. } =>
+            (data.mime_type.clone(), None),
+        MessageKind::Voice { ref data, .. } =>
+            (data.mime_type.clone(), None),
+        MessageKind::Document { ref data, .. } =>
+            (data.mime_type.clone(), data.file_name.clone()),
+        MessageKind::Video { ref data, .. } =>
+            (data.mime_type.clone(), None),
+        _ => (None, None),
+    }
+}
 
+pub fn find_biggest_photo(
+    photos: &Vec<PhotoSize>,
+) -> PhotoSize {
+    let mut photo: Option<&PhotoSize> = None;
+
+    let mut last_size = 0;
+
+    for size in photos.iter() {
+        if last_size == 0 {
+            photo = Some(size);
+            last_size = size.width * size.height;
+
+            continue;
+        }
+
+        if size.width * size.height > last_size {
+            photo = Some(size);
+            last_size = size.width * size.height;
+        }
+    }
+
+    photo.unwrap().clone()
+}
+
+// a ready-made thumbnail has to fill what the listing shows, see
+// `THUMBNAIL_SIZE`
+const MIN_PHOTO_THUMB_SIZE: i64 = THUMBNAIL_SIZE as i64 / 2;
+
+/// Which of a photo's sizes, given as (width, height), is stored and which
+/// one is kept as its thumbnail. The largest is stored, the thumbnail is the
+/// smallest that still fills the listing. None for the thumbnail when only
+/// the stored size does.
+pub fn pick_photo_sizes(
+    sizes: &[(i64, i64)],
+) -> Option<(usize, Option<usize>)> {
+    let original =
+        (0..sizes.len())
+            .max_by_key(|i| sizes[*i].0 * sizes[*i].1)?;
+
+    let thumb =
+        (0..sizes.len())
+            .filter(|i| *i != original)
+            .filter(|i| sizes[*i].0.max(sizes[*i].1) >= MIN_PHOTO_THUMB_SIZE)
+            .min_by_key(|i| sizes[*i].0 * sizes[*i].1);
+
+    Some((original, thumb))
+}
+
+pub async fn process_user_profile_picture(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    bot: &Bot,
+    user: &UserMeta,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // users seen by several bots would otherwise have their picture
+    // downloaded and rewritten by each of them, it's left to whichever bot
+    // stored it unless that one hasn't refreshed it in a day
+    let owned_elsewhere =
+        get_file_meta(&*lock_db(&db), &user.id)
+            .filter(|meta| meta.bot.as_deref().map(|name| name != bot.name).unwrap_or(false))
+            .map(|meta| meta.stored_at)
+            .flatten()
+            .map(|stored_at| chrono::Utc::now().timestamp() - stored_at < 86400)
+            .unwrap_or(false);
+
+    if owned_elsewhere {
+        return Ok(());
+    }
+
+    let user_profile_photos =
+        track_api_call(
+            "getUserProfilePhotos",
+            bot.api.send(
+                GetUserProfilePhotos::new(
+                    &user,
+                ),
+            ),
+        ).await?;
+
+    if user_profile_photos.photos.len() == 0 {
+        return Ok(());
+    }
+
+    if let Some(photo_sizes) = user_profile_photos.photos.first() {
+        let photo =
+            find_biggest_photo(
+                &photo_sizes,
+            );
+
+        if let Ok(file_path) = get_file_path(bot, &photo).await {
+            let file =
+                get_file(
+                    bot,
+                    &file_path,
+                ).await?;
+
+            // check image integrity
+            if image::load_from_memory(&file).is_ok() {
+                let meta =
+                    FileMeta::from_bytes(&file)
+                        .with_file_path(&file_path)
+                        .with_stored_at(chrono::Utc::now().timestamp())
+                        .with_bot(&bot.name);
+
+                let mut writes = PendingWrites::new();
+
+                let file_key =
+                    build_file_key(
+                        FileEntryType::User,
+                        &user.id.to_string(),
+                    );
+
+                writes.put_counted(
+                    &file_key,
+                    place_blob(&file_key, &file)?,
+                    &file_counter_keys("user", None),
+                );
+
+                writes.put(
+                    &build_file_meta_key(&user.id),
+                    serde_json::to_string(&meta)?,
+                );
+
+                writes.commit(&lock_db(&db))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub fn build_user_meta_history_key(
+    user_id: &str,
+    time: i64,
+) -> String {
+    format!(
+        "user:meta_history:{}:{}",
+        user_id,
+        time,
+    )
+}
+
+pub async fn process_user_meta(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    user: &UserMeta,
+) -> Result<UserMeta, Box<dyn std::error::Error>> {
+    let user = user.clone();
+
+    let user_meta =
+        UserMeta {
+            id: user.id.to_string(),
+            first_name: user.first_name,
+            last_name: user.last_name,
+            username: user.username,
+            is_bot: user.is_bot,
+            language_code: user.language_code,
+            v: SCHEMA_VERSION,
+        };
+
+    let db = lock_db(&db);
+
+    let user_meta_key = format!("user:meta:{}", user.id);
+
+    // keep the record we're about to replace when the visible name changed,
+    // so old logs can be rendered with the name people had back then
+    if let Some(previous) =
+    db.get(&user_meta_key)?
+        .map(|v| serde_json::from_slice::<UserMeta>(&v).ok())
+        .flatten() {
+        if previous.username != user_meta.username
+            || previous.first_name != user_meta.first_name
+            || previous.last_name != user_meta.last_name {
+            db.put(
+                build_user_meta_history_key(
+                    &user_meta.id,
+                    chrono::Utc::now().timestamp(),
+                ),
+                &to_versioned_string(&previous)?,
+            )?;
+        }
+    }
+
+    db.put(
+        &user_meta_key,
+        &to_versioned_string(&user_meta)?,
+    )?;
+
+    invalidate_user_meta(&user_meta.id);
+
+    Ok(user_meta)
+}
+
+pub async fn process_user(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    bot: &Bot,
+    user: &UserMeta,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // profile pictures cost two extra api calls and a download per message,
+    // while catching up they're queued so the text gets in first
+    // same goes for when telegram is having a bad day
+    let picture =
+        if telegram_metrics().lag_seconds > CATCHUP_LAG_THRESHOLD || api_health().degraded {
+            defer_user_profile_picture(&lock_db(&db), user)
+        } else {
+            process_user_profile_picture(db.clone(), bot, user).await
+        };
+
+    // the name is worth storing even when the picture failed
+    process_user_meta(db.clone(), user).await?;
+
+    picture
+}
+
+pub fn build_deferred_user_photo_key(
+    user_id: &str,
+) -> String {
+    format!(
+        "deferred:user_photo:{}",
+        user_id,
+    )
+}
+
+fn find_deferred_user_photos(
+    db: &DBWithThreadMode<MultiThreaded>,
+) -> Vec<(Box<[u8]>, UserMeta)> {
+    let mut opts = ReadOptions::default();
+
+    opts.set_iterate_lower_bound(b"deferred:user_photo:".to_vec());
+    opts.set_iterate_upper_bound(b"deferred:user_photo:\x7f".to_vec());
+
+    db.iterator_opt(IteratorMode::From(b"deferred:user_photo:", Direction::Forward), opts)
+        .filter_map(|(key, val)|
+            serde_json::from_slice::<UserMeta>(&val)
+                .ok()
+                .map(|user| (key, user))
+        )
+        .collect()
+}
+
+pub fn defer_user_profile_picture(
+    db: &DBWithThreadMode<MultiThreaded>,
+    user: &UserMeta,
+) -> Result<(), Box<dyn std::error::Error>> {
+    db.put(
+        build_deferred_user_photo_key(&user.id),
+        serde_json::to_string(user)?,
+    )?;
+
+    record_deferred_jobs(count_deferred_jobs(db));
+
+    Ok(())
+}
+
+/// `deferred:thumb:{file_id}` marks an image whose thumbnail wasn't
+/// generated yet because it came in during catch-up.
+pub fn build_deferred_thumb_key(
+    file_id: &str,
+) -> String {
+    format!(
+        "deferred:thumb:{}",
+        file_id,
+    )
+}
+
+fn find_deferred_thumbs(
+    db: &DBWithThreadMode<MultiThreaded>,
+) -> Vec<(Box<[u8]>, String)> {
+    prefix_iter(db, "deferred:thumb:")
+        .filter_map(|(key, _)|
+            String::from_utf8(key.to_vec())
+                .ok()
+                .map(|file_id| file_id.trim_start_matches("deferred:thumb:").to_string())
+                .map(|file_id| (key, file_id))
+        )
+        .collect()
+}
+
+fn count_deferred_jobs(
+    db: &DBWithThreadMode<MultiThreaded>,
+) -> u64 {
+    (find_deferred_user_photos(db).len() + find_deferred_thumbs(db).len()) as u64
+}
+
+/// Generates and stores the thumbnail of an image stored in `file:chat:`,
+/// noting its dimensions on the image's meta record. Returns false when
+/// there's no such image or it doesn't decode. The database is only locked
+/// to read and write records, not while blobs are fetched and stored.
+pub fn store_thumbnail(
+    db: &Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    file_id: &str,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let file_key = build_file_key(FileEntryType::Chat, file_id);
+
+    let (stored, meta) = {
+        let dbi = lock_db(&db);
+
+        (dbi.get(&file_key)?, get_file_meta(&*dbi, file_id))
+    };
+
+    let file =
+        match stored {
+            Some(value) => resolve_blob(&file_key, value)?,
+            None => return Ok(false),
+        };
+
+    let (thumbnail, width, height) =
+        match generate_thumbnail(&file) {
+            Some(thumbnail) => thumbnail,
+            None => return Ok(false),
+        };
+
+    let mut meta = meta.unwrap_or_else(|| FileMeta::from_bytes(&file));
+
+    let thumb_key = build_file_key(FileEntryType::Thumb, file_id);
+
+    let blob = place_blob(&thumb_key, &thumbnail)?;
+
+    let dbi = lock_db(&db);
+
+    put_counted(
+        &dbi,
+        &thumb_key,
+        blob,
+        &file_counter_keys("thumb", meta.message_key.as_deref()),
+    )?;
+
+    meta.thumb_width = Some(width);
+    meta.thumb_height = Some(height);
+
+    store_file_meta(&dbi, file_id, &meta)?;
+
+    Ok(true)
+}
+
+/// Works off up to `limit` profile picture downloads and thumbnails
+/// postponed during catch-up.
+pub async fn process_deferred_jobs(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    bot: &Bot,
+    limit: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let deferred = find_deferred_user_photos(&lock_db(&db));
+
+    for (key, user) in deferred.iter().take(limit) {
+        if let Err(err) = process_user_profile_picture(db.clone(), bot, user).await {
+            dbg!(err);
+        }
+
+        lock_db(&db).delete(key)?;
+    }
+
+    // thumbnails get whatever is left of the limit
+    let limit = limit.saturating_sub(deferred.len());
+
+    if limit > 0 {
+        let deferred = find_deferred_thumbs(&lock_db(&db));
+
+        for (key, file_id) in deferred.iter().take(limit) {
+            if let Err(err) = store_thumbnail(&db, file_id) {
+                dbg!(err);
+            }
+
+            lock_db(&db).delete(key)?;
+        }
+    }
+
+    record_deferred_jobs(count_deferred_jobs(&lock_db(&db)));
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogItemMediaType {
+    Image {
+        width: i64,
+        height: i64,
+        // the smaller size telegram sent that's stored as the thumbnail of
+        // the photo (`file:thumb:` under the photo's id), None when that
+        // was generated or there's none
+        #[serde(default)]
+        thumb_file_id: Option<String>,
+    },
+    Video {
+        duration: i64,
+        width: i64,
+        height: i64,
+        thumb_file_id: Option<String>,
+        mime_type: Option<String>,
+    },
+    Audio {
+        duration: i64,
+        performer: Option<String>,
+        title: Option<String>,
+        mime_type: Option<String>,
+    },
+    Voice {
+        duration: i64,
+        mime_type: Option<String>,
+    },
+    VideoNote {
+        duration: i64,
+        thumb_file_id: Option<String>,
+    },
+    // see `is_animation_document`
+    Animation {
+        duration: i64,
+        width: i64,
+        height: i64,
+        thumb_file_id: Option<String>,
+        mime_type: Option<String>,
+    },
+    Document {
+        file_name: Option<String>,
+        mime_type: Option<String>,
+    },
+    Sticker {
+        emoji: Option<String>,
+        set_name: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogItemMembershipType {
+    Left,
+    Joined,
+    // the ones below only come from `chat_member` updates
+    Banned,
+    Unbanned,
+    Promoted,
+    Demoted,
+}
+
+impl LogItemMembershipType {
+    pub fn code(&self) -> &'static str {
+        match self {
+            LogItemMembershipType::Left => "left",
+            LogItemMembershipType::Joined => "joined",
+            LogItemMembershipType::Banned => "banned",
+            LogItemMembershipType::Unbanned => "unbanned",
+            LogItemMembershipType::Promoted => "promoted",
+            LogItemMembershipType::Demoted => "demoted",
+        }
+    }
+}
+
+/// The telegram types only carry `f32` coordinates. Casting those widens the
+/// binary value and stores 52.52000808715820 for 52.520008, going through
+/// the shortest decimal form keeps what telegram sent instead. It's also
+/// what older log items have on disk, they were serialized from the `f32`.
+pub fn widen_coordinate(
+    value: f32,
+) -> f64 {
+    value
+        .to_string()
+        .parse::<f64>()
+        .unwrap_or(value as f64)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub struct LogItemSpecialTypeLocation {
+    pub longitude: f64,
+    pub latitude: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub struct LogItemSpecialTypePollOption {
+    pub text: String,
+    pub voter_count: i64,
+}
+
+/// Serialized lowercase, unit kinds as plain strings (`"bold"`) and the
+/// others as single key objects (`{"textlink": "https://.."}`). Kinds the
+/// fork doesn't know are recognized by their telegram type name: underline,
+/// strikethrough, spoiler, blockquote and customemoji. The custom emoji id
+/// comes from the update's json, see `InterMessage::with_raw`, and is None
+/// where that wasn't at hand; the message text holds the emoji's fallback
+/// character anyway.
+/// Any other kind is kept as `Unknown` with its telegram type name, the
+/// offset and length stay with it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogItemMessageEntityKind {
+    Mention,
+    Hashtag,
+    BotCommand,
+    Url,
+    Email,
+    Bold,
+    Italic,
+    Code,
+    // with the language of the code block, when telegram knows it
+    Pre(Option<String>),
+    TextLink(String),
+    TextMention(LogItemTextMention),
+    Underline,
+    Strikethrough,
+    Spoiler,
+    Blockquote,
+    CustomEmoji(Option<String>),
+    Unknown(String),
+}
+
+/// `Pre` and `Unknown` used to be unit variants stored as a bare `"pre"`
+/// and `"unknown"`, which the derived implementation won't take for what
+/// are newtype variants now. Custom emoji used to be stored with an empty
+/// id rather than none.
+fn deserialize_entity_kind<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<LogItemMessageEntityKind, D::Error> {
+    let value = serde_json::Value::deserialize(deserializer)?;
+
+    if value == "pre" {
+        return Ok(LogItemMessageEntityKind::Pre(None));
+    }
+
+    // the type wasn't kept back then
+    if value == "unknown" {
+        return Ok(LogItemMessageEntityKind::Unknown(String::new()));
+    }
+
+    if value == serde_json::json!({"customemoji": ""}) {
+        return Ok(LogItemMessageEntityKind::CustomEmoji(None));
+    }
+
+    serde_json::from_value(value)
+        .map_err(serde::de::Error::custom)
+}
+
+/// Older records only stored the mentioned user's id as a bare string, newer
+/// ones also carry the name telegram sent along with the mention.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum LogItemTextMention {
+    User {
+        id: String,
+        name: Option<String>,
+    },
+    Id(String),
+}
+
+impl LogItemTextMention {
+    pub fn id(&self) -> &str {
+        match self {
+            LogItemTextMention::User { id, .. } => id,
+            LogItemTextMention::Id(id) => id,
+        }
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            LogItemTextMention::User { name, .. } => name.as_deref(),
+            LogItemTextMention::Id(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub struct LogItemSpecialTypePollMessageEntity {
+    pub offset: i64,
+    pub length: i64,
+    #[serde(deserialize_with = "deserialize_entity_kind")]
+    pub kind: LogItemMessageEntityKind,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogItemSpecialType {
+    Contact {
+        user_id: Option<i64>,
+        phone_number: String,
+        first_name: String,
+        last_name: Option<String>,
+        // the vCard the contact was shared with, when telegram sent one
+        #[serde(default)]
+        vcard: Option<String>,
+    },
+    Location {
+        latitude: f64,
+        longitude: f64,
+        // set once a live location got moved, the item is rewritten with the
+        // latest coordinates on every edit
+        #[serde(default)]
+        live_updated: Option<i64>,
+    },
+    Venue {
+        location: LogItemSpecialTypeLocation,
+        title: String,
+        address: String,
+        foursquare_id: Option<String>,
+    },
+    Poll {
+        id: String,
+        question: String,
+        options: Vec<LogItemSpecialTypePollOption>,
+        total_voter_count: i64,
+        is_closed: bool,
+        is_anonymous: bool,
+        poll_type: PollType,
+        allows_multiple_answers: bool,
+        correct_option_id: Option<i64>,
+        explanation: Option<String>,
+        explanation_entities: Option<Vec<LogItemSpecialTypePollMessageEntity>>,
+        open_period: Option<i64>,
+        close_date: Option<i64>,
+        // when the counts were last refreshed from a poll update, None means
+        // they're still the snapshot taken when the poll was posted
+        #[serde(default)]
+        updated: Option<i64>,
+    },
+    PinnnedMessage,
+    // the fork doesn't parse the kinds below, they're read from the update's
+    // json, see `build_untyped_log_item`
+    Dice {
+        emoji: String,
+        value: i64,
+    },
+    Game {
+        title: String,
+        description: String,
+    },
+    Payment {
+        currency: String,
+        // in the smallest unit of the currency
+        total_amount: i64,
+        invoice_payload: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogItemChatType {
+    NewTitle {
+        title: String,
+    },
+    NewPhoto {
+        file_id: Option<String>,
+    },
+    DeletePhoto,
+    // the fork doesn't parse the service messages below, they're read from
+    // the update's json, see `build_untyped_log_item`
+    VideoChatStarted,
+    VideoChatEnded {
+        duration: i64,
+    },
+    VideoChatInviteSent {
+        users: Vec<String>,
+    },
+    AutoDeleteTimerChanged {
+        seconds: i64,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogItemMessageEntity {
+    pub offset: i64,
+    pub length: i64,
+    #[serde(deserialize_with = "deserialize_entity_kind")]
+    pub kind: LogItemMessageEntityKind,
+}
+
+/// Stored under `chat:{chat_id}:{timestamp}:{message_id}` (see
+/// `MessageSlot`), the timestamp being the message's established date (see
+/// `message_established_date`): when it was originally sent, which for
+/// forwards is before they got here. Days, listing bounds, cursors, exports
+/// and the index are all in terms of that timestamp. `received_at` on the other hand is when the message arrived
+/// in the chat, whatever it was forwarded from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogItem {
+    Message {
+        user_id: Option<String>,
+        time: i64,
+        // when it arrived in the chat, stored before it was kept only as
+        // `time`, see `received_at()`
+        #[serde(default)]
+        received_at: i64,
+        text: String,
+        entities: Vec<LogItemMessageEntity>,
+        #[serde(default)]
+        via_bot: Option<String>,
+        #[serde(default)]
+        author_signature: Option<String>,
+        source: Option<InterMessage>,
+        // the schema version it was written with, see `to_versioned_string`
+        #[serde(default)]
+        v: u32,
+    },
+    Media {
+        user_id: Option<String>,
+        time: i64,
+        #[serde(default)]
+        received_at: i64,
+        caption: Option<String>,
+        #[serde(rename = "type")]
+        media_type: LogItemMediaType,
+        files: Vec<String>,
+        #[serde(default)]
+        via_bot: Option<String>,
+        #[serde(default)]
+        author_signature: Option<String>,
+        source: Option<InterMessage>,
+        #[serde(default)]
+        v: u32,
+    },
+    Special {
+        user_id: Option<String>,
+        time: i64,
+        #[serde(default)]
+        received_at: i64,
+        #[serde(rename = "type")]
+        special_type: LogItemSpecialType,
+        source: Option<InterMessage>,
+        #[serde(default)]
+        v: u32,
+    },
+    Membership {
+        user_id: Option<String>,
+        time: i64,
+        #[serde(default)]
+        received_at: i64,
+        #[serde(rename = "type")]
+        membership_type: LogItemMembershipType,
+        // whoever made the change when it wasn't the member themselves
+        #[serde(default)]
+        admin_id: Option<String>,
+        source: Option<InterMessage>,
+        #[serde(default)]
+        v: u32,
+    },
+    Chat {
+        user_id: Option<String>,
+        time: i64,
+        #[serde(default)]
+        received_at: i64,
+        #[serde(rename = "type")]
+        chat_type: LogItemChatType,
+        source: Option<InterMessage>,
+        #[serde(default)]
+        v: u32,
+    },
+    Pin {
+        user_id: Option<String>,
+        time: i64,
+        #[serde(default)]
+        received_at: i64,
+        message: Option<String>,
+        message_id: String,
+        source: Option<InterMessage>,
+        #[serde(default)]
+        v: u32,
+    },
+    Unimplemented(String, Option<String>, i64, Option<InterMessage>),
+    // left behind in place of a message an admin removed from the archive
+    Redacted {
+        time: i64,
+        redacted_at: i64,
+        #[serde(default)]
+        v: u32,
+    },
+}
+
+impl Versioned for LogItem {
+    // unimplemented items are a tuple with nowhere to put it, they stay at 0
+    fn version(&self) -> u32 {
+        match self {
+            LogItem::Message { v, .. }
+            | LogItem::Media { v, .. }
+            | LogItem::Special { v, .. }
+            | LogItem::Membership { v, .. }
+            | LogItem::Chat { v, .. }
+            | LogItem::Pin { v, .. }
+            | LogItem::Redacted { v, .. } => *v,
+            LogItem::Unimplemented(..) => 0,
+        }
+    }
+
+    fn set_version(&mut self, version: u32) {
+        match self {
+            LogItem::Message { v, .. }
+            | LogItem::Media { v, .. }
+            | LogItem::Special { v, .. }
+            | LogItem::Membership { v, .. }
+            | LogItem::Chat { v, .. }
+            | LogItem::Pin { v, .. }
+            | LogItem::Redacted { v, .. } => *v = version,
+            LogItem::Unimplemented(..) => {}
+        }
+    }
+}
+
+impl LogItem {
+    pub fn user_id(&self) -> Option<&String> {
+        match self {
+            LogItem::Message { user_id, .. }
+            | LogItem::Media { user_id, .. }
+            | LogItem::Special { user_id, .. }
+            | LogItem::Membership { user_id, .. }
+            | LogItem::Chat { user_id, .. }
+            | LogItem::Pin { user_id, .. }
+            | LogItem::Unimplemented(_, user_id, _, _) => user_id.as_ref(),
+            LogItem::Redacted { .. } => None,
+        }
+    }
+
+    /// When the message arrived in the chat, telegram's date of it. Items
+    /// stored before `received_at` was kept have it as their `time`, as do
+    /// unimplemented ones; redacted ones only keep the timestamp they're
+    /// listed under.
+    pub fn received_at(&self) -> i64 {
+        match self {
+            LogItem::Message { time, received_at, .. }
+            | LogItem::Media { time, received_at, .. }
+            | LogItem::Special { time, received_at, .. }
+            | LogItem::Membership { time, received_at, .. }
+            | LogItem::Chat { time, received_at, .. }
+            | LogItem::Pin { time, received_at, .. } =>
+                match *received_at {
+                    0 => *time,
+                    received_at => received_at,
+                },
+            LogItem::Unimplemented(_, _, time, _)
+            | LogItem::Redacted { time, .. } => *time,
+        }
+    }
+
+    /// When a forwarded message was originally sent, None for everything
+    /// else and for items whose raw message is gone.
+    pub fn original_time(&self) -> Option<i64> {
+        self.source()
+            .map(|source| source.forward.as_ref())
+            .flatten()
+            .map(|forward| forward.date)
+    }
+
+    pub fn source(&self) -> Option<&InterMessage> {
+        match self {
+            LogItem::Message { source, .. }
+            | LogItem::Media { source, .. }
+            | LogItem::Special { source, .. }
+            | LogItem::Membership { source, .. }
+            | LogItem::Chat { source, .. }
+            | LogItem::Pin { source, .. }
+            | LogItem::Unimplemented(_, _, _, source) => source.as_ref(),
+            LogItem::Redacted { .. } => None,
+        }
+    }
+
+    /// Ids of the file blobs (`file:chat:` and `file:video_thumb:`) the
+    /// item refers to.
+    pub fn file_ids(&self) -> Vec<String> {
+        self.file_urls()
+            .into_iter()
+            .map(|(file_id, _)| file_id)
+            .collect()
+    }
+
+    /// Every file the log item refers to along with the `/file/...` url
+    /// serving it, which `FileEntryType::from_url_kind` maps back to the key
+    /// the file was written under.
+    pub fn file_urls(&self) -> Vec<(String, String)> {
+        match self {
+            LogItem::Media { files, media_type, .. } => {
+                let kind =
+                    match media_type {
+                        LogItemMediaType::Image { .. }
+                        | LogItemMediaType::Sticker { .. } => "image",
+                        LogItemMediaType::Video { .. }
+                        | LogItemMediaType::VideoNote { .. }
+                        | LogItemMediaType::Animation { .. } => "video",
+                        _ => "document",
+                    };
+
+                let mut file_urls =
+                    files
+                        .iter()
+                        .map(|file_id| (file_id.clone(), build_file_url(kind, file_id)))
+                        .collect::<Vec<(String, String)>>();
+
+                match media_type {
+                    LogItemMediaType::Video { thumb_file_id: Some(thumb), .. }
+                    | LogItemMediaType::VideoNote { thumb_file_id: Some(thumb), .. }
+                    | LogItemMediaType::Animation { thumb_file_id: Some(thumb), .. } =>
+                        file_urls.push((thumb.clone(), build_file_url("video_thumb", thumb))),
+                    _ => {}
+                }
+
+                file_urls
+            }
+            LogItem::Chat { chat_type: LogItemChatType::NewPhoto { file_id: Some(file_id) }, .. } =>
+                vec!((file_id.clone(), build_file_url("video_thumb", file_id))),
+            _ => vec!(),
+        }
+    }
+}
+
+/// Downloads the message's files. The blobs are only queued, they're
+/// written together with the log item referencing them in `handle_message`.
+/// A photo's ready-made thumbnail is stored as the thumbnail of its stored
+/// size, its id is returned along with the others for `build_log_item`.
+async fn process_files(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    bot: &Bot,
+    message: &InterMessage,
+) -> Result<(Vec<String>, PendingWrites), Box<dyn std::error::Error>> {
+    let (file_refs, failed) =
+        get_files(
+            db.clone(),
+            bot,
+            message,
+        ).await;
+
+    let (mime_type, file_name) = message_file_details(message);
+
+    let message_key =
+        build_message_key(
+            &message_chat_id(message),
+            message_slot(message),
+        );
+
+    let mut writes = PendingWrites::new();
+
+    let (thumbs, file_refs): (Vec<_>, Vec<_>) =
+        file_refs
+            .into_iter()
+            .partition(|(_, _, _, thumb_of)| thumb_of.is_some());
+
+    for (file_id, file_path, file, _) in file_refs.iter() {
+        // files that don't decode are stored the way they came
+        let stripped =
+            match get_strip_exif() {
+                true => strip_image_metadata(&file),
+                false => None,
+            };
+
+        let exif_stripped = stripped.is_some();
+
+        let file = stripped.as_deref().unwrap_or(file.as_slice());
+
+        let mut meta =
+            FileMeta::from_bytes(file)
+                .with_mime_type(mime_type.clone())
+                .with_file_name(file_name.clone())
+                .with_file_path(file_path)
+                .with_message_key(Some(message_key.clone()))
+                .with_stored_at(chrono::Utc::now().timestamp())
+                .with_bot(&bot.name);
+
+        meta.exif_stripped = exif_stripped;
+
+        let file_key =
+            build_file_key(
+                FileEntryType::Chat,
+                &file_id.to_string(),
+            );
+
+        writes.put_counted(
+            &file_key,
+            place_blob(&file_key, file)?,
+            &file_counter_keys("chat", Some(&message_key)),
+        );
+
+        let thumb_key = build_file_key(FileEntryType::Thumb, file_id);
+
+        let ready_thumb =
+            thumbs
+                .iter()
+                .find(|(_, _, _, thumb_of)| thumb_of.as_ref() == Some(file_id))
+                .map(|(_, _, thumb, _)| thumb);
+
+        // telegram already scaled the photo down, nothing left to resize
+        if let Some(thumb) = ready_thumb {
+            writes.put_counted(
+                &thumb_key,
+                place_blob(&thumb_key, thumb)?,
+                &file_counter_keys("thumb", Some(&message_key)),
+            );
+
+            let dimensions = guess_image_dimensions(thumb);
+
+            meta.thumb_width = dimensions.map(|(width, _)| width);
+            meta.thumb_height = dimensions.map(|(_, height)| height);
+        } else if telegram_metrics().lag_seconds > CATCHUP_LAG_THRESHOLD {
+            // resizing dozens of images holds up catching up, those get
+            // their thumbnails once it's done
+            if image::guess_format(file).is_ok() {
+                writes.put(&build_deferred_thumb_key(file_id), "");
+
+                // recounted exactly when the deferred jobs are worked off
+                record_deferred_jobs(telegram_metrics().deferred_jobs + 1);
+            }
+        } else if let Some((thumbnail, width, height)) = generate_thumbnail(file) {
+            writes.put_counted(
+                &thumb_key,
+                place_blob(&thumb_key, &thumbnail)?,
+                &file_counter_keys("thumb", Some(&message_key)),
+            );
+
+            meta.thumb_width = Some(width);
+            meta.thumb_height = Some(height);
+        }
+
+        writes.put(
+            &build_file_meta_key(file_id),
+            serde_json::to_string(&meta)?,
+        );
+    }
+
+    Ok(
+        (
+            // files that weren't stored go last, their failure marker says why
+            file_refs
+                .iter()
+                .chain(thumbs.iter())
+                .map(|(file_id, _, _, _)|
+                    file_id.to_string()
+                )
+                .chain(failed)
+                .collect(),
+            writes,
+        ),
+    )
+}
+
+/// Downloads a thumbnail or chat photo and queues it on `writes`, returning
+/// its file id when it's (going to be) stored. Both go to `file:video_thumb:`,
+/// see `FileEntryType::from_url_kind`.
+pub async fn process_photosize(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    bot: &Bot,
+    photo_size: &PhotoSize,
+    file_id: Option<&str>,
+    message_key: Option<String>,
+    writes: &mut PendingWrites,
+) -> Option<String> {
+    let file_key =
+        build_file_key(
+            FileEntryType::VideoThumb,
+            file_id
+                .unwrap_or(
+                    &photo_size
+                        .file_id
+                        .to_string(),
+                ),
+        );
+
+    {
+        let db = lock_db(&db);
+
+        if db.key_may_exist(&file_key) {
+            println!("{} already exists, not trying to fetch", file_key);
+
+            return Some(photo_size.file_id.clone());
+        }
+    }
+
+    // the message gets stored without it rather than waiting on a failing api
+    if api_health().degraded {
+        return None;
+    }
+
+    if let Ok(file_path) = get_file_path(bot, &photo_size).await {
+        let file =
+            match get_file(
+                bot,
+                &file_path,
+            ).await {
+                Ok(file) => file,
+                Err(err) => {
+                    store_file_error(
+                        db.clone(),
+                        file_id.unwrap_or(&photo_size.file_id),
+                        &err,
+                    );
+
+                    return None;
+                }
+            };
+
+        // check image integrity
+        if image::load_from_memory(&file).is_ok() {
+            let counter_keys = file_counter_keys("video_thumb", message_key.as_deref());
+
+            let meta =
+                FileMeta::from_bytes(&file)
+                    .with_file_path(&file_path)
+                    .with_message_key(message_key)
+                    .with_stored_at(chrono::Utc::now().timestamp())
+                    .with_bot(&bot.name);
+
+            let blob =
+                match place_blob(&file_key, &file) {
+                    Ok(blob) => blob,
+                    Err(err) => {
+                        store_file_failure(
+                            db.clone(),
+                            file_id.unwrap_or(&photo_size.file_id),
+                            &format!("can't store: {}", err),
+                        );
+
+                        return None;
+                    }
+                };
+
+            writes.put_counted(
+                &file_key,
+                blob,
+                &counter_keys,
+            );
+
+            writes.put(
+                &build_file_meta_key(file_id.unwrap_or(&photo_size.file_id)),
+                serde_json::to_string(&meta).ok()?,
+            );
+
+            Some(photo_size.file_id.clone())
+        } else {
+            store_file_failure(
+                db.clone(),
+                file_id.unwrap_or(&photo_size.file_id),
+                "downloaded image is corrupt",
+            );
+
+            None
+        }
+    } else {
+        None
+    }
+}
+
+/// Copies a freshly stored chat photo to the chat's stable
+/// `file:chat_photo:{chat_id}` key, which always holds the current one. Like
+/// profile pictures it's counted as a file of its own kind, not one of the
+/// chat's.
+fn store_current_chat_photo(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    chat_id: &str,
+    file_id: &str,
+    writes: &mut PendingWrites,
+) {
+    let photo_key = build_file_key(FileEntryType::VideoThumb, file_id);
+
+    // queued right now, or stored earlier when the photo was seen before
+    let photo =
+        match writes.value(&photo_key) {
+            Some(photo) => Some(photo.to_vec()),
+            None => lock_db(&db).get(&photo_key).ok().flatten(),
+        };
+
+    let chat_photo_key = build_file_key(FileEntryType::ChatPhoto, chat_id);
+
+    // a blob kept outside of the database is copied, not its pointer
+    let photo =
+        photo.map(|photo|
+            resolve_blob(&photo_key, photo)
+                .and_then(|photo| place_blob(&chat_photo_key, &photo))
+        );
+
+    match photo {
+        Some(Ok(photo)) =>
+            writes.put_counted(
+                &chat_photo_key,
+                &photo,
+                &file_counter_keys("chat_photo", None),
+            ),
+        Some(Err(err)) => {
+            dbg!(err);
+        }
+        None => {}
+    }
+}
+
+pub fn map_entity_kind(
+    kind: &MessageEntityKind,
+) -> LogItemMessageEntityKind {
+    match kind {
+        MessageEntityKind::Mention =>
+            LogItemMessageEntityKind::Mention,
+        MessageEntityKind::Hashtag =>
+            LogItemMessageEntityKind::Hashtag,
+        MessageEntityKind::BotCommand =>
+            LogItemMessageEntityKind::BotCommand,
+        MessageEntityKind::Url =>
+            LogItemMessageEntityKind::Url,
+        MessageEntityKind::Email =>
+            LogItemMessageEntityKind::Email,
+        MessageEntityKind::Bold =>
+            LogItemMessageEntityKind::Bold,
+        MessageEntityKind::Italic =>
+            LogItemMessageEntityKind::Italic,
+        MessageEntityKind::Code =>
+            LogItemMessageEntityKind::Code,
+        // the fork doesn't pass the language along
+        MessageEntityKind::Pre =>
+            LogItemMessageEntityKind::Pre(None),
+        MessageEntityKind::TextLink(v) =>
+            LogItemMessageEntityKind::TextLink(v.clone()),
+        MessageEntityKind::TextMention(v) =>
+            LogItemMessageEntityKind::TextMention(
+                LogItemTextMention::User {
+                    id: v.id.to_string(),
+                    name: Some(resolve_user_meta(&v.into())),
+                },
+            ),
+        MessageEntityKind::Unknown(raw) =>
+            match raw.type_.as_str() {
+                "underline" => LogItemMessageEntityKind::Underline,
+                "strikethrough" => LogItemMessageEntityKind::Strikethrough,
+                "spoiler" => LogItemMessageEntityKind::Spoiler,
+                "blockquote" | "expandable_blockquote" => LogItemMessageEntityKind::Blockquote,
+                // the id is filled in from the update's json, if at hand
+                "custom_emoji" => LogItemMessageEntityKind::CustomEmoji(None),
+                type_ => LogItemMessageEntityKind::Unknown(type_.to_string()),
+            },
+    }
+}
+
+/// Maps a message to the log item stored for it. Thumbnails and chat photos
+/// it downloads are queued on `writes`, to be committed along with the item.
+/// Without a bot nothing is downloaded or written on the side (thumbnails,
+/// mentioned users' meta), which is what reprocessing stored messages relies
+/// on.
+pub async fn build_log_item(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    bot: Option<&Bot>,
+    message: &InterMessage,
+    files: &Vec<String>,
+    writes: &mut PendingWrites,
+) -> LogItem {
+    let msg_from_id =
+        message
+            .from
+            .as_ref()
+            .map(|from|
+                     from.id
+                         .to_string()
+                         .clone(),
+            );
+
+    let msg_via_bot =
+        message
+            .via_bot
+            .as_ref()
+            .map(|bot| bot.id.clone());
+
+    let msg_author_signature = message.author_signature.clone();
+
+    let message_key =
+        build_message_key(
+            &message_chat_id(message),
+            message_slot(message),
+        );
+
+    match message.kind {
+        MessageKind::Text {
+            ref data,
+            ref entities,
+        } => {
+            // a text mention may be the only time we ever see this user
+            for entity in entities.iter().filter(|_| bot.is_some()) {
+                if let MessageEntityKind::TextMention(ref user) = entity.kind {
+                    if let Err(err) = process_user_meta(db.clone(), &UserMeta::from(user)).await {
+                        dbg!(err);
+                    }
+                }
+            }
+
+            LogItem::Message {
+                user_id: msg_from_id,
+                time: message.date,
+                received_at: message.date,
+                text: data.clone(),
+                entities:
+                entities
+                    .clone()
+                    .iter()
+                    .map(|entity|
+                        LogItemMessageEntity {
+                            offset: entity.offset,
+                            length: entity.length,
+                            kind:
+                            match map_entity_kind(&entity.kind) {
+                                LogItemMessageEntityKind::CustomEmoji(None) =>
+                                    LogItemMessageEntityKind::CustomEmoji(
+                                        message.custom_emoji_ids.get(&entity.offset).cloned(),
+                                    ),
+                                kind => kind,
+                            },
+                        }
+                    )
+                    .collect(),
+                via_bot: msg_via_bot,
+                author_signature: msg_author_signature,
+                source: Some(message.clone()),
+                v: SCHEMA_VERSION,
+            }
+        }
+
+        MessageKind::Audio { ref data } => {
+            LogItem::Media {
+                user_id: msg_from_id,
+                time: message.date,
+                received_at: message.date,
+                caption: None,
+                media_type:
+                LogItemMediaType::Audio {
+                    duration: data.duration,
+                    performer: data.performer.clone(),
+                    title: data.title.clone(),
+                    mime_type: data.mime_type.clone(),
+                },
+                files: files.clone(),
+                via_bot: msg_via_bot,
+                author_signature: msg_author_signature,
+                source: Some(message.clone()),
+                v: SCHEMA_VERSION,
+            }
+        }
+
+        MessageKind::Document {
+            ref data,
+            ref caption,
+        } if is_animation_document(data.mime_type.as_deref()) => {
+            // the poster frame, so listings don't load every animation
+            let thumb_file_id =
+                match (&data.thumb, bot) {
+                    (Some(thumb), Some(bot)) => {
+                        process_photosize(
+                            db.clone(),
+                            bot,
+                            thumb,
+                            None,
+                            Some(message_key.clone()),
+                            writes,
+                        ).await
+                    }
+                    _ => None,
+                };
+
+            LogItem::Media {
+                user_id: msg_from_id,
+                time: message.date,
+                received_at: message.date,
+                caption: (*caption).clone(),
+                media_type:
+                LogItemMediaType::Animation {
+                    // only telegram's animation object has these, the
+                    // document the fork parses doesn't
+                    duration: 0,
+                    width: 0,
+                    height: 0,
+                    thumb_file_id,
+                    mime_type: data.mime_type.clone(),
+                },
+                files: files.clone(),
+                via_bot: msg_via_bot,
+                author_signature: msg_author_signature,
+                source: Some(message.clone()),
+                v: SCHEMA_VERSION,
+            }
+        }
+
+        MessageKind::Document {
+            ref data,
+            ref caption,
+        } => {
+            LogItem::Media {
+                user_id: msg_from_id,
+                time: message.date,
+                received_at: message.date,
+                caption: (*caption).clone(),
+                media_type:
+                LogItemMediaType::Document {
+                    file_name: data.file_name.clone(),
+                    mime_type: data.mime_type.clone(),
+                },
+                files: files.clone(),
+                via_bot: msg_via_bot,
+                author_signature: msg_author_signature,
+                source: Some(message.clone()),
+                v: SCHEMA_VERSION,
+            }
+        }
+
+        MessageKind::Photo {
+            ref data,
+            ref caption,
+            ..
+        } => {
+            // `files` has the stored size and the one kept as its thumbnail,
+            // see `process_files`
+            let stored =
+                data
+                    .iter()
+                    .filter(|size| files.contains(&size.file_id))
+                    .collect::<Vec<&PhotoSize>>();
+
+            let photo =
+                stored
+                    .iter()
+                    .max_by_key(|size| size.width * size.height)
+                    .map(|size| (*size).clone())
+                    .unwrap_or_else(||
+                        find_biggest_photo(
+                            &data.clone(),
+                        )
+                    );
+
+            let thumb_file_id =
+                stored
+                    .iter()
+                    .find(|size| size.file_id != photo.file_id)
+                    .map(|size| size.file_id.clone());
+
+            LogItem::Media {
+                user_id: msg_from_id,
+                time: message.date,
+                received_at: message.date,
+                caption: (*caption).clone(),
+                media_type:
+                LogItemMediaType::Image {
+                    width: photo.width,
+                    height: photo.height,
+                    thumb_file_id: thumb_file_id.clone(),
+                },
+                files:
+                files
+                    .iter()
+                    .filter(|file_id| Some(*file_id) != thumb_file_id.as_ref())
+                    .cloned()
+                    .collect(),
+                via_bot: msg_via_bot,
+                author_signature: msg_author_signature,
+                source: Some(message.clone()),
+                v: SCHEMA_VERSION,
+            }
+        }
+
+        MessageKind::Sticker {
+            ref data,
+        } => {
+            // the title is fetched in the background, the listing shows the
+            // set's name until it's there
+            if let (Some(set_name), Some(_)) = (&data.set_name, bot) {
+                if let Err(err) = queue_sticker_set(&lock_db(&db), set_name) {
+                    dbg!(err);
+                }
+            }
+
+            LogItem::Media {
+                user_id: msg_from_id,
+                time: message.date,
+                received_at: message.date,
+                caption: None,
+                media_type:
+                LogItemMediaType::Sticker {
+                    emoji: data.emoji.clone(),
+                    set_name: data.set_name.clone(),
+                },
+                files: files.clone(),
+                via_bot: msg_via_bot,
+                author_signature: msg_author_signature,
+                source: Some(message.clone()),
+                v: SCHEMA_VERSION,
+            }
+        }
+
+        MessageKind::Video {
+            ref data,
+            ref caption,
+            ..
+        } => {
+            let thumb_file_id =
+                match (&data.thumb, bot) {
+                    (Some(thumb), Some(bot)) => {
+                        process_photosize(
+                            db.clone(),
+                            bot,
+                            thumb,
+                            None,
+                            Some(message_key.clone()),
+                            writes,
+                        ).await
+                    }
+                    _ => None,
+                };
+
+            LogItem::Media {
+                user_id: msg_from_id,
+                time: message.date,
+                received_at: message.date,
+                caption: (*caption).clone(),
+                media_type:
+                LogItemMediaType::Video {
+                    duration: data.duration,
+                    width: data.width,
+                    height: data.height,
+                    thumb_file_id,
+                    mime_type: data.mime_type.clone(),
+                },
+                files: files.clone(),
+                via_bot: msg_via_bot,
+                author_signature: msg_author_signature,
+                source: Some(message.clone()),
+                v: SCHEMA_VERSION,
+            }
+        }
+
+        MessageKind::Voice {
+            ref data,
+        } => {
+            LogItem::Media {
+                user_id: msg_from_id,
+                time: message.date,
+                received_at: message.date,
+                caption: None,
+                media_type:
+                LogItemMediaType::Voice {
+                    duration: data.duration,
+                    mime_type: data.mime_type.clone(),
+                },
+                files: files.clone(),
+                via_bot: msg_via_bot,
+                author_signature: msg_author_signature,
+                source: Some(message.clone()),
+                v: SCHEMA_VERSION,
+            }
+        }
+
+        MessageKind::VideoNote {
+            ref data,
+        } => {
+            let thumb_file_id =
+                match (&data.thumb, bot) {
+                    (Some(thumb), Some(bot)) => {
+                        process_photosize(
+                            db.clone(),
+                            bot,
+                            thumb,
+                            None,
+                            Some(message_key.clone()),
+                            writes,
+                        ).await
+                    }
+                    _ => None,
+                };
+
+            LogItem::Media {
+                user_id: msg_from_id,
+                time: message.date,
+                received_at: message.date,
+                caption: None,
+                media_type:
+                LogItemMediaType::VideoNote {
+                    duration: data.duration,
+                    thumb_file_id,
+                },
+                files: files.clone(),
+                via_bot: msg_via_bot,
+                author_signature: msg_author_signature,
+                source: Some(message.clone()),
+                v: SCHEMA_VERSION,
+            }
+        }
+
+        MessageKind::Contact {
+            ref data,
+        } => {
+            LogItem::Special {
+                user_id: msg_from_id,
+                time: message.date,
+                received_at: message.date,
+                special_type:
+                LogItemSpecialType::Contact {
+                    user_id: data.user_id.clone(),
+                    phone_number: data.phone_number.clone(),
+                    first_name: data.first_name.clone(),
+                    last_name: data.last_name.clone(),
+                    // the fork's Contact doesn't expose telegram's vcard
+                    // field, downloads fall back to a card built from the
+                    // fields above
+                    vcard: None,
+                },
+                source: Some(message.clone()),
+                v: SCHEMA_VERSION,
+            }
+        }
+
+        MessageKind::Location {
+            ref data,
+        } => {
+            LogItem::Special {
+                user_id: msg_from_id,
+                time: message.date,
+                received_at: message.date,
+                special_type:
+                LogItemSpecialType::Location {
+                    latitude: widen_coordinate(data.latitude),
+                    longitude: widen_coordinate(data.longitude),
+                    live_updated: message.edit_date,
+                },
+                source: Some(message.clone()),
+                v: SCHEMA_VERSION,
+            }
+        }
+
+        MessageKind::Poll {
+            ref data,
+        } => {
+            LogItem::Special {
+                user_id: msg_from_id,
+                time: message.date,
+                received_at: message.date,
+                special_type:
+                LogItemSpecialType::Poll {
+                    id: data.id.clone(),
+                    question: data.question.clone(),
+                    options:
+                    data.options
+                        .iter()
+                        .map(|option|
+                            LogItemSpecialTypePollOption {
+                                text: option.text.clone(),
+                                voter_count: option.voter_count,
+                            }
+                        )
+                        .collect(),
+                    total_voter_count: data.total_voter_count,
+                    is_closed: data.is_closed,
+                    is_anonymous: data.is_anonymous,
+                    poll_type: data.type_.clone(),
+                    allows_multiple_answers: data.allows_multiple_answers,
+                    correct_option_id: data.correct_option_id.clone(),
+                    explanation: data.explanation.clone(),
+                    explanation_entities:
+                    data.explanation_entities
+                        .clone()
+                        .map(|entities|
+                            entities
+                                .iter()
+                                .map(|entity|
+                                    LogItemSpecialTypePollMessageEntity {
+                                        offset: entity.offset,
+                                        length: entity.length,
+                                        kind: map_entity_kind(&entity.kind),
+                                    }
+                                )
+                                .collect::<Vec<LogItemSpecialTypePollMessageEntity>>()
+                        ),
+                    open_period: data.open_period.clone(),
+                    close_date: data.close_date.clone(),
+                    updated: None,
+                },
+                source: Some(message.clone()),
+                v: SCHEMA_VERSION,
+            }
+        }
+
+        MessageKind::Venue {
+            ref data,
+        } => {
+            LogItem::Special {
+                user_id: msg_from_id,
+                time: message.date,
+                received_at: message.date,
+                special_type:
+                LogItemSpecialType::Venue {
+                    location:
+                    LogItemSpecialTypeLocation {
+                        longitude: widen_coordinate(data.location.longitude),
+                        latitude: widen_coordinate(data.location.latitude),
+                    },
+                    title: data.title.clone(),
+                    address: data.address.clone(),
+                    foursquare_id: data.foursquare_id.clone(),
+                },
+                source: Some(message.clone()),
+                v: SCHEMA_VERSION,
+            }
+        }
+
+        MessageKind::NewChatMembers { .. } => {
+            LogItem::Membership {
+                user_id: msg_from_id,
+                time: message.date,
+                received_at: message.date,
+                membership_type: LogItemMembershipType::Joined,
+                admin_id: None,
+                source: Some(message.clone()),
+                v: SCHEMA_VERSION,
+            }
+        }
+
+        MessageKind::LeftChatMember { .. } => {
+            LogItem::Membership {
+                user_id: msg_from_id,
+                time: message.date,
+                received_at: message.date,
+                membership_type: LogItemMembershipType::Left,
+                admin_id: None,
+                source: Some(message.clone()),
+                v: SCHEMA_VERSION,
+            }
+        }
+
+        MessageKind::NewChatTitle {
+            ref data,
+        } => {
+            LogItem::Chat {
+                user_id: msg_from_id,
+                time: message.date,
+                received_at: message.date,
+                chat_type:
+                LogItemChatType::NewTitle {
+                    title: data.clone(),
+                },
+                source: Some(message.clone()),
+                v: SCHEMA_VERSION,
+            }
+        }
+
+        MessageKind::NewChatPhoto {
+            ref data,
+        } => {
+            let photo_size =
+                find_biggest_photo(
+                    data,
+                );
+
+            let photo =
+                match bot {
+                    Some(bot) =>
+                        process_photosize(
+                            db.clone(),
+                            bot,
+                            &photo_size,
+                            None,
+                            Some(message_key.clone()),
+                            writes,
+                        ).await,
+                    None => None,
+                };
+
+            if let (Some(_), Some(file_id)) = (bot, photo.as_ref()) {
+                store_current_chat_photo(
+                    db.clone(),
+                    &message_chat_id(message),
+                    file_id,
+                    writes,
+                );
+            }
+
+            LogItem::Chat {
+                user_id: msg_from_id,
+                time: message.date,
+                received_at: message.date,
+                chat_type:
+                LogItemChatType::NewPhoto {
+                    file_id: photo.as_ref().map(|p| p.clone()),
+                },
+                source: Some(message.clone()),
+                v: SCHEMA_VERSION,
+            }
+        }
+
+        MessageKind::DeleteChatPhoto => {
+            if bot.is_some() {
+                let key = build_file_key(FileEntryType::ChatPhoto, &message_chat_id(message));
+
+                let deleted =
+                    delete_blob_pointer(
+                        &lock_db(&db),
+                        &key,
+                        &file_counter_keys("chat_photo", None),
+                    );
+
+                // a photo kept outside of the database is deleted after
+                // letting go of the lock
+                let deleted =
+                    match deleted {
+                        Ok(Some(backend)) => delete_external_blob(backend, &key),
+                        Ok(None) => Ok(()),
+                        Err(err) => Err(err),
+                    };
+
+                if let Err(err) = deleted {
+                    dbg!(err);
+                }
+            }
+
+            LogItem::Chat {
+                user_id: msg_from_id,
+                time: message.date,
+                received_at: message.date,
+                chat_type: LogItemChatType::DeletePhoto,
+                source: Some(message.clone()),
+                v: SCHEMA_VERSION,
+            }
+        }
+
+        MessageKind::PinnedMessage {
+            ref data,
+        } => {
+            LogItem::Pin {
+                user_id: msg_from_id,
+                time: message.date,
+                received_at: message.date,
+                message: data.text(),
+                message_id: data.to_message_id().to_string(),
+                source: Some(message.clone()),
+                v: SCHEMA_VERSION,
+            }
+        }
+        MessageKind::GroupChatCreated => {
+            LogItem::Unimplemented(
+                "GroupChatCreated".to_string(),
+                msg_from_id,
+                message.date,
+                Some(message.clone()),
+            )
+        }
+
+        MessageKind::SupergroupChatCreated => {
+            LogItem::Unimplemented(
+                "SupergroupChatCreated".to_string(),
+                msg_from_id,
+                message.date,
+                Some(message.clone()),
+            )
+        }
+
+        MessageKind::ChannelChatCreated => {
+            LogItem::Unimplemented(
+                "ChannelChatCreated".to_string(),
+                msg_from_id,
+                message.date,
+                Some(message.clone()),
+            )
+        }
+
+        MessageKind::MigrateToChatId { .. } => {
+            LogItem::Unimplemented(
+                "MigrateToChatId".to_string(),
+                msg_from_id,
+                message.date,
+                Some(message.clone()),
+            )
+        }
+
+        MessageKind::MigrateFromChatId { .. } => {
+            LogItem::Unimplemented(
+                "MigrateFromChatId".to_string(),
+                msg_from_id,
+                message.date,
+                Some(message.clone()),
+            )
+        }
+
+        MessageKind::Unknown { .. } => {
+            // so the listing has names for them
+            if let (Some(_), Some(untyped_kind)) = (bot, message.untyped_kind.as_ref()) {
+                for user in invited_users(untyped_kind) {
+                    if let Err(err) = process_user_meta(db.clone(), &UserMeta::from(user)).await {
+                        dbg!(err);
+                    }
+                }
+            }
+
+            if let Some(log_item) = build_untyped_log_item(message, msg_from_id.clone()) {
+                return log_item;
+            }
+
+            LogItem::Unimplemented(
+                "Unknown".to_string(),
+                msg_from_id,
+                message.date,
+                Some(message.clone()),
+            )
+        }
+    }
+}
+
+// the users a video chat invite went to, none for other kinds
+fn invited_users(
+    untyped_kind: &UntypedKind,
+) -> Vec<User> {
+    untyped_kind.payload
+        .get("users")
+        .cloned()
+        .map(|users| serde_json::from_value::<Vec<User>>(users).ok())
+        .flatten()
+        .filter(|_| untyped_kind.key.ends_with("_chat_participants_invited"))
+        .unwrap_or_default()
+}
+
+/// Maps the kinds in `UNTYPED_KINDS`, None when the payload isn't what
+/// telegram documents for them.
+fn build_untyped_log_item(
+    message: &InterMessage,
+    msg_from_id: Option<String>,
+) -> Option<LogItem> {
+    let untyped_kind = message.untyped_kind.as_ref()?;
+    let payload = &untyped_kind.payload;
+
+    let str_field = |field: &str| -> Option<String> {
+        payload
+            .get(field)
+            .map(|value| value.as_str())
+            .flatten()
+            .map(|value| value.to_string())
+    };
+
+    let int_field = |field: &str| -> Option<i64> {
+        payload
+            .get(field)
+            .map(|value| value.as_i64())
+            .flatten()
+    };
+
+    let chat_type =
+        match untyped_kind.key.as_str() {
+            "video_chat_started" | "voice_chat_started" =>
+                Some(LogItemChatType::VideoChatStarted),
+            "video_chat_ended" | "voice_chat_ended" =>
+                Some(LogItemChatType::VideoChatEnded {
+                    duration: int_field("duration")?,
+                }),
+            "video_chat_participants_invited" | "voice_chat_participants_invited" =>
+                Some(LogItemChatType::VideoChatInviteSent {
+                    users:
+                    invited_users(untyped_kind)
+                        .iter()
+                        .map(|user| user.id.to_string())
+                        .collect(),
+                }),
+            "message_auto_delete_timer_changed" =>
+                Some(LogItemChatType::AutoDeleteTimerChanged {
+                    seconds: int_field("message_auto_delete_time")?,
+                }),
+            _ => None,
+        };
+
+    if let Some(chat_type) = chat_type {
+        return Some(
+            LogItem::Chat {
+                user_id: msg_from_id,
+                time: message.date,
+                received_at: message.date,
+                chat_type,
+                source: Some(message.clone()),
+                v: SCHEMA_VERSION,
+            },
+        );
+    }
+
+    let special_type =
+        match untyped_kind.key.as_str() {
+            "dice" =>
+                LogItemSpecialType::Dice {
+                    emoji: str_field("emoji")?,
+                    value: int_field("value")?,
+                },
+            "game" =>
+                LogItemSpecialType::Game {
+                    title: str_field("title")?,
+                    description: str_field("description").unwrap_or_default(),
+                },
+            "successful_payment" =>
+                LogItemSpecialType::Payment {
+                    currency: str_field("currency")?,
+                    total_amount: int_field("total_amount")?,
+                    invoice_payload: str_field("invoice_payload").unwrap_or_default(),
+                },
+            _ => return None,
+        };
+
+    Some(
+        LogItem::Special {
+            user_id: msg_from_id,
+            time: message.date,
+            received_at: message.date,
+            special_type,
+            source: Some(message.clone()),
+            v: SCHEMA_VERSION,
+        },
+    )
+}
+
+/// Forwarded messages are filed under the time they were originally sent.
+pub fn message_established_date(
+    message: &InterMessage,
+) -> i64 {
+    message
+        .forward
+        .as_ref()
+        .map(|original_message|
+                 original_message
+                     .date,
+        )
+        .unwrap_or(
+            message
+                .date,
+        )
+}
+
+/// Messages forwarded into private chats are filed under the chat they
+/// were forwarded from.
+pub fn uses_forwarded_chat(
+    message: &InterMessage,
+) -> bool {
+    match &message.chat {
+        ChatMeta::Group(_)
+        | ChatMeta::SuperGroup(_)
+        | ChatMeta::Channel(_) => false,
+        ChatMeta::User(_)
+        | ChatMeta::Unknown(_) => true,
+    }
+}
+
+pub fn message_chat_id(
+    message: &InterMessage,
+) -> String {
+    let use_forwarded_chat = uses_forwarded_chat(message);
+
+    message
+        .forward
+        .as_ref()
+        .map(|original_message| {
+            if !use_forwarded_chat {
+                return None;
+            }
+
+            match original_message.from {
+                ForwardFromMeta::User { ref user } => Some(user.id.clone()),
+                ForwardFromMeta::Channel { ref channel, .. } => Some(channel.id.clone()),
+                ForwardFromMeta::ChannelHiddenUser { .. } => None,
+                ForwardFromMeta::HiddenGroupAdmin { ref chat_id, .. } => Some(chat_id.clone()),
+            }
+        })
+        .flatten()
+        .unwrap_or(
+            message
+                .chat
+                .id(),
+        )
+}
+
+/// Holds when the newest message across all chats arrived.
+pub const LAST_MESSAGE_AT_KEY: &str = "state:last_message_at";
+
+pub fn get_last_message_at(
+    db: &impl ReadStore,
+) -> Option<i64> {
+    db.get(LAST_MESSAGE_AT_KEY)
+        .ok()
+        .flatten()
+        .map(|time| String::from_utf8(time).ok())
+        .flatten()
+        .map(|time| time.parse::<i64>().ok())
+        .flatten()
+}
+
+pub fn build_chat_last_activity_key(
+    chat_id: &str,
+) -> String {
+    format!(
+        "chat_last_activity:{}",
+        chat_id,
+    )
+}
+
+/// Time of the newest message of a chat, None for chats that haven't
+/// had one since this was first recorded.
+pub fn get_chat_last_activity(
+    db: &impl ReadStore,
+    chat_id: &str,
+) -> Option<i64> {
+    db.get(build_chat_last_activity_key(chat_id))
+        .ok()
+        .flatten()
+        .map(|time| String::from_utf8(time).ok())
+        .flatten()
+        .map(|time| time.parse::<i64>().ok())
+        .flatten()
+}
+
+/// `chat_activity:{archived}:{inverted time}:{chat_id}`, what the chats
+/// overview seeks in to page through chats by activity. Chats the bot was
+/// removed from come after all others, the most recent first in each.
+pub fn build_chat_activity_key(
+    chat_id: &str,
+    last_activity: i64,
+    archived: bool,
+) -> String {
+    format!(
+        "chat_activity:{}:{:020}:{}",
+        archived as u8,
+        i64::MAX - last_activity,
+        chat_id,
+    )
+}
+
+/// Moves the `chat_activity:` entry of a chat from where its last activity
+/// and archived state `before` put it to where `after` does.
+pub fn move_chat_activity(
+    db: &DBWithThreadMode<MultiThreaded>,
+    chat_id: &str,
+    before: Option<(i64, bool)>,
+    after: (i64, bool),
+) -> Result<(), rocksdb::Error> {
+    if let Some((last_activity, archived)) = before {
+        db.delete(build_chat_activity_key(chat_id, last_activity, archived))?;
+    }
+
+    db.put(build_chat_activity_key(chat_id, after.0, after.1), b"\0")
+}
+
+/// Makes `time` the last activity of a chat, unless it had a newer one.
+pub fn record_chat_activity(
+    db: &DBWithThreadMode<MultiThreaded>,
+    chat_id: &str,
+    time: i64,
+) -> Result<(), rocksdb::Error> {
+    let last_activity = get_chat_last_activity(db, chat_id);
+
+    // backfilled or late messages don't move it back
+    if last_activity.unwrap_or(0) >= time {
+        return Ok(());
+    }
+
+    let archived = get_chat_policy(chat_id).bot_removed_at.is_some();
+
+    db.put(
+        build_chat_last_activity_key(chat_id),
+        time.to_string(),
+    )?;
+
+    move_chat_activity(db, chat_id, last_activity.map(|last| (last, archived)), (time, archived))
+}
+
+/// Where a log item sits in its chat: the second it was established and the
+/// telegram message id, which keeps messages of the same second apart. Keys
+/// written before the id was part of them have none, they're moved by the
+/// `message_id_keys` migration but still read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MessageSlot {
+    pub time: i64,
+    pub message_id: Option<i64>,
+}
+
+impl MessageSlot {
+    pub fn new(
+        time: i64,
+        message_id: i64,
+    ) -> Self {
+        Self {
+            time,
+            message_id: Some(message_id),
+        }
+    }
+
+    /// `{time}` or `{time}:{message_id}`, the way keys and cursors end.
+    pub fn parse(
+        slot: &str,
+    ) -> Option<Self> {
+        match slot.split_once(':') {
+            Some((time, message_id)) =>
+                Some(Self::new(time.parse().ok()?, message_id.parse().ok()?)),
+            None => Some(Self::from(slot.parse::<i64>().ok()?)),
+        }
+    }
+}
+
+/// A slot without message id, as keys were written before it was kept.
+/// Also what range bounds are built from, it sorts before the second's
+/// other slots.
+impl From<i64> for MessageSlot {
+    fn from(time: i64) -> Self {
+        Self {
+            time,
+            message_id: None,
+        }
+    }
+}
+
+// zero-padded, so that messages of a second keep their order
+impl std::fmt::Display for MessageSlot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.message_id {
+            Some(message_id) => write!(f, "{}:{:010}", self.time, message_id),
+            None => write!(f, "{}", self.time),
+        }
+    }
+}
+
+pub fn message_slot(
+    message: &InterMessage,
+) -> MessageSlot {
+    MessageSlot::new(
+        message_established_date(message),
+        i64::from(message.id),
+    )
+}
+
+pub fn build_message_key(
+    chat_id: &str,
+    slot: impl Into<MessageSlot>,
+) -> String {
+    format!(
+        "chat:{}:{}",
+        chat_id,
+        slot.into(),
+    )
+}
+
+/// The chat id and slot of a `chat:{chat_id}:{slot}` key, None for
+/// `chat:meta:*` and the other keys sharing the prefix.
+pub fn parse_message_key(
+    key: &str,
+) -> Option<(&str, MessageSlot)> {
+    let (chat_id, slot) = key.strip_prefix("chat:")?.split_once(':')?;
+
+    chat_id.parse::<i64>().ok()?;
+
+    Some((chat_id, MessageSlot::parse(slot)?))
+}
+
+/// The slot a log item is stored at. Items stored before message ids were
+/// part of the key are found under their second alone, and a slot without
+/// id finds the first item of its second.
+pub fn find_message_slot(
+    db: &impl ReadStore,
+    chat_id: &str,
+    slot: MessageSlot,
+) -> Option<MessageSlot> {
+    if db.get(build_message_key(chat_id, slot).as_bytes()).ok().flatten().is_some() {
+        return Some(slot);
+    }
+
+    match slot.message_id {
+        Some(_) => {
+            let legacy = MessageSlot::from(slot.time);
+
+            db.get(build_message_key(chat_id, legacy).as_bytes())
+                .ok()
+                .flatten()
+                .map(|_| legacy)
+        }
+        None => {
+            let prefix = format!("{}:", build_message_key(chat_id, slot));
+
+            let mut opts = ReadOptions::default();
+
+            opts.set_iterate_lower_bound(prefix.as_bytes().to_vec());
+            opts.set_iterate_upper_bound(format!("{}\x7f", prefix).as_bytes().to_vec());
+
+            let (key, _) =
+                db.iterator_opt(IteratorMode::From(prefix.as_bytes(), Direction::Forward), opts)
+                    .next()?;
+
+            let key = String::from_utf8(key.to_vec()).ok()?;
+
+            parse_message_key(&key).map(|(_, slot)| slot)
+        }
+    }
+}
+
+pub fn build_chat_index_key(
+    chat_id: &str,
+    time: i64,
+) -> String {
+    format!(
+        "chat_index:{}:{}",
+        chat_id,
+        (time / 86400).to_string(),
+    )
+}
+
+pub fn build_raw_message_key(
+    chat_id: &str,
+    time: i64,
+    message_id: &str,
+) -> String {
+    format!(
+        "chat_raw:{}:{}:{}",
+        chat_id,
+        time,
+        message_id,
+    )
+}
+
+pub fn build_poll_ref_key(
+    poll_id: &str,
+) -> String {
+    format!(
+        "poll_ref:{}",
+        poll_id,
+    )
+}
+
+/// Rewrites a stored poll with the counts from a poll update. Polls posted
+/// before the bot joined (or in chats it isn't in) aren't known and are
+/// skipped silently.
+pub fn handle_poll_update(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    poll: &Poll,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let db = lock_db(&db);
+
+    let message_key =
+        match db.get(build_poll_ref_key(&poll.id))? {
+            Some(message_key) => String::from_utf8(message_key)?,
+            None => return Ok(()),
+        };
+
+    // refs from before message ids were part of the key may point at the
+    // timestamp alone
+    let message_key =
+        match parse_message_key(&message_key) {
+            Some((chat_id, slot)) =>
+                match find_message_slot(&*db, chat_id, slot) {
+                    Some(slot) => build_message_key(chat_id, slot),
+                    None => return Ok(()),
+                },
+            None => return Ok(()),
+        };
+
+    let mut log_item =
+        match db.get(&message_key)? {
+            Some(log_item) => serde_json::from_slice::<LogItem>(&log_item)?,
+            None => return Ok(()),
+        };
+
+    if let LogItem::Special {
+        special_type: LogItemSpecialType::Poll {
+            ref mut options,
+            ref mut total_voter_count,
+            ref mut is_closed,
+            ref mut updated,
+            ..
+        },
+        ..
+    } = log_item {
+        *options =
+            poll.options
+                .iter()
+                .map(|option|
+                    LogItemSpecialTypePollOption {
+                        text: option.text.clone(),
+                        voter_count: option.voter_count,
+                    }
+                )
+                .collect();
+
+        *total_voter_count = poll.total_voter_count;
+        *is_closed = poll.is_closed;
+        *updated = Some(chrono::Utc::now().timestamp());
+    } else {
+        return Ok(());
+    }
+
+    let chat_id = message_key.split(':').nth(1).unwrap_or_default();
+
+    put_counted(
+        &db,
+        &message_key,
+        to_versioned_string(&log_item)?,
+        &message_counter_keys(chat_id),
+    )?;
+
+    invalidate_chat_pages(chat_id);
+
+    Ok(())
+}
+
+/// A member's side of a `chat_member` update, the fork has no type for it.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChatMemberState {
+    pub user: User,
+    // creator, administrator, member, restricted, left or kicked
+    pub status: String,
+    // only sent along with "restricted"
+    #[serde(default)]
+    pub is_member: Option<bool>,
+}
+
+impl ChatMemberState {
+    pub fn is_member(&self) -> bool {
+        match self.status.as_str() {
+            "creator" | "administrator" | "member" => true,
+            "restricted" => self.is_member.unwrap_or(true),
+            _ => false,
+        }
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.status == "creator" || self.status == "administrator"
+    }
+}
+
+/// A `chat_member` or `my_chat_member` update. Those arrive for changes
+/// that come without a service message too: bans, promotions, joins that
+/// needed an admin's approval.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChatMemberUpdate {
+    pub chat: Chat,
+    // who made the change, the member themselves when joining or leaving
+    pub from: User,
+    pub date: i64,
+    pub old_chat_member: ChatMemberState,
+    pub new_chat_member: ChatMemberState,
+}
+
+/// What a member's status change amounts to, None for changes that aren't
+/// worth logging (a restriction being lifted, say).
+pub fn membership_change(
+    old: &ChatMemberState,
+    new: &ChatMemberState,
+) -> Option<LogItemMembershipType> {
+    match (old.status.as_str(), new.status.as_str()) {
+        (old_status, "kicked") if old_status != "kicked" => Some(LogItemMembershipType::Banned),
+        ("kicked", _) if !new.is_member() => Some(LogItemMembershipType::Unbanned),
+        _ if !old.is_member() && new.is_member() => Some(LogItemMembershipType::Joined),
+        _ if old.is_member() && !new.is_member() => Some(LogItemMembershipType::Left),
+        _ if !old.is_admin() && new.is_admin() => Some(LogItemMembershipType::Promoted),
+        _ if old.is_admin() && !new.is_admin() => Some(LogItemMembershipType::Demoted),
+        _ => None,
+    }
+}
+
+/// Logs a membership change from a `chat_member` update. `own` is for the
+/// bot's own ones (`my_chat_member`), a chat the bot was removed from is
+/// marked as such in its policy.
+pub async fn handle_chat_member_update(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    bot: &Bot,
+    update: &ChatMemberUpdate,
+    own: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let chat_id = ChatMeta::from(update.chat.clone()).id();
+
+    // private chats have no members to speak of, a "kicked" bot there was
+    // blocked by its user
+    if is_private_chat_id(&chat_id) || !bot.logs_chat(&chat_id) {
+        return Ok(());
+    }
+
+    let old = &update.old_chat_member;
+    let new = &update.new_chat_member;
+
+    if own {
+        let policy = get_chat_policy(&chat_id);
+
+        let bot_removed_at =
+            match new.is_member() {
+                true => None,
+                false => policy.bot_removed_at.or(Some(update.date)),
+            };
+
+        set_chat_policy(
+            &lock_db(&db),
+            &chat_id,
+            ChatPolicy {
+                bot_removed_at,
+                ..policy
+            },
+        )?;
+    }
+
+    // turned off on the admin page
+    if !get_chat_policy(&chat_id).logging {
+        return Ok(());
+    }
+
+    let user = UserMeta::from(&new.user);
+
+    if is_user_ignored(&lock_db(&db), &user) {
+        return Ok(());
+    }
+
+    let membership_type =
+        match membership_change(old, new) {
+            Some(membership_type) => membership_type,
+            None => return Ok(()),
+        };
+
+    let user_id = user.id.clone();
+
+    let admin_id =
+        Some(update.from.id.to_string())
+            .filter(|admin_id| *admin_id != user_id);
+
+    process_user_meta(db.clone(), &user).await?;
+
+    if admin_id.is_some() {
+        process_user_meta(db.clone(), &UserMeta::from(&update.from)).await?;
+    }
+
+    let log_item =
+        LogItem::Membership {
+            user_id: Some(user_id.clone()),
+            time: update.date,
+            received_at: update.date,
+            membership_type: membership_type.clone(),
+            admin_id,
+            source: None,
+            v: SCHEMA_VERSION,
+        };
+
+    let db = lock_db(&db);
+
+    // membership changes have no message id of their own, they take id 0.
+    // Another change of the same second moves this one a bit later
+    let mut message_key = None;
+
+    for time in update.date..=update.date + MEMBERSHIP_SLOT_SPREAD {
+        let key = build_message_key(&chat_id, MessageSlot::new(time, 0));
+
+        match db.get(&key)?.map(|item| serde_json::from_slice::<LogItem>(&item).ok()) {
+            None => {
+                message_key = Some((key, time));
+
+                break;
+            }
+            Some(Some(LogItem::Membership { user_id: Some(ref stored_user_id), membership_type: ref stored_type, .. }))
+            if *stored_user_id == user_id && *stored_type == membership_type =>
+                return Ok(()),
+            Some(_) => {}
+        }
+    }
+
+    let (message_key, time) =
+        match message_key {
+            Some(message_key) => message_key,
+            None => {
+                tracing::warn!(chat_id = %chat_id, time = update.date, "no free slot for a membership change");
+
+                return Ok(());
+            }
+        };
+
+    let mut writes = PendingWrites::new();
+
+    writes.put_counted(
+        &message_key,
+        to_versioned_string(&log_item)?,
+        &message_counter_keys(&chat_id),
+    );
+
+    writes.put(
+        &build_chat_index_key(&chat_id, time),
+        b"\0",
+    );
+
+    writes.commit(&db)?;
+
+    db.put(
+        format!(
+            "chat_rel:{}",
+            &chat_id,
+        ),
+        &b"\0",
+    )?;
+
+    record_chat_activity(&db, &chat_id, time)?;
+
+    invalidate_chat_pages(&chat_id);
+
+    Ok(())
+}
+
+/// Stores a log item, its day in the chat index (days since start of
+/// epoch) and its search postings in one batch with `writes`, so that a
+/// failed commit leaves none of them behind.
+fn store_log_item(
+    db: &DBWithThreadMode<MultiThreaded>,
+    writes: PendingWrites,
+    chat_id: &str,
+    slot: MessageSlot,
+    log_item: &LogItem,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut writes = writes;
+
+    let message_key = build_message_key(chat_id, slot);
+
+    let message_value = to_versioned_string(log_item)?;
+
+    // a message stored over another one takes its postings' place
+    let previous =
+        match get_search_index() {
+            true =>
+                db.get(&message_key)?
+                    .map(|previous| serde_json::from_slice::<LogItem>(&previous).ok())
+                    .flatten(),
+            false => None,
+        };
+
+    writes.put_counted(
+        &message_key,
+        &message_value,
+        &message_counter_keys(chat_id),
+    );
+
+    let chat_index_key = build_chat_index_key(chat_id, slot.time);
+
+    // cached pages of finished days are stale once one of them gets a late
+    // message, or a new day moves their "next" link
+    let late = slot.time / 86400 < chrono::Utc::now().timestamp() / 86400;
+    let stale_pages = late || db.get(&chat_index_key)?.is_none();
+
+    writes.put(
+        &chat_index_key,
+        b"\0",
+    );
+
+    if get_search_index() {
+        queue_postings(
+            db,
+            &mut writes,
+            chat_id,
+            slot,
+            previous.as_ref(),
+            Some(log_item),
+        )?;
+    }
+
+    writes.commit(db)?;
+
+    if stale_pages {
+        invalidate_chat_pages(chat_id);
+    }
+
+    Ok(())
+}
+
+/// Stores a message. `writes` holds the blobs of `files`, they're committed
+/// in one batch with the log item so that neither ends up stored without
+/// the other.
+pub async fn handle_message(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    bot: &Bot,
+    message: &InterMessage,
+    files: &Vec<String>,
+    writes: PendingWrites,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut writes = writes;
+
+    let log_item =
+        build_log_item(
+            db.clone(),
+            Some(bot),
+            message,
+            files,
+            &mut writes,
+        ).await;
+
+    let db = lock_db(&db);
+
+    let slot = message_slot(message);
+
+    let established_date = slot.time;
+
+    let use_forwarded_chat = uses_forwarded_chat(message);
+
+    let chat_id = message_chat_id(message);
+
+    // store actual message
+
+    store_log_item(
+        &db,
+        writes,
+        &chat_id,
+        slot,
+        &log_item,
+    )?;
+
+    // store chat so that it can be iterated upon
+
+    {
+        let chat_key =
+            format!(
+                "chat_rel:{}",
+                &chat_id,
+            );
+
+        db.put(
+            &chat_key,
+            &b"\0",
+        )?;
+
+        record_chat_activity(&db, &chat_id, established_date)?;
+
+        // so that lookups for the chat go through the bot that's in it
+        db.put(
+            build_chat_bot_key(&chat_id),
+            &bot.name,
+        )?;
+
+        if get_last_message_at(&*db).unwrap_or(0) < message.date {
             db.put(
-                file_key,
-                &file,
-            ).ok()?;
+                LAST_MESSAGE_AT_KEY,
+                message.date.to_string(),
+            )?;
+        }
+
+        record_message_ingested(message.date);
+    }
+
+    // store chat by message id so that it allows direct lookup
+
+    {
+        let message_ref_key =
+            format!(
+                "chat_ref:{}:{}",
+                &chat_id,
+                message.id.to_string(),
+            );
+
+        let message_ref_value = established_date.to_string();
+
+        db.put(
+            &message_ref_key,
+            &message_ref_value,
+        )?;
+    }
+
+    // keep the full message next to kinds we can't map yet, so a later
+    // reprocessing pass can turn them into proper log items in place
+
+    if let LogItem::Unimplemented(..) = log_item {
+        db.put(
+            build_raw_message_key(
+                &chat_id,
+                established_date,
+                &message.id.to_string(),
+            ),
+            serde_json::to_string(message)?,
+        )?;
+    }
+
+    // store polls by poll id so that poll updates can find them
+
+    if let LogItem::Special { special_type: LogItemSpecialType::Poll { ref id, .. }, .. } = log_item {
+        db.put(
+            build_poll_ref_key(id),
+            build_message_key(
+                &chat_id,
+                slot,
+            ),
+        )?;
+    }
+
+    // store pins so that they can be listed without walking the whole chat
+
+    if let LogItem::Pin { .. } = log_item {
+        let chat_pin_key =
+            format!(
+                "chat_pins:{}:{}",
+                &chat_id,
+                slot,
+            );
+
+        db.put(
+            &chat_pin_key,
+            &b"\0",
+        )?;
+    }
+
+    // keep a history of title and photo changes, chat:meta itself only
+    // ever holds the latest record
+
+    let previous_chat_meta =
+        db.get(
+            format!(
+                "chat:meta:{}",
+                &chat_id,
+            ),
+        )
+            .ok()
+            .flatten()
+            .map(|v| serde_json::from_slice::<ChatMeta>(&v).ok())
+            .flatten();
+
+    if chat_id == message.chat.id() {
+        let previous_title =
+            previous_chat_meta
+                .as_ref()
+                .map(|meta| meta.title())
+                .flatten();
+
+        let mut changes = Vec::<ChatMetaChange>::new();
+
+        if let Some(title) = message.chat.title() {
+            if previous_title.as_ref() != Some(&title) {
+                changes.push(
+                    ChatMetaChange::Title {
+                        title,
+                    },
+                );
+            }
+        }
+
+        match log_item {
+            LogItem::Chat { chat_type: LogItemChatType::NewPhoto { ref file_id }, .. } =>
+                changes.push(
+                    ChatMetaChange::Photo {
+                        file_id: file_id.clone(),
+                    },
+                ),
+            LogItem::Chat { chat_type: LogItemChatType::DeletePhoto, .. } =>
+                changes.push(ChatMetaChange::DeletePhoto),
+            // kept in the history so the chat info page can tell how long
+            // the chat expects its messages to live
+            LogItem::Chat { chat_type: LogItemChatType::AutoDeleteTimerChanged { seconds }, .. } =>
+                changes.push(ChatMetaChange::AutoDeleteTimer { seconds }),
+            _ => {}
+        }
+
+        // the title is in the header of every page
+        if !changes.is_empty() {
+            invalidate_chat_pages(&chat_id);
+        }
+
+        for change in changes {
+            let entry =
+                ChatMetaHistoryEntry {
+                    time: message.date,
+                    user_id:
+                    message
+                        .from
+                        .as_ref()
+                        .map(|from| from.id.clone()),
+                    change,
+                    v: SCHEMA_VERSION,
+                };
+
+            let entry = to_versioned_string(&entry)?;
+
+            // replies make us see service messages more than once, so skip
+            // entries we already have and only shift the key on collisions
+            let mut time = message.date;
+
+            loop {
+                let history_key = build_chat_meta_history_key(&chat_id, time);
+
+                match db.get(&history_key)? {
+                    Some(existing) if existing == entry.as_bytes() => break,
+                    Some(_) => time += 1,
+                    None => {
+                        db.put(
+                            &history_key,
+                            &entry,
+                        )?;
+
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    // store chat metadata
+
+    {
+        let chat_meta_key =
+            format!(
+                "chat:meta:{}",
+                &chat_id,
+            );
+
+        let chat_meta_value =
+            message
+                .forward
+                .as_ref()
+                .map(|forward| {
+                    if !use_forwarded_chat {
+                        return None;
+                    }
+
+                    match forward.from {
+                        ForwardFromMeta::User { ref user } =>
+                            Some(to_versioned_string(user)),
+                        ForwardFromMeta::Channel { ref channel, .. } =>
+                            Some(to_versioned_string(channel)),
+                        _ => None,
+                    }
+                })
+                .flatten()
+                .unwrap_or(
+                    to_versioned_string(&message.chat),
+                )?;
+
+        db.put(
+            &chat_meta_key,
+            &chat_meta_value,
+        )?;
+
+        invalidate_chat_meta(&chat_id);
+    }
+
+    // store chat by username so that web routes can take @names
+
+    if chat_id == message.chat.id() {
+        update_chat_username_index(
+            &db,
+            &chat_id,
+            previous_chat_meta.as_ref(),
+            &message.chat,
+            message.date,
+        )?;
+    }
+
+    Ok(())
+}
 
-            Some(photo_size.file_id.clone())
+/// Keeps a sample of a failure to log `inter_msg` for the status page,
+/// with the stage it failed in. Storing the message is told apart by the
+/// kind of error.
+fn note_ingest_error(
+    db: &Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    stage: &str,
+    inter_msg: &InterMessage,
+    err: &Box<dyn std::error::Error>,
+) {
+    let stage =
+        if stage != "store" {
+            stage
+        } else if err.downcast_ref::<rocksdb::Error>().is_some() {
+            "db_write"
+        } else if err.downcast_ref::<serde_json::Error>().is_some() {
+            "serialization"
         } else {
-            None
-        }
-    } else {
-        None
+            stage
+        };
+
+    tracing::error!(stage, error = %err, "failed to log message");
+
+    if let Err(err) =
+        record_ingest_error(
+            &lock_db(db),
+            stage,
+            Some(&message_chat_id(inter_msg)),
+            Some(&inter_msg.id.to_string()),
+            err.as_ref(),
+        )
+    {
+        dbg!(err);
     }
 }
 
-pub fn map_entity_kind(
-    kind: &MessageEntityKind,
-) -> LogItemMessageEntityKind {
-    match kind {
-        MessageEntityKind::Mention =>
-            LogItemMessageEntityKind::Mention,
-        MessageEntityKind::Hashtag =>
-            LogItemMessageEntityKind::Hashtag,
-        MessageEntityKind::BotCommand =>
-            LogItemMessageEntityKind::BotCommand,
-        MessageEntityKind::Url =>
-            LogItemMessageEntityKind::Url,
-        MessageEntityKind::Email =>
-            LogItemMessageEntityKind::Email,
-        MessageEntityKind::Bold =>
-            LogItemMessageEntityKind::Bold,
-        MessageEntityKind::Italic =>
-            LogItemMessageEntityKind::Italic,
-        MessageEntityKind::Code =>
-            LogItemMessageEntityKind::Code,
-        MessageEntityKind::Pre =>
-            LogItemMessageEntityKind::Pre,
-        MessageEntityKind::TextLink(v) =>
-            LogItemMessageEntityKind::TextLink(v.clone()),
-        MessageEntityKind::TextMention(v) =>
-            LogItemMessageEntityKind::TextMention(v.id.to_string().clone()),
-        MessageEntityKind::Unknown(_) =>
-            LogItemMessageEntityKind::Unknown,
+// whatever gets logged on the way carries the chat and the message
+#[tracing::instrument(skip_all, fields(chat_id = %message_chat_id(inter_msg), message_id = %inter_msg.id))]
+pub async fn handle_inter_message(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    bot: &Bot,
+    inter_msg: &InterMessage,
+) -> Result<(), Box<dyn std::error::Error>> {
+    set_panic_message(&message_chat_id(inter_msg), &inter_msg.id.to_string());
+
+    // chats outside the allowlist are left to the bots configured for them
+    if !bot.logs_chat(&message_chat_id(inter_msg)) {
+        return Ok(());
+    }
+
+    // turned off on the admin page
+    if !get_chat_policy(&message_chat_id(inter_msg)).logging {
+        return Ok(());
+    }
+
+    // anybody can message the bot, that doesn't make it public
+    if let ChatMeta::User(_) = inter_msg.chat {
+        if !logs_private_chat(&inter_msg.chat.id()) {
+            return Ok(());
+        }
+    }
+
+    // ignored users' messages are neither stored nor downloaded
+    if let Some(ref from) = inter_msg.from {
+        if is_user_ignored(&lock_db(&db), from) {
+            return Ok(());
+        }
+
+        // command replies and the like aren't part of the conversation
+        if bot.user_id.as_deref() == Some(&from.id) && !get_log_own_messages() {
+            return Ok(());
+        }
+    }
+
+    let (files, writes) =
+        match inter_msg.kind {
+            MessageKind::Audio { .. }
+            | MessageKind::Voice { .. }
+            | MessageKind::Photo { .. }
+            | MessageKind::Document { .. }
+            | MessageKind::Sticker { .. }
+            | MessageKind::Video { .. }
+            | MessageKind::VideoNote { .. } =>
+                process_files(
+                    db.clone(),
+                    bot,
+                    &inter_msg,
+                )
+                    .await
+                    .map_err(|err| {
+                        note_ingest_error(&db, "file_download", inter_msg, &err);
+
+                        err
+                    })?,
+
+            _ => (Vec::new(), PendingWrites::new()),
+        };
+
+    handle_message(
+        db.clone(),
+        bot,
+        dbg!(&inter_msg),
+        &files,
+        writes,
+    )
+        .await
+        .map_err(|err| {
+            note_ingest_error(&db, "store", inter_msg, &err);
+
+            err
+        })?;
+
+    if let Some(ref from) = inter_msg.from {
+        process_user(
+            db.clone(),
+            bot,
+            dbg!(from),
+        )
+            .await
+            .map_err(|err| {
+                note_ingest_error(&db, "user_meta", inter_msg, &err);
+
+                err
+            })?;
     }
+
+    // only the meta, bots rarely have profile pictures worth fetching
+    if let Some(ref via_bot) = inter_msg.via_bot {
+        process_user_meta(
+            db.clone(),
+            via_bot,
+        )
+            .await
+            .map_err(|err| {
+                note_ingest_error(&db, "user_meta", inter_msg, &err);
+
+                err
+            })?;
+    }
+
+    Ok(())
 }
 
-pub async fn build_log_item(
+async fn run(
     db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
-    api: &Api,
-    message: &InterMessage,
-    files: &Vec<String>,
-) -> LogItem {
-    let msg_from_id =
-        message
-            .from
-            .as_ref()
-            .map(|from|
-                     from.id
-                         .to_string()
-                         .clone(),
-            );
+    bot: &Bot,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // a token telegram rejects stays rejected, anything else may be the
+    // network and is worth another try
+    let me =
+        validate_bot(bot)
+            .await
+            .map_err(|err| {
+                let err = format!("{:?}", err);
+
+                if err.contains("Unauthorized") || err.contains("Not Found") {
+                    Box::new(FatalError(err)) as Box<dyn std::error::Error>
+                } else {
+                    err.into()
+                }
+            })?;
 
-    match message.kind {
-        MessageKind::Text {
-            ref data,
-            ref entities,
-        } => {
-            LogItem::Message {
-                user_id: msg_from_id,
-                time: message.date,
-                text: data.clone(),
-                entities:
-                entities
-                    .clone()
+    store_bot_identity(&lock_db(&db), &bot.name, &me)?;
+
+    let bot = &bot.clone().with_user_id(&me.id);
+
+    // stops along with the update stream, the restart brings it back
+    let _digests = spawn_digest_scheduler(db.clone(), bot.clone());
+
+    let _heartbeat = spawn_heartbeat(db.clone(), format!("telegram_handler:{}", bot.name));
+
+    let _sticker_sets = spawn_sticker_set_fetcher(db.clone(), bot.clone());
+
+    let _member_counts = spawn_member_count_poller(db.clone(), bot.clone());
+
+    let _file_retries = spawn_file_retrier(db.clone(), bot.clone());
+
+    let _data_exports = spawn_data_exporter(db.clone(), bot.clone());
+
+    // confirmed with the next call, updates handled before a restart come
+    // again after it
+    let mut offset = 0;
+
+    loop {
+        for raw_update in bot.get_updates(offset).await? {
+            let db = db.clone();
+
+            let update_id =
+                some_or_continue!(
+                    raw_update
+                        .get("update_id")
+                        .map(|update_id| update_id.as_i64())
+                        .flatten()
+                );
+
+            offset = update_id + 1;
+
+            // telegram sends it again after the restart, it'd only panic again
+            if is_panicked_update(&*lock_db(&db), &bot.name, update_id) {
+                tracing::warn!(bot = %bot.name, update_id, "skipping update, it made the handler panic before");
+
+                continue;
+            }
+
+            let _panic_context = enter_update(&bot.name, update_id);
+
+            // the fork doesn't know these update kinds
+            let chat_member_update =
+                ["chat_member", "my_chat_member"]
                     .iter()
-                    .map(|entity|
-                        LogItemMessageEntity {
-                            offset: entity.offset,
-                            length: entity.length,
-                            kind: map_entity_kind(&entity.kind),
+                    .find_map(|kind|
+                        raw_update
+                            .get(kind)
+                            .map(|update| (*kind == "my_chat_member", update.clone()))
+                    );
+
+            if let Some((own, chat_member_update)) = chat_member_update {
+                let chat_member_update =
+                    match serde_json::from_value::<ChatMemberUpdate>(chat_member_update) {
+                        Ok(chat_member_update) => chat_member_update,
+                        Err(err) => {
+                            dbg!(err);
+
+                            continue;
                         }
-                    )
-                    .collect(),
-                source: Some(message.clone()),
+                    };
+
+                tracing::debug!(
+                    chat_id = %chat_member_update.chat.id(),
+                    user_id = %chat_member_update.new_chat_member.user.id,
+                    old_status = %chat_member_update.old_chat_member.status,
+                    new_status = %chat_member_update.new_chat_member.status,
+                    "chat member update"
+                );
+
+                record_telegram_update(
+                    Some(chrono::Utc::now().timestamp() - chat_member_update.date),
+                );
+
+                handle_chat_member_update(
+                    db.clone(),
+                    bot,
+                    &chat_member_update,
+                    own,
+                ).await?;
+
+                continue;
             }
-        }
 
-        MessageKind::Audio { ref data } => {
-            LogItem::Media {
-                user_id: msg_from_id,
-                time: message.date,
-                caption: None,
-                media_type:
-                LogItemMediaType::Audio {
-                    duration: data.duration,
-                    performer: data.performer.clone(),
-                    title: data.title.clone(),
-                    mime_type: data.mime_type.clone(),
-                },
-                files: files.clone(),
-                source: Some(message.clone()),
+            // kept apart from the chats, they never reach the message pipeline
+            let inline_update =
+                INLINE_UPDATES
+                    .iter()
+                    .find_map(|kind| raw_update.get(kind))
+                    .cloned();
+
+            if let Some(inline_update) = inline_update {
+                // asked for while it was still on
+                if !get_log_inline_queries() {
+                    continue;
+                }
+
+                let inline_update =
+                    match serde_json::from_value::<InlineUpdate>(inline_update) {
+                        Ok(inline_update) => inline_update,
+                        Err(err) => {
+                            dbg!(err);
+
+                            continue;
+                        }
+                    };
+
+                record_telegram_update(None);
+
+                if let Err(err) = handle_inline_update(&lock_db(&db), bot, &inline_update) {
+                    dbg!(err);
+                }
+
+                continue;
             }
-        }
 
-        MessageKind::Document {
-            ref data,
-            ref caption,
-        } => {
-            LogItem::Media {
-                user_id: msg_from_id,
-                time: message.date,
-                caption: (*caption).clone(),
-                media_type:
-                LogItemMediaType::Document {
-                    file_name: data.file_name.clone(),
-                    mime_type: data.mime_type.clone(),
+            // for what the fork drops from messages, see `InterMessage::with_raw`
+            let raw_message =
+                ["message", "edited_message", "channel_post", "edited_channel_post"]
+                    .iter()
+                    .find_map(|kind| raw_update.get(kind))
+                    .cloned()
+                    .unwrap_or_default();
+
+            let update =
+                match serde_json::from_value::<Update>(raw_update) {
+                    Ok(update) => update,
+                    Err(err) => {
+                        dbg!(err);
+
+                        continue;
+                    }
+                };
+
+            dbg!(&update);
+
+            let update_time =
+                match update.kind {
+                    UpdateKind::Message(ref message) => Some(message.date),
+                    UpdateKind::EditedMessage(ref message) => message.edit_date.or(Some(message.date)),
+                    UpdateKind::ChannelPost(ref post) => Some(post.date),
+                    UpdateKind::EditedChannelPost(ref post) => post.edit_date.or(Some(post.date)),
+                    _ => None,
+                };
+
+            let metrics =
+                record_telegram_update(
+                    update_time.map(|time| chrono::Utc::now().timestamp() - time),
+                );
+
+            if metrics.lag_seconds > CATCHUP_LAG_THRESHOLD {
+                if metrics.updates % CATCHUP_LOG_INTERVAL == 0 {
+                    println!(
+                        "[telegram_handler] processing backlog: {}s behind, currently at updates from {}, {} profile picture(s) deferred",
+                        metrics.lag_seconds,
+                        update_time
+                            .map(|time| chrono::NaiveDateTime::from_timestamp_opt(time, 0))
+                            .flatten()
+                            .map(|time| time.format("%Y-%m-%d %H:%M").to_string())
+                            .unwrap_or("?".to_string()),
+                        metrics.deferred_jobs,
+                    );
+                }
+            } else if metrics.deferred_jobs > 0 {
+                // a couple per update so that catching up on the backlog of
+                // deferred work doesn't itself become a burst
+                if let Err(err) = process_deferred_jobs(db.clone(), bot, 2).await {
+                    dbg!(err);
+                }
+            }
+
+            match update.kind {
+                UpdateKind::Message(ref message)
+                | UpdateKind::EditedMessage(ref message) => {
+                    let inter_msg = InterMessage::from(message).with_raw(&raw_message);
+
+                    if let UpdateKind::Message(_) = update.kind {
+                        if let Err(err) = handle_command(
+                            db.clone(),
+                            bot,
+                            &inter_msg,
+                        ).await {
+                            dbg!(err);
+                        }
+                    }
+
+                    if let Some(reply_to_message) = inter_msg.reply_to_message.as_ref() {
+                        handle_inter_message(
+                            db.clone(),
+                            bot,
+                            &reply_to_message.as_ref(),
+                        ).await?;
+                    }
+
+                    handle_inter_message(
+                        db.clone(),
+                        bot,
+                        &inter_msg,
+                    ).await?;
                 },
-                files: files.clone(),
-                source: Some(message.clone()),
+                UpdateKind::ChannelPost(ref post)
+                | UpdateKind::EditedChannelPost(ref post) => {
+                    let inter_msg = InterMessage::from(post).with_raw(&raw_message);
+
+                    if let Some(reply_to_message) = inter_msg.reply_to_message.as_ref() {
+                        handle_inter_message(
+                            db.clone(),
+                            bot,
+                            &reply_to_message.as_ref(),
+                        ).await?;
+                    }
+
+                    handle_inter_message(
+                        db.clone(),
+                        bot,
+                        &inter_msg,
+                    ).await?;
+                }
+                UpdateKind::Poll(ref poll) => {
+                    handle_poll_update(
+                        db.clone(),
+                        poll,
+                    )?;
+                }
+                _ => {},
             }
         }
+    }
+}
 
-        MessageKind::Photo {
-            ref data,
-            ref caption,
-            ..
-        } => {
-            let photo =
-                find_biggest_photo(
-                    &data.clone(),
-                );
+pub async fn spawn_worker(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    config: BotConfig,
+) {
+    let bot = Bot::new(&config);
+
+    let worker = format!("telegram_handler:{}", bot.name);
+
+    let mut backoff = RestartBackoff::new(&worker);
+
+    loop {
+        let started = Instant::now();
+
+        if let Err(err) = run(
+            db.clone(),
+            &bot,
+        ).await {
+            if let Err(err) = record_last_error(&lock_db(&db), &worker, &err.to_string()) {
+                dbg!(err);
+            }
 
-            LogItem::Media {
-                user_id: msg_from_id,
-                time: message.date,
-                caption: (*caption).clone(),
-                media_type:
-                LogItemMediaType::Image {
-                    width: photo.width,
-                    height: photo.height,
-                },
-                files: files.clone(),
-                source: Some(message.clone()),
+            if let Some(fatal) = err.downcast_ref::<FatalError>() {
+                exit_fatal(&worker, fatal);
             }
+
+            dbg!(err);
         }
 
-        MessageKind::Sticker {
-            ref data,
-        } => {
-            LogItem::Media {
-                user_id: msg_from_id,
-                time: message.date,
-                caption: None,
-                media_type:
-                LogItemMediaType::Sticker {
-                    emoji: data.emoji.clone(),
-                    set_name: data.set_name.clone(),
-                },
-                files: files.clone(),
-                source: Some(message.clone()),
-            }
+        backoff.wait(started.elapsed()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text_message(id: i64, text: &str) -> InterMessage {
+        InterMessage {
+            id: MessageId::new(id),
+            from: Some(UserMeta {
+                id: "10".to_string(),
+                first_name: "Alice".to_string(),
+                ..UserMeta::default()
+            }),
+            date: 1_600_000_000,
+            chat: ChatMeta::User(UserMeta {
+                id: "10".to_string(),
+                first_name: "Alice".to_string(),
+                ..UserMeta::default()
+            }),
+            forward: None,
+            reply_to_message: None,
+            edit_date: None,
+            kind: MessageKind::Text {
+                data: text.to_string(),
+                entities: vec![],
+            },
+            via_bot: None,
+            author_signature: None,
+            untyped_kind: None,
+            custom_emoji_ids: BTreeMap::new(),
         }
+    }
 
-        MessageKind::Video {
-            ref data,
-            ref caption,
-            ..
-        } => {
-            let thumb_file_id =
-                match data.thumb {
-                    Some(ref thumb) => {
-                        process_photosize(
-                            db.clone(),
-                            &api,
-                            thumb,
-                            None,
-                        ).await
-                    }
-                    _ => None,
-                };
+    #[test]
+    fn inter_message_without_via_bot_or_signature_deserializes() {
+        // what `chat_raw:` records looked like before the fields existed
+        let mut old = serde_json::to_value(&text_message(1, "hi")).unwrap();
 
-            LogItem::Media {
-                user_id: msg_from_id,
-                time: message.date,
-                caption: (*caption).clone(),
-                media_type:
-                LogItemMediaType::Video {
-                    duration: data.duration,
-                    width: data.width,
-                    height: data.height,
-                    thumb_file_id,
-                    mime_type: data.mime_type.clone(),
-                },
-                files: files.clone(),
-                source: Some(message.clone()),
+        old.as_object_mut().unwrap().remove("via_bot");
+        old.as_object_mut().unwrap().remove("author_signature");
+
+        let msg = serde_json::from_value::<InterMessage>(old).unwrap();
+
+        assert!(msg.via_bot.is_none());
+        assert!(msg.author_signature.is_none());
+    }
+
+    #[test]
+    fn with_raw_reads_via_bot_and_signature() {
+        let mut msg = text_message(2, "@gif cats");
+
+        msg.reply_to_message = Some(Box::new(text_message(1, "hi")));
+
+        let raw = serde_json::json!({
+            "message_id": 2,
+            "via_bot": {"id": 20, "is_bot": true, "first_name": "GIF", "username": "gif"},
+            "author_signature": "Editor",
+            "reply_to_message": {
+                "message_id": 1,
+                "via_bot": {"id": 21, "is_bot": true, "first_name": "Vote", "username": "vote"},
+            },
+        });
+
+        let msg = msg.with_raw(&raw);
+
+        let via_bot = msg.via_bot.unwrap();
+
+        assert_eq!(via_bot.id, "20");
+        assert_eq!(via_bot.username.as_deref(), Some("gif"));
+        assert_eq!(msg.author_signature.as_deref(), Some("Editor"));
+
+        let reply = msg.reply_to_message.unwrap();
+
+        assert_eq!(reply.via_bot.unwrap().id, "21");
+        assert!(reply.author_signature.is_none());
+    }
+
+    #[test]
+    fn with_raw_leaves_plain_messages_alone() {
+        let msg = text_message(1, "hi").with_raw(&serde_json::json!({"message_id": 1}));
+
+        assert!(msg.via_bot.is_none());
+        assert!(msg.author_signature.is_none());
+    }
+
+    fn untyped_message(key: &str, payload: serde_json::Value) -> InterMessage {
+        let mut msg = text_message(3, "");
+
+        msg.untyped_kind =
+            Some(UntypedKind {
+                key: key.to_string(),
+                payload,
+            });
+
+        msg
+    }
+
+    #[test]
+    fn dice_game_and_payment_are_mapped_from_the_payload() {
+        let dice = untyped_message("dice", serde_json::json!({"emoji": "🎲", "value": 4}));
+
+        match build_untyped_log_item(&dice, Some("10".to_string())) {
+            Some(LogItem::Special { special_type: LogItemSpecialType::Dice { emoji, value }, user_id, .. }) => {
+                assert_eq!(emoji, "🎲");
+                assert_eq!(value, 4);
+                assert_eq!(user_id.as_deref(), Some("10"));
             }
+            other => panic!("{:?}", other),
         }
 
-        MessageKind::Voice {
-            ref data,
-        } => {
-            LogItem::Media {
-                user_id: msg_from_id,
-                time: message.date,
-                caption: None,
-                media_type:
-                LogItemMediaType::Voice {
-                    duration: data.duration,
-                    mime_type: data.mime_type.clone(),
-                },
-                files: files.clone(),
-                source: Some(message.clone()),
+        let game = untyped_message("game", serde_json::json!({"title": "Lumberjack", "description": "Chop", "photo": []}));
+
+        match build_untyped_log_item(&game, None) {
+            Some(LogItem::Special { special_type: LogItemSpecialType::Game { title, description }, .. }) => {
+                assert_eq!(title, "Lumberjack");
+                assert_eq!(description, "Chop");
             }
+            other => panic!("{:?}", other),
         }
 
-        MessageKind::VideoNote {
-            ref data,
-        } => {
-            let thumb_file_id =
-                match data.thumb {
-                    Some(ref thumb) => {
-                        process_photosize(
-                            db.clone(),
-                            &api,
-                            thumb,
-                            None,
-                        ).await
-                    }
-                    _ => None,
-                };
+        let payment =
+            untyped_message(
+                "successful_payment",
+                serde_json::json!({
+                    "currency": "EUR",
+                    "total_amount": 1250,
+                    "invoice_payload": "order-7",
+                    "telegram_payment_charge_id": "a",
+                    "provider_payment_charge_id": "b",
+                }),
+            );
 
-            LogItem::Media {
-                user_id: msg_from_id,
-                time: message.date,
-                caption: None,
-                media_type:
-                LogItemMediaType::VideoNote {
-                    duration: data.duration,
-                    thumb_file_id,
-                },
-                files: files.clone(),
-                source: Some(message.clone()),
+        match build_untyped_log_item(&payment, None) {
+            Some(LogItem::Special { special_type: LogItemSpecialType::Payment { currency, total_amount, invoice_payload }, .. }) => {
+                assert_eq!(currency, "EUR");
+                assert_eq!(total_amount, 1250);
+                assert_eq!(invoice_payload, "order-7");
             }
+            other => panic!("{:?}", other),
         }
+    }
 
-        MessageKind::Contact {
-            ref data,
-        } => {
-            LogItem::Special {
-                user_id: msg_from_id,
-                time: message.date,
-                special_type:
-                LogItemSpecialType::Contact {
-                    user_id: data.user_id.clone(),
-                    phone_number: data.phone_number.clone(),
-                    first_name: data.first_name.clone(),
-                    last_name: data.last_name.clone(),
-                },
-                source: Some(message.clone()),
+    #[test]
+    fn malformed_untyped_payloads_stay_unmapped() {
+        let dice = untyped_message("dice", serde_json::json!({"emoji": "🎲"}));
+
+        assert!(build_untyped_log_item(&dice, None).is_none());
+        assert!(build_untyped_log_item(&text_message(1, "hi"), None).is_none());
+    }
+
+    #[test]
+    fn video_chats_and_auto_delete_timers_are_mapped_from_the_payload() {
+        let chat_type = |key: &str, payload: serde_json::Value| {
+            match build_untyped_log_item(&untyped_message(key, payload), Some("10".to_string())) {
+                Some(LogItem::Chat { chat_type, user_id, .. }) => {
+                    assert_eq!(user_id.as_deref(), Some("10"));
+
+                    chat_type
+                }
+                other => panic!("{:?}", other),
             }
+        };
+
+        assert!(matches!(
+            chat_type("video_chat_started", serde_json::json!({})),
+            LogItemChatType::VideoChatStarted,
+        ));
+
+        assert!(matches!(
+            chat_type("voice_chat_ended", serde_json::json!({"duration": 95})),
+            LogItemChatType::VideoChatEnded { duration: 95 },
+        ));
+
+        match chat_type(
+            "video_chat_participants_invited",
+            serde_json::json!({"users": [
+                {"id": 20, "is_bot": false, "first_name": "Bob"},
+                {"id": 21, "is_bot": false, "first_name": "Carol"},
+            ]}),
+        ) {
+            LogItemChatType::VideoChatInviteSent { users } => assert_eq!(users, vec!("20", "21")),
+            other => panic!("{:?}", other),
         }
 
-        MessageKind::Location {
-            ref data,
-        } => {
-            LogItem::Special {
-                user_id: msg_from_id,
-                time: message.date,
-                special_type:
-                LogItemSpecialType::Location {
-                    latitude: data.latitude,
-                    longitude: data.longitude,
-                },
-                source: Some(message.clone()),
+        assert!(matches!(
+            chat_type("message_auto_delete_timer_changed", serde_json::json!({"message_auto_delete_time": 86400})),
+            LogItemChatType::AutoDeleteTimerChanged { seconds: 86400 },
+        ));
+    }
+
+    #[test]
+    fn with_raw_reads_custom_emoji_ids() {
+        let raw = serde_json::json!({
+            "message_id": 1,
+            "text": "hi 👍 and 🎉",
+            "entities": [
+                {"type": "bold", "offset": 0, "length": 2},
+                {"type": "custom_emoji", "offset": 3, "length": 2, "custom_emoji_id": "5368324170671202286"},
+                {"type": "custom_emoji", "offset": 10, "length": 2},
+            ],
+        });
+
+        let msg = text_message(1, "hi 👍 and 🎉").with_raw(&raw);
+
+        assert_eq!(msg.custom_emoji_ids.get(&3).map(|id| id.as_str()), Some("5368324170671202286"));
+        assert_eq!(msg.custom_emoji_ids.get(&10), None);
+        assert_eq!(msg.custom_emoji_ids.len(), 1);
+    }
+
+    #[test]
+    fn custom_emoji_stored_with_an_empty_id_have_none() {
+        let entity =
+            serde_json::from_str::<LogItemMessageEntity>(r#"{"offset": 3, "length": 2, "kind": {"customemoji": ""}}"#)
+                .unwrap();
+
+        assert!(matches!(entity.kind, LogItemMessageEntityKind::CustomEmoji(None)));
+
+        let entity =
+            serde_json::from_str::<LogItemMessageEntity>(r#"{"offset": 3, "length": 2, "kind": {"customemoji": "53"}}"#)
+                .unwrap();
+
+        assert!(matches!(entity.kind, LogItemMessageEntityKind::CustomEmoji(Some(ref id)) if id == "53"));
+    }
+
+    fn open_db(name: &str) -> Arc<Mutex<DBWithThreadMode<MultiThreaded>>> {
+        let path =
+            std::env::temp_dir()
+                .join(format!("minuteman-test-{}-{}", name, std::process::id()));
+
+        let _ = std::fs::remove_dir_all(&path);
+
+        Arc::new(Mutex::new(DBWithThreadMode::<MultiThreaded>::open_default(path).unwrap()))
+    }
+
+    /// The message of a captured update under tests/fixtures/updates, read
+    /// the way `run` reads it.
+    fn captured_message(raw_update: &str) -> InterMessage {
+        let raw_update = serde_json::from_str::<serde_json::Value>(raw_update).unwrap();
+        let raw_message = raw_update["message"].clone();
+
+        match serde_json::from_value::<Update>(raw_update).unwrap().kind {
+            UpdateKind::Message(message) => InterMessage::from(&message).with_raw(&raw_message),
+            kind => panic!("not a message: {:?}", kind),
+        }
+    }
+
+    fn captured_log_item(name: &str, raw_update: &str, files: Vec<String>) -> LogItem {
+        futures::executor::block_on(
+            build_log_item(open_db(name), None, &captured_message(raw_update), &files, &mut PendingWrites::new()),
+        )
+    }
+
+    #[test]
+    fn captured_custom_emoji_keep_their_ids_and_fallback_text() {
+        let log_item = captured_log_item("captured-custom-emoji", include_str!("../../tests/fixtures/updates/custom_emoji.json"), vec![]);
+
+        let (text, entities) =
+            match log_item {
+                LogItem::Message { text, entities, .. } => (text, entities),
+                _ => panic!("not a message"),
+            };
+
+        assert_eq!(text, "hi 👍 and 🎉");
+
+        assert!(matches!(entities[0].kind, LogItemMessageEntityKind::Bold));
+        assert!(matches!(entities[1].kind, LogItemMessageEntityKind::CustomEmoji(Some(ref id)) if id == "5368324170671202286"));
+        assert!(matches!(entities[2].kind, LogItemMessageEntityKind::CustomEmoji(Some(ref id)) if id == "5370869711888194012"));
+        assert_eq!((entities[1].offset, entities[1].length), (3, 2));
+
+        let html = crate::components::message_text::render_collapsed_message_text(&text, &entities, 0, 0);
+
+        assert!(html.contains("<span class=\"custom-emoji\">👍</span>"));
+        assert!(html.contains("<span class=\"custom-emoji\">🎉</span>"));
+    }
+
+    #[test]
+    fn captured_unknown_entities_keep_their_type_and_range() {
+        let log_item = captured_log_item("captured-unknown-entity", include_str!("../../tests/fixtures/updates/unknown_entity.json"), vec![]);
+
+        let entities =
+            match log_item {
+                LogItem::Message { entities, .. } => entities,
+                _ => panic!("not a message"),
+            };
+
+        assert!(matches!(entities[0].kind, LogItemMessageEntityKind::Blockquote));
+        assert!(matches!(entities[1].kind, LogItemMessageEntityKind::Unknown(ref kind) if kind == "date_time"));
+        assert_eq!((entities[1].offset, entities[1].length), (10, 4));
+
+        // and it's stored so that it reads back the same
+        let stored = serde_json::to_string(&entities[1]).unwrap();
+
+        assert!(matches!(
+            serde_json::from_str::<LogItemMessageEntity>(&stored).unwrap().kind,
+            LogItemMessageEntityKind::Unknown(ref kind) if kind == "date_time"
+        ));
+    }
+
+    #[test]
+    fn captured_stickers_keep_their_emoji_and_set() {
+        let cases = [
+            ("captured-premium-sticker", include_str!("../../tests/fixtures/updates/premium_sticker.json"), "🎉", "PremiumStickers"),
+            ("captured-video-sticker", include_str!("../../tests/fixtures/updates/video_sticker.json"), "😎", "VideoStickers"),
+        ];
+
+        for (name, raw_update, expected_emoji, expected_set_name) in cases {
+            let log_item = captured_log_item(name, raw_update, vec!["sticker-file".to_string()]);
+
+            match log_item {
+                LogItem::Media { media_type: LogItemMediaType::Sticker { emoji, set_name }, files, .. } => {
+                    assert_eq!(emoji.as_deref(), Some(expected_emoji));
+                    assert_eq!(set_name.as_deref(), Some(expected_set_name));
+                    assert_eq!(files, vec!["sticker-file".to_string()]);
+                }
+                log_item => panic!("not a sticker: {:?}", log_item),
             }
         }
+    }
 
-        MessageKind::Poll {
-            ref data,
-        } => {
-            LogItem::Special {
-                user_id: msg_from_id,
-                time: message.date,
-                special_type:
-                LogItemSpecialType::Poll {
-                    id: data.id.clone(),
-                    question: data.question.clone(),
-                    options:
-                    data.options
-                        .iter()
-                        .map(|option|
-                            LogItemSpecialTypePollOption {
-                                text: option.text.clone(),
-                                voter_count: option.voter_count,
-                            }
-                        )
-                        .collect(),
-                    total_voter_count: data.total_voter_count,
-                    is_closed: data.is_closed,
-                    is_anonymous: data.is_anonymous,
-                    poll_type: data.type_.clone(),
-                    allows_multiple_answers: data.allows_multiple_answers,
-                    correct_option_id: data.correct_option_id.clone(),
-                    explanation: data.explanation.clone(),
-                    explanation_entities:
-                    data.explanation_entities
-                        .clone()
-                        .map(|entities|
-                            entities
-                                .iter()
-                                .map(|entity|
-                                    LogItemSpecialTypePollMessageEntity {
-                                        offset: entity.offset,
-                                        length: entity.length,
-                                        kind: map_entity_kind(&entity.kind),
-                                    }
-                                )
-                                .collect::<Vec<LogItemSpecialTypePollMessageEntity>>()
-                        ),
-                    open_period: data.open_period.clone(),
-                    close_date: data.close_date.clone(),
+    #[test]
+    fn forwards_are_filed_under_their_original_day_and_keep_their_receive_time() {
+        use chrono::NaiveDate;
+
+        use crate::config::ListingOrder;
+        use crate::renderer::chat_listing::{chat_listing_iter, day_time_bounds};
+
+        const DAY: i64 = 86_400;
+
+        let db = open_db("forward-day-bucket");
+
+        // 2020-09-13, forwarded from the day before
+        let mut msg = text_message(1, "old news");
+
+        msg.forward =
+            Some(ForwardMeta {
+                date: msg.date - DAY,
+                from: ForwardFromMeta::ChannelHiddenUser {
+                    sender_name: "Bob".to_string(),
                 },
-                source: Some(message.clone()),
-            }
+            });
+
+        let log_item =
+            futures::executor::block_on(
+                build_log_item(db.clone(), None, &msg, &vec![], &mut PendingWrites::new()),
+            );
+
+        assert_eq!(log_item.received_at(), msg.date);
+        assert_eq!(log_item.original_time(), Some(msg.date - DAY));
+
+        let chat_id = message_chat_id(&msg);
+        let db = lock_db(&db);
+
+        db.put(
+            build_message_key(&chat_id, message_slot(&msg)),
+            to_versioned_string(&log_item).unwrap(),
+        )
+            .unwrap();
+
+        let listed = |date: NaiveDate| {
+            let (time_start, time_end) = day_time_bounds(date);
+
+            let mut items = vec![];
+
+            chat_listing_iter(&*db, &chat_id, &time_start, &time_end, &None, 100, ListingOrder::Asc, |slot, val| {
+                items.push((slot.time, serde_json::from_slice::<LogItem>(val).unwrap()));
+            });
+
+            items
+        };
+
+        // days go by the original time, the item keeps when it arrived
+        assert!(listed(NaiveDate::from_ymd(2020, 9, 13)).is_empty());
+
+        let items = listed(NaiveDate::from_ymd(2020, 9, 12));
+
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].0, msg.date - DAY);
+        assert_eq!(items[0].1.received_at(), msg.date);
+    }
+
+    #[test]
+    fn coordinates_widen_to_the_decimal_telegram_sent() {
+        let cases: [(f32, f64); 10] = [
+            (52.520008, 52.520008),
+            (13.404954, 13.404954),
+            (-33.86882, -33.86882),
+            (0.1, 0.1),
+            (0.0, 0.0),
+            (90.0, 90.0),
+            (-90.0, -90.0),
+            (180.0, 180.0),
+            (-180.0, -180.0),
+            (0.0000001, 0.0000001),
+        ];
+
+        for (value, expected) in cases {
+            assert_eq!(widen_coordinate(value), expected, "{}", value);
         }
 
-        MessageKind::Venue {
-            ref data,
-        } => {
-            LogItem::Special {
-                user_id: msg_from_id,
-                time: message.date,
-                special_type:
-                LogItemSpecialType::Venue {
-                    location:
-                    LogItemSpecialTypeLocation {
-                        longitude: data.location.longitude,
-                        latitude: data.location.latitude,
-                    },
-                    title: data.title.clone(),
-                    address: data.address.clone(),
-                    foursquare_id: data.foursquare_id.clone(),
-                },
-                source: Some(message.clone()),
-            }
+        // a plain cast keeps the binary error of the f32
+        assert_ne!(0.1_f32 as f64, 0.1);
+
+        // the last digit telegram sends survives, neighbouring f32s stay apart
+        let next = f32::from_bits(52.520008_f32.to_bits() + 1);
+
+        assert_ne!(widen_coordinate(next), widen_coordinate(52.520008));
+        assert_eq!(widen_coordinate(next) as f32, next);
+
+        assert!(widen_coordinate(-0.0).is_sign_negative());
+        assert!(widen_coordinate(f32::NAN).is_nan());
+    }
+
+    #[test]
+    fn received_at_falls_back_to_time_for_items_stored_without_it() {
+        let stored = r#"{"message": {"user_id": "1001", "time": 1600000000, "text": "hi", "entities": [], "source": null}}"#;
+
+        assert_eq!(serde_json::from_str::<LogItem>(stored).unwrap().received_at(), 1_600_000_000);
+
+        let stored = r#"{"message": {"user_id": "1001", "time": 1600000000, "received_at": 1600000060, "text": "hi", "entities": [], "source": null}}"#;
+
+        assert_eq!(serde_json::from_str::<LogItem>(stored).unwrap().received_at(), 1_600_000_060);
+    }
+
+    const FAKE_IMAGE: &[u8] = include_bytes!("../../tests/fixtures/gps_exif.jpg");
+
+    // answers of the fake bot api. The bot's token says what fails: `download`
+    // file downloads, `profile` getUserProfilePhotos
+    fn fake_telegram_answer(path: &str) -> warp::reply::Response {
+        use warp::Reply;
+        use warp::http::StatusCode;
+
+        let mut parts = path.splitn(3, '/');
+
+        let (first, second) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+
+        let error =
+            warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({"ok": false, "error_code": 400, "description": "Bad Request: fake"})),
+                StatusCode::BAD_REQUEST,
+            );
+
+        if first == "file" {
+            return match second.trim_start_matches("bot") {
+                "download" => warp::reply::with_status(Vec::<u8>::new(), StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+                _ => FAKE_IMAGE.into_response(),
+            };
         }
 
-        MessageKind::NewChatMembers { .. } => {
-            LogItem::Membership {
-                user_id: msg_from_id,
-                time: message.date,
-                membership_type: LogItemMembershipType::Joined,
-                source: Some(message.clone()),
-            }
+        let result =
+            match (first.trim_start_matches("bot"), second) {
+                (_, "getFile") => serde_json::json!({"file_id": "fake", "file_path": "photos/fake.jpg"}),
+                ("profile", "getUserProfilePhotos") => return error.into_response(),
+                (_, "getUserProfilePhotos") => serde_json::json!({"total_count": 0, "photos": []}),
+                _ => return error.into_response(),
+            };
+
+        warp::reply::json(&serde_json::json!({"ok": true, "result": result})).into_response()
+    }
+
+    // served from a thread of its own, the runtime of a test ends with it
+    static FAKE_TELEGRAM: once_cell::sync::Lazy<String> =
+        once_cell::sync::Lazy::new(|| {
+            use warp::Filter;
+
+            let (sender, receiver) = std::sync::mpsc::channel();
+
+            std::thread::spawn(move || {
+                let runtime =
+                    tokio::runtime::Builder::new_current_thread()
+                        .enable_all()
+                        .build()
+                        .unwrap();
+
+                runtime.block_on(async {
+                    let routes =
+                        warp::path::tail()
+                            .map(|tail: warp::path::Tail| fake_telegram_answer(tail.as_str()));
+
+                    let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+
+                    sender.send(addr).unwrap();
+
+                    server.await;
+                });
+            });
+
+            format!("http://{}/", receiver.recv().unwrap())
+        });
+
+    fn fake_bot(token: &str) -> Bot {
+        std::env::set_var("TELEGRAM_API_URL", &*FAKE_TELEGRAM);
+
+        Bot::new(
+            &BotConfig {
+                name: token.to_string(),
+                token: token.to_string(),
+                chats: vec![],
+            },
+        )
+    }
+
+    fn photo_size(file_id: &str) -> PhotoSize {
+        PhotoSize {
+            file_id: file_id.to_string(),
+            width: 8,
+            height: 8,
+            file_size: Some(FAKE_IMAGE.len() as i64),
         }
+    }
 
-        MessageKind::LeftChatMember { .. } => {
-            LogItem::Membership {
-                user_id: msg_from_id,
-                time: message.date,
-                membership_type: LogItemMembershipType::Left,
-                source: Some(message.clone()),
-            }
+    fn group_message(kind: MessageKind) -> InterMessage {
+        InterMessage {
+            chat: ChatMeta::Group(GroupMeta {
+                id: "-1001".to_string(),
+                title: "Group".to_string(),
+                all_members_are_administrators: false,
+                invite_link: None,
+                v: SCHEMA_VERSION,
+            }),
+            kind,
+            ..text_message(1, "")
         }
+    }
 
-        MessageKind::NewChatTitle {
-            ref data,
-        } => {
-            LogItem::Chat {
-                user_id: msg_from_id,
-                time: message.date,
-                chat_type:
-                LogItemChatType::NewTitle {
-                    title: data.clone(),
+    fn photo_message(file_id: &str) -> InterMessage {
+        group_message(
+            MessageKind::Photo {
+                data: vec![photo_size(file_id)],
+                caption: None,
+                media_group_id: None,
+            },
+        )
+    }
+
+    fn video_message(thumb_file_id: &str) -> InterMessage {
+        group_message(
+            MessageKind::Video {
+                data: Video {
+                    file_id: "video".to_string(),
+                    width: 8,
+                    height: 8,
+                    duration: 1,
+                    thumb: Some(photo_size(thumb_file_id)),
+                    mime_type: Some("video/mp4".to_string()),
+                    file_size: Some(1),
                 },
-                source: Some(message.clone()),
+                caption: None,
+                media_group_id: None,
+            },
+        )
+    }
+
+    fn stored_log_items(db: &DBWithThreadMode<MultiThreaded>) -> Vec<LogItem> {
+        prefix_iter(db, "chat:-1001:")
+            .map(|(_, value)| serde_json::from_slice::<LogItem>(&value).unwrap())
+            .collect()
+    }
+
+    /// Files of stored log items are either stored or marked as failed, and
+    /// every blob stored for a message belongs to a stored log item.
+    fn assert_consistent(db: &DBWithThreadMode<MultiThreaded>) {
+        let mut referenced = Vec::<String>::new();
+
+        for log_item in stored_log_items(db) {
+            for (file_id, url) in log_item.file_urls() {
+                let kind = url.split('/').nth(2).unwrap();
+                let file_key = build_file_key(FileEntryType::from_url_kind(kind).unwrap(), &file_id);
+
+                assert!(
+                    db.get(&file_key).unwrap().is_some()
+                        || db.get(build_file_failure_key(&file_id)).unwrap().is_some(),
+                    "{} is neither stored nor marked as failed",
+                    file_key,
+                );
+
+                referenced.push(file_id);
             }
         }
 
-        MessageKind::NewChatPhoto {
-            ref data,
-        } => {
-            let photo_size =
-                find_biggest_photo(
-                    data,
-                );
+        for kind in ["chat", "video_thumb", "thumb"] {
+            let prefix = format!("file:{}:", kind);
 
-            let photo =
-                process_photosize(
-                    db.clone(),
-                    api,
-                    &photo_size,
-                    None,
-                ).await;
+            for (key, _) in prefix_iter(db, &prefix) {
+                let key = String::from_utf8(key.to_vec()).unwrap();
 
-            LogItem::Chat {
-                user_id: msg_from_id,
-                time: message.date,
-                chat_type:
-                LogItemChatType::NewPhoto {
-                    file_id: photo.as_ref().map(|p| p.clone()),
-                },
-                source: Some(message.clone()),
+                assert!(
+                    referenced.iter().any(|file_id| *file_id == key[prefix.len()..]),
+                    "{} belongs to no stored log item",
+                    key,
+                );
             }
         }
+    }
 
-        MessageKind::DeleteChatPhoto => {
-            LogItem::Chat {
-                user_id: msg_from_id,
-                time: message.date,
-                chat_type: LogItemChatType::DeletePhoto,
-                source: Some(message.clone()),
+    #[tokio::test]
+    async fn a_failed_file_download_stores_the_message_with_the_file_marked_failed() {
+        let db = open_db("ingest-download-fails");
+
+        let result = handle_inter_message(db.clone(), &fake_bot("download"), &photo_message("photo-a")).await;
+
+        assert!(result.is_ok());
+
+        let db = lock_db(&db);
+
+        assert_eq!(stored_log_items(&db).len(), 1);
+        assert!(db.get(build_file_key(FileEntryType::Chat, "photo-a")).unwrap().is_none());
+        assert!(db.get(build_file_failure_key("photo-a")).unwrap().is_some());
+
+        assert_consistent(&db);
+    }
+
+    #[tokio::test]
+    async fn a_failed_blob_write_stores_neither_the_message_nor_its_files() {
+        let db = open_db("ingest-blob-write-fails");
+
+        crate::blob_store::FAILING_BLOB_KEYS.with(|keys| {
+            keys.borrow_mut().push(build_file_key(FileEntryType::Chat, "photo-b"));
+        });
+
+        let result = handle_inter_message(db.clone(), &fake_bot("blob-write"), &photo_message("photo-b")).await;
+
+        crate::blob_store::FAILING_BLOB_KEYS.with(|keys| keys.borrow_mut().clear());
+
+        assert!(result.is_err());
+
+        let db = lock_db(&db);
+
+        assert!(stored_log_items(&db).is_empty());
+        assert!(db.get(build_file_key(FileEntryType::Chat, "photo-b")).unwrap().is_none());
+        assert!(db.get(build_file_key(FileEntryType::Thumb, "photo-b")).unwrap().is_none());
+
+        assert_consistent(&db);
+    }
+
+    #[tokio::test]
+    async fn a_thumbnail_build_log_item_fails_on_is_left_out_of_the_message() {
+        // its download failing, and its blob write
+        let cases = [
+            ("ingest-thumb-download-fails", "download", "thumb-a"),
+            ("ingest-thumb-write-fails", "thumb-write", "thumb-b"),
+        ];
+
+        for (name, token, thumb_file_id) in cases {
+            let db = open_db(name);
+
+            let thumb_key = build_file_key(FileEntryType::VideoThumb, thumb_file_id);
+
+            crate::blob_store::FAILING_BLOB_KEYS.with(|keys| keys.borrow_mut().push(thumb_key.clone()));
+
+            let result = handle_inter_message(db.clone(), &fake_bot(token), &video_message(thumb_file_id)).await;
+
+            crate::blob_store::FAILING_BLOB_KEYS.with(|keys| keys.borrow_mut().clear());
+
+            assert!(result.is_ok(), "{}", name);
+
+            let db = lock_db(&db);
+
+            match stored_log_items(&db).as_slice() {
+                [LogItem::Media { media_type: LogItemMediaType::Video { thumb_file_id, .. }, .. }] =>
+                    assert_eq!(*thumb_file_id, None, "{}", name),
+                log_items => panic!("{}: {:?}", name, log_items),
             }
+
+            assert!(db.get(&thumb_key).unwrap().is_none(), "{}", name);
+            assert!(db.get(build_file_failure_key(thumb_file_id)).unwrap().is_some(), "{}", name);
+
+            assert_consistent(&db);
         }
+    }
 
-        MessageKind::PinnedMessage {
-            ref data,
-        } => {
-            LogItem::Pin {
-                user_id: msg_from_id,
-                time: message.date,
-                message: data.text(),
-                message_id: data.to_message_id().to_string(),
-                source: Some(message.clone()),
+    #[tokio::test]
+    async fn a_failed_process_user_keeps_the_stored_message_and_its_files() {
+        let db = open_db("ingest-process-user-fails");
+
+        let result = handle_inter_message(db.clone(), &fake_bot("profile"), &photo_message("photo-c")).await;
+
+        assert!(result.is_err());
+
+        let db = lock_db(&db);
+
+        assert_eq!(stored_log_items(&db).len(), 1);
+        assert!(db.get(build_file_key(FileEntryType::Chat, "photo-c")).unwrap().is_some());
+        assert!(db.get(build_file_meta_key("photo-c")).unwrap().is_some());
+
+        // the name is stored even though the picture failed
+        assert!(db.get("user:meta:10").unwrap().is_some());
+
+        assert_consistent(&db);
+    }
+
+    #[tokio::test]
+    async fn updates_are_still_handled_after_a_panic_poisoned_the_database_lock() {
+        let db = open_db("ingest-after-poison");
+
+        lock_db(&db).put(crate::workers::panics::build_panicked_update_key("poisoned", 7), b"").unwrap();
+
+        let poisoner = db.clone();
+
+        let _ = std::thread::spawn(move || {
+            let _dbi = poisoner.lock().unwrap();
+
+            panic!("panicked while holding the database");
+        }).join();
+
+        assert!(db.is_poisoned());
+
+        // the update that panicked is skipped, the next one is handled
+        assert!(is_panicked_update(&*lock_db(&db), "poisoned", 7));
+        assert!(!is_panicked_update(&*lock_db(&db), "poisoned", 8));
+
+        let result = handle_inter_message(db.clone(), &fake_bot("poisoned"), &group_message(MessageKind::Text { data: "hi".to_string(), entities: vec![] })).await;
+
+        assert!(result.is_ok());
+
+        let db = lock_db(&db);
+
+        assert_eq!(stored_log_items(&db).len(), 1);
+
+        assert_consistent(&db);
+    }
+
+    #[tokio::test]
+    async fn messages_of_the_same_second_are_all_kept() {
+        use crate::config::ListingOrder;
+        use crate::renderer::chat_listing::{chat_listing_iter, ListingCursor};
+        use crate::utils::resolve_message_ref;
+
+        let db = open_db("same-second");
+        let bot = fake_bot("same-second");
+
+        for (id, text) in [(1, "first"), (2, "second"), (3, "third")] {
+            let message =
+                InterMessage {
+                    id: MessageId::new(id),
+                    ..group_message(MessageKind::Text { data: text.to_string(), entities: vec![] })
+                };
+
+            handle_message(db.clone(), &bot, &message, &vec![], PendingWrites::new()).await.unwrap();
+        }
+
+        let db = lock_db(&db);
+
+        assert_eq!(stored_log_items(&db).len(), 3);
+
+        let slot = resolve_message_ref(&*db, "-1001", "2").unwrap();
+
+        assert_eq!(slot, MessageSlot::new(1_600_000_000, 2));
+        assert_eq!(build_message_key("-1001", slot), "chat:-1001:1600000000:0000000002");
+
+        // a page of one walks through them one by one
+        let mut cursor = None;
+        let mut listed = vec![];
+
+        loop {
+            let page =
+                chat_listing_iter(&*db, "-1001", "1599955200", "1600041600", &cursor, 1, ListingOrder::Asc, |slot, _| {
+                    listed.push(slot.message_id);
+                });
+
+            match page.newer_cursor() {
+                Some(newer) => cursor = MessageSlot::parse(&newer).map(ListingCursor::Newer),
+                None => break,
             }
         }
-        MessageKind::GroupChatCreated => {
-            LogItem::Unimplemented(
-                "GroupChatCreated".to_string(),
-                msg_from_id,
-                message.date,
-                Some(message.clone()),
+
+        assert_eq!(listed, vec![Some(1), Some(2), Some(3)]);
+
+        // a bare timestamp, as older links have it, stands for the whole second
+        let mut listed = vec![];
+
+        chat_listing_iter(&*db, "-1001", "1599955200", "1600041600", &Some(ListingCursor::Older(MessageSlot::from(1_600_000_001))), 10, ListingOrder::Asc, |slot, _| {
+            listed.push(slot.message_id);
+        });
+
+        assert_eq!(listed, vec![Some(1), Some(2), Some(3)]);
+    }
+
+    #[test]
+    fn postings_are_committed_with_the_writes_they_are_queued_on() {
+        use crate::search_index::{build_posting_key, queue_postings, search_index_counter_key};
+        use crate::storage_stats::get_storage_counter;
+
+        let db = open_db("postings-batch");
+        let db = lock_db(&db);
+
+        let slot = MessageSlot::new(1_600_000_000, 1);
+
+        let item = |text: &str| {
+            futures::executor::block_on(
+                build_log_item(open_db("postings-batch-item"), None, &text_message(1, text), &vec![], &mut PendingWrites::new()),
             )
+        };
+
+        let hello = build_posting_key("-1001", "hello", slot);
+        let world = build_posting_key("-1001", "world", slot);
+        let there = build_posting_key("-1001", "there", slot);
+
+        // nothing of writes that are never committed, like those of a
+        // message whose files failed to store
+        let mut writes = PendingWrites::new();
+
+        writes.put(&build_message_key("-1001", slot), "{}");
+
+        queue_postings(&db, &mut writes, "-1001", slot, None, Some(&item("hello world"))).unwrap();
+
+        drop(writes);
+
+        assert!(db.get(&hello).unwrap().is_none());
+        assert_eq!(get_storage_counter(&*db, &search_index_counter_key()).count, 0);
+
+        let mut writes = PendingWrites::new();
+
+        queue_postings(&db, &mut writes, "-1001", slot, None, Some(&item("hello world"))).unwrap();
+
+        writes.commit(&db).unwrap();
+
+        assert!(db.get(&hello).unwrap().is_some());
+        assert!(db.get(&world).unwrap().is_some());
+
+        let counter = get_storage_counter(&*db, &search_index_counter_key());
+
+        assert_eq!(counter.count, 2);
+        assert_eq!(counter.bytes as usize, hello.len() + world.len());
+
+        // an edit moves them
+        let mut writes = PendingWrites::new();
+
+        queue_postings(&db, &mut writes, "-1001", slot, Some(&item("hello world")), Some(&item("hello there"))).unwrap();
+
+        writes.commit(&db).unwrap();
+
+        assert!(db.get(&world).unwrap().is_none());
+        assert!(db.get(&there).unwrap().is_some());
+
+        let counter = get_storage_counter(&*db, &search_index_counter_key());
+
+        assert_eq!(counter.count, 2);
+        assert_eq!(counter.bytes as usize, hello.len() + there.len());
+    }
+}
+
+// Golden files for the on-disk format of log items and chat metadata, under
+// tests/fixtures/log_items. A rename that changes the format fails here
+// instead of silently hiding the rows written before it.
+#[cfg(test)]
+mod golden_tests {
+    use serde::de::DeserializeOwned;
+    use serde_json::Value;
+
+    use super::*;
+
+    const TIME: i64 = 1_600_000_000;
+
+    macro_rules! fixture {
+        ($path:literal) => {
+            include_str!(concat!("../../tests/fixtures/log_items/", $path))
+        };
+    }
+
+    // the matches below are exhaustive on purpose, a new variant doesn't
+    // compile until it has a fixture (and a sample in the `*_samples` fns)
+
+    fn log_item_fixture(item: &LogItem) -> &'static str {
+        match item {
+            LogItem::Message { .. } => fixture!("log_item/message.json"),
+            LogItem::Media { .. } => fixture!("log_item/media.json"),
+            LogItem::Special { .. } => fixture!("log_item/special.json"),
+            LogItem::Membership { .. } => fixture!("log_item/membership.json"),
+            LogItem::Chat { .. } => fixture!("log_item/chat.json"),
+            LogItem::Pin { .. } => fixture!("log_item/pin.json"),
+            LogItem::Unimplemented(..) => fixture!("log_item/unimplemented.json"),
+            LogItem::Redacted { .. } => fixture!("log_item/redacted.json"),
         }
+    }
 
-        MessageKind::SupergroupChatCreated => {
-            LogItem::Unimplemented(
-                "SupergroupChatCreated".to_string(),
-                msg_from_id,
-                message.date,
-                Some(message.clone()),
-            )
+    fn media_type_fixture(media_type: &LogItemMediaType) -> &'static str {
+        match media_type {
+            LogItemMediaType::Image { .. } => fixture!("media_type/image.json"),
+            LogItemMediaType::Video { .. } => fixture!("media_type/video.json"),
+            LogItemMediaType::Audio { .. } => fixture!("media_type/audio.json"),
+            LogItemMediaType::Voice { .. } => fixture!("media_type/voice.json"),
+            LogItemMediaType::VideoNote { .. } => fixture!("media_type/video_note.json"),
+            LogItemMediaType::Animation { .. } => fixture!("media_type/animation.json"),
+            LogItemMediaType::Document { .. } => fixture!("media_type/document.json"),
+            LogItemMediaType::Sticker { .. } => fixture!("media_type/sticker.json"),
+        }
+    }
+
+    fn special_type_fixture(special_type: &LogItemSpecialType) -> &'static str {
+        match special_type {
+            LogItemSpecialType::Contact { .. } => fixture!("special_type/contact.json"),
+            LogItemSpecialType::Location { .. } => fixture!("special_type/location.json"),
+            LogItemSpecialType::Venue { .. } => fixture!("special_type/venue.json"),
+            LogItemSpecialType::Poll { .. } => fixture!("special_type/poll.json"),
+            LogItemSpecialType::PinnnedMessage => fixture!("special_type/pinned_message.json"),
+            LogItemSpecialType::Dice { .. } => fixture!("special_type/dice.json"),
+            LogItemSpecialType::Game { .. } => fixture!("special_type/game.json"),
+            LogItemSpecialType::Payment { .. } => fixture!("special_type/payment.json"),
+        }
+    }
+
+    fn chat_type_fixture(chat_type: &LogItemChatType) -> &'static str {
+        match chat_type {
+            LogItemChatType::NewTitle { .. } => fixture!("chat_type/new_title.json"),
+            LogItemChatType::NewPhoto { .. } => fixture!("chat_type/new_photo.json"),
+            LogItemChatType::DeletePhoto => fixture!("chat_type/delete_photo.json"),
+            LogItemChatType::VideoChatStarted => fixture!("chat_type/video_chat_started.json"),
+            LogItemChatType::VideoChatEnded { .. } => fixture!("chat_type/video_chat_ended.json"),
+            LogItemChatType::VideoChatInviteSent { .. } => fixture!("chat_type/video_chat_invite_sent.json"),
+            LogItemChatType::AutoDeleteTimerChanged { .. } => fixture!("chat_type/auto_delete_timer_changed.json"),
         }
+    }
 
-        MessageKind::ChannelChatCreated => {
-            LogItem::Unimplemented(
-                "ChannelChatCreated".to_string(),
-                msg_from_id,
-                message.date,
-                Some(message.clone()),
-            )
+    fn chat_meta_fixture(chat_meta: &ChatMeta) -> &'static str {
+        match chat_meta {
+            ChatMeta::User(_) => fixture!("chat_meta/user.json"),
+            ChatMeta::Group(_) => fixture!("chat_meta/group.json"),
+            ChatMeta::SuperGroup(_) => fixture!("chat_meta/super_group.json"),
+            ChatMeta::Channel(_) => fixture!("chat_meta/channel.json"),
+            ChatMeta::Unknown(_) => fixture!("chat_meta/unknown.json"),
         }
+    }
 
-        MessageKind::MigrateToChatId { .. } => {
-            LogItem::Unimplemented(
-                "MigrateToChatId".to_string(),
-                msg_from_id,
-                message.date,
-                Some(message.clone()),
-            )
+    fn entity(kind: LogItemMessageEntityKind) -> LogItemMessageEntity {
+        LogItemMessageEntity {
+            offset: 0,
+            length: 5,
+            kind,
         }
+    }
 
-        MessageKind::MigrateFromChatId { .. } => {
+    fn log_item_samples() -> Vec<LogItem> {
+        vec![
+            LogItem::Message {
+                user_id: Some("1001".to_string()),
+                time: TIME,
+                received_at: TIME,
+                text: "hello".to_string(),
+                entities: vec![
+                    entity(LogItemMessageEntityKind::Mention),
+                    entity(LogItemMessageEntityKind::Hashtag),
+                    entity(LogItemMessageEntityKind::BotCommand),
+                    entity(LogItemMessageEntityKind::Url),
+                    entity(LogItemMessageEntityKind::Email),
+                    entity(LogItemMessageEntityKind::Bold),
+                    entity(LogItemMessageEntityKind::Italic),
+                    entity(LogItemMessageEntityKind::Code),
+                    entity(LogItemMessageEntityKind::Pre(Some("rust".to_string()))),
+                    entity(LogItemMessageEntityKind::TextLink("https://example.com".to_string())),
+                    entity(LogItemMessageEntityKind::TextMention(LogItemTextMention::User {
+                        id: "1002".to_string(),
+                        name: Some("Bob".to_string()),
+                    })),
+                    entity(LogItemMessageEntityKind::Underline),
+                    entity(LogItemMessageEntityKind::Strikethrough),
+                    entity(LogItemMessageEntityKind::Spoiler),
+                    entity(LogItemMessageEntityKind::Blockquote),
+                    entity(LogItemMessageEntityKind::CustomEmoji(Some("5368324170671202286".to_string()))),
+                    entity(LogItemMessageEntityKind::Unknown("expandable_blockquote".to_string())),
+                ],
+                via_bot: Some("1003".to_string()),
+                author_signature: Some("Alice".to_string()),
+                source: None,
+                v: 4,
+            },
+            LogItem::Media {
+                user_id: Some("1001".to_string()),
+                time: TIME,
+                received_at: TIME,
+                caption: Some("a photo".to_string()),
+                media_type: media_type_samples().remove(0),
+                files: vec!["file-a".to_string()],
+                via_bot: None,
+                author_signature: None,
+                source: None,
+                v: 4,
+            },
+            LogItem::Special {
+                user_id: Some("1001".to_string()),
+                time: TIME,
+                received_at: TIME,
+                special_type: LogItemSpecialType::Location {
+                    latitude: 52.520008,
+                    longitude: 13.404954,
+                    live_updated: None,
+                },
+                source: None,
+                v: 4,
+            },
+            LogItem::Membership {
+                user_id: Some("1002".to_string()),
+                time: TIME,
+                received_at: TIME,
+                membership_type: LogItemMembershipType::Banned,
+                admin_id: Some("1001".to_string()),
+                source: None,
+                v: 4,
+            },
+            LogItem::Chat {
+                user_id: Some("1001".to_string()),
+                time: TIME,
+                received_at: TIME,
+                chat_type: chat_type_samples().remove(0),
+                source: None,
+                v: 4,
+            },
+            LogItem::Pin {
+                user_id: Some("1001".to_string()),
+                time: TIME,
+                received_at: TIME,
+                message: Some("hello".to_string()),
+                message_id: "42".to_string(),
+                source: None,
+                v: 4,
+            },
             LogItem::Unimplemented(
-                "MigrateFromChatId".to_string(),
-                msg_from_id,
-                message.date,
-                Some(message.clone()),
-            )
-        }
+                "proximity_alert_triggered".to_string(),
+                Some("1001".to_string()),
+                TIME,
+                None,
+            ),
+            LogItem::Redacted {
+                time: TIME,
+                redacted_at: TIME + 60,
+                v: 4,
+            },
+        ]
+    }
 
-        MessageKind::Unknown { .. } => {
-            dbg!(&message);
+    fn media_type_samples() -> Vec<LogItemMediaType> {
+        vec![
+            LogItemMediaType::Image {
+                width: 1280,
+                height: 720,
+                thumb_file_id: Some("thumb-a".to_string()),
+            },
+            LogItemMediaType::Video {
+                duration: 12,
+                width: 1920,
+                height: 1080,
+                thumb_file_id: Some("thumb-a".to_string()),
+                mime_type: Some("video/mp4".to_string()),
+            },
+            LogItemMediaType::Audio {
+                duration: 180,
+                performer: Some("Performer".to_string()),
+                title: Some("Title".to_string()),
+                mime_type: Some("audio/mpeg".to_string()),
+            },
+            LogItemMediaType::Voice {
+                duration: 4,
+                mime_type: Some("audio/ogg".to_string()),
+            },
+            LogItemMediaType::VideoNote {
+                duration: 8,
+                thumb_file_id: None,
+            },
+            LogItemMediaType::Animation {
+                duration: 3,
+                width: 320,
+                height: 240,
+                thumb_file_id: None,
+                mime_type: Some("video/mp4".to_string()),
+            },
+            LogItemMediaType::Document {
+                file_name: Some("notes.pdf".to_string()),
+                mime_type: Some("application/pdf".to_string()),
+            },
+            LogItemMediaType::Sticker {
+                emoji: Some("👍".to_string()),
+                set_name: Some("some_set".to_string()),
+            },
+        ]
+    }
 
-            LogItem::Unimplemented(
-                "Unknown".to_string(),
-                msg_from_id,
-                message.date,
-                Some(message.clone()),
-            )
-        }
+    fn special_type_samples() -> Vec<LogItemSpecialType> {
+        vec![
+            LogItemSpecialType::Contact {
+                user_id: Some(1002),
+                phone_number: "+4930123456".to_string(),
+                first_name: "Bob".to_string(),
+                last_name: None,
+                vcard: Some("BEGIN:VCARD\nEND:VCARD".to_string()),
+            },
+            LogItemSpecialType::Location {
+                latitude: 52.520008,
+                longitude: 13.404954,
+                live_updated: Some(TIME + 30),
+            },
+            LogItemSpecialType::Venue {
+                location: LogItemSpecialTypeLocation {
+                    longitude: 13.404954,
+                    latitude: 52.520008,
+                },
+                title: "Venue".to_string(),
+                address: "Street 1".to_string(),
+                foursquare_id: None,
+            },
+            LogItemSpecialType::Poll {
+                id: "5".to_string(),
+                question: "which?".to_string(),
+                options: vec![
+                    LogItemSpecialTypePollOption {
+                        text: "a".to_string(),
+                        voter_count: 2,
+                    },
+                    LogItemSpecialTypePollOption {
+                        text: "b".to_string(),
+                        voter_count: 1,
+                    },
+                ],
+                total_voter_count: 3,
+                is_closed: false,
+                is_anonymous: true,
+                poll_type: PollType::Quiz,
+                allows_multiple_answers: false,
+                correct_option_id: Some(0),
+                explanation: Some("because".to_string()),
+                explanation_entities: Some(vec![
+                    LogItemSpecialTypePollMessageEntity {
+                        offset: 0,
+                        length: 7,
+                        kind: LogItemMessageEntityKind::Bold,
+                    },
+                ]),
+                open_period: None,
+                close_date: None,
+                updated: Some(TIME + 30),
+            },
+            LogItemSpecialType::PinnnedMessage,
+            LogItemSpecialType::Dice {
+                emoji: "🎲".to_string(),
+                value: 6,
+            },
+            LogItemSpecialType::Game {
+                title: "Game".to_string(),
+                description: "a game".to_string(),
+            },
+            LogItemSpecialType::Payment {
+                currency: "EUR".to_string(),
+                total_amount: 1250,
+                invoice_payload: "order-1".to_string(),
+            },
+        ]
     }
-}
 
-pub async fn handle_message(
-    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
-    api: &Api,
-    message: &InterMessage,
-    files: &Vec<String>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let log_item =
-        build_log_item(
-            db.clone(),
-            api,
-            message,
-            files,
-        ).await;
+    fn chat_type_samples() -> Vec<LogItemChatType> {
+        vec![
+            LogItemChatType::NewTitle {
+                title: "new title".to_string(),
+            },
+            LogItemChatType::NewPhoto {
+                file_id: Some("photo-a".to_string()),
+            },
+            LogItemChatType::DeletePhoto,
+            LogItemChatType::VideoChatStarted,
+            LogItemChatType::VideoChatEnded {
+                duration: 600,
+            },
+            LogItemChatType::VideoChatInviteSent {
+                users: vec!["1002".to_string(), "1003".to_string()],
+            },
+            LogItemChatType::AutoDeleteTimerChanged {
+                seconds: 86_400,
+            },
+        ]
+    }
 
-    let db = db.lock().unwrap();
+    fn chat_meta_samples() -> Vec<ChatMeta> {
+        vec![
+            ChatMeta::User(UserMeta {
+                id: "1001".to_string(),
+                first_name: "Alice".to_string(),
+                last_name: None,
+                username: Some("alice".to_string()),
+                is_bot: false,
+                language_code: Some("en".to_string()),
+                v: 4,
+            }),
+            ChatMeta::Group(GroupMeta {
+                id: "-1001".to_string(),
+                title: "Group".to_string(),
+                all_members_are_administrators: true,
+                invite_link: None,
+                v: 4,
+            }),
+            ChatMeta::SuperGroup(SuperGroupMeta {
+                id: "-1001001".to_string(),
+                title: "Supergroup".to_string(),
+                username: Some("supergroup".to_string()),
+                invite_link: None,
+                v: 4,
+            }),
+            ChatMeta::Channel(ChannelMeta {
+                id: "-1001002".to_string(),
+                title: "Channel".to_string(),
+                username: None,
+                invite_link: Some("https://t.me/+abc".to_string()),
+                v: 4,
+            }),
+            ChatMeta::Unknown(RawChatMeta {
+                id: "-1001003".to_string(),
+                chat_type: "forum".to_string(),
+                title: Some("Forum".to_string()),
+                username: None,
+                first_name: None,
+                last_name: None,
+                invite_link: None,
+                language_code: None,
+                all_members_are_administrators: None,
+                v: 4,
+            }),
+        ]
+    }
 
-    let established_date =
-        message
-            .forward
-            .as_ref()
-            .map(|original_message|
-                     original_message
-                         .date,
-            )
-            .unwrap_or(
-                message
-                    .date,
-            );
+    /// Every sample serializes to its fixture, every fixture deserializes
+    /// to the variant it's for and back to itself, and no fixture in `dir`
+    /// goes without a sample.
+    fn assert_golden<T: Serialize + DeserializeOwned>(
+        dir: &str,
+        samples: Vec<T>,
+        fixture: fn(&T) -> &'static str,
+    ) {
+        for sample in &samples {
+            let expected = serde_json::from_str::<Value>(fixture(sample)).unwrap();
 
-    let use_forwarded_chat =
-        match &message.chat {
-            ChatMeta::Group(_)
-            | ChatMeta::SuperGroup(_)
-            | ChatMeta::Channel(_) => false,
-            ChatMeta::User(_)
-            | ChatMeta::Unknown(_) => true,
-        };
+            assert_eq!(serde_json::to_value(sample).unwrap(), expected, "{}", fixture(sample));
 
-    let chat_id =
-        message
-            .forward
-            .as_ref()
-            .map(|original_message| {
-                if !use_forwarded_chat {
-                    return None;
-                }
+            let parsed = serde_json::from_str::<T>(fixture(sample)).unwrap();
 
-                match original_message.from {
-                    ForwardFromMeta::User { ref user } => Some(user.id.clone()),
-                    ForwardFromMeta::Channel { ref channel, .. } => Some(channel.id.clone()),
-                    ForwardFromMeta::ChannelHiddenUser { .. } => None,
-                    ForwardFromMeta::HiddenGroupAdmin { ref chat_id, .. } => Some(chat_id.clone()),
-                }
-            })
-            .flatten()
-            .unwrap_or(
-                message
-                    .chat
-                    .id(),
-            );
+            assert_eq!(fixture(&parsed), fixture(sample));
+            assert_eq!(serde_json::to_value(&parsed).unwrap(), expected);
+        }
 
-    // store actual message
+        let files =
+            std::fs::read_dir(format!("{}/tests/fixtures/log_items/{}", env!("CARGO_MANIFEST_DIR"), dir))
+                .unwrap()
+                .count();
 
-    {
-        let message_key =
-            format!(
-                "chat:{}:{}",
-                &chat_id,
-                established_date.to_string(),
-            );
+        assert_eq!(samples.len(), files, "every fixture in {} needs a sample", dir);
+    }
 
-        let message_value = serde_json::to_string(&log_item)?;
+    #[test]
+    fn log_items_match_their_fixtures() {
+        assert_golden("log_item", log_item_samples(), log_item_fixture);
+    }
 
-        db.put(
-            &message_key,
-            &message_value,
-        )?;
+    #[test]
+    fn media_types_match_their_fixtures() {
+        assert_golden("media_type", media_type_samples(), media_type_fixture);
     }
 
-    // store chat index (days since start of epoch)
+    #[test]
+    fn special_types_match_their_fixtures() {
+        assert_golden("special_type", special_type_samples(), special_type_fixture);
+    }
 
-    {
-        let chat_index_key =
-            format!(
-                "chat_index:{}:{}",
-                &chat_id,
-                (established_date / 86400).to_string(),
-            );
+    #[test]
+    fn chat_types_match_their_fixtures() {
+        assert_golden("chat_type", chat_type_samples(), chat_type_fixture);
+    }
 
-        db.put(
-            &chat_index_key,
-            &b"\0",
-        )?;
+    #[test]
+    fn chat_metas_match_their_fixtures() {
+        assert_golden("chat_meta", chat_meta_samples(), chat_meta_fixture);
     }
 
-    // store chat so that it can be iterated upon
+    #[test]
+    fn historical_message_deserializes() {
+        let item = serde_json::from_str::<LogItem>(fixture!("historical/message_v0.json")).unwrap();
 
-    {
-        let chat_key =
-            format!(
-                "chat_rel:{}",
-                &chat_id,
-            );
+        // stored before the field existed
+        assert_eq!(item.received_at(), 1_600_000_000);
 
-        db.put(
-            &chat_key,
-            &b"\0",
-        )?;
-    }
+        let (entities, via_bot, author_signature, v) =
+            match item {
+                LogItem::Message { entities, via_bot, author_signature, v, .. } => (entities, via_bot, author_signature, v),
+                _ => panic!("not a message"),
+            };
 
-    // store chat by message id so that it allows direct lookup
+        assert_eq!((via_bot, author_signature, v), (None, None, 0));
 
-    {
-        let message_ref_key =
-            format!(
-                "chat_ref:{}:{}",
-                &chat_id,
-                message.id.to_string(),
-            );
+        assert!(matches!(entities[0].kind, LogItemMessageEntityKind::Pre(None)));
+        assert!(matches!(entities[1].kind, LogItemMessageEntityKind::Unknown(ref kind) if kind.is_empty()));
+        assert!(matches!(entities[2].kind, LogItemMessageEntityKind::CustomEmoji(None)));
+        assert!(matches!(entities[3].kind, LogItemMessageEntityKind::TextMention(LogItemTextMention::Id(ref id)) if id == "1002"));
+    }
 
-        let message_ref_value = established_date.to_string();
+    #[test]
+    fn historical_media_deserializes() {
+        let item = serde_json::from_str::<LogItem>(fixture!("historical/media_image_without_thumb.json")).unwrap();
 
-        db.put(
-            &message_ref_key,
-            &message_ref_value,
-        )?;
+        assert!(matches!(
+            item,
+            LogItem::Media { media_type: LogItemMediaType::Image { width: 1280, height: 720, thumb_file_id: None }, v: 0, .. }
+        ));
     }
 
-    // store chat metadata
+    #[test]
+    fn historical_specials_deserialize() {
+        let item = serde_json::from_str::<LogItem>(fixture!("historical/special_location_without_live_updated.json")).unwrap();
 
-    {
-        let chat_meta_key =
-            format!(
-                "chat:meta:{}",
-                &chat_id,
-            );
+        assert!(matches!(item, LogItem::Special { special_type: LogItemSpecialType::Location { live_updated: None, .. }, .. }));
 
-        let chat_meta_value =
-            message
-                .forward
-                .as_ref()
-                .map(|forward| {
-                    if !use_forwarded_chat {
-                        return None;
-                    }
+        // written from the f32 telegram sent, it reads back as that decimal
+        match item {
+            LogItem::Special { special_type: LogItemSpecialType::Location { latitude, longitude, .. }, .. } => {
+                assert_eq!(latitude, 52.520008);
+                assert_eq!(longitude, 13.404954);
+                assert_eq!(latitude, widen_coordinate(52.520008_f32));
+                assert_eq!(longitude, widen_coordinate(13.404954_f32));
+            }
+            _ => unreachable!(),
+        }
 
-                    match forward.from {
-                        ForwardFromMeta::User { ref user } =>
-                            Some(serde_json::to_string(&user)),
-                        ForwardFromMeta::Channel { ref channel, .. } =>
-                            Some(serde_json::to_string(&channel)),
-                        _ => None,
-                    }
-                })
-                .flatten()
-                .unwrap_or(
-                    serde_json::to_string(&message.chat),
-                )?;
+        let item = serde_json::from_str::<LogItem>(fixture!("historical/special_contact_without_vcard.json")).unwrap();
 
-        db.put(
-            &chat_meta_key,
-            &chat_meta_value,
-        )?;
-    }
+        assert!(matches!(item, LogItem::Special { special_type: LogItemSpecialType::Contact { vcard: None, .. }, .. }));
 
-    Ok(())
-}
+        let item = serde_json::from_str::<LogItem>(fixture!("historical/special_poll_without_updated.json")).unwrap();
 
-pub async fn handle_inter_message(
-    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
-    api: &Api,
-    inter_msg: &InterMessage,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let files =
-        match inter_msg.kind {
-            MessageKind::Audio { .. }
-            | MessageKind::Voice { .. }
-            | MessageKind::Photo { .. }
-            | MessageKind::Document { .. }
-            | MessageKind::Sticker { .. }
-            | MessageKind::Video { .. }
-            | MessageKind::VideoNote { .. } =>
-                process_files(
-                    db.clone(),
-                    &api,
-                    &inter_msg,
-                ).await?,
+        assert!(matches!(item, LogItem::Special { special_type: LogItemSpecialType::Poll { updated: None, .. }, .. }));
+    }
 
-            _ => Vec::new(),
-        };
+    #[test]
+    fn historical_membership_deserializes() {
+        let item = serde_json::from_str::<LogItem>(fixture!("historical/membership_without_admin_id.json")).unwrap();
 
-    handle_message(
-        db.clone(),
-        &api,
-        dbg!(&inter_msg),
-        &files,
-    ).await?;
+        assert!(matches!(
+            item,
+            LogItem::Membership { membership_type: LogItemMembershipType::Joined, admin_id: None, v: 0, .. }
+        ));
+    }
 
-    if let Some(ref from) = inter_msg.from {
-        process_user(
-            db.clone(),
-            &api,
-            dbg!(from),
-        ).await?;
+    #[test]
+    fn historical_chat_meta_deserializes() {
+        let meta = serde_json::from_str::<ChatMeta>(fixture!("historical/chat_meta_user_v0.json")).unwrap();
+
+        assert!(matches!(meta, ChatMeta::User(UserMeta { ref id, v: 0, .. }) if id == "1001"));
     }
 
-    Ok(())
-}
+    #[test]
+    fn entity_kinds_stored_before_they_carried_data_deserialize() {
+        let kind = |json: &str| deserialize_entity_kind(&mut serde_json::Deserializer::from_str(json)).unwrap();
 
-async fn run(
-    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let api = Api::new(get_telegram_api_token());
+        assert!(matches!(kind(r#""pre""#), LogItemMessageEntityKind::Pre(None)));
+        assert!(matches!(kind(r#""unknown""#), LogItemMessageEntityKind::Unknown(ref kind) if kind.is_empty()));
+        assert!(matches!(kind(r#"{"customemoji": ""}"#), LogItemMessageEntityKind::CustomEmoji(None)));
+        assert!(matches!(kind(r#"{"pre": "rust"}"#), LogItemMessageEntityKind::Pre(Some(ref lang)) if lang == "rust"));
+        assert!(matches!(kind(r#""bold""#), LogItemMessageEntityKind::Bold));
+    }
 
-    let mut stream = api.stream();
+    /// Parsed contents of the fixtures in `dir`.
+    fn read_fixtures<T: DeserializeOwned>(dir: &str) -> Vec<(String, T)> {
+        let dir = format!("{}/tests/fixtures/log_items/{}", env!("CARGO_MANIFEST_DIR"), dir);
 
-    while let Some(update) = stream.next().await {
-        let db = db.clone();
+        std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter_map(|path| {
+                let parsed = serde_json::from_str::<T>(&std::fs::read_to_string(&path).unwrap()).ok()?;
 
-        let update = update?;
+                Some((path.display().to_string(), parsed))
+            })
+            .collect()
+    }
 
-        dbg!(&update);
+    #[test]
+    fn every_file_of_a_fixture_resolves_through_its_url() {
+        let mut items = read_fixtures::<LogItem>("log_item");
+
+        items.extend(read_fixtures::<LogItem>("historical"));
+
+        // the media and chat types on their own carry no files, put them
+        // into an item that has some
+        for (path, media_type) in read_fixtures::<LogItemMediaType>("media_type") {
+            items.push((
+                path,
+                LogItem::Media {
+                    user_id: None,
+                    time: TIME,
+                    received_at: TIME,
+                    caption: None,
+                    media_type,
+                    files: vec!["file-a".to_string(), "file-b".to_string()],
+                    via_bot: None,
+                    author_signature: None,
+                    source: None,
+                    v: 4,
+                },
+            ));
+        }
 
-        match update.kind {
-            UpdateKind::Message(ref message)
-            | UpdateKind::EditedMessage(ref message) => {
-                let inter_msg = InterMessage::from(message);
+        for (path, chat_type) in read_fixtures::<LogItemChatType>("chat_type") {
+            items.push((
+                path,
+                LogItem::Chat {
+                    user_id: None,
+                    time: TIME,
+                    received_at: TIME,
+                    chat_type,
+                    source: None,
+                    v: 4,
+                },
+            ));
+        }
 
-                if let Some(reply_to_message) = inter_msg.reply_to_message.as_ref() {
-                    handle_inter_message(
-                        db.clone(),
-                        &api,
-                        &reply_to_message.as_ref(),
-                    ).await?;
-                }
+        let mut resolved = 0;
 
-                handle_inter_message(
-                    db.clone(),
-                    &api,
-                    &inter_msg,
-                ).await?;
-            },
-            UpdateKind::ChannelPost(ref post)
-            | UpdateKind::EditedChannelPost(ref post) => {
-                let inter_msg = InterMessage::from(post);
+        for (path, item) in &items {
+            let file_urls = item.file_urls();
 
-                if let Some(reply_to_message) = inter_msg.reply_to_message.as_ref() {
-                    handle_inter_message(
-                        db.clone(),
-                        &api,
-                        &reply_to_message.as_ref(),
-                    ).await?;
-                }
+            // every stored file is served, the blob and its thumbnail
+            let mut expected_keys =
+                match item {
+                    LogItem::Media { files, .. } =>
+                        files
+                            .iter()
+                            .map(|file_id| build_file_key(FileEntryType::Chat, file_id))
+                            .collect::<Vec<String>>(),
+                    _ => vec![],
+                };
 
-                handle_inter_message(
-                    db.clone(),
-                    &api,
-                    &inter_msg,
-                ).await?;
+            match item {
+                LogItem::Media { media_type: LogItemMediaType::Video { thumb_file_id: Some(thumb), .. }, .. }
+                | LogItem::Media { media_type: LogItemMediaType::VideoNote { thumb_file_id: Some(thumb), .. }, .. }
+                | LogItem::Media { media_type: LogItemMediaType::Animation { thumb_file_id: Some(thumb), .. }, .. }
+                | LogItem::Chat { chat_type: LogItemChatType::NewPhoto { file_id: Some(thumb) }, .. } =>
+                    expected_keys.push(build_file_key(FileEntryType::VideoThumb, thumb)),
+                _ => {}
             }
-            _ => {},
-        }
-    }
 
-    Ok(())
-}
+            let keys =
+                file_urls
+                    .iter()
+                    .map(|(file_id, url)| {
+                        let segments = url.split('/').collect::<Vec<&str>>();
 
-pub async fn spawn_worker(
-    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
-) {
-    loop {
-        if let Err(err) = run(
-            db.clone(),
-        ).await {
-            dbg!(err);
+                        assert_eq!(segments.len(), 4, "{}: {}", path, url);
+                        assert_eq!((segments[0], segments[1]), ("", "file"), "{}: {}", path, url);
+                        assert_eq!(segments[3], file_id, "{}: {}", path, url);
+
+                        // what `get_file` looks the blob up under
+                        assert_ne!(
+                            crate::renderer::get_file::FileRequestType::from(segments[2].to_string()),
+                            crate::renderer::get_file::FileRequestType::Unknown,
+                            "{}: {}",
+                            path,
+                            url,
+                        );
+
+                        let file_entry_type =
+                            FileEntryType::from_url_kind(segments[2])
+                                .unwrap_or_else(|| panic!("{}: no route for {}", path, url));
+
+                        build_file_key(file_entry_type, segments[3])
+                    })
+                    .collect::<Vec<String>>();
+
+            assert_eq!(keys, expected_keys, "{}", path);
+
+            resolved += keys.len();
         }
 
-        tokio::time::sleep(
-            Duration::from_millis(
-                JOB_SLEEP_INTERVAL,
-            ),
-        ).await;
+        // the walk found the fixtures, and they do have files
+        assert!(items.len() >= 29);
+        assert!(resolved >= 20);
     }
 }
@@ -0,0 +1,310 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use pw_telegram_bot_fork::{Chat, ChatId, GetChat, GetChatMember, UserId};
+use rocksdb::{DBWithThreadMode, Direction, IteratorMode, MultiThreaded, ReadOptions};
+use serde::{Deserialize, Serialize};
+
+use crate::{ok_or_continue, some_or_continue};
+use crate::config::get_bots;
+use crate::metrics::track_api_call;
+use crate::storage::lock_db;
+use crate::workers::telegram_handler::{Bot, find_chat_bot, LogItem, parse_message_key, process_user_meta, UserMeta};
+
+// log items looked at per pass over the database before the lock is released
+pub const USER_META_SCAN_BATCH_SIZE: usize = 1_000;
+
+// delay between two telegram api calls, keeps us well below the rate limits
+pub const USER_META_API_INTERVAL: u64 = 1_500u64;
+
+// delay between two complete scans once everything has been caught up
+pub const USER_META_RESCAN_INTERVAL: u64 = 6 * 60 * 60 * 1_000u64;
+
+pub const USER_META_PROGRESS_KEY: &str = "job:user_meta_backfill:progress";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserMetaTombstone {
+    pub reason: String,
+    pub time: i64,
+}
+
+pub fn build_user_meta_tombstone_key(
+    user_id: &str,
+) -> String {
+    format!(
+        "user:meta_tombstone:{}",
+        user_id,
+    )
+}
+
+/// Scans up to `USER_META_SCAN_BATCH_SIZE` log items after the stored
+/// progress key and returns the `(chat_id, user_id)` pairs that have neither
+/// a meta record nor a tombstone, along with the key to resume from (None
+/// once the end of the log has been reached).
+pub fn scan_missing_user_meta(
+    db: &DBWithThreadMode<MultiThreaded>,
+) -> (Vec<(String, String)>, Option<Vec<u8>>) {
+    let progress =
+        db.get(USER_META_PROGRESS_KEY)
+            .ok()
+            .flatten()
+            .unwrap_or(b"chat:".to_vec());
+
+    let mut opts = ReadOptions::default();
+
+    opts.set_iterate_upper_bound(b"chat:\x7f".to_vec());
+
+    let iter =
+        db.iterator_opt(
+            IteratorMode::From(&progress, Direction::Forward),
+            opts,
+        );
+
+    let mut missing = Vec::<(String, String)>::new();
+    let mut last_key = None;
+    let mut scanned = 0;
+    let mut exhausted = true;
+
+    for (key, val) in iter {
+        if key.as_ref() == progress.as_slice() {
+            continue;
+        }
+
+        if scanned >= USER_META_SCAN_BATCH_SIZE {
+            exhausted = false;
+
+            break;
+        }
+
+        scanned += 1;
+
+        last_key = Some(key.to_vec());
+
+        let key = ok_or_continue!(String::from_utf8(key.to_vec()));
+
+        // skips chat:meta:* and friends, only chat:{chat_id}:{slot} are log items
+        let (chat_id, _) = some_or_continue!(parse_message_key(&key));
+
+        let item = ok_or_continue!(serde_json::from_slice::<LogItem>(&val));
+
+        let user_id =
+            match item.user_id() {
+                Some(user_id) => user_id.clone(),
+                None => continue,
+            };
+
+        if missing.iter().any(|(_, id)| id == &user_id) {
+            continue;
+        }
+
+        let has_meta =
+            db.get(format!("user:meta:{}", &user_id))
+                .ok()
+                .flatten()
+                .is_some();
+
+        let has_tombstone =
+            db.get(build_user_meta_tombstone_key(&user_id))
+                .ok()
+                .flatten()
+                .is_some();
+
+        if !has_meta && !has_tombstone {
+            missing.push((chat_id.to_string(), user_id));
+        }
+    }
+
+    let resume_key =
+        if exhausted {
+            None
+        } else {
+            last_key
+        };
+
+    (missing, resume_key)
+}
+
+// what telegram said about a user it was asked about
+enum UserLookup {
+    Found(UserMeta),
+    // telegram doesn't know the user (anymore), asking again won't help
+    Unknown(String),
+}
+
+// whether a failed lookup is telegram saying there's no such user or
+// member, rather than the call not going through. The fork only has the
+// answer as text
+fn is_unknown_user(
+    message: &str,
+) -> bool {
+    let lowercase = message.to_lowercase();
+
+    [
+        "user not found",
+        "member not found",
+        "chat not found",
+        "participant_id_invalid",
+        "user_id_invalid",
+        "peer_id_invalid",
+    ]
+        .iter()
+        .any(|answer| lowercase.contains(answer))
+}
+
+async fn fetch_user_meta(
+    bot: &Bot,
+    chat_id: &str,
+    user_id: &str,
+) -> Result<UserLookup, Box<dyn std::error::Error>> {
+    let chat_id = ChatId::new(chat_id.parse::<i64>()?);
+    let user_id = UserId::new(user_id.parse::<i64>()?);
+
+    // members are the common case, getChat only works for users that have
+    // talked to the bot directly
+    match track_api_call("getChatMember", bot.api.send(GetChatMember::new(chat_id, user_id))).await {
+        Ok(member) => return Ok(UserLookup::Found(member.user.into())),
+        Err(err) if !is_unknown_user(&err.to_string()) => return Err(err.into()),
+        Err(_) => {}
+    }
+
+    match track_api_call("getChat", bot.api.send(GetChat::new(ChatId::from(user_id)))).await {
+        Ok(Chat::Private(user)) => Ok(UserLookup::Found(user.into())),
+        Ok(_) => Ok(UserLookup::Unknown("not a private chat".to_string())),
+        Err(err) if is_unknown_user(&err.to_string()) => Ok(UserLookup::Unknown(err.to_string())),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Runs one batch of the backfill: scans for missing user meta, fetches what
+/// telegram still knows and tombstones the users it doesn't. Lookups that
+/// fail otherwise (timeouts, rate limits) are tried again on the next scan.
+/// Returns false once the scan has reached the end of the log.
+pub async fn backfill_user_meta_batch(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    bots: &[Bot],
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let (missing, resume_key) = {
+        let db = lock_db(&db);
+
+        scan_missing_user_meta(&db)
+    };
+
+    for (chat_id, user_id) in missing.iter() {
+        // only a bot that's in the chat can look up its members
+        let bot =
+            match find_chat_bot(&lock_db(&db), bots, chat_id) {
+                Some(bot) => bot,
+                // no bots configured, nothing can be looked up
+                None => return Ok(false),
+            };
+
+        match fetch_user_meta(bot, chat_id, user_id).await {
+            Ok(UserLookup::Found(user)) => {
+                process_user_meta(
+                    db.clone(),
+                    &user,
+                ).await?;
+
+                println!("[user_meta] resolved {} via {}", user_id, chat_id);
+            }
+            Ok(UserLookup::Unknown(reason)) => {
+                let tombstone =
+                    UserMetaTombstone {
+                        reason: reason.clone(),
+                        time: chrono::Utc::now().timestamp(),
+                    };
+
+                lock_db(&db).put(
+                    build_user_meta_tombstone_key(user_id),
+                    serde_json::to_string(&tombstone)?,
+                )?;
+
+                println!("[user_meta] giving up on {}: {}", user_id, reason);
+            }
+            Err(err) => {
+                println!("[user_meta] couldn't look up {}, trying again later: {}", user_id, err);
+            }
+        }
+
+        tokio::time::sleep(
+            Duration::from_millis(
+                USER_META_API_INTERVAL,
+            ),
+        ).await;
+    }
+
+    let db = lock_db(&db);
+
+    match resume_key {
+        Some(ref key) => db.put(USER_META_PROGRESS_KEY, key)?,
+        None => db.delete(USER_META_PROGRESS_KEY)?,
+    }
+
+    Ok(resume_key.is_some())
+}
+
+/// Runs batches until the whole log has been scanned once.
+pub async fn backfill_user_meta(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+    bots: &[Bot],
+) -> Result<(), Box<dyn std::error::Error>> {
+    while backfill_user_meta_batch(db.clone(), bots).await? {}
+
+    Ok(())
+}
+
+pub async fn spawn_worker(
+    db: Arc<Mutex<DBWithThreadMode<MultiThreaded>>>,
+) {
+    let bots =
+        get_bots()
+            .unwrap_or_default()
+            .iter()
+            .map(Bot::new)
+            .collect::<Vec<Bot>>();
+
+    if bots.is_empty() {
+        return;
+    }
+
+    loop {
+        if let Err(err) = backfill_user_meta(
+            db.clone(),
+            &bots,
+        ).await {
+            dbg!(err);
+        }
+
+        tokio::time::sleep(
+            Duration::from_millis(
+                USER_META_RESCAN_INTERVAL,
+            ),
+        ).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_telegram_not_knowing_a_user_is_definitive() {
+        for message in [
+            "Bad Request: user not found",
+            "Bad Request: member not found",
+            "Bad Request: chat not found",
+            "Bad Request: PARTICIPANT_ID_INVALID",
+        ] {
+            assert!(is_unknown_user(message), "{}", message);
+        }
+
+        for message in [
+            "Too Many Requests: retry after 5",
+            "error sending request for url: operation timed out",
+            "Internal Server Error",
+            "Forbidden: bot was kicked from the supergroup chat",
+        ] {
+            assert!(!is_unknown_user(message), "{}", message);
+        }
+    }
+}
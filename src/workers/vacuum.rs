@@ -0,0 +1,241 @@
+use chrono::Utc;
+use rocksdb::{DBWithThreadMode, MultiThreaded};
+
+use crate::ok_or_continue;
+use crate::blob_store::delete_blob;
+use crate::storage_stats::{file_counter_keys, prefix_iter};
+use crate::workers::telegram_handler::{build_file_key, build_file_meta_key, ChatMetaChange, ChatMetaHistoryEntry, FileEntryType, FileMeta, LogItem, parse_message_key};
+
+// referenced file ids are collected on disk rather than in memory, an
+// archive can hold millions of them
+const VACUUM_REF_PREFIX: &str = "vacuum:ref:";
+
+#[derive(Debug, Clone, Default)]
+pub struct VacuumSummary {
+    pub referenced: usize,
+    pub kept: usize,
+    // unreferenced, but too young to tell apart from a file whose message
+    // isn't written yet
+    pub too_recent: usize,
+    pub deleted: usize,
+    pub reclaimed_bytes: u64,
+}
+
+fn clear_references(
+    db: &DBWithThreadMode<MultiThreaded>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let keys =
+        prefix_iter(db, VACUUM_REF_PREFIX)
+            .map(|(key, _)| key)
+            .collect::<Vec<Box<[u8]>>>();
+
+    for key in keys.iter() {
+        db.delete(key)?;
+    }
+
+    Ok(())
+}
+
+fn collect_references(
+    db: &DBWithThreadMode<MultiThreaded>,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut referenced = 0usize;
+
+    let mut reference = |file_id: &str| -> Result<(), Box<dyn std::error::Error>> {
+        db.put(format!("{}{}", VACUUM_REF_PREFIX, file_id), &b"\0")?;
+        referenced += 1;
+
+        Ok(())
+    };
+
+    for (key, val) in prefix_iter(db, "chat:") {
+        let key = ok_or_continue!(String::from_utf8(key.to_vec()));
+        let parts = key.split(':').collect::<Vec<&str>>();
+
+        // a row that doesn't parse may well refer to files, whatever the run
+        // would delete could be one of them
+        let unreadable = |err: serde_json::Error| format!("{} doesn't parse, not deleting anything: {}", key, err);
+
+        // chat photos shown on the info page live on in the meta history
+        if parts.len() == 4 && parts[1] == "meta_history" {
+            if let ChatMetaHistoryEntry { change: ChatMetaChange::Photo { file_id: Some(file_id) }, .. } = serde_json::from_slice(&val).map_err(unreadable)? {
+                reference(&file_id)?;
+            }
+
+            continue;
+        }
+
+        // only log items, not chat:meta:* and friends
+        if parse_message_key(&key).is_none() {
+            continue;
+        }
+
+        let log_item = serde_json::from_slice::<LogItem>(&val).map_err(unreadable)?;
+
+        for file_id in log_item.file_ids() {
+            reference(&file_id)?;
+        }
+    }
+
+    Ok(referenced)
+}
+
+/// Deletes `file:chat:` and `file:video_thumb:` blobs that no log item
+/// refers to anymore. Blobs stored less than `min_age` seconds ago are
+/// left alone. Profile pictures aren't touched. Thumbnails are derived
+/// data, they go along with their original and are swept once it's gone.
+pub fn vacuum_files(
+    db: &DBWithThreadMode<MultiThreaded>,
+    min_age: i64,
+    dry_run: bool,
+) -> Result<VacuumSummary, Box<dyn std::error::Error>> {
+    let mut summary = VacuumSummary::default();
+
+    // leftovers of an interrupted run would keep orphans alive
+    clear_references(db)?;
+
+    summary.referenced = collect_references(db)?;
+
+    let now = Utc::now().timestamp();
+
+    for kind in ["chat", "video_thumb"] {
+        let prefix = format!("file:{}:", kind);
+
+        for (key, val) in prefix_iter(db, &prefix) {
+            let key = ok_or_continue!(String::from_utf8(key.to_vec()));
+            let file_id = key.trim_start_matches(&prefix);
+
+            if db.get(format!("{}{}", VACUUM_REF_PREFIX, file_id))?.is_some() {
+                summary.kept += 1;
+
+                continue;
+            }
+
+            let meta =
+                db.get(build_file_meta_key(file_id))?
+                    .map(|meta| serde_json::from_slice::<FileMeta>(&meta).ok())
+                    .flatten();
+
+            let recent =
+                meta.as_ref()
+                    .map(|meta| meta.stored_at)
+                    .flatten()
+                    .map(|stored_at| now - stored_at < min_age)
+                    .unwrap_or(false);
+
+            if recent {
+                summary.too_recent += 1;
+
+                continue;
+            }
+
+            summary.deleted += 1;
+            summary.reclaimed_bytes += val.len() as u64;
+
+            if dry_run {
+                continue;
+            }
+
+            delete_blob(
+                db,
+                &key,
+                &file_counter_keys(
+                    kind,
+                    meta.as_ref()
+                        .map(|meta| meta.message_key.as_deref())
+                        .flatten(),
+                ),
+            )?;
+
+            // shares the meta record, so it has to go before that does
+            if let Some(thumb) = db.get(build_file_key(FileEntryType::Thumb, file_id))? {
+                summary.reclaimed_bytes += thumb.len() as u64;
+
+                delete_blob(
+                    db,
+                    &build_file_key(FileEntryType::Thumb, file_id),
+                    &file_counter_keys(
+                        "thumb",
+                        meta.as_ref()
+                            .map(|meta| meta.message_key.as_deref())
+                            .flatten(),
+                    ),
+                )?;
+            }
+
+            db.delete(build_file_meta_key(file_id))?;
+        }
+    }
+
+    // thumbnails whose original was deleted before they were vacuumed along
+    for (key, val) in prefix_iter(db, "file:thumb:") {
+        let key = ok_or_continue!(String::from_utf8(key.to_vec()));
+        let file_id = key.trim_start_matches("file:thumb:");
+
+        if db.get(build_file_key(FileEntryType::Chat, file_id))?.is_some() {
+            continue;
+        }
+
+        summary.deleted += 1;
+        summary.reclaimed_bytes += val.len() as u64;
+
+        if dry_run {
+            continue;
+        }
+
+        let message_key =
+            db.get(build_file_meta_key(file_id))?
+                .map(|meta| serde_json::from_slice::<FileMeta>(&meta).ok())
+                .flatten()
+                .map(|meta| meta.message_key)
+                .flatten();
+
+        delete_blob(
+            db,
+            &key,
+            &file_counter_keys("thumb", message_key.as_deref()),
+        )?;
+    }
+
+    clear_references(db)?;
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_db(name: &str) -> DBWithThreadMode<MultiThreaded> {
+        let path =
+            std::env::temp_dir()
+                .join(format!("minuteman-test-{}-{}", name, std::process::id()));
+
+        let _ = std::fs::remove_dir_all(&path);
+
+        DBWithThreadMode::<MultiThreaded>::open_default(path).unwrap()
+    }
+
+    #[test]
+    fn unparseable_rows_stop_the_run_before_anything_is_deleted() {
+        let db = open_db("vacuum-unparseable");
+
+        db.put(build_file_key(FileEntryType::Chat, "orphan"), b"blob").unwrap();
+        db.put(build_file_key(FileEntryType::Chat, "maybe-referenced"), b"blob").unwrap();
+        db.put("chat:-1001:1600000000", b"{\"media\": {\"files\": [\"maybe-referenced\"").unwrap();
+
+        let err = vacuum_files(&db, 0, false).unwrap_err();
+
+        assert!(err.to_string().contains("chat:-1001:1600000000"), "{}", err);
+        assert!(db.get(build_file_key(FileEntryType::Chat, "orphan")).unwrap().is_some());
+        assert!(db.get(build_file_key(FileEntryType::Chat, "maybe-referenced")).unwrap().is_some());
+
+        // with the row gone, so are both files
+        db.delete("chat:-1001:1600000000").unwrap();
+
+        let summary = vacuum_files(&db, 0, false).unwrap();
+
+        assert_eq!(summary.deleted, 2);
+        assert!(db.get(build_file_key(FileEntryType::Chat, "orphan")).unwrap().is_none());
+    }
+}
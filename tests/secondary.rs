@@ -0,0 +1,136 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+
+use rocksdb::{DBWithThreadMode, MultiThreaded};
+
+use minuteman::storage::{PendingWrites, ReadStore, Storage};
+use minuteman::storage_stats::prefix_iter;
+use minuteman::workers::telegram_handler::build_message_key;
+
+const CHAT_ID: &str = "-1001";
+
+// what the bot does to the database, the reader only sees its effects
+enum Write {
+    Messages(Vec<i64>),
+    Flush,
+    Compact,
+    Stop,
+}
+
+fn temp_path(name: &str) -> PathBuf {
+    let path =
+        std::env::temp_dir()
+            .join(format!("minuteman-test-{}-{}", name, std::process::id()));
+
+    let _ = std::fs::remove_dir_all(&path);
+
+    path
+}
+
+/// Stands in for the bot: owns the primary and nothing else touches it.
+/// Every write is acknowledged once it's in the database.
+fn spawn_writer(
+    path: &Path,
+) -> (Sender<Write>, Receiver<()>, thread::JoinHandle<()>) {
+    let (writes, write_rx) = channel::<Write>();
+    let (done_tx, done) = channel::<()>();
+
+    let db = DBWithThreadMode::<MultiThreaded>::open_default(path).unwrap();
+
+    let handle =
+        thread::spawn(move || {
+            for write in write_rx {
+                match write {
+                    Write::Messages(times) => {
+                        let mut pending = PendingWrites::new();
+
+                        for time in times {
+                            pending.put(&build_message_key(CHAT_ID, time), format!("{{\"time\":{}}}", time));
+                        }
+
+                        pending.commit(&db).unwrap();
+                    }
+                    Write::Flush => db.flush().unwrap(),
+                    Write::Compact => db.compact_range::<&[u8], &[u8]>(None, None),
+                    Write::Stop => break,
+                }
+
+                done_tx.send(()).unwrap();
+            }
+        });
+
+    (writes, done, handle)
+}
+
+fn message_keys(
+    db: &impl ReadStore,
+) -> Vec<String> {
+    prefix_iter(db, &format!("chat:{}:", CHAT_ID))
+        .map(|(key, _)| String::from_utf8(key.to_vec()).unwrap())
+        .collect()
+}
+
+#[test]
+fn secondary_sees_what_the_primary_wrote_once_caught_up() {
+    let primary = temp_path("secondary-primary");
+    let secondary = temp_path("secondary-secondary");
+
+    let (writes, done, writer) = spawn_writer(&primary);
+
+    let write = |write: Write| {
+        writes.send(write).unwrap();
+        done.recv().unwrap();
+    };
+
+    write(Write::Messages(vec![1_600_000_000, 1_600_000_001]));
+    write(Write::Flush);
+
+    let reader =
+        DBWithThreadMode::<MultiThreaded>::open_secondary(
+            primary.to_str().unwrap(),
+            secondary.to_str().unwrap(),
+        )
+            .unwrap();
+
+    assert_eq!(
+        message_keys(&reader),
+        vec![build_message_key(CHAT_ID, 1_600_000_000), build_message_key(CHAT_ID, 1_600_000_001)],
+    );
+
+    // only in the primary's write-ahead log, not flushed yet
+    write(Write::Messages(vec![1_600_000_002]));
+
+    assert_eq!(message_keys(&reader).len(), 2);
+
+    reader.catch_up().unwrap();
+
+    assert_eq!(message_keys(&reader).len(), 3);
+    assert!(reader.get(build_message_key(CHAT_ID, 1_600_000_002)).unwrap().is_some());
+
+    // what the cli and the web frontend read through
+    let view = reader.read_view();
+
+    assert_eq!(message_keys(&view).len(), 3);
+    assert!(view.get(build_message_key(CHAT_ID, 1_600_000_002)).unwrap().is_some());
+
+    drop(view);
+
+    // files the secondary read from are compacted away meanwhile
+    write(Write::Messages(vec![1_600_000_003, 1_600_000_004]));
+    write(Write::Flush);
+    write(Write::Compact);
+    write(Write::Messages(vec![1_600_000_005]));
+
+    reader.catch_up().unwrap();
+
+    assert_eq!(
+        message_keys(&reader),
+        (1_600_000_000..=1_600_000_005)
+            .map(|time| build_message_key(CHAT_ID, time))
+            .collect::<Vec<String>>(),
+    );
+
+    writes.send(Write::Stop).unwrap();
+    writer.join().unwrap();
+}